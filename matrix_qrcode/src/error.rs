@@ -18,10 +18,31 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum DecodingError {
     /// Error decoding the QR code.
-    #[cfg(feature = "decode_image")]
-    #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
+    #[cfg(feature = "decode")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode)))]
     #[error(transparent)]
     Qr(#[from] rqrr::DeQRError),
+    /// The raw frame is smaller than its dimensions and pixel format require.
+    #[cfg(feature = "decode")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode)))]
+    #[error("the frame is too small, expected at least {expected} bytes, found {found} bytes")]
+    FrameSize {
+        /// The number of bytes a frame of the given size and format needs.
+        expected: usize,
+        /// The number of bytes the frame has.
+        found: usize,
+    },
+    /// The dimensions of the raw frame are too large, the size of the frame
+    /// can't be represented.
+    #[cfg(feature = "decode")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode)))]
+    #[error("the frame dimensions {width}x{height} are invalid")]
+    InvalidDimensions {
+        /// The width of the frame.
+        width: u32,
+        /// The height of the frame.
+        height: u32,
+    },
     /// The QR code data is missing the mandatory Matrix header.
     #[error("the decoded QR code is missing the Matrix header")]
     Header,
@@ -58,4 +79,33 @@ pub enum EncodingError {
     /// Error encoding the given flow id, the flow id is too large.
     #[error("The verification flow id length can't be converted into a u16: {0}")]
     FlowId(#[from] std::num::TryFromIntError),
+    /// A field that is required for the QR code wasn't set.
+    #[error("the {0} of the QR code is missing")]
+    MissingField(&'static str),
+    /// One of the keys isn't valid unpadded base64.
+    #[error("the {name} isn't valid base64: {source}")]
+    InvalidKey {
+        /// The name of the key.
+        name: &'static str,
+        /// The error decoding the key.
+        source: base64::DecodeError,
+    },
+    /// One of the keys doesn't have the length of an Ed25519 key.
+    #[error("the {name} has a length of {length} bytes, expected 32 bytes")]
+    KeyLength {
+        /// The name of the key.
+        name: &'static str,
+        /// The length of the decoded key.
+        length: usize,
+    },
+    /// The shared secret is too short to be secure.
+    #[error("the shared secret is too short, length: {0}, at least 8 bytes are required")]
+    SharedSecret(usize),
+    /// One of the SVG colors contains characters that aren't allowed in an
+    /// SVG color value.
+    #[error("the SVG color {0:?} contains invalid characters")]
+    InvalidSvgColor(String),
+    /// The size of the rendered SVG image doesn't fit into an `u32`.
+    #[error("the SVG image is too large, reduce the quiet zone or the module size")]
+    SvgTooLarge,
 }
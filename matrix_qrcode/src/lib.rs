@@ -21,6 +21,7 @@
 //!
 //! ```no_run
 //! # use matrix_qrcode::{QrVerification, DecodingError};
+//! # #[cfg(feature = "decode_image")]
 //! # fn main() -> Result<(), DecodingError> {
 //! use image;
 //!
@@ -28,7 +29,24 @@
 //! let result = QrVerification::from_image(image)?;
 //! # Ok(())
 //! # }
+//! # #[cfg(not(feature = "decode_image"))]
+//! # fn main() {}
 //! ```
+//!
+//! # Features
+//!
+//! Encoding and parsing the payload of a QR code with
+//! [`QrVerification::to_bytes()`] and [`QrVerification::from_bytes()`], as
+//! well as rendering a QR code as SVG, is always available. The optional
+//! features add support for decoding QR codes from images:
+//!
+//! * `decode` - Decode QR codes from raw frames with
+//! [`QrVerification::from_raw_frame()`](QrVerification#method.from_raw_frame).
+//! Doesn't depend on the `image` crate and compiles for
+//! `wasm32-unknown-unknown`.
+//!
+//! * `decode_image` - Decode QR codes from the image types of the `image`
+//! crate, implies `decode`. Enabled by default.
 
 #![cfg_attr(feature = "docs", feature(doc_cfg))]
 #![deny(
@@ -51,11 +69,15 @@ pub use error::{DecodingError, EncodingError};
 #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
 pub use image;
 pub use qrcode;
-#[cfg(feature = "decode_image")]
-#[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
+#[cfg(feature = "decode")]
+#[cfg_attr(feature = "docs", doc(cfg(decode)))]
 pub use rqrr;
+#[cfg(feature = "decode")]
+#[cfg_attr(feature = "docs", doc(cfg(decode)))]
+pub use types::PixelFormat;
 pub use types::{
-    QrVerification, SelfVerificationData, SelfVerificationNoMasterKey, VerificationData,
+    QrVerification, QrVerificationBuilder, SelfVerificationData, SelfVerificationNoMasterKey,
+    SvgOptions, VerificationData,
 };
 
 #[cfg(test)]
@@ -70,7 +92,9 @@ mod test {
 
     #[cfg(feature = "decode_image")]
     use crate::utils::decode_qr;
-    use crate::{DecodingError, QrVerification};
+    #[cfg(feature = "decode")]
+    use crate::PixelFormat;
+    use crate::{DecodingError, EncodingError, QrVerification, QrVerificationBuilder, SvgOptions};
 
     #[cfg(feature = "decode_image")]
     static VERIFICATION: &[u8; 4277] = include_bytes!("../data/verification.png");
@@ -160,6 +184,89 @@ mod test {
         assert_eq!(result, third_result);
     }
 
+    #[test]
+    #[cfg(feature = "decode_image")]
+    fn decode_raw_frame() {
+        let image = Cursor::new(VERIFICATION);
+        let image = image::load(image, ImageFormat::Png).unwrap();
+        let expected = QrVerification::from_image(image.clone()).unwrap();
+
+        let luma = image.to_luma8();
+        let (width, height) = luma.dimensions();
+        let luma = luma.into_raw();
+        let result =
+            QrVerification::from_raw_frame(width, height, PixelFormat::Luma8, &luma).unwrap();
+        assert_eq!(result, expected);
+
+        let chroma_len = 2 * ((width as usize + 1) / 2) * ((height as usize + 1) / 2);
+        let mut yuv = luma.clone();
+        yuv.extend(std::iter::repeat(128).take(chroma_len));
+
+        let result =
+            QrVerification::from_raw_frame(width, height, PixelFormat::Nv12, &yuv).unwrap();
+        assert_eq!(result, expected);
+        let result =
+            QrVerification::from_raw_frame(width, height, PixelFormat::Nv21, &yuv).unwrap();
+        assert_eq!(result, expected);
+
+        let rgba = image.to_rgba8().into_raw();
+        let result =
+            QrVerification::from_raw_frame(width, height, PixelFormat::Rgba8, &rgba).unwrap();
+        assert_eq!(result, expected);
+
+        let result = QrVerification::from_raw_frame(width, height, PixelFormat::Rgba8, &luma);
+        assert!(matches!(
+            result,
+            Err(DecodingError::FrameSize { expected: e, found: f }) if e == rgba.len() && f == luma.len()
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "decode")]
+    fn decode_raw_frame_without_image() {
+        use qrcode::types::Color;
+
+        let data = b"MATRIX\
+                   \x02\x02\x00\x07\
+                   FLOW_ID\
+                   AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+                   BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+                   SHARED_SECRET";
+
+        let verification = QrVerification::from_bytes(data).unwrap();
+        let code = verification.to_qr_code().unwrap();
+
+        // Render the code by hand, like a browser client would get it from a
+        // canvas, with 4 pixels per module and a quiet zone of 4 modules.
+        let scale = 4;
+        let width = (code.width() + 8) * scale;
+        let colors = code.to_colors();
+
+        let mut rgba = Vec::with_capacity(width * width * 4);
+
+        for y in 0..width {
+            for x in 0..width {
+                let (module_x, module_y) =
+                    ((x / scale).wrapping_sub(4), (y / scale).wrapping_sub(4));
+                let dark = module_x < code.width()
+                    && module_y < code.width()
+                    && colors[module_y * code.width() + module_x] == Color::Dark;
+                let value = if dark { 0 } else { 255 };
+
+                rgba.extend_from_slice(&[value, value, value, 255]);
+            }
+        }
+
+        let result =
+            QrVerification::from_raw_frame(width as u32, width as u32, PixelFormat::Rgba8, &rgba)
+                .unwrap();
+
+        assert_eq!(result, verification);
+
+        let result = QrVerification::from_raw_frame(u32::MAX, u32::MAX, PixelFormat::Rgba8, &rgba);
+        assert!(matches!(result, Err(DecodingError::InvalidDimensions { .. })));
+    }
+
     #[test]
     #[cfg(feature = "decode_image")]
     fn decode_invalid_qr() {
@@ -169,6 +276,116 @@ mod test {
         assert!(matches!(result, Err(DecodingError::Header)))
     }
 
+    #[test]
+    fn encode_svg() {
+        let data = b"MATRIX\
+                   \x02\x02\x00\x07\
+                   FLOW_ID\
+                   AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+                   BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+                   SHARED_SECRET";
+
+        let result = QrVerification::from_bytes(data).unwrap();
+
+        let svg = result.to_svg(&SvgOptions::default()).unwrap();
+        // A version 7 QR code is 45 modules wide, plus 4 modules of quiet zone
+        // on each side, times 8 units per module.
+        assert!(svg.contains(r#"viewBox="0 0 424 424""#));
+        assert!(svg.contains(r##"fill="#000000""##));
+        assert!(svg.contains(r##"fill="#ffffff""##));
+        // The top left finder pattern starts right after the quiet zone.
+        assert!(svg.contains("M32 32h56v8h-56z"));
+
+        let options = SvgOptions {
+            quiet_zone: 0,
+            module_size: 1,
+            dark_color: "rebeccapurple".to_owned(),
+            light_color: "transparent".to_owned(),
+        };
+        let svg = result.to_svg(&options).unwrap();
+
+        assert!(svg.contains(r#"viewBox="0 0 45 45""#));
+        assert!(svg.contains(r#"fill="rebeccapurple""#));
+        assert!(svg.contains(r#"fill="transparent""#));
+        assert!(svg.contains("M0 0h7v1h-7z"));
+
+        let options = SvgOptions {
+            dark_color: r#"red"/><script>alert(1)</script><rect fill=""#.to_owned(),
+            ..Default::default()
+        };
+        assert!(matches!(result.to_svg(&options), Err(EncodingError::InvalidSvgColor(_))));
+
+        let options = SvgOptions { module_size: u32::MAX, ..Default::default() };
+        assert!(matches!(result.to_svg(&options), Err(EncodingError::SvgTooLarge)));
+    }
+
+    #[test]
+    fn builder() {
+        let key = |byte: u8| base64::encode_config([byte; 32], base64::STANDARD_NO_PAD);
+        let secret = base64::encode_config([3u8; 16], base64::STANDARD_NO_PAD);
+
+        let verification = QrVerificationBuilder::self_verification("FLOW_ID")
+            .master_key(key(1))
+            .device_key(key(2))
+            .shared_secret(secret.clone())
+            .build()
+            .unwrap();
+
+        assert!(matches!(verification, QrVerification::SelfVerification(_)));
+        assert_eq!(verification.first_key(), key(1));
+        assert_eq!(verification.second_key(), key(2));
+
+        let bytes = verification.to_bytes().unwrap();
+        assert_eq!(QrVerification::from_bytes(bytes).unwrap(), verification);
+
+        let verification = QrVerificationBuilder::self_verification_no_master_key("FLOW_ID")
+            .master_key(key(1))
+            .device_key(key(2))
+            .shared_secret(secret.clone())
+            .build()
+            .unwrap();
+
+        // The own device key comes first if the master key isn't trusted.
+        assert_eq!(verification.first_key(), key(2));
+        assert_eq!(verification.second_key(), key(1));
+
+        let builder = QrVerificationBuilder::self_verification("FLOW_ID")
+            .master_key(key(1))
+            .shared_secret(secret);
+
+        assert!(matches!(builder.clone().build(), Err(EncodingError::MissingField("device key"))));
+        assert!(matches!(
+            builder.clone().device_key("AAAA").build(),
+            Err(EncodingError::KeyLength { name: "device key", length: 3 })
+        ));
+        assert!(matches!(
+            builder.clone().device_key("not base64!").build(),
+            Err(EncodingError::InvalidKey { name: "device key", .. })
+        ));
+        assert!(matches!(
+            builder.clone().device_key(key(2)).shared_secret("AAAA").build(),
+            Err(EncodingError::SharedSecret(3))
+        ));
+        assert!(matches!(
+            QrVerificationBuilder::self_verification("")
+                .master_key(key(1))
+                .device_key(key(2))
+                .shared_secret(base64::encode_config([3u8; 16], base64::STANDARD_NO_PAD))
+                .build(),
+            Err(EncodingError::MissingField("transaction id"))
+        ));
+
+        // The flow id is too long to fit into the QR code.
+        assert!(matches!(
+            QrVerificationBuilder::self_verification("A".repeat(200))
+                .master_key(key(1))
+                .device_key(key(2))
+                .shared_secret(base64::encode_config([3u8; 16], base64::STANDARD_NO_PAD))
+                .build(),
+            Err(EncodingError::Qr(_))
+        ));
+    }
+
     #[test]
     fn decode_invalid_header() {
         let data = b"NonMatrixCode";
@@ -24,11 +24,15 @@ use qrcode::QrCode;
 use ruma_identifiers::EventId;
 
 #[cfg(feature = "decode_image")]
-#[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
 use crate::utils::decode_qr;
+#[cfg(feature = "decode")]
+use crate::utils::decode_raw_frame;
 use crate::{
     error::{DecodingError, EncodingError},
-    utils::{base_64_encode, to_bytes, to_qr_code, HEADER, MAX_MODE, MIN_SECRET_LEN, VERSION},
+    utils::{
+        base64_decode, base_64_encode, to_bytes, to_qr_code, to_svg, HEADER, KEY_LEN, MAX_MODE,
+        MIN_SECRET_LEN, VERSION,
+    },
 };
 
 /// An enum representing the different modes a QR verification can be in.
@@ -44,6 +48,79 @@ pub enum QrVerification {
     SelfVerificationNoMasterKey(SelfVerificationNoMasterKey),
 }
 
+/// Options controlling how a QR code is rendered as an SVG image.
+///
+/// The default options render black modules on a white background with the
+/// quiet zone of four modules the QR code specification asks for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SvgOptions {
+    /// The width of the empty border around the QR code, in modules.
+    pub quiet_zone: u32,
+    /// The width and height of a single module, in SVG user units.
+    pub module_size: u32,
+    /// The color of the dark modules, any valid SVG color value can be used.
+    ///
+    /// Only ASCII letters, digits, whitespace and the characters `#(),.%-`
+    /// are allowed.
+    pub dark_color: String,
+    /// The color of the light modules and of the quiet zone, any valid SVG
+    /// color value can be used.
+    ///
+    /// The same characters as for the `dark_color` are allowed.
+    pub light_color: String,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        Self {
+            quiet_zone: 4,
+            module_size: 8,
+            dark_color: "#000000".to_owned(),
+            light_color: "#ffffff".to_owned(),
+        }
+    }
+}
+
+/// The pixel format of a raw camera frame.
+#[cfg(feature = "decode")]
+#[cfg_attr(feature = "docs", doc(cfg(decode)))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// One byte of luma per pixel.
+    Luma8,
+    /// A plane of one byte luma per pixel followed by a plane of interleaved
+    /// U and V values at half the horizontal and vertical resolution.
+    Nv12,
+    /// Like `Nv12`, but with the V and U values swapped, the default format
+    /// of the Android camera preview.
+    Nv21,
+    /// Four bytes per pixel, red, green, blue and alpha in that order.
+    ///
+    /// This is the format of the `data` of an `ImageData` object in the
+    /// browser.
+    Rgba8,
+}
+
+#[cfg(feature = "decode")]
+impl PixelFormat {
+    /// The number of bytes a frame with the given dimensions needs.
+    ///
+    /// Returns `None` if the size doesn't fit into a `usize`.
+    pub(crate) fn frame_size(self, width: usize, height: usize) -> Option<usize> {
+        let pixels = width.checked_mul(height)?;
+
+        match self {
+            PixelFormat::Luma8 => Some(pixels),
+            PixelFormat::Nv12 | PixelFormat::Nv21 => {
+                let chroma =
+                    (width / 2 + width % 2).checked_mul(height / 2 + height % 2)?.checked_mul(2)?;
+                pixels.checked_add(chroma)
+            }
+            PixelFormat::Rgba8 => pixels.checked_mul(4),
+        }
+    }
+}
+
 #[cfg(feature = "decode_image")]
 #[cfg_attr(feature = "docs", doc(cfg(decode_image)))]
 impl TryFrom<DynamicImage> for QrVerification {
@@ -133,6 +210,45 @@ impl QrVerification {
         Self::decode(image)
     }
 
+    /// Decode and parse a raw camera frame of a QR code into a
+    /// `QrVerification`
+    ///
+    /// Only the luma values of the frame are used for decoding, they are read
+    /// straight out of the frame, so no converted copy of the frame needs to
+    /// be made.
+    ///
+    /// # Arguments
+    ///
+    /// * `width` - The width of the frame in pixels.
+    ///
+    /// * `height` - The height of the frame in pixels.
+    ///
+    /// * `format` - The pixel format of the frame.
+    ///
+    /// * `data` - The pixel data of the frame, rows need to be tightly packed.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_qrcode::{QrVerification, PixelFormat, DecodingError};
+    /// # fn main() -> Result<(), DecodingError> {
+    /// # let (width, height) = (640, 480);
+    /// # let frame = vec![0u8; 640 * 480 * 3 / 2];
+    /// let result = QrVerification::from_raw_frame(width, height, PixelFormat::Nv21, &frame)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "decode")]
+    #[cfg_attr(feature = "docs", doc(cfg(decode)))]
+    pub fn from_raw_frame(
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+        data: &[u8],
+    ) -> Result<Self, DecodingError> {
+        let decoded = decode_raw_frame(width, height, format, data)?;
+        Self::decode_bytes(decoded)
+    }
+
     /// Parse the decoded payload of a QR code in byte slice form as a
     /// `QrVerification`
     ///
@@ -195,6 +311,43 @@ impl QrVerification {
         }
     }
 
+    /// Encode the `QrVerification` into a QR code rendered as an SVG image.
+    ///
+    /// The SVG image is scalable, it can be rendered at any size without
+    /// losing sharpness and doesn't require the `decode_image` feature.
+    ///
+    /// The encoding can fail for the same reasons as
+    /// [`to_qr_code()`](#method.to_qr_code) can, if one of the colors contains
+    /// characters that can't be part of an SVG color value, or if the size of
+    /// the image doesn't fit into an `u32`.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - The options controlling the size and the colors of the
+    /// rendered QR code.
+    ///
+    /// # Example
+    /// ```
+    /// # use matrix_qrcode::{QrVerification, SvgOptions, DecodingError};
+    /// # fn main() -> Result<(), DecodingError> {
+    /// let data = b"MATRIX\
+    ///              \x02\x02\x00\x07\
+    ///              FLOW_ID\
+    ///              AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA\
+    ///              BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB\
+    ///              SHARED_SECRET";
+    ///
+    /// let result = QrVerification::from_bytes(data)?;
+    ///
+    /// let options = SvgOptions { dark_color: "#1f2937".to_owned(), ..Default::default() };
+    /// let svg = result.to_svg(&options).unwrap();
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn to_svg(&self, options: &SvgOptions) -> Result<String, EncodingError> {
+        to_svg(&self.to_qr_code()?, options)
+    }
+
     /// Encode the `QrVerification` into a vector of bytes that can be encoded
     /// as a QR code.
     ///
@@ -670,3 +823,196 @@ impl From<SelfVerificationNoMasterKey> for QrVerification {
         Self::SelfVerificationNoMasterKey(data)
     }
 }
+
+/// The mode and the flow id of the QR code a [`QrVerificationBuilder`] builds.
+#[derive(Clone, Debug)]
+enum BuilderMode {
+    Verification(EventId),
+    SelfVerification(String),
+    SelfVerificationNoMasterKey(String),
+}
+
+/// A builder for a [`QrVerification`] that validates the data of the QR code.
+///
+/// The keys need to be unpadded base64 encoded 32 byte keys and the shared
+/// secret needs to be unpadded base64 of at least 8 random bytes. The data is
+/// checked when the QR code is built, together with the length of the flow id
+/// and the size of the encoded QR code, so that encoding the built
+/// `QrVerification` later on can't fail.
+///
+/// # Example
+/// ```
+/// # use std::convert::TryFrom;
+/// # use matrix_qrcode::{EncodingError, QrVerificationBuilder};
+/// # use ruma_identifiers::EventId;
+/// # fn main() -> Result<(), EncodingError> {
+/// # let event_id = EventId::try_from("$flow:example.org").unwrap();
+/// # let own_master_key = "6ls5KG1VkWDIaDEpNKYtjhV2z0R5MZ3AntJ9dCHiXKg";
+/// # let other_master_key = "h7CdMVGVHfqWIMr6wHjaxCrutRxdkF7pXxjzRZrzvHM";
+/// # let shared_secret = "8hZVPl6sIo6ABSZ5p1JbGA";
+/// let verification = QrVerificationBuilder::verification(event_id)
+///     .master_key(own_master_key)
+///     .other_master_key(other_master_key)
+///     .shared_secret(shared_secret)
+///     .build()?;
+///
+/// let svg = verification.to_svg(&Default::default())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct QrVerificationBuilder {
+    mode: BuilderMode,
+    master_key: Option<String>,
+    other_master_key: Option<String>,
+    device_key: Option<String>,
+    shared_secret: Option<String>,
+}
+
+impl QrVerificationBuilder {
+    fn new(mode: BuilderMode) -> Self {
+        Self {
+            mode,
+            master_key: None,
+            other_master_key: None,
+            device_key: None,
+            shared_secret: None,
+        }
+    }
+
+    /// Build a QR code to verify another user.
+    ///
+    /// Needs our own [`master_key()`](#method.master_key) and the
+    /// [`other_master_key()`](#method.other_master_key) of the other user.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The event id of the `m.key.verification.request` event
+    /// that initiated the verification flow.
+    pub fn verification(event_id: EventId) -> Self {
+        Self::new(BuilderMode::Verification(event_id))
+    }
+
+    /// Build a QR code to verify another device of our own user, where this
+    /// device trusts our cross signing master key.
+    ///
+    /// Needs our own [`master_key()`](#method.master_key) and the
+    /// [`device_key()`](#method.device_key) of the other device.
+    ///
+    /// # Arguments
+    ///
+    /// * `transaction_id` - The transaction id of the verification flow.
+    pub fn self_verification(transaction_id: impl Into<String>) -> Self {
+        Self::new(BuilderMode::SelfVerification(transaction_id.into()))
+    }
+
+    /// Build a QR code to verify another device of our own user, where this
+    /// device doesn't trust our cross signing master key yet.
+    ///
+    /// Needs the [`device_key()`](#method.device_key) of this device and the
+    /// [`master_key()`](#method.master_key) this device believes to be ours.
+    ///
+    /// # Arguments
+    ///
+    /// * `transaction_id` - The transaction id of the verification flow.
+    pub fn self_verification_no_master_key(transaction_id: impl Into<String>) -> Self {
+        Self::new(BuilderMode::SelfVerificationNoMasterKey(transaction_id.into()))
+    }
+
+    /// Set our own cross signing master key, encoded as unpadded base64.
+    pub fn master_key(mut self, key: impl Into<String>) -> Self {
+        self.master_key = Some(key.into());
+        self
+    }
+
+    /// Set the cross signing master key of the user we're verifying, encoded
+    /// as unpadded base64.
+    pub fn other_master_key(mut self, key: impl Into<String>) -> Self {
+        self.other_master_key = Some(key.into());
+        self
+    }
+
+    /// Set the Ed25519 device key, encoded as unpadded base64.
+    ///
+    /// This is the key of the other device if this device trusts the master
+    /// key, and the key of this device otherwise.
+    pub fn device_key(mut self, key: impl Into<String>) -> Self {
+        self.device_key = Some(key.into());
+        self
+    }
+
+    /// Set the shared secret, random bytes encoded as unpadded base64.
+    ///
+    /// The secret needs to be at least 8 bytes long.
+    pub fn shared_secret(mut self, secret: impl Into<String>) -> Self {
+        self.shared_secret = Some(secret.into());
+        self
+    }
+
+    /// Validate the data and build the `QrVerification`.
+    ///
+    /// Returns an error describing the first field that is missing or invalid,
+    /// or a [`EncodingError::Qr`] error if the data doesn't fit into a QR code.
+    pub fn build(self) -> Result<QrVerification, EncodingError> {
+        let shared_secret =
+            self.shared_secret.ok_or(EncodingError::MissingField("shared secret"))?;
+        let secret_len = base64_decode(&shared_secret)?.len();
+
+        if secret_len < MIN_SECRET_LEN {
+            return Err(EncodingError::SharedSecret(secret_len));
+        }
+
+        let verification: QrVerification = match self.mode {
+            BuilderMode::Verification(event_id) => VerificationData::new(
+                event_id,
+                validate_key("master key", self.master_key)?,
+                validate_key("other master key", self.other_master_key)?,
+                shared_secret,
+            )
+            .into(),
+            BuilderMode::SelfVerification(transaction_id) => SelfVerificationData::new(
+                validate_transaction_id(transaction_id)?,
+                validate_key("master key", self.master_key)?,
+                validate_key("device key", self.device_key)?,
+                shared_secret,
+            )
+            .into(),
+            BuilderMode::SelfVerificationNoMasterKey(transaction_id) => {
+                SelfVerificationNoMasterKey::new(
+                    validate_transaction_id(transaction_id)?,
+                    validate_key("device key", self.device_key)?,
+                    validate_key("master key", self.master_key)?,
+                    shared_secret,
+                )
+                .into()
+            }
+        };
+
+        // A long flow id or shared secret can make the data too big for the
+        // QR code.
+        verification.to_qr_code()?;
+
+        Ok(verification)
+    }
+}
+
+fn validate_key(name: &'static str, key: Option<String>) -> Result<String, EncodingError> {
+    let key = key.ok_or(EncodingError::MissingField(name))?;
+    let length =
+        base64_decode(&key).map_err(|source| EncodingError::InvalidKey { name, source })?.len();
+
+    if length != KEY_LEN {
+        Err(EncodingError::KeyLength { name, length })
+    } else {
+        Ok(key)
+    }
+}
+
+fn validate_transaction_id(transaction_id: String) -> Result<String, EncodingError> {
+    if transaction_id.is_empty() {
+        Err(EncodingError::MissingField("transaction id"))
+    } else {
+        u16::try_from(transaction_id.len())?;
+        Ok(transaction_id)
+    }
+}
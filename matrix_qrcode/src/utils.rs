@@ -12,21 +12,24 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::convert::TryInto;
+use std::{convert::TryInto, fmt::Write};
 
 use base64::{decode_config, encode_config, STANDARD_NO_PAD};
 #[cfg(feature = "decode_image")]
 use image::{ImageBuffer, Luma};
-use qrcode::{bits::Bits, EcLevel, QrCode, Version};
+use qrcode::{bits::Bits, types::Color, EcLevel, QrCode, Version};
 
-#[cfg(feature = "decode_image")]
+#[cfg(feature = "decode")]
 use crate::error::DecodingError;
-use crate::error::EncodingError;
+#[cfg(feature = "decode")]
+use crate::types::PixelFormat;
+use crate::{error::EncodingError, types::SvgOptions};
 
 pub(crate) const HEADER: &[u8] = b"MATRIX";
 pub(crate) const VERSION: u8 = 0x2;
 pub(crate) const MAX_MODE: u8 = 0x2;
 pub(crate) const MIN_SECRET_LEN: usize = 8;
+pub(crate) const KEY_LEN: usize = 32;
 
 pub(crate) fn base_64_encode(data: &[u8]) -> String {
     encode_config(data, STANDARD_NO_PAD)
@@ -90,9 +93,136 @@ pub(crate) fn to_qr_code(
     Ok(QrCode::with_bits(bits, EcLevel::L)?)
 }
 
+/// Check that a color only contains characters that can be part of a SVG
+/// color value, e.g. `#1f2937`, `rebeccapurple` or `rgb(31, 41, 55)`.
+///
+/// The color is put into an XML attribute, this makes sure that it can't
+/// break out of it.
+fn validate_svg_color(color: &str) -> Result<&str, EncodingError> {
+    let valid = !color.is_empty()
+        && color.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ' || "#(),.%-".contains(c));
+
+    if valid {
+        Ok(color)
+    } else {
+        Err(EncodingError::InvalidSvgColor(color.to_owned()))
+    }
+}
+
+pub(crate) fn to_svg(code: &QrCode, options: &SvgOptions) -> Result<String, EncodingError> {
+    let width = code.width();
+    let colors = code.to_colors();
+
+    let light = validate_svg_color(&options.light_color)?;
+    let dark = validate_svg_color(&options.dark_color)?;
+
+    // Every coordinate we write out is smaller than the size of the image, if
+    // the size fits into an `u32` the coordinates do as well.
+    let size = options
+        .quiet_zone
+        .checked_mul(2)
+        .and_then(|q| q.checked_add(width.try_into().ok()?))
+        .and_then(|w| w.checked_mul(options.module_size))
+        .ok_or(EncodingError::SvgTooLarge)?;
+
+    let mut svg = String::new();
+
+    // Writing into a `String` can't fail, so the results are ignored.
+    let _ = write!(
+        svg,
+        r#"<?xml version="1.0" standalone="yes"?><svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{size}" height="{size}" viewBox="0 0 {size} {size}" shape-rendering="crispEdges"><rect x="0" y="0" width="{size}" height="{size}" fill="{light}"/><path fill="{dark}" d=""#,
+        size = size,
+        light = light,
+        dark = dark,
+    );
+
+    // Draw a single rectangle for every horizontal run of dark modules, this
+    // keeps the output small compared to a rectangle per module.
+    for (y, row) in colors.chunks(width).enumerate() {
+        let mut x = 0;
+
+        while x < width {
+            if row[x] != Color::Dark {
+                x += 1;
+                continue;
+            }
+
+            let start = x;
+
+            while x < width && row[x] == Color::Dark {
+                x += 1;
+            }
+
+            let _ = write!(
+                svg,
+                "M{x} {y}h{w}v{h}h-{w}z",
+                x = (start as u32 + options.quiet_zone) * options.module_size,
+                y = (y as u32 + options.quiet_zone) * options.module_size,
+                w = (x - start) as u32 * options.module_size,
+                h = options.module_size,
+            );
+        }
+    }
+
+    svg.push_str(r#""/></svg>"#);
+
+    Ok(svg)
+}
+
 #[cfg(feature = "decode_image")]
 pub(crate) fn decode_qr(image: ImageBuffer<Luma<u8>, Vec<u8>>) -> Result<Vec<u8>, DecodingError> {
-    let mut image = rqrr::PreparedImage::prepare(image);
+    let (width, height) = image.dimensions();
+    decode_luma(width as usize, height as usize, |x, y| image.get_pixel(x as u32, y as u32)[0])
+}
+
+#[cfg(feature = "decode")]
+pub(crate) fn decode_raw_frame(
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    data: &[u8],
+) -> Result<Vec<u8>, DecodingError> {
+    let expected = format
+        .frame_size(width as usize, height as usize)
+        .ok_or(DecodingError::InvalidDimensions { width, height })?;
+    let width = width as usize;
+    let height = height as usize;
+
+    if data.len() < expected {
+        return Err(DecodingError::FrameSize { expected, found: data.len() });
+    }
+
+    // The luma values are read straight out of the frame, no intermediate
+    // grey scale copy of the frame is made.
+    match format {
+        // The semi-planar YUV formats start with a full resolution plane of
+        // luma values, the interleaved chroma plane that follows it isn't
+        // needed.
+        PixelFormat::Luma8 | PixelFormat::Nv12 | PixelFormat::Nv21 => {
+            decode_luma(width, height, |x, y| data[y * width + x])
+        }
+        PixelFormat::Rgba8 => decode_luma(width, height, |x, y| {
+            let pixel = &data[(y * width + x) * 4..];
+            rgb_to_luma(pixel[0], pixel[1], pixel[2])
+        }),
+    }
+}
+
+/// Convert a RGB pixel into a luma value using the same coefficients the
+/// `image` crate uses.
+#[cfg(feature = "decode")]
+fn rgb_to_luma(r: u8, g: u8, b: u8) -> u8 {
+    let luma = 2126 * u32::from(r) + 7152 * u32::from(g) + 722 * u32::from(b);
+    (luma / 10000) as u8
+}
+
+#[cfg(feature = "decode")]
+fn decode_luma(
+    width: usize,
+    height: usize,
+    luma: impl FnMut(usize, usize) -> u8,
+) -> Result<Vec<u8>, DecodingError> {
+    let mut image = rqrr::PreparedImage::prepare_from_greyscale(width, height, luma);
     let grids = image.detect_grids();
 
     let mut error = None;
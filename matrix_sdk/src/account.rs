@@ -0,0 +1,328 @@
+//! Management of the account of the logged in user.
+//!
+//! Third party identifiers, i.e. email addresses and phone numbers, are added
+//! to an account in multiple steps:
+//!
+//! 1. A validation token is requested with [`Account::request_email_token()`]
+//!    or [`Account::request_msisdn_token()`], the homeserver sends it to the
+//!    address.
+//! 2. The user clicks on the link in the email, or the token they received via
+//!    SMS is submitted with [`Account::submit_token()`].
+//! 3. The validated identifier is added to the account with
+//!    [`Account::add_3pid()`], this might require user-interactive
+//!    authentication.
+//! 4. Optionally, the identifier is bound to an identity server with
+//!    [`Account::bind_3pid()`] so other users can discover the account.
+
+use bytes::Bytes;
+use ruma::{
+    api::client::r0::{
+        account::{
+            add_3pid, bind_3pid, delete_3pid, request_3pid_management_token_via_msisdn,
+            unbind_3pid, IdentityServerInfo, ThirdPartyIdRemovalStatus,
+        },
+        contact::{get_contacts, request_contact_verification_token},
+        uiaa::AuthData,
+    },
+    assign,
+    thirdparty::Medium,
+    UInt,
+};
+use serde::Deserialize;
+
+use crate::{Client, HttpError, Result};
+
+/// A handle to manage the account of the logged in user.
+///
+/// Created with [`Client::account()`].
+#[derive(Clone, Debug)]
+pub struct Account {
+    pub(crate) client: Client,
+}
+
+/// The response of the endpoint a validation token was submitted to.
+#[derive(Deserialize)]
+struct SubmitTokenResponse {
+    success: bool,
+}
+
+impl Account {
+    /// Get the third party identifiers that are associated with the account.
+    pub async fn threepids(&self) -> Result<Vec<get_contacts::ThirdPartyIdentifier>> {
+        Ok(self.client.send(get_contacts::Request::new(), None).await?.threepids)
+    }
+
+    /// Ask the homeserver to send a validation token to the given email
+    /// address, so it can be added to the account.
+    ///
+    /// Returns the session id that is needed to add the email address once it
+    /// got validated.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_secret` - A secret generated by the client, it needs to be
+    /// used for all the steps of the validation.
+    ///
+    /// * `email` - The email address that should be validated.
+    ///
+    /// * `send_attempt` - The number of the attempt, the homeserver only sends
+    /// a new email if it's larger than the one of the previous request.
+    pub async fn request_email_token(
+        &self,
+        client_secret: &str,
+        email: &str,
+        send_attempt: UInt,
+    ) -> Result<request_contact_verification_token::Response> {
+        let request =
+            request_contact_verification_token::Request::new(client_secret, email, send_attempt);
+        self.client.send(request, None).await
+    }
+
+    /// Ask the homeserver to send a validation token to the given phone
+    /// number, so it can be added to the account.
+    ///
+    /// Returns the session id that is needed to add the phone number once it
+    /// got validated and the URL the token should be submitted to.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_secret` - A secret generated by the client, it needs to be
+    /// used for all the steps of the validation.
+    ///
+    /// * `country` - The two-letter uppercase ISO-3166-1 alpha-2 country code
+    /// the phone number should be parsed as if it were dialled from.
+    ///
+    /// * `phone_number` - The phone number that should be validated.
+    ///
+    /// * `send_attempt` - The number of the attempt, the homeserver only sends
+    /// a new SMS if it's larger than the one of the previous request.
+    pub async fn request_msisdn_token(
+        &self,
+        client_secret: &str,
+        country: &str,
+        phone_number: &str,
+        send_attempt: UInt,
+    ) -> Result<request_3pid_management_token_via_msisdn::Response> {
+        let request = request_3pid_management_token_via_msisdn::Request::new(
+            client_secret,
+            country,
+            phone_number,
+            send_attempt,
+        );
+        self.client.send(request, None).await
+    }
+
+    /// Submit the validation token the user received.
+    ///
+    /// Returns `false` if the token was rejected.
+    ///
+    /// # Arguments
+    ///
+    /// * `submit_url` - The URL the homeserver returned when the token was
+    /// requested.
+    ///
+    /// * `sid` - The session id the homeserver returned when the token was
+    /// requested.
+    ///
+    /// * `client_secret` - The secret that was used to request the token.
+    ///
+    /// * `token` - The token the user received.
+    pub async fn submit_token(
+        &self,
+        submit_url: &str,
+        sid: &str,
+        client_secret: &str,
+        token: &str,
+    ) -> Result<bool> {
+        let body = serde_json::json!({
+            "sid": sid,
+            "client_secret": client_secret,
+            "token": token,
+        });
+
+        let request = http::Request::builder()
+            .method(http::Method::POST)
+            .uri(submit_url)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Bytes::from(serde_json::to_vec(&body)?))
+            .map_err(HttpError::from)?;
+
+        let http_client = &self.client.http_client;
+        let response = http_client.inner.send_request(request, http_client.request_config).await?;
+
+        if !response.status().is_success() {
+            return Err(HttpError::Server(response.status()).into());
+        }
+
+        let response: SubmitTokenResponse = serde_json::from_slice(response.body())?;
+
+        Ok(response.success)
+    }
+
+    /// Add a validated third party identifier to the account.
+    ///
+    /// The homeserver will most likely require user-interactive
+    /// authentication, the first request fails with a `UiaaResponse` that can
+    /// be inspected with [`Error::uiaa_response()`], the request then needs to
+    /// be retried with the `AuthData` of the completed stage.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_secret` - The secret that was used to request the validation
+    /// token.
+    ///
+    /// * `sid` - The session id the homeserver returned when the validation
+    /// token was requested.
+    ///
+    /// * `auth_data` - The user-interactive authentication data.
+    ///
+    /// [`Error::uiaa_response()`]: crate::Error::uiaa_response
+    pub async fn add_3pid(
+        &self,
+        client_secret: &str,
+        sid: &str,
+        auth_data: Option<AuthData<'_>>,
+    ) -> Result<()> {
+        let request = assign!(add_3pid::Request::new(client_secret, sid), { auth: auth_data });
+        self.client.send(request, None).await?;
+
+        Ok(())
+    }
+
+    /// Bind a validated third party identifier to an identity server, other
+    /// users can then discover the account using the identifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `client_secret` - The secret that was used to request the validation
+    /// token.
+    ///
+    /// * `sid` - The session id the identity server returned when the
+    /// validation token was requested.
+    ///
+    /// * `id_server` - The hostname of the identity server.
+    ///
+    /// * `id_access_token` - An access token for the identity server.
+    pub async fn bind_3pid(
+        &self,
+        client_secret: &str,
+        sid: &str,
+        id_server: &str,
+        id_access_token: &str,
+    ) -> Result<()> {
+        let identity_server_info = IdentityServerInfo::new(id_server, id_access_token);
+        let request = bind_3pid::Request::new(client_secret, identity_server_info, sid);
+        self.client.send(request, None).await?;
+
+        Ok(())
+    }
+
+    /// Unbind a third party identifier from the identity server, it stays
+    /// associated with the account.
+    ///
+    /// Returns `true` if the identity server unbound the identifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `medium` - The medium of the identifier.
+    ///
+    /// * `address` - The identifier, e.g. the email address.
+    ///
+    /// * `id_server` - The identity server to unbind from, if `None` the
+    /// homeserver uses the one the identifier was bound with.
+    pub async fn unbind_3pid(
+        &self,
+        medium: Medium,
+        address: &str,
+        id_server: Option<&str>,
+    ) -> Result<bool> {
+        let request = assign!(unbind_3pid::Request::new(medium, address), { id_server });
+        let response = self.client.send(request, None).await?;
+
+        Ok(matches!(response.id_server_unbind_result, ThirdPartyIdRemovalStatus::Success))
+    }
+
+    /// Remove a third party identifier from the account, it's unbound from
+    /// the identity server as well.
+    ///
+    /// Returns `true` if the identity server unbound the identifier.
+    ///
+    /// # Arguments
+    ///
+    /// * `medium` - The medium of the identifier.
+    ///
+    /// * `address` - The identifier, e.g. the email address.
+    ///
+    /// * `id_server` - The identity server to unbind from, if `None` the
+    /// homeserver uses the one the identifier was bound with.
+    pub async fn delete_3pid(
+        &self,
+        medium: Medium,
+        address: &str,
+        id_server: Option<&str>,
+    ) -> Result<bool> {
+        let request = assign!(delete_3pid::Request::new(medium, address), { id_server });
+        let response = self.client.send(request, None).await?;
+
+        Ok(matches!(response.id_server_unbind_result, ThirdPartyIdRemovalStatus::Success))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mockito::{mock, Matcher};
+    use ruma::thirdparty::Medium;
+    use serde_json::json;
+
+    use crate::client::test::logged_in_client;
+
+    #[tokio::test]
+    async fn threepids() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/account/3pid")
+            .match_header("authorization", "Bearer 1234")
+            .with_status(200)
+            .with_body(
+                json!({
+                    "threepids": [{
+                        "medium": "email",
+                        "address": "example@localhost",
+                        "validated_at": 1535176800000u64,
+                        "added_at": 1535336848756u64,
+                    }]
+                })
+                .to_string(),
+            )
+            .create();
+
+        let threepids = client.account().threepids().await.unwrap();
+
+        assert_eq!(threepids.len(), 1);
+        assert_eq!(threepids[0].medium, Medium::Email);
+        assert_eq!(threepids[0].address, "example@localhost");
+    }
+
+    #[tokio::test]
+    async fn add_3pid_requires_uiaa() {
+        let client = logged_in_client().await;
+
+        let _m = mock("POST", "/_matrix/client/r0/account/3pid/add")
+            .match_body(Matcher::Json(json!({ "client_secret": "secret", "sid": "sid" })))
+            .with_status(401)
+            .with_body(
+                json!({
+                    "flows": [{ "stages": ["m.login.password"] }],
+                    "params": {},
+                    "session": "uiaa_session",
+                })
+                .to_string(),
+            )
+            .create();
+
+        let error = client.account().add_3pid("secret", "sid", None).await.unwrap_err();
+        let info = error.uiaa_response().expect("The request should require UIAA");
+
+        assert_eq!(info.session.as_deref(), Some("uiaa_session"));
+    }
+}
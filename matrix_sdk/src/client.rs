@@ -13,12 +13,6 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(feature = "encryption")]
-use std::{
-    collections::BTreeMap,
-    io::{Cursor, Write},
-    path::PathBuf,
-};
 #[cfg(feature = "sso_login")]
 use std::{
     collections::HashMap,
@@ -26,34 +20,55 @@ use std::{
     ops::Range,
 };
 use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
     fmt::{self, Debug},
     future::Future,
     io::Read,
     path::Path,
     result::Result as StdResult,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+};
+#[cfg(feature = "encryption")]
+use std::{
+    io::{Cursor, Write},
+    path::PathBuf,
 };
 
+use bytes::Bytes;
 use dashmap::DashMap;
-use futures_timer::Delay as sleep;
+use futures::{channel::mpsc, future::join_all, Stream};
 use http::HeaderValue;
 #[cfg(feature = "sso_login")]
 use http::Response;
 #[cfg(feature = "encryption")]
 use matrix_sdk_base::crypto::{
-    decrypt_key_export, encrypt_key_export, olm::InboundGroupSession, store::CryptoStoreError,
-    AttachmentDecryptor, OutgoingRequests, RoomMessageRequest, ToDeviceRequest,
+    decrypt_key_export, encrypt_key_export, olm::InboundGroupSession,
+    secret_storage::SecretStorageKey, store::CryptoStoreError, AttachmentDecryptor,
+    IntoCryptoStore, KeysUploadFailure, OutgoingRequests, RoomMessageRequest, ToDeviceRequest,
 };
 use matrix_sdk_base::{
-    deserialized_responses::SyncResponse,
+    deserialized_responses::{SyncResponse, UnreadNotificationsCount},
+    image_pack::{
+        ImagePack, ImagePackRooms, IMAGE_PACK_ROOMS_EVENT_TYPE, USER_IMAGE_PACK_EVENT_TYPE,
+    },
     media::{MediaEventContent, MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
-    BaseClient, BaseClientConfig, Session, Store,
+    BaseClient, BaseClientConfig, EventProcessor, IntoStateStore, Session, StateChange, Store,
 };
 use mime::{self, Mime};
 #[cfg(feature = "sso_login")]
 use rand::{thread_rng, Rng};
 use reqwest::header::InvalidHeaderValue;
-use ruma::{api::SendAccessToken, events::AnyMessageEventContent, identifiers::MxcUri};
+use ruma::{
+    api::SendAccessToken,
+    events::{AnyGlobalAccountDataEvent, AnyMessageEventContent, EventType},
+    identifiers::MxcUri,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value as JsonValue;
 #[cfg(feature = "sso_login")]
 use tokio::{net::TcpListener, sync::oneshot};
 #[cfg(feature = "sso_login")]
@@ -81,6 +96,137 @@ pub enum LoopCtrl {
     Break,
 }
 
+/// The state of the sync loop, see [`Client::sync_state_stream()`].
+#[derive(Debug, Clone)]
+pub enum SyncState {
+    /// The last sync request succeeded and the loop keeps on syncing.
+    Running,
+    /// The last sync request failed, the loop waits before retrying.
+    BackingOff {
+        /// The number of sync requests that failed in a row.
+        attempt: u32,
+    },
+    /// The sync loop stopped.
+    Terminated {
+        /// The error of the last sync request if the loop gave up retrying,
+        /// `None` if it was stopped by the callback or a [`SyncStopToken`].
+        error: Option<Arc<Error>>,
+    },
+}
+
+/// How long the sync loop waits before retrying a failed sync request.
+#[derive(Debug, Clone, Copy)]
+pub enum BackoffStrategy {
+    /// Wait the same time before every retry.
+    Constant(Duration),
+    /// Double the waiting time with every failed attempt, starting at
+    /// `initial`, up to `max`.
+    Exponential {
+        /// The time to wait after the first failure.
+        initial: Duration,
+        /// The maximal time to wait.
+        max: Duration,
+    },
+}
+
+impl BackoffStrategy {
+    /// The time to wait after the given number of failed attempts in a row.
+    fn delay(&self, attempt: u32) -> Duration {
+        match self {
+            BackoffStrategy::Constant(delay) => *delay,
+            BackoffStrategy::Exponential { initial, max } => {
+                let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+                initial.checked_mul(factor).map_or(*max, |d| d.min(*max))
+            }
+        }
+    }
+}
+
+/// A handle to stop a running sync loop.
+///
+/// The loop stops once the sync request that is in flight finishes.
+#[derive(Debug, Clone, Default)]
+pub struct SyncStopToken {
+    stopped: Arc<AtomicBool>,
+}
+
+impl SyncStopToken {
+    /// Create a new stop token.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop the sync loops that use this token.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Was the token used to stop the sync loops.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+}
+
+/// Configuration of the sync loop, see [`Client::sync_with_config()`].
+///
+/// The settings of the individual sync requests, e.g. the long-poll timeout
+/// and the presence, are set with the [`SyncSettings`].
+///
+/// By default failed sync requests are retried every second, forever.
+#[derive(Debug, Clone)]
+pub struct SyncLoopConfig {
+    pub(crate) backoff: BackoffStrategy,
+    pub(crate) max_attempts: Option<u32>,
+    pub(crate) stop_token: Option<SyncStopToken>,
+}
+
+impl Default for SyncLoopConfig {
+    fn default() -> Self {
+        Self {
+            backoff: BackoffStrategy::Constant(Duration::from_secs(1)),
+            max_attempts: None,
+            stop_token: None,
+        }
+    }
+}
+
+impl SyncLoopConfig {
+    /// Create a new default sync loop configuration.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set how long the loop waits before retrying a failed sync request.
+    ///
+    /// # Arguments
+    ///
+    /// * `backoff` - The backoff strategy.
+    pub fn backoff(mut self, backoff: BackoffStrategy) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Set how many sync requests can fail in a row before the loop gives up.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_attempts` - The maximal number of failed sync requests in a row.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Set a token that can be used to stop the sync loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `stop_token` - The token that stops the loop.
+    pub fn stop_token(mut self, stop_token: SyncStopToken) -> Self {
+        self.stop_token = Some(stop_token);
+        self
+    }
+}
+
 use matrix_sdk_common::{
     instant::{Duration, Instant},
     locks::{Mutex, RwLock},
@@ -100,27 +246,37 @@ use ruma::{
     api::{
         client::{
             r0::{
-                account::{register, whoami},
+                account::{register, request_openid_token, whoami},
+                alias::{create_alias, delete_alias, get_alias},
+                capabilities::{get_capabilities, Capabilities},
+                config::set_global_account_data,
                 device::{delete_devices, get_devices},
                 directory::{get_public_rooms, get_public_rooms_filtered},
                 filter::{create_filter::Request as FilterUploadRequest, FilterDefinition},
-                media::{create_content, get_content, get_content_thumbnail},
+                media::{create_content, get_content, get_content_thumbnail, get_media_config},
                 membership::{join_room_by_id, join_room_by_id_or_alias},
                 message::send_message_event,
                 profile::{get_avatar_url, get_display_name, set_avatar_url, set_display_name},
-                room::create_room,
+                push::{get_pushers, Pusher},
+                room::{create_room, Visibility},
                 session::{get_login_types, login, sso_login},
+                state::get_state_events,
                 sync::sync_events,
                 uiaa::AuthData,
             },
             unversioned::{discover_homeserver, get_supported_versions},
+            Error as ClientApiError,
         },
-        error::FromHttpResponseError,
-        OutgoingRequest,
+        error::{FromHttpResponseError, ServerError},
+        EndpointError, IncomingResponse, OutgoingRequest,
     },
     assign,
+    directory::Filter,
+    events::AnyToDeviceEvent,
     presence::PresenceState,
-    DeviceIdBox, RoomId, RoomIdOrAliasId, ServerName, UInt, UserId,
+    serde::Raw,
+    DeviceIdBox, EventId, RoomAliasId, RoomId, RoomIdOrAliasId, RoomVersionId, ServerName, UInt,
+    UserId,
 };
 
 #[cfg(feature = "encryption")]
@@ -132,8 +288,14 @@ use crate::{
 use crate::{
     error::HttpError,
     event_handler::Handler,
-    http_client::{client_with_config, HttpClient, HttpSend},
-    room, Error, EventHandler, Result,
+    http_client::{client_with_config, HttpClient, HttpSend, RuntimeHttpClient},
+    media_repo::{self, MediaConfig},
+    policy_list::PolicyRules,
+    pusher::{set_pusher, HttpPusherConfig, NotifyResponse},
+    room,
+    room_preview::{get_hierarchy, get_room_summary, RoomPreview},
+    runtime::{self, DefaultRuntime, Runtime},
+    Error, EventHandler, Result, SendDecision, SendInterceptor, SyncHook,
 };
 
 const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
@@ -142,6 +304,10 @@ const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
 const DEFAULT_UPLOAD_SPEED: u64 = 125_000;
 /// 5 min minimal upload request timeout, used to clamp the request timeout.
 const MIN_UPLOAD_REQUEST_TIMEOUT: Duration = Duration::from_secs(60 * 5);
+/// OpenID tokens that expire within this duration aren't handed out anymore.
+const OPENID_TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+/// The time a resolved room alias is cached for.
+const ROOM_ALIAS_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
 /// The range of ports the SSO server will try to bind to randomly
 #[cfg(feature = "sso_login")]
 const SSO_SERVER_BIND_RANGE: Range<u16> = 20000..30000;
@@ -157,7 +323,9 @@ pub struct Client {
     /// The URL of the homeserver to connect to.
     homeserver: Arc<RwLock<Url>>,
     /// The underlying HTTP client.
-    http_client: HttpClient,
+    pub(crate) http_client: HttpClient,
+    /// The runtime used to spawn tasks and to sleep.
+    pub(crate) runtime: Arc<dyn Runtime>,
     /// User session data.
     pub(crate) base_client: BaseClient,
     /// Locks making sure we only have one group session sharing request in
@@ -169,9 +337,32 @@ pub struct Client {
     key_claim_lock: Arc<Mutex<()>>,
     pub(crate) members_request_locks: Arc<DashMap<RoomId, Arc<Mutex<()>>>>,
     pub(crate) typing_notice_times: Arc<DashMap<RoomId, Instant>>,
+    /// The rooms that room aliases were resolved to, with the time they were
+    /// resolved.
+    room_alias_cache: Arc<DashMap<RoomAliasId, (RoomId, Instant)>>,
+    /// The cached response of the `/versions` endpoint of the homeserver.
+    server_versions: Arc<RwLock<Option<get_supported_versions::Response>>>,
+    /// The cached capabilities of the homeserver.
+    capabilities: Arc<RwLock<Option<Capabilities>>>,
+    /// The cached configuration of the media repository.
+    media_config: Arc<RwLock<Option<MediaConfig>>>,
+    /// The cached OpenID token of our own user and the time it was requested.
+    openid_token: Arc<Mutex<Option<(Instant, request_openid_token::Response)>>>,
     /// Any implementor of EventHandler will act as the callbacks for various
     /// events.
     event_handler: Arc<RwLock<Option<Handler>>>,
+    /// The hooks that run for every sync response.
+    sync_hooks: Arc<RwLock<Vec<Box<dyn SyncHook>>>>,
+    /// The interceptors that run for every outgoing message event.
+    send_interceptors: Arc<RwLock<Vec<Box<dyn SendInterceptor>>>>,
+    /// The listeners of the state of the sync loop.
+    sync_state_listeners: Arc<StdMutex<Vec<mpsc::UnboundedSender<SyncState>>>>,
+    /// The rules of the watched moderation policy lists.
+    pub(crate) policy_rules: PolicyRules,
+    /// The listeners of new invitations.
+    invite_listeners: Arc<StdMutex<Vec<mpsc::UnboundedSender<room::Invite>>>>,
+    /// The rooms whose invitation was already sent to the invite listeners.
+    notified_invites: Arc<StdMutex<BTreeSet<RoomId>>>,
     /// Whether the client should operate in application service style mode.
     /// This is low-level functionality. For an high-level API check the
     /// `matrix_sdk_appservice` crate.
@@ -210,6 +401,7 @@ pub struct ClientConfig {
     pub(crate) base_config: BaseClientConfig,
     pub(crate) request_config: RequestConfig,
     pub(crate) client: Option<Arc<dyn HttpSend>>,
+    pub(crate) runtime: Option<Arc<dyn Runtime>>,
     pub(crate) appservice_mode: bool,
 }
 
@@ -224,6 +416,7 @@ impl Debug for ClientConfig {
         res.field("user_agent", &self.user_agent)
             .field("disable_ssl_verification", &self.disable_ssl_verification)
             .field("request_config", &self.request_config)
+            .field("runtime", &self.runtime)
             .finish()
     }
 }
@@ -269,13 +462,23 @@ impl ClientConfig {
         Ok(self)
     }
 
-    ///// Set a custom implementation of a `StateStore`.
-    /////
-    ///// The state store should be opened before being set.
-    //pub fn state_store(mut self, store: Box<dyn StateStore>) -> Self {
-    //    self.base_config = self.base_config.state_store(store);
-    //    self
-    //}
+    /// Set a custom implementation of a `StateStore`.
+    ///
+    /// The state store should be opened before being set.
+    pub fn state_store(mut self, store: impl IntoStateStore) -> Self {
+        self.base_config = self.base_config.state_store(store);
+        self
+    }
+
+    /// Set a custom implementation of a `CryptoStore`.
+    ///
+    /// The crypto store should be opened before being set.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub fn crypto_store(mut self, store: impl IntoCryptoStore) -> Self {
+        self.base_config = self.base_config.crypto_store(store);
+        self
+    }
 
     /// Set the path for storage.
     ///
@@ -306,6 +509,15 @@ impl ClientConfig {
         self
     }
 
+    /// Add a processor that runs for every received timeline event.
+    ///
+    /// Processors run in the order they were added, after the event was
+    /// decrypted and before it's stored and passed to the event handler.
+    pub fn event_processor(mut self, processor: impl EventProcessor + 'static) -> Self {
+        self.base_config = self.base_config.event_processor(processor);
+        self
+    }
+
     /// Set the default timeout, fail and retry behavior for all HTTP requests.
     pub fn request_config(mut self, request_config: RequestConfig) -> Self {
         self.request_config = request_config;
@@ -326,6 +538,16 @@ impl ClientConfig {
         self
     }
 
+    /// Specify the async runtime the client should use to spawn tasks and to
+    /// wait between retries of requests and syncs.
+    ///
+    /// Any type that implements the `Runtime` trait can be used, the
+    /// [`DefaultRuntime`](crate::DefaultRuntime) is used if none is set.
+    pub fn runtime(mut self, runtime: Arc<dyn Runtime>) -> Self {
+        self.runtime = Some(runtime);
+        self
+    }
+
     /// Puts the client into application service mode
     ///
     /// This is low-level functionality. For an high-level API check the
@@ -344,6 +566,7 @@ pub struct SyncSettings<'a> {
     pub(crate) timeout: Option<Duration>,
     pub(crate) token: Option<String>,
     pub(crate) full_state: bool,
+    pub(crate) set_presence: PresenceState,
 }
 
 impl<'a> Default for SyncSettings<'a> {
@@ -353,6 +576,7 @@ impl<'a> Default for SyncSettings<'a> {
             timeout: Some(DEFAULT_SYNC_TIMEOUT),
             token: Default::default(),
             full_state: Default::default(),
+            set_presence: PresenceState::Online,
         }
     }
 }
@@ -407,6 +631,17 @@ impl<'a> SyncSettings<'a> {
         self.full_state = full_state;
         self
     }
+
+    /// Set the presence of the user while syncing.
+    ///
+    /// # Arguments
+    ///
+    /// * `presence` - The presence that the server should set for the user,
+    /// `Online` by default.
+    pub fn set_presence(mut self, presence: PresenceState) -> Self {
+        self.set_presence = presence;
+        self
+    }
 }
 
 /// Configuration for requests the `Client` makes.
@@ -532,11 +767,15 @@ impl Client {
     /// * `config` - Configuration for the client.
     pub fn new_with_config(homeserver_url: Url, config: ClientConfig) -> Result<Self> {
         let homeserver = Arc::new(RwLock::new(homeserver_url));
+        let runtime = config.runtime.clone().unwrap_or_else(|| Arc::new(DefaultRuntime));
 
         let client = if let Some(client) = config.client {
             client
         } else {
-            Arc::new(client_with_config(&config)?)
+            Arc::new(RuntimeHttpClient {
+                inner: client_with_config(&config)?,
+                runtime: runtime.clone(),
+            })
         };
 
         let base_client = BaseClient::new_with_config(config.base_config)?;
@@ -548,6 +787,7 @@ impl Client {
         Ok(Self {
             homeserver,
             http_client,
+            runtime,
             base_client,
             #[cfg(feature = "encryption")]
             group_session_locks: Arc::new(DashMap::new()),
@@ -555,7 +795,18 @@ impl Client {
             key_claim_lock: Arc::new(Mutex::new(())),
             members_request_locks: Arc::new(DashMap::new()),
             typing_notice_times: Arc::new(DashMap::new()),
+            room_alias_cache: Arc::new(DashMap::new()),
+            server_versions: Arc::new(RwLock::new(None)),
+            capabilities: Arc::new(RwLock::new(None)),
+            media_config: Arc::new(RwLock::new(None)),
+            openid_token: Arc::new(Mutex::new(None)),
             event_handler: Arc::new(RwLock::new(None)),
+            sync_hooks: Arc::new(RwLock::new(Vec::new())),
+            send_interceptors: Arc::new(RwLock::new(Vec::new())),
+            sync_state_listeners: Arc::new(StdMutex::new(Vec::new())),
+            invite_listeners: Arc::new(StdMutex::new(Vec::new())),
+            notified_invites: Arc::new(StdMutex::new(BTreeSet::new())),
+            policy_rules: PolicyRules::default(),
             appservice_mode: config.appservice_mode,
         })
     }
@@ -589,6 +840,10 @@ impl Client {
     /// given user and configuration. Follows homeserver discovery directions
     /// described [here](https://spec.matrix.org/unstable/client-server-api/#well-known-uri).
     ///
+    /// The advertised homeserver URL needs to be a valid `http` or `https` URL
+    /// and the homeserver needs to respond to a `/versions` request, otherwise
+    /// the discovery fails.
+    ///
     /// # Arguments
     ///
     /// * `user_id` - The id of the user whose homeserver the client should
@@ -603,9 +858,17 @@ impl Client {
         let mut client = Client::new_with_config(homeserver, config)?;
 
         let well_known = client.discover_homeserver().await?;
-        let well_known = Url::parse(well_known.homeserver.base_url.as_ref())?;
-        client.set_homeserver(well_known).await;
-        client.get_supported_versions().await?;
+        let base_url = well_known.homeserver.base_url;
+        let homeserver =
+            Url::parse(&base_url).map_err(|_| Error::InvalidHomeserverUrl(base_url.clone()))?;
+
+        if !matches!(homeserver.scheme(), "http" | "https") {
+            return Err(Error::InvalidHomeserverUrl(base_url));
+        }
+
+        client.set_homeserver(homeserver).await;
+        client.server_versions().await?;
+
         Ok(client)
     }
 
@@ -633,6 +896,9 @@ impl Client {
     pub async fn set_homeserver(&mut self, homeserver_url: Url) {
         let mut homeserver = self.homeserver.write().await;
         *homeserver = homeserver_url;
+        drop(homeserver);
+
+        self.reset_server_info().await;
     }
 
     async fn get_supported_versions(&self) -> Result<get_supported_versions::Response> {
@@ -643,6 +909,132 @@ impl Client {
         .await
     }
 
+    async fn supported_versions(&self) -> Result<get_supported_versions::Response> {
+        if let Some(versions) = self.server_versions.read().await.as_ref() {
+            return Ok(versions.clone());
+        }
+
+        let versions = self.get_supported_versions().await?;
+        *self.server_versions.write().await = Some(versions.clone());
+
+        Ok(versions)
+    }
+
+    /// Get the versions of the Matrix client-server specification the
+    /// homeserver supports.
+    ///
+    /// The response of the homeserver is cached, only the first call sends a
+    /// request.
+    pub async fn server_versions(&self) -> Result<Vec<String>> {
+        Ok(self.supported_versions().await?.versions)
+    }
+
+    /// Get the unstable features the homeserver advertises and whether they
+    /// are enabled.
+    ///
+    /// The response of the homeserver is cached, only the first call sends a
+    /// request.
+    pub async fn unstable_features(&self) -> Result<BTreeMap<String, bool>> {
+        Ok(self.supported_versions().await?.unstable_features)
+    }
+
+    /// Does the homeserver support the given version of the Matrix
+    /// client-server specification, e.g. `r0.6.0`.
+    pub async fn supports_version(&self, version: &str) -> Result<bool> {
+        Ok(self.supported_versions().await?.versions.iter().any(|v| v == version))
+    }
+
+    /// Get the capabilities of the homeserver.
+    ///
+    /// The response of the homeserver is cached, only the first call sends a
+    /// request. Homeservers that don't support the `/capabilities` endpoint
+    /// are treated as if they returned the default capabilities.
+    pub async fn capabilities(&self) -> Result<Capabilities> {
+        if let Some(capabilities) = self.capabilities.read().await.as_ref() {
+            return Ok(capabilities.clone());
+        }
+
+        let capabilities = match self.send(get_capabilities::Request::new(), None).await {
+            Ok(response) => response.capabilities,
+            Err(e) if is_unrecognized_error(&e) => Capabilities::new(),
+            Err(e) => return Err(e),
+        };
+
+        *self.capabilities.write().await = Some(capabilities.clone());
+
+        Ok(capabilities)
+    }
+
+    /// Forget the cached versions, capabilities and media configuration of the
+    /// homeserver.
+    ///
+    /// They are fetched again the next time they are needed, this is done
+    /// automatically if the homeserver URL changes.
+    pub async fn reset_server_info(&self) {
+        self.server_versions.write().await.take();
+        self.capabilities.write().await.take();
+        self.media_config.write().await.take();
+    }
+
+    /// Can our own user change their password.
+    pub async fn can_change_password(&self) -> Result<bool> {
+        Ok(self.capabilities().await?.change_password.enabled)
+    }
+
+    /// Can our own user change their display name.
+    pub async fn can_set_displayname(&self) -> Result<bool> {
+        self.custom_capability_enabled("m.set_displayname").await
+    }
+
+    /// Can our own user change their avatar.
+    pub async fn can_set_avatar_url(&self) -> Result<bool> {
+        self.custom_capability_enabled("m.set_avatar_url").await
+    }
+
+    async fn custom_capability_enabled(&self, capability: &str) -> Result<bool> {
+        Ok(self
+            .capabilities()
+            .await?
+            .custom_capabilities
+            .get(capability)
+            .and_then(|c| c.get("enabled"))
+            .and_then(|e| e.as_bool())
+            .unwrap_or(true))
+    }
+
+    /// Make sure the homeserver didn't disable the given custom capability.
+    ///
+    /// As the spec says, a capability is considered to be enabled if the
+    /// homeserver doesn't tell us otherwise, this includes failures to fetch
+    /// the capabilities.
+    async fn ensure_capability_enabled(&self, capability: &'static str) -> Result<()> {
+        let enabled = self.custom_capability_enabled(capability).await.unwrap_or_else(|e| {
+            warn!("Couldn't fetch the capabilities of the homeserver: {}", e);
+            true
+        });
+
+        if enabled {
+            Ok(())
+        } else {
+            Err(Error::CapabilityDisabled(capability))
+        }
+    }
+
+    /// Get the room versions the homeserver supports.
+    pub async fn supported_room_versions(&self) -> Result<Vec<RoomVersionId>> {
+        Ok(self.capabilities().await?.room_versions.available.keys().cloned().collect())
+    }
+
+    /// Get the room version the homeserver uses for new rooms.
+    pub async fn default_room_version(&self) -> Result<RoomVersionId> {
+        Ok(self.capabilities().await?.room_versions.default)
+    }
+
+    /// Does the homeserver support the given room version.
+    pub async fn is_room_version_supported(&self, version: &RoomVersionId) -> Result<bool> {
+        Ok(self.capabilities().await?.room_versions.available.contains_key(version))
+    }
+
     /// Process a [transaction] received from the homeserver
     ///
     /// # Arguments
@@ -659,14 +1051,169 @@ impl Client {
     ) -> Result<()> {
         let txn_id = incoming_transaction.txn_id.clone();
         let response = incoming_transaction.try_into_sync_response(txn_id)?;
-        let base_client = self.base_client.clone();
-        let sync_response = base_client.receive_sync_response(response).await?;
+        self.process_sync_response(response).await?;
+
+        Ok(())
+    }
+
+    /// Run the sync hooks and the event handler for the given sync response
+    /// and let the base client process it.
+    async fn process_sync_response(
+        &self,
+        mut response: sync_events::Response,
+    ) -> Result<SyncResponse> {
+        let hooks = self.sync_hooks.read().await;
+
+        for hook in hooks.iter() {
+            hook.pre_process(&mut response).await;
+        }
+
+        let sync_response = self.base_client.receive_sync_response(response).await?;
+
+        for hook in hooks.iter() {
+            hook.on_sync_response(&sync_response).await;
+        }
+
+        drop(hooks);
+
+        self.handle_sync_response(&sync_response).await;
+
+        Ok(sync_response)
+    }
+
+    /// Let the base client process the JSON body of a sync response, the
+    /// rooms of the response are deserialized one at a time.
+    async fn process_sync_response_json(&self, body: &[u8]) -> Result<SyncResponse> {
+        let sync_response = self.base_client.receive_sync_response_json(body).await?;
+
+        for hook in self.sync_hooks.read().await.iter() {
+            hook.on_sync_response(&sync_response).await;
+        }
+
+        self.handle_sync_response(&sync_response).await;
+
+        Ok(sync_response)
+    }
 
+    /// Run the event handler and notify the rest of the client about a
+    /// processed sync response.
+    async fn handle_sync_response(&self, sync_response: &SyncResponse) {
         if let Some(handler) = self.event_handler.read().await.as_ref() {
-            handler.handle_sync(&sync_response).await;
+            handler.handle_sync(sync_response).await;
         }
 
-        Ok(())
+        self.policy_rules.handle_sync(sync_response);
+        self.invalidate_room_aliases(sync_response);
+        self.notify_invites(sync_response);
+    }
+
+    /// Drop the cached room aliases of the rooms whose aliases changed in the
+    /// given sync response.
+    fn invalidate_room_aliases(&self, response: &SyncResponse) {
+        if self.room_alias_cache.is_empty() {
+            return;
+        }
+
+        for (room_id, room) in &response.rooms.join {
+            for event in &room.state.events {
+                self.invalidate_room_aliases_for_event(room_id, event);
+            }
+
+            for event in &room.timeline.events {
+                self.invalidate_room_aliases_for_event(room_id, &event.event);
+            }
+        }
+
+        for (room_id, room) in &response.rooms.leave {
+            for event in &room.state.events {
+                self.invalidate_room_aliases_for_event(room_id, event);
+            }
+
+            for event in &room.timeline.events {
+                self.invalidate_room_aliases_for_event(room_id, &event.event);
+            }
+        }
+    }
+
+    fn invalidate_room_aliases_for_event<T>(&self, room_id: &RoomId, event: &Raw<T>) {
+        #[derive(Deserialize)]
+        struct AliasEvent {
+            #[serde(rename = "type")]
+            event_type: String,
+            #[serde(default)]
+            content: JsonValue,
+        }
+
+        let event = match event.deserialize_as::<AliasEvent>() {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+
+        if event.event_type != EventType::RoomCanonicalAlias.as_str()
+            && event.event_type != EventType::RoomAliases.as_str()
+        {
+            return;
+        }
+
+        // The new aliases of the room might have pointed to a different room
+        // before.
+        let content = &event.content;
+        let new_aliases: Vec<&str> = content["alias"]
+            .as_str()
+            .into_iter()
+            .chain(
+                ["alt_aliases", "aliases"]
+                    .iter()
+                    .filter_map(|key| content[*key].as_array())
+                    .flatten()
+                    .filter_map(|alias| alias.as_str()),
+            )
+            .collect();
+
+        self.room_alias_cache
+            .retain(|alias, entry| &entry.0 != room_id && !new_aliases.contains(&alias.as_str()));
+    }
+
+    /// Send the new invitations of the given sync response to the invite
+    /// listeners.
+    ///
+    /// The profiles of the inviters are fetched in the background, so the
+    /// sync isn't held up by them.
+    fn notify_invites(&self, response: &SyncResponse) {
+        let mut notified = self.notified_invites.lock().unwrap();
+
+        // We might get invited again to rooms we joined or left.
+        for room_id in response.rooms.join.keys().chain(response.rooms.leave.keys()) {
+            notified.remove(room_id);
+        }
+
+        let rooms: Vec<_> = response
+            .rooms
+            .invite
+            .keys()
+            .filter_map(|room_id| self.get_invited_room(room_id))
+            .filter(|room| notified.insert(room.room_id().clone()))
+            .collect();
+
+        drop(notified);
+
+        if rooms.is_empty() || self.invite_listeners.lock().unwrap().is_empty() {
+            return;
+        }
+
+        let client = self.clone();
+
+        self.runtime.spawn(Box::pin(async move {
+            let invites = join_all(rooms.iter().map(|room| room.invite_details())).await;
+
+            for invite in invites {
+                client
+                    .invite_listeners
+                    .lock()
+                    .unwrap()
+                    .retain(|l| l.unbounded_send(invite.clone()).is_ok());
+            }
+        }));
     }
 
     /// Is the client logged in.
@@ -742,6 +1289,9 @@ impl Client {
     /// ```
     pub async fn set_display_name(&self, name: Option<&str>) -> Result<()> {
         let user_id = self.user_id().await.ok_or(Error::AuthenticationRequired)?;
+
+        self.ensure_capability_enabled("m.set_displayname").await?;
+
         let request = set_display_name::Request::new(&user_id, name);
         self.send(request, None).await?;
         Ok(())
@@ -811,14 +1361,9 @@ impl Client {
         // TODO: try to offer the avatar from cache, requires avatar cache
         if let Some(url) = self.avatar_url().await? {
             if let (Some(width), Some(height)) = (width, height) {
-                let request =
-                    get_content_thumbnail::Request::from_url(&url, width.into(), height.into())?;
-                let response = self.send(request, None).await?;
-                Ok(Some(response.file))
+                Ok(Some(self.download_thumbnail(&url, width.into(), height.into()).await?))
             } else {
-                let request = get_content::Request::from_url(&url)?;
-                let response = self.send(request, None).await?;
-                Ok(Some(response.file))
+                Ok(Some(self.download_media(&url).await?))
             }
         } else {
             Ok(None)
@@ -830,53 +1375,259 @@ impl Client {
         self.base_client.store()
     }
 
-    /// Sets the mxc avatar url of the client's owner. The avatar gets unset if
-    /// `url` is `None`.
-    pub async fn set_avatar_url(&self, url: Option<&MxcUri>) -> Result<()> {
-        let user_id = self.user_id().await.ok_or(Error::AuthenticationRequired)?;
-        let request = set_avatar_url::Request::new(&user_id, url);
-        self.send(request, None).await?;
-        Ok(())
+    /// Get a handle to manage the account of the logged in user.
+    pub fn account(&self) -> crate::account::Account {
+        crate::account::Account { client: self.clone() }
     }
 
-    /// Upload and set the owning client's avatar.
+    /// Get a handle to inspect the end-to-end encryption setup of the logged
+    /// in user.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub fn encryption(&self) -> crate::encryption_status::Encryption {
+        crate::encryption_status::Encryption { client: self.clone() }
+    }
+
+    /// Get a handle to edit the push rules of the logged in user.
+    pub fn notification_settings(&self) -> crate::notification_settings::NotificationSettings {
+        crate::notification_settings::NotificationSettings { client: self.clone() }
+    }
+
+    /// Get a handle to the moderation policy lists this client watches.
+    pub fn policy_lists(&self) -> crate::policy_list::PolicyLists {
+        crate::policy_list::PolicyLists { client: self.clone() }
+    }
+
+    /// Get a handle to call the admin API of Synapse.
     ///
-    /// The will upload the data produced by the reader to the homeserver's
-    /// content repository, and set the user's avatar to the mxc url for the
-    /// uploaded file.
+    /// The requests are authenticated as the logged in user, which needs to be
+    /// a server admin for most of them to succeed.
+    #[cfg(feature = "synapse-admin")]
+    #[cfg_attr(feature = "docs", doc(cfg(feature = "synapse-admin")))]
+    pub fn synapse_admin(&self) -> crate::synapse_admin::SynapseAdmin {
+        crate::synapse_admin::SynapseAdmin { client: self.clone() }
+    }
+
+    /// Get a stream of unread notification count changes.
     ///
-    /// This is a convenience method for calling [`upload()`](#method.upload),
-    /// followed by [`set_avatar_url()`](#method.set_avatar_url).
+    /// Every time the unread notification counts of a room change, e.g.
+    /// because a new message arrived or because we read the room on another
+    /// device, the room id and the new counts are sent out.
     ///
-    /// # Example
+    /// # Examples
     /// ```no_run
-    /// # use std::{path::Path, fs::File, io::Read};
-    /// # use futures::executor::block_on;
     /// # use matrix_sdk::Client;
+    /// # use futures::{executor::block_on, StreamExt};
     /// # use url::Url;
     /// # block_on(async {
-    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
     /// # let client = Client::new(homeserver).unwrap();
-    /// let path = Path::new("/home/example/selfie.jpg");
-    /// let mut image = File::open(&path).unwrap();
+    /// let mut counts = client.unread_notification_counts_stream();
     ///
-    /// client.upload_avatar(&mime::IMAGE_JPEG, &mut image).await.expect("Can't set avatar");
-    /// # })
+    /// while let Some((room_id, counts)) = counts.next().await {
+    ///     println!("{} has {} unread notifications", room_id, counts.notification_count);
+    /// }
+    /// # });
     /// ```
-    pub async fn upload_avatar<R: Read>(&self, content_type: &Mime, reader: &mut R) -> Result<()> {
-        let upload_response = self.upload(content_type, reader).await?;
-        self.set_avatar_url(Some(&upload_response.content_uri)).await?;
-        Ok(())
+    pub fn unread_notification_counts_stream(
+        &self,
+    ) -> impl Stream<Item = (RoomId, UnreadNotificationsCount)> {
+        self.base_client.unread_notification_counts_stream()
     }
 
-    /// Add `EventHandler` to `Client`.
+    /// Get a stream of the changes of the state of all rooms.
     ///
-    /// The methods of `EventHandler` are called when the respective
-    /// `RoomEvents` occur.
-    pub async fn set_event_handler(&self, handler: Box<dyn EventHandler>) {
-        let handler = Handler { inner: handler, client: self.clone() };
-        *self.event_handler.write().await = Some(handler);
-    }
+    /// Every state event a sync response changes is sent out together with
+    /// the state event it replaced, so bots and clients can react to any kind
+    /// of state, including custom state events, without registering an event
+    /// handler for every event type.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let mut changes = client.state_changes_stream();
+    ///
+    /// while let Some(change) = changes.next().await {
+    ///     println!("{} changed {} {}", change.room_id, change.event_type, change.state_key);
+    /// }
+    /// # });
+    /// ```
+    pub fn state_changes_stream(&self) -> impl Stream<Item = StateChange> {
+        self.base_client.state_changes_stream()
+    }
+
+    /// Get a stream of rooms that became encrypted.
+    ///
+    /// The room id is sent out once the `m.room.encryption` state event of a
+    /// room that wasn't encrypted before was received in a sync.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let mut rooms = client.room_encryption_stream();
+    ///
+    /// while let Some(room_id) = rooms.next().await {
+    ///     println!("{} is now encrypted", room_id);
+    /// }
+    /// # });
+    /// ```
+    pub fn room_encryption_stream(&self) -> impl Stream<Item = RoomId> {
+        self.base_client.room_encryption_stream()
+    }
+
+    /// Get a stream of to-device events with an unknown type.
+    ///
+    /// Custom to-device events and events that were added to the spec after
+    /// this version of the SDK are sent out untouched, already decrypted if
+    /// they were sent as encrypted to-device events. This allows building on
+    /// new MSCs without waiting for typed support in the SDK.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let mut events = client.unknown_to_device_events_stream();
+    ///
+    /// while let Some(event) = events.next().await {
+    ///     println!("Received a to-device event {}", event.json());
+    /// }
+    /// # });
+    /// ```
+    pub fn unknown_to_device_events_stream(&self) -> impl Stream<Item = Raw<AnyToDeviceEvent>> {
+        self.base_client.unknown_to_device_events_stream()
+    }
+
+    /// Sets the mxc avatar url of the client's owner. The avatar gets unset if
+    /// `url` is `None`.
+    pub async fn set_avatar_url(&self, url: Option<&MxcUri>) -> Result<()> {
+        let user_id = self.user_id().await.ok_or(Error::AuthenticationRequired)?;
+
+        self.ensure_capability_enabled("m.set_avatar_url").await?;
+
+        let request = set_avatar_url::Request::new(&user_id, url);
+        self.send(request, None).await?;
+        Ok(())
+    }
+
+    /// Upload and set the owning client's avatar.
+    ///
+    /// The will upload the data produced by the reader to the homeserver's
+    /// content repository, and set the user's avatar to the mxc url for the
+    /// uploaded file.
+    ///
+    /// This is a convenience method for calling [`upload()`](#method.upload),
+    /// followed by [`set_avatar_url()`](#method.set_avatar_url).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::{path::Path, fs::File, io::Read};
+    /// # use futures::executor::block_on;
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let path = Path::new("/home/example/selfie.jpg");
+    /// let mut image = File::open(&path).unwrap();
+    ///
+    /// client.upload_avatar(&mime::IMAGE_JPEG, &mut image).await.expect("Can't set avatar");
+    /// # })
+    /// ```
+    pub async fn upload_avatar<R: Read>(&self, content_type: &Mime, reader: &mut R) -> Result<()> {
+        let upload_response = self.upload(content_type, reader).await?;
+        self.set_avatar_url(Some(&upload_response.content_uri)).await?;
+        Ok(())
+    }
+
+    /// Add a hook that runs for every sync response the client receives.
+    ///
+    /// Hooks run in the order they were added, before the `EventHandler`.
+    pub async fn add_sync_hook(&self, hook: Box<dyn SyncHook>) {
+        self.sync_hooks.write().await.push(hook);
+    }
+
+    /// Add an interceptor that runs for every message event sent to a room.
+    ///
+    /// Interceptors run in the order they were added, before the event gets
+    /// encrypted.
+    pub async fn add_send_interceptor(&self, interceptor: Box<dyn SendInterceptor>) {
+        self.send_interceptors.write().await.push(interceptor);
+    }
+
+    /// Run the send interceptors for an event that is about to be sent to the
+    /// given room.
+    pub(crate) async fn intercept_send(
+        &self,
+        room: &room::Joined,
+        content: &mut AnyMessageEventContent,
+    ) -> Result<()> {
+        for interceptor in self.send_interceptors.read().await.iter() {
+            if let SendDecision::Veto(reason) = interceptor.before_send(room, content).await {
+                return Err(Error::SendVetoed(reason));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get a stream of the state changes of the sync loops this client runs.
+    ///
+    /// A state is only sent when it changes, e.g. [`SyncState::Running`] is
+    /// sent after the first successful sync and after a successful retry, not
+    /// after every sync.
+    ///
+    /// This can be used to display a connection indicator, a
+    /// [`SyncState::BackingOff`] state means that the homeserver can't be
+    /// reached.
+    pub fn sync_state_stream(&self) -> impl Stream<Item = SyncState> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.sync_state_listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    /// Get a stream of the invitations this client receives.
+    ///
+    /// Every invitation is sent once, even if it's part of later sync
+    /// responses as well. The profile of the inviter is fetched in the
+    /// background before the invitation is sent to the stream, so it can be
+    /// displayed right away.
+    pub fn invite_stream(&self) -> impl Stream<Item = room::Invite> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.invite_listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    fn notify_sync_state(&self, state: SyncState) {
+        self.sync_state_listeners
+            .lock()
+            .unwrap()
+            .retain(|l| l.unbounded_send(state.clone()).is_ok());
+    }
+
+    /// Add `EventHandler` to `Client`.
+    ///
+    /// The methods of `EventHandler` are called when the respective
+    /// `RoomEvents` occur.
+    pub async fn set_event_handler(&self, handler: Box<dyn EventHandler>) {
+        let handler = Handler { inner: handler, client: self.clone() };
+        *self.event_handler.write().await = Some(handler);
+    }
 
     /// Get all the rooms the client knows about.
     ///
@@ -1216,7 +1967,7 @@ impl Client {
             },
         );
 
-        tokio::spawn(server);
+        self.runtime.spawn(Box::pin(server));
 
         let sso_url = self.get_sso_login_url(redirect_url.as_str()).await.unwrap();
 
@@ -1339,6 +2090,15 @@ impl Client {
 
     /// Register a user to the server.
     ///
+    /// If the homeserver requires user-interactive authentication the request
+    /// fails with a `UiaaResponse`, the stages of the flows it lists can be
+    /// completed using [`RegistrationStage::to_auth_data`].
+    ///
+    /// If the client isn't logged in yet and the response contains an access
+    /// token, i.e. `inhibit_login` wasn't set, the client will be logged in
+    /// with the new account and the encryption keys of the new device will be
+    /// set up. A client that is already logged in keeps its session.
+    ///
     /// # Arguments
     ///
     /// * `registration` - The easiest way to create this request is using the
@@ -1368,6 +2128,38 @@ impl Client {
     /// client.register(request).await;
     /// # })
     /// ```
+    ///
+    /// Completing a `m.login.dummy` stage after the homeserver asked for it:
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, RegistrationStage};
+    /// # use matrix_sdk::api::r0::account::register::Request as RegistrationRequest;
+    /// # use matrix_sdk::assign;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # block_on(async {
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let request = assign!(RegistrationRequest::new(), {
+    ///     username: Some("user"),
+    ///     password: Some("password"),
+    /// });
+    ///
+    /// if let Err(e) = client.register(request).await {
+    ///     if let Some(info) = e.uiaa_response() {
+    ///         let request = assign!(RegistrationRequest::new(), {
+    ///             username: Some("user"),
+    ///             password: Some("password"),
+    ///             auth: Some(RegistrationStage::Dummy.to_auth_data(info.session.as_deref())),
+    ///         });
+    ///
+    ///         client.register(request).await.unwrap();
+    ///     }
+    /// }
+    /// # })
+    /// ```
+    ///
+    /// [`RegistrationStage::to_auth_data`]: crate::RegistrationStage::to_auth_data
     #[instrument(skip(registration))]
     pub async fn register(
         &self,
@@ -1382,7 +2174,93 @@ impl Client {
         };
 
         let request = registration.into();
-        self.send(request, config).await
+        let response = self.send(request, config).await?;
+
+        // Appservices register users on behalf of their bridge, the client
+        // stays logged in as the appservice. Other logged in clients keep
+        // their session as well.
+        if !self.appservice_mode && !self.logged_in().await {
+            self.receive_register_response(&response).await?;
+        }
+
+        Ok(response)
+    }
+
+    /// Register a guest account and log in with it.
+    ///
+    /// A client that is already logged in keeps its session.
+    ///
+    /// Guest accounts have limited access to the homeserver, they can be
+    /// upgraded to a full account later on using [`upgrade_guest`].
+    ///
+    /// # Arguments
+    ///
+    /// * `initial_device_display_name` - A public display name that will be
+    /// associated with the device of the guest.
+    ///
+    /// [`upgrade_guest`]: #method.upgrade_guest
+    pub async fn register_guest(
+        &self,
+        initial_device_display_name: Option<&str>,
+    ) -> Result<register::Response> {
+        info!("Registering a guest account on {}", self.homeserver().await);
+
+        let request = assign!(register::Request::new(), {
+            kind: register::RegistrationKind::Guest,
+            initial_device_display_name,
+        });
+
+        let response = self.send(request, None).await?;
+
+        if !self.logged_in().await {
+            self.receive_register_response(&response).await?;
+        }
+
+        Ok(response)
+    }
+
+    /// Upgrade the guest account the client is logged in with to a full user
+    /// account.
+    ///
+    /// The registration request is sent using the access token of the guest,
+    /// the homeserver will keep the user id of the guest if the request
+    /// doesn't ask for a different username. The user-interactive
+    /// authentication works like for [`register`].
+    ///
+    /// # Arguments
+    ///
+    /// * `registration` - The registration request for the full account, its
+    /// `kind` is set to `RegistrationKind::User`.
+    ///
+    /// [`register`]: #method.register
+    pub async fn upgrade_guest(
+        &self,
+        registration: impl Into<register::Request<'_>>,
+    ) -> Result<register::Response> {
+        info!("Upgrading a guest account on {}", self.homeserver().await);
+
+        let request = assign!(registration.into(), { kind: register::RegistrationKind::User });
+        let config = Some(self.http_client.request_config.force_auth());
+
+        let response = self.send(request, config).await?;
+        self.receive_register_response(&response).await?;
+
+        Ok(response)
+    }
+
+    async fn receive_register_response(&self, response: &register::Response) -> Result<()> {
+        if let (Some(access_token), Some(device_id)) = (&response.access_token, &response.device_id)
+        {
+            let session = Session {
+                access_token: access_token.clone(),
+                user_id: response.user_id.clone(),
+                device_id: device_id.clone(),
+            };
+
+            self.base_client.restore_login(session).await?;
+        }
+
+        Ok(())
     }
 
     /// Get or upload a sync filter.
@@ -1487,6 +2365,89 @@ impl Client {
         self.send(request, None).await
     }
 
+    /// Resolve a room alias to the `RoomId` of the room it points to.
+    ///
+    /// Resolved aliases are cached for an hour. Aliases that are created or
+    /// deleted using this client update the cache, alias changes of rooms we
+    /// are in drop the aliases of the room from it.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The alias that should be resolved, e.g.
+    /// `#name:example.com`.
+    pub async fn resolve_room_alias(&self, alias: &RoomAliasId) -> Result<RoomId> {
+        if let Some(entry) = self.room_alias_cache.get(alias) {
+            let (room_id, resolved_at) = entry.value();
+
+            if resolved_at.elapsed() < ROOM_ALIAS_CACHE_TTL {
+                return Ok(room_id.clone());
+            }
+        }
+
+        let request = get_alias::Request::new(alias);
+        let room_id = self.send(request, None).await?.room_id;
+        self.room_alias_cache.insert(alias.clone(), (room_id.clone(), Instant::now()));
+
+        Ok(room_id)
+    }
+
+    /// Create a new alias in the room directory of the homeserver that points
+    /// to the given room.
+    ///
+    /// This doesn't advertise the alias in the room, use
+    /// [`Joined::set_canonical_alias()`](room::Joined::set_canonical_alias) or
+    /// [`Joined::add_alt_alias()`](room::Joined::add_alt_alias) for that.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The new alias, the server name of it needs to be the one
+    /// of our homeserver.
+    ///
+    /// * `room_id` - The room the alias should point to.
+    pub async fn create_room_alias(&self, alias: &RoomAliasId, room_id: &RoomId) -> Result<()> {
+        let request = create_alias::Request::new(alias, room_id);
+        self.send(request, None).await?;
+        self.room_alias_cache.insert(alias.clone(), (room_id.clone(), Instant::now()));
+
+        Ok(())
+    }
+
+    /// Remove an alias from the room directory of the homeserver.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The alias that should be removed.
+    pub async fn delete_room_alias(&self, alias: &RoomAliasId) -> Result<()> {
+        let request = delete_alias::Request::new(alias);
+        let response = self.send(request, None).await;
+        self.room_alias_cache.remove(alias);
+
+        response.map(|_| ())
+    }
+
+    /// Ask to join a room.
+    ///
+    /// The room needs to have the `knock` join rule, a moderator of the room
+    /// can then accept or deny the request. Returns the `RoomId` of the room
+    /// that was knocked on.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id_or_alias` - The `RoomId` or `RoomAliasId` of the room.
+    ///
+    /// * `reason` - Optional reason why we want to join the room, it's shown
+    /// to the moderators of the room.
+    pub async fn knock(
+        &self,
+        room_id_or_alias: &RoomIdOrAliasId,
+        reason: Option<&str>,
+    ) -> Result<RoomId> {
+        let request = assign!(knock_room::Request::new(room_id_or_alias), { reason });
+        let response = self.send(request, None).await?;
+
+        Ok(response.room_id)
+    }
+
     /// Search the homeserver's directory of public rooms.
     ///
     /// Sends a request to "_matrix/client/r0/publicRooms", returns
@@ -1566,42 +2527,270 @@ impl Client {
         self.send(request, None).await
     }
 
-    /// Search the homeserver's directory of public rooms with a filter.
+    /// Get a direct message room with the given user, creating it if needed.
     ///
-    /// Sends a request to "_matrix/client/r0/publicRooms", returns
-    /// a `get_public_rooms_filtered::Response`.
+    /// An existing direct message room is reused if the user is still a
+    /// member of it, otherwise a new private room is created, the user is
+    /// invited to it and the room is added to the `m.direct` account data.
+    ///
+    /// Returns the id of the direct message room, a newly created room will
+    /// only be available as a joined room after the next sync.
     ///
     /// # Arguments
     ///
-    /// * `room_search` - The easiest way to create this request is using the
-    /// `get_public_rooms_filtered::Request` itself.
+    /// * `user_id` - The user that the direct message room should be shared
+    /// with.
     ///
     /// # Examples
     /// ```no_run
-    /// # use std::convert::TryFrom;
-    /// # use matrix_sdk::Client;
-    /// # use matrix_sdk::directory::{Filter, RoomNetwork};
-    /// # use matrix_sdk::api::r0::directory::get_public_rooms_filtered::Request as PublicRoomsFilterRequest;
-    /// # use matrix_sdk::assign;
+    /// # use matrix_sdk::{Client, identifiers::user_id};
     /// # use url::Url;
     /// # use futures::executor::block_on;
-    /// # let homeserver = Url::parse("http://example.com").unwrap();
     /// # block_on(async {
-    /// let mut client = Client::new(homeserver).unwrap();
-    ///
-    /// let generic_search_term = Some("matrix-rust-sdk");
-    /// let filter = assign!(Filter::new(), { generic_search_term });
-    /// let request = assign!(PublicRoomsFilterRequest::new(), { filter });
-    ///
-    /// client.public_rooms_filtered(request).await;
-    /// # })
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let room_id = client.create_dm(&user_id!("@alice:example.com")).await.unwrap();
+    /// # });
     /// ```
-    pub async fn public_rooms_filtered(
-        &self,
-        room_search: impl Into<get_public_rooms_filtered::Request<'_>>,
-    ) -> Result<get_public_rooms_filtered::Response> {
-        let request = room_search.into();
-        self.send(request, None).await
+    pub async fn create_dm(&self, user_id: &UserId) -> Result<RoomId> {
+        for room in self.joined_rooms() {
+            if room.direct_targets().contains(user_id)
+                && room.active_members_no_sync().await?.iter().any(|m| m.user_id() == user_id)
+            {
+                return Ok(room.room_id().clone());
+            }
+        }
+
+        let invite = [user_id.clone()];
+        let request = assign!(create_room::Request::new(), {
+            invite: &invite,
+            is_direct: true,
+            preset: Some(create_room::RoomPreset::TrustedPrivateChat),
+            visibility: Visibility::Private,
+        });
+
+        let room_id = self.create_room(request).await?.room_id;
+
+        self.update_direct_rooms(|direct| {
+            direct.entry(user_id.clone()).or_default().push(room_id.clone());
+        })
+        .await?;
+
+        Ok(room_id)
+    }
+
+    /// Modify the `m.direct` account data and upload it if it changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `update` - Closure modifying the map of users to the direct message
+    /// rooms that are shared with them.
+    pub(crate) async fn update_direct_rooms(
+        &self,
+        update: impl FnOnce(&mut BTreeMap<UserId, Vec<RoomId>>),
+    ) -> Result<()> {
+        let user_id = self.user_id().await.ok_or(Error::AuthenticationRequired)?;
+
+        let mut direct = if let Some(AnyGlobalAccountDataEvent::Direct(e)) = self
+            .store()
+            .get_account_data_event(EventType::Direct)
+            .await?
+            .and_then(|e| e.deserialize().ok())
+        {
+            (*e.content).clone()
+        } else {
+            BTreeMap::new()
+        };
+
+        let previous = direct.clone();
+        update(&mut direct);
+        direct.retain(|_, rooms| !rooms.is_empty());
+
+        if direct != previous {
+            let content = serde_json::value::to_raw_value(&direct)?;
+            let request = set_global_account_data::Request::new(
+                content,
+                EventType::Direct.as_str(),
+                &user_id,
+            );
+
+            self.send(request, None).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the content of a global account data event of a type that isn't
+    /// known to ruma.
+    ///
+    /// Returns `None` if the event doesn't exist or couldn't be deserialized.
+    async fn custom_account_data<T: DeserializeOwned>(
+        &self,
+        event_type: &str,
+    ) -> Result<Option<T>> {
+        #[derive(Deserialize)]
+        struct AccountDataEvent<T> {
+            content: T,
+        }
+
+        Ok(self
+            .store()
+            .get_account_data_event(EventType::Custom(event_type.to_owned()))
+            .await?
+            .and_then(|e| e.deserialize_as::<AccountDataEvent<T>>().ok())
+            .map(|e| e.content))
+    }
+
+    /// Get the image pack of our own user, stored in the
+    /// `im.ponies.user_emotes` account data.
+    pub async fn user_image_pack(&self) -> Result<Option<ImagePack>> {
+        self.custom_account_data(USER_IMAGE_PACK_EVENT_TYPE).await
+    }
+
+    /// Replace the image pack of our own user.
+    ///
+    /// # Arguments
+    ///
+    /// * `pack` - The new image pack of the user.
+    pub async fn set_user_image_pack(&self, pack: &ImagePack) -> Result<()> {
+        let user_id = self.user_id().await.ok_or(Error::AuthenticationRequired)?;
+        let content = serde_json::value::to_raw_value(pack)?;
+        let request =
+            set_global_account_data::Request::new(content, USER_IMAGE_PACK_EVENT_TYPE, &user_id);
+
+        self.send(request, None).await?;
+
+        Ok(())
+    }
+
+    /// Get the room image packs our own user enabled globally, stored in the
+    /// `im.ponies.emote_rooms` account data.
+    pub async fn image_pack_rooms(&self) -> Result<ImagePackRooms> {
+        Ok(self.custom_account_data(IMAGE_PACK_ROOMS_EVENT_TYPE).await?.unwrap_or_default())
+    }
+
+    /// Get all the image packs that are available to our own user in every
+    /// room, e.g. to populate a sticker picker.
+    ///
+    /// These are the image pack of the user followed by the room image packs
+    /// the user enabled globally. Packs of rooms we don't know about are
+    /// skipped, use [`Room::image_packs()`] to get the packs of a single room.
+    ///
+    /// [`Room::image_packs()`]: crate::BaseRoom::image_packs
+    pub async fn image_packs(&self) -> Result<Vec<ImagePack>> {
+        let mut packs: Vec<_> = self.user_image_pack().await?.into_iter().collect();
+
+        for (room_id, state_keys) in self.image_pack_rooms().await?.rooms {
+            if let Some(room) = self.store().get_room(&room_id) {
+                let mut room_packs = room.image_packs().await?;
+                packs.extend(state_keys.keys().filter_map(|key| room_packs.remove(key)));
+            }
+        }
+
+        Ok(packs)
+    }
+
+    /// Search the homeserver's directory of public rooms with a filter.
+    ///
+    /// Sends a request to "_matrix/client/r0/publicRooms", returns
+    /// a `get_public_rooms_filtered::Response`.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_search` - The easiest way to create this request is using the
+    /// `get_public_rooms_filtered::Request` itself.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use std::convert::TryFrom;
+    /// # use matrix_sdk::Client;
+    /// # use matrix_sdk::directory::{Filter, RoomNetwork};
+    /// # use matrix_sdk::api::r0::directory::get_public_rooms_filtered::Request as PublicRoomsFilterRequest;
+    /// # use matrix_sdk::assign;
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # block_on(async {
+    /// let mut client = Client::new(homeserver).unwrap();
+    ///
+    /// let generic_search_term = Some("matrix-rust-sdk");
+    /// let filter = assign!(Filter::new(), { generic_search_term });
+    /// let request = assign!(PublicRoomsFilterRequest::new(), { filter });
+    ///
+    /// client.public_rooms_filtered(request).await;
+    /// # })
+    /// ```
+    pub async fn public_rooms_filtered(
+        &self,
+        room_search: impl Into<get_public_rooms_filtered::Request<'_>>,
+    ) -> Result<get_public_rooms_filtered::Response> {
+        let request = room_search.into();
+        self.send(request, None).await
+    }
+
+    /// Get a preview of a room that we aren't a member of, e.g. to show its
+    /// name and topic before asking the user to join it.
+    ///
+    /// The room summary endpoint is used if the homeserver supports it,
+    /// otherwise the state of the room is requested, which only works for
+    /// rooms with a world-readable history. As a last resort the space
+    /// hierarchy and the room directory are searched for the room, those
+    /// don't tell if the room is encrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id_or_alias` - The `RoomId` or `RoomAliasId` of the room.
+    ///
+    /// * `via` - Servers that are in the room, used to find the room if our
+    /// homeserver isn't in it.
+    pub async fn preview_room(
+        &self,
+        room_id_or_alias: &RoomIdOrAliasId,
+        via: &[Box<ServerName>],
+    ) -> Result<RoomPreview> {
+        let request = assign!(get_room_summary::Request::new(room_id_or_alias), { via });
+
+        match self.send(request, None).await {
+            Ok(response) => return Ok(response.into()),
+            Err(e) => debug!("Couldn't get the summary of the room {}: {:?}", room_id_or_alias, e),
+        }
+
+        let (room_id, alias) = match RoomId::try_from(room_id_or_alias.clone()) {
+            Ok(room_id) => (room_id, None),
+            Err(alias) => (self.resolve_room_alias(&alias).await?, Some(alias)),
+        };
+
+        let request = get_state_events::Request::new(&room_id);
+
+        let error = match self.send(request, None).await {
+            Ok(response) => return Ok(RoomPreview::from_state(room_id, &response.room_state)),
+            Err(e) => e,
+        };
+
+        let request =
+            assign!(get_hierarchy::Request::new(&room_id), { limit: Some(UInt::from(1u32)) });
+
+        if let Ok(response) = self.send(request, None).await {
+            if let Some(room) = response.rooms.into_iter().find(|r| r.room_id == room_id) {
+                return Ok(room.into());
+            }
+        }
+
+        let filter = assign!(Filter::new(), {
+            generic_search_term: alias.as_ref().map(|a| a.as_str()),
+        });
+        let request = assign!(get_public_rooms_filtered::Request::new(), {
+            server: via.first().map(|s| &**s),
+            filter,
+        });
+
+        if let Ok(response) = self.send(request, None).await {
+            if let Some(room) = response.chunk.into_iter().find(|r| r.room_id == room_id) {
+                return Ok(room.into());
+            }
+        }
+
+        Err(error)
     }
 
     #[cfg(feature = "encryption")]
@@ -1656,8 +2845,27 @@ impl Client {
         content_type: &Mime,
         reader: &mut impl Read,
     ) -> Result<create_content::Response> {
+        let max_size = match self.media_config().await {
+            Ok(config) => config.upload_size,
+            Err(e) => {
+                warn!("Couldn't fetch the media configuration of the homeserver: {}", e);
+                None
+            }
+        };
+
         let mut data = Vec::new();
-        reader.read_to_end(&mut data)?;
+
+        if let Some(max_size) = max_size {
+            // Read at most one byte more than allowed, that's enough to know that
+            // the content is too large.
+            reader.take(u64::from(max_size) + 1).read_to_end(&mut data)?;
+
+            if data.len() as u64 > u64::from(max_size) {
+                return Err(Error::AttachmentTooLarge { max_size });
+            }
+        } else {
+            reader.read_to_end(&mut data)?;
+        }
 
         let timeout = std::cmp::max(
             Duration::from_secs(data.len() as u64 / DEFAULT_UPLOAD_SPEED),
@@ -1729,12 +2937,88 @@ impl Client {
         if let Some(room) = self.get_joined_room(room_id) {
             room.send(content, txn_id).await
         } else {
-            let content = content.into();
-            let txn_id = txn_id.unwrap_or_else(Uuid::new_v4).to_string();
-            let request = send_message_event::Request::new(room_id, &txn_id, &content);
+            self.send_message_event(room_id, &content.into(), txn_id).await
+        }
+    }
+
+    /// Send a message event with the given transaction id, generating one if
+    /// none is given.
+    ///
+    /// Sending an event with the same transaction id again is idempotent, if
+    /// the server already accepted the event, the event id is taken from the
+    /// store instead of sending the event again.
+    pub(crate) async fn send_message_event(
+        &self,
+        room_id: &RoomId,
+        content: &AnyMessageEventContent,
+        txn_id: Option<Uuid>,
+    ) -> Result<send_message_event::Response> {
+        let txn_id = txn_id.unwrap_or_else(Uuid::new_v4).to_string();
 
-            self.send(request, None).await
+        if let Some(event_id) = self.store().get_transaction_event_id(room_id, &txn_id).await? {
+            return Ok(send_message_event::Response::new(event_id));
         }
+
+        // Remember the transaction before the request goes out, if we're
+        // stopped before the response arrives the event can be sent again with
+        // the same transaction id.
+        self.store().save_transaction_event_id(room_id, &txn_id, None).await?;
+
+        let request = send_message_event::Request::new(room_id, &txn_id, content);
+        let response = match self.send(request, None).await {
+            Ok(r) => r,
+            Err(e) => {
+                if is_rejected_error(&e) {
+                    self.store().remove_transaction_id(room_id, &txn_id).await?;
+                }
+
+                return Err(e);
+            }
+        };
+
+        self.store().save_transaction_event_id(room_id, &txn_id, Some(&response.event_id)).await?;
+
+        Ok(response)
+    }
+
+    /// Get the transaction ids of the events we started to send to the given
+    /// room without getting a response from the server, e.g. because the
+    /// client was stopped in the meantime.
+    ///
+    /// Sending the events again with the same transaction ids makes sure they
+    /// don't end up in the room twice.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the events were sent to.
+    pub async fn pending_transaction_ids(&self, room_id: &RoomId) -> Result<Vec<Uuid>> {
+        Ok(self
+            .store()
+            .get_pending_transaction_ids(room_id)
+            .await?
+            .iter()
+            .filter_map(|t| Uuid::parse_str(t).ok())
+            .collect())
+    }
+
+    /// Get the event id of an event that we sent with the given transaction
+    /// id.
+    ///
+    /// Returns `None` if the server didn't accept the event yet or if the
+    /// remote echo of the event was already received in a sync, the event
+    /// contains the transaction id in that case.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event was sent to.
+    ///
+    /// * `txn_id` - The transaction id that was used to send the event.
+    pub async fn transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        txn_id: &Uuid,
+    ) -> Result<Option<EventId>> {
+        Ok(self.store().get_transaction_event_id(room_id, &txn_id.to_string()).await?)
     }
 
     /// Send an arbitrary request to the server, without updating client state.
@@ -1790,6 +3074,85 @@ impl Client {
         Ok(self.http_client.send(request, config).await?)
     }
 
+    /// Send a request to an endpoint that isn't available as a ruma request
+    /// type, e.g. an unstable endpoint of a MSC or an admin API of a specific
+    /// homeserver implementation.
+    ///
+    /// The request goes through the same HTTP client as every other request of
+    /// the `Client`, the access token is attached if the client is logged in
+    /// and failed requests are retried according to the `RequestConfig`.
+    ///
+    /// Returns the JSON body of the response, an empty body is returned as
+    /// `null`. If the homeserver responds with an error the Matrix error is
+    /// returned as a [`HttpError::ClientApi`] error.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The HTTP method of the request.
+    ///
+    /// * `path` - The path of the endpoint, relative to the homeserver URL,
+    /// e.g. `/_matrix/client/unstable/org.matrix.msc2432/rooms/!r:e.org/
+    /// aliases`.
+    ///
+    /// * `query` - The query parameters of the request.
+    ///
+    /// * `body` - The JSON body of the request, if any.
+    ///
+    /// * `config` - An optional request config that overrides the default
+    /// config of the client.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// use serde_json::json;
+    ///
+    /// let response = client
+    ///     .send_custom_request(
+    ///         http::Method::POST,
+    ///         "/_synapse/admin/v1/purge_history/!room:localhost",
+    ///         &[],
+    ///         Some(json!({ "delete_local_events": false })),
+    ///         None,
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    ///
+    /// println!("Started the purge {}", response["purge_id"]);
+    /// # })
+    /// ```
+    pub async fn send_custom_request(
+        &self,
+        method: http::Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<JsonValue>,
+        config: Option<RequestConfig>,
+    ) -> Result<JsonValue> {
+        let body = body.map(|b| serde_json::to_vec(&b)).transpose()?.map(Bytes::from);
+        let response = self.http_client.send_custom(method, path, query, body, config).await?;
+
+        if !response.status().is_success() {
+            let error = match ClientApiError::try_from_http_response(response) {
+                Ok(e) => ServerError::Known(e),
+                Err(e) => ServerError::Unknown(e),
+            };
+
+            return Err(HttpError::ClientApi(FromHttpResponseError::Http(error)).into());
+        }
+
+        if response.body().is_empty() {
+            Ok(JsonValue::Null)
+        } else {
+            Ok(serde_json::from_slice(response.body())?)
+        }
+    }
+
     #[cfg(feature = "encryption")]
     pub(crate) async fn send_to_device(
         &self,
@@ -1896,6 +3259,105 @@ impl Client {
         self.send(request, None).await
     }
 
+    /// Get the pushers of our own user.
+    pub async fn pushers(&self) -> Result<Vec<Pusher>> {
+        let request = get_pushers::Request::new();
+        Ok(self.send(request, None).await?.pushers)
+    }
+
+    /// Create or update a pusher that sends notifications to a HTTP push
+    /// gateway.
+    ///
+    /// A pusher with the same `app_id` and `pushkey` is updated.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration of the pusher.
+    pub async fn set_pusher(&self, config: &HttpPusherConfig) -> Result<()> {
+        let request = set_pusher::Request {
+            pushkey: &config.pushkey,
+            kind: Some("http"),
+            app_id: &config.app_id,
+            app_display_name: Some(&config.app_display_name),
+            device_display_name: Some(&config.device_display_name),
+            profile_tag: config.profile_tag.as_deref(),
+            lang: Some(&config.lang),
+            data: Some(config.data()),
+            append: config.append,
+        };
+        self.send(request, None).await?;
+
+        Ok(())
+    }
+
+    /// Delete a pusher.
+    ///
+    /// # Arguments
+    ///
+    /// * `pushkey` - The pushkey of the pusher.
+    ///
+    /// * `app_id` - The application id of the pusher.
+    pub async fn delete_pusher(&self, pushkey: &str, app_id: &str) -> Result<()> {
+        let request = set_pusher::Request {
+            pushkey,
+            kind: None,
+            app_id,
+            app_display_name: None,
+            device_display_name: None,
+            profile_tag: None,
+            lang: None,
+            data: None,
+            append: false,
+        };
+        self.send(request, None).await?;
+
+        Ok(())
+    }
+
+    /// Send a test notification to the push gateway of the given pusher.
+    ///
+    /// The notification is sent directly to the push gateway, not through the
+    /// homeserver. This allows applications to check their push setup, the
+    /// notification contains no event but otherwise looks like a notification
+    /// that the homeserver would send.
+    ///
+    /// Returns `false` if the push gateway rejected the pushkey, the pusher
+    /// should be deleted in that case.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The configuration of the pusher that should be tested.
+    pub async fn send_test_push(&self, config: &HttpPusherConfig) -> Result<bool> {
+        let body = serde_json::json!({
+            "notification": {
+                "prio": "low",
+                "devices": [{
+                    "app_id": config.app_id,
+                    "pushkey": config.pushkey,
+                    "data": config.data(),
+                }],
+            }
+        });
+
+        let request = http::Request::builder()
+            .method(http::Method::POST)
+            .uri(config.url.as_str())
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Bytes::from(serde_json::to_vec(&body)?))
+            .map_err(HttpError::from)?;
+
+        let response =
+            self.http_client.inner.send_request(request, self.http_client.request_config).await?;
+
+        if !response.status().is_success() {
+            return Err(HttpError::Server(response.status()).into());
+        }
+
+        let response: NotifyResponse = serde_json::from_slice(response.body())?;
+
+        Ok(!response.rejected.contains(&config.pushkey))
+    }
+
     /// Synchronize the client's state with the latest state on the server.
     ///
     /// **Note**: You should not use this method to repeatedly sync if
@@ -1914,7 +3376,7 @@ impl Client {
             filter: sync_settings.filter.as_ref(),
             since: sync_settings.token.as_deref(),
             full_state: sync_settings.full_state,
-            set_presence: &PresenceState::Online,
+            set_presence: &sync_settings.set_presence,
             timeout: sync_settings.timeout,
         });
 
@@ -1923,14 +3385,26 @@ impl Client {
                 + self.http_client.request_config.timeout,
         );
 
-        let response = self.send(request, Some(request_config)).await?;
-        let sync_response = self.base_client.receive_sync_response(response).await?;
+        // Sync hooks need the whole response, only deserialize the response
+        // one room at a time if there are none.
+        if self.base_client.persists_rooms_in_chunks() && self.sync_hooks.read().await.is_empty() {
+            let response = self.http_client.send_raw(request, Some(request_config)).await?;
 
-        if let Some(handler) = self.event_handler.read().await.as_ref() {
-            handler.handle_sync(&sync_response).await;
-        }
+            if response.status().is_success() {
+                return self.process_sync_response_json(response.body()).await;
+            }
 
-        Ok(sync_response)
+            // Let ruma convert the error response, so the same error as for
+            // a regular request is returned.
+            let response =
+                sync_events::Response::try_from_http_response(response).map_err(HttpError::from)?;
+
+            self.process_sync_response(response).await
+        } else {
+            let response = self.send(request, Some(request_config)).await?;
+
+            self.process_sync_response(response).await
+        }
     }
 
     /// Repeatedly call sync to synchronize the client state with the server.
@@ -2007,25 +3481,120 @@ impl Client {
     #[instrument(skip(callback))]
     pub async fn sync_with_callback<C>(
         &self,
-        mut sync_settings: SyncSettings<'_>,
+        sync_settings: SyncSettings<'_>,
         callback: impl Fn(SyncResponse) -> C,
     ) where
         C: Future<Output = LoopCtrl>,
     {
-        let mut last_sync_time: Option<Instant> = None;
-
-        if sync_settings.token.is_none() {
-            sync_settings.token = self.sync_token().await;
-        }
-
-        loop {
-            let response = self.sync_once(sync_settings.clone()).await;
+        // The default config retries forever, the loop only stops if the
+        // callback tells it to.
+        let _ = self.sync_with_config(sync_settings, SyncLoopConfig::default(), callback).await;
+    }
 
-            let response = match response {
-                Ok(r) => r,
+    /// Repeatedly call sync to synchronize the client state with the server,
+    /// with a configurable error handling.
+    ///
+    /// This behaves like [`sync_with_callback()`](#method.sync_with_callback),
+    /// but failed sync requests are retried according to the given
+    /// [`SyncLoopConfig`] and the loop can be stopped from the outside with a
+    /// [`SyncStopToken`]. The state of the loop is published on the
+    /// [`sync_state_stream()`](#method.sync_state_stream).
+    ///
+    /// Returns the error of the last sync request if the loop gave up
+    /// retrying, `Ok(())` if it was stopped by the callback or the stop token.
+    /// The error is shared with the [`SyncState::Terminated`] state.
+    ///
+    /// # Arguments
+    ///
+    /// * `sync_settings` - Settings for the sync call.
+    ///
+    /// * `config` - The configuration of the sync loop.
+    ///
+    /// * `callback` - A callback that will be called every time a successful
+    ///   response has been fetched from the server.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use matrix_sdk::{
+    /// #     BackoffStrategy, Client, LoopCtrl, SyncLoopConfig, SyncSettings, SyncStopToken,
+    /// # };
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let stop_token = SyncStopToken::new();
+    ///
+    /// let config = SyncLoopConfig::new()
+    ///     .backoff(BackoffStrategy::Exponential {
+    ///         initial: Duration::from_secs(1),
+    ///         max: Duration::from_secs(60),
+    ///     })
+    ///     .max_attempts(10)
+    ///     .stop_token(stop_token.clone());
+    ///
+    /// let result = client
+    ///     .sync_with_config(SyncSettings::new(), config, |_| async { LoopCtrl::Continue })
+    ///     .await;
+    /// # });
+    /// ```
+    #[instrument(skip(config, callback))]
+    pub async fn sync_with_config<C>(
+        &self,
+        mut sync_settings: SyncSettings<'_>,
+        config: SyncLoopConfig,
+        callback: impl Fn(SyncResponse) -> C,
+    ) -> StdResult<(), Arc<Error>>
+    where
+        C: Future<Output = LoopCtrl>,
+    {
+        let mut last_sync_time: Option<Instant> = None;
+        let mut attempt = 0;
+        let mut running = false;
+
+        if sync_settings.token.is_none() {
+            sync_settings.token = self.sync_token().await;
+        }
+
+        loop {
+            if config.stop_token.as_ref().map_or(false, |t| t.is_stopped()) {
+                self.notify_sync_state(SyncState::Terminated { error: None });
+                return Ok(());
+            }
+
+            let response = self.sync_once(sync_settings.clone()).await;
+
+            let response = match response {
+                Ok(r) => {
+                    attempt = 0;
+
+                    // Only notify about the state change, not about every
+                    // successful sync.
+                    if !running {
+                        running = true;
+                        self.notify_sync_state(SyncState::Running);
+                    }
+
+                    r
+                }
                 Err(e) => {
                     error!("Received an invalid response: {}", e);
-                    sleep::new(Duration::from_secs(1)).await;
+                    attempt += 1;
+                    running = false;
+
+                    if config.max_attempts.map_or(false, |max| attempt >= max) {
+                        let error = Arc::new(e);
+                        self.notify_sync_state(SyncState::Terminated {
+                            error: Some(error.clone()),
+                        });
+
+                        return Err(error);
+                    }
+
+                    self.notify_sync_state(SyncState::BackingOff { attempt });
+                    self.runtime.sleep(config.backoff.delay(attempt)).await;
                     continue;
                 }
             };
@@ -2092,7 +3661,8 @@ impl Client {
             }
 
             if callback(response).await == LoopCtrl::Break {
-                return;
+                self.notify_sync_state(SyncState::Terminated { error: None });
+                return Ok(());
             }
 
             let now = Instant::now();
@@ -2102,7 +3672,7 @@ impl Client {
             // the sync timeout.
             if let Some(t) = last_sync_time {
                 if now - t <= Duration::from_secs(1) {
-                    sleep::new(Duration::from_secs(1)).await;
+                    self.runtime.sleep(Duration::from_secs(1)).await;
                 }
             }
 
@@ -2135,6 +3705,36 @@ impl Client {
         Ok(())
     }
 
+    /// Query the devices of the given users right away if they are stale.
+    ///
+    /// Room keys aren't shared with users whose device lists are stale, this
+    /// makes sure that we don't need to wait for the next sync to update
+    /// them.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    #[instrument(skip(users))]
+    pub(crate) async fn update_stale_devices(
+        &self,
+        mut users: impl Iterator<Item = &UserId>,
+    ) -> Result<()> {
+        let olm = match self.base_client.olm_machine().await {
+            Some(o) => o,
+            None => return Ok(()),
+        };
+
+        if users.all(|u| olm.is_user_tracked_and_fresh(u)) {
+            return Ok(());
+        }
+
+        for r in olm.outgoing_requests().await? {
+            if let OutgoingRequests::KeysQuery(request) = r.request() {
+                self.keys_query(r.request_id(), request.device_keys.clone()).await?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Upload the E2E encryption keys.
     ///
     /// This uploads the long lived device keys as well as the required amount
@@ -2158,7 +3758,37 @@ impl Client {
             request.one_time_keys.as_ref().map_or(0, |k| k.len())
         );
 
-        let response = self.send(request.clone(), None).await?;
+        // The status code tells us why the upload failed, proxies in front of
+        // the homeserver might respond without a Matrix error in the body.
+        let (status_code, response) = match self.http_client.send_raw(request.clone(), None).await {
+            Ok(response) => {
+                let status_code = response.status();
+                let response =
+                    <upload_keys::Response as IncomingResponse>::try_from_http_response(response)
+                        .map_err(|e| Error::from(HttpError::from(e)));
+
+                (Some(status_code), response)
+            }
+            Err(HttpError::Server(status_code)) => {
+                (Some(status_code), Err(HttpError::Server(status_code).into()))
+            }
+            Err(e) => (None, Err(e.into())),
+        };
+
+        let response = match response {
+            Ok(r) => r,
+            Err(e) => {
+                if let Some(olm) = self.base_client.olm_machine().await {
+                    let failure = status_code.map_or(KeysUploadFailure::Other, keys_upload_failure);
+
+                    if let Err(e) = olm.mark_keys_upload_as_failed(request_id, failure).await {
+                        warn!("Error while marking the keys upload as failed {:?}", e);
+                    }
+                }
+
+                return Err(e);
+            }
+        };
         self.base_client.mark_request_as_sent(request_id, &response).await?;
 
         Ok(response)
@@ -2316,7 +3946,7 @@ impl Client {
     pub async fn bootstrap_cross_signing(&self, auth_data: Option<AuthData<'_>>) -> Result<()> {
         let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
 
-        let (request, signature_request) = olm.bootstrap_cross_signing(false).await?;
+        let (request_id, request, signature_request) = olm.bootstrap_cross_signing(false).await?;
 
         let request = assign!(UploadSigningKeysRequest::new(), {
             auth: auth_data,
@@ -2325,12 +3955,68 @@ impl Client {
             user_signing_key: request.user_signing_key,
         });
 
-        self.send(request, None).await?;
+        let response = self.send(request, None).await?;
+        self.base_client.mark_request_as_sent(&request_id, &response).await?;
         self.send(signature_request, None).await?;
 
         Ok(())
     }
 
+    /// Replace our cross signing identity with a newly created one.
+    ///
+    /// **Warning**: This will reset the trust between all the devices and
+    /// users that were verified using the old identity.
+    ///
+    /// This uploads the public keys of the new identity and the signature of
+    /// this device. If a secret storage key is given, the private keys of the
+    /// new identity will replace the old ones in secret storage.
+    ///
+    /// # Arguments
+    ///
+    /// * `auth_data` - This request requires user interactive auth, the first
+    /// request needs to set this to `None` and will always fail with an
+    /// `UiaaResponse`. The response will contain information for the
+    /// interactive auth and the same request needs to be made but this time
+    /// with some `auth_data` provided, see [`bootstrap_cross_signing`].
+    ///
+    /// * `secret_storage_key` - The secret storage key that should be used to
+    /// store the new private keys.
+    ///
+    /// [`bootstrap_cross_signing`]: #method.bootstrap_cross_signing
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn reset_cross_signing(
+        &self,
+        auth_data: Option<AuthData<'_>>,
+        secret_storage_key: Option<&SecretStorageKey>,
+    ) -> Result<()> {
+        let olm = self.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        let user_id = self.user_id().await.ok_or(Error::AuthenticationRequired)?;
+
+        let reset = olm.reset_cross_signing(secret_storage_key).await?;
+        let request = reset.upload_signing_keys_request;
+
+        let request = assign!(UploadSigningKeysRequest::new(), {
+            auth: auth_data,
+            master_key: request.master_key,
+            self_signing_key: request.self_signing_key,
+            user_signing_key: request.user_signing_key,
+        });
+
+        let response = self.send(request, None).await?;
+        self.base_client.mark_request_as_sent(&reset.request_id, &response).await?;
+        self.send(reset.upload_signatures_request, None).await?;
+
+        for (secret_name, content) in reset.secret_storage_content {
+            let content = serde_json::value::to_raw_value(&content)?;
+            let request = set_global_account_data::Request::new(content, &secret_name, &user_id);
+
+            self.send(request, None).await?;
+        }
+
+        Ok(())
+    }
+
     /// Get a map holding all the devices of an user.
     ///
     /// This will always return an empty map if the client hasn't been logged
@@ -2387,7 +4073,8 @@ impl Client {
     ///
     /// # Panics
     ///
-    /// This method will panic if it isn't run on a Tokio runtime.
+    /// This method will panic if the [`DefaultRuntime`](crate::DefaultRuntime)
+    /// is used and it isn't run on a Tokio runtime.
     ///
     /// This method will panic if it can't get enough randomness from the OS to
     /// encrypt the exported keys securely.
@@ -2443,8 +4130,7 @@ impl Client {
             Ok(())
         };
 
-        let task = tokio::task::spawn_blocking(encrypt);
-        task.await.expect("Task join error")
+        runtime::run_blocking(&*self.runtime, encrypt).await
     }
 
     /// Import E2EE keys from the given file path.
@@ -2462,7 +4148,8 @@ impl Client {
     ///
     /// # Panics
     ///
-    /// This method will panic if it isn't run on a Tokio runtime.
+    /// This method will panic if the [`DefaultRuntime`](crate::DefaultRuntime)
+    /// is used and it isn't run on a Tokio runtime.
     ///
     /// ```no_run
     /// # use std::{path::PathBuf, time::Duration};
@@ -2494,11 +4181,12 @@ impl Client {
             decrypt_key_export(file, &passphrase)
         };
 
-        let task = tokio::task::spawn_blocking(decrypt);
         // TODO remove this unwrap.
-        let import = task.await.expect("Task join error").unwrap();
+        let import = runtime::run_blocking(&*self.runtime, decrypt).await.unwrap();
 
-        Ok(olm.import_keys(import, |_, _| {}).await?)
+        let result = olm.import_keys(import, |_| {}).await?;
+
+        Ok((result.imported_count, result.total_count))
     }
 
     /// Get a media file's content.
@@ -2527,8 +4215,7 @@ impl Client {
         } else {
             let content: Vec<u8> = match &request.media_type {
                 MediaType::Encrypted(file) => {
-                    let content: Vec<u8> =
-                        self.send(get_content::Request::from_url(&file.url)?, None).await?.file;
+                    let content: Vec<u8> = self.download_media(&file.url).await?;
 
                     #[cfg(feature = "encryption")]
                     let content = {
@@ -2546,14 +4233,9 @@ impl Client {
                 }
                 MediaType::Uri(uri) => {
                     if let MediaFormat::Thumbnail(size) = &request.format {
-                        self.send(
-                            get_content_thumbnail::Request::from_url(uri, size.width, size.height)?,
-                            None,
-                        )
-                        .await?
-                        .file
+                        self.download_thumbnail(uri, size.width, size.height).await?
                     } else {
-                        self.send(get_content::Request::from_url(uri)?, None).await?.file
+                        self.download_media(uri).await?
                     }
                 }
             };
@@ -2566,6 +4248,89 @@ impl Client {
         }
     }
 
+    /// Should the authenticated media endpoints be used, the homeserver needs
+    /// to advertise support for them.
+    async fn use_authenticated_media(&self) -> bool {
+        match self.supported_versions().await {
+            Ok(versions) => {
+                versions.versions.iter().any(|v| v == "v1.11")
+                    || versions
+                        .unstable_features
+                        .get("org.matrix.msc3916.stable")
+                        .copied()
+                        .unwrap_or(false)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Download the content of the given media file.
+    ///
+    /// The authenticated media endpoint is used if the homeserver supports it,
+    /// the legacy endpoint otherwise.
+    pub(crate) async fn download_media(&self, uri: &MxcUri) -> Result<Vec<u8>> {
+        if self.use_authenticated_media().await {
+            let (server_name, media_id) = uri.parts()?;
+            let request = media_repo::get_content::Request { server_name, media_id };
+
+            match self.send(request, None).await {
+                Ok(response) => return Ok(response.file),
+                Err(e) if is_unrecognized_error(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(self.send(get_content::Request::from_url(uri)?, None).await?.file)
+    }
+
+    /// Download a thumbnail of the given media file.
+    ///
+    /// The authenticated media endpoint is used if the homeserver supports it,
+    /// the legacy endpoint otherwise.
+    pub(crate) async fn download_thumbnail(
+        &self,
+        uri: &MxcUri,
+        width: UInt,
+        height: UInt,
+    ) -> Result<Vec<u8>> {
+        if self.use_authenticated_media().await {
+            let (server_name, media_id) = uri.parts()?;
+            let request =
+                media_repo::get_content_thumbnail::Request { server_name, media_id, width, height };
+
+            match self.send(request, None).await {
+                Ok(response) => return Ok(response.file),
+                Err(e) if is_unrecognized_error(&e) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(self
+            .send(get_content_thumbnail::Request::from_url(uri, width, height)?, None)
+            .await?
+            .file)
+    }
+
+    /// Get the configuration of the media repository of the homeserver.
+    ///
+    /// The configuration is cached, only the first call sends a request.
+    pub async fn media_config(&self) -> Result<MediaConfig> {
+        if let Some(config) = self.media_config.read().await.as_ref() {
+            return Ok(config.clone());
+        }
+
+        let upload_size = if self.use_authenticated_media().await {
+            self.send(media_repo::get_media_config::Request::new(), None).await?.upload_size
+        } else {
+            self.send(get_media_config::Request::new(), None).await?.upload_size
+        };
+
+        let config = MediaConfig { upload_size };
+        *self.media_config.write().await = Some(config.clone());
+
+        Ok(config)
+    }
+
     /// Remove a media file's content from the store.
     ///
     /// # Arguments
@@ -2704,10 +4469,105 @@ impl Client {
         let request = whoami::Request::new();
         self.send(request, None).await
     }
+
+    /// Get an OpenID token of our own user.
+    ///
+    /// The token proves the identity of our own user to third parties, e.g.
+    /// widgets, integration managers or identity servers, they can verify it
+    /// with the homeserver.
+    ///
+    /// The token is cached and reused until it's about to expire.
+    pub async fn get_openid_token(&self) -> Result<request_openid_token::Response> {
+        let mut cached = self.openid_token.lock().await;
+
+        if let Some((requested_at, token)) = cached.as_ref() {
+            if requested_at.elapsed() + OPENID_TOKEN_EXPIRY_MARGIN < token.expires_in {
+                return Ok(token.clone());
+            }
+        }
+
+        let user_id = self.user_id().await.ok_or(Error::AuthenticationRequired)?;
+        let requested_at = Instant::now();
+        let token = self.send(request_openid_token::Request::new(&user_id), None).await?;
+        *cached = Some((requested_at, token.clone()));
+
+        Ok(token)
+    }
+}
+
+/// Find out why the homeserver rejected the upload of our keys.
+#[cfg(feature = "encryption")]
+fn keys_upload_failure(status_code: http::StatusCode) -> KeysUploadFailure {
+    match status_code {
+        http::StatusCode::PAYLOAD_TOO_LARGE => KeysUploadFailure::TooLarge,
+        http::StatusCode::BAD_REQUEST => KeysUploadFailure::RejectedKeys,
+        _ => KeysUploadFailure::Other,
+    }
+}
+
+/// Did the homeserver answer the request with an error, e.g. a message event
+/// that it didn't accept.
+fn is_rejected_error(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Http(HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))))
+            if e.status_code.is_client_error()
+    )
+}
+
+/// Did the homeserver reject the request because it doesn't know the endpoint.
+fn is_unrecognized_error(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Http(HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))))
+            if e.status_code == http::StatusCode::NOT_FOUND
+                || e.kind == ruma::api::client::error::ErrorKind::Unrecognized
+    )
+}
+
+mod knock_room {
+    //! [POST /_matrix/client/r0/knock/{roomIdOrAlias}](https://spec.matrix.org/v1.3/client-server-api/#post_matrixclientv3knockroomidoralias)
+
+    use ruma::{api::ruma_api, RoomId, RoomIdOrAliasId};
+
+    ruma_api! {
+        metadata: {
+            description: "Knock on a room, requesting permission to join it.",
+            method: POST,
+            name: "knock_room",
+            path: "/_matrix/client/r0/knock/:room_id_or_alias",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room the user should knock on.
+            #[ruma_api(path)]
+            pub room_id_or_alias: &'a RoomIdOrAliasId,
+
+            /// The reason for joining a room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub reason: Option<&'a str>,
+        }
+
+        response: {
+            /// The room that the user knocked on.
+            pub room_id: RoomId,
+        }
+
+        error: ruma::api::client::Error
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a new `Request` with the given room ID or alias.
+        pub fn new(room_id_or_alias: &'a RoomIdOrAliasId) -> Self {
+            Self { room_id_or_alias, reason: None }
+        }
+    }
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
     use std::{
         collections::BTreeMap,
         convert::{TryFrom, TryInto},
@@ -2716,7 +4576,11 @@ mod test {
         time::Duration,
     };
 
-    use matrix_sdk_base::media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType};
+    use futures::{FutureExt, StreamExt};
+    use matrix_sdk_base::{
+        deserialized_responses::SyncRoomEvent,
+        media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
+    };
     use matrix_sdk_test::{test_json, EventBuilder, EventsJson};
     use mockito::{mock, Matcher};
     use ruma::{
@@ -2742,19 +4606,30 @@ mod test {
         event_id,
         events::{
             room::{
+                join_rules::JoinRule,
                 message::{ImageMessageEventContent, MessageEventContent},
                 ImageInfo,
             },
-            AnyMessageEventContent,
+            AnyMessageEventContent, EventType,
         },
-        mxc_uri, room_id, thirdparty, uint, user_id, UserId,
+        int, mxc_uri, room_alias_id, room_id, thirdparty, uint, user_id,
+        MilliSecondsSinceUnixEpoch, RoomVersionId, UserId,
     };
     use serde_json::json;
 
-    use super::{Client, Session, SyncSettings, Url};
-    use crate::{ClientConfig, HttpError, RequestConfig, RoomMember};
+    use super::{
+        BackoffStrategy, Client, LoopCtrl, Session, SyncLoopConfig, SyncSettings, SyncState,
+        SyncStopToken, Url,
+    };
+    use crate::{
+        notification_settings::RoomNotificationMode,
+        policy_list::PolicyRuleChange,
+        room::{ExportFormat, ExportMedia, RelationType, RelationsPagination},
+        ClientConfig, Error, HttpError, HttpPusherConfig, LocalEchoState, RegistrationStage,
+        RequestConfig, RoomMember,
+    };
 
-    async fn logged_in_client() -> Client {
+    pub(crate) async fn logged_in_client() -> Client {
         let session = Session {
             access_token: "1234".to_owned(),
             user_id: user_id!("@example:localhost"),
@@ -2823,6 +4698,108 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn discovery_invalid_base_url() {
+        let server_url = mockito::server_url();
+        let domain = server_url.strip_prefix("http://").unwrap();
+        let alice = UserId::try_from("@alice:".to_string() + domain).unwrap();
+
+        let _m = mock("GET", "/.well-known/matrix/client")
+            .with_status(200)
+            .with_body(
+                test_json::WELL_KNOWN.to_string().replace("HOMESERVER_URL", "ftp://localhost"),
+            )
+            .create();
+
+        assert!(matches!(
+            Client::new_from_user_id(alice).await,
+            Err(Error::InvalidHomeserverUrl(url)) if url == "ftp://localhost"
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_capabilities() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/capabilities")
+            .with_status(200)
+            .with_body(test_json::CAPABILITIES.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .expect(1)
+            .create();
+
+        assert!(!client.can_change_password().await.unwrap());
+        assert!(!client.can_set_displayname().await.unwrap());
+        assert!(client.can_set_avatar_url().await.unwrap());
+        assert_eq!(client.default_room_version().await.unwrap(), RoomVersionId::Version6);
+        assert!(client.is_room_version_supported(&RoomVersionId::Version5).await.unwrap());
+        assert!(!client.is_room_version_supported(&RoomVersionId::Version4).await.unwrap());
+        assert_eq!(client.supported_room_versions().await.unwrap().len(), 4);
+
+        assert!(matches!(
+            client.set_display_name(Some("Alice")).await,
+            Err(Error::CapabilityDisabled("m.set_displayname"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn server_capabilities_unavailable() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/capabilities")
+            .with_status(403)
+            .with_body(r#"{"errcode": "M_FORBIDDEN", "error": "Forbidden"}"#)
+            .create();
+
+        let set_name =
+            mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/profile/.*/displayname".to_string()))
+                .with_status(200)
+                .with_body("{}")
+                .match_header("authorization", "Bearer 1234")
+                .expect(1)
+                .create();
+
+        client.set_display_name(Some("Alice")).await.unwrap();
+        set_name.assert();
+    }
+
+    #[tokio::test]
+    async fn server_versions() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/versions")
+            .with_status(200)
+            .with_body(test_json::VERSIONS.to_string())
+            .expect(1)
+            .create();
+
+        assert!(client.supports_version("r0.6.0").await.unwrap());
+        assert!(!client.supports_version("v1.1").await.unwrap());
+        assert!(client.unstable_features().await.unwrap()["org.matrix.e2e_cross_signing"]);
+    }
+
+    #[tokio::test]
+    async fn openid_token() {
+        let client = logged_in_client().await;
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/r0/user/.*/openid/request_token".to_string()),
+        )
+        .with_status(200)
+        .with_body(test_json::OPENID_TOKEN.to_string())
+        .match_header("authorization", "Bearer 1234")
+        .expect(1)
+        .create();
+
+        let token = client.get_openid_token().await.unwrap();
+        assert_eq!(token.access_token, "SomeT0kenHere");
+        assert_eq!(token.expires_in, Duration::from_secs(3600));
+
+        let token = client.get_openid_token().await.unwrap();
+        assert_eq!(token.matrix_server_name.as_str(), "example.com");
+    }
+
     #[tokio::test]
     async fn login() {
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
@@ -3031,22 +5008,126 @@ mod test {
     }
 
     #[tokio::test]
-    async fn login_error() {
-        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
-        let client = Client::new(homeserver).unwrap();
+    async fn direct_rooms() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
 
-        let _m = mock("POST", "/_matrix/client/r0/login")
-            .with_status(403)
-            .with_body(test_json::LOGIN_RESPONSE_ERR.to_string())
+        let mut sync = test_json::SYNC.clone();
+        sync["account_data"]["events"].as_array_mut().unwrap().push(json!({
+            "content": {
+                "@example2:localhost": [room_id.as_str()]
+            },
+            "type": "m.direct"
+        }));
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(sync.to_string())
+            .match_header("authorization", "Bearer 1234")
             .create();
 
-        if let Err(err) = client.login("example", "wordpass", None, None).await {
-            if let crate::Error::Http(HttpError::ClientApi(FromHttpResponseError::Http(
-                ServerError::Known(client_api::Error { kind, message, status_code }),
-            ))) = err
-            {
-                if let client_api::error::ErrorKind::Forbidden = kind {
-                } else {
+        let _m = mock("POST", "/_matrix/client/r0/createRoom")
+            .with_status(200)
+            .with_body(json!({ "room_id": "!new:localhost" }).to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        let _m = mock("PUT", "/_matrix/client/r0/user/@example:localhost/account_data/m.direct")
+            .with_status(200)
+            .with_body("{}")
+            .match_header("authorization", "Bearer 1234")
+            .match_body(Matcher::Json(json!({
+                "@example2:localhost": [room_id.as_str()],
+                "@bob:localhost": ["!new:localhost"],
+            })))
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let room = client.get_joined_room(&room_id).unwrap();
+        let example2 = user_id!("@example2:localhost");
+
+        assert!(room.is_direct());
+        assert_eq!(room.direct_targets().into_iter().collect::<Vec<_>>(), [example2.clone()]);
+
+        assert_eq!(client.create_dm(&example2).await.unwrap(), room_id);
+        assert_eq!(
+            client.create_dm(&user_id!("@bob:localhost")).await.unwrap(),
+            room_id!("!new:localhost")
+        );
+    }
+
+    #[tokio::test]
+    async fn direct_room_avatar() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let mut sync = test_json::SYNC.clone();
+        sync["account_data"]["events"].as_array_mut().unwrap().push(json!({
+            "content": {
+                "@example2:localhost": [room_id.as_str()]
+            },
+            "type": "m.direct"
+        }));
+        sync["rooms"]["join"][room_id.as_str()]["timeline"]["events"].as_array_mut().unwrap().push(
+            json!({
+                "content": {
+                    "avatar_url": "mxc://localhost/avatar",
+                    "displayname": "example2",
+                    "membership": "join"
+                },
+                "event_id": "$avatar:localhost",
+                "origin_server_ts": 152037281,
+                "sender": "@example2:localhost",
+                "state_key": "@example2:localhost",
+                "type": "m.room.member",
+            }),
+        );
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(sync.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        client.sync_once(SyncSettings::new()).await.unwrap();
+
+        let room = client.get_joined_room(&room_id).unwrap();
+        assert_eq!(room.avatar_url(), Some(mxc_uri!("mxc://localhost/avatar")));
+
+        let m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/media/r0/download/localhost/avatar\?.*$".to_string()),
+        )
+        .with_status(200)
+        .with_body("avatar")
+        .expect(1)
+        .create();
+
+        // The second request is answered by the media cache.
+        assert_eq!(room.avatar(MediaFormat::File).await.unwrap().unwrap(), b"avatar");
+        assert_eq!(room.avatar(MediaFormat::File).await.unwrap().unwrap(), b"avatar");
+        m.assert();
+    }
+
+    #[tokio::test]
+    async fn login_error() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let client = Client::new(homeserver).unwrap();
+
+        let _m = mock("POST", "/_matrix/client/r0/login")
+            .with_status(403)
+            .with_body(test_json::LOGIN_RESPONSE_ERR.to_string())
+            .create();
+
+        if let Err(err) = client.login("example", "wordpass", None, None).await {
+            if let crate::Error::Http(HttpError::ClientApi(FromHttpResponseError::Http(
+                ServerError::Known(client_api::Error { kind, message, status_code }),
+            ))) = err
+            {
+                if let client_api::error::ErrorKind::Forbidden = kind {
+                } else {
                     panic!("found the wrong `ErrorKind` {:?}, expected `Forbidden", kind);
                 }
                 assert_eq!(message, "Invalid password".to_string());
@@ -3059,6 +5140,86 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn register_guest_and_upgrade() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let client = Client::new(homeserver).unwrap();
+
+        let m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/client/r0/register\?.*kind=guest.*$".to_string()),
+        )
+        .with_status(200)
+        .with_body(
+            json!({
+                "user_id": "@guest:localhost",
+                "access_token": "guest_token",
+                "device_id": "GUESTDEVICE",
+            })
+            .to_string(),
+        )
+        .create();
+
+        client.register_guest(None).await.unwrap();
+        m.assert();
+
+        assert!(client.logged_in().await);
+        assert_eq!(client.user_id().await, Some(user_id!("@guest:localhost")));
+
+        let _m = mock("POST", Matcher::Regex(r"^/_matrix/client/r0/register.*$".to_string()))
+            .match_header("authorization", "Bearer guest_token")
+            .with_status(200)
+            .with_body(
+                json!({
+                    "user_id": "@guest:localhost",
+                    "access_token": "full_token",
+                    "device_id": "GUESTDEVICE",
+                })
+                .to_string(),
+            )
+            .create();
+
+        let request = assign!(RegistrationRequest::new(), {
+            password: Some("password"),
+            auth: Some(RegistrationStage::Dummy.to_auth_data(Some("session"))),
+        });
+
+        client.upgrade_guest(request).await.unwrap();
+        assert_eq!(
+            client.base_client.session().read().await.as_ref().unwrap().access_token,
+            "full_token"
+        );
+    }
+
+    #[tokio::test]
+    async fn register_keeps_session() {
+        let client = logged_in_client().await;
+
+        let _m = mock("POST", Matcher::Regex(r"^/_matrix/client/r0/register.*$".to_string()))
+            .with_status(200)
+            .with_body(
+                json!({
+                    "user_id": "@other:localhost",
+                    "access_token": "other_token",
+                    "device_id": "OTHERDEVICE",
+                })
+                .to_string(),
+            )
+            .create();
+
+        let request = assign!(RegistrationRequest::new(), {
+            username: Some("other"),
+            password: Some("password"),
+        });
+
+        let response = client.register(request).await.unwrap();
+        assert_eq!(response.user_id, user_id!("@other:localhost"));
+
+        let session = client.base_client.session().read().await.clone().unwrap();
+        assert_eq!(session.access_token, "1234");
+        assert_eq!(client.user_id().await, Some(user_id!("@example:localhost")));
+    }
+
     #[tokio::test]
     async fn register_error() {
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
@@ -3143,6 +5304,96 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn knock_room() {
+        let client = logged_in_client().await;
+
+        let _m = mock("POST", Matcher::Regex(r"^/_matrix/client/r0/knock/".to_string()))
+            .with_status(200)
+            .with_body(test_json::ROOM_ID.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .match_body(Matcher::Json(json!({ "reason": "Let me in" })))
+            .create();
+
+        let room_id = room_id!("!testroom:example.org").into();
+
+        assert_eq!(
+            client.knock(&room_id, Some("Let me in")).await.unwrap(),
+            room_id!("!testroom:example.org")
+        );
+    }
+
+    #[tokio::test]
+    async fn pushers() {
+        let client = logged_in_client().await;
+        let gateway =
+            Url::parse(&format!("{}/_matrix/push/v1/notify", mockito::server_url())).unwrap();
+        let config = HttpPusherConfig::new(
+            "pushkey",
+            "org.example.app",
+            "Example App",
+            "Example Device",
+            gateway.clone(),
+        );
+
+        let _m = mock("POST", "/_matrix/client/r0/pushers/set")
+            .with_status(200)
+            .with_body("{}")
+            .match_header("authorization", "Bearer 1234")
+            .match_body(Matcher::PartialJson(json!({
+                "pushkey": "pushkey",
+                "kind": "http",
+                "app_id": "org.example.app",
+                "lang": "en",
+                "data": { "url": gateway.as_str() },
+            })))
+            .create();
+
+        client.set_pusher(&config).await.unwrap();
+
+        let _m = mock("POST", "/_matrix/client/r0/pushers/set")
+            .with_status(200)
+            .with_body("{}")
+            .match_header("authorization", "Bearer 1234")
+            .match_body(Matcher::PartialJson(json!({
+                "pushkey": "pushkey",
+                "kind": null,
+                "app_id": "org.example.app",
+            })))
+            .create();
+
+        client.delete_pusher("pushkey", "org.example.app").await.unwrap();
+
+        let _m = mock("GET", "/_matrix/client/r0/pushers")
+            .with_status(200)
+            .with_body(test_json::PUSHERS.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        let pushers = client.pushers().await.unwrap();
+        assert_eq!(pushers.len(), 1);
+        assert_eq!(pushers[0].pushkey, "pushkey");
+
+        let _m = mock("POST", "/_matrix/push/v1/notify")
+            .with_status(200)
+            .with_body(r#"{ "rejected": [] }"#)
+            .match_body(Matcher::PartialJson(json!({
+                "notification": {
+                    "devices": [{ "app_id": "org.example.app", "pushkey": "pushkey" }]
+                }
+            })))
+            .create();
+
+        assert!(client.send_test_push(&config).await.unwrap());
+
+        let _m = mock("POST", "/_matrix/push/v1/notify")
+            .with_status(200)
+            .with_body(r#"{ "rejected": ["pushkey"] }"#)
+            .create();
+
+        assert!(!client.send_test_push(&config).await.unwrap());
+    }
+
     #[tokio::test]
     async fn invite_user_by_id() {
         let client = logged_in_client().await;
@@ -3239,6 +5490,65 @@ mod test {
         assert_eq!(chunk.len(), 1);
     }
 
+    #[tokio::test]
+    async fn room_preview() {
+        let client = logged_in_client().await;
+        let summarized = room_id!("!summarized:localhost");
+        let public = room_id!("!ol19s:bleecker.street");
+
+        let _m = mock("GET", Matcher::Regex(r".*/rooms/.*summarized.*/summary".to_string()))
+            .with_status(200)
+            .with_body(
+                json!({
+                    "room_id": summarized,
+                    "name": "Summarized",
+                    "num_joined_members": 3,
+                    "join_rule": "knock",
+                    "im.nheko.summary.encryption": "m.megolm.v1.aes-sha2",
+                })
+                .to_string(),
+            )
+            .create();
+
+        let _m = mock("GET", Matcher::Regex(r".*/rooms/.*ol19s.*/summary".to_string()))
+            .with_status(404)
+            .with_body(json!({ "errcode": "M_UNRECOGNIZED", "error": "Unrecognized" }).to_string())
+            .create();
+
+        let _m =
+            mock("GET", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*ol19s.*/state".to_string()))
+                .with_status(403)
+                .with_body(json!({ "errcode": "M_FORBIDDEN", "error": "Not allowed" }).to_string())
+                .create();
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/v1/rooms/.*/hierarchy".to_string()))
+            .with_status(404)
+            .with_body(json!({ "errcode": "M_NOT_FOUND", "error": "Unknown room" }).to_string())
+            .create();
+
+        let _m = mock("POST", Matcher::Regex(r"^/_matrix/client/r0/publicRooms".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::PUBLIC_ROOMS.to_string())
+            .create();
+
+        let preview = client.preview_room(&summarized.clone().into(), &[]).await.unwrap();
+
+        assert_eq!(preview.room_id, summarized);
+        assert_eq!(preview.name.as_deref(), Some("Summarized"));
+        assert_eq!(preview.num_joined_members, 3);
+        assert_eq!(preview.join_rule, Some(JoinRule::Knock));
+        assert_eq!(preview.is_encrypted, Some(true));
+
+        let preview = client.preview_room(&public.clone().into(), &[]).await.unwrap();
+
+        assert_eq!(preview.room_id, public);
+        assert_eq!(preview.topic.as_deref(), Some("Tasty tasty cheese"));
+        assert_eq!(preview.num_joined_members, 37);
+        assert_eq!(preview.join_rule, Some(JoinRule::Public));
+        assert_eq!(preview.is_encrypted, None);
+    }
+
     #[tokio::test]
     async fn leave_room() {
         let client = logged_in_client().await;
@@ -3497,7 +5807,7 @@ mod test {
     }
 
     #[tokio::test]
-    async fn room_attachment_send() {
+    async fn room_message_send_unencrypted_override() {
         let client = logged_in_client().await;
 
         let _m = mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/".to_string()))
@@ -3506,17 +5816,6 @@ mod test {
             .with_body(test_json::EVENT_ID.to_string())
             .create();
 
-        let _m = mock("POST", Matcher::Regex(r"^/_matrix/media/r0/upload".to_string()))
-            .with_status(200)
-            .match_header("content-type", "image/jpeg")
-            .with_body(
-                json!({
-                  "content_uri": "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
-                })
-                .to_string(),
-            )
-            .create();
-
         let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
             .with_status(200)
             .match_header("authorization", "Bearer 1234")
@@ -3527,28 +5826,30 @@ mod test {
 
         let _response = client.sync_once(sync_settings).await.unwrap();
 
-        let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let room = client.get_joined_room(&room_id).unwrap();
 
-        let mut media = Cursor::new("Hello world");
+        let content =
+            AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Hello world"));
 
-        let response =
-            room.send_attachment("image", &mime::IMAGE_JPEG, &mut media, None).await.unwrap();
+        // The room isn't encrypted, so we never encrypt events for it.
+        #[cfg(feature = "encryption")]
+        assert!(client.base_client.encrypt(&room_id, content.clone()).await.is_err());
+
+        let response = room.send_unencrypted_override(content, None).await.unwrap();
 
         assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
     }
 
     #[tokio::test]
-    async fn room_redact() {
-        use matrix_sdk_common::uuid::Uuid;
-
+    async fn room_message_send_with_local_echo() {
         let client = logged_in_client().await;
 
-        let _m =
-            mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/redact/.*?/.*?".to_string()))
-                .with_status(200)
-                .match_header("authorization", "Bearer 1234")
-                .with_body(test_json::EVENT_ID.to_string())
-                .create();
+        let _m = mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::EVENT_ID.to_string())
+            .create();
 
         let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
             .with_status(200)
@@ -3562,18 +5863,27 @@ mod test {
 
         let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
 
-        let event_id = event_id!("$xxxxxxxx:example.com");
+        let content =
+            AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Hello world"));
+        let handle = room.send_with_local_echo(content, None);
 
-        let txn_id = Uuid::new_v4();
-        let reason = Some("Indecent material");
-        let response = room.redact(&event_id, reason, Some(txn_id)).await.unwrap();
+        assert_eq!(room.local_echoes().len(), 1);
+        assert_eq!(handle.local_echo().unwrap().state, LocalEchoState::Pending);
 
-        assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
+        handle.send().await.unwrap();
+
+        assert_eq!(
+            handle.local_echo().unwrap().state,
+            LocalEchoState::Sent(event_id!("$h29iv0s8:example.com"))
+        );
     }
 
     #[tokio::test]
-    async fn user_presence() {
+    async fn room_message_send_idempotent() {
+        use matrix_sdk_common::uuid::Uuid;
+
         let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
 
         let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
             .with_status(200)
@@ -3581,95 +5891,1349 @@ mod test {
             .with_body(test_json::SYNC.to_string())
             .create();
 
-        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/members".to_string()))
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let txn_id = Uuid::new_v4();
+        let send = mock(
+            "PUT",
+            Matcher::Regex(format!(
+                r"^/_matrix/client/r0/rooms/.*/send/m\.room\.message/{}",
+                txn_id
+            )),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .with_body(test_json::EVENT_ID.to_string())
+        .expect(1)
+        .create();
+
+        let room = client.get_joined_room(&room_id).unwrap();
+        let content =
+            AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Hello world"));
+
+        let first = room.send(content.clone(), Some(txn_id)).await.unwrap();
+        let second = room.send(content, Some(txn_id)).await.unwrap();
+        send.assert();
+        assert!(client.pending_transaction_ids(&room_id).await.unwrap().is_empty());
+
+        assert_eq!(first.event_id, second.event_id);
+        assert_eq!(
+            client.transaction_event_id(&room_id, &txn_id).await.unwrap(),
+            Some(event_id!("$h29iv0s8:example.com"))
+        );
+
+        let remote_echo = json!({
+            "next_batch": "s526_47314_0_7_1_1_1_11444_2",
+            "rooms": {
+                "join": {
+                    room_id.as_str(): {
+                        "timeline": {
+                            "events": [{
+                                "content": { "body": "Hello world", "msgtype": "m.text" },
+                                "event_id": "$h29iv0s8:example.com",
+                                "origin_server_ts": 152037280,
+                                "sender": "@example:localhost",
+                                "type": "m.room.message",
+                                "unsigned": { "transaction_id": txn_id.to_string() }
+                            }],
+                            "limited": false,
+                            "prev_batch": "t392-516_47314_0_7_1_1_1_11444_1"
+                        }
+                    }
+                }
+            }
+        });
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
             .with_status(200)
             .match_header("authorization", "Bearer 1234")
-            .with_body(test_json::MEMBERS.to_string())
+            .with_body(remote_echo.to_string())
             .create();
 
         let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
-
         let _response = client.sync_once(sync_settings).await.unwrap();
 
-        let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
-        let members: Vec<RoomMember> = room.active_members().await.unwrap();
+        assert_eq!(client.transaction_event_id(&room_id, &txn_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn room_paginate_backwards() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let _m =
+            mock("GET", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/messages\?.*$".to_string()))
+                .with_status(200)
+                .match_header("authorization", "Bearer 1234")
+                .with_body(test_json::ROOM_MESSAGES.to_string())
+                .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let room = client.get_joined_room(&room_id).unwrap();
+
+        let synced_events =
+            client.store().get_paginated_timeline(&room_id).await.unwrap().unwrap().events.len();
+
+        let chunk = room.paginate_backwards(uint!(3)).await.unwrap();
+
+        assert_eq!(chunk.events.len(), 3);
+        assert!(!chunk.reached_start);
+
+        let timeline = client.store().get_paginated_timeline(&room_id).await.unwrap().unwrap();
+        assert_eq!(timeline.events.len(), synced_events + 3);
+        assert_eq!(timeline.token.as_deref(), Some("t47409-4357353_219380_26003_2265"));
+    }
+
+    #[tokio::test]
+    async fn room_relations() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let _m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/v1/rooms/.*/relations/.*/m.replace\?.*$".to_string()),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .with_body(
+            json!({
+                "chunk": [{
+                    "content": {
+                        "body": "* Hello world",
+                        "msgtype": "m.text",
+                        "m.new_content": {
+                            "body": "Hello world",
+                            "msgtype": "m.text"
+                        },
+                        "m.relates_to": {
+                            "event_id": "$h29iv0s8:example.com",
+                            "rel_type": "m.replace"
+                        }
+                    },
+                    "event_id": "$edit:example.com",
+                    "origin_server_ts": 152037280,
+                    "room_id": "!SVkFJHzfwvuaIEawgC:localhost",
+                    "sender": "@example:localhost",
+                    "type": "m.room.message"
+                }],
+                "next_batch": "page2_token"
+            })
+            .to_string(),
+        )
+        .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
+
+        let relations = room
+            .relations(
+                &event_id!("$h29iv0s8:example.com"),
+                RelationType::Replacement,
+                false,
+                RelationsPagination::default(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(relations.chunk.len(), 1);
+        assert_eq!(relations.next_batch.as_deref(), Some("page2_token"));
+        assert!(relations.prev_batch.is_none());
+    }
+
+    #[tokio::test]
+    async fn room_power_levels() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let _m = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/state/m.room.power_levels/".to_string()),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .with_body(test_json::EVENT_ID.to_string())
+        .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
+        let own_user = user_id!("@example:localhost");
+        let other_user = user_id!("@other:localhost");
+
+        assert!(room.can_user_ban(&own_user).await.unwrap());
+        assert!(!room.can_user_ban(&other_user).await.unwrap());
+        assert!(room.can_user_invite(&other_user).await.unwrap());
+        assert!(room.can_user_redact_own(&other_user).await.unwrap());
+        assert!(!room.can_user_redact_other(&other_user).await.unwrap());
+        assert!(room.can_user_send_message(&other_user, &EventType::RoomMessage).await.unwrap());
+        assert!(!room.can_user_send_state(&other_user, &EventType::RoomTopic).await.unwrap());
+
+        let builder = room.power_levels_builder().await.unwrap();
+        assert!(builder.clone().user(&other_user, int!(200)).is_err());
+
+        let builder = builder.user(&other_user, int!(50)).unwrap();
+        room.update_power_levels(builder).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn room_enable_encryption() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let sync = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+        drop(sync);
+
+        let room = client.get_joined_room(&room_id).unwrap();
+        assert!(!room.is_encrypted());
+
+        let _m = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/state/m.room.encryption/".to_string()),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .match_body(Matcher::PartialJson(json!({ "algorithm": "m.megolm.v1.aes-sha2" })))
+        .with_body(test_json::EVENT_ID.to_string())
+        .create();
+
+        let mut sync = test_json::SYNC.clone();
+        sync["rooms"]["join"][room_id.as_str()]["state"]["events"].as_array_mut().unwrap().push(
+            json!({
+                "content": { "algorithm": "m.megolm.v1.aes-sha2" },
+                "event_id": "$encryption:localhost",
+                "origin_server_ts": 151393756,
+                "sender": "@example:localhost",
+                "state_key": "",
+                "type": "m.room.encryption",
+            }),
+        );
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(sync.to_string())
+            .create();
+
+        let mut encrypted_rooms = client.room_encryption_stream();
+
+        let (enabled, synced) =
+            futures::join!(room.enable_encryption(), client.sync_once(sync_settings));
+        enabled.unwrap();
+        synced.unwrap();
+
+        assert!(room.is_encrypted());
+        assert_eq!(encrypted_rooms.next().await.unwrap(), room_id);
+    }
+
+    #[tokio::test]
+    async fn notification_settings() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let settings = client.notification_settings();
+
+        let mute = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/pushrules/global/override/.*".to_string()),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .match_body(Matcher::PartialJson(json!({
+            "actions": ["dont_notify"],
+            "conditions": [{ "kind": "event_match", "key": "room_id", "pattern": room_id }],
+        })))
+        .with_body("{}")
+        .create();
+
+        let _m = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/pushrules/global/content/rust".to_string()),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .match_body(Matcher::PartialJson(json!({ "pattern": "rust" })))
+        .with_body("{}")
+        .create();
+
+        let _m = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/pushrules/global/content/cheese".to_string()),
+        )
+        .with_status(403)
+        .with_body(json!({ "errcode": "M_FORBIDDEN", "error": "Not allowed" }).to_string())
+        .create();
+
+        assert_eq!(settings.room_notification_mode(&room_id).await.unwrap(), None);
+
+        settings
+            .set_room_notification_mode(&room_id, Some(RoomNotificationMode::Mute))
+            .await
+            .unwrap();
+        mute.assert();
+        assert_eq!(
+            settings.room_notification_mode(&room_id).await.unwrap(),
+            Some(RoomNotificationMode::Mute)
+        );
+
+        // The master rule keeps its priority over the rules the user creates.
+        let push_rules = client.store().get_account_data_event(EventType::PushRules).await;
+        let push_rules: serde_json::Value =
+            serde_json::from_str(push_rules.unwrap().unwrap().json().get()).unwrap();
+        let overrides = push_rules["content"]["global"]["override"].as_array().unwrap();
+        assert_eq!(overrides[0]["rule_id"], ".m.rule.master");
+        assert_eq!(overrides[1]["rule_id"], room_id.as_str());
+
+        settings.add_keyword("rust").await.unwrap();
+        assert_eq!(settings.keywords().await.unwrap(), ["rust"]);
+
+        // A failed request restores the rule it changed.
+        settings.add_keyword("cheese").await.unwrap_err();
+        assert_eq!(settings.keywords().await.unwrap(), ["rust"]);
+    }
+
+    #[tokio::test]
+    async fn policy_lists() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let policy_lists = client.policy_lists();
+
+        policy_lists.watch_room(&room_id).await.unwrap();
+        assert_eq!(policy_lists.watched_rooms(), [room_id.clone()]);
+
+        let mut sync = test_json::SYNC.clone();
+        sync["rooms"]["join"][room_id.as_str()]["timeline"]["events"].as_array_mut().unwrap().push(
+            json!({
+                "event_id": "$policy:localhost",
+                "origin_server_ts": 1,
+                "sender": "@example:localhost",
+                "type": "m.policy.rule.user",
+                "state_key": "rule:evil.org",
+                "content": {
+                    "entity": "@*:evil.org",
+                    "recommendation": "m.ban",
+                    "reason": "spam"
+                }
+            }),
+        );
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(sync.to_string())
+            .create();
+
+        let mut changes = policy_lists.rule_changes();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        match changes.next().await.unwrap() {
+            PolicyRuleChange::Added(rule) => {
+                assert_eq!(rule.state_key, "rule:evil.org");
+                assert_eq!(rule.content.reason, "spam");
+            }
+            PolicyRuleChange::Removed(_) => panic!("The rule wasn't added"),
+        }
+
+        assert!(policy_lists.is_user_banned_by_policy(&user_id!("@spammer:evil.org")));
+        assert!(!policy_lists.is_user_banned_by_policy(&user_id!("@example:localhost")));
+
+        policy_lists.unwatch_room(&room_id);
+        assert!(!policy_lists.is_user_banned_by_policy(&user_id!("@spammer:evil.org")));
+    }
+
+    #[tokio::test]
+    async fn room_state_changes() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+        drop(m);
+
+        let room = client.get_joined_room(&room_id).unwrap();
+        let snapshot = room.state_snapshot().await.unwrap();
+
+        assert!(snapshot["m.room.member"].contains_key("@example:localhost"));
+        assert!(snapshot["m.room.topic"].contains_key(""));
+        assert!(!snapshot.contains_key("org.example.state"));
+
+        let mut sync = test_json::SYNC.clone();
+        let timeline =
+            sync["rooms"]["join"][room_id.as_str()]["timeline"]["events"].as_array_mut().unwrap();
+        timeline.push(json!({
+            "content": { "topic": "New topic" },
+            "event_id": "$topic:localhost",
+            "origin_server_ts": 151957879,
+            "sender": "@example:localhost",
+            "state_key": "",
+            "type": "m.room.topic"
+        }));
+        timeline.push(json!({
+            "content": { "enabled": true },
+            "event_id": "$custom:localhost",
+            "origin_server_ts": 151957880,
+            "sender": "@example:localhost",
+            "state_key": "",
+            "type": "org.example.state"
+        }));
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(sync.to_string())
+            .create();
+
+        let mut changes = room.state_changes();
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        // State that didn't change isn't part of the changes.
+        let topic = changes.next().await.unwrap();
+        assert_eq!(topic.event_type, "m.room.topic");
+        assert!(topic.old.unwrap().json().get().contains("$151957878228ssqrJ:localhost"));
+        assert!(topic.new.json().get().contains("New topic"));
+
+        let custom = changes.next().await.unwrap();
+        assert_eq!(custom.event_type, "org.example.state");
+        assert!(custom.old.is_none());
+
+        let snapshot = room.state_snapshot().await.unwrap();
+        assert!(snapshot["org.example.state"].contains_key(""));
+    }
+
+    #[tokio::test]
+    async fn export_timeline() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let message = |event_id: &str, ts: u64, content: serde_json::Value| {
+            json!({
+                "type": "m.room.message",
+                "event_id": event_id,
+                "room_id": room_id,
+                "sender": "@example:localhost",
+                "origin_server_ts": ts,
+                "content": content,
+            })
+        };
+
+        let chunk = json!([
+            {
+                "type": "m.room.redaction",
+                "event_id": "$redaction",
+                "room_id": room_id,
+                "sender": "@example:localhost",
+                "origin_server_ts": 40,
+                "redacts": "$secret",
+                "content": {},
+            },
+            message("$image", 30, json!({ "msgtype": "m.image", "body": "cat.png", "url": "mxc://localhost/cat" })),
+            message("$secret", 20, json!({ "msgtype": "m.text", "body": "Secret" })),
+            message("$hello", 15, json!({ "msgtype": "m.text", "body": "Hello" })),
+            message("$old", 5, json!({ "msgtype": "m.text", "body": "Too old" })),
+        ]);
+
+        // The export stops paginating once it reached the start of the range.
+        let messages =
+            mock("GET", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/messages".to_string()))
+                .with_status(200)
+                .match_header("authorization", "Bearer 1234")
+                .with_body(json!({ "start": "t1", "end": "t2", "chunk": chunk }).to_string())
+                .expect(2)
+                .create();
+
+        let room = client.get_joined_room(&room_id).unwrap();
+        let range = MilliSecondsSinceUnixEpoch(uint!(10))..;
+
+        let lines = room
+            .export_timeline(range.clone(), ExportFormat::JsonLines { media: ExportMedia::Link })
+            .await
+            .unwrap();
+        let events: Vec<serde_json::Value> =
+            lines.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0]["event_id"], "$hello");
+        assert_eq!(events[1]["event_id"], "$secret");
+        assert_eq!(events[1]["content"], json!({}));
+        assert!(events[2]["content"]["url"]
+            .as_str()
+            .unwrap()
+            .ends_with("/_matrix/media/r0/download/localhost/cat"));
+
+        let html = room
+            .export_timeline(range, ExportFormat::Html { media: ExportMedia::Keep })
+            .await
+            .unwrap();
+
+        messages.assert();
+        assert!(html.contains("Hello"));
+        assert!(html.contains("<img src=\"mxc://localhost/cat\" alt=\"cat.png\">"));
+        assert!(html.contains("Message deleted"));
+        assert!(!html.contains("Secret"));
+        assert!(!html.contains("Too old"));
+    }
+
+    #[tokio::test]
+    async fn room_aliases() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let alias = room_alias_id!("#tutorial:localhost");
+        let other_alias = room_alias_id!("#other:localhost");
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let resolve = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/directory/room/%23tutorial.*".to_string()),
+        )
+        .with_status(200)
+        .with_body(json!({ "room_id": room_id, "servers": ["localhost"] }).to_string())
+        .expect(1)
+        .create();
+
+        let _m = mock(
+            "PUT",
+            Matcher::Regex(r"^/_matrix/client/r0/directory/room/%23other.*".to_string()),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .match_body(Matcher::PartialJson(json!({ "room_id": room_id })))
+        .with_body("{}")
+        .create();
+
+        let _m = mock(
+            "DELETE",
+            Matcher::Regex(r"^/_matrix/client/r0/directory/room/%23other.*".to_string()),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .with_body("{}")
+        .create();
+
+        let add_alt_alias = mock(
+            "PUT",
+            Matcher::Regex(
+                r"^/_matrix/client/r0/rooms/.*/state/m.room.canonical_alias/".to_string(),
+            ),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .match_body(Matcher::PartialJson(json!({
+            "alias": "#tutorial:localhost",
+            "alt_aliases": ["#other:localhost"],
+        })))
+        .with_body(test_json::EVENT_ID.to_string())
+        .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        assert_eq!(client.resolve_room_alias(&alias).await.unwrap(), room_id);
+        // The second lookup is answered from the cache.
+        assert_eq!(client.resolve_room_alias(&alias).await.unwrap(), room_id);
+        resolve.assert();
+
+        let room = client.get_joined_room(&room_id).unwrap();
+
+        room.create_alias(&other_alias).await.unwrap();
+        assert_eq!(client.resolve_room_alias(&other_alias).await.unwrap(), room_id);
+
+        room.add_alt_alias(&other_alias).await.unwrap();
+        add_alt_alias.assert();
+
+        // The stored state only learns about the new alternative alias with the
+        // next sync, so deleting it doesn't send a `m.room.canonical_alias` event.
+        room.delete_alias(&other_alias).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn room_alias_cache_invalidation() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let alias = room_alias_id!("#tutorial:localhost");
+
+        let resolve = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/directory/room/%23tutorial.*".to_string()),
+        )
+        .with_status(200)
+        .with_body(json!({ "room_id": room_id, "servers": ["localhost"] }).to_string())
+        .expect(2)
+        .create();
+
+        assert_eq!(client.resolve_room_alias(&alias).await.unwrap(), room_id);
+        assert_eq!(client.resolve_room_alias(&alias).await.unwrap(), room_id);
+
+        let mut sync = test_json::SYNC.clone();
+        sync["rooms"]["join"][room_id.as_str()]["timeline"]["events"].as_array_mut().unwrap().push(
+            json!({
+                "content": { "alias": "#tutorial:localhost" },
+                "event_id": "$canonical_alias:localhost",
+                "origin_server_ts": 151957879,
+                "sender": "@example:localhost",
+                "state_key": "",
+                "type": "m.room.canonical_alias"
+            }),
+        );
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(sync.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        // The aliases of the room changed, the alias is resolved again.
+        assert_eq!(client.resolve_room_alias(&alias).await.unwrap(), room_id);
+        resolve.assert();
+    }
+
+    #[tokio::test]
+    async fn room_attachment_send() {
+        let client = logged_in_client().await;
+
+        let _m = mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::EVENT_ID.to_string())
+            .create();
+
+        let _m = mock("POST", Matcher::Regex(r"^/_matrix/media/r0/upload".to_string()))
+            .with_status(200)
+            .match_header("content-type", "image/jpeg")
+            .with_body(
+                json!({
+                  "content_uri": "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
+                })
+                .to_string(),
+            )
+            .create();
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
+
+        let mut media = Cursor::new("Hello world");
+
+        let response =
+            room.send_attachment("image", &mime::IMAGE_JPEG, &mut media, None).await.unwrap();
+
+        assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
+    }
+
+    #[tokio::test]
+    async fn room_redact() {
+        use matrix_sdk_common::uuid::Uuid;
+
+        let client = logged_in_client().await;
+
+        let _m =
+            mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/redact/.*?/.*?".to_string()))
+                .with_status(200)
+                .match_header("authorization", "Bearer 1234")
+                .with_body(test_json::EVENT_ID.to_string())
+                .create();
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
+
+        let event_id = event_id!("$xxxxxxxx:example.com");
+
+        let txn_id = Uuid::new_v4();
+        let reason = Some("Indecent material");
+        let response = room.redact(&event_id, reason, Some(txn_id)).await.unwrap();
+
+        assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
+    }
+
+    #[tokio::test]
+    async fn redactions() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let mut sync = test_json::SYNC.clone();
+        sync["rooms"]["join"][room_id.as_str()]["timeline"]["events"]
+            .as_array_mut()
+            .unwrap()
+            .extend(vec![
+                json!({
+                    "content": { "body": "Hello", "msgtype": "m.text" },
+                    "event_id": "$message:localhost",
+                    "origin_server_ts": 152037281,
+                    "sender": "@example2:localhost",
+                    "type": "m.room.message",
+                    "unsigned": {
+                        "m.relations": {
+                            "m.annotation": {
+                                "chunk": [{ "type": "m.reaction", "key": "👍", "count": 1 }]
+                            }
+                        }
+                    }
+                }),
+                json!({
+                    "content": {
+                        "m.relates_to": {
+                            "rel_type": "m.annotation",
+                            "event_id": "$message:localhost",
+                            "key": "👍"
+                        }
+                    },
+                    "event_id": "$reaction:localhost",
+                    "origin_server_ts": 152037282,
+                    "sender": "@example:localhost",
+                    "type": "m.reaction",
+                }),
+                json!({
+                    "content": {},
+                    "redacts": "$reaction:localhost",
+                    "event_id": "$redaction:localhost",
+                    "origin_server_ts": 152037283,
+                    "sender": "@example:localhost",
+                    "type": "m.room.redaction",
+                }),
+                json!({
+                    "content": {
+                        "ban": 50,
+                        "events": {},
+                        "events_default": 0,
+                        "invite": 0,
+                        "kick": 50,
+                        "redact": 50,
+                        "state_default": 50,
+                        "users": {},
+                        "users_default": 0
+                    },
+                    "event_id": "$power_levels:localhost",
+                    "origin_server_ts": 152037284,
+                    "sender": "@example:localhost",
+                    "state_key": "",
+                    "type": "m.room.power_levels",
+                }),
+            ]);
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(sync.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        client.sync_once(SyncSettings::new()).await.unwrap();
+        let room = client.get_joined_room(&room_id).unwrap();
+
+        let event_json = |event: SyncRoomEvent| {
+            serde_json::from_str::<serde_json::Value>(event.event.json().get()).unwrap()
+        };
+
+        let reaction = room.timeline_event(&event_id!("$reaction:localhost")).await.unwrap();
+        let reaction = event_json(reaction.unwrap());
+        assert_eq!(reaction["content"], json!({}));
+        assert_eq!(reaction["unsigned"]["redacted_because"]["event_id"], "$redaction:localhost");
+
+        let message = room.timeline_event(&event_id!("$message:localhost")).await.unwrap();
+        let message = event_json(message.unwrap());
+        assert_eq!(message["content"]["body"], "Hello");
+        assert_eq!(message["unsigned"]["m.relations"]["m.annotation"]["chunk"], json!([]));
+
+        // Our own user lost the power to redact the events of other users.
+        let error = room.redact(&event_id!("$message:localhost"), None, None).await.unwrap_err();
+        assert!(matches!(error, Error::RedactionNotAllowed(_)));
+    }
+
+    #[tokio::test]
+    async fn user_presence() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/members".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::MEMBERS.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
+        let members: Vec<RoomMember> = room.active_members().await.unwrap();
+
+        assert_eq!(1, members.len());
+        // assert!(room.power_levels.is_some())
+    }
+
+    #[tokio::test]
+    async fn member_counts() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/members".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::MEMBERS.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
+
+        // The counts of the room summary are available before the members
+        // are loaded.
+        assert!(!room.are_members_synced());
+        assert_eq!(room.heroes(), vec!["@example2:localhost".to_owned()]);
+        assert_eq!(room.joined_members_count(), 2);
+        assert_eq!(room.invited_members_count(), 0);
+
+        // Loading the full member list reconciles the counts with it.
+        room.active_members().await.unwrap();
+        assert!(room.are_members_synced());
+        assert_eq!(room.joined_members_count(), 1);
+        assert_eq!(room.active_members_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn lazy_loaded_members_keep_member_counts() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+
+        let sync = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::DEFAULT_SYNC_SUMMARY.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings.clone()).await.unwrap();
+        drop(sync);
+
+        let room = client.get_joined_room(&room_id).unwrap();
+        assert_eq!(room.joined_members_count(), 2);
+
+        // The server omits the unchanged counts, the member event of a lazily
+        // loaded member isn't a new join.
+        let mut sync = test_json::DEFAULT_SYNC_SUMMARY.clone();
+        let joined_room = &mut sync["rooms"]["join"][room_id.as_str()];
+        joined_room["summary"] = json!({});
+        joined_room["timeline"]["events"] = json!([]);
+        joined_room["state"]["events"] = json!([{
+            "content": { "membership": "join" },
+            "event_id": "$lazy_member:localhost",
+            "origin_server_ts": 151393755,
+            "sender": "@lazy:localhost",
+            "state_key": "@lazy:localhost",
+            "type": "m.room.member",
+        }]);
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(sync.to_string())
+            .create();
+
+        let _response = client.sync_once(sync_settings).await.unwrap();
+        assert_eq!(room.joined_members_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn calculate_room_names_from_summary() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::DEFAULT_SYNC_SUMMARY.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+        let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
+
+        assert_eq!("example2", room.display_name().await.unwrap());
+        assert_eq!(Some("example2".to_owned()), room.cached_display_name());
+    }
+
+    #[tokio::test]
+    async fn invited_rooms() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::INVITE_SYNC.to_string())
+            .create();
+
+        let _response = client.sync_once(SyncSettings::default()).await.unwrap();
+
+        assert!(client.joined_rooms().is_empty());
+        assert!(client.left_rooms().is_empty());
+        assert!(!client.invited_rooms().is_empty());
+
+        assert!(client.get_invited_room(&room_id!("!696r7674:example.com")).is_some());
+    }
+
+    #[tokio::test]
+    async fn left_rooms() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::LEAVE_SYNC.to_string())
+            .create();
+
+        let _response = client.sync_once(SyncSettings::default()).await.unwrap();
+
+        assert!(client.joined_rooms().is_empty());
+        assert!(!client.left_rooms().is_empty());
+        assert!(client.invited_rooms().is_empty());
+
+        assert!(client.get_left_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).is_some())
+    }
+
+    #[tokio::test]
+    async fn sync() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+
+        let response = client.sync_once(sync_settings).await.unwrap();
+
+        assert_ne!(response.next_batch, "");
+
+        assert!(client.sync_token().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn sync_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        use matrix_sdk_common::{async_trait, deserialized_responses::SyncResponse};
+        use ruma::api::client::r0::sync::sync_events;
+
+        use crate::SyncHook;
+
+        struct PresenceHook(Arc<Mutex<Vec<usize>>>);
+
+        #[async_trait]
+        impl SyncHook for PresenceHook {
+            async fn pre_process(&self, response: &mut sync_events::Response) {
+                let event = json!({
+                    "content": { "presence": "offline" },
+                    "sender": "@bridged:localhost",
+                    "type": "m.presence",
+                });
+                response.presence.events.push(serde_json::from_value(event).unwrap());
+            }
+
+            async fn on_sync_response(&self, response: &SyncResponse) {
+                self.0.lock().unwrap().push(response.presence.events.len());
+            }
+        }
 
-        assert_eq!(1, members.len());
-        // assert!(room.power_levels.is_some())
+        let client = logged_in_client().await;
+        let counts = Arc::new(Mutex::new(Vec::new()));
+        client.add_sync_hook(Box::new(PresenceHook(counts.clone()))).await;
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .with_body(test_json::SYNC.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let response = client.sync_once(sync_settings).await.unwrap();
+
+        assert_eq!(response.presence.events.len(), 2);
+        assert_eq!(*counts.lock().unwrap(), vec![2]);
     }
 
     #[tokio::test]
-    async fn calculate_room_names_from_summary() {
+    async fn event_processors() {
+        use matrix_sdk_common::{async_trait, deserialized_responses::SyncRoomEvent};
+
+        use crate::{BaseRoom, EventProcessor};
+
+        #[derive(Debug)]
+        struct EventSize;
+
+        #[async_trait]
+        impl EventProcessor for EventSize {
+            async fn process(&self, _: &BaseRoom, event: &mut SyncRoomEvent) {
+                let size = event.event.json().get().len();
+                event.set_metadata("size", &size).unwrap();
+            }
+        }
+
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let config = ClientConfig::new().event_processor(EventSize);
+        let client = Client::new_with_config(homeserver, config).unwrap();
+        client
+            .restore_login(Session {
+                access_token: "1234".to_owned(),
+                user_id: user_id!("@example:localhost"),
+                device_id: "DEVICEID".into(),
+            })
+            .await
+            .unwrap();
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(test_json::SYNC.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let response = client.sync_once(sync_settings).await.unwrap();
+
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let event = &response.rooms.join[&room_id].timeline.events[0];
+        let size: usize = event.metadata("size").unwrap();
+        assert_eq!(size, event.event.json().get().len());
+        assert!(event.metadata::<String>("size").is_none());
+        assert!(event.metadata::<usize>("links").is_none());
+    }
+
+    #[tokio::test]
+    async fn send_interceptors() {
+        use matrix_sdk_common::async_trait;
+        use ruma::events::room::message::MessageType;
+
+        use crate::{room, SendDecision, SendInterceptor};
+
+        struct Shouting;
+
+        #[async_trait]
+        impl SendInterceptor for Shouting {
+            async fn before_send(
+                &self,
+                _: &room::Joined,
+                content: &mut AnyMessageEventContent,
+            ) -> SendDecision {
+                let body = match content {
+                    AnyMessageEventContent::RoomMessage(MessageEventContent {
+                        msgtype: MessageType::Text(t),
+                        ..
+                    }) => t.body.clone(),
+                    _ => return SendDecision::Send,
+                };
+
+                if body.contains("password") {
+                    return SendDecision::Veto("the message contains a password".to_owned());
+                }
+
+                *content = AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain(
+                    body.to_uppercase(),
+                ));
+
+                SendDecision::Send
+            }
+        }
+
         let client = logged_in_client().await;
+        client.add_send_interceptor(Box::new(Shouting)).await;
 
         let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
             .with_status(200)
             .match_header("authorization", "Bearer 1234")
-            .with_body(test_json::DEFAULT_SYNC_SUMMARY.to_string())
+            .with_body(test_json::SYNC.to_string())
             .create();
 
         let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
         let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let send = mock("PUT", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/send/".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .match_body(Matcher::PartialJson(json!({ "body": "HELLO WORLD" })))
+            .with_body(test_json::EVENT_ID.to_string())
+            .expect(1)
+            .create();
+
         let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
 
-        assert_eq!("example2", room.display_name().await.unwrap());
+        let content =
+            AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Hello world"));
+        room.send(content, None).await.unwrap();
+
+        let content =
+            AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("My password"));
+        assert!(matches!(room.send(content, None).await, Err(Error::SendVetoed(_))));
+
+        send.assert();
     }
 
     #[tokio::test]
-    async fn invited_rooms() {
+    async fn sync_loop_gives_up() {
         let client = logged_in_client().await;
+        let mut states = Box::pin(client.sync_state_stream());
 
         let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
-            .with_status(200)
+            .with_status(404)
+            .with_body(
+                json!({
+                    "errcode": "M_NOT_FOUND",
+                    "error": "Not found",
+                })
+                .to_string(),
+            )
             .match_header("authorization", "Bearer 1234")
-            .with_body(test_json::INVITE_SYNC.to_string())
+            .expect(2)
             .create();
 
-        let _response = client.sync_once(SyncSettings::default()).await.unwrap();
+        let config = SyncLoopConfig::new()
+            .backoff(BackoffStrategy::Constant(Duration::from_millis(10)))
+            .max_attempts(2);
 
-        assert!(client.joined_rooms().is_empty());
-        assert!(client.left_rooms().is_empty());
-        assert!(!client.invited_rooms().is_empty());
+        let result = client
+            .sync_with_config(SyncSettings::new(), config, |_| async { LoopCtrl::Continue })
+            .await;
 
-        assert!(client.get_invited_room(&room_id!("!696r7674:example.com")).is_some());
+        assert!(result.is_err());
+        assert!(matches!(states.next().await, Some(SyncState::BackingOff { attempt: 1 })));
+        assert!(matches!(states.next().await, Some(SyncState::Terminated { error: Some(_) })));
+
+        _m.assert();
     }
 
     #[tokio::test]
-    async fn left_rooms() {
+    async fn sync_loop_stop_token() {
         let client = logged_in_client().await;
+        let mut states = Box::pin(client.sync_state_stream());
 
         let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
             .with_status(200)
+            .with_body(test_json::SYNC.to_string())
             .match_header("authorization", "Bearer 1234")
-            .with_body(test_json::LEAVE_SYNC.to_string())
+            .expect(1)
             .create();
 
-        let _response = client.sync_once(SyncSettings::default()).await.unwrap();
+        let stop_token = SyncStopToken::new();
+        let config = SyncLoopConfig::new().stop_token(stop_token.clone());
 
-        assert!(client.joined_rooms().is_empty());
-        assert!(!client.left_rooms().is_empty());
-        assert!(client.invited_rooms().is_empty());
+        client
+            .sync_with_config(SyncSettings::new(), config, |_| {
+                let stop_token = stop_token.clone();
+                async move {
+                    stop_token.stop();
+                    LoopCtrl::Continue
+                }
+            })
+            .await
+            .unwrap();
 
-        assert!(client.get_left_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).is_some())
+        assert!(matches!(states.next().await, Some(SyncState::Running)));
+        assert!(matches!(states.next().await, Some(SyncState::Terminated { error: None })));
+
+        _m.assert();
     }
 
     #[tokio::test]
-    async fn sync() {
+    async fn sync_loop_sends_state_changes_only() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
         let client = logged_in_client().await;
+        let mut states = Box::pin(client.sync_state_stream());
 
         let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
             .with_status(200)
             .with_body(test_json::SYNC.to_string())
             .match_header("authorization", "Bearer 1234")
+            .expect(2)
             .create();
 
-        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let syncs = Arc::new(AtomicUsize::new(0));
 
-        let response = client.sync_once(sync_settings).await.unwrap();
+        client
+            .sync_with_config(SyncSettings::new(), SyncLoopConfig::new(), |_| {
+                let syncs = syncs.clone();
+                async move {
+                    if syncs.fetch_add(1, Ordering::SeqCst) == 1 {
+                        LoopCtrl::Break
+                    } else {
+                        LoopCtrl::Continue
+                    }
+                }
+            })
+            .await
+            .unwrap();
 
-        assert_ne!(response.next_batch, "");
+        assert!(matches!(states.next().await, Some(SyncState::Running)));
+        assert!(matches!(states.next().await, Some(SyncState::Terminated { error: None })));
 
-        assert!(client.sync_token().await.is_some());
+        _m.assert();
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let backoff = BackoffStrategy::Exponential {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+        };
+
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(3), Duration::from_secs(4));
+        assert_eq!(backoff.delay(5), Duration::from_secs(10));
+        assert_eq!(backoff.delay(100), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn custom_request() {
+        let client = logged_in_client().await;
+
+        let _m = mock("PUT", "/_matrix/client/unstable/org.example.msc/endpoint?limit=10")
+            .with_status(200)
+            .with_body(r#"{ "chunk": [1, 2] }"#)
+            .match_header("authorization", "Bearer 1234")
+            .match_body(Matcher::Json(json!({ "key": "value" })))
+            .create();
+
+        let response = client
+            .send_custom_request(
+                http::Method::PUT,
+                "/_matrix/client/unstable/org.example.msc/endpoint",
+                &[("limit", "10")],
+                Some(json!({ "key": "value" })),
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response["chunk"], json!([1, 2]));
+
+        let _m = mock("GET", "/_matrix/client/unstable/org.example.msc/endpoint")
+            .with_status(403)
+            .with_body(r#"{ "errcode": "M_FORBIDDEN", "error": "Not an admin" }"#)
+            .create();
+
+        let error = client
+            .send_custom_request(
+                http::Method::GET,
+                "/_matrix/client/unstable/org.example.msc/endpoint",
+                &[],
+                None,
+                None,
+            )
+            .await
+            .unwrap_err();
+
+        if let Error::Http(HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(
+            client_api::Error { kind, .. },
+        )))) = error
+        {
+            assert!(matches!(kind, client_api::error::ErrorKind::Forbidden));
+        } else {
+            panic!("Unexpected error {:?}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_request_with_base_path() {
+        let homeserver = Url::parse(&format!("{}/matrix/", mockito::server_url())).unwrap();
+        let client = Client::new(homeserver).unwrap();
+
+        let _m = mock("GET", "/matrix/_matrix/client/unstable/org.example.msc/endpoint")
+            .with_status(200)
+            .with_body(r#"{ "chunk": [] }"#)
+            .create();
+
+        let response = client
+            .send_custom_request(
+                http::Method::GET,
+                "/_matrix/client/unstable/org.example.msc/endpoint",
+                &[],
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response["chunk"], json!([]));
     }
 
     #[tokio::test]
@@ -3690,6 +7254,7 @@ mod test {
         let room = client.get_joined_room(&room_id!("!SVkFJHzfwvuaIEawgC:localhost")).unwrap();
 
         assert_eq!("tutorial".to_string(), room.display_name().await.unwrap());
+        assert_eq!(Some("tutorial".to_string()), room.cached_display_name());
 
         let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
             .with_status(200)
@@ -3705,6 +7270,160 @@ mod test {
         assert_eq!("My Room Name".to_string(), invited_room.display_name().await.unwrap());
     }
 
+    #[tokio::test]
+    async fn invites() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!696r7674:example.com");
+
+        let mut sync = test_json::INVITE_SYNC.clone();
+        sync["rooms"]["invite"][room_id.as_str()]["invite_state"]["events"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({
+                "sender": "@alice:example.com",
+                "type": "m.room.member",
+                "state_key": "@example:localhost",
+                "content": { "membership": "invite", "is_direct": true }
+            }));
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(sync.to_string())
+            .create();
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/profile/.*alice.*".to_string()))
+            .with_status(200)
+            .with_body(json!({ "displayname": "Alice" }).to_string())
+            .create();
+
+        let reject =
+            mock("POST", Matcher::Regex(r"^/_matrix/client/r0/rooms/.*/leave".to_string()))
+                .with_status(200)
+                .match_header("authorization", "Bearer 1234")
+                .match_body(Matcher::Json(json!({ "reason": "Not interested" })))
+                .with_body("{}")
+                .create();
+
+        let mut invites = client.invite_stream();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let invite = invites.next().await.unwrap();
+        let inviter = invite.inviter().unwrap();
+
+        assert_eq!(invite.room_id(), &room_id);
+        assert_eq!(invite.name().as_deref(), Some("My Room Name"));
+        assert!(invite.is_direct());
+        assert_eq!(inviter.user_id, user_id!("@alice:example.com"));
+        assert_eq!(inviter.display_name.as_deref(), Some("Alice"));
+
+        // The same invitation in a later sync isn't sent again.
+        let _response = client.sync_once(SyncSettings::new()).await.unwrap();
+        assert!(invites.next().now_or_never().is_none());
+
+        invite.reject(Some("Not interested")).await.unwrap();
+        reject.assert();
+    }
+
+    #[tokio::test]
+    async fn unknown_to_device_events() {
+        let client = logged_in_client().await;
+
+        let mut sync = test_json::SYNC.clone();
+        sync["to_device"] = json!({
+            "events": [
+                {
+                    "sender": "@alice:example.com",
+                    "type": "m.dummy",
+                    "content": {}
+                },
+                {
+                    "sender": "@alice:example.com",
+                    "type": "org.example.custom",
+                    "content": { "foo": "bar" }
+                }
+            ]
+        });
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(sync.to_string())
+            .create();
+
+        let mut events = client.unknown_to_device_events_stream();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        let event: serde_json::Value =
+            serde_json::from_str(events.next().await.unwrap().json().get()).unwrap();
+
+        assert_eq!(event["type"], "org.example.custom");
+        assert_eq!(event["content"]["foo"], "bar");
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn encryption_status() {
+        let client = logged_in_client().await;
+
+        let status = client.encryption().status().await.unwrap();
+        assert_eq!(status, crate::encryption_status::EncryptionStatus::default());
+
+        let mut sync = test_json::SYNC.clone();
+        sync["account_data"]["events"].as_array_mut().unwrap().push(json!({
+            "type": "m.secret_storage.default_key",
+            "content": { "key": "key_id" }
+        }));
+
+        let _m = mock("GET", Matcher::Regex(r"^/_matrix/client/r0/sync\?.*$".to_string()))
+            .with_status(200)
+            .match_header("authorization", "Bearer 1234")
+            .with_body(sync.to_string())
+            .create();
+
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(3000));
+        let _response = client.sync_once(sync_settings).await.unwrap();
+
+        assert!(client.encryption().status().await.unwrap().recovery_configured);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn keys_upload_failure_without_matrix_error() {
+        use super::{KeysUploadFailure, OutgoingRequests};
+
+        let client = logged_in_client().await;
+
+        // A proxy in front of the homeserver doesn't respond with a Matrix
+        // error.
+        let _m = mock("POST", "/_matrix/client/r0/keys/upload")
+            .with_status(413)
+            .with_body("<html><body>413 Request Entity Too Large</body></html>")
+            .create();
+
+        let outgoing = client
+            .base_client
+            .outgoing_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|r| matches!(r.request(), OutgoingRequests::KeysUpload(_)))
+            .unwrap();
+        let request = match outgoing.request() {
+            OutgoingRequests::KeysUpload(request) => request,
+            _ => unreachable!(),
+        };
+
+        client.keys_upload(outgoing.request_id(), request).await.unwrap_err();
+
+        let olm = client.base_client.olm_machine().await.unwrap();
+        assert_eq!(olm.keys_upload_diagnostics().last_failure, Some(KeysUploadFailure::TooLarge));
+    }
+
     #[tokio::test]
     async fn delete_devices() {
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
@@ -3835,6 +7554,55 @@ mod test {
         m.assert();
     }
 
+    #[tokio::test]
+    async fn get_authenticated_media_content() {
+        let client = logged_in_client().await;
+
+        let _versions = mock("GET", "/_matrix/client/versions")
+            .with_status(200)
+            .with_body(r#"{ "versions": ["v1.11"] }"#)
+            .create();
+
+        let m = mock("GET", "/_matrix/client/v1/media/download/localhost/textfile")
+            .with_status(200)
+            .with_body("Some very interesting text.")
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        let request = MediaRequest {
+            media_type: MediaType::Uri(mxc_uri!("mxc://localhost/textfile")),
+            format: MediaFormat::File,
+        };
+
+        assert_eq!(
+            client.get_media_content(&request, false).await.unwrap(),
+            b"Some very interesting text."
+        );
+        m.assert();
+    }
+
+    #[tokio::test]
+    async fn upload_too_large() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/media/r0/config")
+            .with_status(200)
+            .with_body(r#"{ "m.upload.size": 10 }"#)
+            .expect(1)
+            .create();
+
+        let upload = mock("POST", "/_matrix/media/r0/upload").expect(0).create();
+
+        assert_eq!(client.media_config().await.unwrap().upload_size, Some(uint!(10)));
+
+        let mut data = Cursor::new("Larger than ten bytes");
+        assert!(matches!(
+            client.upload(&mime::TEXT_PLAIN, &mut data).await,
+            Err(Error::AttachmentTooLarge { max_size }) if max_size == uint!(10)
+        ));
+        upload.assert();
+    }
+
     #[tokio::test]
     async fn get_media_file() {
         let client = logged_in_client().await;
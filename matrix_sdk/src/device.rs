@@ -86,7 +86,17 @@ impl Device {
         &self,
         trust_state: LocalTrust,
     ) -> StdResult<(), CryptoStoreError> {
-        self.inner.set_local_trust(trust_state).await
+        self.inner.set_local_trust(trust_state).await?;
+
+        // A blacklisted device shouldn't be able to decrypt any new messages,
+        // rotate the group sessions it received.
+        if trust_state == LocalTrust::BlackListed {
+            if let Some(olm) = self.client.base_client.olm_machine().await {
+                olm.invalidate_group_sessions_for_device(&self.inner).await?;
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -0,0 +1,114 @@
+//! An overview of the end-to-end encryption setup of the logged in user.
+//!
+//! The [`EncryptionStatus`] collects everything an "Encryption" settings
+//! screen needs to tell the user whether their encrypted messages will stay
+//! readable on their other and future devices.
+
+use futures::{stream, Stream, StreamExt};
+use ruma::events::EventType;
+use tracing::warn;
+
+use crate::{Client, Error, Result};
+
+/// The account data event type pointing to the default secret storage key.
+const SECRET_STORAGE_DEFAULT_KEY: &str = "m.secret_storage.default_key";
+
+/// A summary of the end-to-end encryption setup of the logged in user.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EncryptionStatus {
+    /// Does this device have the private cross signing keys of the user.
+    pub cross_signing_set_up: bool,
+    /// Are room keys uploaded to a server-side key backup.
+    ///
+    /// Room keys are only uploaded to a backup that was verified before it
+    /// got enabled, an enabled backup is a trusted one.
+    pub backup_enabled: bool,
+    /// Does the user have a default secret storage key, which allows a new
+    /// device to recover the cross signing keys and the backup key.
+    pub recovery_configured: bool,
+    /// The number of the user's other devices that aren't verified.
+    pub unverified_own_devices: usize,
+    /// The number of room keys that aren't uploaded to the backup yet.
+    pub keys_not_backed_up: usize,
+}
+
+/// A handle to inspect the end-to-end encryption setup of the logged in user.
+///
+/// Created with [`Client::encryption()`].
+#[derive(Clone, Debug)]
+pub struct Encryption {
+    pub(crate) client: Client,
+}
+
+impl Encryption {
+    /// Get the current encryption status of the logged in user.
+    ///
+    /// Returns an `AuthenticationRequired` error if the client isn't logged
+    /// in.
+    pub async fn status(&self) -> Result<EncryptionStatus> {
+        let olm =
+            self.client.base_client.olm_machine().await.ok_or(Error::AuthenticationRequired)?;
+        let crypto = olm.crypto_status().await?;
+
+        let recovery_configured = self
+            .client
+            .store()
+            .get_account_data_event(EventType::Custom(SECRET_STORAGE_DEFAULT_KEY.to_owned()))
+            .await?
+            .is_some();
+
+        Ok(EncryptionStatus {
+            cross_signing_set_up: crypto.cross_signing_set_up,
+            backup_enabled: crypto.backup_enabled,
+            recovery_configured,
+            unverified_own_devices: crypto.unverified_own_devices,
+            keys_not_backed_up: crypto.keys_not_backed_up,
+        })
+    }
+
+    /// Get a stream of the changes of the encryption status.
+    ///
+    /// The status is re-evaluated when the state of the sync loop changes,
+    /// after our keys were uploaded, after the key backup changed and after
+    /// every sync that the encryption state machine processed. It's sent out
+    /// if it differs from the previously sent one.
+    ///
+    /// The client needs to be logged in when the stream is created, otherwise
+    /// only sync state changes will re-evaluate the status.
+    pub fn status_stream(&self) -> impl Stream<Item = EncryptionStatus> {
+        let client = self.client.clone();
+
+        let crypto_changes = stream::once(async move {
+            match client.base_client.olm_machine().await {
+                Some(olm) => olm.crypto_status_changes_stream().left_stream(),
+                None => stream::empty().right_stream(),
+            }
+        })
+        .flatten();
+
+        let sync_changes = self.client.sync_state_stream().map(|_| ());
+        let triggers = Box::pin(stream::select(sync_changes, crypto_changes));
+
+        stream::unfold(
+            (triggers, self.clone(), None),
+            |(mut triggers, encryption, mut last)| async move {
+                loop {
+                    triggers.next().await?;
+
+                    let status = match encryption.status().await {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!("Failed to get the encryption status: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if last.as_ref() != Some(&status) {
+                        last = Some(status.clone());
+                        return Some((status, (triggers, encryption, last)));
+                    }
+                }
+            },
+        )
+    }
+}
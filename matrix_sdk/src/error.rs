@@ -19,7 +19,7 @@ use std::io::Error as IoError;
 use http::StatusCode;
 #[cfg(feature = "encryption")]
 use matrix_sdk_base::crypto::{store::CryptoStoreError, DecryptorError};
-use matrix_sdk_base::{Error as MatrixError, StoreError};
+use matrix_sdk_base::{Error as MatrixError, PowerLevelsError, StoreError};
 use reqwest::Error as ReqwestError;
 use ruma::{
     api::{
@@ -29,7 +29,9 @@ use ruma::{
         },
         error::{FromHttpResponseError, IntoHttpError, MatrixError as RumaApiError, ServerError},
     },
-    identifiers::Error as IdentifierError,
+    events::EventType,
+    identifiers::{Error as IdentifierError, EventId, RoomId},
+    UInt,
 };
 use serde_json::Error as JsonError;
 use thiserror::Error;
@@ -89,6 +91,10 @@ pub enum HttpError {
     #[error("The request cannot be cloned")]
     UnableToCloneRequest,
 
+    /// The HTTP request couldn't be built, e.g. because of an invalid URL.
+    #[error(transparent)]
+    Build(#[from] http::Error),
+
     /// Tried to send a request without `user_id` in the `Session`
     #[error("missing user_id in session")]
     UserIdRequired,
@@ -139,6 +145,55 @@ pub enum Error {
     /// An error encountered when trying to parse a url.
     #[error(transparent)]
     Url(#[from] UrlParseError),
+
+    /// The room requires events to be encrypted but the event couldn't be
+    /// encrypted, e.g. because the encryption feature is disabled.
+    ///
+    /// Use `send_unencrypted_override()` if the event should be sent in the
+    /// clear nevertheless.
+    #[error("refusing to send an unencrypted event to the encrypted room {0}")]
+    EncryptionRequired(RoomId),
+
+    /// The homeserver URL that was found during homeserver discovery isn't a
+    /// valid `http` or `https` URL.
+    #[error("the discovered homeserver URL {0} is invalid")]
+    InvalidHomeserverUrl(String),
+
+    /// The homeserver disabled the capability that is needed for the request.
+    #[error("the homeserver disabled the {0} capability")]
+    CapabilityDisabled(&'static str),
+
+    /// The attachment is larger than the maximum upload size of the homeserver.
+    #[error("the attachment exceeds the maximum upload size of {max_size} bytes")]
+    AttachmentTooLarge {
+        /// The maximum upload size in bytes.
+        max_size: UInt,
+    },
+
+    /// Our own user isn't allowed to change the power levels of the room in
+    /// the requested way.
+    #[error(transparent)]
+    PowerLevels(#[from] PowerLevelsError),
+
+    /// Our own user doesn't have the power level that is needed to redact the
+    /// event.
+    #[error("not allowed to redact the event {0}")]
+    RedactionNotAllowed(EventId),
+
+    /// Our own user doesn't have the power level that is needed to send a
+    /// state event of the given type.
+    #[error("not allowed to send a {0} state event")]
+    StateEventNotAllowed(EventType),
+
+    /// A [`SendInterceptor`](crate::SendInterceptor) refused to let the
+    /// event be sent.
+    #[error("sending the event was vetoed: {0}")]
+    SendVetoed(String),
+
+    /// The encryption event we sent didn't come back in a sync in time, e.g.
+    /// because no sync loop is running.
+    #[error("timed out waiting for the room to become encrypted")]
+    EncryptionTimeout,
 }
 
 impl Error {
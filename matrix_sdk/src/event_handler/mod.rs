@@ -40,6 +40,7 @@ use ruma::{
             redaction::SyncRedactionEvent,
             tombstone::TombstoneEventContent,
         },
+        tag::TagEventContent,
         typing::TypingEventContent,
         AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
         AnySyncEphemeralRoomEvent, AnySyncMessageEvent, AnySyncRoomEvent, AnySyncStateEvent,
@@ -242,8 +243,12 @@ impl Handler {
         room: Room,
         event: &AnyRoomAccountDataEvent,
     ) {
-        if let AnyRoomAccountDataEvent::FullyRead(event) = event {
-            self.on_non_room_fully_read(room, event).await
+        match event {
+            AnyRoomAccountDataEvent::FullyRead(event) => {
+                self.on_non_room_fully_read(room, event).await
+            }
+            AnyRoomAccountDataEvent::Tag(event) => self.on_non_room_tags(room, event).await,
+            _ => {}
         }
     }
 
@@ -468,6 +473,9 @@ pub trait EventHandler: Send + Sync {
         _: &RoomAccountDataEvent<FullyReadEventContent>,
     ) {
     }
+    /// Fires when `Client` receives a `m.tag` room account data event, i.e.
+    /// when the tags of a room changed.
+    async fn on_non_room_tags(&self, _: Room, _: &RoomAccountDataEvent<TagEventContent>) {}
     /// Fires when `Client` receives a `NonRoomEvent::Typing` event.
     async fn on_non_room_typing(&self, _: Room, _: &SyncEphemeralRoomEvent<TypingEventContent>) {}
     /// Fires when `Client` receives a `NonRoomEvent::Receipt` event.
@@ -501,7 +509,7 @@ mod test {
     use matrix_sdk_common::{async_trait, locks::Mutex};
     use matrix_sdk_test::{async_test, test_json};
     use mockito::{mock, Matcher};
-    use ruma::user_id;
+    use ruma::{room_id, user_id};
     #[cfg(target_arch = "wasm32")]
     pub use wasm_bindgen_test::*;
 
@@ -679,6 +687,9 @@ mod test {
         ) {
             self.0.lock().await.push("account read".to_string())
         }
+        async fn on_non_room_tags(&self, _: Room, _: &RoomAccountDataEvent<TagEventContent>) {
+            self.0.lock().await.push("account tags".to_string())
+        }
         async fn on_non_room_typing(
             &self,
             _: Room,
@@ -707,7 +718,7 @@ mod test {
         }
     }
 
-    use crate::{Client, Session, SyncSettings};
+    use crate::{Client, Session, SyncSettings, TagName};
 
     async fn get_client() -> Client {
         let session = Session {
@@ -884,4 +895,30 @@ mod test {
             ],
         )
     }
+
+    #[async_test]
+    async fn event_handler_tags() {
+        let vec = Arc::new(Mutex::new(Vec::new()));
+        let test_vec = Arc::clone(&vec);
+        let handler = Box::new(EvHandlerTest(vec));
+
+        let room_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let mut sync = test_json::SYNC.clone();
+        sync["rooms"]["join"][room_id.as_str()]["account_data"]["events"]
+            .as_array_mut()
+            .unwrap()
+            .push(test_json::events::TAG.clone());
+
+        let client = get_client().await;
+        client.set_event_handler(handler).await;
+        mock_sync(&client, sync.to_string()).await;
+
+        let v = test_vec.lock().await;
+        assert!(v.contains(&"account tags".to_string()));
+
+        let room = client.get_joined_room(&room_id).unwrap();
+        assert!(!room.is_favourite().await.unwrap());
+        assert_eq!(room.user_tags().await.unwrap(), [TagName::User("u.work".to_owned())]);
+        assert_eq!(room.tag_order(&TagName::from("u.work")).await.unwrap(), Some(0.9));
+    }
 }
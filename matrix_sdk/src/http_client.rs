@@ -12,16 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(all(not(target_arch = "wasm32")))]
-use std::sync::atomic::{AtomicU64, Ordering};
 use std::{convert::TryFrom, fmt::Debug, sync::Arc};
 
 #[cfg(all(not(target_arch = "wasm32")))]
-use backoff::{future::retry, Error as RetryError, ExponentialBackoff};
+use backoff::{backoff::Backoff, Error as RetryError, ExponentialBackoff};
 #[cfg(all(not(target_arch = "wasm32")))]
 use http::StatusCode;
 use http::{HeaderValue, Response as HttpResponse};
-use matrix_sdk_common::{async_trait, locks::RwLock, AsyncTraitDeps};
+use matrix_sdk_common::{async_trait, instant::Instant, locks::RwLock, metrics, AsyncTraitDeps};
 use reqwest::{Client, Response};
 use ruma::api::{
     client::r0::media::create_content, error::FromHttpResponseError, AuthScheme, IncomingResponse,
@@ -30,7 +28,11 @@ use ruma::api::{
 use tracing::trace;
 use url::Url;
 
-use crate::{error::HttpError, Bytes, BytesMut, ClientConfig, RequestConfig, Session};
+use crate::{
+    error::HttpError,
+    runtime::{DefaultRuntime, Runtime},
+    Bytes, BytesMut, ClientConfig, RequestConfig, Session,
+};
 
 /// Abstraction around the http layer. The allows implementors to use different
 /// http libraries.
@@ -128,7 +130,11 @@ impl HttpClient {
             self.try_into_http_request_with_identity_assertion(request, session, config).await?
         };
 
-        self.inner.send_request(request, config).await
+        let now = Instant::now();
+        let response = self.inner.send_request(request, config).await;
+        metrics::record_request(Request::METADATA.name, now.elapsed(), response.is_ok());
+
+        response
     }
 
     async fn try_into_http_request<Request: OutgoingRequest>(
@@ -227,6 +233,73 @@ impl HttpClient {
 
         Ok(response)
     }
+
+    /// Send a request without converting the response, the response is
+    /// returned as is, whatever its status code is.
+    pub(crate) async fn send_raw<Request>(
+        &self,
+        request: Request,
+        config: Option<RequestConfig>,
+    ) -> Result<http::Response<Bytes>, HttpError>
+    where
+        Request: OutgoingRequest + Debug,
+    {
+        let response = self.send_request(request, self.session.clone(), config).await?;
+
+        trace!("Got response: {:?}", response);
+
+        Ok(response)
+    }
+
+    /// Send a request to an endpoint of the homeserver that ruma doesn't
+    /// know about.
+    ///
+    /// The access token is attached if we're logged in, the response is
+    /// returned as is, whatever its status code is.
+    pub async fn send_custom(
+        &self,
+        method: http::Method,
+        path: &str,
+        query: &[(&str, &str)],
+        body: Option<Bytes>,
+        config: Option<RequestConfig>,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        let config = match config {
+            Some(config) => config,
+            None => self.request_config,
+        };
+
+        let mut url = self.homeserver.read().await.clone();
+        // The homeserver might be hosted under a base path, keep it.
+        let path = format!("{}/{}", url.path().trim_end_matches('/'), path.trim_start_matches('/'));
+        url.set_path(&path);
+
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+
+        let mut request = http::Request::builder().method(method).uri(url.as_str());
+
+        if let Some(session) = self.session.read().await.as_ref() {
+            request = request
+                .header(http::header::AUTHORIZATION, format!("Bearer {}", session.access_token));
+        } else if config.force_auth {
+            return Err(HttpError::ForcedAuthenticationWithoutAccessToken);
+        }
+
+        let request = match body {
+            Some(body) => {
+                request.header(http::header::CONTENT_TYPE, "application/json").body(body)?
+            }
+            None => request.body(Bytes::new())?,
+        };
+
+        let now = Instant::now();
+        let response = self.inner.send_request(request, config).await;
+        metrics::record_request("custom", now.elapsed(), response.is_ok());
+
+        response
+    }
 }
 
 /// Build a client with the specified configuration.
@@ -290,6 +363,7 @@ async fn send_request(
     client: &Client,
     request: http::Request<Bytes>,
     _: RequestConfig,
+    _: &dyn Runtime,
 ) -> Result<http::Response<Bytes>, HttpError> {
     let request = reqwest::Request::try_from(request)?;
     let response = client.execute(request).await?;
@@ -302,51 +376,58 @@ async fn send_request(
     client: &Client,
     request: http::Request<Bytes>,
     config: RequestConfig,
+    runtime: &dyn Runtime,
 ) -> Result<http::Response<Bytes>, HttpError> {
     let mut backoff = ExponentialBackoff::default();
     let mut request = reqwest::Request::try_from(request)?;
     let retry_limit = config.retry_limit;
-    let retry_count = AtomicU64::new(1);
+    let mut retry_count = 1;
 
     *request.timeout_mut() = Some(config.timeout);
 
     backoff.max_elapsed_time = config.retry_timeout;
+    backoff.reset();
+
+    loop {
+        let stop = retry_limit.map_or(false, |retry_limit| retry_count >= retry_limit);
+        retry_count += 1;
+
+        match send_request_once(client, &request, stop).await {
+            Ok(response) => return Ok(response),
+            Err(RetryError::Permanent(e)) => return Err(e),
+            Err(RetryError::Transient(e)) => match backoff.next_backoff() {
+                Some(duration) => runtime.sleep(duration).await,
+                None => return Err(e),
+            },
+        }
+    }
+}
 
-    let request = &request;
-    let retry_count = &retry_count;
-
-    let request = || async move {
-        let stop = if let Some(retry_limit) = retry_limit {
-            retry_count.fetch_add(1, Ordering::Relaxed) >= retry_limit
-        } else {
-            false
-        };
-
-        // Turn errors into permanent errors when the retry limit is reached
-        let error_type = if stop { RetryError::Permanent } else { RetryError::Transient };
-
-        let request = request.try_clone().ok_or(HttpError::UnableToCloneRequest)?;
-
-        let response =
-            client.execute(request).await.map_err(|e| error_type(HttpError::Reqwest(e)))?;
+#[cfg(all(not(target_arch = "wasm32")))]
+async fn send_request_once(
+    client: &Client,
+    request: &reqwest::Request,
+    stop: bool,
+) -> Result<http::Response<Bytes>, RetryError<HttpError>> {
+    // Turn errors into permanent errors when the retry limit is reached
+    let error_type = if stop { RetryError::Permanent } else { RetryError::Transient };
 
-        let status_code = response.status();
-        // TODO TOO_MANY_REQUESTS will have a retry timeout which we should
-        // use.
-        if !stop
-            && (status_code.is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS)
-        {
-            return Err(error_type(HttpError::Server(status_code)));
-        }
+    let request = request.try_clone().ok_or(HttpError::UnableToCloneRequest)?;
 
-        let response = response_to_http_response(response)
-            .await
-            .map_err(|e| RetryError::Permanent(HttpError::Reqwest(e)))?;
+    let response = client.execute(request).await.map_err(|e| error_type(HttpError::Reqwest(e)))?;
 
-        Ok(response)
-    };
+    let status_code = response.status();
+    // TODO TOO_MANY_REQUESTS will have a retry timeout which we should
+    // use.
+    if !stop
+        && (status_code.is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS)
+    {
+        return Err(error_type(HttpError::Server(status_code)));
+    }
 
-    let response = retry(backoff, request).await?;
+    let response = response_to_http_response(response)
+        .await
+        .map_err(|e| RetryError::Permanent(HttpError::Reqwest(e)))?;
 
     Ok(response)
 }
@@ -359,6 +440,26 @@ impl HttpSend for Client {
         request: http::Request<Bytes>,
         config: RequestConfig,
     ) -> Result<http::Response<Bytes>, HttpError> {
-        send_request(self, request, config).await
+        send_request(self, request, config, &DefaultRuntime).await
+    }
+}
+
+/// The default HTTP client, a `reqwest` client that waits between retries
+/// using the runtime of the `Client`.
+#[derive(Debug)]
+pub(crate) struct RuntimeHttpClient {
+    pub(crate) inner: Client,
+    pub(crate) runtime: Arc<dyn Runtime>,
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl HttpSend for RuntimeHttpClient {
+    async fn send_request(
+        &self,
+        request: http::Request<Bytes>,
+        config: RequestConfig,
+    ) -> Result<http::Response<Bytes>, HttpError> {
+        send_request(&self.inner, request, config, &*self.runtime).await
     }
 }
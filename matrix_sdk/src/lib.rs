@@ -53,6 +53,10 @@
 //! default.
 //! * `appservice`: Enables low-level appservice functionality. For an
 //!   high-level API there's the `matrix-sdk-appservice` crate
+//! * `async-std`: Provides the [`AsyncStdRuntime`] to run the client on the
+//!   `async-std` runtime.
+//! * `synapse-admin`: Enables helpers for the admin API of Synapse, see the
+//!   [`synapse_admin`] module.
 
 #![deny(
     missing_debug_implementations,
@@ -78,10 +82,12 @@ compile_error!("'sso_login' cannot be enabled on 'wasm32' arch");
 pub use bytes::{Bytes, BytesMut};
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
-pub use matrix_sdk_base::crypto::{EncryptionInfo, LocalTrust};
+pub use matrix_sdk_base::crypto::{EncryptionInfo, LocalTrust, RoomKeySharingProgress};
 pub use matrix_sdk_base::{
-    media, Error as BaseError, Room as BaseRoom, RoomInfo, RoomMember as BaseRoomMember, RoomType,
-    Session, StateChanges, StoreError,
+    image_pack, media, AllowRule, DynStateStore, Error as BaseError, EventProcessor,
+    IntoStateStore, LocalEcho, LocalEchoState, PowerLevelsBuilder, PowerLevelsError,
+    Room as BaseRoom, RoomInfo, RoomMember as BaseRoomMember, RoomPowerLevels, RoomType, Session,
+    StateChange, StateChanges, StateSnapshot, StateStore, StoreError, TagName,
 };
 pub use matrix_sdk_common::*;
 pub use reqwest;
@@ -103,33 +109,62 @@ pub use ruma::{
     thirdparty, uint, Int, MilliSecondsSinceUnixEpoch, Outgoing, SecondsSinceUnixEpoch, UInt,
 };
 
+pub mod account;
 mod client;
 mod error;
 mod event_handler;
 mod http_client;
+mod media_repo;
+pub mod notification_settings;
+pub mod policy_list;
+mod pusher;
+mod registration;
 /// High-level room API
 pub mod room;
 /// High-level room API
 mod room_member;
+mod room_preview;
+mod runtime;
+mod send_interceptors;
+#[cfg(feature = "synapse-admin")]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "synapse-admin")))]
+pub mod synapse_admin;
+mod sync_hooks;
 
 #[cfg(feature = "encryption")]
 mod device;
 #[cfg(feature = "encryption")]
+#[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+pub mod encryption_status;
+#[cfg(feature = "encryption")]
 mod sas;
 #[cfg(feature = "encryption")]
 mod verification_request;
 
-pub use client::{Client, ClientConfig, LoopCtrl, RequestConfig, SyncSettings};
+pub use client::{
+    BackoffStrategy, Client, ClientConfig, LoopCtrl, RequestConfig, SyncLoopConfig, SyncSettings,
+    SyncState, SyncStopToken,
+};
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub use device::Device;
 pub use error::{Error, HttpError, Result};
 pub use event_handler::{CustomEvent, EventHandler};
 pub use http_client::HttpSend;
+pub use media_repo::MediaConfig;
+pub use pusher::HttpPusherConfig;
+pub use registration::{RegistrationStage, ThirdPartyCredentials};
 pub use room_member::RoomMember;
+pub use room_preview::RoomPreview;
+#[cfg(all(feature = "async-std", not(target_arch = "wasm32")))]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "async-std")))]
+pub use runtime::AsyncStdRuntime;
+pub use runtime::{DefaultRuntime, Runtime, RuntimeFuture};
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub use sas::Sas;
+pub use send_interceptors::{SendDecision, SendInterceptor};
+pub use sync_hooks::SyncHook;
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub use verification_request::VerificationRequest;
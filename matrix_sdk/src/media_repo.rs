@@ -0,0 +1,121 @@
+//! Endpoints of the authenticated media repository.
+//!
+//! Homeservers that support at least version 1.11 of the spec serve media
+//! behind `/_matrix/client/v1/media`, requiring an access token. The legacy
+//! endpoints under `/_matrix/media/r0` are used for all other homeservers.
+
+use ruma::UInt;
+
+/// The configuration of the media repository of the homeserver.
+#[derive(Clone, Debug, Default)]
+pub struct MediaConfig {
+    /// The maximum size of an upload in bytes, `None` if the homeserver
+    /// doesn't advertise a limit.
+    pub upload_size: Option<UInt>,
+}
+
+pub(crate) mod get_media_config {
+    //! [GET /_matrix/client/v1/media/config](https://spec.matrix.org/v1.11/client-server-api/#get_matrixclientv1mediaconfig)
+
+    use ruma::{api::ruma_api, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Gets the config for the media repository.",
+            method: GET,
+            name: "get_media_config",
+            path: "/_matrix/client/v1/media/config",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {}
+
+        response: {
+            /// Maximum size of upload in bytes.
+            #[serde(rename = "m.upload.size")]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub upload_size: Option<UInt>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+pub(crate) mod get_content {
+    //! [GET /_matrix/client/v1/media/download/{serverName}/{mediaId}](https://spec.matrix.org/v1.11/client-server-api/#get_matrixclientv1mediadownloadservernamemediaid)
+
+    use ruma::{api::ruma_api, ServerName};
+
+    ruma_api! {
+        metadata: {
+            description: "Retrieve content from the media store.",
+            method: GET,
+            name: "get_media_content",
+            path: "/_matrix/client/v1/media/download/:server_name/:media_id",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The server name from the mxc:// URI (the authoritory component).
+            #[ruma_api(path)]
+            pub server_name: &'a ServerName,
+
+            /// The media ID from the mxc:// URI (the path component).
+            #[ruma_api(path)]
+            pub media_id: &'a str,
+        }
+
+        response: {
+            /// The content that was previously uploaded.
+            #[ruma_api(raw_body)]
+            pub file: Vec<u8>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+pub(crate) mod get_content_thumbnail {
+    //! [GET /_matrix/client/v1/media/thumbnail/{serverName}/{mediaId}](https://spec.matrix.org/v1.11/client-server-api/#get_matrixclientv1mediathumbnailservernamemediaid)
+
+    use ruma::{api::ruma_api, ServerName, UInt};
+
+    ruma_api! {
+        metadata: {
+            description: "Get a thumbnail of content from the media store.",
+            method: GET,
+            name: "get_media_content_thumbnail",
+            path: "/_matrix/client/v1/media/thumbnail/:server_name/:media_id",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The server name from the mxc:// URI (the authoritory component).
+            #[ruma_api(path)]
+            pub server_name: &'a ServerName,
+
+            /// The media ID from the mxc:// URI (the path component).
+            #[ruma_api(path)]
+            pub media_id: &'a str,
+
+            /// The *desired* width of the thumbnail.
+            #[ruma_api(query)]
+            pub width: UInt,
+
+            /// The *desired* height of the thumbnail.
+            #[ruma_api(query)]
+            pub height: UInt,
+        }
+
+        response: {
+            /// A thumbnail of the requested content.
+            #[ruma_api(raw_body)]
+            pub file: Vec<u8>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
@@ -0,0 +1,473 @@
+//! Editing of the push rules of the logged in user.
+//!
+//! Changes are applied to the push rules in the state store right away, so
+//! the notifications of the following events are evaluated with them even
+//! before the homeserver confirmed the change. If a request fails the rule it
+//! changed is restored, and the push rules the homeserver sends in the next
+//! sync always replace the local ones.
+
+use matrix_sdk_base::StateChanges;
+use ruma::{
+    api::client::r0::push::{delete_pushrule, set_pushrule, set_pushrule_enabled, RuleKind},
+    assign,
+    events::{AnyGlobalAccountDataEvent, EventType},
+    push::{Action, PushCondition, Ruleset, Tweak},
+    serde::Raw,
+    RoomId,
+};
+use serde_json::{json, value::to_raw_value, Value as JsonValue};
+use tracing::warn;
+
+use crate::{Client, Error, Result};
+
+/// The scope of the push rules that apply to all the devices of the user.
+const GLOBAL_SCOPE: &str = "global";
+
+/// The server-default rule that disables all notifications, it takes priority
+/// over the rules the user creates.
+const MASTER_RULE_ID: &str = ".m.rule.master";
+
+/// How the user is notified about the messages of a room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomNotificationMode {
+    /// Every message of the room triggers a notification.
+    AllMessages,
+    /// Only messages that mention the user or contain one of their keywords
+    /// trigger a notification.
+    MentionsAndKeywords,
+    /// No message of the room triggers a notification.
+    Mute,
+}
+
+/// A change of a push rule, it's applied to the local push rules first and
+/// then sent to the homeserver.
+#[derive(Clone, Debug)]
+enum RuleCommand {
+    Set {
+        kind: RuleKind,
+        rule_id: String,
+        actions: Vec<Action>,
+        conditions: Vec<PushCondition>,
+        pattern: Option<String>,
+    },
+    Delete {
+        kind: RuleKind,
+        rule_id: String,
+    },
+    SetEnabled {
+        kind: RuleKind,
+        rule_id: String,
+        enabled: bool,
+    },
+}
+
+/// The state of a rule before a [`RuleCommand`] was applied, used to undo the
+/// command if the homeserver rejects it.
+#[derive(Clone, Debug)]
+struct RuleUndo {
+    kind: RuleKind,
+    rule_id: String,
+    /// The position and the content of the rule before the command was
+    /// applied, `None` if the rule didn't exist.
+    previous: Option<(usize, JsonValue)>,
+    /// The content of the rule after the command was applied.
+    applied: Option<JsonValue>,
+}
+
+/// The `m.push_rules` account data event, kept as JSON so rules of any kind
+/// can be modified the same way.
+#[derive(Clone, Debug)]
+struct CachedRules {
+    event: JsonValue,
+}
+
+impl CachedRules {
+    fn rules(&self, kind: &RuleKind) -> &[JsonValue] {
+        self.event["content"]["global"][kind.as_ref()]
+            .as_array()
+            .map(|r| r.as_slice())
+            .unwrap_or(&[])
+    }
+
+    fn rules_mut(&mut self, kind: &RuleKind) -> &mut Vec<JsonValue> {
+        let rules = &mut self.event["content"]["global"][kind.as_ref()];
+
+        if !rules.is_array() {
+            *rules = JsonValue::Array(Vec::new());
+        }
+
+        match rules {
+            JsonValue::Array(rules) => rules,
+            _ => unreachable!("The rules were replaced by an array"),
+        }
+    }
+
+    fn find(&self, kind: &RuleKind, rule_id: &str) -> Option<&JsonValue> {
+        self.rules(kind).iter().find(|r| r["rule_id"] == rule_id)
+    }
+
+    fn position(&self, kind: &RuleKind, rule_id: &str) -> Option<usize> {
+        self.rules(kind).iter().position(|r| r["rule_id"] == rule_id)
+    }
+
+    /// Apply the command and return what's needed to undo it.
+    fn apply(&mut self, command: &RuleCommand) -> Result<RuleUndo> {
+        let (kind, rule_id) = match command {
+            RuleCommand::Set { kind, rule_id, .. }
+            | RuleCommand::Delete { kind, rule_id }
+            | RuleCommand::SetEnabled { kind, rule_id, .. } => (kind, rule_id),
+        };
+
+        let previous = self
+            .position(kind, rule_id)
+            .map(|position| (position, self.rules(kind)[position].clone()));
+
+        self.apply_command(command)?;
+
+        Ok(RuleUndo {
+            kind: kind.clone(),
+            rule_id: rule_id.clone(),
+            previous,
+            applied: self.find(kind, rule_id).cloned(),
+        })
+    }
+
+    /// Restore the rule a command changed.
+    ///
+    /// The rule is left alone if it was changed since the command was
+    /// applied, e.g. by a sync, the newer rule wins then.
+    fn undo(&mut self, undo: &RuleUndo) {
+        if self.find(&undo.kind, &undo.rule_id) != undo.applied.as_ref() {
+            return;
+        }
+
+        let rules = self.rules_mut(&undo.kind);
+        rules.retain(|r| r["rule_id"] != undo.rule_id.as_str());
+
+        if let Some((position, rule)) = &undo.previous {
+            rules.insert((*position).min(rules.len()), rule.clone());
+        }
+    }
+
+    fn apply_command(&mut self, command: &RuleCommand) -> Result<()> {
+        match command {
+            RuleCommand::Set { kind, rule_id, actions, conditions, pattern } => {
+                let mut rule = json!({
+                    "rule_id": rule_id,
+                    "default": false,
+                    "enabled": true,
+                    "actions": actions,
+                });
+
+                if !conditions.is_empty() {
+                    rule["conditions"] = serde_json::to_value(conditions)?;
+                }
+
+                if let Some(pattern) = pattern {
+                    rule["pattern"] = pattern.as_str().into();
+                }
+
+                let rules = self.rules_mut(kind);
+
+                if let Some(existing) = rules.iter_mut().find(|r| r["rule_id"] == rule_id.as_str())
+                {
+                    // Updating a rule keeps its priority.
+                    *existing = rule;
+                } else {
+                    // Rules the user creates take priority over the other
+                    // rules of the same kind, except for the master rule.
+                    let position = rules
+                        .iter()
+                        .position(|r| r["rule_id"] == MASTER_RULE_ID)
+                        .map_or(0, |p| p + 1);
+                    rules.insert(position, rule);
+                }
+            }
+            RuleCommand::Delete { kind, rule_id } => {
+                self.rules_mut(kind).retain(|r| r["rule_id"] != rule_id.as_str());
+            }
+            RuleCommand::SetEnabled { kind, rule_id, enabled } => {
+                if let Some(rule) =
+                    self.rules_mut(kind).iter_mut().find(|r| r["rule_id"] == rule_id.as_str())
+                {
+                    rule["enabled"] = (*enabled).into();
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A handle to edit the push rules of the logged in user.
+///
+/// Created with [`Client::notification_settings()`].
+#[derive(Clone, Debug)]
+pub struct NotificationSettings {
+    pub(crate) client: Client,
+}
+
+impl NotificationSettings {
+    /// Get the notification mode of the given room.
+    ///
+    /// Returns `None` if the user didn't choose a mode for the room, the
+    /// default push rules apply to it then.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room the mode should be fetched for.
+    pub async fn room_notification_mode(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<RoomNotificationMode>> {
+        let rules = self.cached_rules().await?;
+        let enabled = |rule: &&JsonValue| rule["enabled"] != false;
+
+        if rules.find(&RuleKind::Override, room_id.as_str()).filter(enabled).is_some() {
+            return Ok(Some(RoomNotificationMode::Mute));
+        }
+
+        Ok(rules.find(&RuleKind::Room, room_id.as_str()).filter(enabled).map(|rule| {
+            let notifies = rule["actions"]
+                .as_array()
+                .map_or(false, |actions| actions.iter().any(|a| a == "notify"));
+
+            if notifies {
+                RoomNotificationMode::AllMessages
+            } else {
+                RoomNotificationMode::MentionsAndKeywords
+            }
+        }))
+    }
+
+    /// Change the notification mode of the given room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room the mode should be changed for.
+    ///
+    /// * `mode` - The new mode of the room, `None` removes the rules of the
+    /// room so the default push rules apply to it.
+    pub async fn set_room_notification_mode(
+        &self,
+        room_id: &RoomId,
+        mode: Option<RoomNotificationMode>,
+    ) -> Result<()> {
+        let rules = self.cached_rules().await?;
+        let rule_id = room_id.as_str();
+        let mut commands = Vec::new();
+
+        if mode != Some(RoomNotificationMode::Mute)
+            && rules.find(&RuleKind::Override, rule_id).is_some()
+        {
+            commands
+                .push(RuleCommand::Delete { kind: RuleKind::Override, rule_id: rule_id.into() });
+        }
+
+        match mode {
+            Some(RoomNotificationMode::AllMessages) => commands.push(RuleCommand::Set {
+                kind: RuleKind::Room,
+                rule_id: rule_id.into(),
+                actions: vec![Action::Notify, Action::SetTweak(Tweak::Sound("default".into()))],
+                conditions: Vec::new(),
+                pattern: None,
+            }),
+            Some(RoomNotificationMode::MentionsAndKeywords) => commands.push(RuleCommand::Set {
+                kind: RuleKind::Room,
+                rule_id: rule_id.into(),
+                actions: vec![Action::DontNotify],
+                conditions: Vec::new(),
+                pattern: None,
+            }),
+            Some(RoomNotificationMode::Mute) => commands.push(RuleCommand::Set {
+                kind: RuleKind::Override,
+                rule_id: rule_id.into(),
+                actions: vec![Action::DontNotify],
+                conditions: vec![PushCondition::EventMatch {
+                    key: "room_id".into(),
+                    pattern: rule_id.into(),
+                }],
+                pattern: None,
+            }),
+            None => {}
+        }
+
+        let room_rule_kept = matches!(
+            mode,
+            Some(RoomNotificationMode::AllMessages)
+                | Some(RoomNotificationMode::MentionsAndKeywords)
+        );
+
+        if !room_rule_kept && rules.find(&RuleKind::Room, rule_id).is_some() {
+            commands.push(RuleCommand::Delete { kind: RuleKind::Room, rule_id: rule_id.into() });
+        }
+
+        self.run(rules, commands).await
+    }
+
+    /// Get the keywords that trigger a notification when they are found in
+    /// the body of a message.
+    pub async fn keywords(&self) -> Result<Vec<String>> {
+        let rules = self.cached_rules().await?;
+
+        Ok(rules
+            .rules(&RuleKind::Content)
+            .iter()
+            .filter(|r| r["default"] != true)
+            .filter_map(|r| r["pattern"].as_str().map(ToOwned::to_owned))
+            .collect())
+    }
+
+    /// Get notified about messages containing the given keyword.
+    ///
+    /// # Arguments
+    ///
+    /// * `keyword` - The keyword, it can contain the glob-style wildcards `*`
+    /// and `?`.
+    pub async fn add_keyword(&self, keyword: &str) -> Result<()> {
+        let rules = self.cached_rules().await?;
+        let command = RuleCommand::Set {
+            kind: RuleKind::Content,
+            rule_id: keyword.to_owned(),
+            actions: vec![
+                Action::Notify,
+                Action::SetTweak(Tweak::Sound("default".into())),
+                Action::SetTweak(Tweak::Highlight(false)),
+            ],
+            conditions: Vec::new(),
+            pattern: Some(keyword.to_owned()),
+        };
+
+        self.run(rules, vec![command]).await
+    }
+
+    /// Stop getting notified about messages containing the given keyword.
+    ///
+    /// # Arguments
+    ///
+    /// * `keyword` - The keyword that was added with
+    /// [`add_keyword()`](#method.add_keyword).
+    pub async fn remove_keyword(&self, keyword: &str) -> Result<()> {
+        let rules = self.cached_rules().await?;
+
+        let commands = rules
+            .rules(&RuleKind::Content)
+            .iter()
+            .filter(|r| r["default"] != true && r["pattern"] == keyword)
+            .filter_map(|r| r["rule_id"].as_str())
+            .map(|rule_id| RuleCommand::Delete { kind: RuleKind::Content, rule_id: rule_id.into() })
+            .collect();
+
+        self.run(rules, commands).await
+    }
+
+    /// Enable or disable a push rule, e.g. one of the built-in rules like
+    /// `.m.rule.contains_display_name`.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - The kind of the rule.
+    ///
+    /// * `rule_id` - The id of the rule.
+    ///
+    /// * `enabled` - Whether the rule should be enabled.
+    pub async fn set_rule_enabled(
+        &self,
+        kind: RuleKind,
+        rule_id: &str,
+        enabled: bool,
+    ) -> Result<()> {
+        let rules = self.cached_rules().await?;
+        let command = RuleCommand::SetEnabled { kind, rule_id: rule_id.to_owned(), enabled };
+
+        self.run(rules, vec![command]).await
+    }
+
+    /// Get the push rules of the state store, or the default push rules if
+    /// the store doesn't know any.
+    async fn cached_rules(&self) -> Result<CachedRules> {
+        let event = match self.client.store().get_account_data_event(EventType::PushRules).await? {
+            Some(event) => serde_json::from_str(event.json().get())?,
+            None => {
+                let user_id = self.client.user_id().await.ok_or(Error::AuthenticationRequired)?;
+
+                json!({
+                    "type": EventType::PushRules.as_str(),
+                    "content": { "global": Ruleset::server_default(&user_id) },
+                })
+            }
+        };
+
+        Ok(CachedRules { event })
+    }
+
+    async fn save_rules(&self, rules: &CachedRules) -> Result<()> {
+        let raw: Raw<AnyGlobalAccountDataEvent> = Raw::from_json(to_raw_value(&rules.event)?);
+        let event = raw.deserialize()?;
+
+        let mut changes = StateChanges::default();
+        changes.add_account_data(event, raw);
+
+        self.client.store().save_changes(&changes).await?;
+
+        Ok(())
+    }
+
+    /// Apply the given commands to the local push rules and send them to the
+    /// homeserver.
+    ///
+    /// If one of the requests fails, the rules that were changed by it and by
+    /// the commands that weren't sent yet are restored.
+    async fn run(&self, mut rules: CachedRules, commands: Vec<RuleCommand>) -> Result<()> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+
+        let undos = commands.iter().map(|c| rules.apply(c)).collect::<Result<Vec<_>>>()?;
+
+        self.save_rules(&rules).await?;
+
+        for (i, command) in commands.into_iter().enumerate() {
+            if let Err(e) = self.send(command).await {
+                warn!("Couldn't update the push rules, restoring the previous ones: {:?}", e);
+
+                // A sync might have updated the push rules in the meantime,
+                // only undo our own changes on top of the current rules.
+                let mut rules = self.cached_rules().await?;
+
+                for undo in undos[i..].iter().rev() {
+                    rules.undo(undo);
+                }
+
+                self.save_rules(&rules).await?;
+
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, command: RuleCommand) -> Result<()> {
+        match command {
+            RuleCommand::Set { kind, rule_id, actions, conditions, pattern } => {
+                let request = assign!(
+                    set_pushrule::Request::new(GLOBAL_SCOPE, kind, &rule_id, actions),
+                    { conditions, pattern }
+                );
+                self.client.send(request, None).await?;
+            }
+            RuleCommand::Delete { kind, rule_id } => {
+                let request = delete_pushrule::Request::new(GLOBAL_SCOPE, kind, &rule_id);
+                self.client.send(request, None).await?;
+            }
+            RuleCommand::SetEnabled { kind, rule_id, enabled } => {
+                let request =
+                    set_pushrule_enabled::Request::new(GLOBAL_SCOPE, kind, &rule_id, enabled);
+                self.client.send(request, None).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+//! Support for moderation policy lists.
+//!
+//! A policy list is a room whose `m.policy.rule.*` state events recommend
+//! actions, usually bans, against users, rooms or servers. The entity of a
+//! rule can be a glob, e.g. `@*:evil.example.org` or `*.evil.example.org`.
+//!
+//! Rooms that should be used as policy lists are watched with
+//! [`PolicyLists::watch_room()`], their rules are kept up to date with every
+//! sync response the client receives.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use dashmap::DashMap;
+use futures::{channel::mpsc, Stream};
+use matrix_sdk_base::deserialized_responses::SyncResponse;
+use ruma::{events::EventType, serde::Raw, RoomId, ServerName, UserId};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::{Client, Result};
+
+/// The recommendation of a policy rule that the entity should be banned.
+const BAN_RECOMMENDATION: &str = "m.ban";
+
+/// The kind of entity a policy rule applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PolicyEntityType {
+    /// The rule applies to users, the entity is matched against user ids.
+    User,
+    /// The rule applies to rooms, the entity is matched against room ids.
+    Room,
+    /// The rule applies to servers, the entity is matched against server
+    /// names.
+    Server,
+}
+
+impl PolicyEntityType {
+    const ALL: [PolicyEntityType; 3] =
+        [PolicyEntityType::User, PolicyEntityType::Room, PolicyEntityType::Server];
+
+    /// The event type of the rules for this kind of entity.
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            PolicyEntityType::User => "m.policy.rule.user",
+            PolicyEntityType::Room => "m.policy.rule.room",
+            PolicyEntityType::Server => "m.policy.rule.server",
+        }
+    }
+
+    /// The event types that were used for this kind of entity before the
+    /// policy rules were part of the spec.
+    fn legacy_event_types(&self) -> [&'static str; 2] {
+        match self {
+            PolicyEntityType::User => ["m.room.rule.user", "org.matrix.mjolnir.rule.user"],
+            PolicyEntityType::Room => ["m.room.rule.room", "org.matrix.mjolnir.rule.room"],
+            PolicyEntityType::Server => ["m.room.rule.server", "org.matrix.mjolnir.rule.server"],
+        }
+    }
+
+    fn from_event_type(event_type: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|t| t.event_type() == event_type || t.legacy_event_types().contains(&event_type))
+    }
+}
+
+/// The content of a `m.policy.rule.*` state event.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct PolicyRuleEventContent {
+    /// The entity the rule applies to, it can contain the glob-style
+    /// wildcards `*` and `?`.
+    pub entity: String,
+    /// The action the rule recommends, e.g. `m.ban`.
+    pub recommendation: String,
+    /// Why the rule was created.
+    pub reason: String,
+}
+
+/// A policy rule of a watched policy list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PolicyRule {
+    /// The policy list the rule belongs to.
+    pub room_id: RoomId,
+    /// The state key of the state event of the rule.
+    pub state_key: String,
+    /// The kind of entity the rule applies to.
+    pub entity_type: PolicyEntityType,
+    /// The content of the rule.
+    pub content: PolicyRuleEventContent,
+}
+
+impl PolicyRule {
+    /// Does the entity of this rule match the given user id, room id or
+    /// server name.
+    pub fn matches(&self, value: &str) -> bool {
+        glob_matches(&self.content.entity, value)
+    }
+
+    /// Does this rule recommend to ban the entity.
+    pub fn is_ban(&self) -> bool {
+        self.content.recommendation == BAN_RECOMMENDATION
+    }
+}
+
+/// A change of the rules of a watched policy list.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PolicyRuleChange {
+    /// A rule was added or modified.
+    Added(PolicyRule),
+    /// A rule was removed, e.g. by replacing its state event with one that
+    /// has empty content.
+    Removed(PolicyRule),
+}
+
+#[derive(Deserialize)]
+struct StateEventDeHelper {
+    #[serde(rename = "type")]
+    event_type: String,
+    state_key: Option<String>,
+    content: JsonValue,
+}
+
+type RuleKey = (PolicyEntityType, String);
+
+/// The rules of the watched policy lists, shared by all the clones of a
+/// `Client`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PolicyRules {
+    rooms: Arc<DashMap<RoomId, BTreeMap<RuleKey, PolicyRule>>>,
+    listeners: Arc<Mutex<Vec<mpsc::UnboundedSender<PolicyRuleChange>>>>,
+}
+
+impl PolicyRules {
+    /// Update the rules of the given room with a raw state event, events that
+    /// aren't policy rules are ignored.
+    fn handle_event<T>(&self, room_id: &RoomId, event: &Raw<T>) {
+        let event = match event.deserialize_as::<StateEventDeHelper>() {
+            Ok(e) => e,
+            Err(_) => return,
+        };
+
+        let (entity_type, state_key) =
+            match (PolicyEntityType::from_event_type(&event.event_type), event.state_key) {
+                (Some(t), Some(k)) => (t, k),
+                _ => return,
+            };
+
+        let mut rules = match self.rooms.get_mut(room_id) {
+            Some(r) => r,
+            None => return,
+        };
+
+        let key = (entity_type, state_key.clone());
+
+        let change = match serde_json::from_value::<PolicyRuleEventContent>(event.content) {
+            Ok(content) => {
+                let rule =
+                    PolicyRule { room_id: room_id.to_owned(), state_key, entity_type, content };
+                rules.insert(key, rule.clone());
+
+                PolicyRuleChange::Added(rule)
+            }
+            Err(_) => match rules.remove(&key) {
+                Some(rule) => PolicyRuleChange::Removed(rule),
+                None => return,
+            },
+        };
+
+        drop(rules);
+        self.notify(change);
+    }
+
+    /// Update the rules of the watched rooms with the state events of the
+    /// given sync response.
+    pub fn handle_sync(&self, response: &SyncResponse) {
+        if self.rooms.is_empty() {
+            return;
+        }
+
+        for (room_id, room) in &response.rooms.join {
+            for event in &room.state.events {
+                self.handle_event(room_id, event);
+            }
+
+            for event in &room.timeline.events {
+                self.handle_event(room_id, &event.event);
+            }
+        }
+
+        for (room_id, room) in &response.rooms.leave {
+            for event in &room.state.events {
+                self.handle_event(room_id, event);
+            }
+
+            for event in &room.timeline.events {
+                self.handle_event(room_id, &event.event);
+            }
+        }
+    }
+
+    fn notify(&self, change: PolicyRuleChange) {
+        self.listeners.lock().unwrap().retain(|l| l.unbounded_send(change.clone()).is_ok());
+    }
+}
+
+/// A handle to the moderation policy lists the client watches.
+///
+/// Created with [`Client::policy_lists()`].
+#[derive(Clone, Debug)]
+pub struct PolicyLists {
+    pub(crate) client: Client,
+}
+
+impl PolicyLists {
+    fn rules(&self) -> &PolicyRules {
+        &self.client.policy_rules
+    }
+
+    /// Start watching the given room as a policy list.
+    ///
+    /// The rules that are already in the state store are loaded right away,
+    /// the room should be joined so the rules are kept up to date.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room that contains the policy rules.
+    pub async fn watch_room(&self, room_id: &RoomId) -> Result<()> {
+        self.rules().rooms.entry(room_id.to_owned()).or_default();
+
+        for entity_type in PolicyEntityType::ALL.iter() {
+            // The rules using the event type of the spec are loaded last, so
+            // they win if a rule exists under the legacy type as well.
+            let legacy_event_types = entity_type.legacy_event_types();
+            let event_types =
+                legacy_event_types.iter().copied().chain(Some(entity_type.event_type()));
+
+            for event_type in event_types {
+                let events = self
+                    .client
+                    .store()
+                    .get_state_events(room_id, EventType::Custom(event_type.to_owned()))
+                    .await?;
+
+                for event in &events {
+                    self.rules().handle_event(room_id, event);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stop watching the given room as a policy list, its rules don't apply
+    /// anymore.
+    pub fn unwatch_room(&self, room_id: &RoomId) {
+        if let Some((_, rules)) = self.rules().rooms.remove(room_id) {
+            for (_, rule) in rules {
+                self.rules().notify(PolicyRuleChange::Removed(rule));
+            }
+        }
+    }
+
+    /// The rooms that are watched as policy lists.
+    pub fn watched_rooms(&self) -> Vec<RoomId> {
+        self.rules().rooms.iter().map(|r| r.key().clone()).collect()
+    }
+
+    /// Get the rules of all the watched policy lists that apply to the given
+    /// entity.
+    ///
+    /// # Arguments
+    ///
+    /// * `entity_type` - The kind of the entity.
+    ///
+    /// * `entity` - The user id, room id or server name of the entity.
+    pub fn matching_rules(&self, entity_type: PolicyEntityType, entity: &str) -> Vec<PolicyRule> {
+        self.rules()
+            .rooms
+            .iter()
+            .flat_map(|r| {
+                r.value()
+                    .values()
+                    .filter(|rule| rule.entity_type == entity_type && rule.matches(entity))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Is the given user banned by one of the watched policy lists, either
+    /// directly or because their server is banned.
+    pub fn is_user_banned_by_policy(&self, user_id: &UserId) -> bool {
+        self.matching_rules(PolicyEntityType::User, user_id.as_str()).iter().any(PolicyRule::is_ban)
+            || self.is_server_banned_by_policy(user_id.server_name())
+    }
+
+    /// Is the given room banned by one of the watched policy lists.
+    pub fn is_room_banned_by_policy(&self, room_id: &RoomId) -> bool {
+        self.matching_rules(PolicyEntityType::Room, room_id.as_str()).iter().any(PolicyRule::is_ban)
+    }
+
+    /// Is the given server banned by one of the watched policy lists.
+    pub fn is_server_banned_by_policy(&self, server_name: &ServerName) -> bool {
+        self.matching_rules(PolicyEntityType::Server, server_name.as_str())
+            .iter()
+            .any(PolicyRule::is_ban)
+    }
+
+    /// Get a stream of the changes of the rules of the watched policy lists.
+    ///
+    /// This can be used to act on new rules, e.g. to ban the matching users
+    /// from the rooms a bot protects.
+    pub fn rule_changes(&self) -> impl Stream<Item = PolicyRuleChange> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.rules().listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+}
+
+/// Match a value against a glob that can contain the wildcards `*`, which
+/// matches any number of characters, and `?`, which matches a single
+/// character.
+fn glob_matches(glob: &str, value: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let value: Vec<char> = value.chars().collect();
+
+    let (mut g, mut v) = (0, 0);
+    // The position of the last `*` in the glob and the position in the value
+    // it was matched up to, to backtrack to if the rest doesn't match.
+    let mut backtrack = None;
+
+    while v < value.len() {
+        match glob.get(g) {
+            Some(&'*') => {
+                backtrack = Some((g, v));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == value[v] => {
+                g += 1;
+                v += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    g = star + 1;
+                    v = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::glob_matches;
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("@alice:example.org", "@alice:example.org"));
+        assert!(!glob_matches("@alice:example.org", "@alice:example.org.evil"));
+        assert!(glob_matches("@*:evil.org", "@spammer:evil.org"));
+        assert!(!glob_matches("@*:evil.org", "@spammer:notevil.org.com"));
+        assert!(glob_matches("*.evil.org", "matrix.evil.org"));
+        assert!(!glob_matches("*.evil.org", "evil.org"));
+        assert!(glob_matches("@bot?:example.org", "@bot1:example.org"));
+        assert!(!glob_matches("@bot?:example.org", "@bot12:example.org"));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a*b*c", "aXXbYYbc"));
+    }
+}
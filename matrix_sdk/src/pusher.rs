@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// The configuration of a pusher that sends notifications to a HTTP push
+/// gateway.
+#[derive(Clone, Debug)]
+pub struct HttpPusherConfig {
+    /// A unique identifier for the pusher, e.g. the push token of the device.
+    pub pushkey: String,
+    /// A reverse-DNS style identifier of the application.
+    pub app_id: String,
+    /// A name of the application that is shown to the user.
+    pub app_display_name: String,
+    /// A name of the device that is shown to the user.
+    pub device_display_name: String,
+    /// A string that determines which set of device specific push rules this
+    /// pusher executes.
+    pub profile_tag: Option<String>,
+    /// The preferred language for receiving notifications, e.g. `en`.
+    pub lang: String,
+    /// The URL of the `/_matrix/push/v1/notify` endpoint of the push gateway.
+    pub url: Url,
+    /// Should the notifications only contain the event id and room id of the
+    /// event, the push gateway needs to fetch the content itself.
+    pub event_id_only: bool,
+    /// Should the pusher be added next to other pushers with the same pushkey
+    /// for other users, instead of replacing them.
+    pub append: bool,
+}
+
+impl HttpPusherConfig {
+    /// Create a new pusher configuration with the language set to English.
+    ///
+    /// # Arguments
+    ///
+    /// * `pushkey` - A unique identifier for the pusher.
+    ///
+    /// * `app_id` - A reverse-DNS style identifier of the application.
+    ///
+    /// * `app_display_name` - A name of the application that is shown to the
+    /// user.
+    ///
+    /// * `device_display_name` - A name of the device that is shown to the
+    /// user.
+    ///
+    /// * `url` - The URL of the notify endpoint of the push gateway.
+    pub fn new(
+        pushkey: impl Into<String>,
+        app_id: impl Into<String>,
+        app_display_name: impl Into<String>,
+        device_display_name: impl Into<String>,
+        url: Url,
+    ) -> Self {
+        Self {
+            pushkey: pushkey.into(),
+            app_id: app_id.into(),
+            app_display_name: app_display_name.into(),
+            device_display_name: device_display_name.into(),
+            profile_tag: None,
+            lang: "en".to_owned(),
+            url,
+            event_id_only: false,
+            append: false,
+        }
+    }
+
+    pub(crate) fn data(&self) -> PusherData {
+        PusherData {
+            url: self.url.to_string(),
+            format: if self.event_id_only { Some("event_id_only".to_owned()) } else { None },
+        }
+    }
+}
+
+/// The `data` object of a pusher, it's passed to the push gateway.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct PusherData {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+/// The response of the push gateway to a notification.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct NotifyResponse {
+    pub rejected: Vec<String>,
+}
+
+pub(crate) mod set_pusher {
+    //! [POST /_matrix/client/r0/pushers/set](https://spec.matrix.org/v1.3/client-server-api/#post_matrixclientv3pushersset)
+
+    use ruma::api::ruma_api;
+
+    use super::PusherData;
+
+    ruma_api! {
+        metadata: {
+            description: "Create, update or delete a pusher for the active user.",
+            method: POST,
+            name: "set_pusher",
+            path: "/_matrix/client/r0/pushers/set",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// A unique identifier for the pusher.
+            pub pushkey: &'a str,
+
+            /// The kind of the pusher, `None` deletes the pusher.
+            pub kind: Option<&'a str>,
+
+            /// A reverse-DNS style identifier of the application.
+            pub app_id: &'a str,
+
+            /// A name of the application that is shown to the user.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub app_display_name: Option<&'a str>,
+
+            /// A name of the device that is shown to the user.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub device_display_name: Option<&'a str>,
+
+            /// Which set of device specific push rules this pusher executes.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub profile_tag: Option<&'a str>,
+
+            /// The preferred language for receiving notifications.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub lang: Option<&'a str>,
+
+            /// Information for the push gateway.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub data: Option<PusherData>,
+
+            /// Whether to add the pusher next to pushers with the same pushkey
+            /// for other users.
+            pub append: bool,
+        }
+
+        response: {}
+
+        error: ruma::api::client::Error
+    }
+}
@@ -0,0 +1,110 @@
+//! Helpers for the user-interactive authentication stages of the registration
+//! flow.
+//!
+//! The homeserver answers a registration request without a complete `auth`
+//! field with a `UiaaResponse` listing the flows it supports, each stage of a
+//! flow is then completed by sending the registration request again with the
+//! `AuthData` of the stage.
+
+use std::collections::BTreeMap;
+
+use ruma::api::client::r0::uiaa::AuthData;
+use serde_json::{json, Value as JsonValue};
+
+/// The credentials of a third party identifier validation, as returned by the
+/// `requestToken` endpoints.
+#[derive(Clone, Debug)]
+pub struct ThirdPartyCredentials<'a> {
+    /// The session id the homeserver or identity server returned.
+    pub sid: &'a str,
+    /// The client secret that was used in the `requestToken` request.
+    pub client_secret: &'a str,
+    /// The identity server that validated the identifier, if any.
+    pub id_server: Option<&'a str>,
+    /// An access token for the identity server, if any.
+    pub id_access_token: Option<&'a str>,
+}
+
+impl ThirdPartyCredentials<'_> {
+    fn to_json(&self) -> JsonValue {
+        let mut credentials = json!({
+            "sid": self.sid,
+            "client_secret": self.client_secret,
+        });
+
+        if let Some(id_server) = self.id_server {
+            credentials["id_server"] = id_server.into();
+        }
+
+        if let Some(id_access_token) = self.id_access_token {
+            credentials["id_access_token"] = id_access_token.into();
+        }
+
+        credentials
+    }
+}
+
+/// A stage of the user-interactive authentication of a registration request.
+#[derive(Clone, Debug)]
+pub enum RegistrationStage<'a> {
+    /// The `m.login.dummy` stage, it doesn't require any input.
+    Dummy,
+    /// The `m.login.registration_token` stage, the user needs to provide a
+    /// token the homeserver administrator handed out.
+    RegistrationToken {
+        /// The registration token.
+        token: &'a str,
+    },
+    /// The `m.login.email.identity` stage, the user clicked on the link in the
+    /// validation email.
+    EmailIdentity(ThirdPartyCredentials<'a>),
+    /// The `m.login.msisdn` stage, the user submitted the token they received
+    /// via SMS.
+    Msisdn(ThirdPartyCredentials<'a>),
+    /// The `m.login.recaptcha` stage, the response of the reCAPTCHA widget
+    /// is passed through to the homeserver.
+    ReCaptcha {
+        /// The response of the reCAPTCHA widget.
+        response: &'a str,
+    },
+}
+
+impl<'a> RegistrationStage<'a> {
+    /// The type of the stage, as it's listed in the flows of the
+    /// `UiaaResponse`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RegistrationStage::Dummy => "m.login.dummy",
+            RegistrationStage::RegistrationToken { .. } => "m.login.registration_token",
+            RegistrationStage::EmailIdentity(_) => "m.login.email.identity",
+            RegistrationStage::Msisdn(_) => "m.login.msisdn",
+            RegistrationStage::ReCaptcha { .. } => "m.login.recaptcha",
+        }
+    }
+
+    /// Create the `AuthData` that completes this stage.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The session id of the user-interactive authentication,
+    /// as returned in the `UiaaResponse` of the homeserver.
+    pub fn to_auth_data(&self, session: Option<&'a str>) -> AuthData<'a> {
+        let mut auth_parameters = BTreeMap::new();
+
+        match self {
+            RegistrationStage::Dummy => {}
+            RegistrationStage::RegistrationToken { token } => {
+                auth_parameters.insert("token".to_owned(), (*token).into());
+            }
+            RegistrationStage::EmailIdentity(credentials)
+            | RegistrationStage::Msisdn(credentials) => {
+                auth_parameters.insert("threepid_creds".to_owned(), credentials.to_json());
+            }
+            RegistrationStage::ReCaptcha { response } => {
+                auth_parameters.insert("response".to_owned(), (*response).into());
+            }
+        }
+
+        AuthData::DirectRequest { kind: self.kind(), session, auth_parameters }
+    }
+}
@@ -1,17 +1,41 @@
-use std::{ops::Deref, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    ops::{Deref, RangeBounds},
+    sync::Arc,
+};
 
-use matrix_sdk_base::deserialized_responses::MembersResponse;
+use futures::{future, Stream, StreamExt};
+use http::StatusCode;
+#[cfg(feature = "encryption")]
+use matrix_sdk_base::deserialized_responses::SyncRoomEvent;
+use matrix_sdk_base::{
+    deserialized_responses::{MembersResponse, TimelineChunk},
+    media::{MediaFormat, MediaRequest, MediaType},
+    StateChange,
+};
 use matrix_sdk_common::locks::Mutex;
 use ruma::{
-    api::client::r0::{
-        media::{get_content, get_content_thumbnail},
-        membership::{get_member_events, join_room_by_id, leave_room},
-        message::get_message_events,
+    api::{
+        client::r0::{
+            membership::{get_member_events, join_room_by_id, leave_room},
+            message::get_message_events,
+            tag::{create_tag, delete_tag},
+        },
+        error::{FromHttpResponseError, ServerError},
     },
-    UserId,
+    assign,
+    events::tag::TagInfo,
+    uint, EventId, MilliSecondsSinceUnixEpoch, RoomIdOrAliasId, UInt, UserId,
 };
+use serde_json::Value as JsonValue;
+use tracing::warn;
 
-use crate::{BaseRoom, Client, Result, RoomMember};
+use super::{
+    export::{self, ExportFormat, ExportMedia},
+    relations::{get_relating_events, RelationType, Relations, RelationsPagination},
+};
+use crate::{BaseRoom, Client, Error, HttpError, Result, RoomMember, TagName};
 
 /// A struct containing methods that are common for Joined, Invited and Left
 /// Rooms
@@ -53,30 +77,68 @@ impl Common {
 
     /// Join this room.
     ///
-    /// Only invited and left rooms can be joined via this method
+    /// Only invited and left rooms can be joined via this method.
+    ///
+    /// If the room has the `restricted` join rule the join is routed through
+    /// the servers that can authorise it.
     pub(crate) async fn join(&self) -> Result<()> {
-        let request = join_room_by_id::Request::new(self.inner.room_id());
-        let _response = self.client.send(request, None).await?;
+        if self.inner.join_allow_rules().await?.is_some() {
+            let servers = self.inner.authorising_servers().await?;
+            let room_id: RoomIdOrAliasId = self.inner.room_id().clone().into();
+            self.client.join_room_by_id_or_alias(&room_id, &servers).await?;
+        } else {
+            let request = join_room_by_id::Request::new(self.inner.room_id());
+            let _response = self.client.send(request, None).await?;
+        }
 
         Ok(())
     }
 
+    /// Get a stream of the changes of the state of this room.
+    ///
+    /// The current state can be fetched with [`BaseRoom::state_snapshot()`],
+    /// see [`Client::state_changes_stream()`] for the changes of all rooms.
+    pub fn state_changes(&self) -> impl Stream<Item = StateChange> {
+        let room_id = self.inner.room_id().clone();
+
+        self.client.state_changes_stream().filter(move |c| future::ready(c.room_id == room_id))
+    }
+
+    /// Get a stream that yields once this room becomes encrypted.
+    ///
+    /// Whether the room is currently encrypted can be checked with
+    /// [`BaseRoom::is_encrypted()`], see [`Client::room_encryption_stream()`]
+    /// for all rooms.
+    pub fn encryption_changes(&self) -> impl Stream<Item = ()> {
+        let room_id = self.inner.room_id().clone();
+
+        self.client
+            .room_encryption_stream()
+            .filter(move |r| future::ready(*r == room_id))
+            .map(|_| ())
+    }
+
     /// Gets the avatar of this room, if set.
     ///
+    /// A direct message room without an avatar uses the avatar of the member
+    /// it is shared with, see [`BaseRoom::avatar_url()`]. The avatar is
+    /// fetched through the media cache.
+    ///
     /// Returns the avatar. No guarantee on the size of the image is given.
-    /// If no size is given the full-sized avatar will be returned.
     ///
     /// # Arguments
     ///
-    /// * `width` - The desired width of the avatar.
-    ///
-    /// * `height` - The desired height of the avatar.
+    /// * `format` - The desired format of the avatar, the full-sized avatar or
+    /// a thumbnail.
     ///
     /// # Example
     /// ```no_run
     /// # use futures::executor::block_on;
     /// # use matrix_sdk::Client;
     /// # use matrix_sdk::identifiers::room_id;
+    /// # use matrix_sdk::media::{MediaFormat, MediaThumbnailSize};
+    /// # use matrix_sdk::api::r0::media::get_content_thumbnail::Method;
+    /// # use matrix_sdk::uint;
     /// # use url::Url;
     /// # let homeserver = Url::parse("http://example.com").unwrap();
     /// # block_on(async {
@@ -87,24 +149,16 @@ impl Common {
     /// let room = client
     ///     .get_joined_room(&room_id)
     ///     .unwrap();
-    /// if let Some(avatar) = room.avatar(Some(96), Some(96)).await.unwrap() {
+    /// let size = MediaThumbnailSize { method: Method::Scale, width: uint!(96), height: uint!(96) };
+    /// if let Some(avatar) = room.avatar(MediaFormat::Thumbnail(size)).await.unwrap() {
     ///     std::fs::write("avatar.png", avatar);
     /// }
     /// # })
     /// ```
-    pub async fn avatar(&self, width: Option<u32>, height: Option<u32>) -> Result<Option<Vec<u8>>> {
-        // TODO: try to offer the avatar from cache, requires avatar cache
+    pub async fn avatar(&self, format: MediaFormat) -> Result<Option<Vec<u8>>> {
         if let Some(url) = self.avatar_url() {
-            if let (Some(width), Some(height)) = (width, height) {
-                let request =
-                    get_content_thumbnail::Request::from_url(&url, width.into(), height.into())?;
-                let response = self.client.send(request, None).await?;
-                Ok(Some(response.file))
-            } else {
-                let request = get_content::Request::from_url(&url)?;
-                let response = self.client.send(request, None).await?;
-                Ok(Some(response.file))
-            }
+            let request = MediaRequest { media_type: MediaType::Uri(url), format };
+            Ok(Some(self.client.get_media_content(&request, true).await?))
         } else {
             Ok(None)
         }
@@ -149,6 +203,380 @@ impl Common {
         self.client.send(request, None).await
     }
 
+    /// Load older events of the room timeline.
+    ///
+    /// The pagination continues from the token that is stored together with
+    /// the already paginated events, or from the `prev_batch` token of the
+    /// latest sync if the room wasn't paginated yet. The events are decrypted,
+    /// if possible, and stored so the timeline can be restored later on.
+    ///
+    /// Returns the events ordered from the newest to the oldest one, events
+    /// that are already part of the stored timeline are left out. Once the
+    /// start of the room is reached the returned chunk will be empty and
+    /// marked as such.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - The maximum number of events that should be fetched.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use matrix_sdk::{Client, identifiers::room_id, uint};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # let room = client.get_joined_room(&room_id!("!roomid:example.com")).unwrap();
+    /// loop {
+    ///     let chunk = room.paginate_backwards(uint!(20)).await.unwrap();
+    ///
+    ///     if chunk.reached_start {
+    ///         break;
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub async fn paginate_backwards(&self, limit: UInt) -> Result<TimelineChunk> {
+        let room_id = self.inner.room_id();
+        let latest_token = self.inner.last_prev_batch();
+
+        let token = match self.client.store().get_paginated_timeline(room_id).await? {
+            Some(timeline) => timeline.token,
+            None => latest_token.clone(),
+        };
+
+        let token = if let Some(token) = token {
+            token
+        } else {
+            return Ok(TimelineChunk { events: Vec::new(), reached_start: true });
+        };
+
+        let request = assign!(get_message_events::Request::backward(room_id, &token), { limit });
+
+        let response = match self.messages(request).await {
+            Ok(r) => r,
+            // The stored token might have expired on the server, start over
+            // from the latest sync.
+            Err(e) if is_invalid_token_error(&e) && latest_token.as_ref() != Some(&token) => {
+                self.client.store().remove_paginated_timeline(room_id).await?;
+
+                match &latest_token {
+                    Some(latest) => {
+                        let request =
+                            assign!(get_message_events::Request::backward(room_id, latest), {
+                                limit
+                            });
+                        self.messages(request).await?
+                    }
+                    None => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(self.client.base_client.receive_messages(room_id, &response).await?)
+    }
+
+    /// Re-evaluate the trust in the devices that sent the encrypted events of
+    /// the stored timeline of this room.
+    ///
+    /// Events that were sent from a device that was trusted when the event
+    /// was decrypted but isn't anymore get their
+    /// [`EncryptionInfo::trust_change`] set, so they can be marked as sent
+    /// from a device that has since been distrusted.
+    ///
+    /// Returns the events whose trust change was updated.
+    ///
+    /// [`EncryptionInfo::trust_change`]: crate::deserialized_responses::EncryptionInfo::trust_change
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn update_trust_changes(&self) -> Result<Vec<SyncRoomEvent>> {
+        Ok(self.client.base_client.update_trust_changes(self.inner.room_id()).await?)
+    }
+
+    /// Export the timeline of this room, e.g. for data portability or
+    /// compliance reasons.
+    ///
+    /// The events are fetched from the homeserver, starting at the latest
+    /// sync, and are decrypted if possible. Redacted events are exported
+    /// without their content.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The range of timestamps of the events that should be
+    /// exported.
+    ///
+    /// * `format` - The format of the export.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use matrix_sdk::{
+    /// #     Client, identifiers::room_id, room::{ExportFormat, ExportMedia},
+    /// #     MilliSecondsSinceUnixEpoch,
+    /// # };
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # let room = client.get_joined_room(&room_id!("!roomid:example.com")).unwrap();
+    /// let html = room
+    ///     .export_timeline(.., ExportFormat::Html { media: ExportMedia::Embed })
+    ///     .await
+    ///     .unwrap();
+    ///
+    /// std::fs::write("history.html", html).unwrap();
+    /// # });
+    /// ```
+    pub async fn export_timeline(
+        &self,
+        range: impl RangeBounds<MilliSecondsSinceUnixEpoch>,
+        format: ExportFormat,
+    ) -> Result<String> {
+        let room_id = self.inner.room_id();
+        let mut token = self.client.sync_token().await;
+        let mut events = Vec::new();
+        let mut redactions = BTreeMap::new();
+
+        while let Some(from) = token {
+            let request = assign!(get_message_events::Request::backward(room_id, &from), {
+                limit: uint!(100),
+            });
+            let response = self.messages(request).await?;
+            let chunk = self.client.base_client.receive_room_events(room_id, &response.chunk).await;
+
+            token = if response.chunk.is_empty() { None } else { response.end };
+
+            // The events are ordered from the newest to the oldest one, so
+            // a redaction is always seen before the event it redacts.
+            for event in chunk {
+                let mut json: JsonValue = match serde_json::from_str(event.event.json().get()) {
+                    Ok(j) => j,
+                    Err(_) => continue,
+                };
+
+                let timestamp = match export::timestamp(&json) {
+                    Some(t) => t,
+                    None => continue,
+                };
+
+                if export::is_before_range(&range, timestamp) {
+                    token = None;
+                    continue;
+                } else if !range.contains(&timestamp) {
+                    continue;
+                }
+
+                if let Some(redacts) = json.get("redacts").and_then(|r| r.as_str()) {
+                    redactions.insert(redacts.to_owned(), json.clone());
+                }
+
+                let redaction =
+                    json.get("event_id").and_then(|e| redactions.get(e.as_str()?)).cloned();
+
+                if let Some(redaction) = redaction {
+                    self.inner.redact_event(&mut json, &redaction);
+                }
+
+                if let Err(e) = self.export_media(&mut json, format.media()).await {
+                    warn!("Couldn't export the media of an event in room {}: {:?}", room_id, e);
+                }
+
+                events.push(json);
+            }
+        }
+
+        events.reverse();
+
+        Ok(match format {
+            ExportFormat::JsonLines { .. } => events.iter().map(|e| format!("{}\n", e)).collect(),
+            ExportFormat::Html { .. } => {
+                let mut names = BTreeMap::new();
+
+                for sender in events.iter().filter_map(|e| e.get("sender")?.as_str()) {
+                    if names.contains_key(sender) {
+                        continue;
+                    }
+
+                    let member = match UserId::try_from(sender) {
+                        Ok(user_id) => self.inner.get_member(&user_id).await?,
+                        Err(_) => None,
+                    };
+
+                    if let Some(member) = member {
+                        names.insert(sender.to_owned(), member.name().to_owned());
+                    }
+                }
+
+                export::render_html(&self.inner.display_name().await?, &events, &names)
+            }
+        })
+    }
+
+    /// Rewrite or embed the media file of the given event, if it has one.
+    async fn export_media(&self, event: &mut JsonValue, media: ExportMedia) -> Result<()> {
+        let (media_type, mimetype) = match (media, export::media_of(event)) {
+            (ExportMedia::Keep, _) | (_, None) => return Ok(()),
+            (_, Some(m)) => m,
+        };
+
+        let url = match (media, media_type) {
+            (ExportMedia::Link, MediaType::Uri(uri)) => {
+                let (server_name, media_id) = uri.parts()?;
+                let homeserver = self.client.homeserver().await;
+
+                format!(
+                    "{}/_matrix/media/r0/download/{}/{}",
+                    homeserver.as_str().trim_end_matches('/'),
+                    server_name,
+                    media_id
+                )
+            }
+            (ExportMedia::Embed, media_type) => {
+                let request = MediaRequest { media_type, format: MediaFormat::File };
+                let content = self.client.get_media_content(&request, true).await?;
+
+                format!(
+                    "data:{};base64,{}",
+                    mimetype.as_deref().unwrap_or("application/octet-stream"),
+                    base64::encode(content)
+                )
+            }
+            _ => return Ok(()),
+        };
+
+        if let Some(content) = event.get_mut("content").and_then(|c| c.as_object_mut()) {
+            content.insert("url".to_owned(), url.into());
+            content.remove("file");
+        }
+
+        Ok(())
+    }
+
+    /// Fetch the events that relate to the given event with the given relation
+    /// type.
+    ///
+    /// This can be used to fetch the reactions to an event, its edit history
+    /// or the events of a thread. The events are decrypted, if possible, the
+    /// same way the events of the timeline are.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The id of the event whose relations should be fetched.
+    ///
+    /// * `rel_type` - The type of relations that should be fetched.
+    ///
+    /// * `recurse` - Should events that relate to the related events be
+    /// fetched as well, e.g. reactions to the events of a thread.
+    ///
+    /// * `pagination` - Which chunk of the relations should be fetched.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use matrix_sdk::{
+    /// #     Client, identifiers::{event_id, room_id},
+    /// #     room::{RelationType, RelationsPagination},
+    /// # };
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # let room = client.get_joined_room(&room_id!("!roomid:example.com")).unwrap();
+    /// let event_id = event_id!("$h29iv0s8:example.com");
+    /// let mut pagination = RelationsPagination::default();
+    ///
+    /// loop {
+    ///     let edits = room
+    ///         .relations(&event_id, RelationType::Replacement, false, pagination.clone())
+    ///         .await
+    ///         .unwrap();
+    ///
+    ///     if edits.next_batch.is_none() {
+    ///         break;
+    ///     }
+    ///
+    ///     pagination.from = edits.next_batch;
+    /// }
+    /// # });
+    /// ```
+    pub async fn relations(
+        &self,
+        event_id: &EventId,
+        rel_type: RelationType,
+        recurse: bool,
+        pagination: RelationsPagination,
+    ) -> Result<Relations> {
+        let room_id = self.inner.room_id();
+
+        let request = get_relating_events::Request {
+            room_id,
+            event_id,
+            rel_type: rel_type.as_str(),
+            from: pagination.from.as_deref(),
+            to: pagination.to.as_deref(),
+            limit: pagination.limit,
+            dir: pagination.dir,
+            recurse: if recurse { Some(true) } else { None },
+        };
+
+        let response = self.client.send(request, None).await?;
+        let chunk = self.client.base_client.receive_room_events(room_id, &response.chunk).await;
+
+        Ok(Relations {
+            chunk,
+            next_batch: response.next_batch,
+            prev_batch: response.prev_batch,
+            recursion_depth: response.recursion_depth,
+        })
+    }
+
+    /// Add a tag to this room, or update the order of an existing tag.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - The tag that should be added.
+    ///
+    /// * `order` - The position of the room among the rooms with the same
+    /// tag, a number between 0 and 1.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use matrix_sdk::{Client, TagName, identifiers::room_id};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # let room = client.get_joined_room(&room_id!("!roomid:example.com")).unwrap();
+    /// room.set_tag(TagName::Favourite, Some(0.5)).await.unwrap();
+    /// # });
+    /// ```
+    pub async fn set_tag(&self, tag: TagName, order: Option<f64>) -> Result<create_tag::Response> {
+        let tag_info = assign!(TagInfo::new(), { order });
+        let request = create_tag::Request::new(
+            self.inner.own_user_id(),
+            self.inner.room_id(),
+            tag.as_str(),
+            tag_info,
+        );
+
+        self.client.send(request, None).await
+    }
+
+    /// Remove a tag from this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - The tag that should be removed.
+    pub async fn remove_tag(&self, tag: TagName) -> Result<delete_tag::Response> {
+        let request =
+            delete_tag::Request::new(self.inner.own_user_id(), self.inner.room_id(), tag.as_str());
+
+        self.client.send(request, None).await
+    }
+
     pub(crate) async fn request_members(&self) -> Result<Option<MembersResponse>> {
         #[allow(clippy::map_clone)]
         if let Some(mutex) =
@@ -222,6 +650,23 @@ impl Common {
             .collect())
     }
 
+    /// Get the members that are asking to join this room.
+    ///
+    /// *Note*: This method will fetch the members from the homeserver if the
+    /// member list isn't synchronized due to member lazy loading. Because of
+    /// that, it might panic if it isn't run on a tokio thread.
+    pub async fn knocking_members(&self) -> Result<Vec<RoomMember>> {
+        self.ensure_members().await?;
+
+        Ok(self
+            .inner
+            .knocking_members()
+            .await?
+            .into_iter()
+            .map(|member| RoomMember::new(self.client.clone(), member))
+            .collect())
+    }
+
     /// Get all the joined members of this room.
     ///
     /// *Note*: This method will fetch the members from the homeserver if the
@@ -325,3 +770,12 @@ impl Common {
             .collect())
     }
 }
+
+/// Did the server reject a pagination token.
+fn is_invalid_token_error(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Http(HttpError::ClientApi(FromHttpResponseError::Http(ServerError::Known(e))))
+            if e.status_code == StatusCode::BAD_REQUEST || e.status_code == StatusCode::NOT_FOUND
+    )
+}
@@ -0,0 +1,311 @@
+//! Export of room timelines to portable formats.
+
+use std::{
+    collections::BTreeMap,
+    ops::{Bound, RangeBounds},
+};
+
+use matrix_sdk_base::media::{MediaEventContent, MediaType};
+use ruma::{
+    events::{room::message::MessageType, AnySyncMessageEvent, AnySyncRoomEvent},
+    MilliSecondsSinceUnixEpoch,
+};
+use serde_json::Value as JsonValue;
+
+const STYLE: &str = "body { font-family: sans-serif; max-width: 50em; margin: auto; } \
+    .event { margin: 0.5em 0; } \
+    .time { color: #888; font-size: 0.8em; } \
+    .sender { font-weight: bold; } \
+    .notice, .state { color: #666; } \
+    img, video { display: block; max-width: 100%; }";
+
+/// The URL schemes media URLs can have in an HTML export.
+const ALLOWED_URL_SCHEMES: &[&str] = &["mxc://", "https://", "http://", "data:"];
+
+/// The format a room timeline is exported to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One event per line, every event is the JSON object the homeserver sent
+    /// us, decrypted if possible.
+    JsonLines {
+        /// How the media of the events should be exported.
+        media: ExportMedia,
+    },
+    /// A self-contained HTML document that shows the timeline similar to a
+    /// chat client.
+    Html {
+        /// How the media of the events should be exported.
+        media: ExportMedia,
+    },
+}
+
+impl ExportFormat {
+    pub(crate) fn media(&self) -> ExportMedia {
+        match self {
+            ExportFormat::JsonLines { media } | ExportFormat::Html { media } => *media,
+        }
+    }
+}
+
+/// How the media files that the events of an export refer to are handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportMedia {
+    /// The events keep their `mxc://` URIs, the media isn't part of the
+    /// export.
+    Keep,
+    /// The `mxc://` URIs are rewritten to download URLs of the homeserver.
+    ///
+    /// Encrypted media is kept as it is, it can't be used without the keys
+    /// that are part of the event.
+    Link,
+    /// The media is downloaded, decrypted if needed, and embedded into the
+    /// events as `data:` URI, which makes the export self-contained.
+    Embed,
+}
+
+/// Get the timestamp of the given event.
+pub(crate) fn timestamp(event: &JsonValue) -> Option<MilliSecondsSinceUnixEpoch> {
+    serde_json::from_value(event.get("origin_server_ts")?.clone()).ok()
+}
+
+/// Is the given timestamp before the start of the given range.
+pub(crate) fn is_before_range(
+    range: &impl RangeBounds<MilliSecondsSinceUnixEpoch>,
+    timestamp: MilliSecondsSinceUnixEpoch,
+) -> bool {
+    match range.start_bound() {
+        Bound::Included(start) => timestamp < *start,
+        Bound::Excluded(start) => timestamp <= *start,
+        Bound::Unbounded => false,
+    }
+}
+
+/// Get the media file of the given event and its mimetype, if the event has
+/// one.
+pub(crate) fn media_of(event: &JsonValue) -> Option<(MediaType, Option<String>)> {
+    let event: AnySyncRoomEvent = serde_json::from_value(event.clone()).ok()?;
+
+    match event {
+        AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomMessage(m)) => match m.content.msgtype {
+            MessageType::Audio(c) => Some((c.file()?, c.info.and_then(|i| i.mimetype))),
+            MessageType::File(c) => Some((c.file()?, c.info.and_then(|i| i.mimetype))),
+            MessageType::Image(c) => Some((c.file()?, c.info.and_then(|i| i.mimetype))),
+            MessageType::Video(c) => Some((c.file()?, c.info.and_then(|i| i.mimetype))),
+            _ => None,
+        },
+        AnySyncRoomEvent::Message(AnySyncMessageEvent::Sticker(s)) => {
+            Some((s.content.file()?, s.content.info.mimetype))
+        }
+        _ => None,
+    }
+}
+
+/// Render the given events, ordered from the oldest to the newest one, as an
+/// HTML document.
+///
+/// The `names` map the user ids of the senders to their display names.
+pub(crate) fn render_html(
+    title: &str,
+    events: &[JsonValue],
+    names: &BTreeMap<String, String>,
+) -> String {
+    let title = escape_html(title);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        title, STYLE, title
+    );
+
+    for line in events.iter().filter_map(|e| render_event(e, names)) {
+        html.push_str(&line);
+        html.push('\n');
+    }
+
+    html.push_str("</body>\n</html>\n");
+
+    html
+}
+
+fn render_event(event: &JsonValue, names: &BTreeMap<String, String>) -> Option<String> {
+    let name = |user_id: &str| escape_html(names.get(user_id).map_or(user_id, String::as_str));
+
+    let event_type = event.get("type")?.as_str()?;
+    let event_id = event.get("event_id")?.as_str()?;
+    let sender = event.get("sender")?.as_str()?;
+    let content = event.get("content")?;
+    let field = |key: &str| escape_html(content.get(key).and_then(|v| v.as_str()).unwrap_or(""));
+
+    let body = if event.pointer("/unsigned/redacted_because").is_some() {
+        "<em>Message deleted</em>".to_owned()
+    } else {
+        match event_type {
+            "m.room.message" | "m.sticker" => render_message(event_type, content)?,
+            "m.room.encrypted" => "<em>Unable to decrypt message</em>".to_owned(),
+            "m.room.member" => format!(
+                "<span class=\"state\">{} is now {}</span>",
+                name(event.get("state_key")?.as_str()?),
+                field("membership")
+            ),
+            "m.room.name" => {
+                format!("<span class=\"state\">changed the room name to {}</span>", field("name"))
+            }
+            "m.room.topic" => {
+                format!("<span class=\"state\">changed the topic to {}</span>", field("topic"))
+            }
+            _ if event.get("state_key").is_some() => {
+                format!(
+                    "<span class=\"state\">changed the {} state</span>",
+                    escape_html(event_type)
+                )
+            }
+            // Redactions, reactions and the like aren't shown on their own.
+            _ => return None,
+        }
+    };
+
+    let time = event
+        .get("origin_server_ts")
+        .and_then(|t| t.as_u64())
+        .map(format_timestamp)
+        .unwrap_or_default();
+
+    Some(format!(
+        "<div class=\"event\" id=\"{}\"><span class=\"time\">{}</span> \
+         <span class=\"sender\">{}</span> {}</div>",
+        escape_html(event_id),
+        time,
+        name(sender),
+        body
+    ))
+}
+
+fn render_message(event_type: &str, content: &JsonValue) -> Option<String> {
+    let body = escape_html(content.get("body").and_then(|b| b.as_str()).unwrap_or(""));
+    // Don't let a sender smuggle e.g. a `javascript:` URL into the export.
+    let url = content
+        .get("url")
+        .and_then(|u| u.as_str())
+        .filter(|u| ALLOWED_URL_SCHEMES.iter().any(|s| u.starts_with(s)))
+        .map(escape_html);
+
+    let msgtype =
+        if event_type == "m.sticker" { "m.image" } else { content.get("msgtype")?.as_str()? };
+
+    Some(match (msgtype, url) {
+        ("m.image", Some(url)) => format!("<img src=\"{}\" alt=\"{}\">", url, body),
+        ("m.video", Some(url)) => format!("<video src=\"{}\" controls></video>", url),
+        ("m.audio", Some(url)) => format!("<audio src=\"{}\" controls></audio>", url),
+        ("m.file", Some(url)) => format!("<a href=\"{}\" download=\"{}\">{}</a>", url, body, body),
+        ("m.emote", _) => format!("<span class=\"emote\">* {}</span>", body),
+        ("m.notice", _) => format!("<span class=\"notice\">{}</span>", body),
+        _ => format!("<span class=\"body\">{}</span>", body.replace('\n', "<br>")),
+    })
+}
+
+/// Escape the characters that have a special meaning in HTML.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Format a timestamp in milliseconds since the unix epoch as UTC date and
+/// time, e.g. `2021-08-01 12:30:00`.
+fn format_timestamp(timestamp: u64) -> String {
+    let seconds = timestamp / 1000;
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+
+    // Convert the days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use serde_json::json;
+
+    use super::{escape_html, format_timestamp, render_html};
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_782_400_000), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1_627_821_000_123), "2021-08-01 12:30:00");
+    }
+
+    #[test]
+    fn html() {
+        assert_eq!(
+            escape_html("<b>\"Tom & Jerry\"</b>"),
+            "&lt;b&gt;&quot;Tom &amp; Jerry&quot;&lt;/b&gt;"
+        );
+
+        let events = [
+            json!({
+                "type": "m.room.message",
+                "event_id": "$message",
+                "sender": "@alice:example.org",
+                "origin_server_ts": 0,
+                "content": { "msgtype": "m.text", "body": "<script>alert(1)</script>" },
+            }),
+            json!({
+                "type": "m.room.message",
+                "event_id": "$deleted",
+                "sender": "@bob:example.org",
+                "origin_server_ts": 0,
+                "content": {},
+                "unsigned": { "redacted_because": { "type": "m.room.redaction" } },
+            }),
+            json!({
+                "type": "m.room.redaction",
+                "event_id": "$redaction",
+                "sender": "@bob:example.org",
+                "origin_server_ts": 0,
+                "redacts": "$deleted",
+                "content": {},
+            }),
+        ];
+
+        let mut names = BTreeMap::new();
+        names.insert("@alice:example.org".to_owned(), "Alice".to_owned());
+
+        let html = render_html("Room", &events, &names);
+
+        assert!(html.contains("<span class=\"sender\">Alice</span>"));
+        assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("<em>Message deleted</em>"));
+        assert!(!html.contains("$redaction"));
+    }
+}
@@ -1,5 +1,8 @@
 use std::ops::Deref;
 
+use ruma::{api::client::r0::profile::get_profile, MxcUri, RoomId, UserId};
+use tracing::warn;
+
 use crate::{room::Common, BaseRoom, Client, Result, RoomType};
 
 /// A room in the invited state.
@@ -34,9 +37,86 @@ impl Invited {
         self.inner.leave().await
     }
 
+    /// Reject the invitation, telling the inviter why.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Optional reason why the invitation is rejected.
+    pub async fn reject_invitation_with_reason(&self, reason: Option<&str>) -> Result<()> {
+        let request = leave_room::Request { room_id: self.room_id(), reason };
+        self.client.send(request, None).await?;
+
+        Ok(())
+    }
+
     /// Accept the invitation.
+    ///
+    /// If the invite was marked as a direct message the room is added to the
+    /// `m.direct` account data.
     pub async fn accept_invitation(&self) -> Result<()> {
-        self.inner.join().await
+        self.inner.join().await?;
+
+        let targets = self.direct_targets();
+
+        if !targets.is_empty() {
+            let room_id = self.room_id().clone();
+
+            self.client
+                .update_direct_rooms(|direct| {
+                    for user_id in targets {
+                        let rooms = direct.entry(user_id).or_default();
+
+                        if !rooms.contains(&room_id) {
+                            rooms.push(room_id.clone());
+                        }
+                    }
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the details of the invitation, with the profile of the user that
+    /// invited us.
+    ///
+    /// The profile of the inviter is fetched from the homeserver, if that
+    /// fails only the user id of the inviter is known.
+    pub async fn invite_details(&self) -> Invite {
+        let inviter = match self.inviter() {
+            Some(user_id) => Some(self.fetch_inviter_profile(user_id).await),
+            None => None,
+        };
+
+        Invite { room: self.clone(), inviter }
+    }
+
+    async fn fetch_inviter_profile(&self, user_id: UserId) -> Inviter {
+        let request = get_profile::Request::new(&user_id);
+
+        #[cfg(not(feature = "require_auth_for_profile_requests"))]
+        let config = None;
+
+        #[cfg(feature = "require_auth_for_profile_requests")]
+        let config = Some(crate::RequestConfig::new().force_auth());
+
+        match self.client.send(request, config).await {
+            Ok(response) => Inviter {
+                user_id,
+                display_name: response.displayname,
+                avatar_url: response.avatar_url,
+            },
+            Err(e) => {
+                warn!(
+                    room_id = self.room_id().as_str(),
+                    user_id = user_id.as_str(),
+                    "Couldn't fetch the profile of the inviter: {:?}",
+                    e
+                );
+
+                Inviter { user_id, display_name: None, avatar_url: None }
+            }
+        }
     }
 }
 
@@ -47,3 +127,104 @@ impl Deref for Invited {
         &self.inner
     }
 }
+
+/// The profile of the user that invited us to a room.
+#[derive(Clone, Debug)]
+pub struct Inviter {
+    /// The id of the user.
+    pub user_id: UserId,
+    /// The display name of the user, if it's known.
+    pub display_name: Option<String>,
+    /// The avatar of the user, if it's known.
+    pub avatar_url: Option<MxcUri>,
+}
+
+/// An invitation to a room, with everything that's needed to present it to
+/// the user before they accept or reject it.
+///
+/// The details of the room are taken from the stripped state that was sent
+/// along with the invitation.
+#[derive(Clone, Debug)]
+pub struct Invite {
+    room: Invited,
+    inviter: Option<Inviter>,
+}
+
+impl Invite {
+    /// The room we were invited to.
+    pub fn room(&self) -> &Invited {
+        &self.room
+    }
+
+    /// The id of the room we were invited to.
+    pub fn room_id(&self) -> &RoomId {
+        self.room.room_id()
+    }
+
+    /// The name of the room, if it has one.
+    pub fn name(&self) -> Option<String> {
+        self.room.name()
+    }
+
+    /// The avatar of the room, if it has one.
+    pub fn avatar_url(&self) -> Option<MxcUri> {
+        self.room.avatar_url()
+    }
+
+    /// Was the invitation marked as a direct message.
+    pub fn is_direct(&self) -> bool {
+        self.room.is_direct()
+    }
+
+    /// The user that invited us, if the invitation tells us.
+    pub fn inviter(&self) -> Option<&Inviter> {
+        self.inviter.as_ref()
+    }
+
+    /// Accept the invitation.
+    ///
+    /// See [`Invited::accept_invitation()`].
+    pub async fn accept(&self) -> Result<()> {
+        self.room.accept_invitation().await
+    }
+
+    /// Reject the invitation.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Optional reason why the invitation is rejected.
+    pub async fn reject(&self, reason: Option<&str>) -> Result<()> {
+        self.room.reject_invitation_with_reason(reason).await
+    }
+}
+
+mod leave_room {
+    //! [POST /_matrix/client/r0/rooms/{roomId}/leave](https://spec.matrix.org/v1.3/client-server-api/#post_matrixclientv3roomsroomidleave)
+
+    use ruma::{api::ruma_api, RoomId};
+
+    ruma_api! {
+        metadata: {
+            description: "Leave a room, optionally giving a reason.",
+            method: POST,
+            name: "leave_room",
+            path: "/_matrix/client/r0/rooms/:room_id/leave",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room to leave.
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            /// The reason for leaving the room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub reason: Option<&'a str>,
+        }
+
+        response: {}
+
+        error: ruma::api::client::Error
+    }
+}
@@ -2,8 +2,15 @@
 use std::sync::Arc;
 use std::{io::Read, ops::Deref};
 
+use futures::{
+    future::{self, Either},
+    StreamExt,
+};
 #[cfg(feature = "encryption")]
-use matrix_sdk_base::crypto::AttachmentEncryptor;
+use matrix_sdk_base::{
+    crypto::{AttachmentEncryptor, OlmError, RoomKeySharingProgress},
+    Error as MatrixError,
+};
 #[cfg(feature = "encryption")]
 use matrix_sdk_common::locks::Mutex;
 use matrix_sdk_common::{
@@ -30,24 +37,36 @@ use ruma::{
     assign,
     events::{
         room::{
+            canonical_alias::CanonicalAliasEventContent,
+            encryption::EncryptionEventContent,
+            history_visibility::{HistoryVisibility, HistoryVisibilityEventContent},
+            join_rules::{JoinRule, JoinRulesEventContent},
             message::{
                 AudioMessageEventContent, FileMessageEventContent, ImageMessageEventContent,
                 MessageEventContent, MessageType, VideoMessageEventContent,
             },
             EncryptedFile,
         },
-        AnyMessageEventContent, AnyStateEventContent,
+        AnyMessageEventContent, AnyStateEventContent, AnySyncStateEvent, EventType,
     },
-    identifiers::{EventId, UserId},
+    identifiers::{EventId, RoomAliasId, UserId},
     receipt::ReceiptType,
+    EventEncryptionAlgorithm,
 };
+use serde_json::json;
 #[cfg(feature = "encryption")]
-use tracing::instrument;
+use tracing::{instrument, warn};
 
-use crate::{room::Common, BaseRoom, Client, Result, RoomType};
+use crate::{
+    image_pack::PackImage, room::Common, AllowRule, BaseRoom, Client, LocalEcho, LocalEchoState,
+    PowerLevelsBuilder, Result, RoomType,
+};
 
 const TYPING_NOTICE_TIMEOUT: Duration = Duration::from_secs(4);
 const TYPING_NOTICE_RESEND_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long `enable_encryption()` waits for the encryption event to come back
+/// in a sync.
+const ENABLE_ENCRYPTION_TIMEOUT: Duration = Duration::from_secs(60);
 
 /// A room in the joined state.
 ///
@@ -59,6 +78,71 @@ pub struct Joined {
     pub(crate) inner: Common,
 }
 
+/// A handle to an event that was added as a local echo to a room.
+///
+/// The event is only sent out once [`send()`](#method.send) is called, which
+/// allows the caller to decide where the sending happens, e.g. in a spawned
+/// task, while the local echo can already be shown in the timeline.
+#[derive(Debug, Clone)]
+pub struct LocalEchoHandle {
+    room: Joined,
+    transaction_id: Uuid,
+    content: AnyMessageEventContent,
+}
+
+impl LocalEchoHandle {
+    /// The transaction id of the event.
+    ///
+    /// The remote echo of the event will contain this transaction id in its
+    /// unsigned field.
+    pub fn transaction_id(&self) -> Uuid {
+        self.transaction_id
+    }
+
+    /// Get the local echo of the event.
+    ///
+    /// Returns `None` if the remote echo of the event was already received in
+    /// a sync.
+    pub fn local_echo(&self) -> Option<LocalEcho> {
+        let transaction_id = self.transaction_id.to_string();
+
+        self.room
+            .client
+            .base_client
+            .local_echoes(self.room.room_id())
+            .into_iter()
+            .find(|e| e.transaction_id == transaction_id)
+    }
+
+    /// Send the event to the room.
+    ///
+    /// The state of the local echo is updated depending on the outcome of the
+    /// request. A failed event can be retried by calling this method again,
+    /// the same transaction id will be used.
+    pub async fn send(&self) -> Result<send_message_event::Response> {
+        let room_id = self.room.room_id();
+        let transaction_id = self.transaction_id.to_string();
+        let base_client = &self.room.client.base_client;
+
+        base_client.set_local_echo_state(room_id, &transaction_id, LocalEchoState::Pending);
+
+        match self.room.send(self.content.clone(), Some(self.transaction_id)).await {
+            Ok(response) => {
+                base_client.set_local_echo_state(
+                    room_id,
+                    &transaction_id,
+                    LocalEchoState::Sent(response.event_id.clone()),
+                );
+                Ok(response)
+            }
+            Err(e) => {
+                base_client.set_local_echo_state(room_id, &transaction_id, LocalEchoState::Failed);
+                Err(e)
+            }
+        }
+    }
+}
+
 impl Deref for Joined {
     type Target = Common;
 
@@ -85,8 +169,25 @@ impl Joined {
     }
 
     /// Leave this room.
+    ///
+    /// If the room is a direct message it is removed from the `m.direct`
+    /// account data.
     pub async fn leave(&self) -> Result<()> {
-        self.inner.leave().await
+        self.inner.leave().await?;
+
+        if self.is_direct() {
+            let room_id = self.room_id();
+
+            self.client
+                .update_direct_rooms(|direct| {
+                    for rooms in direct.values_mut() {
+                        rooms.retain(|r| r != room_id);
+                    }
+                })
+                .await?;
+        }
+
+        Ok(())
     }
 
     /// Ban the user with `UserId` from this room.
@@ -130,6 +231,28 @@ impl Joined {
         Ok(())
     }
 
+    /// Accept the request of the given user to join this room.
+    ///
+    /// The user is invited to the room, it's up to them to join it afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The `UserId` of the user that knocked on the room.
+    pub async fn accept_knock(&self, user_id: &UserId) -> Result<()> {
+        self.invite_user_by_id(user_id).await
+    }
+
+    /// Deny the request of the given user to join this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The `UserId` of the user that knocked on the room.
+    ///
+    /// * `reason` - Optional reason why the request is denied.
+    pub async fn deny_knock(&self, user_id: &UserId, reason: Option<&str>) -> Result<()> {
+        self.kick_user(user_id, reason).await
+    }
+
     /// Invite the specified user by third party id to this room.
     ///
     /// # Arguments
@@ -288,6 +411,7 @@ impl Joined {
                 let invited =
                     self.client.store().get_invited_user_ids(self.inner.room_id()).await?;
                 let members = joined.iter().chain(&invited);
+                self.client.update_stale_devices(members.clone()).await?;
                 self.client.claim_one_time_keys(members).await?;
             };
 
@@ -318,8 +442,14 @@ impl Joined {
     async fn share_group_session(&self) -> Result<()> {
         let mut requests =
             self.client.base_client.share_group_session(self.inner.room_id()).await?;
+        let progress =
+            self.client.base_client.room_key_sharing_progress(self.inner.room_id()).await;
 
         for request in requests.drain(..) {
+            if progress.as_ref().map_or(false, |p| p.is_cancelled()) {
+                return Err(MatrixError::OlmError(OlmError::RoomKeySharingCancelled).into());
+            }
+
             let response = self.client.send_to_device(&request).await?;
 
             self.client.base_client.mark_request_as_sent(&request.txn_id, &response).await?;
@@ -328,6 +458,22 @@ impl Joined {
         Ok(())
     }
 
+    /// Get a handle to the progress of the latest room key sharing in this
+    /// room.
+    ///
+    /// Sending the first message in a big encrypted room, or the first one
+    /// after the room key was rotated, requires the room key to be encrypted
+    /// for and sent to every device in the room. The handle can be used to
+    /// show the progress of this, or to cancel it, while [`send()`] is
+    /// waiting for the room key to be shared.
+    ///
+    /// [`send()`]: #method.send
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn room_key_sharing_progress(&self) -> Option<RoomKeySharingProgress> {
+        self.client.base_client.room_key_sharing_progress(self.inner.room_id()).await
+    }
+
     /// Send a room message to this room.
     ///
     /// Returns the parsed response from the server.
@@ -377,11 +523,26 @@ impl Joined {
         content: impl Into<AnyMessageEventContent>,
         txn_id: Option<Uuid>,
     ) -> Result<send_message_event::Response> {
+        // Don't encrypt the event again if the server already accepted it.
+        if let Some(txn_id) = &txn_id {
+            if let Some(event_id) = self.client.transaction_event_id(self.room_id(), txn_id).await?
+            {
+                return Ok(send_message_event::Response::new(event_id));
+            }
+        }
+
+        let mut content = content.into();
+        self.client.intercept_send(self, &mut content).await?;
+
+        let encrypted = self.requires_encryption().await?;
+
         #[cfg(not(feature = "encryption"))]
-        let content: AnyMessageEventContent = content.into();
+        if encrypted {
+            return Err(crate::Error::EncryptionRequired(self.inner.room_id().clone()));
+        }
 
         #[cfg(feature = "encryption")]
-        let content = if self.is_encrypted() {
+        let content = if encrypted {
             if !self.are_members_synced() {
                 self.request_members().await?;
                 // TODO query keys here?
@@ -392,14 +553,154 @@ impl Joined {
                 self.client.base_client.encrypt(self.inner.room_id(), content).await?,
             )
         } else {
-            content.into()
+            content
         };
 
-        let txn_id = txn_id.unwrap_or_else(Uuid::new_v4).to_string();
-        let request = send_message_event::Request::new(self.inner.room_id(), &txn_id, &content);
+        self.send_raw(content, txn_id).await
+    }
+
+    /// Send an image of an image pack as a sticker to this room.
+    ///
+    /// The image is sent as a `m.sticker` event, which is encrypted like any
+    /// other message if the room is encrypted. Use
+    /// [`Client::image_packs()`](crate::Client::image_packs) or
+    /// `image_packs()` of the room to find the available images.
+    ///
+    /// # Arguments
+    ///
+    /// * `shortcode` - The short code of the image in its pack.
+    ///
+    /// * `image` - The image that should be sent.
+    ///
+    /// * `txn_id` - A unique `Uuid` that can be attached to the event as its
+    /// transaction ID. If not given one is created for the event.
+    pub async fn send_sticker(
+        &self,
+        shortcode: &str,
+        image: &PackImage,
+        txn_id: Option<Uuid>,
+    ) -> Result<send_message_event::Response> {
+        self.send(AnyMessageEventContent::Sticker(image.sticker_content(shortcode)), txn_id).await
+    }
+
+    /// Add a local echo for a room message and get a handle to send it.
+    ///
+    /// The local echo is immediately available using the
+    /// [`local_echoes()`](#method.local_echoes) method and is removed once the
+    /// remote echo of the event is received in a sync. The remote echo is
+    /// matched to the local one using the transaction id, this works for
+    /// encrypted rooms as well since we decrypt our own events using the
+    /// inbound group session that belongs to our outbound one.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the message event.
+    ///
+    /// * `txn_id` - A unique `Uuid` that is used as the transaction id of the
+    /// event. If not given one is created for the message.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use matrix_sdk::{Client, identifiers::room_id};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # let room_id = room_id!("!test:localhost");
+    /// # let room = client.get_joined_room(&room_id).unwrap();
+    /// use matrix_sdk::events::{
+    ///     AnyMessageEventContent,
+    ///     room::message::MessageEventContent,
+    /// };
+    ///
+    /// let content =
+    ///     AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Hello"));
+    /// let handle = room.send_with_local_echo(content, None);
+    ///
+    /// // The local echo can be shown in the timeline right away.
+    /// assert_eq!(room.local_echoes().len(), 1);
+    ///
+    /// handle.send().await.unwrap();
+    /// # });
+    /// ```
+    pub fn send_with_local_echo(
+        &self,
+        content: impl Into<AnyMessageEventContent>,
+        txn_id: Option<Uuid>,
+    ) -> LocalEchoHandle {
+        let transaction_id = txn_id.unwrap_or_else(Uuid::new_v4);
+        let content = content.into();
+
+        self.client.base_client.add_local_echo(
+            self.inner.room_id(),
+            transaction_id.to_string(),
+            content.clone(),
+        );
+
+        LocalEchoHandle { room: self.clone(), transaction_id, content }
+    }
+
+    /// Get the local echoes of this room, events that we sent, or are
+    /// sending, but didn't receive in a sync yet.
+    pub fn local_echoes(&self) -> Vec<LocalEcho> {
+        self.client.base_client.local_echoes(self.inner.room_id())
+    }
+
+    /// Send a room message to this room without encrypting it, even if the
+    /// room is encrypted.
+    ///
+    /// The [`send()`](#method.send) method refuses to send plaintext events to
+    /// encrypted rooms, this is the escape hatch for the rare cases where a
+    /// plaintext event in an encrypted room is really wanted. The other members
+    /// of the room will see the event in the clear, so use with care.
+    ///
+    /// # Arguments
+    ///
+    /// * `content` - The content of the message event.
+    ///
+    /// * `txn_id` - A unique `Uuid` that can be attached to a `MessageEvent`
+    /// held in its unsigned field as `transaction_id`. If not given one is
+    /// created for the message.
+    pub async fn send_unencrypted_override(
+        &self,
+        content: impl Into<AnyMessageEventContent>,
+        txn_id: Option<Uuid>,
+    ) -> Result<send_message_event::Response> {
+        if self.requires_encryption().await? {
+            warn!(
+                room_id = self.inner.room_id().as_str(),
+                "Sending an unencrypted event to an encrypted room"
+            );
+        }
+
+        let mut content = content.into();
+        self.client.intercept_send(self, &mut content).await?;
 
-        let response = self.client.send(request, None).await?;
-        Ok(response)
+        self.send_raw(content, txn_id).await
+    }
+
+    async fn send_raw(
+        &self,
+        content: AnyMessageEventContent,
+        txn_id: Option<Uuid>,
+    ) -> Result<send_message_event::Response> {
+        self.client.send_message_event(self.inner.room_id(), &content, txn_id).await
+    }
+
+    /// Check if events that are sent to this room need to be encrypted.
+    ///
+    /// Besides the room info, this looks up the `m.room.encryption` event in
+    /// the state store, so we don't rely on the caller to know the encryption
+    /// state of the room.
+    async fn requires_encryption(&self) -> Result<bool> {
+        Ok(self.is_encrypted()
+            || self
+                .client
+                .store()
+                .get_state_event(self.inner.room_id(), EventType::RoomEncryption, "")
+                .await?
+                .is_some())
     }
 
     /// Send an attachment to this room.
@@ -457,7 +758,16 @@ impl Joined {
         mut reader: &mut R,
         txn_id: Option<Uuid>,
     ) -> Result<send_message_event::Response> {
-        let (response, encrypted_file) = if self.is_encrypted() {
+        let encrypted = self.requires_encryption().await?;
+
+        // Don't upload anything in the clear if the event itself can't be
+        // encrypted.
+        #[cfg(not(feature = "encryption"))]
+        if encrypted {
+            return Err(crate::Error::EncryptionRequired(self.inner.room_id().clone()));
+        }
+
+        let (response, encrypted_file) = if encrypted {
             #[cfg(feature = "encryption")]
             let mut reader = AttachmentEncryptor::new(reader);
             #[cfg(feature = "encryption")]
@@ -568,6 +878,301 @@ impl Joined {
         self.client.send(request, None).await
     }
 
+    /// Start modifying the power levels of this room.
+    ///
+    /// The returned builder refuses changes that our own user isn't allowed to
+    /// make, use [`update_power_levels()`](#method.update_power_levels) to
+    /// send the modified power levels to the room.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # use matrix_sdk::{Client, int, identifiers::{room_id, user_id}};
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let mut client = Client::new(homeserver).unwrap();
+    /// # let room_id = room_id!("!test:localhost");
+    /// let room = client.get_joined_room(&room_id).unwrap();
+    ///
+    /// let builder = room
+    ///     .power_levels_builder()
+    ///     .await
+    ///     .unwrap()
+    ///     .user(&user_id!("@moderator:localhost"), int!(50))
+    ///     .unwrap();
+    ///
+    /// room.update_power_levels(builder).await.unwrap();
+    /// # })
+    /// ```
+    pub async fn power_levels_builder(&self) -> Result<PowerLevelsBuilder> {
+        let power_levels = self.inner.power_levels().await?;
+
+        Ok(power_levels.builder(self.inner.own_user_id())?)
+    }
+
+    /// Send the power levels that were modified using a
+    /// [`PowerLevelsBuilder`] to the room.
+    ///
+    /// # Arguments
+    ///
+    /// * `builder` - The builder holding the modified power levels.
+    pub async fn update_power_levels(
+        &self,
+        builder: PowerLevelsBuilder,
+    ) -> Result<send_state_event::Response> {
+        self.send_state_event(AnyStateEventContent::RoomPowerLevels(builder.build()), "").await
+    }
+
+    /// Change who can read the history of this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `history_visibility` - The new history visibility of the room.
+    pub async fn set_history_visibility(
+        &self,
+        history_visibility: HistoryVisibility,
+    ) -> Result<send_state_event::Response> {
+        let content = HistoryVisibilityEventContent::new(history_visibility);
+        self.send_state_event(AnyStateEventContent::RoomHistoryVisibility(content), "").await
+    }
+
+    /// Enable end-to-end encryption in this room.
+    ///
+    /// This sends an `m.room.encryption` state event using the Megolm
+    /// algorithm and waits until the event comes back in a sync, from then on
+    /// messages sent to this room are encrypted. Nothing is sent if the room
+    /// is already encrypted.
+    ///
+    /// A sync loop needs to be running, an `EncryptionTimeout` error is
+    /// returned if the event doesn't come back within a minute.
+    ///
+    /// Returns a `StateEventNotAllowed` error if our own user doesn't have a
+    /// high enough power level to enable encryption.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # futures::executor::block_on(async {
+    /// # let homeserver = url::Url::parse("http://localhost:8080").unwrap();
+    /// # let mut client = matrix_sdk::Client::new(homeserver).unwrap();
+    /// # let room_id = matrix_sdk::identifiers::room_id!("!test:localhost");
+    /// let room = client.get_joined_room(&room_id).unwrap();
+    ///
+    /// room.enable_encryption().await.unwrap();
+    /// assert!(room.is_encrypted());
+    /// # })
+    /// ```
+    pub async fn enable_encryption(&self) -> Result<()> {
+        if self.is_encrypted() {
+            return Ok(());
+        }
+
+        let event_type = EventType::RoomEncryption;
+
+        if !self.can_user_send_state(self.own_user_id(), &event_type).await? {
+            return Err(crate::Error::StateEventNotAllowed(event_type));
+        }
+
+        // Listen before sending, the sync loop might receive the event before
+        // the response of the send request arrives.
+        let mut changes = Box::pin(self.encryption_changes());
+
+        let content = EncryptionEventContent::new(EventEncryptionAlgorithm::MegolmV1AesSha2);
+        self.send_state_event(AnyStateEventContent::RoomEncryption(content), "").await?;
+
+        if !self.is_encrypted() {
+            let timeout = self.client.runtime.sleep(ENABLE_ENCRYPTION_TIMEOUT);
+
+            if let Either::Right(_) = future::select(changes.next(), timeout).await {
+                if !self.is_encrypted() {
+                    return Err(crate::Error::EncryptionTimeout);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Change who can join this room.
+    ///
+    /// Use [`set_restricted_join_rule()`](#method.set_restricted_join_rule) to
+    /// let members of other rooms join this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `join_rule` - The new join rule of the room.
+    pub async fn set_join_rule(&self, join_rule: JoinRule) -> Result<send_state_event::Response> {
+        let content = JoinRulesEventContent::new(join_rule);
+        self.send_state_event(AnyStateEventContent::RoomJoinRules(content), "").await
+    }
+
+    /// Restrict joining this room to users that are allowed to by one of the
+    /// given rules, e.g. to the members of some other room.
+    ///
+    /// Users can still join the room if they are invited.
+    ///
+    /// Returns the event ID of the new `m.room.join_rules` event.
+    ///
+    /// # Arguments
+    ///
+    /// * `allow` - The rules that allow users to join the room. Rules of a
+    /// custom type are sent with only their type.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # futures::executor::block_on(async {
+    /// # let homeserver = url::Url::parse("http://localhost:8080").unwrap();
+    /// # let mut client = matrix_sdk::Client::new(homeserver).unwrap();
+    /// # let room_id = matrix_sdk::identifiers::room_id!("!test:localhost");
+    /// # let room = client.get_joined_room(&room_id).unwrap();
+    /// use matrix_sdk::{identifiers::room_id, AllowRule};
+    ///
+    /// let space = room_id!("!space:localhost");
+    /// room.set_restricted_join_rule(&[AllowRule::RoomMembership(space)]).await.unwrap();
+    /// # })
+    /// ```
+    pub async fn set_restricted_join_rule(&self, allow: &[AllowRule]) -> Result<EventId> {
+        let allow: Vec<_> = allow
+            .iter()
+            .map(|rule| match rule {
+                AllowRule::RoomMembership(room_id) => {
+                    json!({ "type": "m.room_membership", "room_id": room_id })
+                }
+                AllowRule::Custom(rule_type) => json!({ "type": rule_type }),
+            })
+            .collect();
+
+        let content = json!({ "join_rule": "restricted", "allow": allow });
+        let request = send_raw_state_event::Request::new(
+            self.inner.room_id(),
+            EventType::RoomJoinRules.as_str(),
+            "",
+            content,
+        );
+
+        Ok(self.client.send(request, None).await?.event_id)
+    }
+
+    /// Get the content of the current `m.room.canonical_alias` event of this
+    /// room, as it's found in the state store.
+    async fn canonical_alias_content(&self) -> Result<CanonicalAliasEventContent> {
+        let event = self
+            .client
+            .store()
+            .get_state_event(self.inner.room_id(), EventType::RoomCanonicalAlias, "")
+            .await?
+            .and_then(|e| e.deserialize().ok());
+
+        Ok(match event {
+            Some(AnySyncStateEvent::RoomCanonicalAlias(e)) => e.content,
+            _ => CanonicalAliasEventContent::new(),
+        })
+    }
+
+    async fn send_canonical_alias_content(
+        &self,
+        content: CanonicalAliasEventContent,
+    ) -> Result<send_state_event::Response> {
+        self.send_state_event(AnyStateEventContent::RoomCanonicalAlias(content), "").await
+    }
+
+    /// Change the canonical alias of this room.
+    ///
+    /// The alternative aliases of the room are kept. The homeserver refuses
+    /// aliases that don't point to this room, use
+    /// [`create_alias()`](#method.create_alias) to create a new one.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The new canonical alias of the room, `None` removes the
+    /// canonical alias.
+    pub async fn set_canonical_alias(
+        &self,
+        alias: Option<&RoomAliasId>,
+    ) -> Result<send_state_event::Response> {
+        let mut content = self.canonical_alias_content().await?;
+        content.alias = alias.cloned();
+
+        self.send_canonical_alias_content(content).await
+    }
+
+    /// Advertise an additional alias of this room in its
+    /// `m.room.canonical_alias` event.
+    ///
+    /// The homeserver refuses aliases that don't point to this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The alias that should be added to the alternative aliases.
+    pub async fn add_alt_alias(&self, alias: &RoomAliasId) -> Result<send_state_event::Response> {
+        let mut content = self.canonical_alias_content().await?;
+
+        if !content.alt_aliases.contains(alias) {
+            content.alt_aliases.push(alias.clone());
+        }
+
+        self.send_canonical_alias_content(content).await
+    }
+
+    /// Stop advertising an alternative alias of this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The alias that should be removed from the alternative
+    /// aliases.
+    pub async fn remove_alt_alias(
+        &self,
+        alias: &RoomAliasId,
+    ) -> Result<send_state_event::Response> {
+        let mut content = self.canonical_alias_content().await?;
+        content.alt_aliases.retain(|a| a != alias);
+
+        self.send_canonical_alias_content(content).await
+    }
+
+    /// Create a new alias in the room directory of our homeserver that points
+    /// to this room.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The new alias, the server name of it needs to be the one
+    /// of our homeserver.
+    pub async fn create_alias(&self, alias: &RoomAliasId) -> Result<()> {
+        self.client.create_room_alias(alias, self.inner.room_id()).await
+    }
+
+    /// Remove an alias of this room from the room directory of our
+    /// homeserver.
+    ///
+    /// If the alias is the canonical or one of the alternative aliases of this
+    /// room it's removed from the `m.room.canonical_alias` event as well, so
+    /// the room doesn't advertise an alias that doesn't exist anymore.
+    ///
+    /// # Arguments
+    ///
+    /// * `alias` - The alias that should be removed.
+    pub async fn delete_alias(&self, alias: &RoomAliasId) -> Result<()> {
+        self.client.delete_room_alias(alias).await?;
+
+        let mut content = self.canonical_alias_content().await?;
+        let advertised =
+            content.alias.as_ref() == Some(alias) || content.alt_aliases.contains(alias);
+
+        if advertised {
+            if content.alias.as_ref() == Some(alias) {
+                content.alias = None;
+            }
+            content.alt_aliases.retain(|a| a != alias);
+
+            self.send_canonical_alias_content(content).await?;
+        }
+
+        Ok(())
+    }
+
     /// Strips all information out of an event of the room.
     ///
     /// Returns the [`redact_event::Response`] from the server.
@@ -576,6 +1181,12 @@ impl Joined {
     /// with a power level greater than or equal to the redact power level of
     /// the room may redact events there.
     ///
+    /// The power levels of the room are checked before the request is sent,
+    /// [`Error::RedactionNotAllowed`](crate::Error::RedactionNotAllowed) is
+    /// returned if our own user can't redact the event. If the event isn't
+    /// part of the stored timeline we can't know who sent it, only the
+    /// permission to redact our own events is checked then.
+    ///
     /// # Arguments
     ///
     /// * `event_id` - The ID of the event to redact
@@ -606,6 +1217,24 @@ impl Joined {
         reason: Option<&str>,
         txn_id: Option<Uuid>,
     ) -> Result<redact_event::Response> {
+        let own_user_id = self.own_user_id();
+        let sender = self
+            .timeline_event(event_id)
+            .await?
+            .and_then(|e| e.event.deserialize().ok())
+            .map(|e| e.sender().clone());
+
+        let allowed = match sender {
+            Some(sender) if &sender != own_user_id => {
+                self.can_user_redact_other(own_user_id).await?
+            }
+            _ => self.can_user_redact_own(own_user_id).await?,
+        };
+
+        if !allowed {
+            return Err(crate::Error::RedactionNotAllowed(event_id.clone()));
+        }
+
         let txn_id = txn_id.unwrap_or_else(Uuid::new_v4).to_string();
         let request =
             assign!(redact_event::Request::new(self.inner.room_id(), event_id, &txn_id), {
@@ -615,3 +1244,59 @@ impl Joined {
         self.client.send(request, None).await
     }
 }
+
+pub(crate) mod send_raw_state_event {
+    //! [PUT /_matrix/client/r0/rooms/{roomId}/state/{eventType}/{stateKey}](https://matrix.org/docs/spec/client_server/r0.6.1#put-matrix-client-r0-rooms-roomid-state-eventtype-statekey)
+
+    use ruma::{api::ruma_api, EventId, RoomId};
+    use serde_json::Value as JsonValue;
+
+    ruma_api! {
+        metadata: {
+            description: "Send a state event with arbitrary content to a room.",
+            method: PUT,
+            name: "send_raw_state_event",
+            path: "/_matrix/client/r0/rooms/:room_id/state/:event_type/:state_key",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room to set the state in.
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            /// The type of the event.
+            #[ruma_api(path)]
+            pub event_type: &'a str,
+
+            /// The state key of the event.
+            #[ruma_api(path)]
+            pub state_key: &'a str,
+
+            /// The content of the event.
+            #[ruma_api(body)]
+            pub content: JsonValue,
+        }
+
+        response: {
+            /// A unique identifier for the event.
+            pub event_id: EventId,
+        }
+
+        error: ruma::api::client::Error
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a new `Request` with the given room, event type, state key
+        /// and content.
+        pub fn new(
+            room_id: &'a RoomId,
+            event_type: &'a str,
+            state_key: &'a str,
+            content: JsonValue,
+        ) -> Self {
+            Self { room_id, event_type, state_key, content }
+        }
+    }
+}
@@ -3,11 +3,20 @@ use std::ops::Deref;
 use crate::RoomType;
 
 mod common;
+mod export;
 mod invited;
 mod joined;
 mod left;
+mod relations;
 
-pub use self::{common::Common, invited::Invited, joined::Joined, left::Left};
+pub use self::{
+    common::Common,
+    export::{ExportFormat, ExportMedia},
+    invited::{Invite, Invited, Inviter},
+    joined::{Joined, LocalEchoHandle},
+    left::Left,
+    relations::{RelationType, Relations, RelationsPagination},
+};
 
 /// An enum that abstracts over the different states a room can be in.
 #[derive(Debug, Clone)]
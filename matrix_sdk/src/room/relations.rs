@@ -0,0 +1,156 @@
+use std::fmt;
+
+use matrix_sdk_base::deserialized_responses::SyncRoomEvent;
+use ruma::{api::client::r0::message::get_message_events::Direction, UInt};
+
+/// The type of a relation between two events.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RelationType {
+    /// A reaction to an event, `m.annotation`.
+    Annotation,
+    /// An edit of an event, `m.replace`.
+    Replacement,
+    /// An event that is part of the thread of an event, `m.thread`.
+    Thread,
+    /// A reference to an event, `m.reference`.
+    Reference,
+    /// A relation type that isn't known to the SDK.
+    Custom(String),
+}
+
+impl RelationType {
+    /// Get the relation type as it is used in the `rel_type` field of a
+    /// `m.relates_to` object.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Annotation => "m.annotation",
+            Self::Replacement => "m.replace",
+            Self::Thread => "m.thread",
+            Self::Reference => "m.reference",
+            Self::Custom(rel_type) => rel_type,
+        }
+    }
+}
+
+impl fmt::Display for RelationType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Options controlling which chunk of the relations of an event should be
+/// fetched.
+#[derive(Clone, Debug)]
+pub struct RelationsPagination {
+    /// The token to start returning events from, usually the `next_batch`
+    /// token of a previous [`Relations`] response.
+    pub from: Option<String>,
+    /// The token to stop returning events at.
+    pub to: Option<String>,
+    /// The maximum number of events to return.
+    pub limit: Option<UInt>,
+    /// The direction to return events from, defaults to backwards, the newest
+    /// relations come first.
+    pub dir: Direction,
+}
+
+impl Default for RelationsPagination {
+    fn default() -> Self {
+        Self { from: None, to: None, limit: None, dir: Direction::Backward }
+    }
+}
+
+/// A chunk of events that relate to an event.
+#[derive(Clone, Debug, Default)]
+pub struct Relations {
+    /// The related events, encrypted events are decrypted if possible.
+    pub chunk: Vec<SyncRoomEvent>,
+    /// The token to fetch the next chunk of relations, `None` if there are no
+    /// more relations.
+    pub next_batch: Option<String>,
+    /// The token to fetch the previous chunk of relations.
+    pub prev_batch: Option<String>,
+    /// How deep the server followed relations of relations, if the relations
+    /// were fetched recursively.
+    pub recursion_depth: Option<UInt>,
+}
+
+pub(crate) mod get_relating_events {
+    //! [GET /_matrix/client/v1/rooms/{roomId}/relations/{eventId}/{relType}](https://spec.matrix.org/v1.3/client-server-api/#get_matrixclientv1roomsroomidrelationseventidreltype)
+
+    use ruma::{
+        api::{client::r0::message::get_message_events::Direction, ruma_api},
+        events::AnyRoomEvent,
+        serde::Raw,
+        EventId, RoomId, UInt,
+    };
+
+    ruma_api! {
+        metadata: {
+            description: "Get the child events for a given parent event, with a given relation type.",
+            method: GET,
+            name: "get_relating_events",
+            path: "/_matrix/client/v1/rooms/:room_id/relations/:event_id/:rel_type",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The ID of the room containing the parent event.
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            /// The ID of the parent event whose child events are to be returned.
+            #[ruma_api(path)]
+            pub event_id: &'a EventId,
+
+            /// The relation type to search for.
+            #[ruma_api(path)]
+            pub rel_type: &'a str,
+
+            /// The pagination token to start returning results from.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub from: Option<&'a str>,
+
+            /// The pagination token to stop returning results at.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub to: Option<&'a str>,
+
+            /// The maximum number of results to return in a single chunk.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+
+            /// The direction to return events from.
+            #[ruma_api(query)]
+            pub dir: Direction,
+
+            /// Whether to include events which relate indirectly to the given
+            /// event.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub recurse: Option<bool>,
+        }
+
+        response: {
+            /// The child events of the requested event.
+            pub chunk: Vec<Raw<AnyRoomEvent>>,
+
+            /// An opaque string representing a pagination token.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub next_batch: Option<String>,
+
+            /// An opaque string representing a pagination token.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub prev_batch: Option<String>,
+
+            /// If `recurse` was set, the depth to which the server recursed.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub recursion_depth: Option<UInt>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
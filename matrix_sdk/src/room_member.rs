@@ -1,6 +1,6 @@
 use std::ops::Deref;
 
-use ruma::api::client::r0::media::{get_content, get_content_thumbnail};
+use matrix_sdk_base::media::{MediaFormat, MediaRequest, MediaType};
 
 use crate::{BaseRoomMember, Client, Result};
 
@@ -26,14 +26,14 @@ impl RoomMember {
 
     /// Gets the avatar of this member, if set.
     ///
+    /// The avatar is fetched through the media cache.
+    ///
     /// Returns the avatar. No guarantee on the size of the image is given.
-    /// If no size is given the full-sized avatar will be returned.
     ///
     /// # Arguments
     ///
-    /// * `width` - The desired width of the avatar.
-    ///
-    /// * `height` - The desired height of the avatar.
+    /// * `format` - The desired format of the avatar, the full-sized avatar or
+    /// a thumbnail.
     ///
     /// # Example
     /// ```no_run
@@ -41,6 +41,9 @@ impl RoomMember {
     /// # use matrix_sdk::Client;
     /// # use matrix_sdk::identifiers::room_id;
     /// # use matrix_sdk::RoomMember;
+    /// # use matrix_sdk::media::{MediaFormat, MediaThumbnailSize};
+    /// # use matrix_sdk::api::r0::media::get_content_thumbnail::Method;
+    /// # use matrix_sdk::uint;
     /// # use url::Url;
     /// # let homeserver = Url::parse("http://example.com").unwrap();
     /// # block_on(async {
@@ -53,24 +56,16 @@ impl RoomMember {
     ///     .unwrap();
     /// let members = room.members().await.unwrap();
     /// let member = members.first().unwrap();
-    /// if let Some(avatar) = member.avatar(Some(96), Some(96)).await.unwrap() {
+    /// let size = MediaThumbnailSize { method: Method::Scale, width: uint!(96), height: uint!(96) };
+    /// if let Some(avatar) = member.avatar(MediaFormat::Thumbnail(size)).await.unwrap() {
     ///     std::fs::write("avatar.png", avatar);
     /// }
     /// # })
     /// ```
-    pub async fn avatar(&self, width: Option<u32>, height: Option<u32>) -> Result<Option<Vec<u8>>> {
-        // TODO: try to offer the avatar from cache, requires avatar cache
+    pub async fn avatar(&self, format: MediaFormat) -> Result<Option<Vec<u8>>> {
         if let Some(url) = self.avatar_url() {
-            if let (Some(width), Some(height)) = (width, height) {
-                let request =
-                    get_content_thumbnail::Request::from_url(url, width.into(), height.into())?;
-                let response = self.client.send(request, None).await?;
-                Ok(Some(response.file))
-            } else {
-                let request = get_content::Request::from_url(url)?;
-                let response = self.client.send(request, None).await?;
-                Ok(Some(response.file))
-            }
+            let request = MediaRequest { media_type: MediaType::Uri(url.clone()), format };
+            Ok(Some(self.client.get_media_content(&request, true).await?))
         } else {
             Ok(None)
         }
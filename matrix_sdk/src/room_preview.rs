@@ -0,0 +1,263 @@
+//! Previews of rooms that we aren't a member of.
+
+use ruma::{
+    directory::PublicRoomsChunk,
+    events::{
+        room::{join_rules::JoinRule, member::MembershipState},
+        AnyStateEvent,
+    },
+    serde::Raw,
+    MxcUri, RoomAliasId, RoomId,
+};
+
+/// A preview of a room that we aren't a member of, e.g. to show a
+/// confirmation screen before joining it.
+///
+/// Depending on the endpoint the preview was created from, the join rule and
+/// the encryption state of the room might not be known.
+#[derive(Clone, Debug)]
+pub struct RoomPreview {
+    /// The unique id of the room.
+    pub room_id: RoomId,
+    /// The canonical alias of the room, if it has one.
+    pub canonical_alias: Option<RoomAliasId>,
+    /// The name of the room, if it has one.
+    pub name: Option<String>,
+    /// The topic of the room, if it has one.
+    pub topic: Option<String>,
+    /// The avatar of the room, if it has one.
+    pub avatar_url: Option<MxcUri>,
+    /// The number of members that joined the room.
+    pub num_joined_members: u64,
+    /// Who can join the room, if it's known.
+    pub join_rule: Option<JoinRule>,
+    /// Whether the room is encrypted, if it's known.
+    pub is_encrypted: Option<bool>,
+}
+
+impl RoomPreview {
+    /// Create a preview out of the full state of a room.
+    pub(crate) fn from_state(room_id: RoomId, state: &[Raw<AnyStateEvent>]) -> Self {
+        let mut preview = Self {
+            room_id,
+            canonical_alias: None,
+            name: None,
+            topic: None,
+            avatar_url: None,
+            num_joined_members: 0,
+            join_rule: None,
+            is_encrypted: Some(false),
+        };
+
+        for event in state.iter().filter_map(|e| e.deserialize().ok()) {
+            match event {
+                AnyStateEvent::RoomCanonicalAlias(e) => preview.canonical_alias = e.content.alias,
+                AnyStateEvent::RoomName(e) => {
+                    preview.name = e.content.name().map(ToOwned::to_owned)
+                }
+                AnyStateEvent::RoomTopic(e) => preview.topic = Some(e.content.topic),
+                AnyStateEvent::RoomAvatar(e) => preview.avatar_url = e.content.url,
+                AnyStateEvent::RoomJoinRules(e) => preview.join_rule = Some(e.content.join_rule),
+                AnyStateEvent::RoomEncryption(_) => preview.is_encrypted = Some(true),
+                AnyStateEvent::RoomMember(e) if e.content.membership == MembershipState::Join => {
+                    preview.num_joined_members += 1
+                }
+                _ => {}
+            }
+        }
+
+        preview
+    }
+}
+
+impl From<PublicRoomsChunk> for RoomPreview {
+    fn from(chunk: PublicRoomsChunk) -> Self {
+        Self {
+            room_id: chunk.room_id,
+            canonical_alias: chunk.canonical_alias,
+            name: chunk.name,
+            topic: chunk.topic,
+            avatar_url: chunk.avatar_url,
+            num_joined_members: chunk.num_joined_members.into(),
+            // Only public rooms are listed in the room directory.
+            join_rule: Some(JoinRule::Public),
+            is_encrypted: None,
+        }
+    }
+}
+
+impl From<get_room_summary::Response> for RoomPreview {
+    fn from(response: get_room_summary::Response) -> Self {
+        Self {
+            room_id: response.room_id,
+            canonical_alias: response.canonical_alias,
+            name: response.name,
+            topic: response.topic,
+            avatar_url: response.avatar_url,
+            num_joined_members: response.num_joined_members.into(),
+            join_rule: response.join_rule,
+            is_encrypted: Some(response.encryption.is_some()),
+        }
+    }
+}
+
+impl From<get_hierarchy::HierarchyRoom> for RoomPreview {
+    fn from(room: get_hierarchy::HierarchyRoom) -> Self {
+        Self {
+            room_id: room.room_id,
+            canonical_alias: room.canonical_alias,
+            name: room.name,
+            topic: room.topic,
+            avatar_url: room.avatar_url,
+            num_joined_members: room.num_joined_members.into(),
+            join_rule: room.join_rule,
+            is_encrypted: None,
+        }
+    }
+}
+
+pub(crate) mod get_room_summary {
+    //! [GET /_matrix/client/unstable/im.nheko.summary/rooms/{roomIdOrAlias}/summary](https://github.com/matrix-org/matrix-spec-proposals/pull/3266)
+
+    use ruma::{
+        api::ruma_api, events::room::join_rules::JoinRule, MxcUri, RoomAliasId, RoomId,
+        RoomIdOrAliasId, ServerName, UInt,
+    };
+
+    ruma_api! {
+        metadata: {
+            description: "Get a summary of a room, even if we aren't a member of it.",
+            method: GET,
+            name: "get_room_summary",
+            path: "/_matrix/client/unstable/im.nheko.summary/rooms/:room_id_or_alias/summary",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room that should be summarized.
+            #[ruma_api(path)]
+            pub room_id_or_alias: &'a RoomIdOrAliasId,
+
+            /// Servers that are in the room, used if our homeserver isn't.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "<[_]>::is_empty")]
+            pub via: &'a [Box<ServerName>],
+        }
+
+        response: {
+            /// The unique id of the room.
+            pub room_id: RoomId,
+
+            /// The canonical alias of the room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub canonical_alias: Option<RoomAliasId>,
+
+            /// The name of the room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub name: Option<String>,
+
+            /// The topic of the room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub topic: Option<String>,
+
+            /// The avatar of the room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub avatar_url: Option<MxcUri>,
+
+            /// The number of members that joined the room.
+            pub num_joined_members: UInt,
+
+            /// The join rule of the room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub join_rule: Option<JoinRule>,
+
+            /// The encryption algorithm of the room, if it's encrypted.
+            #[serde(
+                rename = "im.nheko.summary.encryption",
+                alias = "encryption",
+                skip_serializing_if = "Option::is_none"
+            )]
+            pub encryption: Option<String>,
+        }
+
+        error: ruma::api::client::Error
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a new `Request` for the given room ID or alias.
+        pub fn new(room_id_or_alias: &'a RoomIdOrAliasId) -> Self {
+            Self { room_id_or_alias, via: &[] }
+        }
+    }
+}
+
+pub(crate) mod get_hierarchy {
+    //! [GET /_matrix/client/v1/rooms/{roomId}/hierarchy](https://spec.matrix.org/v1.3/client-server-api/#get_matrixclientv1roomsroomidhierarchy)
+
+    use ruma::{
+        api::ruma_api, events::room::join_rules::JoinRule, MxcUri, RoomAliasId, RoomId, UInt,
+    };
+    use serde::{Deserialize, Serialize};
+
+    /// A room of the space hierarchy.
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    pub struct HierarchyRoom {
+        /// The unique id of the room.
+        pub room_id: RoomId,
+        /// The canonical alias of the room.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub canonical_alias: Option<RoomAliasId>,
+        /// The name of the room.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub name: Option<String>,
+        /// The topic of the room.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub topic: Option<String>,
+        /// The avatar of the room.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub avatar_url: Option<MxcUri>,
+        /// The number of members that joined the room.
+        pub num_joined_members: UInt,
+        /// The join rule of the room.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub join_rule: Option<JoinRule>,
+    }
+
+    ruma_api! {
+        metadata: {
+            description: "Get the rooms of a space, starting with the space itself.",
+            method: GET,
+            name: "get_hierarchy",
+            path: "/_matrix/client/v1/rooms/:room_id/hierarchy",
+            rate_limited: true,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room the hierarchy starts at.
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            /// The maximum number of rooms in the response.
+            #[ruma_api(query)]
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub limit: Option<UInt>,
+        }
+
+        response: {
+            /// The rooms of the hierarchy, the first one is the room the
+            /// hierarchy starts at.
+            pub rooms: Vec<HierarchyRoom>,
+        }
+
+        error: ruma::api::client::Error
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a new `Request` for the given room.
+        pub fn new(room_id: &'a RoomId) -> Self {
+            Self { room_id, limit: None }
+        }
+    }
+}
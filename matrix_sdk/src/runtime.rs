@@ -0,0 +1,131 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use matrix_sdk_common::{executor, instant::Duration, AsyncTraitDeps};
+
+/// A boxed future that is returned or accepted by a [`Runtime`].
+///
+/// The future needs to be `Send` on every target except WASM.
+#[cfg(not(target_arch = "wasm32"))]
+pub type RuntimeFuture<T> = futures::future::BoxFuture<'static, T>;
+
+/// A boxed future that is returned or accepted by a [`Runtime`].
+///
+/// The future needs to be `Send` on every target except WASM.
+#[cfg(target_arch = "wasm32")]
+pub type RuntimeFuture<T> = futures::future::LocalBoxFuture<'static, T>;
+
+/// Abstraction over the async runtime the client runs on.
+///
+/// The client uses the runtime to spawn background tasks and to wait between
+/// retries of requests and syncs, this allows implementors to run the client
+/// on any async runtime.
+///
+/// The [`DefaultRuntime`] is used unless another runtime is configured using
+/// [`ClientConfig::runtime()`](crate::ClientConfig::runtime).
+pub trait Runtime: AsyncTraitDeps {
+    /// Run the given future in the background.
+    fn spawn(&self, future: RuntimeFuture<()>);
+
+    /// Get a future that resolves once the given duration elapsed.
+    fn sleep(&self, duration: Duration) -> RuntimeFuture<()>;
+
+    /// Run the given blocking task on a thread where blocking is acceptable.
+    ///
+    /// Spawns a new thread for the task by default.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        std::thread::spawn(task);
+    }
+}
+
+/// The runtime the client uses if no other runtime is configured.
+///
+/// Tasks are spawned on the tokio runtime on most targets and using
+/// `wasm-bindgen-futures` on WASM, timers work on any runtime.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultRuntime;
+
+impl Runtime for DefaultRuntime {
+    fn spawn(&self, future: RuntimeFuture<()>) {
+        executor::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> RuntimeFuture<()> {
+        Box::pin(futures_timer::Delay::new(duration))
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        tokio::task::spawn_blocking(task);
+    }
+}
+
+/// A runtime that uses `async-std` to spawn tasks and to sleep.
+#[cfg(all(feature = "async-std", not(target_arch = "wasm32")))]
+#[cfg_attr(feature = "docs", doc(cfg(feature = "async-std")))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStdRuntime;
+
+#[cfg(all(feature = "async-std", not(target_arch = "wasm32")))]
+impl Runtime for AsyncStdRuntime {
+    fn spawn(&self, future: RuntimeFuture<()>) {
+        async_std::task::spawn(future);
+    }
+
+    fn sleep(&self, duration: Duration) -> RuntimeFuture<()> {
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
+
+/// Run the given blocking function using the given runtime and wait for its
+/// result.
+#[cfg(all(feature = "encryption", not(target_arch = "wasm32")))]
+pub(crate) async fn run_blocking<T: Send + 'static>(
+    runtime: &dyn Runtime,
+    task: impl FnOnce() -> T + Send + 'static,
+) -> T {
+    let (sender, receiver) = futures::channel::oneshot::channel();
+
+    runtime.spawn_blocking(Box::new(move || {
+        let _ = sender.send(task());
+    }));
+
+    receiver.await.expect("The blocking task was dropped before it finished")
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    #[cfg(feature = "encryption")]
+    use super::run_blocking;
+    use super::{DefaultRuntime, Runtime};
+
+    #[tokio::test]
+    async fn default_runtime() {
+        let runtime: Arc<dyn Runtime> = Arc::new(DefaultRuntime);
+        let (sender, receiver) = futures::channel::oneshot::channel();
+
+        runtime.spawn(Box::pin(async move {
+            sender.send(1).unwrap();
+        }));
+        runtime.sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(receiver.await.unwrap(), 1);
+
+        #[cfg(feature = "encryption")]
+        assert_eq!(run_blocking(&*runtime, || 2).await, 2);
+    }
+}
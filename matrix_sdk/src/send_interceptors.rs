@@ -0,0 +1,40 @@
+//! Interceptors that run for every message event that is sent to a room.
+
+use matrix_sdk_common::async_trait;
+use ruma::events::AnyMessageEventContent;
+
+use crate::room;
+
+/// The decision of a [`SendInterceptor`] about an outgoing event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SendDecision {
+    /// The event should be sent, possibly after it was modified.
+    Send,
+    /// The event must not be sent, the send fails with a `SendVetoed` error
+    /// containing the reason.
+    Veto(String),
+}
+
+/// An interceptor that is called for every message event before it's sent.
+///
+/// Interceptors can inspect and modify the content of outgoing events, e.g.
+/// to add custom fields or to attach signatures, or they can veto the send.
+/// They run before the event is encrypted, so they see the plain content in
+/// encrypted rooms as well.
+///
+/// Interceptors are added with [`Client::add_send_interceptor()`] and run in
+/// the order they were added, the first one vetoing the send stops the
+/// remaining ones from running.
+///
+/// [`Client::add_send_interceptor()`]: crate::Client::add_send_interceptor
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait SendInterceptor: Send + Sync {
+    /// Called with the content of an event that is about to be sent to the
+    /// given room.
+    async fn before_send(
+        &self,
+        room: &room::Joined,
+        content: &mut AnyMessageEventContent,
+    ) -> SendDecision;
+}
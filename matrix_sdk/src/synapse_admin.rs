@@ -0,0 +1,396 @@
+//! Helpers for the [admin API] of Synapse.
+//!
+//! The admin API is specific to Synapse and requires the user of the [`Client`]
+//! to be a server admin. It's meant for operators that build moderation tooling
+//! on top of the SDK.
+//!
+//! [admin API]: https://matrix-org.github.io/synapse/latest/usage/administration/admin_api/
+
+use ruma::{
+    api::client::r0::admin::get_user_info, assign, EventId, MilliSecondsSinceUnixEpoch, RoomId,
+    UserId,
+};
+
+use crate::{Client, Result};
+
+/// Options for purging the history of a room.
+#[derive(Clone, Debug, Default)]
+pub struct PurgeHistory {
+    /// Purge all the events up to and including this event.
+    pub up_to_event_id: Option<EventId>,
+
+    /// Purge all the events that were sent before this point in time.
+    ///
+    /// Ignored if `up_to_event_id` is set.
+    pub up_to_ts: Option<MilliSecondsSinceUnixEpoch>,
+
+    /// Also purge the events that were sent by users of this homeserver,
+    /// otherwise only events of remote users are purged.
+    pub delete_local_events: bool,
+}
+
+/// The state of a history purge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PurgeStatus {
+    /// The purge is still running.
+    Active,
+    /// The purge finished successfully.
+    Complete,
+    /// The purge failed.
+    Failed,
+    /// A status that isn't known to the SDK.
+    Unknown(String),
+}
+
+impl From<String> for PurgeStatus {
+    fn from(status: String) -> Self {
+        match status.as_str() {
+            "active" => Self::Active,
+            "complete" => Self::Complete,
+            "failed" => Self::Failed,
+            _ => Self::Unknown(status),
+        }
+    }
+}
+
+/// Options for shutting down a room.
+#[derive(Clone, Debug, Default)]
+pub struct RoomShutdown {
+    /// Create a new room, owned by this user, and move all the local members
+    /// of the shut down room to it.
+    pub new_room_user_id: Option<UserId>,
+
+    /// The name of the new room.
+    pub room_name: Option<String>,
+
+    /// The first message that is sent to the new room.
+    pub message: Option<String>,
+
+    /// Prevent users of this homeserver from joining the room in the future.
+    pub block: bool,
+
+    /// Remove all traces of the room from the database of the homeserver.
+    pub purge: bool,
+}
+
+/// A handle to call the admin API of Synapse.
+///
+/// Created with [`Client::synapse_admin()`].
+#[derive(Clone, Debug)]
+pub struct SynapseAdmin {
+    pub(crate) client: Client,
+}
+
+impl SynapseAdmin {
+    /// Get information about the sessions and connections of the given user.
+    ///
+    /// Users that aren't server admins can only get information about
+    /// themselves.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user to get information about.
+    pub async fn whois(&self, user_id: &UserId) -> Result<get_user_info::Response> {
+        self.client.send(get_user_info::Request::new(user_id), None).await
+    }
+
+    /// Deactivate the account of the given user.
+    ///
+    /// This can't be undone.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should be deactivated.
+    ///
+    /// * `erase` - Also mark the user as erased, their events won't be shown
+    /// to users that join rooms later on.
+    pub async fn deactivate_user(&self, user_id: &UserId, erase: bool) -> Result<()> {
+        let request = deactivate_user::Request { user_id, erase };
+        self.client.send(request, None).await?;
+
+        Ok(())
+    }
+
+    /// Start purging old events of the given room from the database of the
+    /// homeserver.
+    ///
+    /// Returns the ID of the purge, the purge runs in the background, use
+    /// [`purge_history_status()`](#method.purge_history_status) to find out if
+    /// it finished.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room whose history should be purged.
+    ///
+    /// * `options` - Which events should be purged.
+    pub async fn purge_history(&self, room_id: &RoomId, options: PurgeHistory) -> Result<String> {
+        let request = assign!(purge_history::Request::new(room_id), {
+            purge_up_to_event_id: options.up_to_event_id.as_ref(),
+            purge_up_to_ts: options.up_to_ts,
+            delete_local_events: options.delete_local_events,
+        });
+
+        Ok(self.client.send(request, None).await?.purge_id)
+    }
+
+    /// Get the status of a purge that was started with
+    /// [`purge_history()`](#method.purge_history).
+    ///
+    /// # Arguments
+    ///
+    /// * `purge_id` - The ID of the purge.
+    pub async fn purge_history_status(&self, purge_id: &str) -> Result<PurgeStatus> {
+        let request = purge_history_status::Request { purge_id };
+
+        Ok(self.client.send(request, None).await?.status.into())
+    }
+
+    /// Shut down the given room, all the local users are removed from it.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room that should be shut down.
+    ///
+    /// * `options` - What should happen to the room and its members.
+    pub async fn shutdown_room(
+        &self,
+        room_id: &RoomId,
+        options: RoomShutdown,
+    ) -> Result<shutdown_room::Response> {
+        let request = shutdown_room::Request {
+            room_id,
+            new_room_user_id: options.new_room_user_id.as_ref(),
+            room_name: options.room_name.as_deref(),
+            message: options.message.as_deref(),
+            block: options.block,
+            purge: options.purge,
+        };
+
+        self.client.send(request, None).await
+    }
+}
+
+pub mod deactivate_user {
+    //! [POST /_synapse/admin/v1/deactivate/{userId}](https://matrix-org.github.io/synapse/latest/admin_api/user_admin_api.html#deactivate-account)
+
+    use ruma::{api::ruma_api, UserId};
+
+    ruma_api! {
+        metadata: {
+            description: "Deactivate the account of a user.",
+            method: POST,
+            name: "deactivate_user",
+            path: "/_synapse/admin/v1/deactivate/:user_id",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The user that should be deactivated.
+            #[ruma_api(path)]
+            pub user_id: &'a UserId,
+
+            /// Whether the user should be marked as erased.
+            pub erase: bool,
+        }
+
+        response: {
+            /// Whether the third party identifiers of the user were unbound
+            /// from the identity server.
+            pub id_server_unbind_result: String,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+pub mod purge_history {
+    //! [POST /_synapse/admin/v1/purge_history/{roomId}](https://matrix-org.github.io/synapse/latest/admin_api/purge_history_api.html)
+
+    use ruma::{api::ruma_api, EventId, MilliSecondsSinceUnixEpoch, RoomId};
+
+    ruma_api! {
+        metadata: {
+            description: "Purge old events of a room from the database.",
+            method: POST,
+            name: "purge_history",
+            path: "/_synapse/admin/v1/purge_history/:room_id",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room whose history should be purged.
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            /// Purge all the events up to and including this event.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub purge_up_to_event_id: Option<&'a EventId>,
+
+            /// Purge all the events that were sent before this point in time.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub purge_up_to_ts: Option<MilliSecondsSinceUnixEpoch>,
+
+            /// Whether events of local users should be purged as well.
+            pub delete_local_events: bool,
+        }
+
+        response: {
+            /// The ID of the purge.
+            pub purge_id: String,
+        }
+
+        error: ruma::api::client::Error
+    }
+
+    impl<'a> Request<'a> {
+        /// Creates a new `Request` that purges nothing for the given room.
+        pub fn new(room_id: &'a RoomId) -> Self {
+            Self {
+                room_id,
+                purge_up_to_event_id: None,
+                purge_up_to_ts: None,
+                delete_local_events: false,
+            }
+        }
+    }
+}
+
+pub mod purge_history_status {
+    //! [GET /_synapse/admin/v1/purge_history_status/{purgeId}](https://matrix-org.github.io/synapse/latest/admin_api/purge_history_api.html#purge-status-query)
+
+    use ruma::api::ruma_api;
+
+    ruma_api! {
+        metadata: {
+            description: "Get the status of a history purge.",
+            method: GET,
+            name: "purge_history_status",
+            path: "/_synapse/admin/v1/purge_history_status/:purge_id",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The ID of the purge.
+            #[ruma_api(path)]
+            pub purge_id: &'a str,
+        }
+
+        response: {
+            /// The status of the purge, `active`, `complete` or `failed`.
+            pub status: String,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+pub mod shutdown_room {
+    //! [DELETE /_synapse/admin/v1/rooms/{roomId}](https://matrix-org.github.io/synapse/latest/admin_api/rooms.html#delete-room-api)
+
+    use ruma::{api::ruma_api, RoomAliasId, RoomId, UserId};
+
+    ruma_api! {
+        metadata: {
+            description: "Shut down a room and remove all local users from it.",
+            method: DELETE,
+            name: "shutdown_room",
+            path: "/_synapse/admin/v1/rooms/:room_id",
+            rate_limited: false,
+            authentication: AccessToken,
+        }
+
+        request: {
+            /// The room that should be shut down.
+            #[ruma_api(path)]
+            pub room_id: &'a RoomId,
+
+            /// The user that should own the room the local members are moved
+            /// to.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub new_room_user_id: Option<&'a UserId>,
+
+            /// The name of the new room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub room_name: Option<&'a str>,
+
+            /// The first message that is sent to the new room.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            pub message: Option<&'a str>,
+
+            /// Whether local users should be prevented from joining the room
+            /// in the future.
+            pub block: bool,
+
+            /// Whether the room should be removed from the database.
+            pub purge: bool,
+        }
+
+        response: {
+            /// The local users that were removed from the room.
+            pub kicked_users: Vec<UserId>,
+
+            /// The local users that couldn't be removed from the room.
+            pub failed_to_kick_users: Vec<UserId>,
+
+            /// The local aliases that were moved to the new room.
+            pub local_aliases: Vec<RoomAliasId>,
+
+            /// The room the local members were moved to, if one was created.
+            pub new_room_id: Option<RoomId>,
+        }
+
+        error: ruma::api::client::Error
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mockito::{mock, Matcher};
+    use ruma::{room_id, user_id};
+    use serde_json::json;
+
+    use super::{PurgeHistory, PurgeStatus};
+    use crate::{Client, Session};
+
+    async fn admin_client() -> Client {
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@admin:localhost"),
+            device_id: "DEVICEID".into(),
+        };
+        let homeserver = url::Url::parse(&mockito::server_url()).unwrap();
+        let client = Client::new(homeserver).unwrap();
+        client.restore_login(session).await.unwrap();
+
+        client
+    }
+
+    #[tokio::test]
+    async fn purge_history() {
+        let client = admin_client().await;
+        let room_id = room_id!("!test:localhost");
+
+        let _m = mock("POST", Matcher::Regex(r"^/_synapse/admin/v1/purge_history/.*".to_owned()))
+            .match_header("authorization", "Bearer 1234")
+            .match_body(Matcher::Json(json!({ "delete_local_events": true })))
+            .with_status(200)
+            .with_body(json!({ "purge_id": "purge" }).to_string())
+            .create();
+
+        let options = PurgeHistory { delete_local_events: true, ..Default::default() };
+        let purge_id = client.synapse_admin().purge_history(&room_id, options).await.unwrap();
+        assert_eq!(purge_id, "purge");
+
+        let _m = mock("GET", "/_synapse/admin/v1/purge_history_status/purge")
+            .match_header("authorization", "Bearer 1234")
+            .with_status(200)
+            .with_body(json!({ "status": "complete" }).to_string())
+            .create();
+
+        let status = client.synapse_admin().purge_history_status(&purge_id).await.unwrap();
+        assert_eq!(status, PurgeStatus::Complete);
+    }
+}
@@ -0,0 +1,31 @@
+//! Hooks that run as part of the processing of every sync response.
+
+use matrix_sdk_common::{async_trait, deserialized_responses::SyncResponse};
+use ruma::api::client::r0::sync::sync_events;
+
+/// A hook that is called for every sync response the client receives.
+///
+/// Unlike an [`EventHandler`], which is notified about single events, a hook
+/// sees the sync response as a whole. This allows embedders like bridges to
+/// layer their own bookkeeping on top of the sync loop.
+///
+/// Hooks are added with [`Client::add_sync_hook()`] and run in the order they
+/// were added, for sync responses of the sync loop as well as for appservice
+/// transactions.
+///
+/// [`EventHandler`]: crate::EventHandler
+/// [`Client::add_sync_hook()`]: crate::Client::add_sync_hook
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait SyncHook: Send + Sync {
+    /// Called with the sync response, as the server sent it, before the
+    /// client processes it.
+    ///
+    /// The response can be modified, e.g. synthetic events can be added to
+    /// it, those are processed and stored as if the server sent them.
+    async fn pre_process(&self, _: &mut sync_events::Response) {}
+
+    /// Called after the sync response was processed and the changes were
+    /// stored.
+    async fn on_sync_response(&self, _: &SyncResponse) {}
+}
@@ -19,58 +19,113 @@ use std::{
     fmt,
     path::{Path, PathBuf},
     result::Result as StdResult,
-    sync::Arc,
+    sync::{Arc, Mutex as StdMutex},
 };
 
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    Stream,
+};
 use matrix_sdk_common::{
     deserialized_responses::{
-        AmbiguityChanges, JoinedRoom, LeftRoom, MemberEvent, MembersResponse, Rooms,
-        StrippedMemberEvent, SyncResponse, SyncRoomEvent, Timeline,
+        AmbiguityChanges, JoinedRoom, LeftRoom, MemberEvent, MembersResponse, MembershipChange,
+        PaginatedTimeline, PaginatedTimelineChanges, Rooms, StrippedMemberEvent, SyncResponse,
+        SyncRoomEvent, Timeline, TimelineChunk, UnreadNotificationsCount,
     },
     instant::Instant,
     locks::RwLock,
+    metrics,
 };
 #[cfg(feature = "encryption")]
 use matrix_sdk_common::{locks::Mutex, uuid::Uuid};
 #[cfg(feature = "encryption")]
 use matrix_sdk_crypto::{
-    store::{CryptoStore, CryptoStoreError},
+    store::{CryptoStoreError, DynCryptoStore, IntoCryptoStore},
     Device, EncryptionSettings, IncomingResponse, MegolmError, OlmError, OlmMachine,
-    OutgoingRequest, Sas, ToDeviceRequest, UserDevices,
+    OutgoingRequest, RoomKeySharingProgress, Sas, ToDeviceRequest, UserDevices,
 };
 #[cfg(feature = "encryption")]
 use ruma::{
     api::client::r0::keys::claim_keys::Request as KeysClaimRequest,
-    events::{
-        room::{encrypted::EncryptedEventContent, history_visibility::HistoryVisibility},
-        AnyMessageEventContent, AnySyncMessageEvent,
-    },
+    events::room::{encrypted::EncryptedEventContent, history_visibility::HistoryVisibility},
     DeviceId,
 };
 use ruma::{
     api::client::r0::{self as api, push::get_notifications::Notification},
     events::{
         room::member::{MemberEventContent, MembershipState},
-        AnyGlobalAccountDataEvent, AnyRoomAccountDataEvent, AnyStrippedStateEvent,
-        AnySyncEphemeralRoomEvent, AnySyncRoomEvent, AnySyncStateEvent, EventContent, EventType,
-        StateEvent,
+        AnyGlobalAccountDataEvent, AnyMessageEventContent, AnyRoomAccountDataEvent, AnyRoomEvent,
+        AnyStrippedStateEvent, AnySyncEphemeralRoomEvent, AnySyncMessageEvent, AnySyncRoomEvent,
+        AnySyncStateEvent, AnyToDeviceEvent, EventContent, EventType, StateEvent,
     },
-    push::{Action, PushConditionRoomCtx, Ruleset},
+    push::{Action, PushConditionRoomCtx, Ruleset, Tweak},
     serde::Raw,
-    MilliSecondsSinceUnixEpoch, RoomId, UInt, UserId,
+    EventId, MilliSecondsSinceUnixEpoch, RoomId, UInt, UserId,
+};
+use serde::Deserialize;
+use serde_json::{
+    value::{to_raw_value, RawValue as RawJsonValue},
+    Value as JsonValue,
 };
-use tracing::{info, warn};
+use tracing::{info, trace, warn};
 use zeroize::Zeroizing;
 
 use crate::{
+    dedup::{self, Position},
     error::Result,
-    rooms::{Room, RoomInfo, RoomType},
+    event_processors::EventProcessor,
+    local_echo::{remote_echo_transaction_id, LocalEcho, LocalEchoState, LocalEchoes},
+    redaction,
+    rooms::{Room, RoomInfo, RoomType, StateChange},
     session::Session,
-    store::{ambiguity_map::AmbiguityCache, Result as StoreResult, StateChanges, Store},
+    store::{
+        ambiguity_map::AmbiguityCache, DynStateStore, IntoStateStore, Result as StoreResult,
+        StateChanges, Store,
+    },
 };
 
 pub type Token = String;
 
+/// The rooms of a sync response that weren't deserialized yet.
+///
+/// Every room is only deserialized once it's processed, this way only a
+/// single room of a large sync response needs to be held in its deserialized
+/// form at any point in time.
+#[derive(Default, Deserialize)]
+struct RawRooms<'a> {
+    #[serde(default, borrow)]
+    join: BTreeMap<RoomId, &'a RawJsonValue>,
+    #[serde(default, borrow)]
+    leave: BTreeMap<RoomId, &'a RawJsonValue>,
+    #[serde(default, borrow)]
+    invite: BTreeMap<RoomId, &'a RawJsonValue>,
+}
+
+/// A sync response whose rooms weren't deserialized yet.
+#[derive(Deserialize)]
+struct RawSyncResponse<'a> {
+    next_batch: String,
+    #[serde(default, borrow)]
+    rooms: RawRooms<'a>,
+    #[serde(default)]
+    presence: api::sync::sync_events::Presence,
+    #[serde(default)]
+    account_data: api::sync::sync_events::GlobalAccountData,
+    #[serde(default)]
+    to_device: api::sync::sync_events::ToDevice,
+    #[serde(default)]
+    device_lists: api::sync::sync_events::DeviceLists,
+    #[serde(default)]
+    device_one_time_keys_count: BTreeMap<ruma::DeviceKeyAlgorithm, UInt>,
+}
+
+/// Deserialize the raw rooms of the given map one at a time.
+fn deserialize_rooms<'a, T: Deserialize<'a>>(
+    rooms: BTreeMap<RoomId, &'a RawJsonValue>,
+) -> impl Iterator<Item = Result<(RoomId, T)>> + 'a {
+    rooms.into_iter().map(|(room_id, room)| Ok((room_id, serde_json::from_str(room.get())?)))
+}
+
 /// A deserialization wrapper for extracting the prev_content field when
 /// found in an `unsigned` field.
 ///
@@ -168,9 +223,25 @@ pub struct BaseClient {
     #[cfg(feature = "encryption")]
     olm: Arc<Mutex<Option<OlmMachine>>>,
     #[cfg(feature = "encryption")]
-    cryptostore: Arc<Mutex<Option<Box<dyn CryptoStore>>>>,
+    cryptostore: Arc<Mutex<Option<Arc<DynCryptoStore>>>>,
     store_path: Arc<Option<PathBuf>>,
     store_passphrase: Arc<Option<Zeroizing<String>>>,
+    room_chunk_size: Option<usize>,
+    /// The processors that run for every received timeline event.
+    event_processors: Arc<Vec<Box<dyn EventProcessor>>>,
+    /// Events that we sent but didn't yet receive in a sync.
+    local_echoes: LocalEchoes,
+    /// Listeners that get notified when the unread notification counts of a
+    /// room change.
+    notification_count_listeners:
+        Arc<StdMutex<Vec<UnboundedSender<(RoomId, UnreadNotificationsCount)>>>>,
+    /// Listeners that get notified when the state of a room changes.
+    state_change_listeners: Arc<StdMutex<Vec<UnboundedSender<StateChange>>>>,
+    /// Listeners that get notified about to-device events with a type that we
+    /// don't know about.
+    unknown_to_device_listeners: Arc<StdMutex<Vec<UnboundedSender<Raw<AnyToDeviceEvent>>>>>,
+    /// Listeners that get notified when a room becomes encrypted.
+    room_encryption_listeners: Arc<StdMutex<Vec<UnboundedSender<RoomId>>>>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -197,9 +268,12 @@ impl fmt::Debug for BaseClient {
 #[derive(Default)]
 pub struct BaseClientConfig {
     #[cfg(feature = "encryption")]
-    crypto_store: Option<Box<dyn CryptoStore>>,
+    crypto_store: Option<Arc<DynCryptoStore>>,
+    state_store: Option<Arc<DynStateStore>>,
     store_path: Option<PathBuf>,
     passphrase: Option<Zeroizing<String>>,
+    room_chunk_size: Option<usize>,
+    event_processors: Vec<Box<dyn EventProcessor>>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -219,8 +293,18 @@ impl BaseClientConfig {
     ///
     /// The crypto store should be opened before being set.
     #[cfg(feature = "encryption")]
-    pub fn crypto_store(mut self, store: Box<dyn CryptoStore>) -> Self {
-        self.crypto_store = Some(store);
+    pub fn crypto_store(mut self, store: impl IntoCryptoStore) -> Self {
+        self.crypto_store = Some(store.into_crypto_store());
+        self
+    }
+
+    /// Set a custom implementation of a `StateStore`.
+    ///
+    /// The state store should be opened before being set. If a custom state
+    /// store is set the store path is only used for the crypto store, unless
+    /// a custom crypto store is set as well.
+    pub fn state_store(mut self, store: impl IntoStateStore) -> Self {
+        self.state_store = Some(store.into_state_store());
         self
     }
 
@@ -252,6 +336,40 @@ impl BaseClientConfig {
         self.passphrase = Some(Zeroizing::new(passphrase));
         self
     }
+
+    /// Persist the rooms of a sync response in chunks of the given size.
+    ///
+    /// By default all the changes a sync response produces are collected and
+    /// written to the state store at once. For very large responses, e.g. the
+    /// initial sync of an account with thousands of rooms, this means that the
+    /// state of every room is held in memory twice.
+    ///
+    /// Setting a chunk size writes the state of the processed rooms to the
+    /// store every `rooms` rooms and releases it. The sync token is only
+    /// stored once the whole response has been processed, so an interrupted
+    /// sync will be retried.
+    ///
+    /// Clients that set a chunk size should pass the body of sync responses to
+    /// [`BaseClient::receive_sync_response_json`], which deserializes the
+    /// rooms of the response one at a time.
+    ///
+    /// # Arguments
+    ///
+    /// * `rooms` - The number of rooms that should be processed before their
+    /// state is written to the store.
+    pub fn room_chunk_size(mut self, rooms: usize) -> Self {
+        self.room_chunk_size = Some(rooms);
+        self
+    }
+
+    /// Add a processor that runs for every received timeline event.
+    ///
+    /// Processors run in the order they were added, after the event was
+    /// decrypted and before it's stored.
+    pub fn event_processor(mut self, processor: impl EventProcessor + 'static) -> Self {
+        self.event_processors.push(Box::new(processor));
+        self
+    }
 }
 
 impl BaseClient {
@@ -267,44 +385,45 @@ impl BaseClient {
     /// * `config` - An optional session if the user already has one from a
     /// previous login call.
     pub fn new_with_config(config: BaseClientConfig) -> Result<Self> {
+        // The sled database is shared with the default crypto store, a custom
+        // state store doesn't have one.
         #[cfg(feature = "sled_state_store")]
-        let stores = if let Some(path) = &config.store_path {
+        let stores = if let Some(store) = config.state_store {
+            (Store::new(store), None)
+        } else if let Some(path) = &config.store_path {
             if config.passphrase.is_some() {
                 info!("Opening an encrypted store in path {}", path.display());
             } else {
                 info!("Opening store in path {}", path.display());
             }
-            Store::open_default(path, config.passphrase.as_deref().map(|p| p.as_str()))?
+            let (store, database) =
+                Store::open_default(path, config.passphrase.as_deref().map(|p| p.as_str()))?;
+            (store, Some(database))
         } else {
-            Store::open_temporary()?
+            let (store, database) = Store::open_temporary()?;
+            (store, Some(database))
         };
         #[cfg(not(feature = "sled_state_store"))]
-        let stores = Store::open_memory_store();
+        let store = config.state_store.map(Store::new).unwrap_or_else(Store::open_memory_store);
 
         #[cfg(all(feature = "encryption", feature = "sled_state_store"))]
-        let crypto_store = if config.crypto_store.is_none() {
+        let crypto_store = match (config.crypto_store, stores.1) {
             #[cfg(feature = "sled_cryptostore")]
-            let store: Option<Box<dyn CryptoStore>> = Some(Box::new(
+            (None, Some(database)) => Some(
                 matrix_sdk_crypto::store::SledStore::open_with_database(
-                    stores.1,
+                    database,
                     config.passphrase.as_deref().map(|p| p.as_str()),
                 )
-                .map_err(OlmError::Store)?,
-            ));
-            #[cfg(not(feature = "sled_cryptostore"))]
-            let store = config.crypto_store;
-
-            store
-        } else {
-            config.crypto_store
+                .map_err(OlmError::Store)?
+                .into_crypto_store(),
+            ),
+            (store, _) => store,
         };
         #[cfg(all(not(feature = "sled_state_store"), feature = "encryption"))]
         let crypto_store = config.crypto_store;
 
         #[cfg(feature = "sled_state_store")]
         let store = stores.0;
-        #[cfg(not(feature = "sled_state_store"))]
-        let store = stores;
 
         Ok(BaseClient {
             session: store.session.clone(),
@@ -316,6 +435,13 @@ impl BaseClient {
             cryptostore: Mutex::new(crypto_store).into(),
             store_path: config.store_path.into(),
             store_passphrase: config.passphrase.into(),
+            room_chunk_size: config.room_chunk_size,
+            event_processors: Arc::new(config.event_processors),
+            local_echoes: Default::default(),
+            notification_count_listeners: Default::default(),
+            state_change_listeners: Default::default(),
+            unknown_to_device_listeners: Default::default(),
+            room_encryption_listeners: Default::default(),
         })
     }
 
@@ -432,7 +558,6 @@ impl BaseClient {
         let mut push_context = self.get_push_room_context(room, room_info, changes).await?;
 
         for event in ruma_timeline.events {
-            #[allow(unused_mut)]
             let mut event: SyncRoomEvent = event.into();
 
             match hoist_room_event_prev_content(&event.event) {
@@ -444,6 +569,27 @@ impl BaseClient {
                                 if let Ok(member) = MemberEvent::try_from(member.clone()) {
                                     ambiguity_cache.handle_event(changes, room_id, &member).await?;
 
+                                    let prev_content = if let Some(content) = &member.prev_content {
+                                        Some(content.clone())
+                                    } else if let Some(prev) = changes
+                                        .members
+                                        .get(room_id)
+                                        .and_then(|m| m.get(&member.state_key))
+                                    {
+                                        Some(prev.content.clone())
+                                    } else {
+                                        self.store
+                                            .get_member_event(room_id, &member.state_key)
+                                            .await?
+                                            .map(|m| m.content)
+                                    };
+
+                                    event.membership_change = Some(MembershipChange::new(
+                                        prev_content.as_ref(),
+                                        &member.content,
+                                        member.sender == member.state_key,
+                                    ));
+
                                     match member.content.membership {
                                         MembershipState::Join | MembershipState::Invite => {
                                             user_ids.insert(member.state_key.clone());
@@ -499,6 +645,18 @@ impl BaseClient {
                         _ => (),
                     }
 
+                    if let Some(transaction_id) = remote_echo_transaction_id(user_id, &event) {
+                        if self.local_echoes.remove(room_id, &transaction_id) {
+                            trace!(
+                                room_id = room_id.as_str(),
+                                transaction_id = transaction_id.as_str(),
+                                "Received the remote echo of a local echo"
+                            );
+                        }
+
+                        changes.add_remote_echo(room_id, transaction_id);
+                    }
+
                     if let Some(context) = &mut push_context {
                         self.update_push_room_context(context, user_id, room_info, changes).await;
                     } else {
@@ -508,7 +666,22 @@ impl BaseClient {
                     if let Some(context) = &push_context {
                         let actions = push_rules.get_actions(&event.event, context).to_vec();
 
-                        if actions.iter().any(|a| matches!(a, Action::Notify)) {
+                        let notify = actions.iter().any(|a| matches!(a, Action::Notify));
+
+                        if room_info.is_encrypted() {
+                            if e.sender() == user_id {
+                                // Sending a message implies that we read the
+                                // room up to it.
+                                room_info.clear_unread_notifications();
+                            } else if notify {
+                                let highlight = actions
+                                    .iter()
+                                    .any(|a| matches!(a, Action::SetTweak(Tweak::Highlight(true))));
+                                room_info.add_unread_notification(e.event_id().clone(), highlight);
+                            }
+                        }
+
+                        if notify {
                             changes.add_notification(
                                 room_id,
                                 Notification::new(
@@ -535,6 +708,10 @@ impl BaseClient {
                 }
             }
 
+            for processor in self.event_processors.iter() {
+                processor.process(room, &mut event).await;
+            }
+
             timeline.events.push(event);
         }
 
@@ -683,24 +860,195 @@ impl BaseClient {
                 continue;
             };
 
-            if let AnyGlobalAccountDataEvent::Direct(e) = &event {
-                for (user_id, rooms) in e.content.iter() {
-                    for room_id in rooms {
-                        if let Some(room) = changes.room_infos.get_mut(room_id) {
-                            room.base_info.dm_target = Some(user_id.clone());
-                        } else if let Some(room) = self.store.get_room(room_id) {
-                            let mut info = room.clone_info();
-                            info.base_info.dm_target = Some(user_id.clone());
-                            changes.add_room(info);
-                        }
-                    }
+            account_data.insert(event.content().event_type().to_owned(), raw_event.clone());
+        }
+
+        changes.account_data = account_data;
+    }
+
+    /// Update the direct message targets of our rooms if the `m.direct`
+    /// account data changed.
+    ///
+    /// This needs to happen after the rooms of a sync were processed, so the
+    /// targets aren't overwritten by the room infos of the sync.
+    fn handle_direct_rooms(&self, changes: &mut StateChanges) {
+        let direct = if let Some(Ok(AnyGlobalAccountDataEvent::Direct(e))) =
+            changes.account_data.get(EventType::Direct.as_str()).map(|e| e.deserialize())
+        {
+            e
+        } else {
+            return;
+        };
+
+        let mut targets: BTreeMap<&RoomId, BTreeSet<UserId>> = BTreeMap::new();
+
+        for (user_id, rooms) in direct.content.iter() {
+            for room_id in rooms {
+                targets.entry(room_id).or_default().insert(user_id.clone());
+            }
+        }
+
+        // Rooms that were removed from the map aren't direct messages anymore.
+        // Invites get their targets from the invite itself and aren't part of
+        // the map yet.
+        for room in self.store.get_rooms() {
+            if room.room_type() == RoomType::Invited {
+                continue;
+            }
+
+            let dm_targets = targets.remove(room.room_id()).unwrap_or_default();
+
+            if let Some(info) = changes.room_infos.get_mut(room.room_id()) {
+                info.base_info.dm_targets = dm_targets;
+            } else if room.direct_targets() != dm_targets {
+                let mut info = room.clone_info();
+                info.base_info.dm_targets = dm_targets;
+                changes.add_room(info);
+            }
+        }
+    }
+
+    /// Get a stream of unread notification count changes.
+    ///
+    /// Every time the unread notification counts of a room change the room id
+    /// and the new counts are sent out, this can be used to update unread
+    /// badges.
+    pub fn unread_notification_counts_stream(
+        &self,
+    ) -> impl Stream<Item = (RoomId, UnreadNotificationsCount)> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.notification_count_listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    /// Get a stream of rooms that became encrypted.
+    ///
+    /// The room id is sent out once the `m.room.encryption` state event of a
+    /// room, that wasn't encrypted before, was received and saved. From then
+    /// on [`Room::is_encrypted()`] returns `true` for the room.
+    pub fn room_encryption_stream(&self) -> impl Stream<Item = RoomId> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.room_encryption_listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    fn notify_room_encryption_listeners(&self, room_id: &RoomId) {
+        self.room_encryption_listeners
+            .lock()
+            .unwrap()
+            .retain(|l| l.unbounded_send(room_id.clone()).is_ok());
+    }
+
+    fn notify_count_listeners(&self, room_id: &RoomId, counts: UnreadNotificationsCount) {
+        self.notification_count_listeners
+            .lock()
+            .unwrap()
+            .retain(|l| l.unbounded_send((room_id.clone(), counts)).is_ok());
+    }
+
+    /// Get a stream of the changes of the room state.
+    ///
+    /// Every state event that a sync response changes is sent out together
+    /// with the state event it replaced, this can be used to react to any
+    /// kind of state without handling every event type separately.
+    pub fn state_changes_stream(&self) -> impl Stream<Item = StateChange> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.state_change_listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    /// Compare the state in the given changes with the state in the store.
+    ///
+    /// This needs to be called before the changes are saved, nothing is
+    /// compared if no one listens to state changes.
+    async fn diff_state(&self, changes: &StateChanges) -> Result<Vec<StateChange>> {
+        let has_listeners = !self.state_change_listeners.lock().unwrap().is_empty();
+
+        if !has_listeners {
+            return Ok(Vec::new());
+        }
+
+        let mut diff = Vec::new();
+
+        for (room_id, event_types) in &changes.state {
+            for (event_type, events) in event_types {
+                for (state_key, new) in events {
+                    let old = self
+                        .store
+                        .get_state_event(room_id, event_type.as_str().into(), state_key)
+                        .await?;
+
+                    diff.push(StateChange {
+                        room_id: room_id.clone(),
+                        event_type: event_type.clone(),
+                        state_key: state_key.clone(),
+                        old,
+                        new: new.clone(),
+                    });
                 }
             }
+        }
 
-            account_data.insert(event.content().event_type().to_owned(), raw_event.clone());
+        for (room_id, members) in &changes.members {
+            for (user_id, new) in members {
+                let old = match self.store.get_member_event(room_id, user_id).await? {
+                    Some(m) => Some(Raw::from_json(to_raw_value(&m)?)),
+                    None => None,
+                };
+
+                diff.push(StateChange {
+                    room_id: room_id.clone(),
+                    event_type: EventType::RoomMember.as_str().to_owned(),
+                    state_key: user_id.to_string(),
+                    old,
+                    new: Raw::from_json(to_raw_value(new)?),
+                });
+            }
         }
 
-        changes.account_data = account_data;
+        // The same state can be part of multiple sync responses, e.g. after a
+        // limited timeline, that isn't a change.
+        diff.retain(|c| c.old.as_ref().map(|o| o.json().get()) != Some(c.new.json().get()));
+
+        Ok(diff)
+    }
+
+    /// Get a stream of to-device events with an unknown type.
+    ///
+    /// To-device events with a type that doesn't have a typed representation
+    /// in the SDK, e.g. events from unstable MSCs or newer versions of the
+    /// spec, are sent out as they were received. Encrypted to-device events
+    /// are sent out in their decrypted form.
+    pub fn unknown_to_device_events_stream(&self) -> impl Stream<Item = Raw<AnyToDeviceEvent>> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.unknown_to_device_listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    fn notify_unknown_to_device_listeners(&self, events: &[Raw<AnyToDeviceEvent>]) {
+        let mut listeners = self.unknown_to_device_listeners.lock().unwrap();
+
+        if listeners.is_empty() {
+            return;
+        }
+
+        for event in events {
+            if let Ok(AnyToDeviceEvent::Custom(_)) = event.deserialize() {
+                listeners.retain(|l| l.unbounded_send(event.clone()).is_ok());
+            }
+        }
+    }
+
+    fn notify_state_listeners(&self, diff: Vec<StateChange>) {
+        let mut listeners = self.state_change_listeners.lock().unwrap();
+
+        for change in diff {
+            listeners.retain(|l| l.unbounded_send(change.clone()).is_ok());
+        }
     }
 
     /// Receive a response from a sync call.
@@ -711,6 +1059,54 @@ impl BaseClient {
     pub async fn receive_sync_response(
         &self,
         response: api::sync::sync_events::Response,
+    ) -> Result<SyncResponse> {
+        self.receive_sync_response_helper(response, RawRooms::default()).await
+    }
+
+    /// Receive the JSON body of a response from a sync call.
+    ///
+    /// Unlike [`BaseClient::receive_sync_response`], this doesn't require the
+    /// whole response to be deserialized up front. The rooms of the response
+    /// are deserialized one at a time while they are processed, together with
+    /// a [room chunk size] this keeps the memory that is needed to process
+    /// large sync responses, e.g. the initial sync of a big account, in check.
+    ///
+    /// # Arguments
+    ///
+    /// * `body` - The JSON body of a successful sync response.
+    ///
+    /// [room chunk size]: BaseClientConfig::room_chunk_size
+    pub async fn receive_sync_response_json(&self, body: &[u8]) -> Result<SyncResponse> {
+        let RawSyncResponse {
+            next_batch,
+            rooms,
+            presence,
+            account_data,
+            to_device,
+            device_lists,
+            device_one_time_keys_count,
+        } = serde_json::from_slice(body)?;
+
+        let mut response = api::sync::sync_events::Response::new(next_batch);
+        response.presence = presence;
+        response.account_data = account_data;
+        response.to_device = to_device;
+        response.device_lists = device_lists;
+        response.device_one_time_keys_count = device_one_time_keys_count;
+
+        self.receive_sync_response_helper(response, rooms).await
+    }
+
+    /// Does the client persist the rooms of sync responses in chunks, see
+    /// [`BaseClientConfig::room_chunk_size`].
+    pub fn persists_rooms_in_chunks(&self) -> bool {
+        self.room_chunk_size.is_some()
+    }
+
+    async fn receive_sync_response_helper(
+        &self,
+        response: api::sync::sync_events::Response,
+        raw_rooms: RawRooms<'_>,
     ) -> Result<SyncResponse> {
         #[cfg(test)]
         let api::sync::sync_events::Response {
@@ -769,13 +1165,22 @@ impl BaseClient {
         let push_rules = self.get_push_rules(&changes).await?;
 
         let mut new_rooms = Rooms::default();
+        let mut processed_rooms = 0;
 
-        for (room_id, new_info) in rooms.join {
+        let joined = rooms.join.into_iter().map(Ok).chain(deserialize_rooms(raw_rooms.join));
+
+        for room in joined {
+            let (room_id, new_info): (RoomId, api::sync::sync_events::JoinedRoom) = room?;
             let room = self.store.get_or_create_room(&room_id, RoomType::Joined).await;
             let mut room_info = room.clone_info();
             room_info.mark_as_joined();
 
+            // Servers send member counts if lazy member loading is enabled,
+            // and omit them if they didn't change. We only keep the counts up
+            // to date ourselves if the server never sends them, member events
+            // of lazily loaded members aren't membership changes.
             room_info.update_summary(&new_info.summary);
+            let has_member_counts = room_info.has_server_member_counts();
             room_info.set_prev_batch(new_info.timeline.prev_batch.as_deref());
 
             let mut user_ids = self
@@ -800,7 +1205,7 @@ impl BaseClient {
                 room_info.mark_members_missing();
             }
 
-            let timeline = self
+            let mut timeline = self
                 .handle_timeline(
                     &room,
                     new_info.timeline,
@@ -812,6 +1217,14 @@ impl BaseClient {
                 )
                 .await?;
 
+            if !has_member_counts {
+                self.update_member_counts(&changes, &mut room_info).await?;
+            }
+
+            self.handle_redactions(&room_id, &mut room_info, &mut timeline, &mut changes).await?;
+            self.update_paginated_timeline(&room_id, &timeline).await?;
+            self.handle_own_read_receipt(&room, &mut room_info, &changes).await?;
+
             self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes)
                 .await;
 
@@ -829,7 +1242,19 @@ impl BaseClient {
                         o.update_tracked_users(user_ids).await
                     }
 
-                    o.update_tracked_users(&user_ids).await
+                    o.update_tracked_users(&user_ids).await;
+
+                    // Members that left or got banned shouldn't receive any
+                    // new room keys, make sure the outbound group session gets
+                    // rotated before the next message is sent.
+                    if let Some(members) = changes.members.get(&room_id) {
+                        let left = members.values().filter_map(|m| match m.content.membership {
+                            MembershipState::Leave | MembershipState::Ban => Some(&m.state_key),
+                            _ => None,
+                        });
+
+                        o.room_members_left(&room_id, left).await?;
+                    }
                 }
             }
 
@@ -848,9 +1273,13 @@ impl BaseClient {
             );
 
             changes.add_room(room_info);
+            self.maybe_save_room_chunk(&mut changes, &mut processed_rooms).await?;
         }
 
-        for (room_id, new_info) in rooms.leave {
+        let left = rooms.leave.into_iter().map(Ok).chain(deserialize_rooms(raw_rooms.leave));
+
+        for room in left {
+            let (room_id, new_info): (RoomId, api::sync::sync_events::LeftRoom) = room?;
             let room = self.store.get_or_create_room(&room_id, RoomType::Left).await;
             let mut room_info = room.clone_info();
             room_info.mark_as_left();
@@ -864,7 +1293,7 @@ impl BaseClient {
                 )
                 .await?;
 
-            let timeline = self
+            let mut timeline = self
                 .handle_timeline(
                     &room,
                     new_info.timeline,
@@ -876,6 +1305,8 @@ impl BaseClient {
                 )
                 .await?;
 
+            self.handle_redactions(&room_id, &mut room_info, &mut timeline, &mut changes).await?;
+
             self.handle_room_account_data(&room_id, &new_info.account_data.events, &mut changes)
                 .await;
 
@@ -883,9 +1314,13 @@ impl BaseClient {
             new_rooms
                 .leave
                 .insert(room_id, LeftRoom::new(timeline, new_info.state, new_info.account_data));
+            self.maybe_save_room_chunk(&mut changes, &mut processed_rooms).await?;
         }
 
-        for (room_id, new_info) in rooms.invite {
+        let invited = rooms.invite.into_iter().map(Ok).chain(deserialize_rooms(raw_rooms.invite));
+
+        for room in invited {
+            let (room_id, new_info): (RoomId, api::sync::sync_events::InvitedRoom) = room?;
             {
                 let room = self.store.get_or_create_room(&room_id, RoomType::Invited).await;
                 let mut room_info = room.clone_info();
@@ -899,11 +1334,20 @@ impl BaseClient {
             let (members, state_events) =
                 self.handle_invited_state(&new_info.invite_state.events, &mut room_info);
 
+            if let Some(member) = members.get(room.own_user_id()) {
+                room_info.inviter = Some(member.sender.clone());
+
+                if member.content.is_direct == Some(true) {
+                    room_info.base_info.dm_targets.insert(member.sender.clone());
+                }
+            }
+
             changes.stripped_members.insert(room_id.clone(), members);
             changes.stripped_state.insert(room_id.clone(), state_events);
             changes.add_stripped_room(room_info);
 
             new_rooms.invite.insert(room_id, new_info);
+            self.maybe_save_room_chunk(&mut changes, &mut processed_rooms).await?;
         }
 
         changes.presence = presence
@@ -917,11 +1361,18 @@ impl BaseClient {
 
         changes.ambiguity_maps = ambiguity_cache.cache;
 
+        self.handle_direct_rooms(&mut changes);
+
+        let state_diff = self.diff_state(&changes).await?;
+
         self.store.save_changes(&changes).await?;
         *self.sync_token.write().await = Some(next_batch.clone());
-        self.apply_changes(&changes).await;
+        self.apply_changes(&changes).await?;
+        self.notify_state_listeners(state_diff);
+        self.notify_unknown_to_device_listeners(&to_device.events);
 
         info!("Processed a sync response in {:?}", now.elapsed());
+        metrics::record_sync_processing(now.elapsed());
 
         let response = SyncResponse {
             next_batch,
@@ -941,12 +1392,534 @@ impl BaseClient {
         Ok(response)
     }
 
-    async fn apply_changes(&self, changes: &StateChanges) {
+    /// Write the room state that was collected so far to the store if the
+    /// configured room chunk size has been reached.
+    async fn maybe_save_room_chunk(
+        &self,
+        changes: &mut StateChanges,
+        processed_rooms: &mut usize,
+    ) -> Result<()> {
+        let chunk_size = if let Some(size) = self.room_chunk_size {
+            size
+        } else {
+            return Ok(());
+        };
+
+        *processed_rooms += 1;
+
+        if *processed_rooms < chunk_size {
+            return Ok(());
+        }
+
+        *processed_rooms = 0;
+
+        let chunk = StateChanges {
+            members: std::mem::take(&mut changes.members),
+            profiles: std::mem::take(&mut changes.profiles),
+            state: std::mem::take(&mut changes.state),
+            room_account_data: std::mem::take(&mut changes.room_account_data),
+            room_infos: std::mem::take(&mut changes.room_infos),
+            receipts: std::mem::take(&mut changes.receipts),
+            stripped_state: std::mem::take(&mut changes.stripped_state),
+            stripped_members: std::mem::take(&mut changes.stripped_members),
+            invited_room_info: std::mem::take(&mut changes.invited_room_info),
+            ..Default::default()
+        };
+
+        let state_diff = self.diff_state(&chunk).await?;
+
+        self.store.save_changes(&chunk).await?;
+        self.apply_changes(&chunk).await?;
+        self.notify_state_listeners(state_diff);
+
+        Ok(())
+    }
+
+    async fn apply_changes(&self, changes: &StateChanges) -> Result<()> {
+        let mut updated = StateChanges::default();
+
         for (room_id, room_info) in &changes.room_infos {
             if let Some(room) = self.store.get_room(room_id) {
-                room.update_summary(room_info.clone())
+                let counts = room.unread_notification_counts();
+                let was_encrypted = room.is_encrypted();
+                room.update_summary(room_info.clone());
+
+                if room.unread_notification_counts() != counts {
+                    self.notify_count_listeners(room_id, room.unread_notification_counts());
+                }
+
+                if !was_encrypted && room.is_encrypted() {
+                    self.notify_room_encryption_listeners(room_id);
+                }
+
+                // The display name and the avatar depend on the state and the
+                // members of the room, which are now in the store, so this is
+                // the place to keep the cached values up to date.
+                let renamed = room.update_display_name().await?;
+                let avatar_changed = room.update_direct_avatar_url().await?;
+
+                if renamed || avatar_changed {
+                    updated.add_room(room.clone_info());
+                }
+            }
+        }
+
+        if !updated.room_infos.is_empty() {
+            self.store.save_changes(&updated).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Mark the locally tracked notifications of a room as read if our own
+    /// user sent a read receipt in this sync.
+    async fn handle_own_read_receipt(
+        &self,
+        room: &Room,
+        room_info: &mut RoomInfo,
+        changes: &StateChanges,
+    ) -> Result<()> {
+        if room_info.local_unread_notifications.is_empty() {
+            return Ok(());
+        }
+
+        let own_user_id = room.own_user_id();
+        let read_event = changes.receipts.get(room.room_id()).and_then(|receipts| {
+            receipts.iter().find_map(|(event_id, receipts)| {
+                receipts.read.as_ref().filter(|r| r.contains_key(own_user_id)).map(|_| event_id)
+            })
+        });
+
+        if let Some(event_id) = read_event {
+            let timeline: Vec<EventId> = self
+                .store
+                .get_paginated_timeline(room.room_id())
+                .await?
+                .map(|t| t.events)
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|e| e.event.deserialize().ok().map(|e| e.event_id().clone()))
+                .collect();
+
+            room_info.mark_notifications_read(event_id, |id| timeline.iter().position(|e| e == id));
+        }
+
+        Ok(())
+    }
+
+    /// Apply the redactions that are part of a sync timeline.
+    ///
+    /// The redacted events are pruned in the timeline of the sync, in the
+    /// state that is about to be saved, in the stored state and in the stored
+    /// timeline of the room. If a redacted event was a reaction or an edit it
+    /// is removed from the bundled aggregations of the event it relates to.
+    async fn handle_redactions(
+        &self,
+        room_id: &RoomId,
+        room_info: &mut RoomInfo,
+        timeline: &mut Timeline,
+        changes: &mut StateChanges,
+    ) -> Result<()> {
+        let redactions: Vec<(usize, EventId, JsonValue)> = timeline
+            .events
+            .iter()
+            .enumerate()
+            .filter_map(|(position, e)| match e.event.deserialize() {
+                Ok(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomRedaction(r))) => {
+                    Some((position, r.redacts, serde_json::from_str(e.event.json().get()).ok()?))
+                }
+                _ => None,
+            })
+            .collect();
+
+        if redactions.is_empty() {
+            return Ok(());
+        }
+
+        let room_version = room_info.room_version();
+
+        let mut stored = self.store.get_paginated_timeline(room_id).await?;
+        let mut stored_changed = BTreeSet::new();
+
+        for (position, redacts, redaction_event) in redactions {
+            // Only the events that came before the redaction can be redacted
+            // by it, the stored timeline is older than the whole sync.
+            let mut original = redaction::redact_timeline_event(
+                &mut timeline.events[..position],
+                &redacts,
+                &redaction_event,
+                &room_version,
+            );
+
+            if let Some(stored) = &mut stored {
+                if let Some(o) = redaction::redact_timeline_event(
+                    &mut stored.events,
+                    &redacts,
+                    &redaction_event,
+                    &room_version,
+                ) {
+                    original.get_or_insert(o);
+                    stored_changed.insert(redacts.to_string());
+                }
+            }
+
+            let original = match original {
+                Some(o) => o,
+                None => {
+                    // We don't know the event, it might still be part of
+                    // the state that is about to be saved.
+                    self.redact_state_event(
+                        room_id,
+                        room_info,
+                        None,
+                        &redacts,
+                        &redaction_event,
+                        changes,
+                    )
+                    .await?;
+                    continue;
+                }
+            };
+
+            if let Some(relation) = redaction::Relation::of(&original) {
+                redaction::remove_from_aggregations(
+                    &mut timeline.events[..position],
+                    &relation,
+                    &redacts,
+                );
+
+                if let Some(stored) = &mut stored {
+                    if redaction::remove_from_aggregations(&mut stored.events, &relation, &redacts)
+                    {
+                        stored_changed.insert(relation.event_id.clone());
+                    }
+                }
             }
+
+            let state = original
+                .get("type")
+                .and_then(|t| t.as_str())
+                .zip(original.get("state_key").and_then(|k| k.as_str()));
+
+            if state.is_some() {
+                self.redact_state_event(
+                    room_id,
+                    room_info,
+                    state,
+                    &redacts,
+                    &redaction_event,
+                    changes,
+                )
+                .await?;
+            }
+        }
+
+        if let Some(stored) = stored.filter(|_| !stored_changed.is_empty()) {
+            let updated = stored
+                .events
+                .into_iter()
+                .filter(|e| {
+                    redaction::event_id(&e.event)
+                        .map_or(false, |id| stored_changed.contains(id.as_str()))
+                })
+                .collect();
+            let changes = PaginatedTimelineChanges { updated, ..Default::default() };
+
+            self.store.update_paginated_timeline(room_id, &changes).await?;
         }
+
+        Ok(())
+    }
+
+    /// Prune a redacted state event in the state that is about to be saved
+    /// and, if the type and state key of the event are known, in the stored
+    /// state of the room.
+    async fn redact_state_event(
+        &self,
+        room_id: &RoomId,
+        room_info: &mut RoomInfo,
+        state: Option<(&str, &str)>,
+        redacts: &EventId,
+        redaction_event: &JsonValue,
+        changes: &mut StateChanges,
+    ) -> Result<()> {
+        let room_version = room_info.room_version();
+
+        let redact_member = |member: &MemberEvent| -> Option<MemberEvent> {
+            let mut json = serde_json::to_value(member).ok()?;
+            redaction::redact_json(&mut json, redaction_event, &room_version);
+            serde_json::from_value(json).ok()
+        };
+
+        let mut member = changes
+            .members
+            .get(room_id)
+            .and_then(|m| m.values().find(|m| &m.event_id == redacts))
+            .cloned();
+
+        if member.is_none() {
+            if let Some(("m.room.member", state_key)) = state {
+                if let Ok(user_id) = UserId::try_from(state_key) {
+                    member = self
+                        .store
+                        .get_member_event(room_id, &user_id)
+                        .await?
+                        .filter(|m| &m.event_id == redacts);
+                }
+            }
+        }
+
+        if let Some(member) = member {
+            if let Some(redacted) = redact_member(&member) {
+                if redacted.sender == redacted.state_key {
+                    changes
+                        .profiles
+                        .entry(room_id.clone())
+                        .or_insert_with(BTreeMap::new)
+                        .insert(redacted.sender.clone(), redacted.content.clone());
+                }
+
+                changes
+                    .members
+                    .entry(room_id.clone())
+                    .or_insert_with(BTreeMap::new)
+                    .insert(redacted.state_key.clone(), redacted);
+            }
+
+            return Ok(());
+        }
+
+        let mut event = changes.state.get(room_id).and_then(|s| {
+            s.values()
+                .flat_map(|e| e.values())
+                .find(|e| redaction::event_id(e).as_ref() == Some(redacts))
+                .cloned()
+        });
+
+        if event.is_none() {
+            if let Some((event_type, state_key)) = state {
+                event = self
+                    .store
+                    .get_state_event(room_id, event_type.into(), state_key)
+                    .await?
+                    .filter(|e| redaction::event_id(e).as_ref() == Some(redacts));
+            }
+        }
+
+        if let Some(redacted) =
+            event.and_then(|e| redaction::redact_raw(&e, redaction_event, &room_version))
+        {
+            if let Ok(e) = redacted.deserialize() {
+                room_info.handle_state_event(&e.content());
+                changes.add_state_event(room_id, e, redacted);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keep the stored timeline of a room connected to the live timeline.
+    ///
+    /// Events of a sync are prepended to the stored timeline, if the sync
+    /// timeline is limited a gap appeared and the stored timeline is replaced
+    /// by the events of the sync.
+    async fn update_paginated_timeline(&self, room_id: &RoomId, timeline: &Timeline) -> Result<()> {
+        let stored = if timeline.limited {
+            None
+        } else {
+            self.store.get_paginated_timeline(room_id).await?
+        };
+
+        if let Some(mut stored) = stored {
+            if timeline.events.is_empty() {
+                return Ok(());
+            }
+
+            let merged = dedup::merge_events(
+                &mut stored.events,
+                timeline.events.iter().rev().cloned(),
+                Position::Front,
+            );
+            let changes = PaginatedTimelineChanges {
+                prepended: merged.added,
+                updated: merged.replaced,
+                ..Default::default()
+            };
+
+            Ok(self.store.update_paginated_timeline(room_id, &changes).await?)
+        } else {
+            let mut events = Vec::new();
+            dedup::merge_events(&mut events, timeline.events.iter().rev().cloned(), Position::Back);
+            let paginated = PaginatedTimeline { events, token: timeline.prev_batch.clone() };
+
+            Ok(self.store.save_paginated_timeline(room_id, &paginated).await?)
+        }
+    }
+
+    /// Receive a `/messages` response that paginated backwards through the
+    /// timeline of a room.
+    ///
+    /// Encrypted events are decrypted and the events are appended to the
+    /// stored timeline of the room, together with the token to continue
+    /// paginating.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id this response belongs to.
+    ///
+    /// * `response` - The raw response that was received from the server.
+    pub async fn receive_messages(
+        &self,
+        room_id: &RoomId,
+        response: &api::message::get_message_events::Response,
+    ) -> Result<TimelineChunk> {
+        let events = self.receive_room_events(room_id, &response.chunk).await;
+
+        // The server omits the end token, or returns an empty chunk, once the
+        // start of the room was reached.
+        let reached_start = response.end.is_none() || response.chunk.is_empty();
+
+        let mut paginated = self.store.get_paginated_timeline(room_id).await?.unwrap_or_default();
+        // The server might return events we already know about, e.g. if the
+        // sync that started the timeline overlaps with the pagination token.
+        let merged = dedup::merge_events(&mut paginated.events, events, Position::Back);
+
+        let changes = PaginatedTimelineChanges {
+            appended: merged.added.clone(),
+            updated: merged.replaced,
+            token: Some(if reached_start { None } else { response.end.clone() }),
+            ..Default::default()
+        };
+
+        self.store.update_paginated_timeline(room_id, &changes).await?;
+
+        Ok(TimelineChunk { events: merged.added, reached_start })
+    }
+
+    /// Re-evaluate the trust in the devices that sent the encrypted events of
+    /// the stored timeline of a room.
+    ///
+    /// The [`EncryptionInfo`] of every decrypted event gets its
+    /// `trust_change` updated, which marks e.g. events that were sent from a
+    /// device that was trusted when the event was decrypted but has since
+    /// been blacklisted. Which changes are reported can be configured with
+    /// [`OlmMachine::set_trust_change_warnings`].
+    ///
+    /// Returns the events whose trust change was updated.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id of the timeline that should be updated.
+    ///
+    /// [`EncryptionInfo`]: matrix_sdk_common::deserialized_responses::EncryptionInfo
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn update_trust_changes(&self, room_id: &RoomId) -> Result<Vec<SyncRoomEvent>> {
+        // Work on a clone of the machine, so the lock isn't held while the
+        // whole timeline is scanned.
+        let olm = match self.olm_machine().await {
+            Some(o) => o,
+            None => return Ok(Vec::new()),
+        };
+        let mut paginated = match self.store.get_paginated_timeline(room_id).await? {
+            Some(p) => p,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut updated = Vec::new();
+
+        for event in &mut paginated.events {
+            if let Some(info) = &mut event.encryption_info {
+                let trust_change = olm.trust_change(info).await?;
+
+                if info.trust_change != trust_change {
+                    info.trust_change = trust_change;
+                    updated.push(event.clone());
+                }
+            }
+        }
+
+        if !updated.is_empty() {
+            let changes =
+                PaginatedTimelineChanges { updated: updated.clone(), ..Default::default() };
+            self.store.update_paginated_timeline(room_id, &changes).await?;
+        }
+
+        Ok(updated)
+    }
+
+    /// Receive room events that were fetched outside of a sync, e.g. the
+    /// relations of an event.
+    ///
+    /// The events are converted to the same form that timeline events of a
+    /// sync have, encrypted events are decrypted if possible.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room id the events belong to.
+    ///
+    /// * `events` - The raw events that were received from the server.
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub async fn receive_room_events(
+        &self,
+        room_id: &RoomId,
+        events: &[Raw<AnyRoomEvent>],
+    ) -> Vec<SyncRoomEvent> {
+        let mut converted = Vec::with_capacity(events.len());
+
+        for raw in events {
+            #[allow(unused_mut)]
+            let mut event: SyncRoomEvent =
+                Raw::<AnySyncRoomEvent>::from_json(raw.clone().into_json()).into();
+
+            if let Ok(AnySyncRoomEvent::State(AnySyncStateEvent::RoomMember(member))) =
+                hoist_room_event_prev_content(&event.event)
+            {
+                if let Ok(member) = MemberEvent::try_from(member) {
+                    event.membership_change = Some(member.membership_change());
+                }
+            }
+
+            #[cfg(feature = "encryption")]
+            if let Ok(AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomEncrypted(encrypted))) =
+                event.event.deserialize()
+            {
+                if let Some(olm) = self.olm_machine().await {
+                    if let Ok(decrypted) = olm.decrypt_room_event(&encrypted, room_id).await {
+                        event = decrypted;
+                    }
+                }
+            }
+
+            converted.push(event);
+        }
+
+        converted
+    }
+
+    /// Update the member counts of a room with the membership changes of a sync
+    /// that didn't contain member counts.
+    async fn update_member_counts(
+        &self,
+        changes: &StateChanges,
+        room_info: &mut RoomInfo,
+    ) -> StoreResult<()> {
+        let room_id = room_info.room_id.clone();
+
+        if let Some(members) = changes.members.get(&*room_id) {
+            for (user_id, member) in members {
+                // Fall back to the previous content of the event if we don't
+                // know the member yet, e.g. if the timeline was limited.
+                let previous = match self.store.get_member_event(&room_id, user_id).await? {
+                    Some(m) => Some(m.content.membership),
+                    None => member.prev_content.as_ref().map(|c| c.membership.clone()),
+                };
+
+                room_info.update_member_counts(previous.as_ref(), &member.content.membership);
+            }
+        }
+
+        Ok(())
     }
 
     /// Receive a get member events response and convert it to a deserialized
@@ -974,6 +1947,14 @@ impl BaseClient {
             let mut room_info = room.clone_info();
             room_info.mark_members_synced();
 
+            // The response contains the full member list, the counts we got
+            // from the server or calculated ourselves might be out of date.
+            let count = |state: MembershipState| {
+                members.iter().filter(|m| m.content.membership == state).count() as u64
+            };
+            room_info
+                .set_member_counts(count(MembershipState::Join), count(MembershipState::Invite));
+
             let mut changes = StateChanges::default();
 
             #[cfg(feature = "encryption")]
@@ -1018,7 +1999,7 @@ impl BaseClient {
             changes.add_room(room_info);
 
             self.store.save_changes(&changes).await?;
-            self.apply_changes(&changes).await;
+            self.apply_changes(&changes).await?;
         }
 
         Ok(MembersResponse {
@@ -1129,9 +2110,11 @@ impl BaseClient {
     #[cfg(feature = "encryption")]
     #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
     pub async fn share_group_session(&self, room_id: &RoomId) -> Result<Vec<Arc<ToDeviceRequest>>> {
-        let olm = self.olm.lock().await;
+        // Don't hold the lock while the room key is shared, the keys queries
+        // that sharing might wait for need it to be processed.
+        let olm = self.olm.lock().await.clone();
 
-        match &*olm {
+        match &olm {
             Some(o) => {
                 let (history_visibility, settings) = self
                     .get_room(room_id)
@@ -1151,6 +2134,7 @@ impl BaseClient {
 
                 let settings = settings.ok_or(MegolmError::EncryptionNotEnabled)?;
                 let settings = EncryptionSettings::new(settings, history_visibility);
+                settings.check_algorithm()?;
 
                 Ok(o.share_group_session(room_id, members, settings).await?)
             }
@@ -1158,6 +2142,56 @@ impl BaseClient {
         }
     }
 
+    /// Add a local echo for an event that is about to be sent to the given
+    /// room.
+    ///
+    /// The local echo will be removed once the remote echo of the event, with
+    /// the same transaction id, is received in a sync.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event is sent to.
+    ///
+    /// * `transaction_id` - The transaction id that is used to send the event.
+    ///
+    /// * `content` - The unencrypted content of the event.
+    pub fn add_local_echo(
+        &self,
+        room_id: &RoomId,
+        transaction_id: String,
+        content: AnyMessageEventContent,
+    ) {
+        self.local_echoes.add(room_id, transaction_id, content)
+    }
+
+    /// Update the state of the local echo with the given transaction id.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event was sent to.
+    ///
+    /// * `transaction_id` - The transaction id of the event.
+    ///
+    /// * `state` - The new state of the local echo.
+    pub fn set_local_echo_state(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+        state: LocalEchoState,
+    ) {
+        self.local_echoes.set_state(room_id, transaction_id, state)
+    }
+
+    /// Get the local echoes of the given room, in the order the events were
+    /// sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the local echoes belong to.
+    pub fn local_echoes(&self, room_id: &RoomId) -> Vec<LocalEcho> {
+        self.local_echoes.get(room_id)
+    }
+
     /// Get the room with the given room id.
     ///
     /// # Arguments
@@ -1175,6 +2209,15 @@ impl BaseClient {
         room_id: &RoomId,
         content: impl Into<AnyMessageEventContent>,
     ) -> Result<EncryptedEventContent> {
+        // Never encrypt events for rooms that didn't enable encryption, the
+        // other members wouldn't expect encrypted events there.
+        let encrypted = self.get_room(room_id).map(|r| r.is_encrypted()).unwrap_or(false)
+            || self.store.get_state_event(room_id, EventType::RoomEncryption, "").await?.is_some();
+
+        if !encrypted {
+            return Err(MegolmError::EncryptionNotEnabled.into());
+        }
+
         let olm = self.olm.lock().await;
 
         match &*olm {
@@ -1202,6 +2245,17 @@ impl BaseClient {
         }
     }
 
+    /// Get a handle to the progress of the latest room key sharing in the
+    /// given room.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn room_key_sharing_progress(
+        &self,
+        room_id: &RoomId,
+    ) -> Option<RoomKeySharingProgress> {
+        self.olm.lock().await.as_ref().and_then(|o| o.room_key_sharing_progress(room_id))
+    }
+
     /// Get a `Sas` verification object with the given flow id.
     ///
     /// # Arguments
@@ -1448,4 +2502,93 @@ impl BaseClient {
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use matrix_sdk_common::deserialized_responses::MembershipChange;
+    use matrix_sdk_test::{async_test, test_json};
+    use ruma::{events::room::member::MemberEventContent, room_id, user_id};
+    use serde_json::json;
+
+    use super::{BaseClient, BaseClientConfig};
+    use crate::{rooms::RoomType, session::Session};
+
+    fn content(membership: &str, displayname: Option<&str>) -> MemberEventContent {
+        serde_json::from_value(json!({ "membership": membership, "displayname": displayname }))
+            .unwrap()
+    }
+
+    #[test]
+    fn membership_changes() {
+        let join = content("join", Some("Alice"));
+
+        assert_eq!(MembershipChange::new(None, &join, true), MembershipChange::Joined);
+        assert_eq!(
+            MembershipChange::new(Some(&content("invite", None)), &join, true),
+            MembershipChange::InvitationAccepted
+        );
+        assert_eq!(MembershipChange::new(Some(&join), &join, true), MembershipChange::None);
+        assert_eq!(
+            MembershipChange::new(Some(&join), &content("join", Some("Bob")), true),
+            MembershipChange::ProfileChanged {
+                display_name_changed: true,
+                avatar_url_changed: false
+            }
+        );
+        assert_eq!(
+            MembershipChange::new(Some(&join), &content("leave", None), true),
+            MembershipChange::Left
+        );
+        assert_eq!(
+            MembershipChange::new(Some(&join), &content("leave", None), false),
+            MembershipChange::Kicked
+        );
+        assert_eq!(
+            MembershipChange::new(Some(&join), &content("ban", None), false),
+            MembershipChange::KickedAndBanned
+        );
+        assert_eq!(
+            MembershipChange::new(Some(&content("invite", None)), &content("leave", None), true),
+            MembershipChange::InvitationRejected
+        );
+        assert_eq!(
+            MembershipChange::new(Some(&content("knock", None)), &content("invite", None), false),
+            MembershipChange::KnockAccepted
+        );
+    }
+
+    #[async_test]
+    async fn sync_response_json_in_chunks() {
+        let config = BaseClientConfig::new().room_chunk_size(1);
+        let client = BaseClient::new_with_config(config).unwrap();
+        client
+            .restore_login(Session {
+                access_token: "1234".to_owned(),
+                user_id: user_id!("@example:localhost"),
+                device_id: "DEVICEID".into(),
+            })
+            .await
+            .unwrap();
+
+        let mut body = test_json::SYNC.clone();
+        body["rooms"]["invite"] = test_json::INVITE_SYNC["rooms"]["invite"].clone();
+        let body = serde_json::to_vec(&body).unwrap();
+
+        let response = client.receive_sync_response_json(&body).await.unwrap();
+
+        let joined_id = room_id!("!SVkFJHzfwvuaIEawgC:localhost");
+        let invited_id = room_id!("!696r7674:example.com");
+
+        assert!(response.rooms.join.contains_key(&joined_id));
+        assert!(response.rooms.invite.contains_key(&invited_id));
+        assert!(!response.rooms.join[&joined_id].timeline.events.is_empty());
+
+        // Both rooms were written to the store, each one in its own chunk.
+        assert_eq!(client.get_room(&joined_id).unwrap().room_type(), RoomType::Joined);
+        assert_eq!(client.get_room(&invited_id).unwrap().room_type(), RoomType::Invited);
+        assert!(!client.store().get_joined_user_ids(&joined_id).await.unwrap().is_empty());
+        assert_eq!(client.sync_token().await.as_deref(), Some(response.next_batch.as_str()));
+
+        // The same response is ignored the second time.
+        let response = client.receive_sync_response_json(&body).await.unwrap();
+        assert!(response.rooms.join.is_empty());
+    }
+}
@@ -0,0 +1,229 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! De-duplication of the events of a stored timeline.
+//!
+//! Gappy syncs and back-pagination can both return events we already know
+//! about, every event of a timeline should still only show up once.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use matrix_sdk_common::deserialized_responses::SyncRoomEvent;
+use ruma::EventId;
+use serde_json::Value as JsonValue;
+
+use crate::redaction::event_id;
+
+/// Where new events are put in a timeline.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Position {
+    /// In front of the existing events, the events are newer.
+    Front,
+    /// After the existing events, the events are older.
+    Back,
+}
+
+/// An index of the events of a timeline by their event id.
+#[derive(Debug, Default)]
+pub(crate) struct SeenEvents {
+    positions: BTreeMap<EventId, usize>,
+}
+
+impl SeenEvents {
+    /// Index the given timeline events.
+    pub fn new(events: &[SyncRoomEvent]) -> Self {
+        let mut seen = Self::default();
+
+        for (position, event) in events.iter().enumerate() {
+            if let Some(event_id) = event_id(&event.event) {
+                seen.positions.entry(event_id).or_insert(position);
+            }
+        }
+
+        seen
+    }
+
+    /// Get the position of the event with the given id.
+    pub fn position(&self, event_id: &EventId) -> Option<usize> {
+        self.positions.get(event_id).copied()
+    }
+}
+
+/// Should the `new` copy of an event replace the `existing` copy.
+///
+/// A redacted copy always wins since the redaction happened after the other
+/// copy was created, after that decrypted copies are preferred, then copies
+/// that have bundled relations and then copies with more unsigned data. If
+/// both copies are equally good the existing one is kept.
+pub(crate) fn prefer_new(existing: &SyncRoomEvent, new: &SyncRoomEvent) -> bool {
+    fn rank(event: &SyncRoomEvent) -> (bool, bool, bool, usize) {
+        let json: JsonValue = serde_json::from_str(event.event.json().get()).unwrap_or_default();
+        let unsigned = json.get("unsigned");
+
+        (
+            unsigned.and_then(|u| u.get("redacted_because")).is_some(),
+            json.get("type").and_then(|t| t.as_str()) != Some("m.room.encrypted"),
+            unsigned.and_then(|u| u.get("m.relations")).is_some(),
+            unsigned.and_then(|u| u.as_object()).map_or(0, |u| u.len()),
+        )
+    }
+
+    rank(new) > rank(existing)
+}
+
+/// The result of merging new events into a timeline.
+#[derive(Debug, Default)]
+pub(crate) struct MergedEvents {
+    /// The events that weren't part of the timeline before.
+    pub added: Vec<SyncRoomEvent>,
+    /// The events of the timeline that were replaced by a better copy.
+    pub replaced: Vec<SyncRoomEvent>,
+}
+
+/// Merge new events into a timeline.
+///
+/// Events that are already part of the timeline, or that are part of the new
+/// events multiple times, are only kept once, the copy that is kept is
+/// decided by [`prefer_new`].
+///
+/// # Arguments
+///
+/// * `timeline` - The events of the timeline.
+///
+/// * `events` - The new events, in the same order as the events of the
+/// timeline.
+///
+/// * `position` - Where the new events should be put.
+pub(crate) fn merge_events(
+    timeline: &mut Vec<SyncRoomEvent>,
+    events: impl IntoIterator<Item = SyncRoomEvent>,
+    position: Position,
+) -> MergedEvents {
+    let seen = SeenEvents::new(timeline);
+    let mut added: Vec<SyncRoomEvent> = Vec::new();
+    let mut added_seen = BTreeMap::new();
+    let mut replaced = BTreeSet::new();
+
+    for event in events {
+        let id = match event_id(&event.event) {
+            Some(id) => id,
+            None => {
+                added.push(event);
+                continue;
+            }
+        };
+
+        if let Some(position) = seen.position(&id) {
+            if prefer_new(&timeline[position], &event) {
+                timeline[position] = event;
+                replaced.insert(position);
+            }
+        } else if let Some(&position) = added_seen.get(&id) {
+            if prefer_new(&added[position], &event) {
+                added[position] = event;
+            }
+        } else {
+            added_seen.insert(id, added.len());
+            added.push(event);
+        }
+    }
+
+    let replaced = replaced.into_iter().map(|p| timeline[p].clone()).collect();
+
+    match position {
+        Position::Front => {
+            timeline.splice(0..0, added.iter().cloned());
+        }
+        Position::Back => timeline.extend(added.iter().cloned()),
+    }
+
+    MergedEvents { added, replaced }
+}
+
+#[cfg(test)]
+mod test {
+    use matrix_sdk_common::deserialized_responses::SyncRoomEvent;
+    use ruma::serde::Raw;
+    use serde_json::{json, value::to_raw_value, Value as JsonValue};
+
+    use super::{merge_events, Position};
+
+    fn event(event_id: &str, unsigned: JsonValue) -> SyncRoomEvent {
+        let event = json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "sender": "@alice:example.org",
+            "origin_server_ts": 1,
+            "content": { "msgtype": "m.text", "body": event_id },
+            "unsigned": unsigned,
+        });
+
+        Raw::from_json(to_raw_value(&event).unwrap()).into()
+    }
+
+    fn ids(events: &[SyncRoomEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| {
+                let json: JsonValue = serde_json::from_str(e.event.json().get()).unwrap();
+                json["event_id"].as_str().unwrap().to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn duplicates_are_merged() {
+        let mut timeline = vec![event("$b", json!({})), event("$a", json!({}))];
+
+        let merged = merge_events(
+            &mut timeline,
+            vec![
+                event("$d", json!({})),
+                event("$c", json!({})),
+                event("$d", json!({})),
+                event("$b", json!({})),
+            ],
+            Position::Front,
+        );
+
+        assert_eq!(ids(&merged.added), ["$d", "$c"]);
+        assert!(merged.replaced.is_empty());
+        assert_eq!(ids(&timeline), ["$d", "$c", "$b", "$a"]);
+
+        let merged = merge_events(&mut timeline, vec![event("$a", json!({}))], Position::Back);
+
+        assert!(merged.added.is_empty());
+        assert_eq!(ids(&timeline), ["$d", "$c", "$b", "$a"]);
+    }
+
+    #[test]
+    fn richer_copy_is_kept() {
+        let relations = json!({ "m.relations": { "m.annotation": { "chunk": [] } } });
+        let redacted = json!({ "redacted_because": { "type": "m.room.redaction" } });
+
+        let mut timeline = vec![event("$a", json!({}))];
+
+        merge_events(&mut timeline, vec![event("$a", relations)], Position::Back);
+        assert!(timeline[0].event.json().get().contains("m.relations"));
+
+        // A copy with less data doesn't replace the existing one.
+        merge_events(&mut timeline, vec![event("$a", json!({ "age": 1 }))], Position::Front);
+        assert!(timeline[0].event.json().get().contains("m.relations"));
+
+        let merged = merge_events(&mut timeline, vec![event("$a", redacted)], Position::Back);
+        assert!(timeline[0].event.json().get().contains("redacted_because"));
+        assert_eq!(ids(&merged.replaced), ["$a"]);
+        assert_eq!(timeline.len(), 1);
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Processing of received timeline events.
+
+use matrix_sdk_common::{async_trait, deserialized_responses::SyncRoomEvent};
+
+use crate::Room;
+
+/// A processor that runs for every timeline event the client receives.
+///
+/// Processors run after an event was decrypted and before it's stored and
+/// passed on to event handlers. They can derive data from the event, e.g. a
+/// sanitized version of its HTML body, the links it contains or a spam
+/// score, and attach it to the event with [`SyncRoomEvent::set_metadata()`].
+/// The metadata is stored together with the event and can be read back with
+/// [`SyncRoomEvent::metadata()`].
+///
+/// Processors are added with
+/// [`BaseClientConfig::event_processor()`](crate::BaseClientConfig::event_processor)
+/// and run in the order they were added.
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait EventProcessor: Send + Sync {
+    /// Process an event that was received in the timeline of the given room.
+    async fn process(&self, room: &Room, event: &mut SyncRoomEvent);
+}
@@ -0,0 +1,219 @@
+//! Types for [image packs](https://github.com/matrix-org/matrix-doc/pull/2545),
+//! collections of custom emoticons and stickers.
+//!
+//! Image packs are stored in the `im.ponies.room_emotes` state events of a
+//! room and in the `im.ponies.user_emotes` account data of the user. The
+//! `im.ponies.emote_rooms` account data lists the room packs the user enabled
+//! globally.
+
+use std::collections::BTreeMap;
+
+use ruma::{
+    events::{room::ImageInfo, sticker::StickerEventContent},
+    MxcUri, RoomId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+/// The event type of the state events holding the image packs of a room.
+pub const ROOM_IMAGE_PACK_EVENT_TYPE: &str = "im.ponies.room_emotes";
+
+/// The event type of the account data holding the image pack of the user.
+pub const USER_IMAGE_PACK_EVENT_TYPE: &str = "im.ponies.user_emotes";
+
+/// The event type of the account data listing the room image packs the user
+/// enabled globally.
+pub const IMAGE_PACK_ROOMS_EVENT_TYPE: &str = "im.ponies.emote_rooms";
+
+/// What an image of an image pack is meant to be used as.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "String", into = "String")]
+pub enum PackUsage {
+    /// The image is a custom emoticon, to be used inline in messages.
+    Emoticon,
+    /// The image is a sticker, to be sent as a `m.sticker` event.
+    Sticker,
+    /// A usage that isn't known to the SDK.
+    Custom(String),
+}
+
+impl PackUsage {
+    /// Get the usage as it is used in the `usage` list of an image pack.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Emoticon => "emoticon",
+            Self::Sticker => "sticker",
+            Self::Custom(usage) => usage,
+        }
+    }
+}
+
+impl From<String> for PackUsage {
+    fn from(usage: String) -> Self {
+        match usage.as_str() {
+            "emoticon" => Self::Emoticon,
+            "sticker" => Self::Sticker,
+            _ => Self::Custom(usage),
+        }
+    }
+}
+
+impl From<PackUsage> for String {
+    fn from(usage: PackUsage) -> Self {
+        usage.as_str().to_owned()
+    }
+}
+
+/// Information about an image pack as a whole.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PackInfo {
+    /// The name of the pack that should be shown to users.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+
+    /// The avatar of the pack.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<MxcUri>,
+
+    /// What the images of the pack are meant to be used as, if the images
+    /// don't specify it themselves. Empty if they can be used as anything.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub usage: Vec<PackUsage>,
+
+    /// The attribution of the pack.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attribution: Option<String>,
+}
+
+/// A single image of an image pack.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PackImage {
+    /// The URL of the image.
+    pub url: MxcUri,
+
+    /// The text that should be used in place of the image, e.g. as the body
+    /// of a sticker. The short code of the image is used if this isn't set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+
+    /// Metadata about the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub info: Option<ImageInfo>,
+
+    /// What the image is meant to be used as. If empty, the usage of the pack
+    /// applies.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub usage: Vec<PackUsage>,
+}
+
+impl PackImage {
+    /// Create the content of a `m.sticker` event that sends this image.
+    ///
+    /// # Arguments
+    ///
+    /// * `shortcode` - The short code of the image in its pack, used as the
+    /// body of the sticker if the image doesn't have a body.
+    pub fn sticker_content(&self, shortcode: &str) -> StickerEventContent {
+        StickerEventContent::new(
+            self.body.clone().unwrap_or_else(|| shortcode.to_owned()),
+            self.info.clone().unwrap_or_default(),
+            self.url.clone(),
+        )
+    }
+}
+
+/// An image pack, a collection of custom emoticons and stickers.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ImagePack {
+    /// The images of the pack, keyed by their short code.
+    #[serde(default)]
+    pub images: BTreeMap<String, PackImage>,
+
+    /// Information about the pack as a whole.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pack: Option<PackInfo>,
+}
+
+impl ImagePack {
+    /// Is the given image of this pack meant to be used as the given usage.
+    ///
+    /// Images that neither they nor their pack specify a usage can be used as
+    /// anything.
+    pub fn is_usable_as(&self, image: &PackImage, usage: &PackUsage) -> bool {
+        let usages = if !image.usage.is_empty() {
+            &image.usage
+        } else if let Some(pack) = &self.pack {
+            &pack.usage
+        } else {
+            return true;
+        };
+
+        usages.is_empty() || usages.contains(usage)
+    }
+
+    /// Get the images of this pack that are meant to be used as stickers,
+    /// together with their short codes.
+    pub fn stickers(&self) -> impl Iterator<Item = (&str, &PackImage)> {
+        self.images_usable_as(PackUsage::Sticker)
+    }
+
+    /// Get the images of this pack that are meant to be used as emoticons,
+    /// together with their short codes.
+    pub fn emoticons(&self) -> impl Iterator<Item = (&str, &PackImage)> {
+        self.images_usable_as(PackUsage::Emoticon)
+    }
+
+    fn images_usable_as(&self, usage: PackUsage) -> impl Iterator<Item = (&str, &PackImage)> {
+        self.images
+            .iter()
+            .filter(move |(_, image)| self.is_usable_as(image, &usage))
+            .map(|(shortcode, image)| (shortcode.as_str(), image))
+    }
+}
+
+/// The room image packs the user enabled globally, the content of the
+/// `im.ponies.emote_rooms` account data.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ImagePackRooms {
+    /// The enabled packs, keyed by the room they are in and the state key of
+    /// their state event.
+    #[serde(default)]
+    pub rooms: BTreeMap<RoomId, BTreeMap<String, JsonValue>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ImagePackStateEvent {
+    pub state_key: String,
+    pub content: ImagePack,
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::{ImagePack, PackUsage};
+
+    #[test]
+    fn image_usage() {
+        let pack: ImagePack = serde_json::from_value(json!({
+            "images": {
+                "cat": { "url": "mxc://localhost/cat" },
+                "dog": { "url": "mxc://localhost/dog", "usage": ["emoticon"] },
+                "fox": { "url": "mxc://localhost/fox", "usage": ["sticker", "org.custom"] },
+            },
+            "pack": { "display_name": "Animals", "usage": ["sticker"] },
+        }))
+        .unwrap();
+
+        let stickers: Vec<_> = pack.stickers().map(|(shortcode, _)| shortcode).collect();
+        let emoticons: Vec<_> = pack.emoticons().map(|(shortcode, _)| shortcode).collect();
+
+        assert_eq!(stickers, ["cat", "fox"]);
+        assert_eq!(emoticons, ["dog"]);
+        assert_eq!(pack.images["fox"].usage[1], PackUsage::Custom("org.custom".to_owned()));
+
+        let sticker = pack.images["cat"].sticker_content("cat");
+        assert_eq!(sticker.body, "cat");
+        assert_eq!(sticker.url.as_str(), "mxc://localhost/cat");
+    }
+}
@@ -44,15 +44,25 @@ pub use crate::{
 };
 
 mod client;
+mod dedup;
 mod error;
+mod event_processors;
+pub mod image_pack;
+mod local_echo;
 pub mod media;
+mod redaction;
 mod rooms;
 mod session;
 mod store;
 
 pub use client::{BaseClient, BaseClientConfig};
+pub use event_processors::EventProcessor;
+pub use local_echo::{LocalEcho, LocalEchoState};
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub use matrix_sdk_crypto as crypto;
-pub use rooms::{Room, RoomInfo, RoomMember, RoomType};
-pub use store::{StateChanges, StateStore, Store, StoreError};
+pub use rooms::{
+    AllowRule, PowerLevelsBuilder, PowerLevelsError, Room, RoomInfo, RoomMember, RoomPowerLevels,
+    RoomType, StateChange, StateSnapshot, TagName,
+};
+pub use store::{DynStateStore, IntoStateStore, StateChanges, StateStore, Store, StoreError};
@@ -0,0 +1,192 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Local echoes of events that we sent but didn't yet receive in a sync.
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use matrix_sdk_common::deserialized_responses::SyncRoomEvent;
+use ruma::{events::AnyMessageEventContent, EventId, MilliSecondsSinceUnixEpoch, RoomId, UserId};
+use serde::Deserialize;
+
+/// The state of a local echo.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LocalEchoState {
+    /// The event is still being sent to the server.
+    Pending,
+    /// The server accepted the event, we're waiting for the remote echo to
+    /// arrive in a sync.
+    Sent(EventId),
+    /// Sending the event failed, it can be retried using the same transaction
+    /// id.
+    Failed,
+}
+
+/// A local echo of an event that we sent.
+///
+/// Local echoes allow an event to be shown in the timeline immediately, before
+/// the server accepted the event. Once the remote echo of the event arrives in
+/// a sync, the local echo is removed and replaced by the remote one, the two
+/// are matched using the transaction id of the event.
+#[derive(Clone, Debug)]
+pub struct LocalEcho {
+    /// The transaction id that was used to send the event.
+    pub transaction_id: String,
+    /// The room the event was sent to.
+    pub room_id: RoomId,
+    /// The content of the event, before it got encrypted.
+    pub content: AnyMessageEventContent,
+    /// The time the local echo was created.
+    pub created: MilliSecondsSinceUnixEpoch,
+    /// The current state of the local echo.
+    pub state: LocalEchoState,
+}
+
+/// The parts of a room event that are needed to match it to a local echo.
+#[derive(Deserialize)]
+struct RemoteEcho {
+    sender: UserId,
+    #[serde(default)]
+    unsigned: RemoteEchoUnsigned,
+}
+
+#[derive(Default, Deserialize)]
+struct RemoteEchoUnsigned {
+    transaction_id: Option<String>,
+}
+
+/// The local echoes of all rooms, indexed by room.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LocalEchoes {
+    inner: Arc<DashMap<RoomId, Vec<LocalEcho>>>,
+}
+
+impl LocalEchoes {
+    pub fn add(&self, room_id: &RoomId, transaction_id: String, content: AnyMessageEventContent) {
+        let echo = LocalEcho {
+            transaction_id,
+            room_id: room_id.clone(),
+            content,
+            created: MilliSecondsSinceUnixEpoch::now(),
+            state: LocalEchoState::Pending,
+        };
+
+        let mut echoes = self.inner.entry(room_id.clone()).or_insert_with(Vec::new);
+
+        // Retrying a failed event reuses the transaction id, replace the old
+        // echo in that case.
+        echoes.retain(|e| e.transaction_id != echo.transaction_id);
+        echoes.push(echo);
+    }
+
+    pub fn get(&self, room_id: &RoomId) -> Vec<LocalEcho> {
+        self.inner.get(room_id).map(|e| e.clone()).unwrap_or_default()
+    }
+
+    pub fn set_state(&self, room_id: &RoomId, transaction_id: &str, state: LocalEchoState) {
+        if let Some(mut echoes) = self.inner.get_mut(room_id) {
+            if let Some(echo) = echoes.iter_mut().find(|e| e.transaction_id == transaction_id) {
+                echo.state = state;
+            }
+        }
+    }
+
+    /// Remove the local echo with the given transaction id.
+    ///
+    /// Returns `true` if a local echo was removed.
+    pub fn remove(&self, room_id: &RoomId, transaction_id: &str) -> bool {
+        if let Some(mut echoes) = self.inner.get_mut(room_id) {
+            let count = echoes.len();
+            echoes.retain(|e| e.transaction_id != transaction_id);
+
+            echoes.len() != count
+        } else {
+            false
+        }
+    }
+}
+
+/// Get the transaction id of the given event, received in a sync, if it's the
+/// remote echo of an event we sent.
+pub(crate) fn remote_echo_transaction_id(
+    own_user_id: &UserId,
+    event: &SyncRoomEvent,
+) -> Option<String> {
+    // Only the sender of an event gets the transaction id, in the unsigned part
+    // of the event. Encrypted events were already decrypted at this point and
+    // carry over the unsigned part of the encrypted event.
+    let remote: RemoteEcho = serde_json::from_str(event.event.clone().into_json().get()).ok()?;
+
+    if &remote.sender != own_user_id {
+        return None;
+    }
+
+    remote.unsigned.transaction_id
+}
+
+#[cfg(test)]
+mod test {
+    use matrix_sdk_common::deserialized_responses::SyncRoomEvent;
+    use ruma::{
+        events::{room::message::MessageEventContent, AnyMessageEventContent, AnySyncRoomEvent},
+        room_id,
+        serde::Raw,
+        user_id,
+    };
+    use serde_json::json;
+
+    use super::{remote_echo_transaction_id, LocalEchoState, LocalEchoes};
+
+    #[test]
+    fn remote_echo_replaces_local_echo() {
+        let echoes = LocalEchoes::default();
+        let room_id = room_id!("!test:localhost");
+        let user_id = user_id!("@example:localhost");
+
+        let content =
+            AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Hello world"));
+        echoes.add(&room_id, "txn1".to_owned(), content.clone());
+        echoes.add(&room_id, "txn2".to_owned(), content);
+
+        echoes.set_state(&room_id, "txn1", LocalEchoState::Failed);
+        assert_eq!(echoes.get(&room_id)[0].state, LocalEchoState::Failed);
+
+        let event = |sender: &str| -> SyncRoomEvent {
+            let event = json!({
+                "content": { "body": "Hello world", "msgtype": "m.text" },
+                "event_id": "$h29iv0s8:example.com",
+                "origin_server_ts": 152037280,
+                "sender": sender,
+                "type": "m.room.message",
+                "unsigned": { "transaction_id": "txn1" }
+            });
+
+            serde_json::from_value::<Raw<AnySyncRoomEvent>>(event).unwrap().into()
+        };
+
+        // Other users can't resolve our local echoes.
+        assert!(remote_echo_transaction_id(&user_id, &event("@other:localhost")).is_none());
+
+        let transaction_id = remote_echo_transaction_id(&user_id, &event(user_id.as_str()));
+        assert_eq!(transaction_id.as_deref(), Some("txn1"));
+        assert!(echoes.remove(&room_id, "txn1"));
+        assert!(!echoes.remove(&room_id, "txn1"));
+
+        let remaining = echoes.get(&room_id);
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].transaction_id, "txn2");
+        assert_eq!(remaining[0].state, LocalEchoState::Pending);
+    }
+}
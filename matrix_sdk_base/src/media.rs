@@ -8,12 +8,15 @@ use ruma::{
                 AudioMessageEventContent, FileMessageEventContent, ImageMessageEventContent,
                 LocationMessageEventContent, VideoMessageEventContent,
             },
-            EncryptedFile,
+            EncryptedFile, ImageInfo,
         },
         sticker::StickerEventContent,
+        AnySyncRoomEvent, EventType,
     },
+    serde::Raw,
     MxcUri, UInt,
 };
+use serde::{Deserialize, Serialize};
 
 const UNIQUE_SEPARATOR: &str = "_";
 
@@ -122,6 +125,72 @@ impl MediaEventContent for StickerEventContent {
     }
 }
 
+/// The content of a `m.sticker` event whose image may be encrypted.
+///
+/// Stickers that are sent to encrypted rooms can contain an encrypted file
+/// instead of a plain URL, which `StickerEventContent` can't represent.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StickerContent {
+    /// A textual representation of the sticker.
+    pub body: String,
+
+    /// Metadata about the image of the sticker.
+    #[serde(default)]
+    pub info: ImageInfo,
+
+    /// The URL of the image if it isn't encrypted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<MxcUri>,
+
+    /// The image if it is encrypted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<Box<EncryptedFile>>,
+}
+
+impl StickerContent {
+    /// Get the sticker content out of a `m.sticker` event.
+    ///
+    /// Returns `None` if the event isn't a sticker or if it couldn't be
+    /// deserialized.
+    pub fn from_event(event: &Raw<AnySyncRoomEvent>) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct StickerEvent {
+            #[serde(rename = "type")]
+            event_type: String,
+            content: StickerContent,
+        }
+
+        event
+            .deserialize_as::<StickerEvent>()
+            .ok()
+            .filter(|e| e.event_type == EventType::Sticker.as_str())
+            .map(|e| e.content)
+    }
+}
+
+impl From<StickerEventContent> for StickerContent {
+    fn from(content: StickerEventContent) -> Self {
+        Self { body: content.body, info: content.info, url: Some(content.url), file: None }
+    }
+}
+
+impl MediaEventContent for StickerContent {
+    fn file(&self) -> Option<MediaType> {
+        self.url
+            .as_ref()
+            .map(|uri| MediaType::Uri(uri.clone()))
+            .or_else(|| self.file.as_ref().map(|e| MediaType::Encrypted(e.clone())))
+    }
+
+    fn thumbnail(&self) -> Option<MediaType> {
+        if let Some(uri) = self.info.thumbnail_url.as_ref() {
+            Some(MediaType::Uri(uri.clone()))
+        } else {
+            self.info.thumbnail_file.as_ref().map(|file| MediaType::Encrypted(file.clone()))
+        }
+    }
+}
+
 impl MediaEventContent for AudioMessageEventContent {
     fn file(&self) -> Option<MediaType> {
         self.url
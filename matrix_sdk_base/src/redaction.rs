@@ -0,0 +1,373 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pruning of redacted events following the redaction rules of the spec.
+
+use matrix_sdk_common::deserialized_responses::SyncRoomEvent;
+use ruma::{serde::Raw, EventId, RoomVersionId};
+use serde::Deserialize;
+use serde_json::{json, value::to_raw_value, Value as JsonValue};
+
+/// The top level keys of an event that are preserved when it gets redacted.
+const PRESERVED_KEYS: &[&str] = &[
+    "event_id",
+    "type",
+    "room_id",
+    "sender",
+    "state_key",
+    "content",
+    "hashes",
+    "signatures",
+    "depth",
+    "prev_events",
+    "prev_state",
+    "auth_events",
+    "origin",
+    "origin_server_ts",
+    "membership",
+];
+
+/// The keys of the content of an event of the given type that are preserved
+/// when it gets redacted.
+fn preserved_content_keys(
+    event_type: &str,
+    room_version: &RoomVersionId,
+) -> &'static [&'static str] {
+    let version = room_version.as_str();
+
+    match event_type {
+        "m.room.member" if version == "9" => &["membership", "join_authorised_via_users_server"],
+        "m.room.member" => &["membership"],
+        "m.room.create" => &["creator"],
+        "m.room.join_rules" if matches!(version, "8" | "9") => &["join_rule", "allow"],
+        "m.room.join_rules" => &["join_rule"],
+        "m.room.power_levels" => &[
+            "ban",
+            "events",
+            "events_default",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+        "m.room.aliases" if matches!(version, "1" | "2" | "3" | "4" | "5") => &["aliases"],
+        "m.room.history_visibility" => &["history_visibility"],
+        _ => &[],
+    }
+}
+
+fn retain_keys(object: &mut serde_json::Map<String, JsonValue>, keys: &[&str]) {
+    let removed: Vec<String> =
+        object.keys().filter(|k| !keys.contains(&k.as_str())).cloned().collect();
+
+    for key in removed {
+        object.remove(&key);
+    }
+}
+
+/// Strip the given event of everything the redaction rules of the room version
+/// don't preserve.
+///
+/// The redaction event is put into the `unsigned` field of the event, like the
+/// homeserver does it for events that are redacted when they are sent to us.
+pub(crate) fn redact_json(
+    event: &mut JsonValue,
+    redaction: &JsonValue,
+    room_version: &RoomVersionId,
+) {
+    let object = match event.as_object_mut() {
+        Some(o) => o,
+        None => return,
+    };
+
+    let event_type = object.get("type").and_then(|t| t.as_str()).unwrap_or_default().to_owned();
+
+    retain_keys(object, PRESERVED_KEYS);
+
+    if let Some(content) = object.get_mut("content").and_then(|c| c.as_object_mut()) {
+        retain_keys(content, preserved_content_keys(&event_type, room_version));
+    }
+
+    object.insert("unsigned".to_owned(), json!({ "redacted_because": redaction }));
+}
+
+/// Redact the given raw event.
+///
+/// Returns `None` if the event isn't a valid JSON object.
+pub(crate) fn redact_raw<T>(
+    event: &Raw<T>,
+    redaction: &JsonValue,
+    room_version: &RoomVersionId,
+) -> Option<Raw<T>> {
+    let mut event: JsonValue = serde_json::from_str(event.json().get()).ok()?;
+    redact_json(&mut event, redaction, room_version);
+
+    Some(Raw::from_json(to_raw_value(&event).ok()?))
+}
+
+#[derive(Deserialize)]
+struct EventIdDeHelper {
+    event_id: EventId,
+}
+
+/// Get the event id of the given raw event without deserializing all of it.
+pub(crate) fn event_id<T>(event: &Raw<T>) -> Option<EventId> {
+    serde_json::from_str::<EventIdDeHelper>(event.json().get()).ok().map(|e| e.event_id)
+}
+
+/// The relation of an event to another event, as found in the
+/// `m.relates_to` field of its content.
+#[derive(Clone, Debug)]
+pub(crate) struct Relation {
+    /// The type of the relation, e.g. `m.annotation`.
+    pub rel_type: String,
+    /// The event the event relates to.
+    pub event_id: String,
+    /// The key of an annotation, e.g. the emoji of a reaction.
+    pub key: Option<String>,
+}
+
+impl Relation {
+    /// Get the relation of the given event, if it has one.
+    pub fn of(event: &JsonValue) -> Option<Self> {
+        let relates_to = event.get("content")?.get("m.relates_to")?;
+
+        Some(Self {
+            rel_type: relates_to.get("rel_type")?.as_str()?.to_owned(),
+            event_id: relates_to.get("event_id")?.as_str()?.to_owned(),
+            key: relates_to.get("key").and_then(|k| k.as_str()).map(|k| k.to_owned()),
+        })
+    }
+
+    /// Remove the related event, which got redacted, from the bundled
+    /// aggregations of the event it relates to.
+    ///
+    /// Returns true if the aggregations changed.
+    fn remove_from_aggregations(&self, related: &mut JsonValue, redacted_event_id: &str) -> bool {
+        let aggregation =
+            match related.pointer_mut(&format!("/unsigned/m.relations/{}", self.rel_type)) {
+                Some(a) => a,
+                None => return false,
+            };
+
+        match self.rel_type.as_str() {
+            "m.replace" => {
+                let is_latest_edit =
+                    aggregation.get("event_id").and_then(|e| e.as_str()) == Some(redacted_event_id);
+
+                if is_latest_edit {
+                    // We don't know about the edit that came before, the event
+                    // is shown unedited until the homeserver tells us better.
+                    if let Some(relations) =
+                        related.pointer_mut("/unsigned/m.relations").and_then(|r| r.as_object_mut())
+                    {
+                        relations.remove("m.replace");
+                    }
+                }
+
+                is_latest_edit
+            }
+            "m.annotation" => {
+                let key = match &self.key {
+                    Some(k) => k,
+                    None => return false,
+                };
+
+                let chunk = match aggregation.get_mut("chunk").and_then(|c| c.as_array_mut()) {
+                    Some(c) => c,
+                    None => return false,
+                };
+
+                let position = match chunk
+                    .iter()
+                    .position(|a| a.get("key").and_then(|k| k.as_str()) == Some(key.as_str()))
+                {
+                    Some(p) => p,
+                    None => return false,
+                };
+
+                let count = chunk[position].get("count").and_then(|c| c.as_u64()).unwrap_or(1);
+
+                if count <= 1 {
+                    chunk.remove(position);
+                } else {
+                    chunk[position]["count"] = (count - 1).into();
+                }
+
+                true
+            }
+            _ => {
+                let chunk = match aggregation.get_mut("chunk").and_then(|c| c.as_array_mut()) {
+                    Some(c) => c,
+                    None => return false,
+                };
+
+                let len = chunk.len();
+                chunk.retain(|r| {
+                    r.get("event_id").and_then(|e| e.as_str()) != Some(redacted_event_id)
+                });
+
+                chunk.len() != len
+            }
+        }
+    }
+}
+
+/// Redact the event with the given event id in a list of timeline events.
+///
+/// Returns the content of the event as it was before the redaction, `None` if
+/// the event isn't part of the list.
+pub(crate) fn redact_timeline_event(
+    events: &mut [SyncRoomEvent],
+    redacts: &EventId,
+    redaction: &JsonValue,
+    room_version: &RoomVersionId,
+) -> Option<JsonValue> {
+    let event = events.iter_mut().find(|e| event_id(&e.event).as_ref() == Some(redacts))?;
+
+    let mut json: JsonValue = serde_json::from_str(event.event.json().get()).ok()?;
+    let original = json.clone();
+
+    redact_json(&mut json, redaction, room_version);
+    event.event = Raw::from_json(to_raw_value(&json).ok()?);
+
+    Some(original)
+}
+
+/// Remove a redacted event from the bundled aggregations of the event it
+/// relates to, if the event is part of the given list of timeline events.
+///
+/// Returns true if an event of the list changed.
+pub(crate) fn remove_from_aggregations(
+    events: &mut [SyncRoomEvent],
+    relation: &Relation,
+    redacted_event_id: &EventId,
+) -> bool {
+    let event = match events
+        .iter_mut()
+        .find(|e| event_id(&e.event).map_or(false, |id| id.as_str() == relation.event_id))
+    {
+        Some(e) => e,
+        None => return false,
+    };
+
+    let mut json: JsonValue = match serde_json::from_str(event.event.json().get()) {
+        Ok(j) => j,
+        Err(_) => return false,
+    };
+
+    if !relation.remove_from_aggregations(&mut json, redacted_event_id.as_str()) {
+        return false;
+    }
+
+    match to_raw_value(&json) {
+        Ok(raw) => {
+            event.event = Raw::from_json(raw);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::RoomVersionId;
+    use serde_json::json;
+
+    use super::{redact_json, Relation};
+
+    #[test]
+    fn redaction_rules() {
+        let redaction = json!({ "type": "m.room.redaction", "redacts": "$member" });
+
+        let mut member = json!({
+            "type": "m.room.member",
+            "event_id": "$member",
+            "sender": "@alice:example.org",
+            "state_key": "@alice:example.org",
+            "origin_server_ts": 1,
+            "content": {
+                "membership": "join",
+                "displayname": "Alice",
+            },
+            "unsigned": { "age": 10 },
+        });
+
+        redact_json(&mut member, &redaction, &RoomVersionId::Version6);
+
+        assert_eq!(member["content"], json!({ "membership": "join" }));
+        assert_eq!(member["unsigned"], json!({ "redacted_because": redaction }));
+        assert_eq!(member["sender"], "@alice:example.org");
+
+        let mut join_rules = json!({
+            "type": "m.room.join_rules",
+            "content": { "join_rule": "restricted", "allow": [] },
+        });
+        let mut old_join_rules = join_rules.clone();
+
+        redact_json(&mut join_rules, &redaction, &RoomVersionId::Version8);
+        redact_json(&mut old_join_rules, &redaction, &RoomVersionId::Version6);
+
+        assert_eq!(join_rules["content"], json!({ "join_rule": "restricted", "allow": [] }));
+        assert_eq!(old_join_rules["content"], json!({ "join_rule": "restricted" }));
+    }
+
+    #[test]
+    fn aggregations() {
+        let mut event = json!({
+            "type": "m.room.message",
+            "event_id": "$message",
+            "content": { "body": "Hello" },
+            "unsigned": {
+                "m.relations": {
+                    "m.annotation": {
+                        "chunk": [
+                            { "type": "m.reaction", "key": "👍", "count": 2 },
+                            { "type": "m.reaction", "key": "🎉", "count": 1 },
+                        ]
+                    },
+                    "m.replace": { "event_id": "$edit" },
+                }
+            }
+        });
+
+        let reaction = |key: &str| {
+            Relation::of(&json!({
+                "content": {
+                    "m.relates_to": { "rel_type": "m.annotation", "event_id": "$message", "key": key }
+                }
+            }))
+            .unwrap()
+        };
+
+        assert!(reaction("👍").remove_from_aggregations(&mut event, "$reaction1"));
+        assert!(reaction("🎉").remove_from_aggregations(&mut event, "$reaction2"));
+        assert!(!reaction("😀").remove_from_aggregations(&mut event, "$reaction3"));
+
+        assert_eq!(
+            event["unsigned"]["m.relations"]["m.annotation"]["chunk"],
+            json!([{ "type": "m.reaction", "key": "👍", "count": 1 }])
+        );
+
+        let edit = Relation::of(&json!({
+            "content": { "m.relates_to": { "rel_type": "m.replace", "event_id": "$message" } }
+        }))
+        .unwrap();
+
+        assert!(!edit.remove_from_aggregations(&mut event, "$older_edit"));
+        assert!(edit.remove_from_aggregations(&mut event, "$edit"));
+        assert!(event["unsigned"]["m.relations"].get("m.replace").is_none());
+    }
+}
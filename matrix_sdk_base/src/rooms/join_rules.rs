@@ -0,0 +1,112 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ruma::RoomId;
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
+
+/// A rule that allows users to join a room with the `restricted` join rule
+/// without being invited.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AllowRule {
+    /// Members of the given room are allowed to join, `m.room_membership`.
+    RoomMembership(RoomId),
+    /// A rule of a type that isn't known to the SDK, contains the type of the
+    /// rule.
+    Custom(String),
+}
+
+#[derive(Deserialize)]
+struct AllowRuleJson {
+    #[serde(rename = "type")]
+    rule_type: String,
+    room_id: Option<RoomId>,
+}
+
+#[derive(Deserialize)]
+struct JoinRulesJson {
+    content: JoinRulesContentJson,
+}
+
+#[derive(Deserialize)]
+struct JoinRulesContentJson {
+    join_rule: String,
+    #[serde(default)]
+    allow: Vec<AllowRuleJson>,
+}
+
+/// Parse the `allow` rules out of a `m.room.join_rules` event.
+///
+/// Returns `None` if the event couldn't be parsed or if the join rule of the
+/// room isn't `restricted`, the `allow` rules don't apply in that case.
+pub(crate) fn restricted_allow_rules(event: &RawJsonValue) -> Option<Vec<AllowRule>> {
+    let event: JoinRulesJson = serde_json::from_str(event.get()).ok()?;
+
+    if event.content.join_rule != "restricted" {
+        return None;
+    }
+
+    Some(
+        event
+            .content
+            .allow
+            .into_iter()
+            .map(|rule| match (rule.rule_type.as_str(), rule.room_id) {
+                ("m.room_membership", Some(room_id)) => AllowRule::RoomMembership(room_id),
+                _ => AllowRule::Custom(rule.rule_type),
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::room_id;
+    use serde_json::{json, value::to_raw_value};
+
+    use super::{restricted_allow_rules, AllowRule};
+
+    #[test]
+    fn parse_allow_rules() {
+        let event = to_raw_value(&json!({
+            "type": "m.room.join_rules",
+            "state_key": "",
+            "content": {
+                "join_rule": "restricted",
+                "allow": [
+                    { "type": "m.room_membership", "room_id": "!space:localhost" },
+                    { "type": "org.example.custom" }
+                ]
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            restricted_allow_rules(&event).unwrap(),
+            vec![
+                AllowRule::RoomMembership(room_id!("!space:localhost")),
+                AllowRule::Custom("org.example.custom".to_owned())
+            ]
+        );
+
+        let event = to_raw_value(&json!({
+            "type": "m.room.join_rules",
+            "state_key": "",
+            "content": { "join_rule": "public" }
+        }))
+        .unwrap();
+
+        assert!(restricted_allow_rules(&event).is_none());
+    }
+}
@@ -17,7 +17,10 @@ use std::sync::Arc;
 use ruma::{
     events::{
         presence::PresenceEvent,
-        room::{member::MemberEventContent, power_levels::PowerLevelsEventContent},
+        room::{
+            member::{MemberEventContent, MembershipState},
+            power_levels::PowerLevelsEventContent,
+        },
         SyncStateEvent,
     },
     MxcUri, UserId,
@@ -43,6 +46,16 @@ impl RoomMember {
         &self.event.state_key
     }
 
+    /// Get the membership state of this member.
+    pub fn membership(&self) -> &MembershipState {
+        &self.event.content.membership
+    }
+
+    /// Is this member asking to join the room.
+    pub fn is_knocking(&self) -> bool {
+        self.membership() == &MembershipState::Knock
+    }
+
     /// Get the display name of the member if there is one.
     pub fn display_name(&self) -> Option<&str> {
         if let Some(p) = self.profile.as_ref() {
@@ -1,10 +1,16 @@
+mod join_rules;
 mod members;
 mod normal;
+mod power_levels;
+mod tags;
 
-use std::cmp::max;
+use std::{cmp::max, collections::BTreeSet};
 
+pub(crate) use join_rules::restricted_allow_rules;
+pub use join_rules::AllowRule;
 pub use members::RoomMember;
-pub use normal::{Room, RoomInfo, RoomType};
+pub use normal::{Room, RoomInfo, RoomType, StateSnapshot};
+pub use power_levels::{PowerLevelsBuilder, PowerLevelsError, RoomPowerLevels};
 use ruma::{
     events::{
         room::{
@@ -12,11 +18,43 @@ use ruma::{
             guest_access::GuestAccess, history_visibility::HistoryVisibility, join_rules::JoinRule,
             tombstone::TombstoneEventContent,
         },
-        AnyStateEventContent,
+        AnyStateEventContent, AnySyncStateEvent,
     },
-    MxcUri, RoomAliasId, UserId,
+    serde::Raw,
+    MxcUri, RoomAliasId, RoomId, UserId,
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+pub use tags::TagName;
+
+/// A change of a single piece of room state, found while processing a sync
+/// response.
+#[derive(Clone, Debug)]
+pub struct StateChange {
+    /// The room the state belongs to.
+    pub room_id: RoomId,
+    /// The type of the state event.
+    pub event_type: String,
+    /// The state key of the state event.
+    pub state_key: String,
+    /// The state event that got replaced, `None` if the state is new.
+    pub old: Option<Raw<AnySyncStateEvent>>,
+    /// The state event that is now the current state.
+    pub new: Raw<AnySyncStateEvent>,
+}
+
+#[derive(Deserialize)]
+struct StateKeyDeHelper {
+    #[serde(rename = "type")]
+    event_type: String,
+    state_key: String,
+}
+
+/// Get the event type and the state key of the given raw state event without
+/// deserializing all of it.
+pub(crate) fn state_key_of<T>(event: &Raw<T>) -> Option<(String, String)> {
+    let event: StateKeyDeHelper = serde_json::from_str(event.json().get()).ok()?;
+    Some((event.event_type, event.state_key))
+}
 
 /// A base room info struct that is the backbone of normal as well as stripped
 /// rooms. Holds all the state events that are important to present a room to
@@ -29,9 +67,10 @@ pub struct BaseRoomInfo {
     pub canonical_alias: Option<RoomAliasId>,
     /// The `m.room.create` event content of this room.
     pub create: Option<CreateEventContent>,
-    /// The user id this room is sharing the direct message with, if the room is
-    /// a direct message.
-    pub dm_target: Option<UserId>,
+    /// The user ids this room is sharing the direct message with, if the room
+    /// is a direct message.
+    #[serde(default, alias = "dm_target", deserialize_with = "deserialize_dm_targets")]
+    pub dm_targets: BTreeSet<UserId>,
     /// The `m.room.encryption` event content that enabled E2EE in this room.
     pub encryption: Option<EncryptionEventContent>,
     /// The guest access policy of this room.
@@ -50,6 +89,25 @@ pub struct BaseRoomInfo {
     pub topic: Option<String>,
 }
 
+/// The DM targets as they were stored, older versions stored a single,
+/// optional `dm_target`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DmTargets {
+    Multiple(BTreeSet<UserId>),
+    Single(Option<UserId>),
+}
+
+fn deserialize_dm_targets<'de, D>(deserializer: D) -> Result<BTreeSet<UserId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(match DmTargets::deserialize(deserializer)? {
+        DmTargets::Multiple(targets) => targets,
+        DmTargets::Single(target) => target.into_iter().collect(),
+    })
+}
+
 impl BaseRoomInfo {
     /// Create a new, empty base room info.
     pub fn new() -> Self {
@@ -135,7 +193,7 @@ impl Default for BaseRoomInfo {
             avatar_url: None,
             canonical_alias: None,
             create: None,
-            dm_target: None,
+            dm_targets: BTreeSet::new(),
             encryption: None,
             guest_access: GuestAccess::CanJoin,
             history_visibility: HistoryVisibility::WorldReadable,
@@ -169,9 +227,15 @@ fn calculate_room_name(
         let mut names = heroes;
         names.sort_unstable();
 
-        // TODO: What length does the spec want us to use here and in
-        // the `else`?
-        format!("{}, and {} others", names.join(", "), (invited_joined - heroes_count))
+        // Our own user isn't part of the heroes, so it doesn't count as one
+        // of the others either.
+        let others = invited_joined_minus_one - heroes_count;
+
+        if others == 1 {
+            format!("{}, and 1 other", names.join(", "))
+        } else {
+            format!("{}, and {} others", names.join(", "), others)
+        }
     } else {
         "".to_string()
     };
@@ -190,7 +254,29 @@ fn calculate_room_name(
 
 #[cfg(test)]
 mod tests {
+    use ruma::user_id;
+    use serde_json::json;
+
     use super::*;
+
+    #[test]
+    fn deserialize_old_dm_target() {
+        let mut info = serde_json::to_value(BaseRoomInfo::new()).unwrap();
+        let object = info.as_object_mut().unwrap();
+        object.remove("dm_targets");
+        object.insert("dm_target".to_owned(), json!("@alice:example.org"));
+
+        let info: BaseRoomInfo = serde_json::from_value(info).unwrap();
+        assert_eq!(info.dm_targets, vec![user_id!("@alice:example.org")].into_iter().collect());
+
+        let mut info = serde_json::to_value(BaseRoomInfo::new()).unwrap();
+        let object = info.as_object_mut().unwrap();
+        object.remove("dm_targets");
+        object.insert("dm_target".to_owned(), json!(null));
+
+        let info: BaseRoomInfo = serde_json::from_value(info).unwrap();
+        assert!(info.dm_targets.is_empty());
+    }
     #[test]
 
     fn test_calculate_room_name() {
@@ -204,6 +290,9 @@ mod tests {
         assert_eq!("a, b, c", actual);
 
         actual = calculate_room_name(5, 0, vec!["a", "b", "c"]);
+        assert_eq!("a, b, c, and 1 other", actual);
+
+        actual = calculate_room_name(4, 2, vec!["a", "b", "c"]);
         assert_eq!("a, b, c, and 2 others", actual);
 
         actual = calculate_room_name(0, 0, vec![]);
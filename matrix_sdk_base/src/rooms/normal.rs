@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use std::{
+    collections::{BTreeMap, BTreeSet},
     convert::TryFrom,
     sync::{Arc, RwLock as SyncRwLock},
 };
@@ -28,23 +29,33 @@ use ruma::{
         room::{
             create::CreateEventContent, encryption::EncryptionEventContent,
             guest_access::GuestAccess, history_visibility::HistoryVisibility, join_rules::JoinRule,
-            tombstone::TombstoneEventContent,
+            member::MembershipState, tombstone::TombstoneEventContent,
         },
         tag::Tags,
         AnyRoomAccountDataEvent, AnyStateEventContent, AnySyncStateEvent, EventType,
     },
     receipt::ReceiptType,
-    EventId, MxcUri, RoomAliasId, RoomId, UserId,
+    serde::Raw,
+    EventId, MxcUri, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 use tracing::info;
 
-use super::{BaseRoomInfo, RoomMember};
+use super::{
+    restricted_allow_rules, state_key_of, AllowRule, BaseRoomInfo, RoomMember, RoomPowerLevels,
+    TagName,
+};
 use crate::{
-    deserialized_responses::UnreadNotificationsCount,
+    deserialized_responses::{SyncRoomEvent, UnreadNotificationsCount},
+    image_pack::{ImagePack, ImagePackStateEvent, ROOM_IMAGE_PACK_EVENT_TYPE},
+    redaction,
     store::{Result as StoreResult, StateStore},
 };
 
+/// All the current state of a room, grouped by event type and state key.
+pub type StateSnapshot = BTreeMap<String, BTreeMap<String, Raw<AnySyncStateEvent>>>;
+
 /// The underlying room data structure collecting state for joined, left and
 /// invited rooms.
 #[derive(Debug, Clone)]
@@ -66,6 +77,10 @@ pub struct RoomSummary {
     joined_member_count: u64,
     /// The number of members that are considered to be invited to the room.
     invited_member_count: u64,
+    /// Did the server ever send us the member counts, in which case it keeps
+    /// sending them when they change.
+    #[serde(default)]
+    server_member_counts: bool,
 }
 
 /// Enum keeping track in which state the room is, e.g. if our own user is
@@ -97,6 +112,10 @@ impl Room {
             members_synced: false,
             last_prev_batch: None,
             base_info: BaseRoomInfo::new(),
+            display_name: None,
+            direct_avatar_url: None,
+            inviter: None,
+            local_unread_notifications: Vec::new(),
         };
 
         Self::restore(own_user_id, store, room_info)
@@ -131,8 +150,11 @@ impl Room {
     }
 
     /// Get the unread notification counts.
+    ///
+    /// For encrypted rooms the counts are calculated locally, see
+    /// [`RoomInfo::unread_notification_counts()`].
     pub fn unread_notification_counts(&self) -> UnreadNotificationsCount {
-        self.inner.read().unwrap().notification_counts
+        self.inner.read().unwrap().unread_notification_counts()
     }
 
     /// Check if the room has it's members fully synced.
@@ -145,6 +167,37 @@ impl Room {
         self.inner.read().unwrap().members_synced
     }
 
+    /// Get the heroes of the room, the members the server suggests to use to
+    /// calculate the display name of a room without a name or an alias.
+    ///
+    /// The heroes are only sent by the server if lazy member loading is
+    /// enabled for the sync, the list is empty otherwise.
+    pub fn heroes(&self) -> Vec<String> {
+        self.inner.read().unwrap().summary.heroes.clone()
+    }
+
+    /// Get the number of members that are joined to the room.
+    ///
+    /// The count is correct even if the members of the room weren't loaded
+    /// yet, it is taken from the room summary the server sends if lazy member
+    /// loading is enabled, and kept up to date with the membership changes the
+    /// client sees otherwise.
+    pub fn joined_members_count(&self) -> u64 {
+        self.inner.read().unwrap().summary.joined_member_count
+    }
+
+    /// Get the number of members that are invited to the room.
+    ///
+    /// See [`Room::joined_members_count()`] for how the count is kept correct.
+    pub fn invited_members_count(&self) -> u64 {
+        self.inner.read().unwrap().summary.invited_member_count
+    }
+
+    /// Get the number of members that are joined or invited to the room.
+    pub fn active_members_count(&self) -> u64 {
+        self.inner.read().unwrap().active_members_count()
+    }
+
     /// Get the `prev_batch` token that was received from the last sync. May be
     /// `None` if the last sync contained the full room history.
     pub fn last_prev_batch(&self) -> Option<String> {
@@ -153,7 +206,7 @@ impl Room {
 
     /// Get the avatar url of this room.
     pub fn avatar_url(&self) -> Option<MxcUri> {
-        self.inner.read().unwrap().base_info.avatar_url.clone()
+        self.inner.read().unwrap().avatar_url()
     }
 
     /// Get the canonical alias of this room.
@@ -170,9 +223,15 @@ impl Room {
         self.inner.read().unwrap().base_info.create.clone()
     }
 
+    /// Get the version of this room, it determines e.g. which parts of an
+    /// event survive a redaction.
+    pub fn room_version(&self) -> RoomVersionId {
+        self.inner.read().unwrap().room_version()
+    }
+
     /// Is this room considered a direct message.
     pub fn is_direct(&self) -> bool {
-        self.inner.read().unwrap().base_info.dm_target.is_some()
+        !self.inner.read().unwrap().base_info.dm_targets.is_empty()
     }
 
     /// If this room is a direct message, get the member that we're sharing the
@@ -182,7 +241,21 @@ impl Room {
     /// the target might not even be in the room anymore. This setting should
     /// only be considered as guidance.
     pub fn direct_target(&self) -> Option<UserId> {
-        self.inner.read().unwrap().base_info.dm_target.clone()
+        self.inner.read().unwrap().base_info.dm_targets.iter().next().cloned()
+    }
+
+    /// If this room is a direct message, get all the users that the room is
+    /// marked as a direct message with in the `m.direct` account data.
+    ///
+    /// For an invite the users are taken from the invite, if it was marked as
+    /// a direct message.
+    pub fn direct_targets(&self) -> BTreeSet<UserId> {
+        self.inner.read().unwrap().base_info.dm_targets.clone()
+    }
+
+    /// If this room is in the invited state, get the user that invited us.
+    pub fn inviter(&self) -> Option<UserId> {
+        self.inner.read().unwrap().inviter.clone()
     }
 
     /// Is the room encrypted.
@@ -206,11 +279,32 @@ impl Room {
         self.inner.read().unwrap().base_info.history_visibility.clone()
     }
 
+    /// Can users that join the room see the messages that were sent before
+    /// they joined.
+    ///
+    /// This is the case if the history visibility of the room is `shared` or
+    /// `world_readable`.
+    pub fn has_shared_history(&self) -> bool {
+        matches!(
+            self.history_visibility(),
+            HistoryVisibility::Shared | HistoryVisibility::WorldReadable
+        )
+    }
+
     /// Is the room considered to be public.
     pub fn is_public(&self) -> bool {
         matches!(self.join_rule(), JoinRule::Public)
     }
 
+    /// Is the room restricted, can members of some other rooms join the room
+    /// without being invited.
+    ///
+    /// Use [`join_allow_rules()`](#method.join_allow_rules) to find out which
+    /// rooms these are.
+    pub fn is_restricted(&self) -> bool {
+        self.join_rule().as_ref() == "restricted"
+    }
+
     /// Get the join rule policy of this room.
     pub fn join_rule(&self) -> JoinRule {
         self.inner.read().unwrap().base_info.join_rule.clone()
@@ -254,6 +348,58 @@ impl Room {
         self.calculate_name().await
     }
 
+    /// Get the display name of the room as it was calculated the last time the
+    /// state or the members of the room changed.
+    ///
+    /// Unlike [`display_name()`](#method.display_name) this doesn't need to
+    /// access the store, it will return `None` if the display name wasn't
+    /// calculated yet.
+    pub fn cached_display_name(&self) -> Option<String> {
+        self.inner.read().unwrap().display_name.clone()
+    }
+
+    /// Recalculate the avatar a direct message room falls back to if it
+    /// doesn't have an avatar of its own, and cache it.
+    ///
+    /// The fallback is the avatar of the member the room is shared with.
+    /// Rooms that are shared with more than one user don't have a fallback.
+    ///
+    /// Returns true if the fallback avatar changed.
+    pub(crate) async fn update_direct_avatar_url(&self) -> StoreResult<bool> {
+        let targets = self.direct_targets();
+
+        let avatar_url = if targets.len() == 1 {
+            let target = targets.iter().next().expect("The direct targets contain one user");
+            self.get_member(target).await?.and_then(|m| m.avatar_url().cloned())
+        } else {
+            None
+        };
+
+        let mut inner = self.inner.write().unwrap();
+
+        if inner.direct_avatar_url != avatar_url {
+            inner.direct_avatar_url = avatar_url;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Recalculate the display name of the room and cache it.
+    ///
+    /// Returns true if the display name changed.
+    pub(crate) async fn update_display_name(&self) -> StoreResult<bool> {
+        let name = self.calculate_name().await?;
+        let mut inner = self.inner.write().unwrap();
+
+        if inner.display_name.as_ref() != Some(&name) {
+            inner.display_name = Some(name);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     /// Get the list of users ids that are considered to be joined members of
     /// this room.
     pub async fn joined_user_ids(&self) -> StoreResult<Vec<UserId>> {
@@ -312,6 +458,48 @@ impl Room {
         Ok(members)
     }
 
+    /// Get the list of `RoomMember`s that are asking to join this room.
+    ///
+    /// A moderator can accept the request by inviting the member or deny it by
+    /// kicking the member.
+    pub async fn knocking_members(&self) -> StoreResult<Vec<RoomMember>> {
+        Ok(self.members().await?.into_iter().filter(|m| m.is_knocking()).collect())
+    }
+
+    /// Get the rules that allow users to join this room without an invite.
+    ///
+    /// Returns `None` if the join rule of the room isn't `restricted`.
+    pub async fn join_allow_rules(&self) -> StoreResult<Option<Vec<AllowRule>>> {
+        Ok(self
+            .store
+            .get_state_event(self.room_id(), EventType::RoomJoinRules, "")
+            .await?
+            .and_then(|e| restricted_allow_rules(e.json())))
+    }
+
+    /// Get the servers that can authorise a join to this room if it has the
+    /// `restricted` join rule.
+    ///
+    /// Only servers that have a joined member which is allowed to invite users
+    /// can authorise a join, the servers with the most of those members come
+    /// first. The list is meant to be used as the `server_name` list of a join
+    /// request.
+    pub async fn authorising_servers(&self) -> StoreResult<Vec<Box<ServerName>>> {
+        let power_levels = self.power_levels().await?;
+        let mut servers: BTreeMap<Box<ServerName>, usize> = BTreeMap::new();
+
+        for user_id in self.joined_user_ids().await? {
+            if power_levels.can_user_invite(&user_id) {
+                *servers.entry(user_id.server_name().to_owned()).or_default() += 1;
+            }
+        }
+
+        let mut servers: Vec<_> = servers.into_iter().collect();
+        servers.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+        Ok(servers.into_iter().map(|(server, _)| server).collect())
+    }
+
     async fn calculate_name(&self) -> StoreResult<String> {
         let summary = {
             let inner = self.inner.read().unwrap();
@@ -325,8 +513,6 @@ impl Room {
             }
             inner.summary.clone()
         };
-        // TODO what should we do here? We have correct counts only if lazy
-        // loading is used.
         let joined = summary.joined_member_count;
         let invited = summary.invited_member_count;
         let heroes_count = summary.heroes.len() as u64;
@@ -363,6 +549,79 @@ impl Room {
         Ok(inner.base_info.calculate_room_name(joined, invited, members))
     }
 
+    /// Get the power levels of this room.
+    ///
+    /// If the room doesn't have a `m.room.power_levels` event the defaults of
+    /// the spec are used, the creator of the room has full control over it.
+    pub async fn power_levels(&self) -> StoreResult<RoomPowerLevels> {
+        let event = self
+            .store
+            .get_state_event(self.room_id(), EventType::RoomPowerLevels, "")
+            .await?
+            .and_then(|e| e.deserialize().ok());
+
+        Ok(if let Some(AnySyncStateEvent::RoomPowerLevels(e)) = event {
+            RoomPowerLevels::new(e.content)
+        } else {
+            let creator = self.create_content().map(|c| c.creator);
+            RoomPowerLevels::without_event(creator.as_ref())
+        })
+    }
+
+    /// Can the given user send a message event of the given type in this room.
+    pub async fn can_user_send_message(
+        &self,
+        user_id: &UserId,
+        event_type: &EventType,
+    ) -> StoreResult<bool> {
+        Ok(self.power_levels().await?.can_user_send_message(user_id, event_type))
+    }
+
+    /// Can the given user send a state event of the given type in this room.
+    pub async fn can_user_send_state(
+        &self,
+        user_id: &UserId,
+        event_type: &EventType,
+    ) -> StoreResult<bool> {
+        Ok(self.power_levels().await?.can_user_send_state(user_id, event_type))
+    }
+
+    /// Get an event of the stored timeline of this room.
+    ///
+    /// Only events that were received in a sync while the timeline wasn't
+    /// interrupted by a gap, or that were fetched using back-pagination, can
+    /// be found.
+    pub async fn timeline_event(&self, event_id: &EventId) -> StoreResult<Option<SyncRoomEvent>> {
+        Ok(self.store.get_paginated_timeline(self.room_id()).await?.and_then(|t| {
+            t.events.into_iter().find(|e| redaction::event_id(&e.event).as_ref() == Some(event_id))
+        }))
+    }
+
+    /// Can the given user redact their own events in this room.
+    pub async fn can_user_redact_own(&self, user_id: &UserId) -> StoreResult<bool> {
+        Ok(self.power_levels().await?.can_user_redact_own(user_id))
+    }
+
+    /// Can the given user redact events of other users in this room.
+    pub async fn can_user_redact_other(&self, user_id: &UserId) -> StoreResult<bool> {
+        Ok(self.power_levels().await?.can_user_redact_other(user_id))
+    }
+
+    /// Can the given user invite other users to this room.
+    pub async fn can_user_invite(&self, user_id: &UserId) -> StoreResult<bool> {
+        Ok(self.power_levels().await?.can_user_invite(user_id))
+    }
+
+    /// Can the given user kick other users from this room.
+    pub async fn can_user_kick(&self, user_id: &UserId) -> StoreResult<bool> {
+        Ok(self.power_levels().await?.can_user_kick(user_id))
+    }
+
+    /// Can the given user ban other users from this room.
+    pub async fn can_user_ban(&self, user_id: &UserId) -> StoreResult<bool> {
+        Ok(self.power_levels().await?.can_user_ban(user_id))
+    }
+
     pub(crate) fn clone_info(&self) -> RoomInfo {
         (*self.inner.read().unwrap()).clone()
     }
@@ -372,6 +631,38 @@ impl Room {
         *inner = summary;
     }
 
+    /// Get all the current state of this room, grouped by event type and
+    /// state key.
+    ///
+    /// This includes state events of types the SDK doesn't know about, which
+    /// can be deserialized into custom event types.
+    pub async fn state_snapshot(&self) -> StoreResult<StateSnapshot> {
+        let mut snapshot = StateSnapshot::new();
+
+        for event in self.store.get_room_state(self.room_id()).await? {
+            if let Some((event_type, state_key)) = state_key_of(&event) {
+                snapshot.entry(event_type).or_default().insert(state_key, event);
+            }
+        }
+
+        Ok(snapshot)
+    }
+
+    /// Strip the given event of everything the redaction rules of the version
+    /// of this room don't preserve.
+    ///
+    /// Events that are received in a sync are redacted automatically, this is
+    /// useful for events that were fetched in other ways.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event that should be redacted, as JSON.
+    ///
+    /// * `redaction` - The `m.room.redaction` event that redacts the event.
+    pub fn redact_event(&self, event: &mut JsonValue, redaction: &JsonValue) {
+        redaction::redact_json(event, redaction, &self.room_version())
+    }
+
     /// Get the `RoomMember` with the given `user_id`.
     ///
     /// Returns `None` if the member was never part of this room, otherwise
@@ -447,6 +738,53 @@ impl Room {
         }
     }
 
+    /// Get the names of the tags of this room.
+    pub async fn tag_names(&self) -> StoreResult<Vec<TagName>> {
+        Ok(self
+            .tags()
+            .await?
+            .map(|tags| tags.keys().map(|t| TagName::from(t.as_str())).collect())
+            .unwrap_or_default())
+    }
+
+    /// Get the order of the given tag of this room.
+    ///
+    /// Returns `None` if the room doesn't have the tag or the tag doesn't
+    /// have an order.
+    pub async fn tag_order(&self, tag: &TagName) -> StoreResult<Option<f64>> {
+        Ok(self.tags().await?.and_then(|tags| tags.get(tag.as_str()).and_then(|t| t.order)))
+    }
+
+    /// Has the room been marked as a favourite.
+    pub async fn is_favourite(&self) -> StoreResult<bool> {
+        Ok(self.tag_names().await?.contains(&TagName::Favourite))
+    }
+
+    /// Has the room been marked as low priority.
+    pub async fn is_low_priority(&self) -> StoreResult<bool> {
+        Ok(self.tag_names().await?.contains(&TagName::LowPriority))
+    }
+
+    /// Get the tags the user created for this room.
+    pub async fn user_tags(&self) -> StoreResult<Vec<TagName>> {
+        Ok(self.tag_names().await?.into_iter().filter(TagName::is_user_tag).collect())
+    }
+
+    /// Get the image packs of this room, keyed by the state key of their
+    /// `im.ponies.room_emotes` state event.
+    ///
+    /// Packs whose state event couldn't be deserialized are skipped.
+    pub async fn image_packs(&self) -> StoreResult<BTreeMap<String, ImagePack>> {
+        Ok(self
+            .store
+            .get_state_events(self.room_id(), EventType::Custom(ROOM_IMAGE_PACK_EVENT_TYPE.into()))
+            .await?
+            .into_iter()
+            .filter_map(|e| e.deserialize_as::<ImagePackStateEvent>().ok())
+            .map(|e| (e.state_key, e.content))
+            .collect())
+    }
+
     /// Get the read receipt as a `EventId` and `Receipt` tuple for the given
     /// `user_id` in this room.
     pub async fn user_read_receipt(
@@ -486,6 +824,29 @@ pub struct RoomInfo {
     /// Base room info which holds some basic event contents important for the
     /// room state.
     pub base_info: BaseRoomInfo,
+    /// The display name of the room, calculated the last time the state or
+    /// the members of the room changed.
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// The avatar of the member a direct message room is shared with,
+    /// calculated the last time the state or the members of the room changed.
+    #[serde(default)]
+    pub(crate) direct_avatar_url: Option<MxcUri>,
+    /// The user that invited us to the room, if the room is in the invited
+    /// state.
+    #[serde(default)]
+    pub(crate) inviter: Option<UserId>,
+    /// The notifying events of an encrypted room that our own user didn't read
+    /// yet, ordered from the oldest to the newest event.
+    #[serde(default)]
+    pub(crate) local_unread_notifications: Vec<UnreadNotification>,
+}
+
+/// An event that triggered a notification but wasn't read yet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct UnreadNotification {
+    pub event_id: EventId,
+    pub highlight: bool,
 }
 
 impl RoomInfo {
@@ -522,6 +883,79 @@ impl RoomInfo {
         self.base_info.encryption.is_some()
     }
 
+    /// Get the avatar of the room.
+    ///
+    /// This is the avatar that was set in the `m.room.avatar` state event, a
+    /// direct message room without an avatar uses the avatar of the member it
+    /// is shared with.
+    pub fn avatar_url(&self) -> Option<MxcUri> {
+        self.base_info.avatar_url.clone().or_else(|| self.direct_avatar_url.clone())
+    }
+
+    /// The version of the room, rooms without a `m.room.create` event are
+    /// treated as version 1 rooms.
+    pub(crate) fn room_version(&self) -> RoomVersionId {
+        self.base_info
+            .create
+            .as_ref()
+            .map(|c| c.room_version.clone())
+            .unwrap_or(RoomVersionId::Version1)
+    }
+
+    /// Get the unread notification counts of the room.
+    ///
+    /// The server can't evaluate push rules for encrypted events, so for
+    /// encrypted rooms the counts are calculated by the client from the
+    /// decrypted events and our own read receipts. For other rooms the counts
+    /// the server sent are returned.
+    pub fn unread_notification_counts(&self) -> UnreadNotificationsCount {
+        if self.is_encrypted() {
+            let unread = &self.local_unread_notifications;
+
+            UnreadNotificationsCount {
+                highlight_count: unread.iter().filter(|n| n.highlight).count() as u64,
+                notification_count: unread.len() as u64,
+            }
+        } else {
+            self.notification_counts
+        }
+    }
+
+    pub(crate) fn add_unread_notification(&mut self, event_id: EventId, highlight: bool) {
+        self.local_unread_notifications.push(UnreadNotification { event_id, highlight });
+    }
+
+    pub(crate) fn clear_unread_notifications(&mut self) {
+        self.local_unread_notifications.clear();
+    }
+
+    /// Mark the unread notifications up to, and including, the given event as
+    /// read.
+    ///
+    /// # Arguments
+    ///
+    /// * `event_id` - The event our own user sent a read receipt for.
+    ///
+    /// * `position` - Function returning the position of an event in the
+    /// timeline, ordered from the newest to the oldest event.
+    pub(crate) fn mark_notifications_read(
+        &mut self,
+        event_id: &EventId,
+        position: impl Fn(&EventId) -> Option<usize>,
+    ) {
+        let unread = &mut self.local_unread_notifications;
+
+        if let Some(index) = unread.iter().position(|n| &n.event_id == event_id) {
+            unread.drain(..=index);
+        } else if let Some(read) = position(event_id) {
+            unread.retain(|n| position(&n.event_id).map_or(false, |p| p < read));
+        } else {
+            // We don't know where the event is, it's most likely newer than
+            // the events we know about.
+            unread.clear();
+        }
+    }
+
     pub(crate) fn handle_state_event(&mut self, event: &AnyStateEventContent) -> bool {
         self.base_info.handle_state_event(event)
     }
@@ -544,11 +978,13 @@ impl RoomInfo {
 
             if let Some(joined) = summary.joined_member_count {
                 self.summary.joined_member_count = joined.into();
+                self.summary.server_member_counts = true;
                 changed = true;
             }
 
             if let Some(invited) = summary.invited_member_count {
                 self.summary.invited_member_count = invited.into();
+                self.summary.server_member_counts = true;
                 changed = true;
             }
         }
@@ -556,6 +992,50 @@ impl RoomInfo {
         changed
     }
 
+    /// Does the server send us the member counts of this room.
+    ///
+    /// Servers that do, e.g. because lazy member loading is enabled, omit the
+    /// counts if they didn't change.
+    pub(crate) fn has_server_member_counts(&self) -> bool {
+        self.summary.server_member_counts
+    }
+
+    /// Replace the member counts with the ones of a complete member list.
+    pub(crate) fn set_member_counts(&mut self, joined: u64, invited: u64) {
+        self.summary.joined_member_count = joined;
+        self.summary.invited_member_count = invited;
+    }
+
+    /// Update the member counts for a member whose membership changed from
+    /// `previous` to `new`.
+    pub(crate) fn update_member_counts(
+        &mut self,
+        previous: Option<&MembershipState>,
+        new: &MembershipState,
+    ) {
+        if previous == Some(new) {
+            return;
+        }
+
+        match previous {
+            Some(MembershipState::Join) => {
+                self.summary.joined_member_count =
+                    self.summary.joined_member_count.saturating_sub(1)
+            }
+            Some(MembershipState::Invite) => {
+                self.summary.invited_member_count =
+                    self.summary.invited_member_count.saturating_sub(1)
+            }
+            _ => {}
+        }
+
+        match new {
+            MembershipState::Join => self.summary.joined_member_count += 1,
+            MembershipState::Invite => self.summary.invited_member_count += 1,
+            _ => {}
+        }
+    }
+
     /// The number of active members (invited + joined) in the room.
     ///
     /// The return value is saturated at `u64::MAX`.
@@ -563,3 +1043,103 @@ impl RoomInfo {
         self.summary.joined_member_count.saturating_add(self.summary.invited_member_count)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use ruma::{
+        api::client::r0::sync::sync_events::RoomSummary as RumaSummary,
+        event_id,
+        events::room::{encryption::EncryptionEventContent, member::MembershipState},
+        room_id, user_id, EventEncryptionAlgorithm,
+    };
+
+    use super::{Room, RoomType};
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn local_unread_notifications() {
+        let room = Room::new(
+            &user_id!("@example:localhost"),
+            Arc::new(MemoryStore::new()),
+            &room_id!("!test:localhost"),
+            RoomType::Joined,
+        );
+        let mut info = room.clone_info();
+
+        info.add_unread_notification(event_id!("$first:localhost"), false);
+        info.add_unread_notification(event_id!("$second:localhost"), true);
+        info.add_unread_notification(event_id!("$third:localhost"), false);
+
+        // Unencrypted rooms use the counts of the server.
+        assert_eq!(info.unread_notification_counts().notification_count, 0);
+
+        info.base_info.encryption =
+            Some(EncryptionEventContent::new(EventEncryptionAlgorithm::MegolmV1AesSha2));
+
+        let counts = info.unread_notification_counts();
+        assert_eq!(counts.notification_count, 3);
+        assert_eq!(counts.highlight_count, 1);
+
+        info.mark_notifications_read(&event_id!("$first:localhost"), |_| None);
+        assert_eq!(info.unread_notification_counts().notification_count, 2);
+
+        // A receipt for an event that didn't notify, placed between the
+        // remaining notifications in the timeline.
+        let timeline = [
+            event_id!("$third:localhost"),
+            event_id!("$quiet:localhost"),
+            event_id!("$second:localhost"),
+        ];
+        info.mark_notifications_read(&event_id!("$quiet:localhost"), |id| {
+            timeline.iter().position(|e| e == id)
+        });
+
+        let counts = info.unread_notification_counts();
+        assert_eq!(counts.notification_count, 1);
+        assert_eq!(counts.highlight_count, 0);
+
+        info.mark_notifications_read(&event_id!("$unknown:localhost"), |_| None);
+        assert_eq!(info.unread_notification_counts().notification_count, 0);
+    }
+
+    #[test]
+    fn member_counts() {
+        let room = Room::new(
+            &user_id!("@example:localhost"),
+            Arc::new(MemoryStore::new()),
+            &room_id!("!test:localhost"),
+            RoomType::Joined,
+        );
+        let mut info = room.clone_info();
+
+        info.update_member_counts(None, &MembershipState::Join);
+        info.update_member_counts(None, &MembershipState::Invite);
+        info.update_member_counts(None, &MembershipState::Invite);
+        assert_eq!(info.summary.joined_member_count, 1);
+        assert_eq!(info.summary.invited_member_count, 2);
+
+        info.update_member_counts(Some(&MembershipState::Invite), &MembershipState::Join);
+        info.update_member_counts(Some(&MembershipState::Join), &MembershipState::Join);
+        assert_eq!(info.summary.joined_member_count, 2);
+        assert_eq!(info.summary.invited_member_count, 1);
+
+        info.update_member_counts(Some(&MembershipState::Join), &MembershipState::Leave);
+        info.update_member_counts(Some(&MembershipState::Invite), &MembershipState::Ban);
+        assert_eq!(info.active_members_count(), 1);
+
+        // The counts of the server replace the calculated ones.
+        let mut summary = RumaSummary::new();
+        summary.heroes = vec!["@alice:localhost".to_owned()];
+        summary.joined_member_count = Some(5u32.into());
+        summary.invited_member_count = Some(1u32.into());
+        info.update_summary(&summary);
+
+        room.update_summary(info);
+        assert_eq!(room.heroes(), vec!["@alice:localhost".to_owned()]);
+        assert_eq!(room.joined_members_count(), 5);
+        assert_eq!(room.invited_members_count(), 1);
+        assert_eq!(room.active_members_count(), 6);
+    }
+}
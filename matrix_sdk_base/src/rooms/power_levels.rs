@@ -0,0 +1,395 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ruma::{
+    events::{room::power_levels::PowerLevelsEventContent, EventType},
+    int, Int, UserId,
+};
+use thiserror::Error;
+
+/// Error type for changes to the power levels of a room that our own user
+/// isn't allowed to make.
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum PowerLevelsError {
+    /// The new power level is higher than the power level of our own user.
+    #[error("can't set a power level of {level}, our own power level is {own_level}")]
+    AboveOwnLevel {
+        /// The power level that should have been set.
+        level: Int,
+        /// The power level of our own user.
+        own_level: Int,
+    },
+
+    /// The power level that should be changed is already higher than the
+    /// power level of our own user.
+    #[error("can't change a power level of {level}, our own power level is {own_level}")]
+    CurrentLevelAboveOwnLevel {
+        /// The current power level.
+        level: Int,
+        /// The power level of our own user.
+        own_level: Int,
+    },
+
+    /// The user whose power level should be changed has the same or a higher
+    /// power level as our own user.
+    #[error("can't change the power level of {0}, their power level isn't lower than ours")]
+    UserLevelNotBelowOwnLevel(UserId),
+
+    /// Our own user isn't allowed to change the power levels of the room at
+    /// all.
+    #[error("our own user isn't allowed to change the power levels of the room")]
+    NotAllowed,
+}
+
+/// Is the given event type one of the state events the spec defines.
+fn is_state_event(event_type: &EventType) -> bool {
+    matches!(
+        event_type,
+        EventType::RoomAliases
+            | EventType::RoomAvatar
+            | EventType::RoomCanonicalAlias
+            | EventType::RoomCreate
+            | EventType::RoomEncryption
+            | EventType::RoomGuestAccess
+            | EventType::RoomHistoryVisibility
+            | EventType::RoomJoinRules
+            | EventType::RoomMember
+            | EventType::RoomName
+            | EventType::RoomPinnedEvents
+            | EventType::RoomPowerLevels
+            | EventType::RoomServerAcl
+            | EventType::RoomThirdPartyInvite
+            | EventType::RoomTombstone
+            | EventType::RoomTopic
+    )
+}
+
+/// The power levels of a room, with the defaults the spec mandates applied.
+#[derive(Clone, Debug)]
+pub struct RoomPowerLevels {
+    content: PowerLevelsEventContent,
+}
+
+impl RoomPowerLevels {
+    /// Create the power levels from the content of the `m.room.power_levels`
+    /// event of a room.
+    pub fn new(content: PowerLevelsEventContent) -> Self {
+        Self { content }
+    }
+
+    /// Create the power levels of a room that doesn't have a
+    /// `m.room.power_levels` event.
+    ///
+    /// The creator of the room has a power level of 100, all other users and
+    /// all actions, including sending state events, have a power level of 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `creator` - The user that created the room, if known.
+    pub fn without_event(creator: Option<&UserId>) -> Self {
+        let mut content = PowerLevelsEventContent::default();
+        content.state_default = int!(0);
+
+        if let Some(creator) = creator {
+            content.users.insert(creator.clone(), int!(100));
+        }
+
+        Self { content }
+    }
+
+    /// Get the underlying `m.room.power_levels` event content.
+    pub fn content(&self) -> &PowerLevelsEventContent {
+        &self.content
+    }
+
+    /// Get the power level of the given user.
+    pub fn user_power_level(&self, user_id: &UserId) -> Int {
+        self.content.users.get(user_id).copied().unwrap_or(self.content.users_default)
+    }
+
+    /// Get the power level that is needed to send a message event of the given
+    /// type.
+    pub fn message_power_level(&self, event_type: &EventType) -> Int {
+        self.content.events.get(event_type).copied().unwrap_or(self.content.events_default)
+    }
+
+    /// Get the power level that is needed to send a state event of the given
+    /// type.
+    pub fn state_power_level(&self, event_type: &EventType) -> Int {
+        self.content.events.get(event_type).copied().unwrap_or(self.content.state_default)
+    }
+
+    /// Can the given user send a message event of the given type.
+    pub fn can_user_send_message(&self, user_id: &UserId, event_type: &EventType) -> bool {
+        self.user_power_level(user_id) >= self.message_power_level(event_type)
+    }
+
+    /// Can the given user send a state event of the given type.
+    pub fn can_user_send_state(&self, user_id: &UserId, event_type: &EventType) -> bool {
+        self.user_power_level(user_id) >= self.state_power_level(event_type)
+    }
+
+    /// Can the given user redact their own events.
+    ///
+    /// Redacting your own events only requires permission to send the
+    /// redaction event.
+    pub fn can_user_redact_own(&self, user_id: &UserId) -> bool {
+        self.can_user_send_message(user_id, &EventType::RoomRedaction)
+    }
+
+    /// Can the given user redact events of other users.
+    pub fn can_user_redact_other(&self, user_id: &UserId) -> bool {
+        self.can_user_redact_own(user_id) && self.user_power_level(user_id) >= self.content.redact
+    }
+
+    /// Can the given user invite other users to the room.
+    pub fn can_user_invite(&self, user_id: &UserId) -> bool {
+        self.user_power_level(user_id) >= self.content.invite
+    }
+
+    /// Can the given user kick other users from the room.
+    pub fn can_user_kick(&self, user_id: &UserId) -> bool {
+        self.user_power_level(user_id) >= self.content.kick
+    }
+
+    /// Can the given user ban other users from the room.
+    pub fn can_user_ban(&self, user_id: &UserId) -> bool {
+        self.user_power_level(user_id) >= self.content.ban
+    }
+
+    /// Start modifying the power levels as the given user.
+    ///
+    /// The builder refuses any change that the given user isn't allowed to
+    /// make.
+    ///
+    /// # Arguments
+    ///
+    /// * `own_user_id` - The user that will send the modified power levels.
+    pub fn builder(&self, own_user_id: &UserId) -> Result<PowerLevelsBuilder, PowerLevelsError> {
+        if !self.can_user_send_state(own_user_id, &EventType::RoomPowerLevels) {
+            return Err(PowerLevelsError::NotAllowed);
+        }
+
+        Ok(PowerLevelsBuilder {
+            own_user_id: own_user_id.clone(),
+            own_level: self.user_power_level(own_user_id),
+            content: self.content.clone(),
+        })
+    }
+}
+
+/// A builder to modify the power levels of a room.
+///
+/// Every change is checked against the power level of our own user, changes
+/// that the server would reject are refused right away.
+#[derive(Clone, Debug)]
+pub struct PowerLevelsBuilder {
+    own_user_id: UserId,
+    own_level: Int,
+    content: PowerLevelsEventContent,
+}
+
+impl PowerLevelsBuilder {
+    fn check_level(&self, current: Int, new: Int) -> Result<(), PowerLevelsError> {
+        if current > self.own_level {
+            Err(PowerLevelsError::CurrentLevelAboveOwnLevel {
+                level: current,
+                own_level: self.own_level,
+            })
+        } else if new > self.own_level {
+            Err(PowerLevelsError::AboveOwnLevel { level: new, own_level: self.own_level })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn set_level(
+        mut self,
+        level: Int,
+        field: impl Fn(&mut PowerLevelsEventContent) -> &mut Int,
+    ) -> Result<Self, PowerLevelsError> {
+        let current = *field(&mut self.content);
+        self.check_level(current, level)?;
+        *field(&mut self.content) = level;
+
+        Ok(self)
+    }
+
+    /// Set the power level of the given user.
+    ///
+    /// Our own user can only change the power level of users with a lower
+    /// power level, or lower its own power level.
+    pub fn user(mut self, user_id: &UserId, level: Int) -> Result<Self, PowerLevelsError> {
+        let current =
+            self.content.users.get(user_id).copied().unwrap_or(self.content.users_default);
+
+        if user_id != &self.own_user_id && current >= self.own_level {
+            return Err(PowerLevelsError::UserLevelNotBelowOwnLevel(user_id.clone()));
+        }
+
+        self.check_level(current, level)?;
+        self.content.users.insert(user_id.clone(), level);
+
+        if user_id == &self.own_user_id {
+            self.own_level = level;
+        }
+
+        Ok(self)
+    }
+
+    /// Set the power level that is needed to send events of the given type.
+    ///
+    /// If the event type didn't have a power level of its own until now, the
+    /// current level is `state_default` for state events and
+    /// `events_default` for all other events, custom event types are treated
+    /// as message events.
+    pub fn event(mut self, event_type: EventType, level: Int) -> Result<Self, PowerLevelsError> {
+        let default = if is_state_event(&event_type) {
+            self.content.state_default
+        } else {
+            self.content.events_default
+        };
+        let current = self.content.events.get(&event_type).copied().unwrap_or(default);
+        self.check_level(current, level)?;
+        self.content.events.insert(event_type, level);
+
+        Ok(self)
+    }
+
+    /// Set the power level that is needed to ban users.
+    pub fn ban(self, level: Int) -> Result<Self, PowerLevelsError> {
+        self.set_level(level, |c| &mut c.ban)
+    }
+
+    /// Set the power level that is needed to kick users.
+    pub fn kick(self, level: Int) -> Result<Self, PowerLevelsError> {
+        self.set_level(level, |c| &mut c.kick)
+    }
+
+    /// Set the power level that is needed to invite users.
+    pub fn invite(self, level: Int) -> Result<Self, PowerLevelsError> {
+        self.set_level(level, |c| &mut c.invite)
+    }
+
+    /// Set the power level that is needed to redact events of other users.
+    pub fn redact(self, level: Int) -> Result<Self, PowerLevelsError> {
+        self.set_level(level, |c| &mut c.redact)
+    }
+
+    /// Set the default power level that is needed to send message events.
+    pub fn events_default(self, level: Int) -> Result<Self, PowerLevelsError> {
+        self.set_level(level, |c| &mut c.events_default)
+    }
+
+    /// Set the default power level that is needed to send state events.
+    pub fn state_default(self, level: Int) -> Result<Self, PowerLevelsError> {
+        self.set_level(level, |c| &mut c.state_default)
+    }
+
+    /// Set the default power level of users.
+    pub fn users_default(self, level: Int) -> Result<Self, PowerLevelsError> {
+        self.set_level(level, |c| &mut c.users_default)
+    }
+
+    /// Get the modified `m.room.power_levels` event content, ready to be sent
+    /// to the room.
+    pub fn build(self) -> PowerLevelsEventContent {
+        self.content
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ruma::{events::EventType, int, user_id};
+
+    use super::{PowerLevelsError, RoomPowerLevels};
+
+    #[test]
+    fn defaults_without_event() {
+        let creator = user_id!("@creator:localhost");
+        let other = user_id!("@other:localhost");
+        let levels = RoomPowerLevels::without_event(Some(&creator));
+
+        assert_eq!(levels.user_power_level(&creator), int!(100));
+        assert_eq!(levels.user_power_level(&other), int!(0));
+
+        assert!(levels.can_user_send_state(&other, &EventType::RoomTopic));
+        assert!(levels.can_user_send_message(&other, &EventType::RoomMessage));
+        assert!(levels.can_user_redact_own(&other));
+        assert!(!levels.can_user_redact_other(&other));
+        assert!(!levels.can_user_ban(&other));
+        assert!(levels.can_user_ban(&creator));
+    }
+
+    #[test]
+    fn builder_refuses_changes_above_own_level() {
+        let own = user_id!("@own:localhost");
+        let moderator = user_id!("@moderator:localhost");
+        let other = user_id!("@other:localhost");
+
+        let mut content = RoomPowerLevels::without_event(None).content().clone();
+        content.state_default = int!(50);
+        content.users.insert(own.clone(), int!(50));
+        content.users.insert(moderator.clone(), int!(50));
+        let levels = RoomPowerLevels::new(content);
+
+        assert_eq!(levels.builder(&other).unwrap_err(), PowerLevelsError::NotAllowed);
+
+        let builder = levels.builder(&own).unwrap();
+
+        assert_eq!(
+            builder.clone().user(&other, int!(100)).unwrap_err(),
+            PowerLevelsError::AboveOwnLevel { level: int!(100), own_level: int!(50) }
+        );
+        assert_eq!(
+            builder.clone().user(&moderator, int!(0)).unwrap_err(),
+            PowerLevelsError::UserLevelNotBelowOwnLevel(moderator.clone())
+        );
+
+        let content = builder
+            .user(&other, int!(50))
+            .unwrap()
+            .ban(int!(40))
+            .unwrap()
+            .user(&own, int!(10))
+            .unwrap()
+            .build();
+
+        assert_eq!(content.users[&other], int!(50));
+        assert_eq!(content.users[&own], int!(10));
+        assert_eq!(content.ban, int!(40));
+    }
+
+    #[test]
+    fn builder_event_levels_use_defaults() {
+        let own = user_id!("@own:localhost");
+
+        let mut content = RoomPowerLevels::without_event(None).content().clone();
+        content.state_default = int!(80);
+        content.events_default = int!(0);
+        content.users.insert(own.clone(), int!(50));
+        content.events.insert(EventType::RoomPowerLevels, int!(50));
+        let levels = RoomPowerLevels::new(content);
+
+        let builder = levels.builder(&own).unwrap();
+
+        assert_eq!(
+            builder.clone().event(EventType::RoomTopic, int!(10)).unwrap_err(),
+            PowerLevelsError::CurrentLevelAboveOwnLevel { level: int!(80), own_level: int!(50) }
+        );
+
+        let content = builder.event(EventType::RoomMessage, int!(10)).unwrap().build();
+        assert_eq!(content.events[&EventType::RoomMessage], int!(10));
+    }
+}
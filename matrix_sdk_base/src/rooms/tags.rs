@@ -0,0 +1,87 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+/// The name of a room tag.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TagName {
+    /// The `m.favourite` tag, the room is a favourite of the user.
+    Favourite,
+    /// The `m.lowpriority` tag, the room is of low priority to the user.
+    LowPriority,
+    /// The `m.server_notice` tag, the room is used for server notices.
+    ServerNotice,
+    /// A tag the user created, including the `u.` prefix.
+    User(String),
+    /// Any other tag.
+    Custom(String),
+}
+
+impl TagName {
+    /// Get the tag name as it is used in the `m.tag` event.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Favourite => "m.favourite",
+            Self::LowPriority => "m.lowpriority",
+            Self::ServerNotice => "m.server_notice",
+            Self::User(tag) | Self::Custom(tag) => tag,
+        }
+    }
+
+    /// Is this a tag the user created.
+    pub fn is_user_tag(&self) -> bool {
+        matches!(self, Self::User(_))
+    }
+}
+
+impl From<&str> for TagName {
+    fn from(tag: &str) -> Self {
+        match tag {
+            "m.favourite" => Self::Favourite,
+            "m.lowpriority" => Self::LowPriority,
+            "m.server_notice" => Self::ServerNotice,
+            t if t.starts_with("u.") => Self::User(t.to_owned()),
+            t => Self::Custom(t.to_owned()),
+        }
+    }
+}
+
+impl From<String> for TagName {
+    fn from(tag: String) -> Self {
+        tag.as_str().into()
+    }
+}
+
+impl fmt::Display for TagName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TagName;
+
+    #[test]
+    fn tag_name_roundtrip() {
+        for tag in &["m.favourite", "m.lowpriority", "m.server_notice", "u.work", "org.custom"] {
+            assert_eq!(TagName::from(*tag).as_str(), *tag);
+        }
+
+        assert_eq!(TagName::from("m.favourite"), TagName::Favourite);
+        assert!(TagName::from("u.work").is_user_tag());
+        assert_eq!(TagName::from("org.custom"), TagName::Custom("org.custom".to_owned()));
+    }
+}
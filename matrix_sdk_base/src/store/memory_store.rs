@@ -32,12 +32,16 @@ use ruma::{
     receipt::ReceiptType,
     serde::Raw,
 };
+use serde_json::value::to_raw_value;
 use tracing::info;
 
 use super::{Result, RoomInfo, StateChanges, StateStore};
 use crate::{
-    deserialized_responses::{MemberEvent, StrippedMemberEvent},
+    deserialized_responses::{
+        MemberEvent, PaginatedTimeline, PaginatedTimelineChanges, StrippedMemberEvent,
+    },
     media::{MediaRequest, UniqueKey},
+    redaction,
 };
 
 #[derive(Debug, Clone)]
@@ -66,6 +70,8 @@ pub struct MemoryStore {
     room_event_receipts:
         Arc<DashMap<RoomId, DashMap<String, DashMap<EventId, DashMap<UserId, Receipt>>>>>,
     media: Arc<Mutex<LruCache<String, Vec<u8>>>>,
+    paginated_timelines: Arc<DashMap<RoomId, PaginatedTimeline>>,
+    transaction_event_ids: Arc<DashMap<(RoomId, String), Option<EventId>>>,
 }
 
 impl MemoryStore {
@@ -90,6 +96,8 @@ impl MemoryStore {
             room_user_receipts: DashMap::new().into(),
             room_event_receipts: DashMap::new().into(),
             media: Arc::new(Mutex::new(LruCache::new(100))),
+            paginated_timelines: DashMap::new().into(),
+            transaction_event_ids: DashMap::new().into(),
         }
     }
 
@@ -271,6 +279,12 @@ impl MemoryStore {
             }
         }
 
+        for (room, transaction_ids) in &changes.remote_echoes {
+            for transaction_id in transaction_ids {
+                self.transaction_event_ids.remove(&(room.clone(), transaction_id.clone()));
+            }
+        }
+
         info!("Saved changes in {:?}", now.elapsed());
 
         Ok(())
@@ -293,6 +307,40 @@ impl MemoryStore {
         }))
     }
 
+    async fn get_state_events(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+    ) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        Ok(self
+            .room_state
+            .get(room_id)
+            .and_then(|e| {
+                e.get(event_type.as_ref()).map(|s| s.iter().map(|e| e.value().clone()).collect())
+            })
+            .unwrap_or_default())
+    }
+
+    async fn get_room_state(&self, room_id: &RoomId) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        let mut events: Vec<Raw<AnySyncStateEvent>> = self
+            .room_state
+            .get(room_id)
+            .map(|e| {
+                e.iter()
+                    .flat_map(|s| s.iter().map(|e| e.value().clone()).collect::<Vec<_>>())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if let Some(members) = self.members.get(room_id) {
+            for member in members.iter() {
+                events.push(Raw::from_json(to_raw_value(member.value())?));
+            }
+        }
+
+        Ok(events)
+    }
+
     async fn get_profile(
         &self,
         room_id: &RoomId,
@@ -424,6 +472,93 @@ impl MemoryStore {
 
         Ok(())
     }
+
+    async fn get_paginated_timeline(&self, room_id: &RoomId) -> Result<Option<PaginatedTimeline>> {
+        Ok(self.paginated_timelines.get(room_id).map(|t| t.clone()))
+    }
+
+    async fn save_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+        timeline: &PaginatedTimeline,
+    ) -> Result<()> {
+        self.paginated_timelines.insert(room_id.clone(), timeline.clone());
+
+        Ok(())
+    }
+
+    async fn update_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+        changes: &PaginatedTimelineChanges,
+    ) -> Result<()> {
+        let mut timeline = self.paginated_timelines.entry(room_id.clone()).or_default();
+
+        for event in &changes.updated {
+            if let Some(id) = redaction::event_id(&event.event) {
+                if let Some(existing) = timeline
+                    .events
+                    .iter_mut()
+                    .find(|e| redaction::event_id(&e.event).as_ref() == Some(&id))
+                {
+                    *existing = event.clone();
+                }
+            }
+        }
+
+        timeline.events.splice(0..0, changes.prepended.iter().cloned());
+        timeline.events.extend(changes.appended.iter().cloned());
+
+        if let Some(token) = &changes.token {
+            timeline.token = token.clone();
+        }
+
+        Ok(())
+    }
+
+    async fn remove_paginated_timeline(&self, room_id: &RoomId) -> Result<()> {
+        self.paginated_timelines.remove(room_id);
+
+        Ok(())
+    }
+
+    async fn save_transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+        event_id: Option<&EventId>,
+    ) -> Result<()> {
+        self.transaction_event_ids
+            .insert((room_id.clone(), transaction_id.to_owned()), event_id.cloned());
+
+        Ok(())
+    }
+
+    async fn get_transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+    ) -> Result<Option<EventId>> {
+        Ok(self
+            .transaction_event_ids
+            .get(&(room_id.clone(), transaction_id.to_owned()))
+            .and_then(|e| e.clone()))
+    }
+
+    async fn get_pending_transaction_ids(&self, room_id: &RoomId) -> Result<Vec<String>> {
+        Ok(self
+            .transaction_event_ids
+            .iter()
+            .filter(|e| &e.key().0 == room_id && e.value().is_none())
+            .map(|e| e.key().1.clone())
+            .collect())
+    }
+
+    async fn remove_transaction_id(&self, room_id: &RoomId, transaction_id: &str) -> Result<()> {
+        self.transaction_event_ids.remove(&(room_id.clone(), transaction_id.to_owned()));
+
+        Ok(())
+    }
 }
 
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -458,6 +593,18 @@ impl StateStore for MemoryStore {
         self.get_state_event(room_id, event_type, state_key).await
     }
 
+    async fn get_state_events(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+    ) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        self.get_state_events(room_id, event_type).await
+    }
+
+    async fn get_room_state(&self, room_id: &RoomId) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        self.get_room_state(room_id).await
+    }
+
     async fn get_profile(
         &self,
         room_id: &RoomId,
@@ -555,6 +702,55 @@ impl StateStore for MemoryStore {
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
         self.remove_media_content_for_uri(uri).await
     }
+
+    async fn get_paginated_timeline(&self, room_id: &RoomId) -> Result<Option<PaginatedTimeline>> {
+        self.get_paginated_timeline(room_id).await
+    }
+
+    async fn save_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+        timeline: &PaginatedTimeline,
+    ) -> Result<()> {
+        self.save_paginated_timeline(room_id, timeline).await
+    }
+
+    async fn update_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+        changes: &PaginatedTimelineChanges,
+    ) -> Result<()> {
+        self.update_paginated_timeline(room_id, changes).await
+    }
+
+    async fn remove_paginated_timeline(&self, room_id: &RoomId) -> Result<()> {
+        self.remove_paginated_timeline(room_id).await
+    }
+
+    async fn save_transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+        event_id: Option<&EventId>,
+    ) -> Result<()> {
+        self.save_transaction_event_id(room_id, transaction_id, event_id).await
+    }
+
+    async fn get_transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+    ) -> Result<Option<EventId>> {
+        self.get_transaction_event_id(room_id, transaction_id).await
+    }
+
+    async fn get_pending_transaction_ids(&self, room_id: &RoomId) -> Result<Vec<String>> {
+        self.get_pending_transaction_ids(room_id).await
+    }
+
+    async fn remove_transaction_id(&self, room_id: &RoomId, transaction_id: &str) -> Result<()> {
+        self.remove_transaction_id(room_id, transaction_id).await
+    }
 }
 
 #[cfg(test)]
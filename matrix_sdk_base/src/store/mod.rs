@@ -21,7 +21,7 @@ use std::{
 };
 
 use dashmap::DashMap;
-use matrix_sdk_common::{async_trait, locks::RwLock, AsyncTraitDeps};
+use matrix_sdk_common::{async_trait, instant::Instant, locks::RwLock, metrics, AsyncTraitDeps};
 use ruma::{
     api::client::r0::push::get_notifications::Notification,
     events::{
@@ -39,7 +39,9 @@ use ruma::{
 use sled::Db;
 
 use crate::{
-    deserialized_responses::{MemberEvent, StrippedMemberEvent},
+    deserialized_responses::{
+        MemberEvent, PaginatedTimeline, PaginatedTimelineChanges, StrippedMemberEvent,
+    },
     media::MediaRequest,
     rooms::{RoomInfo, RoomType},
     Room, Session,
@@ -51,7 +53,7 @@ mod memory_store;
 mod sled_store;
 
 #[cfg(not(feature = "sled_state_store"))]
-use self::memory_store::MemoryStore;
+pub(crate) use self::memory_store::MemoryStore;
 #[cfg(feature = "sled_state_store")]
 use self::sled_store::SledStore;
 
@@ -133,6 +135,27 @@ pub trait StateStore: AsyncTraitDeps {
         state_key: &str,
     ) -> Result<Option<Raw<AnySyncStateEvent>>>;
 
+    /// Get all the state events of the given type out of the state store.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the state events were received for.
+    ///
+    /// * `event_type` - The event type of the state events.
+    async fn get_state_events(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+    ) -> Result<Vec<Raw<AnySyncStateEvent>>>;
+
+    /// Get all the state events of the given room out of the state store,
+    /// including the member events.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the state events were received for.
+    async fn get_room_state(&self, room_id: &RoomId) -> Result<Vec<Raw<AnySyncStateEvent>>>;
+
     /// Get the current profile for the given user in the given room.
     ///
     /// # Arguments
@@ -280,6 +303,159 @@ pub trait StateStore: AsyncTraitDeps {
     ///
     /// * `uri` - The `MxcUri` of the media files.
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()>;
+
+    /// Get the events of a room timeline that were fetched using
+    /// back-pagination, together with the token to continue paginating.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the timeline belongs to.
+    async fn get_paginated_timeline(&self, room_id: &RoomId) -> Result<Option<PaginatedTimeline>>;
+
+    /// Store the events of a room timeline that were fetched using
+    /// back-pagination, replacing the previously stored ones.
+    ///
+    /// This rewrites the whole timeline,
+    /// [`StateStore::update_paginated_timeline`] should be used to add events
+    /// to an existing timeline.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the timeline belongs to.
+    ///
+    /// * `timeline` - The paginated timeline.
+    async fn save_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+        timeline: &PaginatedTimeline,
+    ) -> Result<()>;
+
+    /// Apply changes to the stored timeline of a room.
+    ///
+    /// Only the changed events are written, the rest of the stored timeline
+    /// stays untouched. If no timeline is stored for the room, the changes
+    /// start a new one.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the timeline belongs to.
+    ///
+    /// * `changes` - The changes of the timeline.
+    async fn update_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+        changes: &PaginatedTimelineChanges,
+    ) -> Result<()>;
+
+    /// Remove the paginated timeline of a room, e.g. because a gap in the
+    /// timeline appeared.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the timeline belongs to.
+    async fn remove_paginated_timeline(&self, room_id: &RoomId) -> Result<()>;
+
+    /// Remember the event id the server assigned to an event that we sent
+    /// with the given transaction id.
+    ///
+    /// The transaction id is saved with no event id before the event is sent,
+    /// and with the event id once the server accepted the event. The mapping
+    /// is removed once the remote echo of the event is received in a sync.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event was sent to.
+    ///
+    /// * `transaction_id` - The transaction id that was used to send the event.
+    ///
+    /// * `event_id` - The event id the server assigned to the event, `None` if
+    /// the event is about to be sent.
+    async fn save_transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+        event_id: Option<&EventId>,
+    ) -> Result<()>;
+
+    /// Get the transaction ids of the events we started to send to the given
+    /// room without getting a response from the server, e.g. because the
+    /// client was stopped in the meantime.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the events were sent to.
+    async fn get_pending_transaction_ids(&self, room_id: &RoomId) -> Result<Vec<String>>;
+
+    /// Forget about the given transaction id, e.g. because the server rejected
+    /// the event.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event was sent to.
+    ///
+    /// * `transaction_id` - The transaction id that was used to send the event.
+    async fn remove_transaction_id(&self, room_id: &RoomId, transaction_id: &str) -> Result<()>;
+
+    /// Get the event id of an event that we sent with the given transaction id,
+    /// if the server accepted the event and its remote echo wasn't received
+    /// yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room the event was sent to.
+    ///
+    /// * `transaction_id` - The transaction id that was used to send the event.
+    async fn get_transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+    ) -> Result<Option<EventId>>;
+}
+
+/// A type-erased [`StateStore`].
+///
+/// This is the type the [`Store`] wrapper holds its store as, custom store
+/// implementations are converted to it using [`IntoStateStore`].
+pub type DynStateStore = dyn StateStore;
+
+/// A type that can be type-erased into an `Arc<DynStateStore>`.
+///
+/// This is implemented for every [`StateStore`] implementation, either on its
+/// own or inside an `Arc`, as well as for stores that are already
+/// type-erased.
+pub trait IntoStateStore {
+    #[doc(hidden)]
+    fn into_state_store(self) -> Arc<DynStateStore>;
+}
+
+impl<T> IntoStateStore for T
+where
+    T: StateStore + 'static,
+{
+    fn into_state_store(self) -> Arc<DynStateStore> {
+        Arc::new(self)
+    }
+}
+
+impl<T> IntoStateStore for Arc<T>
+where
+    T: StateStore + 'static,
+{
+    fn into_state_store(self) -> Arc<DynStateStore> {
+        self
+    }
+}
+
+impl IntoStateStore for Arc<DynStateStore> {
+    fn into_state_store(self) -> Arc<DynStateStore> {
+        self
+    }
+}
+
+impl IntoStateStore for Box<DynStateStore> {
+    fn into_state_store(self) -> Arc<DynStateStore> {
+        self.into()
+    }
 }
 
 /// A state store wrapper for the SDK.
@@ -288,7 +464,7 @@ pub trait StateStore: AsyncTraitDeps {
 /// `StateStore` implementation.
 #[derive(Debug, Clone)]
 pub struct Store {
-    inner: Arc<dyn StateStore>,
+    inner: Arc<DynStateStore>,
     pub(crate) session: Arc<RwLock<Option<Session>>>,
     pub(crate) sync_token: Arc<RwLock<Option<String>>>,
     rooms: Arc<DashMap<RoomId, Room>>,
@@ -296,12 +472,12 @@ pub struct Store {
 }
 
 impl Store {
-    fn new(inner: Box<dyn StateStore>) -> Self {
+    pub(crate) fn new(inner: Arc<DynStateStore>) -> Self {
         let session = Arc::new(RwLock::new(None));
         let sync_token = Arc::new(RwLock::new(None));
 
         Self {
-            inner: inner.into(),
+            inner,
             session,
             sync_token,
             rooms: DashMap::new().into(),
@@ -328,11 +504,18 @@ impl Store {
         Ok(())
     }
 
+    /// Save the set of state changes in the store.
+    pub async fn save_changes(&self, changes: &StateChanges) -> Result<()> {
+        let now = Instant::now();
+        let result = self.inner.save_changes(changes).await;
+        metrics::record_store_operation("state", "save_changes", now.elapsed());
+
+        result
+    }
+
     #[cfg(not(feature = "sled_state_store"))]
     pub(crate) fn open_memory_store() -> Self {
-        let inner = Box::new(MemoryStore::new());
-
-        Self::new(inner)
+        Self::new(MemoryStore::new().into_state_store())
     }
 
     /// Open the default Sled store.
@@ -351,14 +534,14 @@ impl Store {
             SledStore::open_with_path(path)?
         };
 
-        Ok((Self::new(Box::new(inner.clone())), inner.inner))
+        Ok((Self::new(inner.clone().into_state_store()), inner.inner))
     }
 
     #[cfg(feature = "sled_state_store")]
     pub(crate) fn open_temporary() -> Result<(Self, Db)> {
         let inner = SledStore::open()?;
 
-        Ok((Self::new(Box::new(inner.clone())), inner.inner))
+        Ok((Self::new(inner.clone().into_state_store()), inner.inner))
     }
 
     /// Get all the rooms this store knows about.
@@ -404,7 +587,7 @@ impl Store {
 }
 
 impl Deref for Store {
-    type Target = dyn StateStore;
+    type Target = DynStateStore;
 
     fn deref(&self) -> &Self::Target {
         &*self.inner
@@ -453,6 +636,9 @@ pub struct StateChanges {
     pub ambiguity_maps: BTreeMap<RoomId, BTreeMap<String, BTreeSet<UserId>>>,
     /// A map of `RoomId` to a vector of `Notification`s
     pub notifications: BTreeMap<RoomId, Vec<Notification>>,
+    /// A map of `RoomId` to the transaction ids of events we sent whose remote
+    /// echo was received.
+    pub remote_echoes: BTreeMap<RoomId, BTreeSet<String>>,
 }
 
 impl StateChanges {
@@ -466,6 +652,12 @@ impl StateChanges {
         self.presence.insert(event.sender, raw_event);
     }
 
+    /// Update the `StateChanges` struct with the transaction id of an event we
+    /// sent, whose remote echo was received.
+    pub fn add_remote_echo(&mut self, room_id: &RoomId, transaction_id: String) {
+        self.remote_echoes.entry(room_id.to_owned()).or_default().insert(transaction_id);
+    }
+
     /// Update the `StateChanges` struct with the given `RoomInfo`.
     pub fn add_room(&mut self, room: RoomInfo) {
         self.room_infos.insert(room.room_id.as_ref().to_owned(), room);
@@ -19,14 +19,13 @@ use std::{
     convert::{TryFrom, TryInto},
     path::{Path, PathBuf},
     sync::Arc,
-    time::Instant,
 };
 
 use futures::{
     stream::{self, Stream},
     TryStreamExt,
 };
-use matrix_sdk_common::async_trait;
+use matrix_sdk_common::{async_trait, instant::Instant};
 use ruma::{
     events::{
         presence::PresenceEvent,
@@ -39,6 +38,7 @@ use ruma::{
     EventId, MxcUri, RoomId, UserId,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::value::to_raw_value;
 use sled::{
     transaction::{ConflictableTransactionError, TransactionError},
     Config, Db, Transactional, Tree,
@@ -48,8 +48,11 @@ use tracing::info;
 use self::store_key::{EncryptedEvent, StoreKey};
 use super::{Result, RoomInfo, StateChanges, StateStore, StoreError};
 use crate::{
-    deserialized_responses::MemberEvent,
+    deserialized_responses::{
+        MemberEvent, PaginatedTimeline, PaginatedTimelineChanges, SyncRoomEvent,
+    },
     media::{MediaRequest, UniqueKey},
+    redaction,
 };
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -58,6 +61,14 @@ pub enum DatabaseType {
     Encrypted(store_key::EncryptedStoreKey),
 }
 
+/// A transaction id of an event we sent, with the event id the server
+/// assigned to the event once it accepted it.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredTransaction {
+    transaction_id: String,
+    event_id: Option<EventId>,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum SerializationError {
     #[error(transparent)]
@@ -154,6 +165,30 @@ impl EncodeKey for EventType {
     }
 }
 
+/// Encode the position of an event in a stored timeline, so that the byte
+/// order of the encoded positions matches the order of the positions.
+fn encode_timeline_position(room_id: &RoomId, position: i64) -> Vec<u8> {
+    [room_id.encode(), ((position as u64) ^ (1 << 63)).to_be_bytes().to_vec()].concat()
+}
+
+/// Metadata about the stored timeline of a room.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TimelineMetadata {
+    /// The token to continue paginating backwards.
+    token: Option<String>,
+    /// The position the next event that is put in front of the timeline gets.
+    next_front: i64,
+    /// The position the next event that is put at the back of the timeline
+    /// gets.
+    next_back: i64,
+}
+
+impl TimelineMetadata {
+    fn new() -> Self {
+        Self { token: None, next_front: 0, next_back: -1 }
+    }
+}
+
 /// Get the value at `position` in encoded `key`.
 ///
 /// The key must have been encoded with the `EncodeKey` trait. `position`
@@ -189,6 +224,10 @@ pub struct SledStore {
     room_user_receipts: Tree,
     room_event_receipts: Tree,
     media: Tree,
+    timeline_events: Tree,
+    timeline_event_positions: Tree,
+    timeline_metadata: Tree,
+    transaction_event_ids: Tree,
 }
 
 impl std::fmt::Debug for SledStore {
@@ -226,6 +265,14 @@ impl SledStore {
 
         let media = db.open_tree("media")?;
 
+        // Timelines used to be stored as a single value per room, they are
+        // only a cache of the room history so they can just be dropped.
+        db.drop_tree("paginated_timelines")?;
+        let timeline_events = db.open_tree("timeline_events")?;
+        let timeline_event_positions = db.open_tree("timeline_event_positions")?;
+        let timeline_metadata = db.open_tree("timeline_metadata")?;
+        let transaction_event_ids = db.open_tree("transaction_event_ids")?;
+
         Ok(Self {
             path,
             inner: db,
@@ -247,6 +294,10 @@ impl SledStore {
             room_user_receipts,
             room_event_receipts,
             media,
+            timeline_events,
+            timeline_event_positions,
+            timeline_metadata,
+            transaction_event_ids,
         })
     }
 
@@ -556,6 +607,13 @@ impl SledStore {
 
         ret?;
 
+        for (room, transaction_ids) in &changes.remote_echoes {
+            for transaction_id in transaction_ids {
+                self.transaction_event_ids
+                    .remove((room.as_str(), transaction_id.as_str()).encode())?;
+            }
+        }
+
         self.inner.flush_async().await?;
 
         info!("Saved changes in {:?}", now.elapsed());
@@ -580,6 +638,38 @@ impl SledStore {
             .transpose()?)
     }
 
+    pub async fn get_state_events(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+    ) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        self.room_state
+            .scan_prefix((room_id.as_str(), event_type.as_str()).encode())
+            .map(|e| {
+                e.map_err(StoreError::Sled)
+                    .and_then(|(_, e)| self.deserialize_event(&e).map_err(Into::into))
+            })
+            .collect()
+    }
+
+    pub async fn get_room_state(&self, room_id: &RoomId) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        let mut events = self
+            .room_state
+            .scan_prefix(room_id.encode())
+            .map(|e| {
+                e.map_err(StoreError::Sled)
+                    .and_then(|(_, e)| self.deserialize_event(&e).map_err(Into::into))
+            })
+            .collect::<Result<Vec<Raw<AnySyncStateEvent>>>>()?;
+
+        for member in self.members.scan_prefix(room_id.encode()) {
+            let member: MemberEvent = self.deserialize_event(&member?.1)?;
+            events.push(Raw::from_json(to_raw_value(&member)?));
+        }
+
+        Ok(events)
+    }
+
     pub async fn get_profile(
         &self,
         room_id: &RoomId,
@@ -768,6 +858,188 @@ impl SledStore {
 
         Ok(self.media.apply_batch(batch)?)
     }
+
+    pub async fn get_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<PaginatedTimeline>> {
+        let metadata: TimelineMetadata = match self.timeline_metadata.get(room_id.encode())? {
+            Some(m) => self.deserialize_event(&m)?,
+            None => return Ok(None),
+        };
+
+        // The positions grow with newer events, iterate in reverse to get
+        // the newest event first.
+        let events = self
+            .timeline_events
+            .scan_prefix(room_id.encode())
+            .values()
+            .rev()
+            .map(|e| self.deserialize_event(&e?).map_err(StoreError::from))
+            .collect::<Result<_>>()?;
+
+        Ok(Some(PaginatedTimeline { events, token: metadata.token }))
+    }
+
+    pub async fn save_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+        timeline: &PaginatedTimeline,
+    ) -> Result<()> {
+        self.remove_paginated_timeline(room_id).await?;
+
+        let changes = PaginatedTimelineChanges {
+            appended: timeline.events.clone(),
+            token: Some(timeline.token.clone()),
+            ..Default::default()
+        };
+
+        self.update_paginated_timeline(room_id, &changes).await
+    }
+
+    pub async fn update_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+        changes: &PaginatedTimelineChanges,
+    ) -> Result<()> {
+        let mut metadata: TimelineMetadata = self
+            .timeline_metadata
+            .get(room_id.encode())?
+            .map(|m| self.deserialize_event(&m))
+            .transpose()?
+            .unwrap_or_else(TimelineMetadata::new);
+
+        let mut events = sled::Batch::default();
+        let mut positions = sled::Batch::default();
+
+        let mut add_event = |position: i64, event: &SyncRoomEvent| -> Result<()> {
+            let key = encode_timeline_position(room_id, position);
+            events.insert(key.as_slice(), self.serialize_event(event)?);
+
+            if let Some(event_id) = redaction::event_id(&event.event) {
+                positions.insert(
+                    (room_id.as_str(), event_id.as_str()).encode(),
+                    key[key.len() - 8..].to_vec(),
+                );
+            }
+
+            Ok(())
+        };
+
+        // The prepended events are ordered from the newest to the oldest
+        // event, the oldest one needs the lowest position.
+        for event in changes.prepended.iter().rev() {
+            add_event(metadata.next_front, event)?;
+            metadata.next_front += 1;
+        }
+
+        for event in &changes.appended {
+            add_event(metadata.next_back, event)?;
+            metadata.next_back -= 1;
+        }
+
+        for event in &changes.updated {
+            let position = redaction::event_id(&event.event)
+                .map(|id| {
+                    self.timeline_event_positions.get((room_id.as_str(), id.as_str()).encode())
+                })
+                .transpose()?
+                .flatten();
+
+            if let Some(position) = position {
+                let key = [room_id.encode(), position.to_vec()].concat();
+                events.insert(key, self.serialize_event(event)?);
+            }
+        }
+
+        if let Some(token) = &changes.token {
+            metadata.token = token.clone();
+        }
+
+        self.timeline_events.apply_batch(events)?;
+        self.timeline_event_positions.apply_batch(positions)?;
+        self.timeline_metadata.insert(room_id.encode(), self.serialize_event(&metadata)?)?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_paginated_timeline(&self, room_id: &RoomId) -> Result<()> {
+        let mut events = sled::Batch::default();
+        let mut positions = sled::Batch::default();
+
+        for key in self.timeline_events.scan_prefix(room_id.encode()).keys() {
+            events.remove(key?);
+        }
+
+        for key in self.timeline_event_positions.scan_prefix(room_id.encode()).keys() {
+            positions.remove(key?);
+        }
+
+        self.timeline_events.apply_batch(events)?;
+        self.timeline_event_positions.apply_batch(positions)?;
+        self.timeline_metadata.remove(room_id.encode())?;
+
+        Ok(())
+    }
+
+    pub async fn save_transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+        event_id: Option<&EventId>,
+    ) -> Result<()> {
+        let transaction = StoredTransaction {
+            transaction_id: transaction_id.to_owned(),
+            event_id: event_id.cloned(),
+        };
+
+        self.transaction_event_ids.insert(
+            (room_id.as_str(), transaction_id).encode(),
+            self.serialize_event(&transaction)?,
+        )?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+    ) -> Result<Option<EventId>> {
+        Ok(self
+            .transaction_event_ids
+            .get((room_id.as_str(), transaction_id).encode())?
+            .map(|t| self.deserialize_event::<StoredTransaction>(&t))
+            .transpose()?
+            .and_then(|t| t.event_id))
+    }
+
+    pub async fn get_pending_transaction_ids(&self, room_id: &RoomId) -> Result<Vec<String>> {
+        let mut transaction_ids = Vec::new();
+
+        for transaction in self.transaction_event_ids.scan_prefix(room_id.encode()).values() {
+            let transaction: StoredTransaction = self.deserialize_event(&transaction?)?;
+
+            if transaction.event_id.is_none() {
+                transaction_ids.push(transaction.transaction_id);
+            }
+        }
+
+        Ok(transaction_ids)
+    }
+
+    pub async fn remove_transaction_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+    ) -> Result<()> {
+        self.transaction_event_ids.remove((room_id.as_str(), transaction_id).encode())?;
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -801,6 +1073,18 @@ impl StateStore for SledStore {
         self.get_state_event(room_id, event_type, state_key).await
     }
 
+    async fn get_state_events(
+        &self,
+        room_id: &RoomId,
+        event_type: EventType,
+    ) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        self.get_state_events(room_id, event_type).await
+    }
+
+    async fn get_room_state(&self, room_id: &RoomId) -> Result<Vec<Raw<AnySyncStateEvent>>> {
+        self.get_room_state(room_id).await
+    }
+
     async fn get_profile(
         &self,
         room_id: &RoomId,
@@ -893,6 +1177,55 @@ impl StateStore for SledStore {
     async fn remove_media_content_for_uri(&self, uri: &MxcUri) -> Result<()> {
         self.remove_media_content_for_uri(uri).await
     }
+
+    async fn get_paginated_timeline(&self, room_id: &RoomId) -> Result<Option<PaginatedTimeline>> {
+        self.get_paginated_timeline(room_id).await
+    }
+
+    async fn save_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+        timeline: &PaginatedTimeline,
+    ) -> Result<()> {
+        self.save_paginated_timeline(room_id, timeline).await
+    }
+
+    async fn update_paginated_timeline(
+        &self,
+        room_id: &RoomId,
+        changes: &PaginatedTimelineChanges,
+    ) -> Result<()> {
+        self.update_paginated_timeline(room_id, changes).await
+    }
+
+    async fn remove_paginated_timeline(&self, room_id: &RoomId) -> Result<()> {
+        self.remove_paginated_timeline(room_id).await
+    }
+
+    async fn save_transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+        event_id: Option<&EventId>,
+    ) -> Result<()> {
+        self.save_transaction_event_id(room_id, transaction_id, event_id).await
+    }
+
+    async fn get_transaction_event_id(
+        &self,
+        room_id: &RoomId,
+        transaction_id: &str,
+    ) -> Result<Option<EventId>> {
+        self.get_transaction_event_id(room_id, transaction_id).await
+    }
+
+    async fn get_pending_transaction_ids(&self, room_id: &RoomId) -> Result<Vec<String>> {
+        self.get_pending_transaction_ids(room_id).await
+    }
+
+    async fn remove_transaction_id(&self, room_id: &RoomId, transaction_id: &str) -> Result<()> {
+        self.remove_transaction_id(room_id, transaction_id).await
+    }
 }
 
 #[cfg(test)]
@@ -918,7 +1251,9 @@ mod test {
 
     use super::{SledStore, StateChanges};
     use crate::{
-        deserialized_responses::MemberEvent,
+        deserialized_responses::{
+            MemberEvent, PaginatedTimeline, PaginatedTimelineChanges, SyncRoomEvent,
+        },
         media::{MediaFormat, MediaRequest, MediaThumbnailSize, MediaType},
         StateStore,
     };
@@ -955,6 +1290,69 @@ mod test {
         }
     }
 
+    fn timeline_event(event_id: &str, body: &str) -> SyncRoomEvent {
+        let event = json!({
+            "type": "m.room.message",
+            "event_id": event_id,
+            "sender": user_id(),
+            "origin_server_ts": 0u64,
+            "content": { "msgtype": "m.text", "body": body },
+        });
+
+        Raw::from_json(serde_json::value::to_raw_value(&event).unwrap()).into()
+    }
+
+    fn timeline_bodies(timeline: &PaginatedTimeline) -> Vec<String> {
+        timeline
+            .events
+            .iter()
+            .map(|e| {
+                let json: serde_json::Value = serde_json::from_str(e.event.json().get()).unwrap();
+                json["content"]["body"].as_str().unwrap().to_owned()
+            })
+            .collect()
+    }
+
+    #[async_test]
+    async fn test_paginated_timeline_saving() {
+        let store = SledStore::open().unwrap();
+        let room_id = room_id!("!test:localhost");
+
+        assert!(store.get_paginated_timeline(&room_id).await.unwrap().is_none());
+
+        let timeline = PaginatedTimeline {
+            events: vec![timeline_event("$b", "b"), timeline_event("$a", "a")],
+            token: Some("token".to_owned()),
+        };
+        store.save_paginated_timeline(&room_id, &timeline).await.unwrap();
+
+        let changes = PaginatedTimelineChanges {
+            prepended: vec![timeline_event("$d", "d"), timeline_event("$c", "c")],
+            appended: vec![timeline_event("$z", "z")],
+            updated: vec![timeline_event("$a", "edited")],
+            token: Some(None),
+        };
+        store.update_paginated_timeline(&room_id, &changes).await.unwrap();
+
+        let timeline = store.get_paginated_timeline(&room_id).await.unwrap().unwrap();
+        assert_eq!(timeline_bodies(&timeline), ["d", "c", "b", "edited", "z"]);
+        assert!(timeline.token.is_none());
+
+        // Only the token changes, none of the events are touched.
+        let changes =
+            PaginatedTimelineChanges { token: Some(Some("new".to_owned())), ..Default::default() };
+        store.update_paginated_timeline(&room_id, &changes).await.unwrap();
+
+        let timeline = store.get_paginated_timeline(&room_id).await.unwrap().unwrap();
+        assert_eq!(timeline.events.len(), 5);
+        assert_eq!(timeline.token.as_deref(), Some("new"));
+
+        store.remove_paginated_timeline(&room_id).await.unwrap();
+        assert!(store.get_paginated_timeline(&room_id).await.unwrap().is_none());
+        assert!(store.timeline_events.is_empty());
+        assert!(store.timeline_event_positions.is_empty());
+    }
+
     #[async_test]
     async fn test_member_saving() {
         let store = SledStore::open().unwrap();
@@ -1000,6 +1398,25 @@ mod test {
             .is_some());
     }
 
+    #[async_test]
+    async fn test_state_events_getting() {
+        let store = SledStore::open().unwrap();
+        let room_id = room_id!("!test:localhost");
+
+        let raw_event = power_level_event();
+        let event = raw_event.deserialize().unwrap();
+
+        let mut changes = StateChanges::default();
+        changes.add_state_event(&room_id, event, raw_event);
+        store.save_changes(&changes).await.unwrap();
+
+        assert_eq!(
+            store.get_state_events(&room_id, EventType::RoomPowerLevels).await.unwrap().len(),
+            1
+        );
+        assert!(store.get_state_events(&room_id, EventType::RoomTopic).await.unwrap().is_empty());
+    }
+
     #[async_test]
     async fn test_receipts_saving() {
         let store = SledStore::open().unwrap();
@@ -9,14 +9,15 @@ use ruma::{
         },
     },
     events::{
-        room::member::MemberEventContent, AnySyncRoomEvent, StateEvent, StrippedStateEvent,
-        SyncStateEvent, Unsigned,
+        room::member::{MemberEventContent, MembershipState},
+        AnySyncRoomEvent, StateEvent, StrippedStateEvent, SyncStateEvent, Unsigned,
     },
     identifiers::{DeviceKeyAlgorithm, EventId, RoomId, UserId},
     serde::Raw,
     DeviceIdBox, MilliSecondsSinceUnixEpoch,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 
 /// A change in ambiguity of room members that an `m.room.member` event
 /// triggers.
@@ -40,7 +41,7 @@ pub struct AmbiguityChanges {
 }
 
 /// The verification state of the device that sent an event to us.
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum VerificationState {
     /// The device is trusted.
     Trusted,
@@ -50,6 +51,18 @@ pub enum VerificationState {
     UnknownDevice,
 }
 
+/// How the trust in the device that sent an event changed since the event was
+/// decrypted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TrustChange {
+    /// The device was trusted when the event was decrypted but isn't anymore,
+    /// e.g. because the device got blacklisted or removed.
+    Downgraded,
+    /// The device wasn't trusted when the event was decrypted but has since
+    /// been verified.
+    Upgraded,
+}
+
 /// The algorithm specific information of a decrypted event.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum AlgorithmInfo {
@@ -83,6 +96,19 @@ pub struct EncryptionInfo {
     /// is the state of the device at the time of decryption. It may change in
     /// the future if a device gets verified or deleted.
     pub verification_state: VerificationState,
+    /// Was the event decrypted with a room key that we didn't receive
+    /// directly from the sender, i.e. a forwarded room key, one imported from
+    /// a file or one restored from a backup. Such room keys can't vouch for
+    /// the sender, the event should be trusted less.
+    #[serde(default)]
+    pub indirect_room_key: bool,
+    /// Has the trust in the sending device changed since the event was
+    /// decrypted, set when the encryption info gets re-evaluated.
+    ///
+    /// This allows clients to mark messages that were sent from a device that
+    /// has since been distrusted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trust_change: Option<TrustChange>,
 }
 
 /// A customized version of a room event coming from a sync that holds optional
@@ -94,11 +120,146 @@ pub struct SyncRoomEvent {
     /// The encryption info about the event. Will be `None` if the event was not
     /// encrypted.
     pub encryption_info: Option<EncryptionInfo>,
+    /// What changed about the membership of a room member, if the event is a
+    /// `m.room.member` event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub membership_change: Option<MembershipChange>,
+    /// Data that was attached to the event while it was received, e.g. by
+    /// an event processor, keyed by the name of its producer.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, JsonValue>,
+}
+
+impl SyncRoomEvent {
+    /// Get the metadata that was attached to the event under the given key.
+    ///
+    /// Returns `None` if there is no metadata with the given key or if it
+    /// can't be deserialized into the requested type.
+    pub fn metadata<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.metadata.get(key).and_then(|v| serde_json::from_value(v.clone()).ok())
+    }
+
+    /// Attach metadata to the event under the given key, replacing the
+    /// metadata that was previously stored under it.
+    pub fn set_metadata<T: Serialize>(
+        &mut self,
+        key: impl Into<String>,
+        value: &T,
+    ) -> Result<(), serde_json::Error> {
+        self.metadata.insert(key.into(), serde_json::to_value(value)?);
+        Ok(())
+    }
 }
 
 impl From<Raw<AnySyncRoomEvent>> for SyncRoomEvent {
     fn from(inner: Raw<AnySyncRoomEvent>) -> Self {
-        Self { encryption_info: None, event: inner }
+        Self {
+            encryption_info: None,
+            event: inner,
+            membership_change: None,
+            metadata: BTreeMap::new(),
+        }
+    }
+}
+
+/// What changed about the membership of a room member between two consecutive
+/// `m.room.member` events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MembershipChange {
+    /// Nothing changed.
+    None,
+    /// The member joined the room.
+    Joined,
+    /// The member accepted an invitation and joined the room.
+    InvitationAccepted,
+    /// The member left the room.
+    Left,
+    /// The member was kicked out of the room.
+    Kicked,
+    /// The member was banned.
+    Banned,
+    /// The member was kicked out of the room and banned.
+    KickedAndBanned,
+    /// The member was unbanned.
+    Unbanned,
+    /// The member was invited to the room.
+    Invited,
+    /// The member rejected an invitation.
+    InvitationRejected,
+    /// The invitation of the member was revoked.
+    InvitationRevoked,
+    /// The member asked to join the room.
+    Knocked,
+    /// The request of the member to join the room was accepted, the member was
+    /// invited.
+    KnockAccepted,
+    /// The member retracted the request to join the room.
+    KnockRetracted,
+    /// The request of the member to join the room was denied.
+    KnockDenied,
+    /// The member changed their profile while staying in the room.
+    ProfileChanged {
+        /// Did the display name of the member change.
+        display_name_changed: bool,
+        /// Did the avatar of the member change.
+        avatar_url_changed: bool,
+    },
+    /// The membership changed in a way that isn't known to the SDK, e.g. to a
+    /// custom membership state.
+    Unknown,
+}
+
+impl MembershipChange {
+    /// Compute the change between the content of the previous `m.room.member`
+    /// event of a member and the content of the new one.
+    ///
+    /// # Arguments
+    ///
+    /// * `prev_content` - The content of the previous event, `None` if the
+    /// member didn't have a membership before.
+    ///
+    /// * `content` - The content of the new event.
+    ///
+    /// * `by_member` - Was the new event sent by the member itself, i.e. is the
+    /// sender of the event the same as its state key.
+    pub fn new(
+        prev_content: Option<&MemberEventContent>,
+        content: &MemberEventContent,
+        by_member: bool,
+    ) -> Self {
+        use MembershipState::*;
+
+        let prev_membership = prev_content.map(|c| &c.membership).unwrap_or(&Leave);
+
+        match (prev_membership, &content.membership) {
+            (Join, Join) => {
+                let prev_content = prev_content.expect("A join membership has previous content");
+                let display_name_changed = prev_content.displayname != content.displayname;
+                let avatar_url_changed = prev_content.avatar_url != content.avatar_url;
+
+                if display_name_changed || avatar_url_changed {
+                    Self::ProfileChanged { display_name_changed, avatar_url_changed }
+                } else {
+                    Self::None
+                }
+            }
+            (Invite, Join) => Self::InvitationAccepted,
+            (_, Join) => Self::Joined,
+            (Join, Leave) if by_member => Self::Left,
+            (Join, Leave) => Self::Kicked,
+            (Invite, Leave) if by_member => Self::InvitationRejected,
+            (Invite, Leave) => Self::InvitationRevoked,
+            (Knock, Leave) if by_member => Self::KnockRetracted,
+            (Knock, Leave) => Self::KnockDenied,
+            (Ban, Leave) => Self::Unbanned,
+            (Leave, Leave) | (Ban, Ban) | (Invite, Invite) | (Knock, Knock) => Self::None,
+            (Join, Ban) => Self::KickedAndBanned,
+            (_, Ban) => Self::Banned,
+            (Knock, Invite) => Self::KnockAccepted,
+            (_, Invite) => Self::Invited,
+            (_, Knock) => Self::Knocked,
+            _ => Self::Unknown,
+        }
     }
 }
 
@@ -176,7 +337,7 @@ impl JoinedRoom {
 }
 
 /// Counts of unread notifications for a room.
-#[derive(Copy, Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
 pub struct UnreadNotificationsCount {
     /// The number of unread notifications for this room with the highlight flag
     /// set.
@@ -235,6 +396,65 @@ impl Timeline {
     }
 }
 
+/// A chunk of older events of a room timeline, fetched using back-pagination.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TimelineChunk {
+    /// The events of the chunk, ordered from the newest to the oldest event.
+    ///
+    /// Events that are already part of the stored timeline are left out, so
+    /// an event never shows up twice.
+    pub events: Vec<SyncRoomEvent>,
+
+    /// True if the start of the room was reached, there are no older events
+    /// to paginate to.
+    pub reached_start: bool,
+}
+
+/// The events of a room timeline that were fetched using back-pagination,
+/// as they are persisted in the state store.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct PaginatedTimeline {
+    /// The events, ordered from the newest to the oldest event.
+    pub events: Vec<SyncRoomEvent>,
+
+    /// The token to continue paginating backwards, `None` if the start of the
+    /// room was reached.
+    pub token: Option<String>,
+}
+
+/// Changes to the stored timeline of a room.
+///
+/// Only the events that changed are part of the changes, this allows the
+/// state store to persist them without rewriting the whole timeline.
+#[derive(Clone, Debug, Default)]
+pub struct PaginatedTimelineChanges {
+    /// Events that are newer than the stored events, ordered from the newest
+    /// to the oldest event.
+    pub prepended: Vec<SyncRoomEvent>,
+
+    /// Events that are older than the stored events, ordered from the newest
+    /// to the oldest event.
+    pub appended: Vec<SyncRoomEvent>,
+
+    /// Stored events that changed, e.g. because they were redacted. They
+    /// replace the stored copy of the event with the same event id.
+    pub updated: Vec<SyncRoomEvent>,
+
+    /// The new token to continue paginating backwards, `None` if the token
+    /// didn't change.
+    pub token: Option<Option<String>>,
+}
+
+impl PaginatedTimelineChanges {
+    /// Are there no changes.
+    pub fn is_empty(&self) -> bool {
+        self.prepended.is_empty()
+            && self.appended.is_empty()
+            && self.updated.is_empty()
+            && self.token.is_none()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(
     try_from = "SyncStateEvent<MemberEventContent>",
@@ -250,6 +470,18 @@ pub struct MemberEvent {
     pub unsigned: Unsigned,
 }
 
+impl MemberEvent {
+    /// Get what changed about the membership of the member compared to the
+    /// previous `m.room.member` event of the member.
+    pub fn membership_change(&self) -> MembershipChange {
+        MembershipChange::new(
+            self.prev_content.as_ref(),
+            &self.content,
+            self.sender == self.state_key,
+        )
+    }
+}
+
 impl TryFrom<SyncStateEvent<MemberEventContent>> for MemberEvent {
     type Error = ruma::identifiers::Error;
 
@@ -1,3 +1,9 @@
+//! Async locks that work with and without a tokio runtime.
+//!
+//! The tokio locks don't need a running tokio runtime, only the `sync`
+//! feature, they are used on native targets. WASM targets use the locks of
+//! the `futures-locks` crate, only the API that both share should be used.
+
 // could switch to futures-lock completely at some point, blocker:
 // https://github.com/asomers/futures-locks/issues/34
 // https://www.reddit.com/r/rust/comments/f4zldz/i_audited_3_different_implementation_of_async/
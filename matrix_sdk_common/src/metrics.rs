@@ -0,0 +1,85 @@
+//! Optional metrics instrumentation for the SDK.
+//!
+//! The functions in this module report metrics through the [`metrics`] facade
+//! if the `metrics` feature is enabled, otherwise they are no-ops. An
+//! application that wants to collect the metrics needs to install a recorder,
+//! e.g. a Prometheus or OpenTelemetry exporter.
+//!
+//! [`metrics`]: https://docs.rs/metrics
+
+use std::time::Duration;
+
+/// Record how long a request to the given endpoint took.
+///
+/// # Arguments
+///
+/// * `endpoint` - The name of the endpoint that was requested.
+///
+/// * `duration` - The time it took to receive a response.
+///
+/// * `success` - Did the request succeed.
+pub fn record_request(endpoint: &'static str, duration: Duration, success: bool) {
+    #[cfg(feature = "metrics")]
+    metrics_facade::histogram!(
+        "matrix_sdk_request_duration_seconds",
+        duration,
+        "endpoint" => endpoint,
+        "success" => if success { "true" } else { "false" }
+    );
+    #[cfg(not(feature = "metrics"))]
+    let _ = (endpoint, duration, success);
+}
+
+/// Record how long the processing of a sync response took.
+pub fn record_sync_processing(duration: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics_facade::histogram!("matrix_sdk_sync_processing_duration_seconds", duration);
+    #[cfg(not(feature = "metrics"))]
+    let _ = duration;
+}
+
+/// Record the outcome of a room event decryption.
+///
+/// # Arguments
+///
+/// * `success` - Was the event successfully decrypted, if this is false the
+/// event is counted as unable to decrypt.
+pub fn record_decryption(success: bool) {
+    #[cfg(feature = "metrics")]
+    if success {
+        metrics_facade::increment_counter!("matrix_sdk_decryption_successes_total");
+    } else {
+        metrics_facade::increment_counter!("matrix_sdk_decryption_failures_total");
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = success;
+}
+
+/// Record that a room key was shared with the given number of devices.
+pub fn record_room_key_shares(devices: usize) {
+    #[cfg(feature = "metrics")]
+    metrics_facade::counter!("matrix_sdk_room_key_shares_total", devices as u64);
+    #[cfg(not(feature = "metrics"))]
+    let _ = devices;
+}
+
+/// Record how long a store operation took.
+///
+/// # Arguments
+///
+/// * `store` - The name of the store, e.g. `"state"` or `"crypto"`.
+///
+/// * `operation` - The name of the operation, e.g. `"save_changes"`.
+///
+/// * `duration` - The time the operation took.
+pub fn record_store_operation(store: &'static str, operation: &'static str, duration: Duration) {
+    #[cfg(feature = "metrics")]
+    metrics_facade::histogram!(
+        "matrix_sdk_store_operation_duration_seconds",
+        duration,
+        "store" => store,
+        "operation" => operation
+    );
+    #[cfg(not(feature = "metrics"))]
+    let _ = (store, operation, duration);
+}
@@ -0,0 +1,65 @@
+//! Time handling that works with and without a tokio runtime.
+//!
+//! `std::time::Instant::now()` panics on WASM targets, the [`Instant`] of the
+//! `instant` crate works everywhere and should be used instead.
+//!
+//! Instants are relative to an unspecified point in time, e.g. the last boot
+//! of the machine, which makes them unsuitable to be persisted. Values that
+//! are stored should use wall-clock time, [`instant_to_timestamp()`] and
+//! [`timestamp_to_instant()`] convert between the two.
+
+pub use instant::{Duration, Instant};
+use ruma::{MilliSecondsSinceUnixEpoch, UInt};
+
+/// Wait until the given duration has passed.
+///
+/// The timer doesn't depend on a tokio runtime, it works on WASM targets as
+/// well.
+pub async fn sleep(duration: Duration) {
+    futures_timer::Delay::new(duration).await
+}
+
+/// Get the wall-clock time the given duration ago.
+pub fn time_ago(duration: Duration) -> MilliSecondsSinceUnixEpoch {
+    let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+    let duration = duration.as_millis() as u64;
+
+    MilliSecondsSinceUnixEpoch(UInt::new_saturating(now.saturating_sub(duration)))
+}
+
+/// Get the time that passed since the given wall-clock time.
+///
+/// Returns a zero duration if the time lies in the future, e.g. because the
+/// system clock was adjusted.
+pub fn elapsed_since(timestamp: MilliSecondsSinceUnixEpoch) -> Duration {
+    let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+    let timestamp: u64 = timestamp.get().into();
+
+    Duration::from_millis(now.saturating_sub(timestamp))
+}
+
+/// Convert an [`Instant`] to the wall-clock time it corresponds to, e.g. to
+/// persist it.
+pub fn instant_to_timestamp(instant: Instant) -> MilliSecondsSinceUnixEpoch {
+    let now = Instant::now();
+    let elapsed = if instant < now { now - instant } else { Duration::default() };
+
+    time_ago(elapsed)
+}
+
+/// Convert a wall-clock time back to an [`Instant`].
+///
+/// Returns `None` if the time can't be represented as an `Instant`, e.g.
+/// because it lies before the last boot of the machine. Callers should decide
+/// if such a time is treated as infinitely long ago or as right now.
+pub fn timestamp_to_instant(timestamp: MilliSecondsSinceUnixEpoch) -> Option<Instant> {
+    let now = Instant::now();
+    let wall_clock_now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+    let timestamp: u64 = timestamp.get().into();
+
+    if timestamp > wall_clock_now {
+        now.checked_add(Duration::from_millis(timestamp - wall_clock_now))
+    } else {
+        now.checked_sub(Duration::from_millis(wall_clock_now - timestamp))
+    }
+}
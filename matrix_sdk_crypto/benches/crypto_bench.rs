@@ -260,6 +260,54 @@ pub fn devices_missing_sessions_collecting(c: &mut Criterion) {
     group.finish()
 }
 
+pub fn device_loading(c: &mut Criterion) {
+    let runtime = Builder::new_multi_thread().build().expect("Can't create runtime");
+
+    let response = huge_keys_query_resopnse();
+    let uuid = Uuid::new_v4();
+    let users: Vec<UserId> = response.device_keys.keys().cloned().collect();
+
+    let count = response.device_keys.values().fold(0, |acc, d| acc + d.len());
+
+    let mut group = c.benchmark_group("Device loading");
+    group.throughput(Throughput::Elements(count as u64));
+
+    let name = format!("{} devices", count);
+
+    let machine = OlmMachine::new(&alice_id(), &alice_device_id());
+    runtime.block_on(machine.mark_request_as_sent(&uuid, &response)).unwrap();
+
+    group.bench_function(BenchmarkId::new("memory store", &name), |b| {
+        b.to_async(&runtime).iter(|| async {
+            for user_id in &users {
+                machine.get_user_devices(user_id).await.unwrap();
+            }
+        })
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let machine = runtime
+        .block_on(OlmMachine::new_with_default_store(
+            &alice_id(),
+            &alice_device_id(),
+            dir.path(),
+            None,
+        ))
+        .unwrap();
+
+    runtime.block_on(machine.mark_request_as_sent(&uuid, &response)).unwrap();
+
+    group.bench_function(BenchmarkId::new("sled store", &name), |b| {
+        b.to_async(&runtime).iter(|| async {
+            for user_id in &users {
+                machine.get_user_devices(user_id).await.unwrap();
+            }
+        })
+    });
+
+    group.finish()
+}
+
 fn criterion() -> Criterion {
     #[cfg(target_os = "linux")]
     let criterion = Criterion::default().with_profiler(perf::FlamegraphProfiler::new(100));
@@ -273,5 +321,6 @@ criterion_group! {
     name = benches;
     config = criterion();
     targets = keys_query, keys_claiming, room_key_sharing, devices_missing_sessions_collecting,
+        device_loading,
 }
 criterion_main!(benches);
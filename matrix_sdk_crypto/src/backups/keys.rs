@@ -0,0 +1,271 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::BTreeMap, convert::TryInto};
+
+use getrandom::getrandom;
+use olm_rs::{
+    errors::OlmPkDecryptionError,
+    pk::{OlmPkDecryption, OlmPkEncryption, PkMessage},
+};
+use ruma::{DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId};
+use serde::{Deserialize, Serialize};
+use serde_json::Error as JsonError;
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use crate::{
+    olm::{ExportedGroupSessionKey, ExportedRoomKey, InboundGroupSession},
+    utilities::{decode, encode, DecodeError},
+};
+
+const KEY_SIZE: usize = 32;
+
+/// Error type for the decryption of room keys that were stored in a backup.
+#[derive(Debug, Error)]
+pub enum BackupDecryptionError {
+    /// The backup decryption key doesn't have the correct length.
+    #[error("The backup decryption key has an invalid length, expected {0} bytes")]
+    InvalidKeyLength(usize),
+
+    /// The backup decryption key isn't valid base64.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+
+    /// The encrypted session data couldn't be decrypted.
+    #[error(transparent)]
+    Decryption(#[from] OlmPkDecryptionError),
+
+    /// The decrypted session data isn't a valid room key.
+    #[error(transparent)]
+    Json(#[from] JsonError),
+}
+
+/// The private key of a `m.megolm_backup.v1.curve25519-aes-sha2` room key
+/// backup.
+///
+/// The key is used to decrypt room keys that were downloaded from the backup.
+pub struct BackupDecryptionKey {
+    inner: Box<[u8; KEY_SIZE]>,
+}
+
+impl Drop for BackupDecryptionKey {
+    fn drop(&mut self) {
+        self.inner.zeroize()
+    }
+}
+
+impl std::fmt::Debug for BackupDecryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BackupDecryptionKey").field("public_key", &self.public_key()).finish()
+    }
+}
+
+impl Clone for BackupDecryptionKey {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl BackupDecryptionKey {
+    /// Create a new random backup decryption key.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn new() -> Self {
+        let mut inner = Box::new([0u8; KEY_SIZE]);
+        getrandom(&mut inner[..]).expect("Can't generate randomness for a backup key");
+
+        Self { inner }
+    }
+
+    /// Create a backup decryption key from the given raw private key.
+    pub fn from_bytes(key: [u8; KEY_SIZE]) -> Self {
+        Self { inner: Box::new(key) }
+    }
+
+    /// Try to create a backup decryption key from an unpadded base64 encoded
+    /// private key.
+    pub fn from_base64(key: &str) -> Result<Self, BackupDecryptionError> {
+        let mut decoded = decode(key)?;
+
+        let key: Result<[u8; KEY_SIZE], _> = decoded.as_slice().try_into();
+        let length = decoded.len();
+        decoded.zeroize();
+
+        key.map(Self::from_bytes).map_err(|_| BackupDecryptionError::InvalidKeyLength(length))
+    }
+
+    /// Export the private key as unpadded base64.
+    pub fn to_base64(&self) -> String {
+        encode(&self.inner[..])
+    }
+
+    fn pk_decryption(&self) -> OlmPkDecryption {
+        OlmPkDecryption::from_private_key(&self.inner[..])
+            .expect("A 32 byte private key is always a valid Curve25519 key")
+    }
+
+    /// Get the public part of the key, this is the key that is published in
+    /// the `auth_data` of the backup version.
+    pub fn public_key(&self) -> String {
+        self.pk_decryption().public_key().to_owned()
+    }
+
+    /// Decrypt the session data of a backed up room key.
+    pub fn decrypt_session_data(
+        &self,
+        session_data: &EncryptedSessionData,
+    ) -> Result<BackedUpRoomKey, BackupDecryptionError> {
+        let message = PkMessage::new(
+            session_data.ephemeral.clone(),
+            session_data.mac.clone(),
+            session_data.ciphertext.clone(),
+        );
+
+        let plaintext = self.pk_decryption().decrypt(message)?;
+
+        Ok(serde_json::from_str(&plaintext)?)
+    }
+}
+
+impl Default for BackupDecryptionKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encrypt a room key so it can be uploaded to the backup with the given
+/// public key.
+///
+/// `is_verified` tells if the device the room key is from is verified.
+pub(crate) async fn encrypt_for_backup(
+    public_key: &str,
+    session: &InboundGroupSession,
+    is_verified: bool,
+) -> KeyBackupData {
+    let room_key: BackedUpRoomKey = session.export().await.into();
+    let plaintext = serde_json::to_string(&room_key).expect("Can't serialize a backed up room key");
+
+    let message = OlmPkEncryption::new(public_key).encrypt(&plaintext);
+
+    KeyBackupData {
+        first_message_index: session.first_known_index(),
+        forwarded_count: session.forwarding_key_chain().len() as u32,
+        is_verified,
+        session_data: EncryptedSessionData {
+            ephemeral: message.ephemeral_key,
+            ciphertext: message.ciphertext,
+            mac: message.mac,
+        },
+    }
+}
+
+/// The encrypted part of a room key that is stored in a backup.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EncryptedSessionData {
+    /// The unpadded base64 encoded ephemeral Curve25519 key.
+    pub ephemeral: String,
+    /// The unpadded base64 encoded ciphertext.
+    pub ciphertext: String,
+    /// The unpadded base64 encoded MAC of the ciphertext.
+    pub mac: String,
+}
+
+/// A room key as it is stored on the server in a room key backup.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KeyBackupData {
+    /// The index of the first message in the session that the key can
+    /// decrypt.
+    pub first_message_index: u32,
+    /// The number of times this key has been forwarded via key-sharing between
+    /// devices.
+    pub forwarded_count: u32,
+    /// Whether the device backing up the key verified the device that the key
+    /// is from.
+    pub is_verified: bool,
+    /// The encrypted room key.
+    pub session_data: EncryptedSessionData,
+}
+
+/// The decrypted session data of a backed up room key.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BackedUpRoomKey {
+    /// The encryption algorithm that the session uses.
+    pub algorithm: EventEncryptionAlgorithm,
+
+    /// The Curve25519 key of the device which initiated the session originally.
+    pub sender_key: String,
+
+    /// The key for the session.
+    pub session_key: ExportedGroupSessionKey,
+
+    /// The Ed25519 key of the device which initiated the session originally.
+    pub sender_claimed_keys: BTreeMap<DeviceKeyAlgorithm, String>,
+
+    /// Chain of Curve25519 keys through which this session was forwarded, via
+    /// m.forwarded_room_key events.
+    #[serde(default)]
+    pub forwarding_curve25519_key_chain: Vec<String>,
+
+    /// Was the session created while the history of the room was visible to
+    /// users that join later on.
+    #[serde(default, rename = "org.matrix.msc3061.shared_history")]
+    pub shared_history: bool,
+}
+
+impl From<ExportedRoomKey> for BackedUpRoomKey {
+    fn from(key: ExportedRoomKey) -> Self {
+        Self {
+            algorithm: key.algorithm,
+            sender_key: key.sender_key,
+            session_key: key.session_key,
+            sender_claimed_keys: key.sender_claimed_keys,
+            forwarding_curve25519_key_chain: key.forwarding_curve25519_key_chain,
+            shared_history: key.shared_history,
+        }
+    }
+}
+
+impl BackedUpRoomKey {
+    /// Turn the backed up room key into an exported room key for the given
+    /// room and session id.
+    pub(crate) fn into_export(self, room_id: RoomId, session_id: String) -> ExportedRoomKey {
+        ExportedRoomKey {
+            algorithm: self.algorithm,
+            room_id,
+            sender_key: self.sender_key,
+            session_id,
+            session_key: self.session_key,
+            sender_claimed_keys: self.sender_claimed_keys,
+            forwarding_curve25519_key_chain: self.forwarding_curve25519_key_chain,
+            shared_history: self.shared_history,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BackupDecryptionKey;
+
+    #[test]
+    fn backup_key_base64_roundtrip() {
+        let key = BackupDecryptionKey::new();
+        let decoded = BackupDecryptionKey::from_base64(&key.to_base64()).unwrap();
+
+        assert_eq!(key.public_key(), decoded.public_key());
+        assert!(BackupDecryptionKey::from_base64("dGVzdA").is_err());
+    }
+}
@@ -0,0 +1,944 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for server-side room key backups.
+//!
+//! Room keys that we receive can be uploaded to a
+//! `m.megolm_backup.v1.curve25519-aes-sha2` backup once the backup was enabled.
+//! Room keys that are missing when a room event is decrypted can be downloaded
+//! from the backup, if the backup decryption key was given to the
+//! [`OlmMachine`].
+//!
+//! [`OlmMachine`]: ../struct.OlmMachine.html
+
+mod keys;
+
+use std::{collections::BTreeMap, convert::TryFrom, sync::Arc};
+
+use dashmap::{DashMap, DashSet};
+use futures::{StreamExt, TryStreamExt};
+use keys::encrypt_for_backup;
+pub use keys::{
+    BackedUpRoomKey, BackupDecryptionError, BackupDecryptionKey, EncryptedSessionData,
+    KeyBackupData,
+};
+use matrix_sdk_common::{
+    instant::{Duration, Instant},
+    locks::RwLock,
+    uuid::Uuid,
+};
+use olm_rs::inbound_group_session::OlmInboundGroupSession;
+use ruma::{DeviceIdBox, DeviceKeyAlgorithm, DeviceKeyId, EventEncryptionAlgorithm, RoomId};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{
+    identities::UserIdentities,
+    olm::{InboundGroupSession, ReadOnlyAccount, Utility},
+    secret_storage::SecretStorageKey,
+    store::{Changes, Result as StoreResult, Store},
+    utilities::log_id,
+};
+
+/// The algorithm of the backups we support.
+pub const BACKUP_ALGORITHM: &str = "m.megolm_backup.v1.curve25519-aes-sha2";
+
+/// The name of the secret that holds the backup decryption key in secret
+/// storage.
+pub const BACKUP_SECRET_NAME: &str = "m.megolm_backup.v1";
+
+/// A request to download room keys from a server-side key backup.
+///
+/// Every requested room key can be fetched using the
+/// `/room_keys/keys/{roomId}/{sessionId}` endpoint. If many sessions of a
+/// single room are requested, the `/room_keys/keys/{roomId}` endpoint can be
+/// used instead.
+#[derive(Clone, Debug)]
+pub struct KeysBackupDownloadRequest {
+    /// The unique id of the request, needed to pass the response back to the
+    /// [`OlmMachine`].
+    ///
+    /// [`OlmMachine`]: ../struct.OlmMachine.html
+    pub request_id: Uuid,
+    /// The version of the backup the keys should be downloaded from.
+    pub version: String,
+    /// The session ids of the room keys that should be downloaded, grouped by
+    /// room.
+    pub rooms: BTreeMap<RoomId, Vec<String>>,
+}
+
+/// A request to upload room keys to a server-side key backup.
+///
+/// The request should be sent out using the `/room_keys/keys` endpoint.
+#[derive(Clone, Debug)]
+pub struct KeysBackupRequest {
+    /// The unique id of the request, needed to mark the request as sent.
+    pub request_id: Uuid,
+    /// The version of the backup the keys should be uploaded to.
+    pub version: String,
+    /// The encrypted room keys, grouped by room and session id.
+    pub rooms: BTreeMap<RoomId, BTreeMap<String, KeyBackupData>>,
+}
+
+impl KeysBackupRequest {
+    /// Get the number of room keys this request uploads.
+    pub fn key_count(&self) -> usize {
+        self.rooms.values().map(|r| r.len()).sum()
+    }
+}
+
+/// The reason an upload of room keys to the backup failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupUploadFailure {
+    /// The server responded with `M_WRONG_ROOM_KEYS_VERSION`, the backup we're
+    /// uploading to isn't the current backup anymore.
+    WrongVersion,
+    /// Any other failure, the room keys will be uploaded again with the next
+    /// request.
+    Other,
+}
+
+/// The progress of the upload of our room keys to the backup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BackupProgress {
+    /// The total number of room keys we have.
+    pub total: usize,
+    /// The number of room keys that have been uploaded to the backup.
+    pub backed_up: usize,
+}
+
+impl BackupProgress {
+    /// The number of room keys that still need to be uploaded.
+    pub fn remaining(&self) -> usize {
+        self.total.saturating_sub(self.backed_up)
+    }
+}
+
+/// A newly created backup version that still needs to be uploaded to the
+/// server.
+#[derive(Debug)]
+pub struct NewBackup {
+    /// The private key of the backup, this should be stored in secret storage
+    /// so other devices can restore keys from the backup.
+    pub decryption_key: BackupDecryptionKey,
+    /// The algorithm of the backup.
+    pub algorithm: String,
+    /// The signed `auth_data` of the backup.
+    pub auth_data: Value,
+}
+
+impl NewBackup {
+    /// Get the public key of the backup.
+    pub fn public_key(&self) -> String {
+        self.decryption_key.public_key()
+    }
+
+    /// Encrypt the backup decryption key with the given secret storage key.
+    ///
+    /// The returned content should be uploaded as the `m.megolm_backup.v1`
+    /// account data event.
+    pub fn secret_storage_content(&self, key: &SecretStorageKey) -> Value {
+        key.encrypt_secret_content(BACKUP_SECRET_NAME, &self.decryption_key.to_base64())
+    }
+}
+
+/// The result of checking the signatures of a backup version.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupTrust {
+    /// Is the backup signed by this device.
+    pub device_signature: bool,
+    /// Is the backup signed by our cross signing master key, while our cross
+    /// signing identity is verified.
+    pub identity_signature: bool,
+    /// The other devices of ours that signed the backup, and whether the
+    /// device is trusted.
+    pub device_signatures: BTreeMap<DeviceIdBox, bool>,
+    /// Does the public key of the backup match the backup decryption key we
+    /// have, `None` if we don't have a backup decryption key.
+    pub matches_decryption_key: Option<bool>,
+}
+
+impl BackupTrust {
+    /// Can the backup be trusted.
+    ///
+    /// A backup is trusted if it was signed by this device, by our verified
+    /// cross signing identity or by one of our trusted devices, and if its
+    /// public key doesn't contradict the backup decryption key we have.
+    pub fn is_trusted(&self) -> bool {
+        self.matches_decryption_key != Some(false)
+            && (self.device_signature
+                || self.identity_signature
+                || self.device_signatures.values().any(|trusted| *trusted))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct UploadKey {
+    version: String,
+    public_key: String,
+}
+
+#[derive(Clone, Debug)]
+struct DownloadKey {
+    version: String,
+    key: BackupDecryptionKey,
+}
+
+/// State machine that restores missing room keys from a server-side key
+/// backup.
+#[derive(Clone, Debug)]
+pub(crate) struct BackupMachine {
+    account: ReadOnlyAccount,
+    store: Store,
+    upload_key: Arc<RwLock<Option<UploadKey>>>,
+    /// Upload requests that were handed out but weren't marked as sent yet.
+    uploads_in_flight: Arc<DashMap<Uuid, (String, Vec<InboundGroupSession>)>>,
+    download_key: Arc<RwLock<Option<DownloadKey>>>,
+    /// Room keys that are missing and should be downloaded.
+    pending_downloads: Arc<DashSet<(RoomId, String)>>,
+    /// Download requests that were handed out but didn't get a response yet,
+    /// with the time they were handed out.
+    downloads_in_flight: Arc<DashMap<Uuid, (String, BTreeMap<RoomId, Vec<String>>, Instant)>>,
+    /// Room keys that were requested but are not in the backup, with the time
+    /// we found out about it.
+    not_in_backup: Arc<DashMap<(RoomId, String), Instant>>,
+}
+
+impl BackupMachine {
+    /// The maximum number of room keys a single upload request will contain.
+    const MAX_UPLOAD_BATCH: usize = 100;
+    /// The maximum number of room keys a single download request will contain.
+    const MAX_DOWNLOAD_BATCH: usize = 50;
+    /// The time we wait until we ask the backup again for a room key it didn't
+    /// contain.
+    const NOT_IN_BACKUP_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+    /// The time after which a download request that didn't get a response is
+    /// considered to be lost, its room keys are queued up again.
+    const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+    pub fn new(account: ReadOnlyAccount, store: Store) -> Self {
+        Self {
+            account,
+            store,
+            upload_key: Arc::new(RwLock::new(None)),
+            uploads_in_flight: Arc::new(DashMap::new()),
+            download_key: Arc::new(RwLock::new(None)),
+            pending_downloads: Arc::new(DashSet::new()),
+            downloads_in_flight: Arc::new(DashMap::new()),
+            not_in_backup: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Start uploading our room keys to the backup with the given version and
+    /// public key.
+    ///
+    /// If we were previously uploading to a different backup version, all our
+    /// room keys will be uploaded again.
+    pub async fn enable_upload(&self, version: String, public_key: String) -> StoreResult<()> {
+        let mut upload_key = self.upload_key.write().await;
+
+        let version_changed = upload_key.as_ref().map(|k| k.version != version).unwrap_or(false);
+
+        if version_changed {
+            self.store.reset_backup_state().await?;
+        }
+
+        self.uploads_in_flight.clear();
+        *upload_key = Some(UploadKey { version, public_key });
+
+        Ok(())
+    }
+
+    /// Stop uploading our room keys to the backup.
+    pub async fn disable_upload(&self) {
+        self.upload_key.write().await.take();
+        self.uploads_in_flight.clear();
+    }
+
+    /// Are we uploading our room keys to a backup.
+    pub async fn is_upload_enabled(&self) -> bool {
+        self.upload_key.read().await.is_some()
+    }
+
+    /// Create a new backup version with a fresh backup key.
+    ///
+    /// The `auth_data` of the backup will be signed by this device.
+    pub async fn create_backup(&self) -> NewBackup {
+        let decryption_key = BackupDecryptionKey::new();
+
+        let mut auth_data = json!({ "public_key": decryption_key.public_key() });
+        let signature = self.account.sign_json(auth_data.clone()).await;
+
+        auth_data["signatures"] = json!({
+            self.account.user_id().as_str(): {
+                format!("ed25519:{}", self.account.device_id()): signature,
+            }
+        });
+
+        NewBackup { decryption_key, algorithm: BACKUP_ALGORITHM.to_owned(), auth_data }
+    }
+
+    /// Forget about the current backup, all our room keys will be marked as
+    /// not backed up.
+    pub async fn delete_backup(&self) -> StoreResult<()> {
+        self.disable_upload().await;
+        self.disable_download().await;
+
+        self.store.reset_backup_state().await
+    }
+
+    /// Get a request that uploads a batch of room keys that aren't backed up
+    /// yet.
+    ///
+    /// Only a single upload request will be in flight at a time.
+    pub async fn upload_request(&self) -> StoreResult<Option<KeysBackupRequest>> {
+        let upload_key = self.upload_key.read().await;
+
+        let upload_key = if let Some(k) = upload_key.as_ref() {
+            k
+        } else {
+            return Ok(None);
+        };
+
+        if !self.uploads_in_flight.is_empty() {
+            return Ok(None);
+        }
+
+        // Don't go through the store if we know that everything is backed up.
+        if self.store.backup_progress().await?.remaining() == 0 {
+            return Ok(None);
+        }
+
+        let sessions: Vec<InboundGroupSession> = self
+            .store
+            .inbound_group_sessions_for_backup_stream()
+            .take(Self::MAX_UPLOAD_BATCH)
+            .try_collect()
+            .await?;
+
+        if sessions.is_empty() {
+            return Ok(None);
+        }
+
+        let mut rooms = BTreeMap::new();
+        let mut verified_senders = BTreeMap::new();
+
+        for session in &sessions {
+            let is_verified = match verified_senders.get(session.sender_key()) {
+                Some(v) => *v,
+                None => {
+                    let verified = self.is_sender_verified(session.sender_key()).await?;
+                    verified_senders.insert(session.sender_key().to_owned(), verified);
+                    verified
+                }
+            };
+
+            let is_verified = is_verified && session.source().is_direct();
+            let data = encrypt_for_backup(&upload_key.public_key, session, is_verified).await;
+
+            rooms
+                .entry(session.room_id().to_owned())
+                .or_insert_with(BTreeMap::new)
+                .insert(session.session_id().to_owned(), data);
+        }
+
+        let request_id = Uuid::new_v4();
+        self.uploads_in_flight.insert(request_id, (upload_key.version.clone(), sessions));
+
+        Ok(Some(KeysBackupRequest { request_id, version: upload_key.version.clone(), rooms }))
+    }
+
+    /// Is the device with the given Curve25519 key verified.
+    ///
+    /// Room keys don't know which user they belong to, so the devices of all
+    /// the users we track are considered.
+    async fn is_sender_verified(&self, sender_key: &str) -> StoreResult<bool> {
+        if sender_key == self.account.identity_keys().curve25519() {
+            return Ok(true);
+        }
+
+        let mut users = self.store.tracked_users();
+        users.insert(self.account.user_id().clone());
+
+        for user_id in users {
+            if let Some(device) = self.store.get_device_from_curve_key(&user_id, sender_key).await?
+            {
+                return Ok(device.trust_state());
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Mark the upload request with the given id as successfully sent.
+    pub async fn mark_upload_as_sent(&self, request_id: &Uuid) -> StoreResult<()> {
+        let (version, sessions) =
+            if let Some((_, request)) = self.uploads_in_flight.remove(request_id) {
+                request
+            } else {
+                return Ok(());
+            };
+
+        let is_current =
+            self.upload_key.read().await.as_ref().map(|k| k.version == version).unwrap_or(false);
+
+        if is_current {
+            let session_ids: Vec<_> =
+                sessions.iter().map(|s| (s.room_id(), s.sender_key(), s.session_id())).collect();
+
+            self.store.mark_inbound_group_sessions_as_backed_up(&version, &session_ids).await?;
+
+            for session in &sessions {
+                session.mark_as_backed_up(&version);
+            }
+
+            info!("Uploaded {} room keys to the backup", sessions.len());
+        }
+
+        Ok(())
+    }
+
+    /// Mark the upload request with the given id as failed.
+    ///
+    /// If the backup version we're uploading to isn't the current version
+    /// anymore, uploads will stop until a new version is enabled.
+    pub async fn mark_upload_as_failed(&self, request_id: &Uuid, failure: BackupUploadFailure) {
+        self.uploads_in_flight.remove(request_id);
+
+        if failure == BackupUploadFailure::WrongVersion {
+            warn!("The backup version changed, stopping the upload of room keys");
+            self.upload_key.write().await.take();
+        }
+    }
+
+    /// Get the progress of the upload of our room keys.
+    pub async fn progress(&self) -> StoreResult<BackupProgress> {
+        self.store.backup_progress().await
+    }
+
+    /// Check the signatures of the `auth_data` of a
+    /// `m.megolm_backup.v1.curve25519-aes-sha2` backup version.
+    pub async fn verify_backup(&self, auth_data: &Value) -> StoreResult<BackupTrust> {
+        let mut trust = BackupTrust::default();
+
+        if let Some(download_key) = self.download_key.read().await.as_ref() {
+            let public_key = auth_data.get("public_key").and_then(|k| k.as_str());
+            trust.matches_decryption_key =
+                Some(public_key == Some(download_key.key.public_key().as_str()));
+        }
+
+        let user_id = self.account.user_id();
+
+        let key_ids: Vec<DeviceKeyId> = auth_data
+            .get("signatures")
+            .and_then(|s| s.get(user_id.as_str()))
+            .and_then(|s| s.as_object())
+            .map(|s| s.keys().filter_map(|k| DeviceKeyId::try_from(k.as_str()).ok()).collect())
+            .unwrap_or_default();
+
+        let own_identity =
+            self.store.get_user_identity(user_id).await?.and_then(|i| i.own().cloned());
+        let utility = Utility::new();
+        let mut auth_data = auth_data.clone();
+
+        for key_id in key_ids {
+            if key_id.algorithm() != DeviceKeyAlgorithm::Ed25519 {
+                continue;
+            }
+
+            let mut is_valid =
+                |key: &str| utility.verify_json(user_id, &key_id, key, &mut auth_data).is_ok();
+
+            if key_id.device_id() == self.account.device_id() {
+                trust.device_signature = is_valid(self.account.identity_keys().ed25519());
+            } else if let Some(master_key) =
+                own_identity.as_ref().and_then(|i| i.master_key().get_key(&key_id))
+            {
+                trust.identity_signature = is_valid(master_key)
+                    && own_identity.as_ref().map(|i| i.is_verified()).unwrap_or(false);
+            } else if let Some(device) =
+                self.store.get_readonly_device(user_id, key_id.device_id()).await?
+            {
+                let is_signed = device
+                    .get_key(DeviceKeyAlgorithm::Ed25519)
+                    .map(|k| is_valid(k))
+                    .unwrap_or(false);
+
+                if is_signed {
+                    let device_owner = own_identity.clone().map(UserIdentities::Own);
+                    let trusted = device.trust_state(&own_identity, &device_owner);
+
+                    trust.device_signatures.insert(device.device_id().into(), trusted);
+                }
+            }
+        }
+
+        Ok(trust)
+    }
+
+    /// Enable the download of missing room keys from the backup with the
+    /// given version.
+    pub async fn enable_download(&self, version: String, key: BackupDecryptionKey) {
+        *self.download_key.write().await = Some(DownloadKey { version, key });
+        // A different backup might contain the keys the old one didn't have.
+        self.not_in_backup.clear();
+    }
+
+    /// Disable the download of missing room keys.
+    pub async fn disable_download(&self) {
+        self.download_key.write().await.take();
+        self.pending_downloads.clear();
+        self.downloads_in_flight.clear();
+        self.not_in_backup.clear();
+    }
+
+    fn is_known_missing(&self, key: &(RoomId, String)) -> bool {
+        self.not_in_backup
+            .get(key)
+            .map(|t| t.elapsed() < Self::NOT_IN_BACKUP_TIMEOUT)
+            .unwrap_or(false)
+    }
+
+    fn is_download_in_flight(&self, room_id: &RoomId, session_id: &str) -> bool {
+        self.downloads_in_flight.iter().any(|r| {
+            r.value().1.get(room_id).map(|s| s.iter().any(|s| s == session_id)).unwrap_or(false)
+        })
+    }
+
+    /// Queue up the room keys of a download request again.
+    fn requeue_download(&self, rooms: BTreeMap<RoomId, Vec<String>>) {
+        for (room_id, session_ids) in rooms {
+            for session_id in session_ids {
+                self.pending_downloads.insert((room_id.clone(), session_id));
+            }
+        }
+    }
+
+    /// Queue up the room keys of the download requests that didn't get a
+    /// response in time again.
+    fn requeue_expired_downloads(&self) {
+        let expired: Vec<Uuid> = self
+            .downloads_in_flight
+            .iter()
+            .filter(|r| r.value().2.elapsed() >= Self::DOWNLOAD_TIMEOUT)
+            .map(|r| *r.key())
+            .collect();
+
+        for request_id in expired {
+            if let Some((_, (_, rooms, _))) = self.downloads_in_flight.remove(&request_id) {
+                warn!("The backup download request {} timed out, retrying it", request_id);
+                self.requeue_download(rooms);
+            }
+        }
+    }
+
+    /// Queue up a missing room key to be downloaded from the backup.
+    ///
+    /// This is a noop if downloading room keys isn't enabled or if we recently
+    /// found out that the backup doesn't contain the room key.
+    pub async fn queue_missing_session(&self, room_id: &RoomId, session_id: &str) {
+        if self.download_key.read().await.is_none() {
+            return;
+        }
+
+        let key = (room_id.clone(), session_id.to_owned());
+
+        // Room keys that are already being downloaded will be queued up again
+        // if their download fails.
+        if !self.is_known_missing(&key) && !self.is_download_in_flight(room_id, session_id) {
+            self.pending_downloads.insert(key);
+        }
+    }
+
+    /// Get a request to download the queued up room keys, if there are any.
+    pub async fn download_request(&self) -> Option<KeysBackupDownloadRequest> {
+        let version = self.download_key.read().await.as_ref()?.version.clone();
+
+        self.requeue_expired_downloads();
+
+        let batch: Vec<(RoomId, String)> = self
+            .pending_downloads
+            .iter()
+            .take(Self::MAX_DOWNLOAD_BATCH)
+            .map(|k| k.key().clone())
+            .collect();
+
+        if batch.is_empty() {
+            return None;
+        }
+
+        let mut rooms = BTreeMap::new();
+
+        for key in batch {
+            self.pending_downloads.remove(&key);
+            let (room_id, session_id) = key;
+            rooms.entry(room_id).or_insert_with(Vec::new).push(session_id);
+        }
+
+        let request_id = Uuid::new_v4();
+        self.downloads_in_flight
+            .insert(request_id, (version.clone(), rooms.clone(), Instant::now()));
+
+        Some(KeysBackupDownloadRequest { request_id, version, rooms })
+    }
+
+    /// Mark the download request with the given id as failed, its room keys
+    /// will be part of the next download request again.
+    pub fn mark_download_as_failed(&self, request_id: &Uuid) {
+        if let Some((_, (_, rooms, _))) = self.downloads_in_flight.remove(request_id) {
+            self.requeue_download(rooms);
+        }
+    }
+
+    /// Receive the room keys that were downloaded for a download request.
+    ///
+    /// Returns the room and session ids of the room keys that were restored,
+    /// events that were encrypted with those can now be decrypted.
+    pub async fn receive_backed_up_keys(
+        &self,
+        request_id: &Uuid,
+        mut keys: BTreeMap<RoomId, BTreeMap<String, KeyBackupData>>,
+    ) -> StoreResult<Vec<(RoomId, String)>> {
+        let (version, requested) = if let Some((_, (version, requested, _))) =
+            self.downloads_in_flight.remove(request_id)
+        {
+            (version, requested)
+        } else {
+            warn!("Received room keys for an unknown backup download request {}", request_id);
+            return Ok(Vec::new());
+        };
+
+        let download_key = self.download_key.read().await;
+
+        let key = match download_key.as_ref() {
+            Some(k) if k.version == version => &k.key,
+            _ => {
+                info!("The backup version changed while downloading room keys, dropping them");
+                return Ok(Vec::new());
+            }
+        };
+
+        let mut restored = Vec::new();
+        let mut sessions = Vec::new();
+
+        for (room_id, session_ids) in requested {
+            let mut room_keys = keys.remove(&room_id).unwrap_or_default();
+
+            for session_id in session_ids {
+                let session = room_keys
+                    .remove(&session_id)
+                    .and_then(|k| Self::restore_session(key, &version, &room_id, &session_id, &k));
+
+                if let Some(session) = session {
+                    let existing = self
+                        .store
+                        .get_inbound_group_session(&room_id, session.sender_key(), &session_id)
+                        .await?;
+
+                    let is_better = existing
+                        .map(|e| e.first_known_index() > session.first_known_index())
+                        .unwrap_or(true);
+
+                    if is_better {
+                        sessions.push(session);
+                    }
+
+                    restored.push((room_id.clone(), session_id));
+                } else {
+                    self.not_in_backup.insert((room_id.clone(), session_id), Instant::now());
+                }
+            }
+        }
+
+        info!("Restored {} room keys from the backup", restored.len());
+
+        let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
+        self.store.save_changes(changes).await?;
+
+        Ok(restored)
+    }
+
+    fn restore_session(
+        key: &BackupDecryptionKey,
+        version: &str,
+        room_id: &RoomId,
+        session_id: &str,
+        data: &KeyBackupData,
+    ) -> Option<InboundGroupSession> {
+        let room_key = match key.decrypt_session_data(&data.session_data) {
+            Ok(k) => k,
+            Err(e) => {
+                warn!("Failed to decrypt the backed up room key {}: {}", log_id(session_id), e);
+                return None;
+            }
+        };
+
+        if room_key.algorithm != EventEncryptionAlgorithm::MegolmV1AesSha2 {
+            warn!("Backed up room key {} uses an unsupported algorithm", log_id(session_id));
+            return None;
+        }
+
+        // Don't trust the server to give us the session we asked for.
+        match OlmInboundGroupSession::import(&room_key.session_key.0) {
+            Ok(s) if s.session_id() == session_id => {}
+            _ => {
+                warn!("Backed up room key {} doesn't match its session id", log_id(session_id));
+                return None;
+            }
+        }
+
+        let session = InboundGroupSession::from_backup(
+            room_key.into_export(room_id.clone(), session_id.into()),
+        )
+        .map_err(|e| {
+            warn!("Failed to restore the backed up room key {}: {}", log_id(session_id), e)
+        })
+        .ok()?;
+
+        // The key came from the backup, there's no need to upload it again.
+        session.mark_as_backed_up(version);
+
+        Some(session)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use matrix_sdk_common::locks::Mutex;
+    use matrix_sdk_test::async_test;
+    use olm_rs::pk::OlmPkEncryption;
+    use ruma::{room_id, user_id, DeviceIdBox, UserId};
+    use serde_json::json;
+
+    use super::{
+        BackupDecryptionKey, BackupMachine, BackupUploadFailure, EncryptedSessionData,
+        KeyBackupData, BACKUP_SECRET_NAME,
+    };
+    use crate::{
+        olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
+        secret_storage::SecretStorageKey,
+        store::{CryptoStore, MemoryStore, Store},
+        verification::VerificationMachine,
+    };
+
+    fn user_id() -> UserId {
+        user_id!("@example:localhost")
+    }
+
+    fn device_id() -> DeviceIdBox {
+        "WSKKLTJZCL".into()
+    }
+
+    fn backup_machine() -> (ReadOnlyAccount, BackupMachine) {
+        let identity = Arc::new(Mutex::new(PrivateCrossSigningIdentity::empty(user_id())));
+        let user_id = Arc::new(user_id());
+        let account = ReadOnlyAccount::new(&user_id, &device_id());
+        let store: Arc<dyn CryptoStore> = Arc::new(MemoryStore::new());
+        let verification = VerificationMachine::new(account.clone(), identity.clone(), store);
+        let store = Store::new(user_id, identity, Arc::new(MemoryStore::new()), verification);
+
+        (account.clone(), BackupMachine::new(account, store))
+    }
+
+    #[async_test]
+    async fn restore_missing_session() {
+        let (account, machine) = backup_machine();
+        let room_id = room_id!("!test:localhost");
+        let key = BackupDecryptionKey::new();
+
+        let (_, session) = account.create_group_session_pair_with_defaults(&room_id).await.unwrap();
+        let session_id = session.session_id().to_owned();
+
+        machine.queue_missing_session(&room_id, &session_id).await;
+        assert!(machine.download_request().await.is_none());
+
+        machine.enable_download("1".to_owned(), key.clone()).await;
+        machine.queue_missing_session(&room_id, &session_id).await;
+        machine.queue_missing_session(&room_id, "missing").await;
+
+        let request = machine.download_request().await.unwrap();
+        assert_eq!(request.version, "1");
+        assert_eq!(request.rooms[&room_id].len(), 2);
+
+        let plaintext = serde_json::to_string(&session.export().await).unwrap();
+        let message = OlmPkEncryption::new(&key.public_key()).encrypt(&plaintext);
+
+        let data = KeyBackupData {
+            first_message_index: 0,
+            forwarded_count: 0,
+            is_verified: false,
+            session_data: EncryptedSessionData {
+                ephemeral: message.ephemeral_key,
+                ciphertext: message.ciphertext,
+                mac: message.mac,
+            },
+        };
+
+        let mut room_keys = BTreeMap::new();
+        room_keys.insert(session_id.clone(), data);
+        let mut keys = BTreeMap::new();
+        keys.insert(room_id.clone(), room_keys);
+
+        let restored = machine.receive_backed_up_keys(&request.request_id, keys).await.unwrap();
+        assert_eq!(restored, vec![(room_id.clone(), session_id.clone())]);
+
+        assert!(machine
+            .store
+            .get_inbound_group_session(&room_id, session.sender_key(), &session_id)
+            .await
+            .unwrap()
+            .is_some());
+
+        // The backup didn't contain the other session, don't ask for it again.
+        machine.queue_missing_session(&room_id, "missing").await;
+        assert!(machine.download_request().await.is_none());
+    }
+
+    #[async_test]
+    async fn retry_failed_download() {
+        let (_, machine) = backup_machine();
+        let room_id = room_id!("!test:localhost");
+
+        machine.enable_download("1".to_owned(), BackupDecryptionKey::new()).await;
+        machine.queue_missing_session(&room_id, "session").await;
+
+        let request = machine.download_request().await.unwrap();
+
+        // The room key is already being downloaded, it waits for the request.
+        machine.queue_missing_session(&room_id, "session").await;
+        assert!(machine.download_request().await.is_none());
+
+        machine.mark_download_as_failed(&request.request_id);
+        assert!(machine.downloads_in_flight.is_empty());
+
+        let request = machine.download_request().await.unwrap();
+        assert_eq!(request.rooms[&room_id], ["session"]);
+    }
+
+    #[async_test]
+    async fn upload_room_keys() {
+        let (account, machine) = backup_machine();
+        let room_id = room_id!("!test:localhost");
+        let key = BackupDecryptionKey::new();
+
+        let (_, session) = account.create_group_session_pair_with_defaults(&room_id).await.unwrap();
+        machine.store.save_inbound_group_sessions(&[session.clone()]).await.unwrap();
+
+        assert!(machine.upload_request().await.unwrap().is_none());
+
+        machine.enable_upload("1".to_owned(), key.public_key()).await.unwrap();
+        assert_eq!(machine.progress().await.unwrap().remaining(), 1);
+
+        let request = machine.upload_request().await.unwrap().unwrap();
+        assert_eq!(request.key_count(), 1);
+        // Only one request is in flight at a time.
+        assert!(machine.upload_request().await.unwrap().is_none());
+
+        let data = &request.rooms[&room_id][session.session_id()];
+        let decrypted = key.decrypt_session_data(&data.session_data).unwrap();
+        assert_eq!(decrypted.sender_key, session.sender_key());
+        // The room key was created by this device.
+        assert!(data.is_verified);
+
+        machine.mark_upload_as_failed(&request.request_id, BackupUploadFailure::Other).await;
+        let request = machine.upload_request().await.unwrap().unwrap();
+        machine.mark_upload_as_sent(&request.request_id).await.unwrap();
+
+        assert_eq!(machine.progress().await.unwrap().remaining(), 0);
+        assert!(machine.upload_request().await.unwrap().is_none());
+
+        // New room keys are counted without going through the store again.
+        let (_, other) = account.create_group_session_pair_with_defaults(&room_id).await.unwrap();
+        machine.store.save_inbound_group_sessions(&[other]).await.unwrap();
+
+        let progress = machine.progress().await.unwrap();
+        assert_eq!(progress.total, 2);
+        assert_eq!(progress.remaining(), 1);
+
+        let request = machine.upload_request().await.unwrap().unwrap();
+        machine.mark_upload_as_sent(&request.request_id).await.unwrap();
+        assert_eq!(machine.progress().await.unwrap().remaining(), 0);
+
+        // A new backup version means that we need to upload everything again.
+        machine.enable_upload("2".to_owned(), key.public_key()).await.unwrap();
+        let request = machine.upload_request().await.unwrap().unwrap();
+
+        machine.mark_upload_as_failed(&request.request_id, BackupUploadFailure::WrongVersion).await;
+        assert!(machine.upload_request().await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn verify_backup_signatures() {
+        let (account, machine) = backup_machine();
+        let key = BackupDecryptionKey::new();
+
+        let mut auth_data = json!({ "public_key": key.public_key() });
+        let signature = account.sign_json(auth_data.clone()).await;
+        auth_data["signatures"] = json!({
+            account.user_id().as_str(): {
+                format!("ed25519:{}", account.device_id()): signature,
+            }
+        });
+
+        let trust = machine.verify_backup(&auth_data).await.unwrap();
+        assert!(trust.device_signature);
+        assert_eq!(trust.matches_decryption_key, None);
+        assert!(trust.is_trusted());
+
+        machine.enable_download("1".to_owned(), BackupDecryptionKey::new()).await;
+        let trust = machine.verify_backup(&auth_data).await.unwrap();
+        assert_eq!(trust.matches_decryption_key, Some(false));
+        assert!(!trust.is_trusted());
+
+        auth_data["public_key"] = json!("tampered");
+        machine.disable_download().await;
+        let trust = machine.verify_backup(&auth_data).await.unwrap();
+        assert!(!trust.device_signature);
+        assert!(!trust.is_trusted());
+    }
+
+    #[async_test]
+    async fn reset_backup() {
+        let (account, machine) = backup_machine();
+        let room_id = room_id!("!test:localhost");
+
+        let (_, session) = account.create_group_session_pair_with_defaults(&room_id).await.unwrap();
+        machine.store.save_inbound_group_sessions(&[session]).await.unwrap();
+
+        let old_backup = machine.create_backup().await;
+        machine.enable_upload("1".to_owned(), old_backup.public_key()).await.unwrap();
+        let request = machine.upload_request().await.unwrap().unwrap();
+        machine.mark_upload_as_sent(&request.request_id).await.unwrap();
+        assert_eq!(machine.progress().await.unwrap().remaining(), 0);
+
+        machine.delete_backup().await.unwrap();
+        assert_eq!(machine.progress().await.unwrap().remaining(), 1);
+        assert!(machine.upload_request().await.unwrap().is_none());
+
+        let new_backup = machine.create_backup().await;
+        assert_ne!(old_backup.public_key(), new_backup.public_key());
+        assert!(machine.verify_backup(&new_backup.auth_data).await.unwrap().device_signature);
+
+        let secret_storage_key = SecretStorageKey::new();
+        let content = new_backup.secret_storage_content(&secret_storage_key);
+        let secret =
+            secret_storage_key.decrypt_secret_content(BACKUP_SECRET_NAME, &content).unwrap();
+        assert_eq!(secret, new_backup.decryption_key.to_base64());
+
+        machine.enable_upload("2".to_owned(), new_backup.public_key()).await.unwrap();
+        assert!(machine.upload_request().await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+use dashmap::DashMap;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use ruma::{EventId, RoomId};
+use tracing::debug;
+
+use crate::{olm::InboundGroupSession, utilities::log_id};
+
+/// The maximal number of events we remember for a single missing group
+/// session.
+const MAX_EVENTS_PER_SESSION: usize = 100;
+
+/// The maximal number of missing group sessions we keep track of.
+const MAX_TRACKED_SESSIONS: usize = 1000;
+
+/// Notification that room keys, which were missing while decrypting some
+/// events, were received.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomKeysReceived {
+    /// The room the room keys belong to.
+    pub room_id: RoomId,
+    /// The unique ids of the group sessions that were received.
+    pub session_ids: Vec<String>,
+    /// The events that failed to decrypt because the group sessions were
+    /// missing, they should be decrypted again.
+    pub event_ids: Vec<EventId>,
+}
+
+/// Remembers which events failed to decrypt because of a missing group
+/// session, and notifies the subscribers once the group session arrives.
+///
+/// Only a bounded number of sessions and events per session are remembered,
+/// a misbehaving room can't make us grow without limits.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DecryptionRetryQueue {
+    failures: Arc<DashMap<(RoomId, String), BTreeSet<EventId>>>,
+    subscribers: Arc<Mutex<Vec<UnboundedSender<RoomKeysReceived>>>>,
+}
+
+impl DecryptionRetryQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember that the given event failed to decrypt because the group
+    /// session with the given session id is missing.
+    pub fn record_failure(&self, room_id: &RoomId, session_id: &str, event_id: &EventId) {
+        let key = (room_id.to_owned(), session_id.to_owned());
+
+        if !self.failures.contains_key(&key) && self.failures.len() >= MAX_TRACKED_SESSIONS {
+            debug!(
+                room_id = %log_id(room_id.as_str()),
+                session_id = %log_id(session_id),
+                "Too many missing group sessions are tracked, not retrying the decryption"
+            );
+            return;
+        }
+
+        let mut events = self.failures.entry(key).or_insert_with(BTreeSet::new);
+
+        if events.len() < MAX_EVENTS_PER_SESSION {
+            events.insert(event_id.to_owned());
+        }
+    }
+
+    /// Notify the subscribers about the group sessions that arrived and that
+    /// we were waiting for.
+    pub fn sessions_received(&self, sessions: &[InboundGroupSession]) {
+        let mut received: BTreeMap<RoomId, RoomKeysReceived> = BTreeMap::new();
+
+        for session in sessions {
+            let key = (session.room_id().to_owned(), session.session_id().to_owned());
+
+            if let Some((_, events)) = self.failures.remove(&key) {
+                let entry = received.entry(session.room_id().to_owned()).or_insert_with(|| {
+                    RoomKeysReceived {
+                        room_id: session.room_id().to_owned(),
+                        session_ids: Vec::new(),
+                        event_ids: Vec::new(),
+                    }
+                });
+
+                entry.session_ids.push(session.session_id().to_owned());
+                entry.event_ids.extend(events);
+            }
+        }
+
+        if received.is_empty() {
+            return;
+        }
+
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        for (_, info) in received {
+            subscribers.retain(|s| s.unbounded_send(info.clone()).is_ok());
+        }
+    }
+
+    /// Get a stream of notifications about received room keys that were
+    /// missing.
+    pub fn subscribe(&self) -> UnboundedReceiver<RoomKeysReceived> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+
+        receiver
+    }
+}
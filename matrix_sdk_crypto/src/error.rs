@@ -12,12 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use matrix_sdk_common::uuid::Uuid;
 use olm_rs::errors::{OlmGroupSessionError, OlmSessionError};
-use ruma::{identifiers::Error as IdentifierError, DeviceId, UserId};
+use ruma::{
+    events::key::verification::VerificationMethod, identifiers::Error as IdentifierError, DeviceId,
+    DeviceIdBox, EventEncryptionAlgorithm, UserId,
+};
 use serde_json::Error as SerdeError;
 use thiserror::Error;
 
 use super::store::CryptoStoreError;
+use crate::{
+    olm::{RoomKeySource, WithheldCode},
+    RequestType,
+};
 
 pub type OlmResult<T> = Result<T, OlmError>;
 pub type MegolmResult<T> = Result<T, MegolmError>;
@@ -27,8 +35,8 @@ pub type MegolmResult<T> = Result<T, MegolmError>;
 #[derive(Error, Debug)]
 pub enum OlmError {
     /// The event that should have been decrypted is malformed.
-    #[error(transparent)]
-    EventError(#[from] EventError),
+    #[error("{0}")]
+    EventError(#[source] EventError, ErrorContext),
 
     /// The received decrypted event couldn't be deserialized.
     #[error(transparent)]
@@ -36,11 +44,11 @@ pub enum OlmError {
 
     /// The underlying Olm session operation returned an error.
     #[error("can't finish Olm Session operation {0}")]
-    OlmSession(#[from] OlmSessionError),
+    OlmSession(#[source] OlmSessionError, ErrorContext),
 
     /// The underlying group session operation returned an error.
     #[error("can't finish Olm Session operation {0}")]
-    OlmGroupSession(#[from] OlmGroupSessionError),
+    OlmGroupSession(#[source] OlmGroupSessionError, ErrorContext),
 
     /// The storage layer returned an error.
     #[error("failed to read or write to the crypto store {0}")]
@@ -63,15 +71,116 @@ pub enum OlmError {
         "encryption failed because the device does not \
             have a valid Olm session with us"
     )]
-    MissingSession,
+    MissingSession(ErrorContext),
+
+    /// The sharing of a room key was cancelled using a
+    /// [`RoomKeySharingProgress`](crate::RoomKeySharingProgress) handle.
+    #[error("the sharing of the room key was cancelled")]
+    RoomKeySharingCancelled,
+
+    /// The response that was passed to `mark_request_as_sent()` doesn't
+    /// belong to the type of request that was handed out with the given
+    /// request id.
+    #[error(
+        "received a {received:?} response for the request {request_id}, \
+         but a {expected:?} response was expected"
+    )]
+    MismatchedResponse {
+        /// The id of the request.
+        request_id: Uuid,
+        /// The type of the request that was handed out.
+        expected: RequestType,
+        /// The type of the response that was received.
+        received: RequestType,
+    },
+
+    /// The response that was passed to `mark_request_as_sent()` belongs to a
+    /// request id that we didn't hand out, or that we forgot about because we
+    /// didn't get a response for it in time.
+    #[error("received a {received:?} response for the unknown request {request_id}")]
+    UnknownRequest {
+        /// The id of the request.
+        request_id: Uuid,
+        /// The type of the response that was received.
+        received: RequestType,
+    },
+}
+
+impl OlmError {
+    /// Get a stable, machine readable code describing the kind of the error.
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            OlmError::EventError(..) => "malformed_event",
+            OlmError::JsonError(_) => "invalid_json",
+            OlmError::OlmSession(..) => "olm_session_error",
+            OlmError::OlmGroupSession(..) => "olm_group_session_error",
+            OlmError::Store(_) => "store_error",
+            OlmError::SessionWedged(..) => "session_wedged",
+            OlmError::ReplayedMessage(..) => "replayed_message",
+            OlmError::MissingSession(_) => "missing_olm_session",
+            OlmError::RoomKeySharingCancelled => "room_key_sharing_cancelled",
+            OlmError::MismatchedResponse { .. } => "mismatched_response",
+            OlmError::UnknownRequest { .. } => "unknown_request",
+        }
+    }
+
+    /// Get the identifiers of the user, device and session that were
+    /// involved in the failed operation, as far as they are known.
+    pub fn context(&self) -> ErrorContext {
+        match self {
+            OlmError::SessionWedged(user_id, sender_key)
+            | OlmError::ReplayedMessage(user_id, sender_key) => ErrorContext {
+                user_id: Some(user_id.clone()),
+                sender_key: Some(sender_key.clone()),
+                ..Default::default()
+            },
+            OlmError::MissingSession(context)
+            | OlmError::EventError(_, context)
+            | OlmError::OlmSession(_, context)
+            | OlmError::OlmGroupSession(_, context) => context.clone(),
+            _ => ErrorContext::default(),
+        }
+    }
+
+    /// Attach the given context to the error if the error doesn't carry one
+    /// yet.
+    pub(crate) fn with_context(mut self, new_context: ErrorContext) -> Self {
+        match &mut self {
+            OlmError::MissingSession(context)
+            | OlmError::EventError(_, context)
+            | OlmError::OlmSession(_, context)
+            | OlmError::OlmGroupSession(_, context) => context.fill_from(new_context),
+            _ => {}
+        }
+
+        self
+    }
+}
+
+impl From<EventError> for OlmError {
+    fn from(e: EventError) -> Self {
+        OlmError::EventError(e, ErrorContext::default())
+    }
+}
+
+impl From<OlmSessionError> for OlmError {
+    fn from(e: OlmSessionError) -> Self {
+        OlmError::OlmSession(e, ErrorContext::default())
+    }
+}
+
+impl From<OlmGroupSessionError> for OlmError {
+    fn from(e: OlmGroupSessionError) -> Self {
+        OlmError::OlmGroupSession(e, ErrorContext::default())
+    }
 }
 
 /// Error representing a failure during a group encryption operation.
 #[derive(Error, Debug)]
 pub enum MegolmError {
     /// The event that should have been decrypted is malformed.
-    #[error(transparent)]
-    EventError(#[from] EventError),
+    #[error("{0}")]
+    EventError(#[source] EventError, ErrorContext),
 
     /// The received decrypted event couldn't be deserialized.
     #[error(transparent)]
@@ -80,21 +189,120 @@ pub enum MegolmError {
     /// Decryption failed because the session needed to decrypt the event is
     /// missing.
     #[error("decryption failed because the session to decrypt the message is missing")]
-    MissingSession,
+    MissingSession(ErrorContext),
+
+    /// Decryption failed because the sender withheld the session needed to
+    /// decrypt the event from us.
+    #[error("decryption failed because the sender withheld the session: {0}")]
+    Withheld(WithheldCode, ErrorContext),
+
+    /// Decryption was refused because the session needed to decrypt the
+    /// event wasn't received directly from the sender, and the
+    /// `IndirectRoomKeyPolicy` rejects such sessions.
+    #[error("decryption was refused because the session was received indirectly: {0:?}")]
+    IndirectRoomKey(RoomKeySource, ErrorContext),
 
     /// The underlying group session operation returned an error.
     #[error("can't finish Olm group session operation {0}")]
-    OlmGroupSession(#[from] OlmGroupSessionError),
+    OlmGroupSession(#[source] OlmGroupSessionError, ErrorContext),
 
     /// The room where a group session should be shared is not encrypted.
     #[error("The room where a group session should be shared is not encrypted")]
     EncryptionNotEnabled,
 
+    /// The room is configured to use an encryption algorithm that we don't
+    /// support.
+    #[error("the room encryption algorithm {0} isn't supported")]
+    UnsupportedAlgorithm(EventEncryptionAlgorithm),
+
     /// The storage layer returned an error.
     #[error(transparent)]
     Store(#[from] CryptoStoreError),
 }
 
+impl MegolmError {
+    /// Get a stable, machine readable code describing the kind of the error.
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            MegolmError::EventError(..) => "malformed_event",
+            MegolmError::JsonError(_) => "invalid_json",
+            MegolmError::MissingSession(_) => "missing_megolm_session",
+            MegolmError::Withheld(..) => "withheld_session",
+            MegolmError::IndirectRoomKey(..) => "indirect_room_key",
+            MegolmError::OlmGroupSession(..) => "olm_group_session_error",
+            MegolmError::EncryptionNotEnabled => "encryption_not_enabled",
+            MegolmError::UnsupportedAlgorithm(_) => "unsupported_algorithm",
+            MegolmError::Store(_) => "store_error",
+        }
+    }
+
+    /// Get the identifiers of the user, device and session that were
+    /// involved in the failed operation, as far as they are known.
+    pub fn context(&self) -> ErrorContext {
+        match self {
+            MegolmError::MissingSession(context)
+            | MegolmError::Withheld(_, context)
+            | MegolmError::IndirectRoomKey(_, context)
+            | MegolmError::EventError(_, context)
+            | MegolmError::OlmGroupSession(_, context) => context.clone(),
+            _ => ErrorContext::default(),
+        }
+    }
+
+    /// Attach the given context to the error if the error doesn't carry one
+    /// yet.
+    pub(crate) fn with_context(mut self, new_context: ErrorContext) -> Self {
+        match &mut self {
+            MegolmError::MissingSession(context)
+            | MegolmError::Withheld(_, context)
+            | MegolmError::IndirectRoomKey(_, context)
+            | MegolmError::EventError(_, context)
+            | MegolmError::OlmGroupSession(_, context) => context.fill_from(new_context),
+            _ => {}
+        }
+
+        self
+    }
+}
+
+impl From<EventError> for MegolmError {
+    fn from(e: EventError) -> Self {
+        MegolmError::EventError(e, ErrorContext::default())
+    }
+}
+
+impl From<OlmGroupSessionError> for MegolmError {
+    fn from(e: OlmGroupSessionError) -> Self {
+        MegolmError::OlmGroupSession(e, ErrorContext::default())
+    }
+}
+
+/// The identifiers that were involved in a failed cryptographic operation.
+///
+/// Fields are `None` if the identifier isn't known or doesn't apply to the
+/// operation that failed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The user the event was sent by or should have been sent to.
+    pub user_id: Option<UserId>,
+    /// The device of the user.
+    pub device_id: Option<DeviceIdBox>,
+    /// The Curve25519 key of the sender device.
+    pub sender_key: Option<String>,
+    /// The id of the Olm or Megolm session.
+    pub session_id: Option<String>,
+}
+
+impl ErrorContext {
+    /// Fill in the identifiers that aren't known yet from the other context.
+    fn fill_from(&mut self, other: ErrorContext) {
+        self.user_id = self.user_id.take().or(other.user_id);
+        self.device_id = self.device_id.take().or(other.device_id);
+        self.sender_key = self.sender_key.take().or(other.sender_key);
+        self.session_id = self.session_id.take().or(other.session_id);
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum EventError {
     #[error("the Olm message has a unsupported type")]
@@ -158,10 +366,47 @@ pub enum SignatureError {
     #[error("the signature didn't match the provided key")]
     VerificationError,
 
+    #[error("the ed25519 key of the device differs from the previously known one")]
+    KeyChanged,
+
     #[error(transparent)]
     JsonError(#[from] SerdeError),
 }
 
+/// Error describing why a verification request can't transition into a
+/// verification flow.
+#[derive(Error, Debug)]
+pub enum VerificationRequestError {
+    /// The request isn't yet, or isn't anymore, in the ready state.
+    #[error("the verification request isn't in the ready state")]
+    NotReady,
+
+    /// The method isn't supported by both sides of the verification request.
+    #[error("the verification method {0:?} isn't supported by both sides")]
+    UnsupportedMethod(VerificationMethod),
+
+    /// The device that accepted the verification request isn't known to us.
+    #[error("the device {1} of {0} that accepted the verification request is unknown")]
+    UnknownDevice(UserId, DeviceIdBox),
+
+    /// A verification flow was already started for this verification request.
+    #[error("a verification flow was already started for the verification request")]
+    AlreadyStarted,
+
+    /// The scanned QR code belongs to a different verification flow.
+    #[error("the scanned QR code belongs to a different verification flow")]
+    FlowIdMismatch,
+
+    /// The keys in the scanned QR code don't match the keys we trust, the
+    /// verification request was cancelled.
+    #[error("the keys in the scanned QR code don't match the keys we trust")]
+    KeyMismatch,
+
+    /// The crypto store failed to load the other device or identity.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
 #[derive(Error, Debug)]
 pub(crate) enum SessionCreationError {
     #[error(
@@ -12,12 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
 
 use aes_ctr::{
     cipher::{NewStreamCipher, SyncStreamCipher},
     Aes256Ctr,
 };
+use base64::{write::EncoderWriter, STANDARD_NO_PAD};
 use byteorder::{BigEndian, ReadBytesExt};
 use getrandom::getrandom;
 use hmac::{Hmac, Mac, NewMac};
@@ -25,9 +26,11 @@ use pbkdf2::pbkdf2;
 use serde_json::Error as SerdeError;
 use sha2::{Sha256, Sha512};
 use thiserror::Error;
+use zeroize::Zeroize;
 
 use crate::{
     olm::ExportedRoomKey,
+    store::CryptoStoreError,
     utilities::{decode, encode, DecodeError},
 };
 
@@ -64,6 +67,21 @@ pub enum KeyExportError {
     /// The key export doesn't all the required fields.
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    /// The room keys couldn't be loaded from the store.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// The progress, and once it's done the result, of a room key import.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RoomKeyImportResult {
+    /// The number of room keys that were imported.
+    pub imported_count: usize,
+    /// The number of room keys that were skipped because we already have the
+    /// same or a better version of the room key.
+    pub skipped_count: usize,
+    /// The total number of room keys that were found in the key export.
+    pub total_count: usize,
 }
 
 /// Try to decrypt a reader into a list of exported room keys.
@@ -147,6 +165,143 @@ pub fn encrypt_key_export(
     Ok([HEADER.to_owned(), ciphertext, FOOTER.to_owned()].join("\n"))
 }
 
+/// A writer that encrypts room keys into the key export format one by one.
+///
+/// Unlike [`encrypt_key_export`] this doesn't need to hold the whole export in
+/// memory, the encrypted export is written out as the room keys are added.
+///
+/// # Examples
+/// ```no_run
+/// # use std::io::Cursor;
+/// # use matrix_sdk_crypto::{OlmMachine, KeyExportWriter};
+/// # use ruma::{user_id, room_id};
+/// # use futures::executor::block_on;
+/// # let alice = user_id!("@alice:example.org");
+/// # let machine = OlmMachine::new(&alice, "DEVICEID".into());
+/// # block_on(async {
+/// let room_id = room_id!("!test:localhost");
+/// let mut writer = KeyExportWriter::new(Vec::new(), "1234", 100_000).unwrap();
+///
+/// for key in machine.export_keys(|s| s.room_id() == &room_id).await.unwrap() {
+///     writer.write_key(&key).unwrap();
+/// }
+///
+/// let encrypted_export = writer.finish().unwrap();
+/// # });
+/// ```
+pub struct KeyExportWriter<W: Write> {
+    encoder: EncoderWriter<W>,
+    aes: Aes256Ctr,
+    hmac: Hmac<Sha256>,
+    key_count: usize,
+}
+
+impl<W: Write> std::fmt::Debug for KeyExportWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyExportWriter").field("key_count", &self.key_count).finish()
+    }
+}
+
+impl<W: Write> KeyExportWriter<W> {
+    /// Create a new key export writer.
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - The writer the encrypted export will be written to.
+    ///
+    /// * `passphrase` - The passphrase that will be used to encrypt the
+    ///   exported
+    /// room keys.
+    ///
+    /// * `rounds` - The number of rounds that should be used for the key
+    /// derivation, see [`encrypt_key_export`].
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// encrypt the exported keys securely.
+    pub fn new(mut writer: W, passphrase: &str, rounds: u32) -> Result<Self, KeyExportError> {
+        let mut salt = [0u8; SALT_SIZE];
+        let mut iv = [0u8; IV_SIZE];
+        let mut derived_keys = [0u8; KEY_SIZE * 2];
+
+        getrandom(&mut salt).expect("Can't generate randomness");
+        getrandom(&mut iv).expect("Can't generate randomness");
+
+        let mut iv = u128::from_be_bytes(iv);
+        iv &= !(1 << 63);
+
+        pbkdf2::<Hmac<Sha512>>(passphrase.as_bytes(), &salt, rounds, &mut derived_keys);
+        let (key, hmac_key) = derived_keys.split_at(KEY_SIZE);
+
+        let aes = Aes256Ctr::new_var(key, &iv.to_be_bytes()).expect("Can't create AES object");
+        let mut hmac = Hmac::<Sha256>::new_varkey(hmac_key).expect("Can't create HMAC object");
+
+        let mut header: Vec<u8> = vec![];
+
+        header.extend(&VERSION.to_be_bytes());
+        header.extend(&salt);
+        header.extend(&iv.to_be_bytes());
+        header.extend(&rounds.to_be_bytes());
+
+        hmac.update(&header);
+
+        writer.write_all(HEADER.as_bytes())?;
+        writer.write_all(b"\n")?;
+
+        let mut encoder = EncoderWriter::new(writer, STANDARD_NO_PAD);
+        encoder.write_all(&header)?;
+
+        let mut writer = Self { encoder, aes, hmac, key_count: 0 };
+        writer.write_plaintext(b"[")?;
+
+        Ok(writer)
+    }
+
+    fn write_plaintext(&mut self, plaintext: &[u8]) -> Result<(), KeyExportError> {
+        let mut ciphertext = plaintext.to_vec();
+        self.aes.apply_keystream(&mut ciphertext);
+        self.hmac.update(&ciphertext);
+        self.encoder.write_all(&ciphertext)?;
+
+        Ok(())
+    }
+
+    /// Encrypt a room key and add it to the export.
+    pub fn write_key(&mut self, key: &ExportedRoomKey) -> Result<(), KeyExportError> {
+        if self.key_count > 0 {
+            self.write_plaintext(b",")?;
+        }
+
+        let mut plaintext = serde_json::to_vec(key)?;
+        self.write_plaintext(&plaintext)?;
+        plaintext.zeroize();
+
+        self.key_count += 1;
+
+        Ok(())
+    }
+
+    /// Get the number of room keys that were added to the export.
+    pub fn key_count(&self) -> usize {
+        self.key_count
+    }
+
+    /// Finish the export, returning the underlying writer.
+    pub fn finish(mut self) -> Result<W, KeyExportError> {
+        self.write_plaintext(b"]")?;
+
+        let mac = self.hmac.finalize().into_bytes();
+        self.encoder.write_all(&mac)?;
+
+        let mut writer = self.encoder.finish()?;
+        writer.write_all(b"\n")?;
+        writer.write_all(FOOTER.as_bytes())?;
+
+        Ok(writer)
+    }
+}
+
 fn encrypt_helper(mut plaintext: &mut [u8], passphrase: &str, rounds: u32) -> String {
     let mut salt = [0u8; SALT_SIZE];
     let mut iv = [0u8; IV_SIZE];
@@ -233,7 +388,10 @@ mod test {
     use proptest::prelude::*;
     use ruma::room_id;
 
-    use super::{decode, decrypt_helper, decrypt_key_export, encrypt_helper, encrypt_key_export};
+    use super::{
+        decode, decrypt_helper, decrypt_key_export, encrypt_helper, encrypt_key_export,
+        KeyExportWriter, RoomKeyImportResult,
+    };
     use crate::machine::test::get_prepared_machine;
 
     const PASSPHRASE: &str = "1234";
@@ -302,7 +460,33 @@ mod test {
         let decrypted = decrypt_key_export(Cursor::new(encrypted), "1234").unwrap();
 
         assert_eq!(export, decrypted);
-        assert_eq!(machine.import_keys(decrypted, |_, _| {}).await.unwrap(), (0, 1));
+        assert_eq!(
+            machine.import_keys(decrypted, |_| {}).await.unwrap(),
+            RoomKeyImportResult { imported_count: 0, skipped_count: 1, total_count: 1 }
+        );
+    }
+
+    #[async_test]
+    async fn test_streamed_export() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        let export = machine.export_keys(|s| s.room_id() == &room_id).await.unwrap();
+
+        let mut writer = KeyExportWriter::new(Vec::new(), "1234", 1).unwrap();
+
+        for key in &export {
+            writer.write_key(key).unwrap();
+        }
+
+        let encrypted = writer.finish().unwrap();
+        let decrypted = decrypt_key_export(Cursor::new(encrypted), "1234").unwrap();
+
+        assert_eq!(export, decrypted);
+
+        let empty = KeyExportWriter::new(Vec::new(), "1234", 1).unwrap().finish().unwrap();
+        assert!(decrypt_key_export(Cursor::new(empty), "1234").unwrap().is_empty());
     }
 
     #[test]
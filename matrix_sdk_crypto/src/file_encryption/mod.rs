@@ -2,4 +2,6 @@ mod attachments;
 mod key_export;
 
 pub use attachments::{AttachmentDecryptor, AttachmentEncryptor, DecryptorError, EncryptionInfo};
-pub use key_export::{decrypt_key_export, encrypt_key_export, KeyExportError};
+pub use key_export::{
+    decrypt_key_export, encrypt_key_export, KeyExportError, KeyExportWriter, RoomKeyImportResult,
+};
@@ -41,10 +41,16 @@ use tracing::warn;
 
 use super::{atomic_bool_deserializer, atomic_bool_serializer};
 use crate::{
-    error::{EventError, OlmError, OlmResult, SignatureError},
+    error::{ErrorContext, EventError, OlmError, OlmResult, SignatureError},
     identities::{OwnUserIdentity, UserIdentities},
-    olm::{InboundGroupSession, PrivateCrossSigningIdentity, Session, Utility},
-    store::{Changes, CryptoStore, DeviceChanges, Result as StoreResult},
+    olm::{
+        InboundGroupSession, PrivateCrossSigningIdentity, Session, Utility, SHARED_HISTORY_FIELD,
+    },
+    store::{
+        caches::IdentifierInterner, Changes, CryptoStore, DeviceChanges, Result as StoreResult,
+    },
+    types::{Curve25519PublicKey, Ed25519PublicKey},
+    utilities::log_id,
     verification::VerificationMachine,
     OutgoingVerificationRequest, Sas, ToDeviceRequest,
 };
@@ -70,6 +76,12 @@ pub struct ReadOnlyDevice {
         deserialize_with = "local_trust_deserializer"
     )]
     trust_state: Arc<Atomic<LocalTrust>>,
+    #[serde(
+        default,
+        serialize_with = "atomic_bool_serializer",
+        deserialize_with = "atomic_bool_deserializer"
+    )]
+    key_changed: Arc<AtomicBool>,
 }
 
 impl std::fmt::Debug for ReadOnlyDevice {
@@ -81,6 +93,7 @@ impl std::fmt::Debug for ReadOnlyDevice {
             .field("keys", self.keys())
             .field("deleted", &self.deleted.load(Ordering::SeqCst))
             .field("trust_state", &self.trust_state)
+            .field("key_changed", &self.key_changed.load(Ordering::SeqCst))
             .finish()
     }
 }
@@ -162,6 +175,7 @@ impl Device {
     ///
     /// * `trust_state` - The new trust state that should be set for the device.
     pub async fn set_local_trust(&self, trust_state: LocalTrust) -> StoreResult<()> {
+        let previous = self.inner.local_trust_state();
         self.inner.set_trust_state(trust_state);
 
         let changes = Changes {
@@ -169,7 +183,13 @@ impl Device {
             ..Default::default()
         };
 
-        self.verification_machine.store.save_changes(changes).await
+        let ret = self.verification_machine.store.save_changes(changes).await;
+
+        if ret.is_err() {
+            self.inner.set_trust_state(previous);
+        }
+
+        ret
     }
 
     /// Encrypt the given content for this `Device`.
@@ -213,7 +233,12 @@ impl Device {
             );
         };
 
-        let content = serde_json::to_value(content)?;
+        let mut content = serde_json::to_value(content)?;
+
+        if let Some(content) = content.as_object_mut() {
+            content.insert(SHARED_HISTORY_FIELD.to_owned(), session.shared_history().into());
+        }
+
         self.encrypt(EventType::ForwardedRoomKey, content).await
     }
 }
@@ -308,6 +333,7 @@ impl ReadOnlyDevice {
             algorithms: algorithms.into(),
             keys: Arc::new(keys),
             deleted: Arc::new(AtomicBool::new(false)),
+            key_changed: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -331,7 +357,27 @@ impl ReadOnlyDevice {
         self.keys.get(&DeviceKeyId::from_parts(algorithm, &self.device_id))
     }
 
+    /// Get the Curve25519 identity key of this device.
+    ///
+    /// Returns `None` if the device doesn't have a Curve25519 key or if the
+    /// key isn't a valid Curve25519 public key.
+    pub fn curve25519_key(&self) -> Option<Curve25519PublicKey> {
+        self.get_key(DeviceKeyAlgorithm::Curve25519).and_then(|k| k.parse().ok())
+    }
+
+    /// Get the Ed25519 fingerprint key of this device.
+    ///
+    /// Returns `None` if the device doesn't have an Ed25519 key or if the key
+    /// isn't a valid Ed25519 public key.
+    pub fn ed25519_key(&self) -> Option<Ed25519PublicKey> {
+        self.get_key(DeviceKeyAlgorithm::Ed25519).and_then(|k| k.parse().ok())
+    }
+
     /// Get a map containing all the device keys.
+    ///
+    /// The keys are unpadded base64 strings as the device uploaded them, use
+    /// [`curve25519_key()`](#method.curve25519_key) or
+    /// [`ed25519_key()`](#method.ed25519_key) to compare keys.
     pub fn keys(&self) -> &BTreeMap<DeviceKeyId, String> {
         &self.keys
     }
@@ -376,6 +422,17 @@ impl ReadOnlyDevice {
         self.deleted.load(Ordering::Relaxed)
     }
 
+    /// Did the server give us an update for this device that changed its
+    /// ed25519 key.
+    ///
+    /// The ed25519 key of a device never changes, such updates are rejected
+    /// and the device keeps the key we first saw. A device that is in this
+    /// state is either being forged by the server or its device ID was reused
+    /// by a new device, either way the user should be warned about it.
+    pub fn key_changed(&self) -> bool {
+        self.key_changed.load(Ordering::Relaxed)
+    }
+
     pub(crate) fn trust_state(
         &self,
         own_identity: &Option<OwnUserIdentity>,
@@ -430,10 +487,17 @@ impl ReadOnlyDevice {
             warn!(
                 "Trying to encrypt a Megolm session for user {} on device {}, \
                 but the device doesn't have a curve25519 key",
-                self.user_id(),
-                self.device_id()
+                log_id(self.user_id().as_str()),
+                log_id(self.device_id().as_str())
             );
-            return Err(EventError::MissingSenderKey.into());
+            return Err(OlmError::EventError(
+                EventError::MissingSenderKey,
+                ErrorContext {
+                    user_id: Some(self.user_id().clone()),
+                    device_id: Some(self.device_id().into()),
+                    ..Default::default()
+                },
+            ));
         };
 
         let session = if let Some(s) = store.get_sessions(sender_key).await? {
@@ -449,19 +513,42 @@ impl ReadOnlyDevice {
             warn!(
                 "Trying to encrypt a Megolm session for user {} on device {}, \
                 but no Olm session is found",
-                self.user_id(),
-                self.device_id()
+                log_id(self.user_id().as_str()),
+                log_id(self.device_id().as_str())
             );
-            return Err(OlmError::MissingSession);
+            return Err(OlmError::MissingSession(ErrorContext {
+                user_id: Some(self.user_id().clone()),
+                device_id: Some(self.device_id().into()),
+                ..Default::default()
+            }));
         };
 
-        let message = session.encrypt(self, event_type, content).await?;
+        let message = session.encrypt(self, event_type, content).await.map_err(|e| {
+            e.with_context(ErrorContext {
+                user_id: Some(self.user_id().clone()),
+                device_id: Some(self.device_id().into()),
+                sender_key: Some(sender_key.to_owned()),
+                session_id: Some(session.session_id().to_owned()),
+            })
+        })?;
 
         Ok((session, message))
     }
 
     /// Update a device with a new device keys struct.
+    ///
+    /// Updates that change the ed25519 key of the device are rejected and the
+    /// device is marked with the [`key_changed()`](#method.key_changed) flag.
     pub(crate) fn update_device(&mut self, device_keys: &DeviceKeys) -> Result<(), SignatureError> {
+        let key_id = DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, self.device_id());
+        let new_key =
+            device_keys.keys.get(&key_id).and_then(|k| k.parse::<Ed25519PublicKey>().ok());
+
+        if new_key.is_none() || new_key != self.ed25519_key() {
+            self.key_changed.store(true, Ordering::Relaxed);
+            return Err(SignatureError::KeyChanged);
+        }
+
         self.verify_device_keys(device_keys)?;
 
         let display_name = Arc::new(device_keys.unsigned.device_display_name.clone());
@@ -523,6 +610,14 @@ impl ReadOnlyDevice {
         self.is_signed_by_device(&mut json!(&one_time_key))
     }
 
+    /// Replace the user and device id of this device with the shared copies
+    /// the given interner holds.
+    pub(crate) fn with_interned_ids(mut self, interner: &IdentifierInterner) -> Self {
+        self.user_id = interner.user_id(&self.user_id);
+        self.device_id = interner.device_id(&self.device_id);
+        self
+    }
+
     /// Mark the device as deleted.
     pub(crate) fn mark_as_deleted(&self) {
         self.deleted.store(true, Ordering::Relaxed);
@@ -553,6 +648,7 @@ impl TryFrom<&DeviceKeys> for ReadOnlyDevice {
             display_name: Arc::new(device_keys.unsigned.device_display_name.clone()),
             deleted: Arc::new(AtomicBool::new(false)),
             trust_state: Arc::new(Atomic::new(LocalTrust::Unset)),
+            key_changed: Arc::new(AtomicBool::new(false)),
         };
 
         device.verify_device_keys(device_keys)?;
@@ -570,10 +666,13 @@ impl PartialEq for ReadOnlyDevice {
 pub(crate) mod test {
     use std::convert::TryFrom;
 
-    use ruma::{encryption::DeviceKeys, user_id, DeviceKeyAlgorithm};
+    use ruma::{encryption::DeviceKeys, user_id, DeviceKeyAlgorithm, DeviceKeyId};
     use serde_json::json;
 
-    use crate::identities::{LocalTrust, ReadOnlyDevice};
+    use crate::{
+        error::SignatureError,
+        identities::{LocalTrust, ReadOnlyDevice},
+    };
 
     fn device_keys() -> DeviceKeys {
         let device_keys = json!({
@@ -627,6 +726,20 @@ pub(crate) mod test {
         );
     }
 
+    #[test]
+    fn typed_device_keys() {
+        let device = get_device();
+
+        assert_eq!(
+            device.curve25519_key().unwrap().to_base64(),
+            "xfgbLIC5WAl1OIkpOzoxpCe8FsRDT6nch7NQsOb15nc"
+        );
+        assert_eq!(
+            device.ed25519_key().unwrap().to_base64(),
+            "2/5LWJMow5zhJqakV88SIc7q/1pa8fmkfgAzx72w9G4"
+        );
+    }
+
     #[test]
     fn update_a_device() {
         let mut device = get_device();
@@ -642,6 +755,60 @@ pub(crate) mod test {
         assert_eq!(&display_name, device.display_name().as_ref().unwrap());
     }
 
+    #[test]
+    fn forged_device_update() {
+        let mut device = get_device();
+        let curve_key_id =
+            DeviceKeyId::from_parts(DeviceKeyAlgorithm::Curve25519, device.device_id());
+
+        let mut device_keys = device_keys();
+        device_keys
+            .keys
+            .insert(curve_key_id, "ZHtvN2m8TmYQ5NGS4/BvS3V7e8d8N2bBXRTlAyv6mF4".to_owned());
+
+        assert!(matches!(
+            device.update_device(&device_keys),
+            Err(SignatureError::VerificationError)
+        ));
+        assert_eq!(
+            device.get_key(DeviceKeyAlgorithm::Curve25519).unwrap(),
+            "xfgbLIC5WAl1OIkpOzoxpCe8FsRDT6nch7NQsOb15nc"
+        );
+        assert!(!device.key_changed());
+    }
+
+    #[test]
+    fn device_key_substitution() {
+        let mut device = get_device();
+        let ed_key_id = DeviceKeyId::from_parts(DeviceKeyAlgorithm::Ed25519, device.device_id());
+
+        let mut device_keys = device_keys();
+        device_keys
+            .keys
+            .insert(ed_key_id, "nE6W2fCblxDcOFmeEtCHNl8/l8bXcu7GKyAswA4r3mM".to_owned());
+        device_keys.unsigned.device_display_name = Some("Mallory's phone".to_owned());
+
+        assert!(matches!(device.update_device(&device_keys), Err(SignatureError::KeyChanged)));
+        assert!(device.key_changed());
+        assert_eq!("Alice's mobile phone", device.display_name().as_ref().unwrap());
+        assert_eq!(
+            device.get_key(DeviceKeyAlgorithm::Ed25519).unwrap(),
+            "2/5LWJMow5zhJqakV88SIc7q/1pa8fmkfgAzx72w9G4"
+        );
+
+        // The key change needs to be remembered after a restart.
+        let serialized = serde_json::to_value(&device).unwrap();
+        let deserialized: ReadOnlyDevice = serde_json::from_value(serialized.clone()).unwrap();
+        assert!(deserialized.key_changed());
+
+        // Devices that were stored before the flag existed didn't see a key
+        // change.
+        let mut serialized = serialized;
+        serialized.as_object_mut().unwrap().remove("key_changed");
+        let deserialized: ReadOnlyDevice = serde_json::from_value(serialized).unwrap();
+        assert!(!deserialized.key_changed());
+    }
+
     #[test]
     fn delete_a_device() {
         let device = get_device();
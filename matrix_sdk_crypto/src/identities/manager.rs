@@ -18,8 +18,12 @@ use std::{
     sync::Arc,
 };
 
+use dashmap::DashMap;
 use futures::future::join_all;
-use matrix_sdk_common::executor::spawn;
+use matrix_sdk_common::{
+    executor::spawn,
+    time::{sleep, Duration, Instant},
+};
 use ruma::{
     api::client::r0::keys::get_keys::Response as KeysQueryResponse,
     encryption::DeviceKeys,
@@ -28,13 +32,14 @@ use ruma::{
 use tracing::{trace, warn};
 
 use crate::{
-    error::OlmResult,
+    error::{OlmResult, SignatureError},
     identities::{
         MasterPubkey, OwnUserIdentity, ReadOnlyDevice, SelfSigningPubkey, UserIdentities,
         UserIdentity, UserSigningPubkey,
     },
     requests::KeysQueryRequest,
     store::{Changes, DeviceChanges, IdentityChanges, Result as StoreResult, Store},
+    utilities::log_id,
 };
 
 enum DeviceChange {
@@ -48,13 +53,31 @@ pub(crate) struct IdentityManager {
     user_id: Arc<UserId>,
     device_id: Arc<DeviceId>,
     store: Store,
+    /// Users that are part of a keys query request that is currently in
+    /// flight, with the time the request was created.
+    keys_query_in_flight: Arc<DashMap<UserId, Instant>>,
+    /// The time we last received the keys of a user.
+    last_keys_query: Arc<DashMap<UserId, Instant>>,
 }
 
 impl IdentityManager {
     const MAX_KEY_QUERY_USERS: usize = 250;
+    /// The time after which we consider a keys query request that didn't get
+    /// a response as failed and query the users again.
+    const KEYS_QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+    /// The minimal time between two keys queries for the same user.
+    const MIN_KEYS_QUERY_INTERVAL: Duration = Duration::from_secs(5);
+    /// The time between two checks if the devices of a user were updated.
+    const STALE_DEVICES_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
     pub fn new(user_id: Arc<UserId>, device_id: Arc<DeviceId>, store: Store) -> Self {
-        IdentityManager { user_id, device_id, store }
+        IdentityManager {
+            user_id,
+            device_id,
+            store,
+            keys_query_in_flight: Arc::new(DashMap::new()),
+            last_keys_query: Arc::new(DashMap::new()),
+        }
     }
 
     fn user_id(&self) -> &UserId {
@@ -88,7 +111,11 @@ impl IdentityManager {
         self.store.save_changes(changes).await?;
         let updated_users: Vec<&UserId> = response.device_keys.keys().collect();
 
+        let now = Instant::now();
+
         for user_id in updated_users {
+            self.keys_query_in_flight.remove(user_id);
+            self.last_keys_query.insert(user_id.clone(), now);
             self.store.update_tracked_user(user_id, false).await?;
         }
 
@@ -103,27 +130,51 @@ impl IdentityManager {
             store.get_readonly_device(&device_keys.user_id, &device_keys.device_id).await?;
 
         if let Some(mut device) = old_device {
-            if let Err(e) = device.update_device(&device_keys) {
-                warn!(
-                    "Failed to update the device keys for {} {}: {:?}",
-                    device.user_id(),
-                    device.device_id(),
-                    e
-                );
-                Ok(DeviceChange::None)
-            } else {
-                Ok(DeviceChange::Updated(device))
+            let key_changed = device.key_changed();
+
+            match device.update_device(&device_keys) {
+                Ok(()) => Ok(DeviceChange::Updated(device)),
+                Err(SignatureError::KeyChanged) => {
+                    warn!(
+                        "The ed25519 key of the device {} {} changed, rejecting the update",
+                        log_id(device.user_id().as_str()),
+                        log_id(device.device_id().as_str()),
+                    );
+
+                    // The keys of the device stay untouched, but the device
+                    // needs to be stored so the key change is remembered.
+                    if key_changed {
+                        Ok(DeviceChange::None)
+                    } else {
+                        Ok(DeviceChange::Updated(device))
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to update the device keys for {} {}: {:?}",
+                        log_id(device.user_id().as_str()),
+                        log_id(device.device_id().as_str()),
+                        e
+                    );
+                    Ok(DeviceChange::None)
+                }
             }
         } else {
             match ReadOnlyDevice::try_from(&device_keys) {
                 Ok(d) => {
-                    trace!("Adding a new device to the device store {:?}", d);
+                    trace!(
+                        "Adding a new device to the device store {} {}",
+                        log_id(d.user_id().as_str()),
+                        log_id(d.device_id().as_str())
+                    );
                     Ok(DeviceChange::New(d))
                 }
                 Err(e) => {
                     warn!(
                         "Failed to create a new device for {} {}: {:?}",
-                        device_keys.user_id, device_keys.device_id, e
+                        log_id(device_keys.user_id.as_str()),
+                        log_id(device_keys.device_id.as_str()),
+                        e
                     );
                     Ok(DeviceChange::None)
                 }
@@ -149,7 +200,10 @@ impl IdentityManager {
             } else if user_id != device_keys.user_id || device_id != device_keys.device_id {
                 warn!(
                     "Mismatch in device keys payload of device {}|{} from user {}|{}",
-                    device_id, device_keys.device_id, user_id, device_keys.user_id
+                    log_id(device_id.as_str()),
+                    log_id(device_keys.device_id.as_str()),
+                    log_id(user_id.as_str()),
+                    log_id(device_keys.user_id.as_str())
                 );
                 None
             } else {
@@ -241,7 +295,10 @@ impl IdentityManager {
             let self_signing = if let Some(s) = response.self_signing_keys.get(user_id) {
                 SelfSigningPubkey::from(s)
             } else {
-                warn!("User identity for user {} didn't contain a self signing pubkey", user_id);
+                warn!(
+                    "User identity for user {} didn't contain a self signing pubkey",
+                    log_id(user_id.as_str())
+                );
                 continue;
             };
 
@@ -255,7 +312,7 @@ impl IdentityManager {
                             warn!(
                                 "User identity for our own user {} didn't \
                                   contain a user signing pubkey",
-                                user_id
+                                log_id(user_id.as_str())
                             );
                             continue;
                         };
@@ -276,7 +333,7 @@ impl IdentityManager {
                     {
                         warn!(
                             "User id mismatch in one of the cross signing keys for user {}",
-                            user_id
+                            log_id(user_id.as_str())
                         );
                         continue;
                     }
@@ -287,12 +344,15 @@ impl IdentityManager {
                     warn!(
                         "User identity for our own user {} didn't contain a \
                         user signing pubkey",
-                        user_id
+                        log_id(user_id.as_str())
                     );
                     continue;
                 }
             } else if master_key.user_id() != user_id || self_signing.user_id() != user_id {
-                warn!("User id mismatch in one of the cross signing keys for user {}", user_id);
+                warn!(
+                    "User id mismatch in one of the cross signing keys for user {}",
+                    log_id(user_id.as_str())
+                );
                 continue;
             } else {
                 UserIdentity::new(master_key, self_signing)
@@ -301,7 +361,11 @@ impl IdentityManager {
 
             match result {
                 Ok((i, new)) => {
-                    trace!("Updated or created new user identity for {}: {:?}", user_id, i);
+                    trace!(
+                        "Updated or created new user identity for {}: {:?}",
+                        log_id(user_id.as_str()),
+                        i
+                    );
                     if new {
                         changes.new.push(i);
                     } else {
@@ -309,7 +373,11 @@ impl IdentityManager {
                     }
                 }
                 Err(e) => {
-                    warn!("Couldn't update or create new user identity for {}: {:?}", user_id, e);
+                    warn!(
+                        "Couldn't update or create new user identity for {}: {:?}",
+                        log_id(user_id.as_str()),
+                        e
+                    );
                     continue;
                 }
             }
@@ -326,15 +394,28 @@ impl IdentityManager {
     /// The response of a successful key query requests needs to be passed to
     /// the [`OlmMachine`] with the [`receive_keys_query_response`].
     ///
+    /// Users that are already part of a request that is in flight, or that
+    /// were queried less than `MIN_KEYS_QUERY_INTERVAL` ago, won't be part of
+    /// the returned requests.
+    ///
     /// [`OlmMachine`]: struct.OlmMachine.html
     /// [`receive_keys_query_response`]: #method.receive_keys_query_response
     pub async fn users_for_key_query(&self) -> Vec<KeysQueryRequest> {
-        let users = self.store.users_for_key_query();
+        let now = Instant::now();
+
+        let users: Vec<UserId> = self
+            .store
+            .users_for_key_query()
+            .into_iter()
+            .filter(|u| !self.is_query_in_flight(u, now) && !self.is_query_throttled(u, now))
+            .collect();
 
         if users.is_empty() {
             Vec::new()
         } else {
-            let users: Vec<UserId> = users.into_iter().collect();
+            for user in &users {
+                self.keys_query_in_flight.insert(user.clone(), now);
+            }
 
             users
                 .chunks(Self::MAX_KEY_QUERY_USERS)
@@ -344,6 +425,94 @@ impl IdentityManager {
         }
     }
 
+    fn is_query_in_flight(&self, user_id: &UserId, now: Instant) -> bool {
+        self.keys_query_in_flight
+            .get(user_id)
+            .map(|t| now.duration_since(*t) < Self::KEYS_QUERY_TIMEOUT)
+            .unwrap_or(false)
+    }
+
+    fn is_query_throttled(&self, user_id: &UserId, now: Instant) -> bool {
+        self.last_keys_query
+            .get(user_id)
+            .map(|t| now.duration_since(*t) < Self::MIN_KEYS_QUERY_INTERVAL)
+            .unwrap_or(false)
+    }
+
+    /// Get the time we last received the devices of the given user from a
+    /// keys query.
+    ///
+    /// Returns `None` if the devices of the user weren't fetched since the
+    /// `OlmMachine` was created.
+    pub fn last_keys_query(&self, user_id: &UserId) -> Option<Instant> {
+        self.last_keys_query.get(user_id).map(|t| *t)
+    }
+
+    /// Is the given user tracked and are his devices up to date.
+    ///
+    /// The devices of a user are considered to be stale if the user was
+    /// reported in the `device_lists` of a sync response and we didn't query
+    /// his devices since.
+    pub fn is_user_tracked_and_fresh(&self, user_id: &UserId) -> bool {
+        self.store.is_user_tracked(user_id) && !self.store.users_for_key_query().contains(user_id)
+    }
+
+    /// Get the tracked users out of the given users whose devices are stale.
+    pub fn stale_users<'a>(&self, users: impl IntoIterator<Item = &'a UserId>) -> Vec<UserId> {
+        let dirty = self.store.users_for_key_query();
+
+        users
+            .into_iter()
+            .filter(|u| dirty.contains(*u) && self.store.is_user_tracked(u))
+            .cloned()
+            .collect()
+    }
+
+    /// Wait until the devices of the given users aren't stale anymore.
+    ///
+    /// Returns the users whose devices are still stale after the timeout has
+    /// passed, the list is empty if all the devices were updated.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The users whose devices should be up to date.
+    ///
+    /// * `timeout` - The maximal time that should be waited for the keys
+    /// queries of the users to complete.
+    pub async fn wait_for_fresh_devices(&self, users: &[UserId], timeout: Duration) -> Vec<UserId> {
+        let start = Instant::now();
+        let mut stale = self.stale_users(users);
+
+        while !stale.is_empty() {
+            let remaining = match timeout.checked_sub(start.elapsed()) {
+                Some(r) if r > Duration::from_secs(0) => r,
+                _ => break,
+            };
+
+            sleep(Self::STALE_DEVICES_POLL_INTERVAL.min(remaining)).await;
+            stale = self.stale_users(&stale);
+        }
+
+        stale
+    }
+
+    /// Queue up the given users for a keys query, bypassing the in-flight
+    /// deduplication and the throttling of keys queries.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The users whose devices should be refreshed, users that
+    /// aren't tracked will start to be tracked.
+    pub async fn force_refresh(&self, users: impl IntoIterator<Item = &UserId>) -> StoreResult<()> {
+        for user in users {
+            self.keys_query_in_flight.remove(user);
+            self.last_keys_query.remove(user);
+            self.store.update_tracked_user(user, true).await?;
+        }
+
+        Ok(())
+    }
+
     /// Mark that the given user has changed his devices.
     ///
     /// This will queue up the given user for a key query.
@@ -390,7 +559,11 @@ impl IdentityManager {
 pub(crate) mod test {
     use std::sync::Arc;
 
-    use matrix_sdk_common::locks::Mutex;
+    use futures::join;
+    use matrix_sdk_common::{
+        locks::Mutex,
+        time::{sleep, Duration},
+    };
     use matrix_sdk_test::async_test;
     use ruma::{
         api::{client::r0::keys::get_keys::Response as KeyQueryResponse, IncomingResponse},
@@ -595,6 +768,57 @@ pub(crate) mod test {
         assert!(manager.users_for_key_query().await.is_empty())
     }
 
+    #[async_test]
+    async fn test_manager_key_query_deduplication() {
+        let manager = manager();
+        let other_user = other_user_id();
+
+        manager.update_tracked_users(vec![&other_user]).await;
+        assert_eq!(manager.users_for_key_query().await.len(), 1);
+        assert!(manager.users_for_key_query().await.is_empty());
+
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+        manager.mark_user_as_changed(&other_user).await.unwrap();
+        assert!(manager.users_for_key_query().await.is_empty());
+
+        manager.force_refresh(vec![&other_user]).await.unwrap();
+        let requests = manager.users_for_key_query().await;
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].device_keys.contains_key(&other_user));
+    }
+
+    #[async_test]
+    async fn test_manager_device_freshness() {
+        let manager = manager();
+        let other_user = other_user_id();
+
+        assert!(!manager.is_user_tracked_and_fresh(&other_user));
+        assert!(manager.stale_users(vec![&other_user]).is_empty());
+
+        manager.update_tracked_users(vec![&other_user]).await;
+        assert!(!manager.is_user_tracked_and_fresh(&other_user));
+        assert!(manager.last_keys_query(&other_user).is_none());
+
+        let stale =
+            manager.wait_for_fresh_devices(&[other_user.clone()], Duration::from_millis(10)).await;
+        assert_eq!(stale, vec![other_user.clone()]);
+
+        manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+        assert!(manager.is_user_tracked_and_fresh(&other_user));
+        assert!(manager.last_keys_query(&other_user).is_some());
+
+        manager.mark_user_as_changed(&other_user).await.unwrap();
+        assert_eq!(manager.stale_users(vec![&other_user]), vec![other_user.clone()]);
+
+        let users = [other_user.clone()];
+        let (stale, _) =
+            join!(manager.wait_for_fresh_devices(&users, Duration::from_secs(10)), async {
+                sleep(Duration::from_millis(100)).await;
+                manager.receive_keys_query_response(&other_key_query()).await.unwrap();
+            });
+        assert!(stale.is_empty());
+    }
+
     #[async_test]
     async fn test_manager_key_query_response() {
         let manager = manager();
@@ -20,7 +20,10 @@
 // If we don't trust the device store an object that remembers the request and
 // let the users introspect that object.
 
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
 
 use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use matrix_sdk_common::uuid::Uuid;
@@ -40,13 +43,21 @@ use tracing::{error, info, trace, warn};
 
 use crate::{
     error::{OlmError, OlmResult},
-    olm::{InboundGroupSession, Session, ShareState},
+    olm::{
+        InboundGroupSession, Session, ShareDecision, ShareState, SharingHistoryEntry,
+        WithheldReason,
+    },
     requests::{OutgoingRequest, ToDeviceRequest},
     session_manager::GroupSessionCache,
     store::{Changes, CryptoStoreError, Store},
+    utilities::log_id,
     Device,
 };
 
+/// The maximal number of incoming key requests that wait for the approval of
+/// the user.
+const MAX_PENDING_APPROVALS: usize = 100;
+
 /// An error describing why a key share request won't be honored.
 #[derive(Debug, Clone, Error, PartialEq)]
 pub enum KeyshareDecision {
@@ -61,6 +72,76 @@ pub enum KeyshareDecision {
     /// The key request is from a device we own, yet we don't trust it.
     #[error("requesting device isn't trusted")]
     UntrustedDevice,
+    /// The key forwarding policy doesn't allow the key to be shared with the
+    /// requesting device.
+    #[error("the key forwarding policy refused the key request")]
+    RefusedByPolicy,
+    /// The key forwarding policy wants the user to decide if the key should be
+    /// shared.
+    #[error("the key request is waiting for the approval of the user")]
+    AwaitingApproval,
+}
+
+/// The decision of a custom [`KeyForwardingPolicy`] for a single incoming
+/// room key request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyForwardingDecision {
+    /// Share the room key with the requesting device.
+    ///
+    /// The room key is still only shared if the device is a trusted device of
+    /// ours, or if the room key was shared with the device in the first place.
+    Share,
+    /// Refuse to share the room key with the requesting device.
+    Refuse,
+    /// Keep the request around until it gets manually approved or refused,
+    /// e.g. after the user has been prompted.
+    AskUser,
+}
+
+/// The policy that decides if incoming room key requests are honored.
+#[derive(Clone)]
+pub enum KeyForwardingPolicy {
+    /// Never share room keys with devices that request them.
+    Never,
+    /// Share room keys only with our own verified devices.
+    OwnVerifiedDevices,
+    /// Share room keys with our own verified devices and with devices of other
+    /// users that were meant to receive the room key in the first place.
+    Automatic,
+    /// Let a callback decide for every incoming room key request.
+    Custom(Arc<dyn Fn(&Device, &RequestedKeyInfo) -> KeyForwardingDecision + Send + Sync>),
+}
+
+impl Default for KeyForwardingPolicy {
+    fn default() -> Self {
+        KeyForwardingPolicy::Automatic
+    }
+}
+
+#[cfg(not(tarpaulin_include))]
+impl std::fmt::Debug for KeyForwardingPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyForwardingPolicy::Never => write!(f, "Never"),
+            KeyForwardingPolicy::OwnVerifiedDevices => write!(f, "OwnVerifiedDevices"),
+            KeyForwardingPolicy::Automatic => write!(f, "Automatic"),
+            KeyForwardingPolicy::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// An incoming room key request that is waiting to be manually approved or
+/// refused.
+#[derive(Debug, Clone)]
+pub struct IncomingKeyRequest {
+    /// The user that requested the room key.
+    pub user_id: UserId,
+    /// The device that requested the room key.
+    pub device_id: DeviceIdBox,
+    /// The unique id of the request.
+    pub request_id: String,
+    /// Info about the requested room key.
+    pub info: RequestedKeyInfo,
 }
 
 /// A queue where we store room key requests that we want to serve but the
@@ -134,6 +215,11 @@ pub(crate) struct KeyRequestMachine {
     >,
     wait_queue: WaitQueue,
     users_for_key_claim: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
+    forwarding_policy: Arc<RwLock<KeyForwardingPolicy>>,
+    pending_approval: Arc<
+        DashMap<(UserId, DeviceIdBox, String), ToDeviceEvent<RoomKeyRequestToDeviceEventContent>>,
+    >,
+    approved_requests: Arc<DashSet<(UserId, DeviceIdBox, String)>>,
 }
 
 /// A struct describing an outgoing key request.
@@ -224,7 +310,108 @@ impl KeyRequestMachine {
             incoming_key_requests: DashMap::new().into(),
             wait_queue: WaitQueue::new(),
             users_for_key_claim,
+            forwarding_policy: Arc::new(RwLock::new(KeyForwardingPolicy::default())),
+            pending_approval: DashMap::new().into(),
+            approved_requests: DashSet::new().into(),
+        }
+    }
+
+    /// Set the policy that decides if incoming room key requests are honored.
+    pub fn set_forwarding_policy(&self, policy: KeyForwardingPolicy) {
+        *self.forwarding_policy.write().unwrap() = policy;
+    }
+
+    /// Get the incoming room key requests that are waiting to be manually
+    /// approved or refused.
+    pub fn pending_key_requests(&self) -> Vec<IncomingKeyRequest> {
+        self.pending_approval
+            .iter()
+            .filter_map(|e| {
+                let (user_id, device_id, request_id) = e.key().clone();
+
+                e.value().content.body.clone().map(|info| IncomingKeyRequest {
+                    user_id,
+                    device_id,
+                    request_id,
+                    info,
+                })
+            })
+            .collect()
+    }
+
+    /// Approve a pending incoming room key request, the room key will be
+    /// shared with the requesting device regardless of the forwarding policy.
+    ///
+    /// The room key is still only shared if the device is a trusted device of
+    /// ours, or if the room key was shared with the device in the first place.
+    ///
+    /// Returns the Olm session that was used to share the key, if the key
+    /// could be shared right away, the session needs to be saved.
+    pub async fn approve_key_request(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        request_id: &str,
+    ) -> OlmResult<Option<Session>> {
+        let key = (user_id.to_owned(), device_id.into(), request_id.to_owned());
+
+        if let Some((key, event)) = self.pending_approval.remove(&key) {
+            info!(
+                "The key request {} from {} {} was approved",
+                request_id,
+                log_id(user_id.as_str()),
+                log_id(device_id.as_str())
+            );
+
+            self.approved_requests.insert(key);
+            self.handle_key_request(&event).await
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Refuse a pending incoming room key request.
+    ///
+    /// Returns true if the request was pending, false otherwise.
+    pub async fn refuse_key_request(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        request_id: &str,
+    ) -> Result<bool, CryptoStoreError> {
+        let key = (user_id.to_owned(), device_id.into(), request_id.to_owned());
+
+        let event = if let Some((_, event)) = self.pending_approval.remove(&key) {
+            event
+        } else {
+            return Ok(false);
+        };
+
+        info!(
+            "The key request {} from {} {} was refused",
+            request_id,
+            log_id(user_id.as_str()),
+            log_id(device_id.as_str())
+        );
+
+        let session = if let Some(info) = &event.content.body {
+            self.store
+                .get_inbound_group_session(&info.room_id, &info.sender_key, &info.session_id)
+                .await?
+        } else {
+            None
+        };
+
+        if let (Some(session), Some(device)) =
+            (session, self.store.get_device(user_id, device_id).await?)
+        {
+            let decision = ShareDecision::Withheld(WithheldReason::KeyRequestRefused(
+                KeyshareDecision::RefusedByPolicy.to_string(),
+            ));
+            self.record_sharing_decision(&session, &device, decision).await?;
         }
+
+        Ok(true)
     }
 
     /// Load stored outgoing requests that were not yet sent out.
@@ -355,7 +542,8 @@ impl KeyRequestMachine {
                     warn!(
                         "Received a key request from {} {} with a request \
                           action, but no key info was found",
-                        event.sender, event.content.requesting_device_id
+                        log_id(event.sender.as_str()),
+                        log_id(event.content.requesting_device_id.as_str())
                     );
                     return Ok(None);
                 }
@@ -382,7 +570,9 @@ impl KeyRequestMachine {
         } else {
             info!(
                 "Received a key request from {} {} for an unknown inbound group session {}.",
-                &event.sender, &event.content.requesting_device_id, &key_info.session_id
+                log_id(event.sender.as_str()),
+                log_id(event.content.requesting_device_id.as_str()),
+                log_id(&key_info.session_id)
             );
             return Ok(None);
         };
@@ -391,34 +581,87 @@ impl KeyRequestMachine {
             self.store.get_device(&event.sender, &event.content.requesting_device_id).await?;
 
         if let Some(device) = device {
-            match self.should_share_key(&device, &session).await {
+            let request_key = (
+                device.user_id().to_owned(),
+                device.device_id().into(),
+                event.content.request_id.clone(),
+            );
+            let approved = self.approved_requests.contains(&request_key);
+
+            let decision = if approved {
+                self.should_share_key(&device, &session).await
+            } else {
+                self.apply_forwarding_policy(&device, &session, key_info).await
+            };
+
+            match decision {
+                Err(KeyshareDecision::AwaitingApproval) => {
+                    info!(
+                        "Received a key request from {} {} for {}, waiting for the user to \
+                         approve it",
+                        log_id(device.user_id().as_str()),
+                        log_id(device.device_id().as_str()),
+                        log_id(&key_info.session_id),
+                    );
+
+                    if !self.pending_approval.contains_key(&request_key)
+                        && self.pending_approval.len() >= MAX_PENDING_APPROVALS
+                    {
+                        warn!(
+                            "Too many key requests are waiting for approval, dropping the key \
+                             request from {} {}",
+                            log_id(device.user_id().as_str()),
+                            log_id(device.device_id().as_str()),
+                        );
+                    } else {
+                        self.pending_approval.insert(request_key, event.clone());
+                    }
+
+                    Ok(None)
+                }
                 Err(e) => {
                     info!(
                         "Received a key request from {} {} that we won't serve: {}",
-                        device.user_id(),
-                        device.device_id(),
+                        log_id(device.user_id().as_str()),
+                        log_id(device.device_id().as_str()),
                         e
                     );
 
+                    self.approved_requests.remove(&request_key);
+
+                    let decision =
+                        ShareDecision::Withheld(WithheldReason::KeyRequestRefused(e.to_string()));
+                    self.record_sharing_decision(&session, &device, decision).await?;
+
                     Ok(None)
                 }
                 Ok(message_index) => {
                     info!(
                         "Serving a key request for {} from {} {} with message_index {:?}.",
-                        key_info.session_id,
-                        device.user_id(),
-                        device.device_id(),
+                        log_id(&key_info.session_id),
+                        log_id(device.user_id().as_str()),
+                        log_id(device.device_id().as_str()),
                         message_index,
                     );
 
                     match self.share_session(&session, &device, message_index).await {
-                        Ok(s) => Ok(Some(s)),
-                        Err(OlmError::MissingSession) => {
+                        Ok(s) => {
+                            self.approved_requests.remove(&request_key);
+
+                            let decision = ShareDecision::Forwarded {
+                                request_id: event.content.request_id.clone(),
+                                message_index,
+                            };
+                            self.record_sharing_decision(&session, &device, decision).await?;
+
+                            Ok(Some(s))
+                        }
+                        Err(OlmError::MissingSession(_)) => {
                             info!(
                                 "Key request from {} {} is missing an Olm session, \
                              putting the request in the wait queue",
-                                device.user_id(),
-                                device.device_id()
+                                log_id(device.user_id().as_str()),
+                                log_id(device.device_id().as_str())
                             );
                             self.handle_key_share_without_session(device, event);
 
@@ -431,7 +674,8 @@ impl KeyRequestMachine {
         } else {
             warn!(
                 "Received a key request from an unknown device {} {}.",
-                &event.sender, &event.content.requesting_device_id
+                log_id(event.sender.as_str()),
+                log_id(event.content.requesting_device_id.as_str())
             );
             self.store.update_tracked_user(&event.sender, true).await?;
 
@@ -439,6 +683,26 @@ impl KeyRequestMachine {
         }
     }
 
+    /// Record the decision we took for a key request in the sharing history
+    /// of the requested session.
+    async fn record_sharing_decision(
+        &self,
+        session: &InboundGroupSession,
+        device: &Device,
+        decision: ShareDecision,
+    ) -> Result<(), CryptoStoreError> {
+        let entry = SharingHistoryEntry::new(
+            session.session_id(),
+            session.room_id(),
+            device.user_id(),
+            device.device_id(),
+            decision,
+        );
+        let changes = Changes { sharing_history: vec![entry], ..Default::default() };
+
+        self.store.save_changes(changes).await
+    }
+
     async fn share_session(
         &self,
         session: &InboundGroupSession,
@@ -469,6 +733,41 @@ impl KeyRequestMachine {
         Ok(used_session)
     }
 
+    /// Check if the forwarding policy allows us to share a session with the
+    /// given device.
+    ///
+    /// Returns the message index the session should be shared at, `None` if
+    /// the session should be shared at its first known index.
+    async fn apply_forwarding_policy(
+        &self,
+        device: &Device,
+        session: &InboundGroupSession,
+        key_info: &RequestedKeyInfo,
+    ) -> Result<Option<u32>, KeyshareDecision> {
+        let policy = self.forwarding_policy.read().unwrap().clone();
+
+        match policy {
+            KeyForwardingPolicy::Never => Err(KeyshareDecision::RefusedByPolicy),
+            KeyForwardingPolicy::OwnVerifiedDevices => {
+                if device.user_id() != self.user_id() {
+                    Err(KeyshareDecision::RefusedByPolicy)
+                } else if device.trust_state() {
+                    Ok(None)
+                } else {
+                    Err(KeyshareDecision::UntrustedDevice)
+                }
+            }
+            KeyForwardingPolicy::Automatic => self.should_share_key(device, session).await,
+            KeyForwardingPolicy::Custom(callback) => match callback(device, key_info) {
+                // Still don't share the session with untrusted devices or
+                // share more of it than the device was supposed to get.
+                KeyForwardingDecision::Share => self.should_share_key(device, session).await,
+                KeyForwardingDecision::Refuse => Err(KeyshareDecision::RefusedByPolicy),
+                KeyForwardingDecision::AskUser => Err(KeyshareDecision::AwaitingApproval),
+            },
+        }
+    }
+
     /// Check if it's ok to share a session with the given device.
     ///
     /// The logic for this currently is as follows:
@@ -609,7 +908,11 @@ impl KeyRequestMachine {
         &self,
         key_info: RequestedKeyInfo,
     ) -> Result<OutgoingRequest, CryptoStoreError> {
-        info!("Creating new outgoing room key request {:#?}", key_info);
+        info!(
+            room_id = %log_id(key_info.room_id.as_str()),
+            session_id = %log_id(&key_info.session_id),
+            "Creating new outgoing room key request"
+        );
 
         let request = OutgoingKeyRequest {
             request_recipient: self.user_id().to_owned(),
@@ -695,7 +998,11 @@ impl KeyRequestMachine {
         let info = self.store.get_outgoing_key_request(id).await?;
 
         if let Some(mut info) = info {
-            trace!("Marking outgoing key request as sent {:#?}", info);
+            trace!(
+                request_id = %info.request_id,
+                session_id = %log_id(&info.info.session_id),
+                "Marking outgoing key request as sent"
+            );
             info.sent_out = true;
             self.save_outgoing_key_info(info).await?;
         }
@@ -710,7 +1017,11 @@ impl KeyRequestMachine {
     /// This will queue up a request cancellation.
     async fn mark_as_done(&self, key_info: OutgoingKeyRequest) -> Result<(), CryptoStoreError> {
         // TODO perhaps only remove the key info if the first known index is 0.
-        trace!("Successfully received a forwarded room key for {:#?}", key_info);
+        trace!(
+            request_id = %key_info.request_id,
+            session_id = %log_id(&key_info.info.session_id),
+            "Successfully received a forwarded room key"
+        );
 
         self.outgoing_to_device_requests.remove(&key_info.request_id);
         // TODO return the key info instead of deleting it so the sync handler
@@ -728,11 +1039,14 @@ impl KeyRequestMachine {
         &self,
         sender_key: &str,
         event: &mut ToDeviceEvent<ForwardedRoomKeyToDeviceEventContent>,
+        shared_history: bool,
     ) -> Result<(Option<AnyToDeviceEvent>, Option<InboundGroupSession>), CryptoStoreError> {
         let key_info = self.get_key_info(&event.content).await?;
 
         if let Some(info) = key_info {
-            let session = InboundGroupSession::from_forwarded_key(sender_key, &mut event.content)?;
+            let mut session =
+                InboundGroupSession::from_forwarded_key(sender_key, &mut event.content)?;
+            session.set_shared_history(shared_history);
 
             let old_session = self
                 .store
@@ -764,9 +1078,9 @@ impl KeyRequestMachine {
             if let Some(s) = &session {
                 info!(
                     "Received a forwarded room key from {} for room {} with session id {}",
-                    event.sender,
-                    s.room_id(),
-                    s.session_id()
+                    log_id(event.sender.as_str()),
+                    log_id(s.room_id().as_str()),
+                    log_id(s.session_id())
                 );
             }
 
@@ -774,7 +1088,7 @@ impl KeyRequestMachine {
         } else {
             info!(
                 "Received a forwarded room key from {}, but no key info was found.",
-                event.sender,
+                log_id(event.sender.as_str()),
             );
             Ok((None, None))
         }
@@ -793,12 +1107,13 @@ mod test {
         events::{
             forwarded_room_key::ForwardedRoomKeyToDeviceEventContent,
             room::encrypted::EncryptedEventContent,
-            room_key_request::RoomKeyRequestToDeviceEventContent, AnyToDeviceEvent, ToDeviceEvent,
+            room_key_request::{RequestedKeyInfo, RoomKeyRequestToDeviceEventContent},
+            AnyToDeviceEvent, ToDeviceEvent,
         },
-        room_id, user_id, DeviceIdBox, RoomId, UserId,
+        room_id, user_id, DeviceIdBox, EventEncryptionAlgorithm, RoomId, UserId,
     };
 
-    use super::{KeyRequestMachine, KeyshareDecision};
+    use super::{KeyForwardingDecision, KeyForwardingPolicy, KeyRequestMachine, KeyshareDecision};
     use crate::{
         identities::{LocalTrust, ReadOnlyDevice},
         olm::{Account, PrivateCrossSigningIdentity, ReadOnlyAccount},
@@ -1006,8 +1321,10 @@ mod test {
                 .is_none()
         );
 
-        let (_, first_session) =
-            machine.receive_forwarded_room_key(&session.sender_key, &mut event).await.unwrap();
+        let (_, first_session) = machine
+            .receive_forwarded_room_key(&session.sender_key, &mut event, false)
+            .await
+            .unwrap();
         let first_session = first_session.unwrap();
 
         assert_eq!(first_session.first_known_index(), 10);
@@ -1040,8 +1357,10 @@ mod test {
 
         let mut event = ToDeviceEvent { sender: alice_id(), content };
 
-        let (_, second_session) =
-            machine.receive_forwarded_room_key(&session.sender_key, &mut event).await.unwrap();
+        let (_, second_session) = machine
+            .receive_forwarded_room_key(&session.sender_key, &mut event, false)
+            .await
+            .unwrap();
 
         assert!(second_session.is_none());
 
@@ -1051,8 +1370,10 @@ mod test {
 
         let mut event = ToDeviceEvent { sender: alice_id(), content };
 
-        let (_, second_session) =
-            machine.receive_forwarded_room_key(&session.sender_key, &mut event).await.unwrap();
+        let (_, second_session) = machine
+            .receive_forwarded_room_key(&session.sender_key, &mut event, false)
+            .await
+            .unwrap();
 
         assert_eq!(second_session.unwrap().first_known_index(), 0);
     }
@@ -1143,6 +1464,78 @@ mod test {
         );
     }
 
+    #[async_test]
+    async fn forwarding_policy() {
+        let machine = get_machine().await;
+        let account = account();
+
+        let own_device =
+            machine.store.get_device(&alice_id(), &alice_device_id()).await.unwrap().unwrap();
+        own_device.set_trust_state(LocalTrust::Verified);
+
+        let bob_device = ReadOnlyDevice::from_account(&bob_account()).await;
+        bob_device.set_trust_state(LocalTrust::Verified);
+        machine.store.save_devices(&[bob_device]).await.unwrap();
+        let bob_device =
+            machine.store.get_device(&bob_id(), &bob_device_id()).await.unwrap().unwrap();
+
+        let (_, inbound) =
+            account.create_group_session_pair_with_defaults(&room_id()).await.unwrap();
+
+        let key_info = RequestedKeyInfo::new(
+            EventEncryptionAlgorithm::MegolmV1AesSha2,
+            room_id(),
+            inbound.sender_key().to_owned(),
+            inbound.session_id().to_owned(),
+        );
+
+        // The default policy shares with our own verified devices.
+        assert!(machine.apply_forwarding_policy(&own_device, &inbound, &key_info).await.is_ok());
+
+        machine.set_forwarding_policy(KeyForwardingPolicy::Never);
+        assert_eq!(
+            machine
+                .apply_forwarding_policy(&own_device, &inbound, &key_info)
+                .await
+                .expect_err("Should never share"),
+            KeyshareDecision::RefusedByPolicy
+        );
+
+        machine.set_forwarding_policy(KeyForwardingPolicy::OwnVerifiedDevices);
+        assert!(machine.apply_forwarding_policy(&own_device, &inbound, &key_info).await.is_ok());
+        assert_eq!(
+            machine
+                .apply_forwarding_policy(&bob_device, &inbound, &key_info)
+                .await
+                .expect_err("Should not share with other users"),
+            KeyshareDecision::RefusedByPolicy
+        );
+
+        machine.set_forwarding_policy(KeyForwardingPolicy::Custom(Arc::new(|_, _| {
+            KeyForwardingDecision::AskUser
+        })));
+        assert_eq!(
+            machine
+                .apply_forwarding_policy(&bob_device, &inbound, &key_info)
+                .await
+                .expect_err("Should wait for the user"),
+            KeyshareDecision::AwaitingApproval
+        );
+
+        // A custom policy can't make us share a session the device shouldn't
+        // get.
+        machine.set_forwarding_policy(KeyForwardingPolicy::Custom(Arc::new(|_, _| {
+            KeyForwardingDecision::Share
+        })));
+        assert_eq!(
+            machine
+                .apply_forwarding_policy(&bob_device, &inbound, &key_info)
+                .await
+                .expect_err("Should not share a session that wasn't shared with the device"),
+            KeyshareDecision::MissingOutboundSession
+        );
+    }
+
     #[async_test]
     async fn key_share_cycle() {
         let alice_machine = get_machine().await;
@@ -1255,7 +1648,7 @@ mod test {
 
         if let AnyToDeviceEvent::ForwardedRoomKey(mut e) = decrypted.event.deserialize().unwrap() {
             let (_, session) = alice_machine
-                .receive_forwarded_room_key(&decrypted.sender_key, &mut e)
+                .receive_forwarded_room_key(&decrypted.sender_key, &mut e, false)
                 .await
                 .unwrap();
             alice_machine.store.save_inbound_group_sessions(&[session.unwrap()]).await.unwrap();
@@ -1404,7 +1797,7 @@ mod test {
 
         if let AnyToDeviceEvent::ForwardedRoomKey(mut e) = decrypted.event.deserialize().unwrap() {
             let (_, session) = alice_machine
-                .receive_forwarded_room_key(&decrypted.sender_key, &mut e)
+                .receive_forwarded_room_key(&decrypted.sender_key, &mut e, false)
                 .await
                 .unwrap();
             alice_machine.store.save_inbound_group_sessions(&[session.unwrap()]).await.unwrap();
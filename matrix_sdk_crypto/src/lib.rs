@@ -27,6 +27,8 @@
 )]
 #![cfg_attr(feature = "docs", feature(doc_cfg))]
 
+pub mod backups;
+mod decryption_retry;
 mod error;
 mod file_encryption;
 mod identities;
@@ -34,25 +36,48 @@ mod key_request;
 mod machine;
 pub mod olm;
 mod requests;
+pub mod secret_storage;
 mod session_manager;
 pub mod store;
+mod to_device_inbox;
+pub mod types;
 mod utilities;
 mod verification;
 
-pub use error::{MegolmError, OlmError};
+pub use decryption_retry::RoomKeysReceived;
+pub use error::{ErrorContext, MegolmError, OlmError, VerificationRequestError};
 pub use file_encryption::{
     decrypt_key_export, encrypt_key_export, AttachmentDecryptor, AttachmentEncryptor,
-    DecryptorError, EncryptionInfo, KeyExportError,
+    DecryptorError, EncryptionInfo, KeyExportError, KeyExportWriter, RoomKeyImportResult,
 };
 pub use identities::{
     Device, LocalTrust, OwnUserIdentity, ReadOnlyDevice, UserDevices, UserIdentities, UserIdentity,
 };
-pub use machine::OlmMachine;
-pub use olm::EncryptionSettings;
+pub use key_request::{IncomingKeyRequest, KeyForwardingDecision, KeyForwardingPolicy};
+pub use machine::{CryptoStatus, IndirectRoomKeyPolicy, OlmMachine, TrustChangeWarnings};
+#[cfg(feature = "qrcode")]
+#[cfg_attr(feature = "docs", doc(cfg(qrcode)))]
+pub use matrix_qrcode;
 pub(crate) use olm::ReadOnlyAccount;
+pub use olm::{
+    CrossSigningReset, EncryptionSettings, KeysUploadDiagnostics, KeysUploadFailure, RoomKeySource,
+    RoomKeyWithheldInfo, ShareDecision, SharingHistoryEntry, StoredRoomKeyBundleData, WithheldCode,
+    WithheldReason,
+};
 pub use requests::{
     IncomingResponse, KeysQueryRequest, OutgoingRequest, OutgoingRequests,
-    OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest,
+    OutgoingVerificationRequest, RequestType, RoomMessageRequest, StoredOutgoingRequest,
+    StoredRequest, ToDeviceRequest,
+};
+pub use session_manager::{PendingToDeviceMessage, RoomKeySharingProgress};
+pub use store::{CryptoStoreError, DynCryptoStore, IntoCryptoStore};
+pub use to_device_inbox::StoredToDeviceEvent;
+pub use utilities::set_identifier_redaction;
+#[cfg(feature = "qrcode")]
+#[cfg_attr(feature = "docs", doc(cfg(qrcode)))]
+pub use verification::QrVerification;
+pub use verification::{
+    bytes_to_decimal, bytes_to_emoji, bytes_to_emoji_index, emoji_from_index,
+    short_auth_string_info, AcceptSettings, InterruptedVerification, Sas, StoredVerificationFlow,
+    Verification, VerificationRequest,
 };
-pub use store::CryptoStoreError;
-pub use verification::{AcceptSettings, Sas, VerificationRequest};
@@ -14,12 +14,28 @@
 
 #[cfg(feature = "sled_cryptostore")]
 use std::path::Path;
-use std::{collections::BTreeMap, mem, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    future::Future,
+    io::Write,
+    mem,
+    sync::{Arc, Mutex as StdMutex, RwLock as StdRwLock},
+};
 
 use dashmap::DashMap;
+use futures::{
+    channel::mpsc::{unbounded, UnboundedSender},
+    future::{select, Either},
+    pin_mut, Stream,
+};
 use matrix_sdk_common::{
-    deserialized_responses::{AlgorithmInfo, EncryptionInfo, SyncRoomEvent, VerificationState},
+    deserialized_responses::{
+        AlgorithmInfo, EncryptionInfo, SyncRoomEvent, TrustChange, VerificationState,
+    },
     locks::Mutex,
+    metrics,
+    time::{instant_to_timestamp, Duration},
     uuid::Uuid,
 };
 use ruma::{
@@ -34,33 +50,53 @@ use ruma::{
     },
     assign,
     events::{
-        room::encrypted::{EncryptedEventContent, EncryptedEventScheme},
-        room_key::RoomKeyToDeviceEventContent,
-        AnyMessageEventContent, AnyRoomEvent, AnyToDeviceEvent, SyncMessageEvent, ToDeviceEvent,
+        room::encrypted::EncryptedEventContent, room_key::RoomKeyToDeviceEventContent,
+        AnyMessageEventContent, AnyRoomEvent, AnyToDeviceEvent, EventContent, EventType,
+        SyncMessageEvent, ToDeviceEvent,
     },
-    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId, UInt, UserId,
+    serde::Raw,
+    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, EventEncryptionAlgorithm,
+    MilliSecondsSinceUnixEpoch, RoomId, UInt, UserId,
 };
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, error, info, instrument, trace, warn};
+use zeroize::Zeroizing;
 
 #[cfg(feature = "sled_cryptostore")]
 use crate::store::sled::SledStore;
 use crate::{
-    error::{EventError, MegolmError, MegolmResult, OlmError, OlmResult},
-    identities::{Device, IdentityManager, UserDevices},
-    key_request::KeyRequestMachine,
+    backups::{
+        BackupDecryptionKey, BackupMachine, BackupProgress, BackupTrust, BackupUploadFailure,
+        KeyBackupData, KeysBackupDownloadRequest, KeysBackupRequest, NewBackup,
+    },
+    error::{ErrorContext, MegolmError, MegolmResult, OlmError, OlmResult},
+    file_encryption::{KeyExportError, KeyExportWriter, RoomKeyImportResult},
+    identities::{Device, IdentityManager, LocalTrust, ReadOnlyDevice, UserDevices},
+    key_request::{IncomingKeyRequest, KeyForwardingPolicy, KeyRequestMachine},
     olm::{
-        Account, EncryptionSettings, ExportedRoomKey, GroupSessionKey, IdentityKeys,
-        InboundGroupSession, OlmDecryptionInfo, PrivateCrossSigningIdentity, ReadOnlyAccount,
-        SessionType,
+        has_shared_history_flag, Account, CrossSigningReset, EncryptionSettings, ExportedRoomKey,
+        GroupEncryptedContent, GroupSessionKey, IdentityKeys, InboundGroupSession,
+        KeysUploadDiagnostics, KeysUploadFailure, OlmDecryptionInfo, PrivateCrossSigningIdentity,
+        ReadOnlyAccount, RoomKeyWithheldEvent, RoomKeyWithheldInfo, SessionType,
+        SharingHistoryEntry, ROOM_KEY_WITHHELD_EVENT_TYPE,
     },
-    requests::{IncomingResponse, OutgoingRequest, UploadSigningKeysRequest},
-    session_manager::{GroupSessionManager, SessionManager},
+    requests::{
+        IncomingResponse, OutgoingRequest, RequestTracker, RequestType, StoredOutgoingRequest,
+        UploadSigningKeysRequest,
+    },
+    secret_storage::{
+        SecretImportError, SecretStorageKey, MASTER_KEY_SECRET_NAME, SELF_SIGNING_KEY_SECRET_NAME,
+        USER_SIGNING_KEY_SECRET_NAME,
+    },
+    session_manager::{GroupSessionManager, RoomKeySharingProgress, SessionManager},
     store::{
-        Changes, CryptoStore, DeviceChanges, IdentityChanges, MemoryStore, Result as StoreResult,
-        Store,
+        Changes, CrossProcessStoreLock, CrossProcessStoreLockGuard, DeviceChanges, DynCryptoStore,
+        IdentityChanges, IntoCryptoStore, MemoryStore, Result as StoreResult, Store,
     },
+    to_device_inbox::{is_retryable, StoredToDeviceEvent, MAX_ATTEMPTS, MAX_INBOX_SIZE},
+    types::Curve25519PublicKey,
+    utilities::log_id,
     verification::{Sas, VerificationMachine, VerificationRequest},
-    ToDeviceRequest,
+    RoomKeysReceived, ToDeviceRequest,
 };
 
 /// State machine implementation of the Olm/Megolm encryption protocol used for
@@ -95,7 +131,104 @@ pub struct OlmMachine {
     /// State machine handling public user identities and devices, keeping track
     /// of when a key query needs to be done and handling one.
     identity_manager: IdentityManager,
+    /// State machine restoring missing room keys from a server-side key
+    /// backup.
+    backup_machine: BackupMachine,
     cross_signing_request: Arc<Mutex<Option<UploadSignaturesRequest>>>,
+    /// A newly created cross signing identity whose public keys weren't
+    /// uploaded yet, it replaces our identity once the upload succeeds.
+    pending_cross_signing: Arc<Mutex<Option<PendingCrossSigning>>>,
+    /// The lock that serializes the access to the store between processes,
+    /// if enabled.
+    store_lock: Option<CrossProcessStoreLock>,
+    /// Decides if events can be decrypted with room keys that we didn't
+    /// receive directly from the sender.
+    indirect_room_key_policy: Arc<StdRwLock<IndirectRoomKeyPolicy>>,
+    /// Decides which changes of the trust in a sender device are reported.
+    trust_change_warnings: Arc<StdRwLock<TrustChangeWarnings>>,
+    /// How long sharing a room key waits for the stale device lists of the
+    /// recipients to be updated.
+    stale_devices_timeout: Arc<StdRwLock<Duration>>,
+    /// The requests we handed out and the responses we already handled.
+    request_tracker: RequestTracker,
+    /// The subscribers that want to know when the [`CryptoStatus`] might have
+    /// changed.
+    crypto_status_listeners: Arc<StdMutex<Vec<UnboundedSender<()>>>>,
+}
+
+/// A cross signing identity that was created but whose public keys aren't
+/// uploaded yet.
+///
+/// The upload requires user interactive auth and might need to be retried,
+/// the same identity and requests are handed out until the upload succeeds.
+#[derive(Clone, Debug)]
+struct PendingCrossSigning {
+    identity: PrivateCrossSigningIdentity,
+    request_id: Uuid,
+    request: UploadSigningKeysRequest,
+    signature_request: UploadSignaturesRequest,
+}
+
+/// Policy deciding if events can be decrypted with room keys that we didn't
+/// receive directly from the sender of the events.
+///
+/// Such room keys were forwarded to us by another device, imported from a key
+/// export file or restored from a server-side key backup. They don't prove
+/// that the sender really sent the event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndirectRoomKeyPolicy {
+    /// Decrypt the events, the [`EncryptionInfo`] of the event will be marked
+    /// as decrypted with an indirect room key.
+    Allow,
+    /// Refuse to decrypt the events, for deployments that only want to trust
+    /// room keys that were exchanged between the sender and us.
+    Reject,
+}
+
+impl Default for IndirectRoomKeyPolicy {
+    fn default() -> Self {
+        IndirectRoomKeyPolicy::Allow
+    }
+}
+
+/// Setting deciding which changes of the trust in the device that sent an
+/// event are reported when the [`EncryptionInfo`] of the event is
+/// re-evaluated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrustChangeWarnings {
+    /// Don't report any trust changes.
+    Disabled,
+    /// Only report devices that were trusted when the event was decrypted but
+    /// aren't trusted anymore.
+    Downgrades,
+    /// Report downgrades as well as devices that got verified after the event
+    /// was decrypted.
+    All,
+}
+
+impl Default for TrustChangeWarnings {
+    fn default() -> Self {
+        TrustChangeWarnings::Downgrades
+    }
+}
+
+/// A summary of the end-to-end encryption setup of our own account, see
+/// [`OlmMachine::crypto_status()`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CryptoStatus {
+    /// Do we have the private cross signing keys of our user.
+    pub cross_signing_set_up: bool,
+    /// Are our room keys uploaded to a server-side key backup.
+    ///
+    /// Keys are only uploaded to a backup that was enabled using
+    /// [`OlmMachine::enable_backup()`], which requires the backup to be
+    /// verified first.
+    pub backup_enabled: bool,
+    /// The number of our own devices, not counting this device, that aren't
+    /// verified.
+    pub unverified_own_devices: usize,
+    /// The number of room keys that aren't uploaded to the backup yet.
+    pub keys_not_backed_up: usize,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -109,6 +242,11 @@ impl std::fmt::Debug for OlmMachine {
 }
 
 impl OlmMachine {
+    /// The key of the cross process lock that protects the crypto store.
+    const STORE_LOCK_KEY: &'static str = "crypto_store";
+    /// The default time sharing a room key waits for stale device lists.
+    const STALE_DEVICES_TIMEOUT: Duration = Duration::from_secs(10);
+
     /// Create a new memory based OlmMachine.
     ///
     /// The created machine will keep the encryption keys only in memory and
@@ -120,7 +258,7 @@ impl OlmMachine {
     ///
     /// * `device_id` - The unique id of the device that owns this machine.
     pub fn new(user_id: &UserId, device_id: &DeviceId) -> Self {
-        let store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
+        let store = MemoryStore::new().into_crypto_store();
         let device_id: DeviceIdBox = device_id.into();
         let account = ReadOnlyAccount::new(user_id, &device_id);
 
@@ -136,14 +274,13 @@ impl OlmMachine {
     fn new_helper(
         user_id: &UserId,
         device_id: DeviceIdBox,
-        store: Box<dyn CryptoStore>,
+        store: Arc<DynCryptoStore>,
         account: ReadOnlyAccount,
         user_identity: PrivateCrossSigningIdentity,
     ) -> Self {
         let user_id = Arc::new(user_id.clone());
         let user_identity = Arc::new(Mutex::new(user_identity));
 
-        let store: Arc<dyn CryptoStore> = store.into();
         let verification_machine =
             VerificationMachine::new(account.clone(), user_identity.clone(), store.clone());
         let store =
@@ -171,6 +308,7 @@ impl OlmMachine {
         );
         let identity_manager =
             IdentityManager::new(user_id.clone(), device_id.clone(), store.clone());
+        let backup_machine = BackupMachine::new(account.inner.clone(), store.clone());
 
         OlmMachine {
             user_id,
@@ -183,7 +321,15 @@ impl OlmMachine {
             verification_machine,
             key_request_machine,
             identity_manager,
+            backup_machine,
             cross_signing_request: Arc::new(Mutex::new(None)),
+            pending_cross_signing: Arc::new(Mutex::new(None)),
+            store_lock: None,
+            indirect_room_key_policy: Default::default(),
+            trust_change_warnings: Default::default(),
+            stale_devices_timeout: Arc::new(StdRwLock::new(Self::STALE_DEVICES_TIMEOUT)),
+            request_tracker: RequestTracker::default(),
+            crypto_status_listeners: Default::default(),
         }
     }
 
@@ -202,40 +348,58 @@ impl OlmMachine {
     ///
     /// * `device_id` - The unique id of the device that owns this machine.
     ///
-    /// * `store` - A `Cryptostore` implementation that will be used to store
-    /// the encryption keys.
+    /// * `store` - A [`CryptoStore`] implementation that will be used to store
+    /// the encryption keys, see [`IntoCryptoStore`] for the accepted types.
     ///
-    /// [`Cryptostore`]: trait.CryptoStore.html
+    /// [`CryptoStore`]: crate::store::CryptoStore
     pub async fn new_with_store(
         user_id: UserId,
         device_id: DeviceIdBox,
-        store: Box<dyn CryptoStore>,
+        store: impl IntoCryptoStore,
     ) -> StoreResult<Self> {
-        let account = match store.load_account().await? {
-            Some(a) => {
+        let store = store.into_crypto_store();
+        let (account, identity) = match store.load_account().await? {
+            Some(account) => {
                 debug!("Restored account");
-                a
+
+                let identity = match store.load_identity().await? {
+                    Some(i) => {
+                        debug!("Restored the cross signing identity");
+                        i
+                    }
+                    None => {
+                        debug!("Creating an empty cross signing identity stub");
+                        PrivateCrossSigningIdentity::empty(user_id.clone())
+                    }
+                };
+
+                (account, identity)
             }
             None => {
-                debug!("Creating a new account");
+                debug!("Creating a new account and an empty cross signing identity stub");
+
                 let account = ReadOnlyAccount::new(&user_id, &device_id);
-                store.save_account(account.clone()).await?;
-                account
+                let identity = PrivateCrossSigningIdentity::empty(user_id.clone());
+
+                // Persist the account, and with it our device keys, together
+                // with the identity, a crash between two separate writes
+                // would leave a half initialized store behind.
+                let changes = Changes {
+                    account: Some(account.clone()),
+                    private_identity: Some(identity.clone()),
+                    ..Default::default()
+                };
+                store.save_changes(changes).await?;
+
+                (account, identity)
             }
         };
 
-        let identity = match store.load_identity().await? {
-            Some(i) => {
-                debug!("Restored the cross signing identity");
-                i
-            }
-            None => {
-                debug!("Creating an empty cross signing identity stub");
-                PrivateCrossSigningIdentity::empty(user_id.clone())
-            }
-        };
+        let machine = OlmMachine::new_helper(&user_id, device_id, store, account, identity);
+        machine.verification_machine.load_stored_flows().await?;
+        machine.session_manager.load_pending_messages().await?;
 
-        Ok(OlmMachine::new_helper(&user_id, device_id, store, account, identity))
+        Ok(machine)
     }
 
     /// Create a new machine with the default crypto store.
@@ -257,7 +421,104 @@ impl OlmMachine {
     ) -> StoreResult<Self> {
         let store = SledStore::open_with_passphrase(path, passphrase)?;
 
-        OlmMachine::new_with_store(user_id.to_owned(), device_id.into(), Box::new(store)).await
+        OlmMachine::new_with_store(user_id.to_owned(), device_id.into(), store).await
+    }
+
+    /// Enable the lock that serializes the access to the crypto store between
+    /// multiple processes.
+    ///
+    /// This is needed if another process, e.g. a notification service
+    /// extension on mobile platforms, opens the same store. Once enabled,
+    /// [`receive_sync_changes()`](#method.receive_sync_changes) and
+    /// [`mark_request_as_sent()`](#method.mark_request_as_sent) hold the lock
+    /// while they modify the store, other operations can be protected using
+    /// [`lock_store()`](#method.lock_store).
+    ///
+    /// # Arguments
+    ///
+    /// * `holder` - A name that is unique to this process.
+    pub fn enable_cross_process_store_lock(&mut self, holder: &str) {
+        self.store_lock = Some(self.store.cross_process_lock(Self::STORE_LOCK_KEY, holder));
+    }
+
+    /// Acquire the cross process lock of the store, waiting until other
+    /// processes release it.
+    ///
+    /// Returns `None` if the lock isn't enabled. If another process held the
+    /// lock since we held it last, the objects we cached in memory are loaded
+    /// from the store again.
+    ///
+    /// The lock is held until the guard is released or until the lease
+    /// expires.
+    pub async fn lock_store(&self) -> StoreResult<Option<CrossProcessStoreLockGuard<'_>>> {
+        let lock = if let Some(lock) = &self.store_lock {
+            lock
+        } else {
+            return Ok(None);
+        };
+
+        let guard = lock.lock().await?;
+
+        if guard.is_dirty() {
+            self.reload_caches().await?;
+        }
+
+        Ok(Some(guard))
+    }
+
+    /// Run the given operation while holding the cross process lock of the
+    /// store, if it's enabled.
+    ///
+    /// The lease of the lock is renewed while the operation runs.
+    async fn with_store_lock<T>(
+        &self,
+        operation: impl Future<Output = OlmResult<T>>,
+    ) -> OlmResult<T> {
+        let guard = self.lock_store().await?;
+
+        let result = if let Some(guard) = &guard {
+            pin_mut!(operation);
+            let keep_alive = guard.keep_alive();
+            pin_mut!(keep_alive);
+
+            match select(operation, keep_alive).await {
+                Either::Left((result, _)) => result,
+                Either::Right((renewal, operation)) => {
+                    warn!(
+                        error = ?renewal.err(),
+                        "Lost the cross process store lock while holding it"
+                    );
+                    operation.await
+                }
+            }
+        } else {
+            operation.await
+        };
+
+        if let Some(guard) = guard {
+            guard.release().await?;
+        }
+
+        result
+    }
+
+    /// Drop the objects we cached in memory and load them from the store,
+    /// another process might have modified them.
+    async fn reload_caches(&self) -> StoreResult<()> {
+        debug!("Reloading the crypto caches, another process modified the store");
+
+        self.store.invalidate_caches().await?;
+        self.group_session_manager.session_cache().clear();
+
+        if let Some(account) = self.store.load_account().await? {
+            self.account.inner.replace_with(&account).await;
+        }
+
+        if let Some(identity) = self.store.load_identity().await? {
+            *self.user_identity.lock().await = identity;
+        }
+
+        Ok(())
     }
 
     /// The unique user id that owns this `OlmMachine` instance.
@@ -281,6 +542,12 @@ impl OlmMachine {
     /// out to the server and the responses need to be passed back to the state
     /// machine using [`mark_request_as_sent`].
     ///
+    /// To-device requests and signature uploads are put into the store when
+    /// they are created and kept there until they are marked as sent, requests
+    /// that weren't acknowledged before a restart will be returned again, with
+    /// the same request and transaction ids. Requests that didn't get
+    /// acknowledged within [`StoredOutgoingRequest::TTL`] are dropped.
+    ///
     /// [`mark_request_as_sent`]: #method.mark_request_as_sent
     pub async fn outgoing_requests(&self) -> StoreResult<Vec<OutgoingRequest>> {
         let mut requests = Vec::new();
@@ -302,9 +569,48 @@ impl OlmMachine {
             requests.push(request);
         }
 
-        requests.append(&mut self.verification_machine.outgoing_messages());
+        requests.append(&mut self.stored_outgoing_requests().await?);
         requests.append(&mut self.key_request_machine.outgoing_to_device_requests().await?);
 
+        self.request_tracker.evict();
+
+        for request in &requests {
+            self.request_tracker.track(request.request_id, request.request.request_type());
+        }
+
+        Ok(requests)
+    }
+
+    /// Get the to-device requests and signature uploads that are waiting in
+    /// the store, this includes the ones a previous run didn't get
+    /// acknowledged, and the verification requests that can't be stored.
+    async fn stored_outgoing_requests(&self) -> StoreResult<Vec<OutgoingRequest>> {
+        // Requests that got queued up outside of an event handler, e.g. by
+        // scanning a QR code, still need to be put into the store.
+        self.verification_machine.persist_outgoing_requests().await?;
+
+        let mut requests: Vec<OutgoingRequest> = self
+            .verification_machine
+            .outgoing_messages()
+            .into_iter()
+            .filter(|r| StoredOutgoingRequest::from_request(r).is_none())
+            .collect();
+
+        for request in self.store.get_outgoing_requests().await? {
+            if request.is_expired() {
+                warn!(
+                    request_id = request.request_id.to_string().as_str(),
+                    "Dropping an outgoing request that wasn't acknowledged in time",
+                );
+
+                self.verification_machine.mark_request_as_sent(&request.request_id);
+                self.session_manager.mark_outgoing_request_as_sent(&request.request_id);
+                self.store.remove_outgoing_request(request.request_id).await?;
+            } else {
+                requests.push(request.into());
+            }
+        }
+
         Ok(requests)
     }
 
@@ -317,12 +623,66 @@ impl OlmMachine {
     ///
     /// * `response` - The response that was received from the server after the
     /// outgoing request was sent out.
+    ///
+    /// Marking a request as sent more than once is a no-op. An
+    /// [`OlmError::MismatchedResponse`] error is returned if the response
+    /// doesn't belong to the type of the request we handed out with the given
+    /// id, an [`OlmError::UnknownRequest`] error if we didn't hand out a
+    /// request with the given id.
+    ///
+    /// The current `/sendToDevice` response doesn't report failures for
+    /// individual devices, a to-device request is considered to be delivered
+    /// to all of its recipients once it's marked as sent.
     pub async fn mark_request_as_sent<'a>(
         &self,
         request_id: &Uuid,
         response: impl Into<IncomingResponse<'a>>,
     ) -> OlmResult<()> {
-        match response.into() {
+        self.with_store_lock(self.mark_request_as_sent_helper(request_id, response.into())).await
+    }
+
+    async fn mark_request_as_sent_helper(
+        &self,
+        request_id: &Uuid,
+        response: IncomingResponse<'_>,
+    ) -> OlmResult<()> {
+        let response_type = response.request_type();
+
+        if !self.request_tracker.complete(request_id, response_type)? {
+            debug!(
+                request_id = request_id.to_string().as_str(),
+                response_type = ?response_type,
+                "The request was already marked as sent",
+            );
+
+            return Ok(());
+        }
+
+        if let Err(e) = self.handle_response(request_id, response).await {
+            // The response wasn't handled, it may be passed in again.
+            self.request_tracker.reopen(request_id);
+            return Err(e);
+        }
+
+        if matches!(
+            response_type,
+            RequestType::KeysUpload
+                | RequestType::KeysQuery
+                | RequestType::SigningKeysUpload
+                | RequestType::SignatureUpload
+        ) {
+            self.notify_crypto_status_change();
+        }
+
+        Ok(())
+    }
+
+    async fn handle_response(
+        &self,
+        request_id: &Uuid,
+        response: IncomingResponse<'_>,
+    ) -> OlmResult<()> {
+        match response {
             IncomingResponse::KeysUpload(response) => {
                 self.receive_keys_upload_response(response).await?;
             }
@@ -334,12 +694,14 @@ impl OlmMachine {
             }
             IncomingResponse::ToDevice(_) => {
                 self.mark_to_device_request_as_sent(request_id).await?;
+                self.store.remove_outgoing_request(*request_id).await?;
             }
             IncomingResponse::SigningKeysUpload(_) => {
                 self.receive_cross_signing_upload_response().await?;
             }
             IncomingResponse::SignatureUpload(_) => {
                 self.verification_machine.mark_request_as_sent(request_id);
+                self.store.remove_outgoing_request(*request_id).await?;
             }
             IncomingResponse::RoomMessage(_) => {
                 self.verification_machine.mark_request_as_sent(request_id);
@@ -350,13 +712,42 @@ impl OlmMachine {
     }
 
     /// Mark the cross signing identity as shared.
-    async fn receive_cross_signing_upload_response(&self) -> StoreResult<()> {
-        let identity = self.user_identity.lock().await;
-        identity.mark_as_shared();
+    ///
+    /// If a new identity was created, it replaces our current one now that its
+    /// public keys are known to the server.
+    async fn receive_cross_signing_upload_response(&self) -> OlmResult<()> {
+        let mut identity = self.user_identity.lock().await;
+        let pending = self.pending_cross_signing.lock().await.take();
 
-        let changes = Changes { private_identity: Some(identity.clone()), ..Default::default() };
+        if let Some(pending) = pending {
+            info!("The new cross signing identity was uploaded");
+
+            pending.identity.mark_as_shared();
+            let public = pending.identity.as_public_identity().await.expect(
+                "Couldn't create a public version of the identity from a new private identity",
+            );
+
+            *identity = pending.identity;
+
+            let changes = Changes {
+                identities: IdentityChanges { new: vec![public.into()], ..Default::default() },
+                private_identity: Some(identity.clone()),
+                ..Default::default()
+            };
+            self.store.save_changes(changes).await?;
+
+            // Our own identity changed, make sure that we pick up the new
+            // public identity and the new signatures of our devices right away.
+            self.identity_manager.force_refresh(std::iter::once(self.user_id())).await?;
+        } else {
+            identity.mark_as_shared();
+
+            let changes =
+                Changes { private_identity: Some(identity.clone()), ..Default::default() };
+            self.store.save_changes(changes).await?;
+        }
 
-        self.store.save_changes(changes).await
+        Ok(())
     }
 
     /// Create a new cross signing identity and get the upload request to push
@@ -367,39 +758,192 @@ impl OlmMachine {
     /// devices.
     ///
     /// Uploading these keys will require user interactive auth.
+    ///
+    /// A new identity only replaces our current one once the upload request
+    /// was marked as sent using [`mark_request_as_sent`]. Until then, calling
+    /// this method again, e.g. to retry the upload with user interactive auth,
+    /// returns the requests of the same new identity.
+    ///
+    /// Returns the unique id of the upload request, it needs to be passed to
+    /// [`mark_request_as_sent`] together with the response, the upload request
+    /// and the request that uploads the signature of our own device.
+    ///
+    /// [`mark_request_as_sent`]: #method.mark_request_as_sent
     pub async fn bootstrap_cross_signing(
         &self,
         reset: bool,
-    ) -> StoreResult<(UploadSigningKeysRequest, UploadSignaturesRequest)> {
-        let mut identity = self.user_identity.lock().await;
-
-        if identity.is_empty().await || reset {
-            info!("Creating new cross signing identity");
-            let (id, request, signature_request) = self.account.bootstrap_cross_signing().await;
+    ) -> StoreResult<(Uuid, UploadSigningKeysRequest, UploadSignaturesRequest)> {
+        let identity = self.user_identity.lock().await;
 
-            *identity = id;
+        let requests = if identity.is_empty().await || reset {
+            let mut pending = self.pending_cross_signing.lock().await;
 
-            let public = identity.as_public_identity().await.expect(
-                "Couldn't create a public version of the identity from a new private identity",
-            );
+            if pending.is_none() {
+                info!("Creating new cross signing identity");
+                let (identity, request, signature_request) =
+                    self.account.bootstrap_cross_signing().await;
 
-            let changes = Changes {
-                identities: IdentityChanges { new: vec![public.into()], ..Default::default() },
-                private_identity: Some(identity.clone()),
-                ..Default::default()
-            };
+                *pending = Some(PendingCrossSigning {
+                    identity,
+                    request_id: Uuid::new_v4(),
+                    request,
+                    signature_request,
+                });
+            } else {
+                info!("Reusing the cross signing identity that wasn't uploaded yet");
+            }
 
-            self.store.save_changes(changes).await?;
+            let pending = pending.as_ref().expect("A pending cross signing identity was created");
 
-            Ok((request, signature_request))
+            (pending.request_id, pending.request.clone(), pending.signature_request.clone())
         } else {
             info!("Trying to upload the existing cross signing identity");
             let request = identity.as_upload_request().await;
             // TODO remove this expect.
             let signature_request =
                 identity.sign_account(&self.account).await.expect("Can't sign device keys");
-            Ok((request, signature_request))
-        }
+            (Uuid::new_v4(), request, signature_request)
+        };
+
+        self.request_tracker.track(requests.0, RequestType::SigningKeysUpload);
+
+        Ok(requests)
+    }
+
+    /// Replace our cross signing identity with a newly created one.
+    ///
+    /// **Warning**: This will reset the trust between all the devices and
+    /// users that were verified using the old identity.
+    ///
+    /// The returned [`CrossSigningReset`] contains the requests that need to be
+    /// sent out to finish the reset:
+    ///
+    /// 1. The `upload_signing_keys_request` needs to be sent out first, it
+    ///    requires user interactive auth. The response needs to be passed to
+    ///    [`mark_request_as_sent`] together with the `request_id`.
+    /// 2. The `upload_signatures_request` uploads the signature of our own
+    ///    device, made by the new self-signing key.
+    /// 3. The `secret_storage_content` needs to be uploaded as global account
+    ///    data, replacing the old private keys in secret storage.
+    ///
+    /// The new identity replaces our current one once the
+    /// `upload_signing_keys_request` was marked as sent, our own user is then
+    /// marked as changed and the next keys query will pick up the new identity
+    /// and update the trust of our other devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_storage_key` - The secret storage key that should be used to
+    /// encrypt the new private keys. If this is `None` the private keys won't
+    /// be put into secret storage.
+    ///
+    /// [`mark_request_as_sent`]: #method.mark_request_as_sent
+    pub async fn reset_cross_signing(
+        &self,
+        secret_storage_key: Option<&SecretStorageKey>,
+    ) -> StoreResult<CrossSigningReset> {
+        let (request_id, upload_signing_keys_request, upload_signatures_request) =
+            self.bootstrap_cross_signing(true).await?;
+
+        let secret_storage_content = if let Some(key) = secret_storage_key {
+            self.pending_cross_signing
+                .lock()
+                .await
+                .as_ref()
+                .expect("A pending cross signing identity was just created")
+                .identity
+                .export_secrets()
+                .await
+                .iter()
+                .map(|(name, secret)| (name.to_string(), key.encrypt_secret_content(name, secret)))
+                .collect()
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(CrossSigningReset {
+            request_id,
+            upload_signing_keys_request,
+            upload_signatures_request,
+            secret_storage_content,
+        })
+    }
+
+    /// Verify our own identity using the private cross signing keys that are
+    /// stored in secret storage.
+    ///
+    /// This is the "Verify with Security Key" flow, the private keys are
+    /// decrypted using the secret storage key, checked against our published
+    /// cross signing identity and imported. Our identity is marked as
+    /// verified and this device gets signed by the imported self-signing key.
+    ///
+    /// Our own identity needs to be known, e.g. a keys query for our own user
+    /// needs to have been done, before this is called.
+    ///
+    /// Returns the request that uploads the signature of this device.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_storage_key` - The secret storage key, restored from the
+    /// recovery key or passphrase the user entered.
+    ///
+    /// * `secrets` - The content of the account data events that hold the
+    /// private cross signing keys, keyed by the event type. The
+    /// `m.cross_signing.master` and `m.cross_signing.self_signing` secrets are
+    /// required, the `m.cross_signing.user_signing` secret is optional.
+    pub async fn verify_with_secret_storage(
+        &self,
+        secret_storage_key: &SecretStorageKey,
+        secrets: &BTreeMap<String, serde_json::Value>,
+    ) -> Result<UploadSignaturesRequest, SecretImportError> {
+        let public_identity = self
+            .store
+            .get_user_identity(self.user_id())
+            .await?
+            .and_then(|i| i.own().cloned())
+            .ok_or(SecretImportError::MissingIdentity)?;
+
+        let decrypt = |secret_name: &'static str| -> Result<_, SecretImportError> {
+            secrets
+                .get(secret_name)
+                .map(|c| secret_storage_key.decrypt_secret_content(secret_name, c))
+                .transpose()
+                .map(|s| s.map(Zeroizing::new))
+                .map_err(SecretImportError::from)
+        };
+
+        let master_key = decrypt(MASTER_KEY_SECRET_NAME)?
+            .ok_or(SecretImportError::MissingSecret(MASTER_KEY_SECRET_NAME))?;
+        let self_signing_key = decrypt(SELF_SIGNING_KEY_SECRET_NAME)?
+            .ok_or(SecretImportError::MissingSecret(SELF_SIGNING_KEY_SECRET_NAME))?;
+        let user_signing_key = decrypt(USER_SIGNING_KEY_SECRET_NAME)?;
+
+        let identity = PrivateCrossSigningIdentity::from_secrets(
+            &public_identity,
+            &master_key,
+            &self_signing_key,
+            user_signing_key.as_ref().map(|k| k.as_str()),
+        )?;
+
+        let signature_request = identity.sign_account(&self.account).await?;
+
+        public_identity.mark_as_verified();
+
+        let changes = Changes {
+            identities: IdentityChanges {
+                changed: vec![public_identity.into()],
+                ..Default::default()
+            },
+            private_identity: Some(identity.clone()),
+            ..Default::default()
+        };
+
+        self.store.save_changes(changes).await?;
+        *self.user_identity.lock().await = identity;
+
+        info!("Imported our private cross signing keys from secret storage");
+
+        Ok(signature_request)
     }
 
     /// Should device or one-time keys be uploaded to the server.
@@ -481,7 +1025,13 @@ impl OlmMachine {
         &self,
         users: impl Iterator<Item = &UserId>,
     ) -> StoreResult<Option<(Uuid, KeysClaimRequest)>> {
-        self.session_manager.get_missing_sessions(users).await
+        let request = self.session_manager.get_missing_sessions(users).await?;
+
+        if let Some((request_id, _)) = &request {
+            self.request_tracker.track(*request_id, RequestType::KeysClaim);
+        }
+
+        Ok(request)
     }
 
     /// Receive a successful key claim response and create new Olm sessions with
@@ -507,7 +1057,14 @@ impl OlmMachine {
         &self,
         response: &KeysQueryResponse,
     ) -> OlmResult<(DeviceChanges, IdentityChanges)> {
-        self.identity_manager.receive_keys_query_response(response).await
+        let (devices, identities) =
+            self.identity_manager.receive_keys_query_response(response).await?;
+
+        // Devices that got deleted shouldn't be able to decrypt any new
+        // messages, rotate the sessions that they received.
+        self.group_session_manager.invalidate_for_devices(&devices.deleted).await?;
+
+        Ok((devices, identities))
     }
 
     /// Get a request to upload E2EE keys to the server.
@@ -524,6 +1081,46 @@ impl OlmMachine {
         Some(assign!(upload_keys::Request::new(), { device_keys, one_time_keys }))
     }
 
+    /// Mark the keys upload request with the given id as failed.
+    ///
+    /// Depending on the failure the pending one-time keys are discarded, so
+    /// the next upload doesn't contain the same keys the server rejected, and
+    /// the number of one-time keys a single upload contains is lowered.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_id` - The unique id of the keys upload request.
+    ///
+    /// * `failure` - The reason the upload failed.
+    pub async fn mark_keys_upload_as_failed(
+        &self,
+        request_id: &Uuid,
+        failure: KeysUploadFailure,
+    ) -> StoreResult<()> {
+        debug!(request_id = %request_id, "Marking a keys upload request as failed");
+
+        if self.account.mark_keys_upload_as_failed(failure).await {
+            self.store.save_account(self.account.inner.clone()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get diagnostics about the upload of our one-time keys, e.g. how many
+    /// uploads failed in a row.
+    pub fn keys_upload_diagnostics(&self) -> KeysUploadDiagnostics {
+        self.account.keys_upload_diagnostics()
+    }
+
+    /// Set the maximal number of one-time keys a single keys upload request
+    /// contains.
+    ///
+    /// The missing one-time keys are uploaded with the following requests.
+    /// Defaults to 50, which usually fits all the keys into a single request.
+    pub fn set_one_time_keys_batch_size(&self, batch_size: usize) {
+        self.account.set_one_time_keys_batch_size(batch_size)
+    }
+
     /// Decrypt a to-device event.
     ///
     /// Returns a decrypted `ToDeviceEvent` if the decryption was successful,
@@ -532,6 +1129,7 @@ impl OlmMachine {
     /// # Arguments
     ///
     /// * `event` - The to-device event that should be decrypted.
+    #[instrument(skip(self, event), fields(sender = %log_id(event.sender.as_str())))]
     async fn decrypt_to_device_event(
         &self,
         event: &ToDeviceEvent<EncryptedEventContent>,
@@ -555,29 +1153,39 @@ impl OlmMachine {
     }
 
     /// Create a group session from a room key and add it to our crypto store.
+    #[instrument(
+        skip(self, sender_key, signing_key, event),
+        fields(
+            sender = %log_id(event.sender.as_str()),
+            room_id = %log_id(event.content.room_id.as_str()),
+            session_id = %log_id(&event.content.session_id)
+        )
+    )]
     async fn add_room_key(
         &self,
         sender_key: &str,
         signing_key: &str,
         event: &mut ToDeviceEvent<RoomKeyToDeviceEventContent>,
+        shared_history: bool,
     ) -> OlmResult<(Option<AnyToDeviceEvent>, Option<InboundGroupSession>)> {
         match event.content.algorithm {
             EventEncryptionAlgorithm::MegolmV1AesSha2 => {
                 let session_key = GroupSessionKey(mem::take(&mut event.content.session_key));
 
-                let session = InboundGroupSession::new(
+                let mut session = InboundGroupSession::new(
                     sender_key,
                     signing_key,
                     &event.content.room_id,
                     session_key,
                     None,
                 )?;
+                session.set_shared_history(shared_history);
 
                 info!(
                     "Received a new room key from {} for room {} with session id {}",
-                    event.sender,
-                    event.content.room_id,
-                    session.session_id()
+                    log_id(event.sender.as_str()),
+                    log_id(event.content.room_id.as_str()),
+                    log_id(session.session_id())
                 );
                 let event = AnyToDeviceEvent::RoomKey(event.clone());
                 Ok((Some(event), Some(session)))
@@ -639,6 +1247,80 @@ impl OlmMachine {
         self.group_session_manager.encrypt(room_id, content).await
     }
 
+    /// Encrypt a custom to-device event for a single device.
+    ///
+    /// This is useful for device-to-device protocols, the returned request
+    /// needs to be sent out and the response passed back to the machine using
+    /// [`mark_request_as_sent`].
+    ///
+    /// Returns `None` if we don't share an Olm session with the device yet. In
+    /// that case the device is included in the next [`get_missing_sessions`]
+    /// key claim request, the event is encrypted once the session is
+    /// established and the request can be fetched with
+    /// [`outgoing_requests`].
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device the event should be sent
+    /// to.
+    ///
+    /// * `event_type` - The type of the event.
+    ///
+    /// * `content` - The plaintext content of the event that should be
+    /// encrypted.
+    ///
+    /// [`mark_request_as_sent`]: #method.mark_request_as_sent
+    /// [`get_missing_sessions`]: #method.get_missing_sessions
+    /// [`outgoing_requests`]: #method.outgoing_requests
+    pub async fn encrypt_to_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        event_type: EventType,
+        content: serde_json::Value,
+    ) -> OlmResult<Option<ToDeviceRequest>> {
+        self.encrypt_to_devices(&[(user_id.to_owned(), device_id.into())], event_type, content)
+            .await
+    }
+
+    /// Encrypt a custom to-device event for multiple devices.
+    ///
+    /// The event is encrypted for every device separately, devices we don't
+    /// share an Olm session with yet are handled like in
+    /// [`encrypt_to_device`], unknown devices are skipped.
+    ///
+    /// Returns `None` if the event couldn't be encrypted for any of the
+    /// devices right away.
+    ///
+    /// # Arguments
+    ///
+    /// * `devices` - The user/device pairs the event should be sent to.
+    ///
+    /// * `event_type` - The type of the event.
+    ///
+    /// * `content` - The plaintext content of the event that should be
+    /// encrypted.
+    ///
+    /// [`encrypt_to_device`]: #method.encrypt_to_device
+    pub async fn encrypt_to_devices(
+        &self,
+        devices: &[(UserId, DeviceIdBox)],
+        event_type: EventType,
+        content: serde_json::Value,
+    ) -> OlmResult<Option<ToDeviceRequest>> {
+        let request = self
+            .with_store_lock(self.session_manager.encrypt_to_devices(devices, event_type, content))
+            .await?;
+
+        if let Some(request) = &request {
+            self.request_tracker.track(request.txn_id, RequestType::ToDevice);
+        }
+
+        Ok(request)
+    }
+
     /// Invalidate the currently active outbound group session for the given
     /// room.
     ///
@@ -648,21 +1330,234 @@ impl OlmMachine {
         self.group_session_manager.invalidate_group_session(room_id).await
     }
 
-    /// Get to-device requests to share a group session with users in a room.
+    /// Invalidate the outbound group session of the given room if it was shared
+    /// with any of the given users.
+    ///
+    /// This should be called for users that left or got banned from a room.
+    /// The session will be rotated the next time a group session is shared
+    /// for the room, so the users that left won't receive any new room keys.
+    ///
+    /// Returns true if a session was invalidated.
     ///
     /// # Arguments
     ///
-    /// `room_id` - The room id of the room where the group session will be
-    /// used.
+    /// * `room_id` - The id of the room the users left.
     ///
-    /// `users` - The list of users that should receive the group session.
-    pub async fn share_group_session(
+    /// * `users` - The users that left the room.
+    pub async fn room_members_left(
+        &self,
+        room_id: &RoomId,
+        users: impl Iterator<Item = &UserId>,
+    ) -> StoreResult<bool> {
+        self.group_session_manager.invalidate_for_left_users(room_id, users).await
+    }
+
+    /// Invalidate all the outbound group sessions that were shared with the
+    /// given device.
+    ///
+    /// This should be called after a device got blacklisted, the sessions will
+    /// be rotated the next time a group session is shared for the affected
+    /// rooms.
+    ///
+    /// Returns the list of rooms whose session was invalidated.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device that shouldn't receive new room keys anymore.
+    pub async fn invalidate_group_sessions_for_device(
+        &self,
+        device: &ReadOnlyDevice,
+    ) -> StoreResult<Vec<RoomId>> {
+        self.group_session_manager.invalidate_for_devices(&[device.clone()]).await
+    }
+
+    /// Set the local trust state of multiple devices at once.
+    ///
+    /// All the trust states are saved in a single store transaction. The
+    /// outbound group sessions that were shared with devices that get
+    /// blacklisted are invalidated in the same transaction, they will be
+    /// rotated the next time a group session is shared for the affected rooms.
+    ///
+    /// Unknown devices are skipped.
+    ///
+    /// Returns the list of rooms whose session was invalidated.
+    ///
+    /// # Arguments
+    ///
+    /// * `devices` - The user/device pairs and the new trust state that should
+    /// be set for them.
+    pub async fn set_local_trust_bulk(
+        &self,
+        devices: &[(UserId, DeviceIdBox, LocalTrust)],
+    ) -> StoreResult<Vec<RoomId>> {
+        let mut changed = Vec::new();
+
+        for (user_id, device_id, trust_state) in devices {
+            if let Some(device) = self.store.get_readonly_device(user_id, device_id).await? {
+                changed.push((device, *trust_state));
+            } else {
+                warn!(
+                    "Tried to set the local trust state of {} {}, but the device is unknown",
+                    log_id(user_id.as_str()),
+                    log_id(device_id.as_str())
+                );
+            }
+        }
+
+        self.save_local_trust(changed).await
+    }
+
+    /// Blacklist all the devices of the given user that we don't consider to
+    /// be verified, either locally or using cross signing.
+    ///
+    /// This behaves like [`set_local_trust_bulk`], the outbound group sessions
+    /// that were shared with the blacklisted devices are invalidated.
+    ///
+    /// Returns the list of rooms whose session was invalidated.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user whose unverified devices should be blacklisted.
+    ///
+    /// [`set_local_trust_bulk`]: #method.set_local_trust_bulk
+    pub async fn blacklist_all_unverified_devices_of(
+        &self,
+        user_id: &UserId,
+    ) -> StoreResult<Vec<RoomId>> {
+        let changed = self
+            .get_user_devices(user_id)
+            .await?
+            .devices()
+            .filter(|d| {
+                !(d.user_id() == self.user_id() && d.device_id() == self.device_id())
+                    && !d.trust_state()
+                    && !d.is_blacklisted()
+            })
+            .map(|d| (d.inner, LocalTrust::BlackListed))
+            .collect();
+
+        self.save_local_trust(changed).await
+    }
+
+    async fn save_local_trust(
+        &self,
+        devices: Vec<(ReadOnlyDevice, LocalTrust)>,
+    ) -> StoreResult<Vec<RoomId>> {
+        if devices.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let blacklisted: Vec<ReadOnlyDevice> = devices
+            .iter()
+            .filter(|(_, t)| *t == LocalTrust::BlackListed)
+            .map(|(d, _)| d.clone())
+            .collect();
+
+        let sessions = self.group_session_manager.sessions_shared_with(&blacklisted);
+        let rooms: Vec<RoomId> = sessions.iter().map(|s| s.room_id().to_owned()).collect();
+
+        // The devices and sessions are shared with the rest of the machine,
+        // they need to be changed so they get saved with the new state.
+        // Remember the previous state so we can roll back if saving fails.
+        let previous_invalidation: Vec<bool> = sessions.iter().map(|s| s.invalidated()).collect();
+        let previous_trust: Vec<LocalTrust> =
+            devices.iter().map(|(d, _)| d.local_trust_state()).collect();
+
+        for session in &sessions {
+            session.invalidate_session();
+        }
+
+        let changed: Vec<ReadOnlyDevice> = devices
+            .into_iter()
+            .map(|(device, trust_state)| {
+                device.set_trust_state(trust_state);
+                device
+            })
+            .collect();
+
+        let changes = Changes {
+            devices: DeviceChanges { changed: changed.clone(), ..Default::default() },
+            outbound_group_sessions: sessions.clone(),
+            ..Default::default()
+        };
+
+        if let Err(e) = self.store.save_changes(changes).await {
+            for (session, invalidated) in sessions.iter().zip(previous_invalidation) {
+                session.restore_invalidation(invalidated);
+            }
+
+            for (device, trust_state) in changed.iter().zip(previous_trust) {
+                device.set_trust_state(trust_state);
+            }
+
+            return Err(e);
+        }
+
+        Ok(rooms)
+    }
+
+    /// Get to-device requests to share a group session with users in a room.
+    ///
+    /// # Arguments
+    ///
+    /// `room_id` - The room id of the room where the group session will be
+    /// used.
+    ///
+    /// `users` - The list of users that should receive the group session.
+    ///
+    /// If the device lists of some of the users are stale, this waits until
+    /// the keys queries for them complete, at most for the time that was set
+    /// with [`set_stale_devices_timeout`]. The group session is shared with
+    /// the devices we know about once the timeout passes.
+    ///
+    /// [`set_stale_devices_timeout`]: #method.set_stale_devices_timeout
+    #[instrument(
+        skip(self, users, encryption_settings),
+        fields(room_id = %log_id(room_id.as_str()))
+    )]
+    pub async fn share_group_session(
         &self,
         room_id: &RoomId,
         users: impl Iterator<Item = &UserId>,
         encryption_settings: impl Into<EncryptionSettings>,
     ) -> OlmResult<Vec<Arc<ToDeviceRequest>>> {
-        self.group_session_manager.share_group_session(room_id, users, encryption_settings).await
+        let users: Vec<UserId> = users.cloned().collect();
+        let timeout = *self.stale_devices_timeout.read().unwrap();
+
+        let stale = self.identity_manager.wait_for_fresh_devices(&users, timeout).await;
+
+        if !stale.is_empty() {
+            warn!(
+                stale_users = ?stale,
+                "The device lists of some users are still stale, sharing the room key with the \
+                 devices we know about"
+            );
+        }
+
+        let requests = self
+            .group_session_manager
+            .share_group_session(room_id, users.iter(), encryption_settings)
+            .await?;
+
+        for request in &requests {
+            self.request_tracker.track(request.txn_id, RequestType::ToDevice);
+        }
+
+        metrics::record_room_key_shares(requests.iter().map(|r| r.message_count()).sum());
+
+        Ok(requests)
+    }
+
+    /// Get a handle to the progress of the latest room key sharing in the
+    /// given room.
+    ///
+    /// The handle is available as soon as the devices that should receive
+    /// the room key are known, [`share_group_session()`] can be called in a
+    /// separate task to follow the progress while the room key is shared.
+    ///
+    /// [`share_group_session()`]: #method.share_group_session
+    pub fn room_key_sharing_progress(&self, room_id: &RoomId) -> Option<RoomKeySharingProgress> {
+        self.group_session_manager.sharing_progress(room_id)
     }
 
     /// Receive and properly handle a decrypted to-device event.
@@ -686,13 +1581,15 @@ impl OlmMachine {
             }
         };
 
+        let shared_history = has_shared_history_flag(&decrypted.event);
+
         match event {
-            AnyToDeviceEvent::RoomKey(mut e) => {
-                Ok(self.add_room_key(&decrypted.sender_key, &decrypted.signing_key, &mut e).await?)
-            }
+            AnyToDeviceEvent::RoomKey(mut e) => Ok(self
+                .add_room_key(&decrypted.sender_key, &decrypted.signing_key, &mut e, shared_history)
+                .await?),
             AnyToDeviceEvent::ForwardedRoomKey(mut e) => Ok(self
                 .key_request_machine
-                .receive_forwarded_room_key(&decrypted.sender_key, &mut e)
+                .receive_forwarded_room_key(&decrypted.sender_key, &mut e, shared_history)
                 .await?),
             _ => {
                 warn!("Received an unexpected encrypted to-device event");
@@ -776,14 +1673,32 @@ impl OlmMachine {
     /// response returned.
     ///
     /// [`decrypt_room_event`]: #method.decrypt_room_event
+    #[instrument(
+        skip(self, to_device_events, changed_devices, one_time_keys_counts),
+        fields(to_device_events = to_device_events.events.len())
+    )]
     pub async fn receive_sync_changes(
         &self,
         to_device_events: ToDevice,
         changed_devices: &DeviceLists,
         one_time_keys_counts: &BTreeMap<DeviceKeyAlgorithm, UInt>,
+    ) -> OlmResult<ToDevice> {
+        self.with_store_lock(self.receive_sync_changes_helper(
+            to_device_events,
+            changed_devices,
+            one_time_keys_counts,
+        ))
+        .await
+    }
+
+    async fn receive_sync_changes_helper(
+        &self,
+        to_device_events: ToDevice,
+        changed_devices: &DeviceLists,
+        one_time_keys_counts: &BTreeMap<DeviceKeyAlgorithm, UInt>,
     ) -> OlmResult<ToDevice> {
         // Remove verification objects that have expired or are done.
-        self.verification_machine.garbage_collect();
+        self.verification_machine.garbage_collect().await?;
 
         // Always save the account, a new session might get created which also
         // touches the account.
@@ -798,6 +1713,8 @@ impl OlmMachine {
             }
         }
 
+        let inbox = self.store.get_inbox_events().await?;
+        let mut new_inbox_events = Vec::new();
         let mut events = Vec::new();
 
         for mut raw_event in to_device_events.events {
@@ -805,59 +1722,53 @@ impl OlmMachine {
                 Ok(e) => e,
                 Err(e) => {
                     // Skip invalid events.
-                    warn!("Received an invalid to-device event {:?} {:?}", e, raw_event);
+                    warn!("Received an invalid to-device event {}", e);
                     continue;
                 }
             };
 
-            info!("Received a to-device event {:?}", event);
+            info!(
+                "Received a to-device event of type {} from {}",
+                event.content().event_type(),
+                log_id(event.sender().as_str())
+            );
 
             match event {
                 AnyToDeviceEvent::RoomEncrypted(e) => {
                     let decrypted = match self.decrypt_to_device_event(&e).await {
                         Ok(e) => e,
                         Err(err) => {
-                            warn!("Failed to decrypt to-device event from {} {}", e.sender, err);
-
-                            if let OlmError::SessionWedged(sender, curve_key) = err {
-                                if let Err(e) = self
-                                    .session_manager
-                                    .mark_device_as_wedged(&sender, &curve_key)
-                                    .await
-                                {
-                                    error!(
-                                        "Couldn't mark device from {} to be unwedged {:?}",
-                                        sender, e
-                                    );
-                                }
+                            warn!(
+                                "Failed to decrypt to-device event from {} {}",
+                                log_id(e.sender.as_str()),
+                                err
+                            );
+
+                            if is_retryable(&err) {
+                                new_inbox_events.push(StoredToDeviceEvent::new(raw_event.clone()));
+                            } else {
+                                self.recover_wedged_session(&err).await;
                             }
+
                             continue;
                         }
                     };
 
-                    // New sessions modify the account so we need to save that
-                    // one as well.
-                    match decrypted.session {
-                        SessionType::New(s) => {
-                            changes.sessions.push(s);
-                            changes.account = Some(self.account.inner.clone());
+                    raw_event = self.handle_decrypted_to_device(decrypted, &mut changes).await;
+                }
+                AnyToDeviceEvent::Custom(e)
+                    if e.content.event_type == ROOM_KEY_WITHHELD_EVENT_TYPE =>
+                {
+                    match serde_json::from_str::<RoomKeyWithheldEvent>(raw_event.json().get()) {
+                        Ok(event) => {
+                            if let Some(info) = RoomKeyWithheldInfo::from_event(event) {
+                                changes.withheld_session_info.push(info);
+                            }
                         }
-                        SessionType::Existing(s) => {
-                            changes.sessions.push(s);
+                        Err(err) => {
+                            warn!("Received an invalid room key withheld event {:?}", err)
                         }
                     }
-
-                    changes.message_hashes.push(decrypted.message_hash);
-
-                    if let Some(group_session) = decrypted.inbound_group_session {
-                        changes.inbound_group_sessions.push(group_session);
-                    }
-
-                    if let Some(event) = decrypted.deserialized_event {
-                        self.handle_to_device_event(&event).await;
-                    }
-
-                    raw_event = decrypted.event;
                 }
                 e => self.handle_to_device_event(&e).await,
             }
@@ -865,18 +1776,155 @@ impl OlmMachine {
             events.push(raw_event);
         }
 
+        // The events of this sync might have created the sessions that were
+        // missing for the events in the inbox.
+        let (processed, mut failed) =
+            self.retry_inbox_events(inbox, &mut changes, &mut events).await;
+
         let changed_sessions = self.key_request_machine.collect_incoming_key_requests().await?;
 
         changes.sessions.extend(changed_sessions);
 
         self.store.save_changes(changes).await?;
 
+        // Only touch the inbox once the results of processing the events are
+        // stored, a failure in between will make us process them again.
+        for id in processed {
+            self.store.remove_inbox_event(id).await?;
+        }
+
+        let free_slots = MAX_INBOX_SIZE.saturating_sub(failed.len());
+
+        if new_inbox_events.len() > free_slots {
+            warn!(
+                "The to-device inbox is full, dropping {} to-device events",
+                new_inbox_events.len() - free_slots
+            );
+        }
+
+        failed.extend(new_inbox_events.into_iter().take(free_slots));
+
+        for event in &failed {
+            self.store.save_inbox_event(event).await?;
+        }
+
+        // New room keys or changes to our devices might have arrived.
+        self.notify_crypto_status_change();
+
         let mut to_device = ToDevice::new();
         to_device.events = events;
 
         Ok(to_device)
     }
 
+    /// Collect the changes a decrypted to-device event brings and handle the
+    /// decrypted event, returns the decrypted event.
+    async fn handle_decrypted_to_device(
+        &self,
+        decrypted: OlmDecryptionInfo,
+        changes: &mut Changes,
+    ) -> Raw<AnyToDeviceEvent> {
+        // New sessions modify the account so we need to save that one as
+        // well.
+        match decrypted.session {
+            SessionType::New(s) => {
+                changes.sessions.push(s);
+                changes.account = Some(self.account.inner.clone());
+            }
+            SessionType::Existing(s) => {
+                changes.sessions.push(s);
+            }
+        }
+
+        changes.message_hashes.push(decrypted.message_hash);
+
+        if let Some(group_session) = decrypted.inbound_group_session {
+            changes.inbound_group_sessions.push(group_session);
+        }
+
+        if let Some(event) = decrypted.deserialized_event {
+            self.handle_to_device_event(&event).await;
+        }
+
+        decrypted.event
+    }
+
+    /// Start the recovery of the Olm session if decrypting a to-device event
+    /// failed because the session is wedged, or because we gave up waiting for
+    /// a session with the sender.
+    async fn recover_wedged_session(&self, error: &OlmError) {
+        let (sender, curve_key) = match error {
+            OlmError::SessionWedged(sender, curve_key) => (sender, curve_key.as_str()),
+            OlmError::MissingSession(ErrorContext {
+                user_id: Some(sender),
+                sender_key: Some(curve_key),
+                ..
+            }) => (sender, curve_key.as_str()),
+            _ => return,
+        };
+
+        if let Err(e) = self.session_manager.mark_device_as_wedged(sender, curve_key).await {
+            error!("Couldn't mark device from {} to be unwedged {:?}", log_id(sender.as_str()), e);
+        }
+    }
+
+    /// Try to process the to-device events of the inbox again.
+    ///
+    /// Returns the ids of the inbox entries that should be removed, because
+    /// they were processed or because we gave up on them, and the entries
+    /// that should be retried again later.
+    async fn retry_inbox_events(
+        &self,
+        inbox: Vec<StoredToDeviceEvent>,
+        changes: &mut Changes,
+        events: &mut Vec<Raw<AnyToDeviceEvent>>,
+    ) -> (Vec<Uuid>, Vec<StoredToDeviceEvent>) {
+        let mut processed = Vec::new();
+        let mut failed = Vec::new();
+
+        for mut entry in inbox {
+            let event = match entry.event.deserialize() {
+                Ok(AnyToDeviceEvent::RoomEncrypted(e)) => e,
+                _ => {
+                    warn!("Removing an invalid to-device event from the inbox");
+                    processed.push(entry.id);
+                    continue;
+                }
+            };
+
+            match self.decrypt_to_device_event(&event).await {
+                Ok(decrypted) => {
+                    debug!(
+                        "Processed a to-device event from {} after {} failed attempts",
+                        log_id(event.sender.as_str()),
+                        entry.attempts
+                    );
+
+                    events.push(self.handle_decrypted_to_device(decrypted, changes).await);
+                    processed.push(entry.id);
+                }
+                Err(e) => {
+                    entry.attempts += 1;
+
+                    if is_retryable(&e) && entry.attempts < MAX_ATTEMPTS {
+                        failed.push(entry);
+                    } else {
+                        warn!(
+                            "Giving up on a to-device event from {} after {} attempts {}",
+                            log_id(event.sender.as_str()),
+                            entry.attempts,
+                            e
+                        );
+                        self.recover_wedged_session(&e).await;
+                        processed.push(entry.id);
+                    }
+                }
+            }
+        }
+
+        (processed, failed)
+    }
+
     /// Request a room key from our devices.
     ///
     /// This method will return a request cancellation and a new key request if
@@ -898,15 +1946,18 @@ impl OlmMachine {
         event: &SyncMessageEvent<EncryptedEventContent>,
         room_id: &RoomId,
     ) -> MegolmResult<(Option<OutgoingRequest>, OutgoingRequest)> {
-        let content = match &event.content.scheme {
-            EncryptedEventScheme::MegolmV1AesSha2(c) => c,
-            _ => return Err(EventError::UnsupportedAlgorithm.into()),
-        };
+        let content = GroupEncryptedContent::try_from(&event.content.scheme)?;
 
-        Ok(self
+        let (cancellation, request) = self
             .key_request_machine
-            .request_key(room_id, &content.sender_key, &content.session_id)
-            .await?)
+            .request_key(room_id, content.sender_key, content.session_id)
+            .await?;
+
+        for r in cancellation.iter().chain(std::iter::once(&request)) {
+            self.request_tracker.track(r.request_id, r.request.request_type());
+        }
+
+        Ok((cancellation, request))
     }
 
     async fn get_encryption_info(
@@ -915,13 +1966,19 @@ impl OlmMachine {
         sender: &UserId,
         device_id: &DeviceId,
     ) -> StoreResult<EncryptionInfo> {
-        let verification_state = if let Some(device) =
-            self.get_device(sender, device_id).await?.filter(|d| {
-                d.get_key(DeviceKeyAlgorithm::Curve25519)
-                    .map(|k| k == session.sender_key())
-                    .unwrap_or(false)
-            }) {
-            if (self.user_id() == device.user_id() && self.device_id() == device.device_id())
+        let sender_key = Curve25519PublicKey::from_base64(session.sender_key()).ok();
+
+        let verification_state = if let Some(device) = self
+            .get_device(sender, device_id)
+            .await?
+            .filter(|d| sender_key.is_some() && d.curve25519_key() == sender_key)
+        {
+            // Only a room key that we received directly from the device can
+            // vouch for the sender claimed keys, forwarded, restored or
+            // imported room keys can't be fully trusted.
+            if !session.source().is_direct() {
+                VerificationState::Untrusted
+            } else if (self.user_id() == device.user_id() && self.device_id() == device.device_id())
                 || device.is_trusted()
             {
                 VerificationState::Trusted
@@ -944,6 +2001,50 @@ impl OlmMachine {
                 forwarding_curve25519_key_chain: session.forwarding_key_chain().to_vec(),
             },
             verification_state,
+            indirect_room_key: !session.source().is_direct(),
+            trust_change: None,
+        })
+    }
+
+    /// Check if the trust in the device that sent an event changed since the
+    /// event was decrypted.
+    ///
+    /// The [`VerificationState`] of the [`EncryptionInfo`] is the state of the
+    /// device at the time of decryption, this compares it to the current
+    /// state of the device. Which changes are reported is decided by the
+    /// [`TrustChangeWarnings`] setting.
+    ///
+    /// # Arguments
+    ///
+    /// * `info` - The encryption info of a decrypted event.
+    pub async fn trust_change(&self, info: &EncryptionInfo) -> StoreResult<Option<TrustChange>> {
+        let warnings = *self.trust_change_warnings.read().unwrap();
+
+        if warnings == TrustChangeWarnings::Disabled {
+            return Ok(None);
+        }
+
+        let sender_key = match &info.algorithm_info {
+            AlgorithmInfo::MegolmV1AesSha2 { curve25519_key, .. } => {
+                Curve25519PublicKey::from_base64(curve25519_key).ok()
+            }
+        };
+
+        let trusted_now = !info.indirect_room_key
+            && self
+                .get_device(&info.sender, &info.sender_device)
+                .await?
+                .filter(|d| sender_key.is_some() && d.curve25519_key() == sender_key)
+                .map_or(false, |d| {
+                    (self.user_id() == d.user_id() && self.device_id() == d.device_id())
+                        || d.is_trusted()
+                });
+        let trusted_then = info.verification_state == VerificationState::Trusted;
+
+        Ok(match (trusted_then, trusted_now) {
+            (true, false) => Some(TrustChange::Downgraded),
+            (false, true) if warnings == TrustChangeWarnings::All => Some(TrustChange::Upgraded),
+            _ => None,
         })
     }
 
@@ -954,35 +2055,98 @@ impl OlmMachine {
     /// * `event` - The event that should be decrypted.
     ///
     /// * `room_id` - The ID of the room where the event was sent to.
+    #[instrument(
+        skip(self, event),
+        fields(
+            room_id = %log_id(room_id.as_str()),
+            sender = %log_id(event.sender.as_str()),
+            event_id = %log_id(event.event_id.as_str())
+        )
+    )]
     pub async fn decrypt_room_event(
         &self,
         event: &SyncMessageEvent<EncryptedEventContent>,
         room_id: &RoomId,
     ) -> MegolmResult<SyncRoomEvent> {
-        let content = match &event.content.scheme {
-            EncryptedEventScheme::MegolmV1AesSha2(c) => c,
-            _ => return Err(EventError::UnsupportedAlgorithm.into()),
-        };
+        let result = self.decrypt_room_event_helper(event, room_id).await;
+        metrics::record_decryption(result.is_ok());
+
+        result
+    }
+
+    /// Get a stream of notifications about received room keys that were
+    /// missing when we tried to decrypt some events.
+    ///
+    /// The events a notification lists should be passed to
+    /// [`decrypt_room_event`] again, they can now be decrypted.
+    ///
+    /// [`decrypt_room_event`]: #method.decrypt_room_event
+    pub fn room_keys_received_stream(&self) -> impl Stream<Item = RoomKeysReceived> {
+        self.store.decryption_retries().subscribe()
+    }
+
+    async fn decrypt_room_event_helper(
+        &self,
+        event: &SyncMessageEvent<EncryptedEventContent>,
+        room_id: &RoomId,
+    ) -> MegolmResult<SyncRoomEvent> {
+        let content = GroupEncryptedContent::try_from(&event.content.scheme).map_err(|e| {
+            MegolmError::from(e).with_context(ErrorContext {
+                user_id: Some(event.sender.clone()),
+                ..Default::default()
+            })
+        })?;
 
         let session = self
             .store
-            .get_inbound_group_session(room_id, &content.sender_key, &content.session_id)
+            .get_inbound_group_session(room_id, content.sender_key, content.session_id)
             .await?;
         // TODO check if the Olm session is wedged and re-request the key.
         let session = if let Some(s) = session {
             s
         } else {
             self.key_request_machine
-                .create_outgoing_key_request(room_id, &content.sender_key, &content.session_id)
+                .create_outgoing_key_request(room_id, content.sender_key, content.session_id)
                 .await?;
-            return Err(MegolmError::MissingSession);
+            self.backup_machine.queue_missing_session(room_id, content.session_id).await;
+            self.store.decryption_retries().record_failure(
+                room_id,
+                content.session_id,
+                &event.event_id,
+            );
+
+            let withheld_info = self.store.get_withheld_info(room_id, content.session_id).await?;
+            let context = Self::decryption_error_context(&event.sender, &content);
+
+            return Err(match withheld_info {
+                Some(i) if i.sender_key == content.sender_key => {
+                    MegolmError::Withheld(i.code, context)
+                }
+                _ => MegolmError::MissingSession(context),
+            });
         };
 
+        if !session.source().is_direct()
+            && *self.indirect_room_key_policy.read().unwrap() == IndirectRoomKeyPolicy::Reject
+        {
+            return Err(MegolmError::IndirectRoomKey(
+                session.source(),
+                Self::decryption_error_context(&event.sender, &content),
+            ));
+        }
+
         // TODO check the message index.
         // TODO check if this is from a verified device.
-        let (decrypted_event, _) = session.decrypt(event).await?;
+        let (decrypted_event, _) = session
+            .decrypt(event)
+            .await
+            .map_err(|e| e.with_context(Self::decryption_error_context(&event.sender, &content)))?;
 
-        trace!("Successfully decrypted a Megolm event {:?}", decrypted_event);
+        trace!(
+            "Successfully decrypted a Megolm event from {} in {}",
+            log_id(event.sender.as_str()),
+            log_id(room_id.as_str())
+        );
 
         if let Ok(e) = decrypted_event.deserialize() {
             let event = e.into_full_event(room_id.to_owned());
@@ -993,56 +2157,396 @@ impl OlmMachine {
         }
 
         let encryption_info =
-            self.get_encryption_info(&session, &event.sender, &content.device_id).await?;
+            self.get_encryption_info(&session, &event.sender, content.device_id).await?;
 
-        Ok(SyncRoomEvent { encryption_info: Some(encryption_info), event: decrypted_event })
+        Ok(SyncRoomEvent {
+            encryption_info: Some(encryption_info),
+            event: decrypted_event,
+            membership_change: None,
+            metadata: Default::default(),
+        })
     }
 
-    /// Update the tracked users.
+    fn decryption_error_context(sender: &UserId, content: &GroupEncryptedContent) -> ErrorContext {
+        ErrorContext {
+            user_id: Some(sender.clone()),
+            device_id: Some(content.device_id.into()),
+            sender_key: Some(content.sender_key.to_owned()),
+            session_id: Some(content.session_id.to_owned()),
+        }
+    }
+
+    /// Get the encryption info of an encrypted room event without decrypting
+    /// it.
     ///
-    /// # Arguments
+    /// The verification state of the sender's device is computed as of now.
+    /// This can be used to refresh the verification state of events that were
+    /// decrypted before, e.g. after a device or a user identity got verified.
     ///
-    /// * `users` - An iterator over user ids that should be marked for
-    /// tracking.
+    /// Returns a `MissingSession` error if we don't have the group session
+    /// the event was encrypted with.
     ///
-    /// This will mark users that weren't seen before for a key query and
-    /// tracking.
+    /// # Arguments
     ///
-    /// If the user is already known to the Olm machine it will not be
-    /// considered for a key query.
-    pub async fn update_tracked_users(&self, users: impl IntoIterator<Item = &UserId>) {
-        self.identity_manager.update_tracked_users(users).await
+    /// * `event` - The encrypted event whose encryption info should be
+    /// computed.
+    ///
+    /// * `room_id` - The ID of the room where the event was sent to.
+    pub async fn get_event_encryption_info(
+        &self,
+        event: &SyncMessageEvent<EncryptedEventContent>,
+        room_id: &RoomId,
+    ) -> MegolmResult<EncryptionInfo> {
+        let content = GroupEncryptedContent::try_from(&event.content.scheme)?;
+
+        let session = self
+            .store
+            .get_inbound_group_session(room_id, content.sender_key, content.session_id)
+            .await?
+            .ok_or_else(|| {
+                MegolmError::MissingSession(Self::decryption_error_context(&event.sender, &content))
+            })?;
+
+        Ok(self.get_encryption_info(&session, &event.sender, content.device_id).await?)
     }
 
-    /// Get a specific device of a user.
+    /// Check if the given backup version can be trusted.
+    ///
+    /// This checks the signatures of the backup's `auth_data` against this
+    /// device, our cross signing identity and our other devices. If the backup
+    /// decryption key was given to the machine, the public key of the backup
+    /// is checked against it as well.
     ///
     /// # Arguments
     ///
-    /// * `user_id` - The unique id of the user that the device belongs to.
+    /// * `auth_data` - The `auth_data` of a
+    /// `m.megolm_backup.v1.curve25519-aes-sha2` backup version.
+    pub async fn verify_backup(&self, auth_data: &serde_json::Value) -> StoreResult<BackupTrust> {
+        self.backup_machine.verify_backup(auth_data).await
+    }
+
+    /// Start uploading our room keys to the server-side key backup with the
+    /// given version.
     ///
-    /// * `device_id` - The unique id of the device.
+    /// The caller is responsible to check that the backup can be trusted
+    /// before enabling it, e.g. using [`verify_backup`].
     ///
-    /// Returns a `Device` if one is found and the crypto store didn't throw an
-    /// error.
+    /// # Arguments
     ///
-    /// # Example
+    /// * `version` - The version of the backup the keys should be uploaded
+    /// to.
     ///
-    /// ```
-    /// # use std::convert::TryFrom;
-    /// # use matrix_sdk_crypto::OlmMachine;
-    /// # use ruma::UserId;
-    /// # use futures::executor::block_on;
-    /// # let alice = UserId::try_from("@alice:example.org").unwrap();
-    /// # let machine = OlmMachine::new(&alice, "DEVICEID".into());
-    /// # block_on(async {
-    /// let device = machine.get_device(&alice, "DEVICEID".into()).await;
+    /// * `public_key` - The Curve25519 public key of the backup, found in the
+    /// `auth_data` of the backup version.
     ///
-    /// println!("{:?}", device);
-    /// # });
-    /// ```
-    pub async fn get_device(
-        &self,
-        user_id: &UserId,
+    /// [`verify_backup`]: #method.verify_backup
+    pub async fn enable_backup(&self, version: String, public_key: String) -> StoreResult<()> {
+        self.backup_machine.enable_upload(version, public_key).await?;
+        self.notify_crypto_status_change();
+
+        Ok(())
+    }
+
+    /// Stop uploading our room keys to the server-side key backup.
+    pub async fn disable_backup(&self) {
+        self.backup_machine.disable_upload().await;
+        self.notify_crypto_status_change();
+    }
+
+    /// Get a request that uploads a batch of our room keys which aren't backed
+    /// up yet.
+    ///
+    /// This should be called periodically, e.g. after every sync, and until it
+    /// returns `None` to upload all our room keys. Returns `None` if the
+    /// backup isn't enabled, if an upload is already in flight or if all our
+    /// room keys are backed up.
+    ///
+    /// Once the request was sent out it needs to be marked as sent using
+    /// [`mark_backup_request_as_sent`] or as failed using
+    /// [`mark_backup_request_as_failed`].
+    ///
+    /// [`mark_backup_request_as_sent`]: #method.mark_backup_request_as_sent
+    /// [`mark_backup_request_as_failed`]: #method.mark_backup_request_as_failed
+    pub async fn backup_keys(&self) -> StoreResult<Option<KeysBackupRequest>> {
+        self.backup_machine.upload_request().await
+    }
+
+    /// Mark the backup request with the given id as sent, the room keys it
+    /// contained will be marked as backed up.
+    pub async fn mark_backup_request_as_sent(&self, request_id: &Uuid) -> StoreResult<()> {
+        self.backup_machine.mark_upload_as_sent(request_id).await?;
+        self.notify_crypto_status_change();
+
+        Ok(())
+    }
+
+    /// Mark the backup request with the given id as failed.
+    ///
+    /// If the server responded with `M_WRONG_ROOM_KEYS_VERSION` the upload of
+    /// room keys stops. The client should fetch the current backup version,
+    /// check if it can be trusted and enable it using [`enable_backup`].
+    ///
+    /// [`enable_backup`]: #method.enable_backup
+    pub async fn mark_backup_request_as_failed(
+        &self,
+        request_id: &Uuid,
+        failure: BackupUploadFailure,
+    ) {
+        self.backup_machine.mark_upload_as_failed(request_id, failure).await
+    }
+
+    /// Forget about the current server-side key backup.
+    ///
+    /// This stops the upload and download of room keys and marks all our room
+    /// keys as not backed up. The backup version itself needs to be deleted on
+    /// the server using the `/room_keys/version/{version}` endpoint.
+    pub async fn delete_backup(&self) -> StoreResult<()> {
+        self.backup_machine.delete_backup().await?;
+        self.notify_crypto_status_change();
+
+        Ok(())
+    }
+
+    /// Reset the server-side key backup, creating a new backup version with a
+    /// fresh backup key.
+    ///
+    /// This deletes the current backup locally, see [`delete_backup`]. To
+    /// finish the reset the client needs to:
+    ///
+    /// 1. Delete the old backup version on the server.
+    /// 2. Upload the new backup version using the `/room_keys/version`
+    /// endpoint.
+    /// 3. Store the backup decryption key in secret storage using
+    /// [`NewBackup::secret_storage_content`].
+    /// 4. Enable the new backup version using [`enable_backup`], after which
+    /// all our room keys will be re-encrypted and uploaded with
+    /// [`backup_keys`].
+    ///
+    /// [`delete_backup`]: #method.delete_backup
+    /// [`enable_backup`]: #method.enable_backup
+    /// [`backup_keys`]: #method.backup_keys
+    /// [`NewBackup::secret_storage_content`]: backups/struct.NewBackup.html#method.secret_storage_content
+    pub async fn reset_backup(&self) -> StoreResult<NewBackup> {
+        self.backup_machine.delete_backup().await?;
+        self.notify_crypto_status_change();
+
+        Ok(self.backup_machine.create_backup().await)
+    }
+
+    /// Get the number of our room keys and how many of them are backed up.
+    pub async fn backup_progress(&self) -> StoreResult<BackupProgress> {
+        self.backup_machine.progress().await
+    }
+
+    /// Are our room keys being uploaded to a server-side key backup.
+    pub async fn is_backup_enabled(&self) -> bool {
+        self.backup_machine.is_upload_enabled().await
+    }
+
+    /// Get a summary of the end-to-end encryption setup of our own account.
+    ///
+    /// This can be used to show the user what is missing to keep their
+    /// encrypted messages readable on other and future devices.
+    pub async fn crypto_status(&self) -> StoreResult<CryptoStatus> {
+        let cross_signing_set_up = !self.user_identity.lock().await.is_empty().await;
+
+        let unverified_own_devices = self
+            .get_user_devices(self.user_id())
+            .await?
+            .devices()
+            .filter(|d| d.device_id() != self.device_id() && !d.trust_state())
+            .count();
+
+        Ok(CryptoStatus {
+            cross_signing_set_up,
+            backup_enabled: self.is_backup_enabled().await,
+            unverified_own_devices,
+            keys_not_backed_up: self.backup_progress().await?.remaining(),
+        })
+    }
+
+    /// Get a stream that yields every time the [`CryptoStatus`] might have
+    /// changed.
+    ///
+    /// This happens after our keys were uploaded, after the devices or the
+    /// cross signing keys of users changed, after the backup got enabled or
+    /// disabled, after room keys were backed up and after new to-device
+    /// events were received. [`crypto_status`] needs to be called to get the
+    /// new status.
+    ///
+    /// [`crypto_status`]: #method.crypto_status
+    pub fn crypto_status_changes_stream(&self) -> impl Stream<Item = ()> {
+        let (sender, receiver) = unbounded();
+        self.crypto_status_listeners.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    fn notify_crypto_status_change(&self) {
+        self.crypto_status_listeners.lock().unwrap().retain(|l| l.unbounded_send(()).is_ok());
+    }
+
+    /// Enable the restoring of missing room keys from the server-side key
+    /// backup with the given version.
+    ///
+    /// Once enabled, room keys that are missing while decrypting room events
+    /// will be queued up for a download, the requests to download them can be
+    /// fetched using [`backup_download_request`].
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - The version of the backup the keys should be restored
+    /// from.
+    ///
+    /// * `key` - The private key of the backup.
+    ///
+    /// [`backup_download_request`]: #method.backup_download_request
+    pub async fn enable_backup_download(&self, version: String, key: BackupDecryptionKey) {
+        self.backup_machine.enable_download(version, key).await
+    }
+
+    /// Stop restoring missing room keys from the server-side key backup.
+    pub async fn disable_backup_download(&self) {
+        self.backup_machine.disable_download().await
+    }
+
+    /// Get a request to download missing room keys from the server-side key
+    /// backup.
+    ///
+    /// Returns `None` if restoring keys from the backup isn't enabled or if no
+    /// room keys are missing.
+    ///
+    /// The downloaded room keys need to be passed back to the machine using
+    /// [`receive_backed_up_room_keys`]. If the request fails it needs to be
+    /// marked as failed using [`mark_backup_download_as_failed`].
+    ///
+    /// [`receive_backed_up_room_keys`]: #method.receive_backed_up_room_keys
+    /// [`mark_backup_download_as_failed`]: #method.mark_backup_download_as_failed
+    pub async fn backup_download_request(&self) -> Option<KeysBackupDownloadRequest> {
+        self.backup_machine.download_request().await
+    }
+
+    /// Mark the backup download request with the given id as failed.
+    ///
+    /// The room keys of the request will be part of the next download request
+    /// again.
+    pub fn mark_backup_download_as_failed(&self, request_id: &Uuid) {
+        self.backup_machine.mark_download_as_failed(request_id)
+    }
+
+    /// Receive room keys that were downloaded from the server-side key backup.
+    ///
+    /// Returns the room and session ids of the restored room keys, decryption
+    /// of events that failed because of those missing room keys should be
+    /// retried.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_id` - The id of the [`KeysBackupDownloadRequest`] the keys
+    /// were downloaded for.
+    ///
+    /// * `keys` - The downloaded room keys, grouped by room and session id.
+    /// Room keys that were requested but aren't part of this map will not be
+    /// requested again for some time.
+    ///
+    /// [`KeysBackupDownloadRequest`]: backups/struct.KeysBackupDownloadRequest.html
+    pub async fn receive_backed_up_room_keys(
+        &self,
+        request_id: &Uuid,
+        keys: BTreeMap<RoomId, BTreeMap<String, KeyBackupData>>,
+    ) -> StoreResult<Vec<(RoomId, String)>> {
+        self.backup_machine.receive_backed_up_keys(request_id, keys).await
+    }
+
+    /// Update the tracked users.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - An iterator over user ids that should be marked for
+    /// tracking.
+    ///
+    /// This will mark users that weren't seen before for a key query and
+    /// tracking.
+    ///
+    /// If the user is already known to the Olm machine it will not be
+    /// considered for a key query.
+    pub async fn update_tracked_users(&self, users: impl IntoIterator<Item = &UserId>) {
+        self.identity_manager.update_tracked_users(users).await
+    }
+
+    /// Query the devices of the given users again, even if they were queried
+    /// recently or a query for them is currently in flight.
+    ///
+    /// The keys query request will be part of the next
+    /// [`outgoing_requests`] call.
+    ///
+    /// # Arguments
+    ///
+    /// * `users` - The users whose devices should be refreshed.
+    ///
+    /// [`outgoing_requests`]: #method.outgoing_requests
+    pub async fn force_refresh_devices(
+        &self,
+        users: impl IntoIterator<Item = &UserId>,
+    ) -> StoreResult<()> {
+        self.identity_manager.force_refresh(users).await
+    }
+
+    /// Is the given user tracked and are his devices up to date.
+    ///
+    /// The devices of a user become stale once the user shows up in the
+    /// `device_lists` of a sync response, they are up to date again once the
+    /// keys query for the user completes.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that should be checked.
+    pub fn is_user_tracked_and_fresh(&self, user_id: &UserId) -> bool {
+        self.identity_manager.is_user_tracked_and_fresh(user_id)
+    }
+
+    /// Get the time the devices of the given user were last fetched from the
+    /// server.
+    ///
+    /// Returns `None` if the devices weren't fetched since this `OlmMachine`
+    /// was created.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user whose devices were fetched.
+    pub fn last_devices_fetch(&self, user_id: &UserId) -> Option<MilliSecondsSinceUnixEpoch> {
+        self.identity_manager.last_keys_query(user_id).map(instant_to_timestamp)
+    }
+
+    /// Get a specific device of a user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The unique id of the user that the device belongs to.
+    ///
+    /// * `device_id` - The unique id of the device.
+    ///
+    /// Returns a `Device` if one is found and the crypto store didn't throw an
+    /// error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use std::convert::TryFrom;
+    /// # use matrix_sdk_crypto::OlmMachine;
+    /// # use ruma::UserId;
+    /// # use futures::executor::block_on;
+    /// # let alice = UserId::try_from("@alice:example.org").unwrap();
+    /// # let machine = OlmMachine::new(&alice, "DEVICEID".into());
+    /// # block_on(async {
+    /// let device = machine.get_device(&alice, "DEVICEID".into()).await;
+    ///
+    /// println!("{:?}", device);
+    /// # });
+    /// ```
+    pub async fn get_device(
+        &self,
+        user_id: &UserId,
         device_id: &DeviceId,
     ) -> StoreResult<Option<Device>> {
         self.store.get_device(user_id, device_id).await
@@ -1075,17 +2579,149 @@ impl OlmMachine {
         self.store.get_user_devices(user_id).await
     }
 
+    /// Release the memory the crypto store uses to cache objects which are
+    /// currently not in use.
+    ///
+    /// This can be called if the application is under memory pressure, the
+    /// released objects will be loaded from the store again once needed.
+    pub fn release_memory(&self) {
+        self.store.release_memory()
+    }
+
+    /// Delete all the end-to-end encryption data of this device.
+    ///
+    /// This removes our Olm account, all the Olm sessions, room keys, private
+    /// cross signing keys and the rest of the data from the crypto store. If
+    /// the store persists its data on disk the files will be deleted as well.
+    ///
+    /// This should be called once the device has been logged out, the machine
+    /// is consumed since it can't be used anymore after the data is gone.
+    pub async fn logout(self) -> StoreResult<()> {
+        self.backup_machine.disable_download().await;
+        self.backup_machine.disable_upload().await;
+
+        self.store.clear().await?;
+
+        info!("Deleted all the end-to-end encryption data of the device");
+
+        Ok(())
+    }
+
+    /// Set the policy that decides if incoming room key requests from other
+    /// devices are honored.
+    ///
+    /// By default room keys are shared with our own verified devices and with
+    /// devices of other users that were meant to receive the room key in the
+    /// first place.
+    pub fn set_key_forwarding_policy(&self, policy: KeyForwardingPolicy) {
+        self.key_request_machine.set_forwarding_policy(policy)
+    }
+
+    /// Set the policy that decides if events can be decrypted with room keys
+    /// that we didn't receive directly from the sender.
+    ///
+    /// By default such events are decrypted and their [`EncryptionInfo`] is
+    /// marked accordingly.
+    pub fn set_indirect_room_key_policy(&self, policy: IndirectRoomKeyPolicy) {
+        *self.indirect_room_key_policy.write().unwrap() = policy;
+    }
+
+    /// Set which changes of the trust in the sender device are reported by
+    /// [`trust_change`].
+    ///
+    /// By default only devices that lost our trust are reported.
+    ///
+    /// [`trust_change`]: #method.trust_change
+    pub fn set_trust_change_warnings(&self, warnings: TrustChangeWarnings) {
+        *self.trust_change_warnings.write().unwrap() = warnings;
+    }
+
+    /// Set how long [`share_group_session`] waits for the stale device lists
+    /// of the recipients to be updated.
+    ///
+    /// Defaults to 10 seconds, a zero duration disables the waiting.
+    ///
+    /// [`share_group_session`]: #method.share_group_session
+    pub fn set_stale_devices_timeout(&self, timeout: Duration) {
+        *self.stale_devices_timeout.write().unwrap() = timeout;
+    }
+
+    /// Get the incoming room key requests that are waiting to be approved or
+    /// refused by the user.
+    ///
+    /// Room key requests end up here if a custom [`KeyForwardingPolicy`]
+    /// decided to ask the user.
+    pub fn pending_room_key_requests(&self) -> Vec<IncomingKeyRequest> {
+        self.key_request_machine.pending_key_requests()
+    }
+
+    /// Approve a pending incoming room key request, the room key will be
+    /// shared with the requesting device.
+    ///
+    /// The to-device request that shares the room key will be returned by the
+    /// next [`outgoing_requests()`](#method.outgoing_requests) call.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The pending room key request that should be approved.
+    pub async fn approve_room_key_request(&self, request: &IncomingKeyRequest) -> OlmResult<()> {
+        if let Some(session) = self
+            .key_request_machine
+            .approve_key_request(&request.user_id, &request.device_id, &request.request_id)
+            .await?
+        {
+            let changes = Changes { sessions: vec![session], ..Default::default() };
+            self.store.save_changes(changes).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Refuse a pending incoming room key request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The pending room key request that should be refused.
+    pub async fn refuse_room_key_request(&self, request: &IncomingKeyRequest) -> StoreResult<()> {
+        self.key_request_machine
+            .refuse_key_request(&request.user_id, &request.device_id, &request.request_id)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Get the sharing history of the group session with the given session id.
+    ///
+    /// The history records for every device that was considered to receive
+    /// the room key of the session if the device received it, be it directly
+    /// or as an answer to a room key request, and if not, why it didn't.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The unique id of the group session.
+    pub async fn session_sharing_history(
+        &self,
+        session_id: &str,
+    ) -> StoreResult<Vec<SharingHistoryEntry>> {
+        self.store.get_sharing_history(session_id).await
+    }
+
     /// Import the given room keys into our store.
     ///
     /// # Arguments
     ///
     /// * `exported_keys` - A list of previously exported keys that should be
-    /// imported into our store. If we already have a better version of a key
-    /// the key will *not* be imported.
+    /// imported into our store. If we already have a better version of a key,
+    /// that is a version that can decrypt the same or more messages, the key
+    /// will *not* be imported.
+    ///
+    /// * `progress_listener` - A closure that will be called for every key in
+    /// the list, with the number of keys that were imported and skipped so far
+    /// and the total number of keys in the list.
     ///
-    /// Returns a tuple of numbers that represent the number of sessions that
-    /// were imported and the total number of sessions that were found in the
-    /// key export.
+    /// Returns the number of keys that were imported, the number of keys that
+    /// were skipped and the total number of keys that were found in the key
+    /// export.
     ///
     /// # Examples
     /// ```no_run
@@ -1098,66 +2734,84 @@ impl OlmMachine {
     /// # block_on(async {
     /// # let export = Cursor::new("".to_owned());
     /// let exported_keys = decrypt_key_export(export, "1234").unwrap();
-    /// machine.import_keys(exported_keys, |_, _| {}).await.unwrap();
+    /// machine
+    ///     .import_keys(exported_keys, |progress| {
+    ///         println!("Imported {} out of {} keys", progress.imported_count, progress.total_count)
+    ///     })
+    ///     .await
+    ///     .unwrap();
     /// # });
     /// ```
     pub async fn import_keys(
         &self,
         exported_keys: Vec<ExportedRoomKey>,
-        progress_listener: impl Fn(usize, usize),
-    ) -> StoreResult<(usize, usize)> {
-        struct ShallowSessions {
-            inner: BTreeMap<Arc<RoomId>, u32>,
-        }
-
-        impl ShallowSessions {
-            fn has_better_session(&self, session: &InboundGroupSession) -> bool {
-                self.inner
-                    .get(&session.room_id)
-                    .map(|existing| existing <= &session.first_known_index())
-                    .unwrap_or(false)
-            }
-        }
+        progress_listener: impl Fn(&RoomKeyImportResult),
+    ) -> StoreResult<RoomKeyImportResult> {
+        let mut existing_sessions: BTreeMap<(Arc<RoomId>, Arc<str>, String), u32> = self
+            .store
+            .get_inbound_group_sessions()
+            .await?
+            .into_iter()
+            .map(|s| {
+                let index = s.first_known_index();
+                let session_id = s.session_id().to_owned();
+                ((s.room_id, s.sender_key, session_id), index)
+            })
+            .collect();
 
         let mut sessions = Vec::new();
+        let mut result =
+            RoomKeyImportResult { total_count: exported_keys.len(), ..Default::default() };
 
-        let existing_sessions = ShallowSessions {
-            inner: self
-                .store
-                .get_inbound_group_sessions()
-                .await?
-                .into_iter()
-                .map(|s| {
-                    let index = s.first_known_index();
-                    (s.room_id, index)
-                })
-                .collect(),
-        };
-
-        let total_sessions = exported_keys.len();
-
-        for (i, key) in exported_keys.into_iter().enumerate() {
+        for key in exported_keys {
             let session = InboundGroupSession::from_export(key)?;
 
+            let key = (
+                session.room_id.clone(),
+                session.sender_key.clone(),
+                session.session_id().to_owned(),
+            );
+
             // Only import the session if we didn't have this session or if it's
             // a better version of the same session, that is the first known
             // index is lower.
-            if !existing_sessions.has_better_session(&session) {
-                sessions.push(session)
+            if existing_sessions
+                .get(&key)
+                .map(|existing| *existing <= session.first_known_index())
+                .unwrap_or(false)
+            {
+                result.skipped_count += 1;
+            } else {
+                existing_sessions.insert(key, session.first_known_index());
+
+                // The export may contain multiple versions of the same
+                // session, only keep the best one.
+                if let Some(worse) = sessions.iter().position(|s: &InboundGroupSession| {
+                    s.room_id() == session.room_id()
+                        && s.sender_key() == session.sender_key()
+                        && s.session_id() == session.session_id()
+                }) {
+                    sessions[worse] = session;
+                    result.skipped_count += 1;
+                } else {
+                    sessions.push(session);
+                    result.imported_count += 1;
+                }
             }
 
-            progress_listener(i, total_sessions)
+            progress_listener(&result);
         }
 
-        let num_sessions = sessions.len();
-
         let changes = Changes { inbound_group_sessions: sessions, ..Default::default() };
 
         self.store.save_changes(changes).await?;
 
-        info!("Successfully imported {} inbound group sessions", num_sessions);
+        info!(
+            "Successfully imported {} inbound group sessions, skipped {} sessions",
+            result.imported_count, result.skipped_count
+        );
 
-        Ok((num_sessions, total_sessions))
+        Ok(result)
     }
 
     /// Export the keys that match the given predicate.
@@ -1209,51 +2863,160 @@ impl OlmMachine {
 
         Ok(exported)
     }
-}
-
-#[cfg(test)]
-pub(crate) mod test {
-    static USER_ID: &str = "@bob:example.org";
-
-    use std::{
-        collections::BTreeMap,
-        convert::{TryFrom, TryInto},
-        sync::Arc,
-    };
-
-    use http::Response;
-    use matrix_sdk_test::test_json;
-    use ruma::{
-        api::{
-            client::r0::keys::{claim_keys, get_keys, upload_keys, OneTimeKey},
-            IncomingResponse,
-        },
-        events::{
-            room::{
-                encrypted::EncryptedEventContent,
-                message::{MessageEventContent, MessageType},
-            },
-            AnyMessageEventContent, AnySyncMessageEvent, AnySyncRoomEvent, AnyToDeviceEvent,
-            EventType, SyncMessageEvent, ToDeviceEvent, Unsigned,
-        },
-        identifiers::{
-            event_id, room_id, user_id, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, UserId,
-        },
-        serde::Raw,
-        uint, MilliSecondsSinceUnixEpoch,
-    };
-    use serde_json::json;
 
-    use crate::{
-        machine::OlmMachine,
-        olm::Utility,
-        verification::test::{outgoing_request_to_event, request_to_event},
-        EncryptionSettings, ReadOnlyDevice, ToDeviceRequest,
+    /// Export the room keys of the given rooms into an encrypted key export.
+    ///
+    /// The room keys are encrypted and written out one by one, so exporting
+    /// the keys of a room with a large history doesn't require the whole
+    /// export to be held in memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `rooms` - The rooms for which the room keys should be exported.
+    ///
+    /// * `passphrase` - The passphrase that will be used to encrypt the
+    /// exported room keys.
+    ///
+    /// * `rounds` - The number of rounds that should be used for the key
+    /// derivation when the passphrase gets turned into an AES key, see
+    /// [`encrypt_key_export`](crate::encrypt_key_export).
+    ///
+    /// * `writer` - The writer the encrypted export will be written to.
+    ///
+    /// * `progress_listener` - A closure that will be called with the number
+    /// of room keys that were exported so far and the total number of room
+    /// keys that will be exported.
+    ///
+    /// Returns the writer once the export has been finished.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS to
+    /// encrypt the exported keys securely.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk_crypto::OlmMachine;
+    /// # use ruma::{user_id, room_id};
+    /// # use futures::executor::block_on;
+    /// # let alice = user_id!("@alice:example.org");
+    /// # let machine = OlmMachine::new(&alice, "DEVICEID".into());
+    /// # block_on(async {
+    /// let room_id = room_id!("!test:localhost");
+    /// let file = std::fs::File::create("room-keys.txt").unwrap();
+    ///
+    /// machine
+    ///     .export_room_keys_for_rooms(&[room_id], "1234", 100_000, file, |exported, total| {
+    ///         println!("Exported {} out of {} room keys", exported, total)
+    ///     })
+    ///     .await
+    ///     .unwrap();
+    /// # });
+    /// ```
+    pub async fn export_room_keys_for_rooms<W: Write>(
+        &self,
+        rooms: &[RoomId],
+        passphrase: &str,
+        rounds: u32,
+        writer: W,
+        progress_listener: impl Fn(usize, usize),
+    ) -> Result<W, KeyExportError> {
+        let sessions: Vec<InboundGroupSession> = self
+            .store
+            .get_inbound_group_sessions()
+            .await?
+            .into_iter()
+            .filter(|s| rooms.contains(s.room_id()))
+            .collect();
+
+        let total = sessions.len();
+        let mut writer = KeyExportWriter::new(writer, passphrase, rounds)?;
+
+        for session in sessions {
+            let export = session.export().await;
+            writer.write_key(&export)?;
+            progress_listener(writer.key_count(), total);
+        }
+
+        writer.finish()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    static USER_ID: &str = "@bob:example.org";
+
+    use std::{
+        collections::BTreeMap,
+        convert::{TryFrom, TryInto},
+        sync::Arc,
+    };
+
+    use futures::{FutureExt, StreamExt};
+    use http::Response;
+    use matrix_sdk_common::{
+        deserialized_responses::{TrustChange, VerificationState},
+        uuid::Uuid,
+    };
+    use matrix_sdk_test::test_json;
+    use ruma::{
+        api::{
+            client::r0::{
+                keys::{claim_keys, get_keys, upload_keys, upload_signing_keys, OneTimeKey},
+                sync::sync_events::{DeviceLists, ToDevice},
+                to_device::send_event_to_device::Response as ToDeviceResponse,
+            },
+            IncomingResponse,
+        },
+        events::{
+            room::{
+                encrypted::{EncryptedEventContent, EncryptedEventScheme},
+                message::{MessageEventContent, MessageType},
+            },
+            AnyMessageEventContent, AnySyncMessageEvent, AnySyncRoomEvent, AnyToDeviceEvent,
+            EventType, SyncMessageEvent, ToDeviceEvent, Unsigned,
+        },
+        identifiers::{
+            event_id, room_id, user_id, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, UserId,
+        },
+        serde::Raw,
+        uint, MilliSecondsSinceUnixEpoch,
+    };
+    use serde_json::json;
+
+    use crate::{
+        backups::BackupDecryptionKey,
+        decrypt_key_export,
+        error::MegolmError,
+        file_encryption::RoomKeyImportResult,
+        machine::{CryptoStatus, IndirectRoomKeyPolicy, OlmMachine, TrustChangeWarnings},
+        olm::{
+            GroupEncryptedContent, KeysUploadFailure, RoomKeySource, ShareDecision, Utility,
+            WithheldCode,
+        },
+        requests::{OutgoingRequest, StoredOutgoingRequest},
+        secret_storage::SecretStorageKey,
+        store::{CryptoStore, DynCryptoStore, MemoryStore},
+        to_device_inbox::StoredToDeviceEvent,
+        verification::test::{outgoing_request_to_event, request_to_event},
+        EncryptionSettings, LocalTrust, OlmError, OutgoingRequests, ReadOnlyDevice, RequestType,
+        ToDeviceRequest,
     };
 
     /// These keys need to be periodically uploaded to the server.
     type OneTimeKeys = BTreeMap<DeviceKeyId, OneTimeKey>;
 
+    async fn bootstrap_cross_signing(machine: &OlmMachine) {
+        let (request_id, _, _) = machine.bootstrap_cross_signing(false).await.unwrap();
+        mark_cross_signing_as_uploaded(machine, &request_id).await;
+    }
+
+    async fn mark_cross_signing_as_uploaded(machine: &OlmMachine, request_id: &Uuid) {
+        let response = upload_signing_keys::Response::new();
+        machine.mark_request_as_sent(request_id, &response).await.unwrap();
+    }
+
     fn alice_id() -> UserId {
         user_id!("@alice:example.org")
     }
@@ -1369,6 +3132,22 @@ pub(crate) mod test {
         assert!(machine.should_upload_keys().await);
     }
 
+    #[tokio::test]
+    async fn create_olm_machine_with_custom_store() {
+        let store = Arc::new(MemoryStore::new());
+
+        let machine =
+            OlmMachine::new_with_store(user_id(), alice_device_id(), store.clone()).await.unwrap();
+
+        // The account of the first machine is restored from the shared store,
+        // no matter how the store was type-erased.
+        let erased: Arc<DynCryptoStore> = store;
+        let restored =
+            OlmMachine::new_with_store(user_id(), alice_device_id(), erased).await.unwrap();
+
+        assert_eq!(machine.identity_keys().curve25519(), restored.identity_keys().curve25519());
+    }
+
     #[tokio::test]
     async fn receive_keys_upload_response() {
         let machine = OlmMachine::new(&user_id(), &alice_device_id());
@@ -1406,6 +3185,103 @@ pub(crate) mod test {
         assert!(machine.account.generate_one_time_keys().await.is_err());
     }
 
+    #[tokio::test]
+    async fn mark_request_as_sent_validation() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+
+        let request = machine
+            .outgoing_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|r| matches!(r.request(), OutgoingRequests::KeysUpload(_)))
+            .unwrap();
+
+        let result =
+            machine.mark_request_as_sent(request.request_id(), &ToDeviceResponse::new()).await;
+        assert!(matches!(
+            result,
+            Err(OlmError::MismatchedResponse {
+                expected: RequestType::KeysUpload,
+                received: RequestType::ToDevice,
+                ..
+            })
+        ));
+
+        let mut response = keys_upload_response();
+        response.one_time_key_counts.insert(DeviceKeyAlgorithm::SignedCurve25519, uint!(50));
+        machine.mark_request_as_sent(request.request_id(), &response).await.unwrap();
+        assert!(!machine.should_upload_keys().await);
+
+        // Marking the same request as sent again doesn't handle the response
+        // a second time.
+        response.one_time_key_counts.insert(DeviceKeyAlgorithm::SignedCurve25519, uint!(10));
+        machine.mark_request_as_sent(request.request_id(), &response).await.unwrap();
+        assert!(!machine.should_upload_keys().await);
+
+        let result =
+            machine.mark_request_as_sent(request.request_id(), &ToDeviceResponse::new()).await;
+        assert!(matches!(result, Err(OlmError::MismatchedResponse { .. })));
+
+        // A response for a request we never handed out isn't handled.
+        let result = machine.mark_request_as_sent(&Uuid::new_v4(), &response).await;
+        assert!(matches!(
+            result,
+            Err(OlmError::UnknownRequest { received: RequestType::KeysUpload, .. })
+        ));
+        assert!(!machine.should_upload_keys().await);
+    }
+
+    #[tokio::test]
+    async fn keys_upload_failures() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        machine.set_one_time_keys_batch_size(10);
+
+        let one_time_keys = |request: Option<upload_keys::Request>| {
+            request.unwrap().one_time_keys.unwrap().into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+        };
+
+        let keys = one_time_keys(machine.keys_for_upload().await);
+        assert_eq!(keys.len(), 10);
+
+        // An upload that failed for an unknown reason is retried with the
+        // same keys.
+        machine
+            .mark_keys_upload_as_failed(&Uuid::new_v4(), KeysUploadFailure::Other)
+            .await
+            .unwrap();
+        assert_eq!(one_time_keys(machine.keys_for_upload().await), keys);
+
+        // Keys that the server rejected are replaced with new keys.
+        machine
+            .mark_keys_upload_as_failed(&Uuid::new_v4(), KeysUploadFailure::RejectedKeys)
+            .await
+            .unwrap();
+        let new_keys = one_time_keys(machine.keys_for_upload().await);
+        assert_eq!(new_keys.len(), 10);
+        assert!(new_keys.iter().all(|k| !keys.contains(k)));
+
+        // Uploads that are too large are split up, the keys are kept.
+        machine
+            .mark_keys_upload_as_failed(&Uuid::new_v4(), KeysUploadFailure::TooLarge)
+            .await
+            .unwrap();
+        let split_keys = one_time_keys(machine.keys_for_upload().await);
+        assert_eq!(split_keys.len(), 5);
+        assert!(split_keys.iter().all(|k| new_keys.contains(k)));
+
+        let diagnostics = machine.keys_upload_diagnostics();
+        assert_eq!(diagnostics.batch_size, 5);
+        assert_eq!(diagnostics.consecutive_failures, 3);
+        assert_eq!(diagnostics.last_failure, Some(KeysUploadFailure::TooLarge));
+        assert_eq!(diagnostics.discarded_keys, 10);
+
+        machine.receive_keys_upload_response(&keys_upload_response()).await.unwrap();
+        let diagnostics = machine.keys_upload_diagnostics();
+        assert_eq!(diagnostics.consecutive_failures, 0);
+        assert_eq!(diagnostics.last_failure, None);
+    }
+
     #[tokio::test]
     async fn test_device_key_signing() {
         let machine = OlmMachine::new(&user_id(), &alice_device_id());
@@ -1562,47 +3438,664 @@ pub(crate) mod test {
         keys.insert(one_time_key.0.clone(), one_time_key.1.clone());
         bob_keys.insert(bob_machine.device_id().into(), keys);
 
-        let mut one_time_keys = BTreeMap::new();
-        one_time_keys.insert(bob_machine.user_id().clone(), bob_keys);
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys.insert(bob_machine.user_id().clone(), bob_keys);
+
+        let response = claim_keys::Response::new(one_time_keys);
+
+        alice_machine.receive_keys_claim_response(&response).await.unwrap();
+
+        let session = alice_machine
+            .store
+            .get_sessions(bob_machine.account.identity_keys().curve25519())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(!session.lock().await.is_empty())
+    }
+
+    #[tokio::test]
+    async fn test_olm_encryption() {
+        let (alice, bob) = get_machine_pair_with_session().await;
+
+        let bob_device = alice.get_device(&bob.user_id, &bob.device_id).await.unwrap().unwrap();
+
+        let event = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: bob_device.encrypt(EventType::Dummy, json!({})).await.unwrap().1,
+        };
+
+        let event = bob.decrypt_to_device_event(&event).await.unwrap().event.deserialize().unwrap();
+
+        if let AnyToDeviceEvent::Dummy(e) = event {
+            assert_eq!(&e.sender, alice.user_id());
+        } else {
+            panic!("Wrong event type found {:?}", event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_decrypted_event_is_passed_on_untouched() {
+        let (alice, bob) = get_machine_pair_with_session().await;
+
+        let bob_device = alice.get_device(&bob.user_id, &bob.device_id).await.unwrap().unwrap();
+        let content = json!({ "org.example.future_field": "value" });
+
+        let event = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: bob_device.encrypt(EventType::Dummy, content.clone()).await.unwrap().1,
+        };
+
+        let decrypted = bob.decrypt_to_device_event(&event).await.unwrap();
+        let decrypted: serde_json::Value =
+            serde_json::from_str(decrypted.event.json().get()).unwrap();
+
+        assert_eq!(decrypted["type"], "m.dummy");
+        assert_eq!(decrypted["content"], content);
+    }
+
+    #[tokio::test]
+    async fn to_device_inbox() {
+        // An event that can be processed by now is handed out like the events
+        // of the current sync and removed from the inbox.
+        let (alice, bob) = get_machine_pair_with_session().await;
+        let bob_device = alice.get_device(&bob.user_id, &bob.device_id).await.unwrap().unwrap();
+        let (_, content) = bob_device.encrypt(EventType::Dummy, json!({})).await.unwrap();
+
+        let event = json!({
+            "sender": alice.user_id(),
+            "type": "m.room.encrypted",
+            "content": content,
+        });
+        let stored = StoredToDeviceEvent::new(serde_json::from_value(event).unwrap());
+        bob.store.save_inbox_event(&stored).await.unwrap();
+
+        let received = bob
+            .receive_sync_changes(ToDevice::new(), &DeviceLists::new(), &BTreeMap::new())
+            .await
+            .unwrap();
+
+        assert_eq!(received.events.len(), 1);
+        assert!(matches!(received.events[0].deserialize(), Ok(AnyToDeviceEvent::Dummy(_))));
+        assert!(bob.store.get_inbox_events().await.unwrap().is_empty());
+
+        // A message that our existing session can't decrypt means that the
+        // session is wedged, retrying it won't help.
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let event = json!({
+            "sender": alice.user_id(),
+            "type": "m.room.encrypted",
+            "content": {
+                "algorithm": "m.olm.v1.curve25519-aes-sha2",
+                "sender_key": alice.identity_keys().curve25519(),
+                "ciphertext": {
+                    bob.identity_keys().curve25519(): { "type": 1, "body": "c2Vzc2lvbg" }
+                }
+            }
+        });
+
+        let mut to_device = ToDevice::new();
+        to_device.events.push(serde_json::from_value(event.clone()).unwrap());
+        let received = bob
+            .receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new())
+            .await
+            .unwrap();
+
+        assert!(received.events.is_empty());
+        assert!(bob.store.get_inbox_events().await.unwrap().is_empty());
+
+        // Such an event that already is in the inbox is given up as well.
+        let stored = StoredToDeviceEvent::new(serde_json::from_value(event).unwrap());
+        bob.store.save_inbox_event(&stored).await.unwrap();
+        bob.receive_sync_changes(ToDevice::new(), &DeviceLists::new(), &BTreeMap::new())
+            .await
+            .unwrap();
+
+        assert!(bob.store.get_inbox_events().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lost_olm_session_is_recreated() {
+        let (alice, bob, one_time_keys) = get_machine_pair().await;
+
+        // Alice doesn't have a session with Bob, e.g. because her store was
+        // cleared, and receives a normal Olm message from him.
+        let event = json!({
+            "sender": bob.user_id(),
+            "type": "m.room.encrypted",
+            "content": {
+                "algorithm": "m.olm.v1.curve25519-aes-sha2",
+                "sender_key": bob.identity_keys().curve25519(),
+                "ciphertext": {
+                    alice.identity_keys().curve25519(): { "type": 1, "body": "c2Vzc2lvbg" }
+                }
+            }
+        });
+
+        let mut to_device = ToDevice::new();
+        to_device.events.push(serde_json::from_value(event).unwrap());
+        let received = alice
+            .receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new())
+            .await
+            .unwrap();
+
+        assert!(received.events.is_empty());
+        assert!(alice.store.get_inbox_events().await.unwrap().is_empty());
+
+        // The session is wedged, a new one is claimed even if we don't ask for
+        // any user explicitly.
+        let (_, request) = alice.get_missing_sessions(std::iter::empty()).await.unwrap().unwrap();
+        assert!(request.one_time_keys[bob.user_id()].contains_key(bob.device_id()));
+
+        let one_time_key = one_time_keys.into_iter().next().unwrap();
+        let mut keys = BTreeMap::new();
+        keys.insert(one_time_key.0, one_time_key.1);
+        let mut bob_keys = BTreeMap::new();
+        bob_keys.insert(bob.device_id().into(), keys);
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys.insert(bob.user_id().clone(), bob_keys);
+
+        alice.receive_keys_claim_response(&claim_keys::Response::new(one_time_keys)).await.unwrap();
+
+        let sessions =
+            alice.store.get_sessions(bob.identity_keys().curve25519()).await.unwrap().unwrap();
+        assert_eq!(sessions.lock().await.len(), 1);
+
+        // Bob is told about the new session with a dummy message.
+        assert!(alice
+            .outgoing_requests()
+            .await
+            .unwrap()
+            .iter()
+            .any(|r| matches!(r.request(), OutgoingRequests::ToDeviceRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn test_room_key_sharing() {
+        let (alice, bob) = get_machine_pair_with_session().await;
+
+        let room_id = room_id!("!test:example.org");
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let event = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+
+        let alice_session =
+            alice.group_session_manager.get_outbound_group_session(&room_id).unwrap();
+
+        let decrypted = bob.decrypt_to_device_event(&event).await.unwrap();
+
+        bob.store.save_sessions(&[decrypted.session.session()]).await.unwrap();
+        bob.store
+            .save_inbound_group_sessions(&[decrypted.inbound_group_session.unwrap()])
+            .await
+            .unwrap();
+        let event = decrypted.deserialized_event.unwrap();
+
+        if let AnyToDeviceEvent::RoomKey(event) = event {
+            assert_eq!(&event.sender, alice.user_id());
+            assert!(event.content.session_key.is_empty());
+        } else {
+            panic!("expected RoomKeyEvent found {:?}", event);
+        }
+
+        let session = bob
+            .store
+            .get_inbound_group_session(
+                &room_id,
+                alice.account.identity_keys().curve25519(),
+                alice_session.session_id(),
+            )
+            .await;
+
+        assert!(session.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn session_sharing_history() {
+        let (alice, bob) = get_machine_pair_with_session().await;
+        let room_id = room_id!("!test:example.org");
+
+        alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let session = alice.group_session_manager.get_outbound_group_session(&room_id).unwrap();
+
+        let history = alice.session_sharing_history(session.session_id()).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(&history[0].user_id, bob.user_id());
+        assert_eq!(&*history[0].device_id, bob.device_id());
+        assert_eq!(history[0].decision, ShareDecision::Shared { message_index: 0 });
+    }
+
+    #[tokio::test]
+    async fn group_session_rotation_on_membership_and_device_changes() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let session = alice.group_session_manager.get_outbound_group_session(&room_id).unwrap();
+
+        // Users that never received the session don't invalidate it.
+        assert!(!alice
+            .room_members_left(&room_id, [user_id!("@carol:localhost")].iter())
+            .await
+            .unwrap());
+        assert!(!session.invalidated());
+
+        assert!(alice.room_members_left(&room_id, [bob.user_id().clone()].iter()).await.unwrap());
+        assert!(session.invalidated());
+
+        alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let rotated = alice.group_session_manager.get_outbound_group_session(&room_id).unwrap();
+        assert_ne!(session.session_id(), rotated.session_id());
+        assert!(!rotated.invalidated());
+
+        let bob_device = alice.get_device(bob.user_id(), bob.device_id()).await.unwrap().unwrap();
+
+        assert_eq!(
+            alice.invalidate_group_sessions_for_device(&bob_device.inner).await.unwrap(),
+            vec![room_id.clone()]
+        );
+        assert!(rotated.invalidated());
+    }
+
+    #[tokio::test]
+    async fn bulk_local_trust() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let session = alice.group_session_manager.get_outbound_group_session(&room_id).unwrap();
+
+        let rooms = alice
+            .set_local_trust_bulk(&[
+                (bob.user_id().clone(), bob.device_id().into(), LocalTrust::Verified),
+                (bob.user_id().clone(), "UNKNOWNDEVICE".into(), LocalTrust::BlackListed),
+            ])
+            .await
+            .unwrap();
+
+        assert!(rooms.is_empty());
+        assert!(!session.invalidated());
+
+        let bob_device = alice.get_device(bob.user_id(), bob.device_id()).await.unwrap().unwrap();
+        assert!(bob_device.trust_state());
+        assert!(alice.blacklist_all_unverified_devices_of(bob.user_id()).await.unwrap().is_empty());
+
+        alice
+            .set_local_trust_bulk(&[(
+                bob.user_id().clone(),
+                bob.device_id().into(),
+                LocalTrust::Unset,
+            )])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            alice.blacklist_all_unverified_devices_of(bob.user_id()).await.unwrap(),
+            vec![room_id.clone()]
+        );
+        assert!(session.invalidated());
+
+        let bob_device = alice.get_device(bob.user_id(), bob.device_id()).await.unwrap().unwrap();
+        assert!(bob_device.is_blacklisted());
+    }
+
+    #[tokio::test]
+    async fn outgoing_requests_are_sent_again_after_a_restart() {
+        let (bob, one_time_keys) = get_prepared_machine().await;
+        let store = Arc::new(MemoryStore::new());
+        let alice =
+            OlmMachine::new_with_store(alice_id(), alice_device_id(), store.clone()).await.unwrap();
+
+        let alice_device = ReadOnlyDevice::from_machine(&alice).await;
+        let bob_device = ReadOnlyDevice::from_machine(&bob).await;
+        alice.store.save_devices(&[bob_device]).await.unwrap();
+        bob.store.save_devices(&[alice_device]).await.unwrap();
+
+        let event_type = EventType::from("org.example.custom");
+        alice
+            .encrypt_to_device(bob.user_id(), bob.device_id(), event_type, json!({ "foo": "bar" }))
+            .await
+            .unwrap();
+
+        let one_time_key = one_time_keys.iter().next().unwrap();
+        let mut keys = BTreeMap::new();
+        keys.insert(one_time_key.0.clone(), one_time_key.1.clone());
+        let mut bob_keys = BTreeMap::new();
+        bob_keys.insert(bob.device_id().into(), keys);
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys.insert(bob.user_id().clone(), bob_keys);
+
+        alice.receive_keys_claim_response(&claim_keys::Response::new(one_time_keys)).await.unwrap();
+
+        // The request is stored as soon as it gets created.
+        assert_eq!(store.get_outgoing_requests().await.unwrap().len(), 1);
+
+        async fn to_device_requests(machine: &OlmMachine) -> Vec<(Uuid, Uuid)> {
+            machine
+                .outgoing_requests()
+                .await
+                .unwrap()
+                .into_iter()
+                .filter_map(|r| r.request().to_device().map(|t| (*r.request_id(), t.txn_id)))
+                .collect()
+        }
+
+        let requests = to_device_requests(&alice).await;
+        assert_eq!(requests.len(), 1);
+
+        // A machine that is restored from the same store, e.g. after a crash,
+        // sends the request again using the same transaction id.
+        let restored =
+            OlmMachine::new_with_store(alice_id(), alice_device_id(), store).await.unwrap();
+        assert_eq!(to_device_requests(&restored).await, requests);
+
+        restored.mark_request_as_sent(&requests[0].0, &ToDeviceResponse::new()).await.unwrap();
+        assert!(to_device_requests(&restored).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn expired_outgoing_requests_are_dropped() {
+        let store = Arc::new(MemoryStore::new());
+        let alice =
+            OlmMachine::new_with_store(alice_id(), alice_device_id(), store.clone()).await.unwrap();
+
+        let request = OutgoingRequest {
+            request_id: Uuid::new_v4(),
+            request: Arc::new(
+                ToDeviceRequest {
+                    event_type: EventType::Dummy,
+                    txn_id: Uuid::new_v4(),
+                    messages: BTreeMap::new(),
+                }
+                .into(),
+            ),
+        };
+
+        let mut stored = StoredOutgoingRequest::from_request(&request).unwrap();
+        stored.created_at = MilliSecondsSinceUnixEpoch(uint!(0));
+        store.save_outgoing_request(&stored).await.unwrap();
+
+        let requests = alice.outgoing_requests().await.unwrap();
+        assert!(!requests.iter().any(|r| r.request_id() == &request.request_id));
+        assert!(store.get_outgoing_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_to_device() {
+        let (alice, bob, one_time_keys) = get_machine_pair().await;
+        let event_type = EventType::from("org.example.custom");
+        let content = json!({ "foo": "bar" });
+
+        let request = alice
+            .encrypt_to_device(bob.user_id(), bob.device_id(), event_type.clone(), content.clone())
+            .await
+            .unwrap();
+        assert!(request.is_none());
+
+        let (_, claim_request) = alice.get_missing_sessions([].iter()).await.unwrap().unwrap();
+        assert!(claim_request.one_time_keys[bob.user_id()].contains_key(bob.device_id()));
+
+        let one_time_key = one_time_keys.iter().next().unwrap();
+        let mut keys = BTreeMap::new();
+        keys.insert(one_time_key.0.clone(), one_time_key.1.clone());
+        let mut bob_keys = BTreeMap::new();
+        bob_keys.insert(bob.device_id().into(), keys);
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys.insert(bob.user_id().clone(), bob_keys);
+
+        alice.receive_keys_claim_response(&claim_keys::Response::new(one_time_keys)).await.unwrap();
+
+        let request = alice
+            .outgoing_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .find_map(|r| match r.request() {
+                OutgoingRequests::ToDeviceRequest(r) => Some(r.clone()),
+                _ => None,
+            })
+            .expect("The pending to-device message wasn't queued up");
+
+        let event = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(vec![Arc::new(request)]),
+        };
+
+        let decrypted = bob.decrypt_to_device_event(&event).await.unwrap();
+        let decrypted: serde_json::Value =
+            serde_json::from_str(decrypted.event.json().get()).unwrap();
+
+        assert_eq!(decrypted["type"], "org.example.custom");
+        assert_eq!(decrypted["content"], content);
+
+        let request =
+            alice.encrypt_to_device(bob.user_id(), bob.device_id(), event_type, content).await;
+        assert!(request.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_megolm_encryption() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let event = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+
+        let group_session =
+            bob.decrypt_to_device_event(&event).await.unwrap().inbound_group_session;
+        bob.store.save_inbound_group_sessions(&[group_session.unwrap()]).await.unwrap();
+
+        let plaintext = "It is a secret to everybody";
+
+        let content = MessageEventContent::text_plain(plaintext);
+
+        let encrypted_content = alice
+            .encrypt(&room_id, AnyMessageEventContent::RoomMessage(content.clone()))
+            .await
+            .unwrap();
+
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org"),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
+        };
+
+        let decrypted_event =
+            bob.decrypt_room_event(&event, &room_id).await.unwrap().event.deserialize().unwrap();
+
+        if let AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomMessage(SyncMessageEvent {
+            sender,
+            content,
+            ..
+        })) = decrypted_event
+        {
+            assert_eq!(&sender, alice.user_id());
+            if let MessageType::Text(c) = &content.msgtype {
+                assert_eq!(&c.body, plaintext);
+            } else {
+                panic!("Decrypted event has a mismatched content");
+            }
+        } else {
+            panic!("Decrypted room event has the wrong type")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_event_encryption_info() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let event = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+
+        let group_session =
+            bob.decrypt_to_device_event(&event).await.unwrap().inbound_group_session;
+        bob.store.save_inbound_group_sessions(&[group_session.unwrap()]).await.unwrap();
+
+        let content = MessageEventContent::text_plain("It is a secret to everybody");
+        let encrypted_content =
+            alice.encrypt(&room_id, AnyMessageEventContent::RoomMessage(content)).await.unwrap();
 
-        let response = claim_keys::Response::new(one_time_keys);
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org"),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
+        };
 
-        alice_machine.receive_keys_claim_response(&response).await.unwrap();
+        let encryption_info =
+            bob.decrypt_room_event(&event, &room_id).await.unwrap().encryption_info.unwrap();
+        assert_eq!(encryption_info.verification_state, VerificationState::Untrusted);
 
-        let session = alice_machine
-            .store
-            .get_sessions(bob_machine.account.identity_keys().curve25519())
+        bob.get_device(alice.user_id(), alice.device_id())
             .await
             .unwrap()
+            .unwrap()
+            .set_local_trust(LocalTrust::Verified)
+            .await
             .unwrap();
 
-        assert!(!session.lock().await.is_empty())
+        let refreshed = bob.get_event_encryption_info(&event, &room_id).await.unwrap();
+        assert_eq!(refreshed.verification_state, VerificationState::Trusted);
+        assert_eq!(refreshed.sender_device, encryption_info.sender_device);
+
+        assert!(matches!(
+            bob.get_event_encryption_info(&event, &room_id!("!other:example.org")).await,
+            Err(MegolmError::MissingSession(_))
+        ));
     }
 
     #[tokio::test]
-    async fn test_olm_encryption() {
-        let (alice, bob) = get_machine_pair_with_session().await;
+    async fn test_trust_change() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
 
-        let bob_device = alice.get_device(&bob.user_id, &bob.device_id).await.unwrap().unwrap();
+        let to_device_requests = alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
 
         let event = ToDeviceEvent {
             sender: alice.user_id().clone(),
-            content: bob_device.encrypt(EventType::Dummy, json!({})).await.unwrap().1,
+            content: to_device_requests_to_content(to_device_requests),
         };
 
-        let event = bob.decrypt_to_device_event(&event).await.unwrap().event.deserialize().unwrap();
+        let group_session =
+            bob.decrypt_to_device_event(&event).await.unwrap().inbound_group_session;
+        bob.store.save_inbound_group_sessions(&[group_session.unwrap()]).await.unwrap();
 
-        if let AnyToDeviceEvent::Dummy(e) = event {
-            assert_eq!(&e.sender, alice.user_id());
-        } else {
-            panic!("Wrong event type found {:?}", event);
-        }
+        let content = MessageEventContent::text_plain("It is a secret to everybody");
+        let encrypted_content =
+            alice.encrypt(&room_id, AnyMessageEventContent::RoomMessage(content)).await.unwrap();
+
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org"),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
+        };
+
+        let device = bob.get_device(alice.user_id(), alice.device_id()).await.unwrap().unwrap();
+
+        let untrusted =
+            bob.decrypt_room_event(&event, &room_id).await.unwrap().encryption_info.unwrap();
+        device.set_local_trust(LocalTrust::Verified).await.unwrap();
+        let trusted =
+            bob.decrypt_room_event(&event, &room_id).await.unwrap().encryption_info.unwrap();
+
+        assert_eq!(trusted.verification_state, VerificationState::Trusted);
+        assert_eq!(bob.trust_change(&trusted).await.unwrap(), None);
+        // Upgrades are only reported if requested.
+        assert_eq!(bob.trust_change(&untrusted).await.unwrap(), None);
+        bob.set_trust_change_warnings(TrustChangeWarnings::All);
+        assert_eq!(bob.trust_change(&untrusted).await.unwrap(), Some(TrustChange::Upgraded));
+
+        device.set_local_trust(LocalTrust::BlackListed).await.unwrap();
+
+        assert_eq!(bob.trust_change(&trusted).await.unwrap(), Some(TrustChange::Downgraded));
+        assert_eq!(bob.trust_change(&untrusted).await.unwrap(), None);
+
+        bob.set_trust_change_warnings(TrustChangeWarnings::Disabled);
+        assert_eq!(bob.trust_change(&trusted).await.unwrap(), None);
     }
 
     #[tokio::test]
-    async fn test_room_key_sharing() {
-        let (alice, bob) = get_machine_pair_with_session().await;
-
+    async fn test_room_keys_received_stream() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
         let room_id = room_id!("!test:example.org");
 
         let to_device_requests = alice
@@ -1614,44 +4107,48 @@ pub(crate) mod test {
             .await
             .unwrap();
 
-        let event = ToDeviceEvent {
+        let content = MessageEventContent::text_plain("It is a secret to everybody");
+        let encrypted_content =
+            alice.encrypt(&room_id, AnyMessageEventContent::RoomMessage(content)).await.unwrap();
+        let session_id = GroupEncryptedContent::try_from(&encrypted_content.scheme)
+            .unwrap()
+            .session_id
+            .to_owned();
+
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org"),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
             sender: alice.user_id().clone(),
-            content: to_device_requests_to_content(to_device_requests),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
         };
 
-        let alice_session =
-            alice.group_session_manager.get_outbound_group_session(&room_id).unwrap();
+        let mut stream = bob.room_keys_received_stream();
 
-        let decrypted = bob.decrypt_to_device_event(&event).await.unwrap();
+        assert!(matches!(
+            bob.decrypt_room_event(&event, &room_id).await,
+            Err(MegolmError::MissingSession(_))
+        ));
 
-        bob.store.save_sessions(&[decrypted.session.session()]).await.unwrap();
-        bob.store
-            .save_inbound_group_sessions(&[decrypted.inbound_group_session.unwrap()])
-            .await
-            .unwrap();
-        let event = decrypted.deserialized_event.unwrap();
+        let to_device = ToDeviceEvent {
+            sender: alice.user_id().clone(),
+            content: to_device_requests_to_content(to_device_requests),
+        };
+        let group_session =
+            bob.decrypt_to_device_event(&to_device).await.unwrap().inbound_group_session;
+        bob.store.save_inbound_group_sessions(&[group_session.unwrap()]).await.unwrap();
 
-        if let AnyToDeviceEvent::RoomKey(event) = event {
-            assert_eq!(&event.sender, alice.user_id());
-            assert!(event.content.session_key.is_empty());
-        } else {
-            panic!("expected RoomKeyEvent found {:?}", event);
-        }
+        let received = stream.next().await.unwrap();
 
-        let session = bob
-            .store
-            .get_inbound_group_session(
-                &room_id,
-                alice.account.identity_keys().curve25519(),
-                alice_session.session_id(),
-            )
-            .await;
+        assert_eq!(received.room_id, room_id);
+        assert_eq!(received.session_ids, vec![session_id]);
+        assert_eq!(received.event_ids, vec![event.event_id.clone()]);
 
-        assert!(session.unwrap().is_some());
+        bob.decrypt_room_event(&event, &room_id).await.unwrap();
     }
 
     #[tokio::test]
-    async fn test_megolm_encryption() {
+    async fn test_decryption_error_context() {
         let (alice, bob) = get_machine_pair_with_setup_sessions().await;
         let room_id = room_id!("!test:example.org");
 
@@ -1664,24 +4161,67 @@ pub(crate) mod test {
             .await
             .unwrap();
 
-        let event = ToDeviceEvent {
+        let to_device = ToDeviceEvent {
             sender: alice.user_id().clone(),
             content: to_device_requests_to_content(to_device_requests),
         };
-
         let group_session =
-            bob.decrypt_to_device_event(&event).await.unwrap().inbound_group_session;
+            bob.decrypt_to_device_event(&to_device).await.unwrap().inbound_group_session;
         bob.store.save_inbound_group_sessions(&[group_session.unwrap()]).await.unwrap();
 
-        let plaintext = "It is a secret to everybody";
+        let content = MessageEventContent::text_plain("It is a secret to everybody");
+        let mut encrypted_content =
+            alice.encrypt(&room_id, AnyMessageEventContent::RoomMessage(content)).await.unwrap();
+        let session_id = GroupEncryptedContent::try_from(&encrypted_content.scheme)
+            .unwrap()
+            .session_id
+            .to_owned();
 
-        let content = MessageEventContent::text_plain(plaintext);
+        if let EncryptedEventScheme::MegolmV1AesSha2(c) = &mut encrypted_content.scheme {
+            c.ciphertext = "not a megolm message".to_owned();
+        }
 
-        let encrypted_content = alice
-            .encrypt(&room_id, AnyMessageEventContent::RoomMessage(content.clone()))
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org"),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
+        };
+
+        let error = bob.decrypt_room_event(&event, &room_id).await.unwrap_err();
+        assert_eq!(error.error_code(), "olm_group_session_error");
+
+        let context = error.context();
+        assert_eq!(context.user_id.as_ref(), Some(alice.user_id()));
+        assert_eq!(context.device_id.as_deref(), Some(alice.device_id()));
+        assert_eq!(context.sender_key.as_deref(), Some(alice.identity_keys().curve25519()));
+        assert_eq!(context.session_id, Some(session_id));
+    }
+
+    #[tokio::test]
+    async fn test_withheld_room_key() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        // Alice creates a group session but the room key never reaches Bob.
+        alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
             .await
             .unwrap();
 
+        let content = MessageEventContent::text_plain("It is a secret to everybody");
+        let encrypted_content =
+            alice.encrypt(&room_id, AnyMessageEventContent::RoomMessage(content)).await.unwrap();
+        let session_id = GroupEncryptedContent::try_from(&encrypted_content.scheme)
+            .unwrap()
+            .session_id
+            .to_owned();
+
         let event = SyncMessageEvent {
             event_id: event_id!("$xxxxx:example.org"),
             origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
@@ -1690,24 +4230,76 @@ pub(crate) mod test {
             unsigned: Unsigned::default(),
         };
 
-        let decrypted_event =
-            bob.decrypt_room_event(&event, &room_id).await.unwrap().event.deserialize().unwrap();
-
-        if let AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomMessage(SyncMessageEvent {
-            sender,
-            content,
-            ..
-        })) = decrypted_event
-        {
-            assert_eq!(&sender, alice.user_id());
-            if let MessageType::Text(c) = &content.msgtype {
-                assert_eq!(&c.body, plaintext);
-            } else {
-                panic!("Decrypted event has a mismatched content");
+        let error = bob.decrypt_room_event(&event, &room_id).await.unwrap_err();
+        assert_eq!(error.error_code(), "missing_megolm_session");
+
+        let context = error.context();
+        assert_eq!(context.user_id.as_ref(), Some(alice.user_id()));
+        assert_eq!(context.device_id.as_deref(), Some(alice.device_id()));
+        assert_eq!(context.sender_key.as_deref(), Some(alice.identity_keys().curve25519()));
+        assert_eq!(context.session_id, Some(session_id.clone()));
+
+        let withheld = json!({
+            "sender": alice.user_id(),
+            "type": "m.room_key.withheld",
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "room_id": room_id,
+                "session_id": session_id,
+                "sender_key": alice.identity_keys().curve25519(),
+                "code": "m.unverified",
+                "reason": "Device not verified",
             }
-        } else {
-            panic!("Decrypted room event has the wrong type")
-        }
+        });
+
+        let mut to_device = ToDevice::new();
+        to_device.events.push(serde_json::from_value(withheld).unwrap());
+        bob.receive_sync_changes(to_device, &DeviceLists::new(), &BTreeMap::new()).await.unwrap();
+
+        let info = bob.store.get_withheld_info(&room_id, &session_id).await.unwrap().unwrap();
+        assert_eq!(info.code, WithheldCode::Unverified);
+        assert_eq!(info.sender, *alice.user_id());
+
+        assert!(matches!(
+            bob.decrypt_room_event(&event, &room_id).await,
+            Err(MegolmError::Withheld(WithheldCode::Unverified, _))
+        ));
+    }
+
+    #[tokio::test]
+    #[cfg(feature = "sled_cryptostore")]
+    async fn cross_process_store_lock() {
+        use crate::store::SledStore;
+
+        // Two handles to the same database, each with its own caches, like
+        // two processes that open the same store.
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let app_store = SledStore::open_with_database(db.clone(), None).unwrap();
+        let extension_store = SledStore::open_with_database(db, None).unwrap();
+
+        let mut app =
+            OlmMachine::new_with_store(user_id(), alice_device_id(), app_store).await.unwrap();
+        let mut extension =
+            OlmMachine::new_with_store(user_id(), alice_device_id(), extension_store)
+                .await
+                .unwrap();
+
+        app.enable_cross_process_store_lock("app");
+        extension.enable_cross_process_store_lock("extension");
+
+        extension.lock_store().await.unwrap().unwrap().release().await.unwrap();
+        assert!(!extension.account.inner.shared());
+
+        let guard = app.lock_store().await.unwrap().unwrap();
+        app.receive_keys_upload_response(&keys_upload_response()).await.unwrap();
+        assert!(extension.store_lock.as_ref().unwrap().try_lock_once().await.unwrap().is_none());
+        guard.release().await.unwrap();
+
+        // The extension notices that the app held the lock and picks up the
+        // changes it made to the account.
+        let guard = extension.lock_store().await.unwrap().unwrap();
+        assert!(guard.is_dirty());
+        assert!(extension.account.inner.shared());
     }
 
     #[tokio::test]
@@ -1823,4 +4415,206 @@ pub(crate) mod test {
         assert!(bob_sas.is_done());
         assert!(alice_device.is_trusted());
     }
+
+    #[tokio::test]
+    async fn export_room_keys_for_rooms() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:example.org");
+        let other_room_id = room_id!("!other:example.org");
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        machine.create_outbound_group_session_with_defaults(&other_room_id).await.unwrap();
+
+        let progress = std::sync::Mutex::new(Vec::new());
+
+        let export = machine
+            .export_room_keys_for_rooms(&[room_id.clone()], "1234", 1, Vec::new(), |done, total| {
+                progress.lock().unwrap().push((done, total))
+            })
+            .await
+            .unwrap();
+
+        let keys = decrypt_key_export(std::io::Cursor::new(export), "1234").unwrap();
+
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].room_id, room_id);
+        assert_eq!(*progress.lock().unwrap(), vec![(1, 1)]);
+    }
+
+    #[tokio::test]
+    async fn import_keys_provenance() {
+        let (machine, _) = get_prepared_machine().await;
+        let (other_machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+        let export = machine.export_keys(|s| s.room_id() == &room_id).await.unwrap();
+        let session_id = export[0].session_id.clone();
+
+        let progress = std::sync::Mutex::new(Vec::new());
+        let result =
+            other_machine.import_keys(export, |p| progress.lock().unwrap().push(*p)).await.unwrap();
+
+        assert_eq!(
+            result,
+            RoomKeyImportResult { imported_count: 1, skipped_count: 0, total_count: 1 }
+        );
+        assert_eq!(*progress.lock().unwrap(), vec![result]);
+
+        let session = other_machine
+            .store
+            .get_inbound_group_session(&room_id, machine.identity_keys().curve25519(), &session_id)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(session.source(), RoomKeySource::FileImport);
+    }
+
+    #[tokio::test]
+    async fn indirect_room_key_policy() {
+        let (alice, bob) = get_machine_pair_with_setup_sessions().await;
+        let room_id = room_id!("!test:example.org");
+
+        alice
+            .share_group_session(
+                &room_id,
+                [bob.user_id().clone()].iter(),
+                EncryptionSettings::default(),
+            )
+            .await
+            .unwrap();
+
+        let export = alice.export_keys(|s| s.room_id() == &room_id).await.unwrap();
+        bob.import_keys(export, |_| {}).await.unwrap();
+
+        let content = MessageEventContent::text_plain("It is a secret to everybody");
+        let encrypted_content =
+            alice.encrypt(&room_id, AnyMessageEventContent::RoomMessage(content)).await.unwrap();
+
+        let event = SyncMessageEvent {
+            event_id: event_id!("$xxxxx:example.org"),
+            origin_server_ts: MilliSecondsSinceUnixEpoch::now(),
+            sender: alice.user_id().clone(),
+            content: encrypted_content,
+            unsigned: Unsigned::default(),
+        };
+
+        let encryption_info =
+            bob.decrypt_room_event(&event, &room_id).await.unwrap().encryption_info.unwrap();
+
+        assert!(encryption_info.indirect_room_key);
+        assert_eq!(encryption_info.verification_state, VerificationState::Untrusted);
+
+        bob.set_indirect_room_key_policy(IndirectRoomKeyPolicy::Reject);
+
+        assert!(matches!(
+            bob.decrypt_room_event(&event, &room_id).await,
+            Err(MegolmError::IndirectRoomKey(RoomKeySource::FileImport, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn crypto_status() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:example.org");
+
+        assert_eq!(machine.crypto_status().await.unwrap(), CryptoStatus::default());
+
+        let (request_id, _, _) = machine.bootstrap_cross_signing(false).await.unwrap();
+        assert!(!machine.crypto_status().await.unwrap().cross_signing_set_up);
+
+        mark_cross_signing_as_uploaded(&machine, &request_id).await;
+        machine.create_outbound_group_session_with_defaults(&room_id).await.unwrap();
+
+        let status = machine.crypto_status().await.unwrap();
+        assert!(status.cross_signing_set_up);
+        assert!(!status.backup_enabled);
+        assert_eq!(status.keys_not_backed_up, 1);
+
+        let mut changes = machine.crypto_status_changes_stream();
+        assert!(changes.next().now_or_never().is_none());
+
+        let key = BackupDecryptionKey::new();
+        machine.enable_backup("1".to_owned(), key.public_key()).await.unwrap();
+        assert!(machine.crypto_status().await.unwrap().backup_enabled);
+        assert!(changes.next().now_or_never().is_some());
+
+        machine.disable_backup().await;
+        assert!(!machine.crypto_status().await.unwrap().backup_enabled);
+    }
+
+    #[tokio::test]
+    async fn reset_cross_signing() {
+        let (machine, _) = get_prepared_machine().await;
+        let secret_storage_key = SecretStorageKey::new();
+
+        bootstrap_cross_signing(&machine).await;
+        let old_secrets = machine.user_identity.lock().await.export_secrets().await;
+
+        let reset = machine.reset_cross_signing(Some(&secret_storage_key)).await.unwrap();
+
+        // The new identity isn't used before it's uploaded, retrying the
+        // upload hands out the same identity.
+        assert_eq!(machine.user_identity.lock().await.export_secrets().await, old_secrets);
+        let retry = machine.reset_cross_signing(Some(&secret_storage_key)).await.unwrap();
+        assert_eq!(retry.request_id, reset.request_id);
+        assert_eq!(
+            serde_json::to_value(retry.upload_signing_keys_request.master_key).unwrap(),
+            serde_json::to_value(&reset.upload_signing_keys_request.master_key).unwrap()
+        );
+
+        mark_cross_signing_as_uploaded(&machine, &reset.request_id).await;
+        let new_secrets = machine.user_identity.lock().await.export_secrets().await;
+
+        assert!(reset.upload_signing_keys_request.master_key.is_some());
+        assert_eq!(reset.secret_storage_content.len(), 3);
+        assert_ne!(old_secrets, new_secrets);
+
+        for (name, secret) in new_secrets {
+            let content = &reset.secret_storage_content[name];
+            let decrypted = secret_storage_key.decrypt_secret_content(name, content).unwrap();
+            assert_eq!(decrypted, *secret);
+        }
+
+        assert!(machine.store.users_for_key_query().contains(machine.user_id()));
+    }
+
+    #[tokio::test]
+    async fn verify_with_secret_storage() {
+        let (machine, _) = get_prepared_machine().await;
+        let secret_storage_key = SecretStorageKey::new();
+
+        let reset = machine.reset_cross_signing(Some(&secret_storage_key)).await.unwrap();
+        mark_cross_signing_as_uploaded(&machine, &reset.request_id).await;
+        let public_identity =
+            machine.user_identity.lock().await.as_public_identity().await.unwrap();
+
+        let other_machine = OlmMachine::new(&user_id(), "OTHERDEVICE".into());
+        let changes = Changes {
+            identities: IdentityChanges {
+                new: vec![public_identity.clone().into()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        other_machine.store.save_changes(changes).await.unwrap();
+
+        let wrong_key = SecretStorageKey::new();
+        assert!(other_machine
+            .verify_with_secret_storage(&wrong_key, &reset.secret_storage_content)
+            .await
+            .is_err());
+
+        let signature_request = other_machine
+            .verify_with_secret_storage(&secret_storage_key, &reset.secret_storage_content)
+            .await
+            .unwrap();
+
+        assert!(signature_request.signed_keys[&user_id()].contains_key("OTHERDEVICE"));
+        assert!(!other_machine.user_identity.lock().await.is_empty().await);
+
+        let identity = other_machine.store.get_user_identity(&user_id()).await.unwrap().unwrap();
+        assert!(identity.own().unwrap().is_verified());
+    }
 }
@@ -19,11 +19,11 @@ use std::{
     ops::Deref,
     sync::{
         atomic::{AtomicBool, AtomicI64, Ordering},
-        Arc,
+        Arc, Mutex as StdMutex,
     },
 };
 
-use matrix_sdk_common::{instant::Instant, locks::Mutex};
+use matrix_sdk_common::locks::Mutex;
 use olm_rs::{
     account::{IdentityKeys, OlmAccount, OneTimeKeys},
     errors::{OlmAccountError, OlmSessionError},
@@ -46,10 +46,10 @@ use ruma::{
         UserId,
     },
     serde::{CanonicalJsonValue, Raw},
-    UInt,
+    MilliSecondsSinceUnixEpoch, UInt,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::{json, value::to_raw_value, Value};
 use sha2::{Digest, Sha256};
 use tracing::{debug, trace, warn};
 
@@ -58,11 +58,11 @@ use super::{
     Session,
 };
 use crate::{
-    error::{EventError, OlmResult, SessionCreationError},
+    error::{ErrorContext, EventError, OlmResult, SessionCreationError},
     identities::ReadOnlyDevice,
     requests::UploadSigningKeysRequest,
     store::{Changes, Store},
-    utilities::encode,
+    utilities::{encode, log_id},
     OlmError,
 };
 
@@ -133,14 +133,21 @@ impl Account {
             return Err(EventError::UnsupportedAlgorithm.into());
         };
 
+        let context = ErrorContext {
+            user_id: Some(event.sender.clone()),
+            sender_key: Some(content.sender_key.clone()),
+            ..Default::default()
+        };
+
         let identity_keys = self.inner.identity_keys();
         let own_key = identity_keys.curve25519();
         let own_ciphertext = content.ciphertext.get(own_key);
 
         // Try to find a ciphertext that was meant for our device.
         if let Some(ciphertext) = own_ciphertext {
-            let message_type: u8 =
-                ciphertext.message_type.try_into().map_err(|_| EventError::UnsupportedOlmType)?;
+            let message_type: u8 = ciphertext.message_type.try_into().map_err(|_| {
+                OlmError::from(EventError::UnsupportedOlmType).with_context(context.clone())
+            })?;
 
             let sha = Sha256::new()
                 .chain(&content.sender_key)
@@ -155,7 +162,9 @@ impl Account {
             // Create a OlmMessage from the ciphertext and the type.
             let message =
                 OlmMessage::from_type_and_ciphertext(message_type.into(), ciphertext.body.clone())
-                    .map_err(|_| EventError::UnsupportedOlmType)?;
+                    .map_err(|_| {
+                        OlmError::from(EventError::UnsupportedOlmType).with_context(context.clone())
+                    })?;
 
             // Decrypt the OlmMessage and get a Ruma event out of it.
             let (session, event, signing_key) =
@@ -168,10 +177,10 @@ impl Account {
                             return Err(OlmError::SessionWedged(user_id, sender_key));
                         }
                     }
-                    Err(e) => return Err(e),
+                    Err(e) => return Err(e.with_context(context)),
                 };
 
-            debug!("Decrypted a to-device event {:?}", event);
+            debug!("Decrypted a to-device event from {}", log_id(&content.sender_key));
 
             Ok(OlmDecryptionInfo {
                 session,
@@ -184,7 +193,7 @@ impl Account {
             })
         } else {
             warn!("Olm event doesn't contain a ciphertext for our key");
-            Err(EventError::MissingCiphertext.into())
+            Err(OlmError::from(EventError::MissingCiphertext).with_context(context))
         }
     }
 
@@ -215,6 +224,7 @@ impl Account {
         );
         self.inner.update_uploaded_key_count(count);
         self.inner.mark_keys_as_published().await;
+        self.inner.mark_keys_upload_as_succeeded();
         self.store.save_account(self.inner.clone()).await?;
 
         Ok(())
@@ -268,7 +278,9 @@ impl Account {
                         warn!(
                             "Found a matching Olm session yet decryption failed
                               for sender {} and sender_key {} {:?}",
-                            sender, sender_key, e
+                            log_id(sender.as_str()),
+                            log_id(sender_key),
+                            e
                         );
                         return Err(OlmError::SessionWedged(
                             sender.to_owned(),
@@ -306,7 +318,8 @@ impl Account {
                     warn!(
                         "Failed to decrypt a non-pre-key message with all \
                           available sessions {} {}",
-                        sender, sender_key
+                        log_id(sender.as_str()),
+                        log_id(sender_key)
                     );
                     return Err(OlmError::SessionWedged(sender.to_owned(), sender_key.to_owned()));
                 }
@@ -320,7 +333,9 @@ impl Account {
                                 warn!(
                                     "Failed to create a new Olm session for {} {}
                                       from a prekey message: {}",
-                                    sender, sender_key, e
+                                    log_id(sender.as_str()),
+                                    log_id(sender_key),
+                                    e
                                 );
                                 return Err(OlmError::SessionWedged(
                                     sender.to_owned(),
@@ -417,10 +432,51 @@ impl Account {
         let signing_key =
             keys.get(&DeviceKeyAlgorithm::Ed25519).ok_or(EventError::MissingSigningKey)?;
 
-        Ok((
-            Raw::from(serde_json::from_value::<AnyToDeviceEvent>(decrypted_json)?),
-            signing_key.to_owned(),
-        ))
+        // Keep the decrypted JSON as it is instead of round-tripping it
+        // through `AnyToDeviceEvent`, this way unknown fields and event types
+        // that we can't deserialize yet are passed on to the user untouched.
+        Ok((Raw::from_json(to_raw_value(&decrypted_json)?), signing_key.to_owned()))
+    }
+}
+
+/// The reason an upload of our device and one-time keys failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeysUploadFailure {
+    /// The request was too large for the server, e.g. it responded with `413
+    /// Payload Too Large`. The pending one-time keys are kept and the
+    /// following uploads contain half as many of them.
+    TooLarge,
+    /// The server rejected some of our one-time keys, e.g. because it already
+    /// has a key with the same key id or a signature didn't verify. The
+    /// pending one-time keys are discarded and new ones are generated.
+    RejectedKeys,
+    /// Any other failure, the same keys will be uploaded again with the next
+    /// request.
+    Other,
+}
+
+/// Diagnostics about the upload of our one-time keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeysUploadDiagnostics {
+    /// The maximal number of one-time keys a single upload contains.
+    pub batch_size: usize,
+    /// The number of uploads that failed in a row.
+    pub consecutive_failures: u32,
+    /// The reason the last upload failed, `None` if it succeeded.
+    pub last_failure: Option<KeysUploadFailure>,
+    /// The number of one-time keys that were discarded because an upload
+    /// containing them failed.
+    pub discarded_keys: usize,
+}
+
+impl Default for KeysUploadDiagnostics {
+    fn default() -> Self {
+        Self {
+            batch_size: ReadOnlyAccount::ONE_TIME_KEYS_BATCH_SIZE,
+            consecutive_failures: 0,
+            last_failure: None,
+            discarded_keys: 0,
+        }
     }
 }
 
@@ -440,6 +496,8 @@ pub struct ReadOnlyAccount {
     /// needs to set this for us, depending on the count we will suggest the
     /// client to upload new keys.
     uploaded_signed_key_count: Arc<AtomicI64>,
+    /// The batch size and the failures of the one-time key uploads.
+    keys_upload: Arc<StdMutex<KeysUploadDiagnostics>>,
 }
 
 /// A typed representation of a base64 encoded string containing the account
@@ -489,6 +547,9 @@ impl fmt::Debug for ReadOnlyAccount {
 }
 
 impl ReadOnlyAccount {
+    /// The default maximal number of one-time keys a single upload contains.
+    const ONE_TIME_KEYS_BATCH_SIZE: usize = 50;
+
     const ALGORITHMS: &'static [&'static EventEncryptionAlgorithm] = &[
         &EventEncryptionAlgorithm::OlmV1Curve25519AesSha2,
         &EventEncryptionAlgorithm::MegolmV1AesSha2,
@@ -507,6 +568,7 @@ impl ReadOnlyAccount {
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::new(false)),
             uploaded_signed_key_count: Arc::new(AtomicI64::new(0)),
+            keys_upload: Default::default(),
         }
     }
 
@@ -553,6 +615,22 @@ impl ReadOnlyAccount {
         self.shared.store(true, Ordering::Relaxed);
     }
 
+    /// Replace the state of this account with the state of the given account.
+    ///
+    /// This is used to pick up the changes another process made to the
+    /// account, every copy of this account will see the new state.
+    pub(crate) async fn replace_with(&self, other: &ReadOnlyAccount) {
+        // Stores that keep the account in memory hand out a copy of this very
+        // account, locking it twice would deadlock.
+        if Arc::ptr_eq(&self.inner, &other.inner) {
+            return;
+        }
+
+        std::mem::swap(&mut *self.inner.lock().await, &mut *other.inner.lock().await);
+        self.shared.store(other.shared(), Ordering::Relaxed);
+        self.uploaded_signed_key_count.store(other.uploaded_key_count(), Ordering::Relaxed);
+    }
+
     /// Get the one-time keys of the account.
     ///
     /// This can be empty, keys need to be generated first.
@@ -584,11 +662,70 @@ impl ReadOnlyAccount {
 
         let key_count = max_on_server - count;
         let key_count: usize = key_count.try_into().unwrap_or(max_keys);
+        let key_count = key_count.min(self.keys_upload_diagnostics().batch_size);
+
+        // Keys of a previous upload that failed will be uploaded again, only
+        // top them up to the batch size.
+        let pending = self.one_time_keys().await.curve25519().len();
+        let key_count = key_count.saturating_sub(pending);
 
         self.generate_one_time_keys_helper(key_count).await;
         Ok(key_count as u64)
     }
 
+    /// Get the batch size and the failures of the one-time key uploads.
+    pub(crate) fn keys_upload_diagnostics(&self) -> KeysUploadDiagnostics {
+        *self.keys_upload.lock().unwrap()
+    }
+
+    /// Set the maximal number of one-time keys a single upload contains.
+    pub(crate) fn set_one_time_keys_batch_size(&self, batch_size: usize) {
+        self.keys_upload.lock().unwrap().batch_size = batch_size.max(1);
+    }
+
+    /// Handle a failed upload of our keys.
+    ///
+    /// Returns true if the pending one-time keys were discarded and the
+    /// account needs to be saved.
+    pub(crate) async fn mark_keys_upload_as_failed(&self, failure: KeysUploadFailure) -> bool {
+        // Only keys the server rejected need to be replaced, the other keys
+        // are uploaded again, in smaller batches if the upload was too large.
+        let discard = failure == KeysUploadFailure::RejectedKeys;
+        let discarded = if discard { self.one_time_keys().await.curve25519().len() } else { 0 };
+
+        if discard {
+            // Olm can't forget about unpublished keys, marking them as
+            // published makes sure that they won't be uploaded again.
+            self.mark_keys_as_published().await;
+        }
+
+        let mut diagnostics = self.keys_upload.lock().unwrap();
+        diagnostics.consecutive_failures = diagnostics.consecutive_failures.saturating_add(1);
+        diagnostics.last_failure = Some(failure);
+        diagnostics.discarded_keys += discarded;
+
+        if failure == KeysUploadFailure::TooLarge {
+            diagnostics.batch_size = (diagnostics.batch_size / 2).max(1);
+        }
+
+        warn!(
+            ?failure,
+            discarded_keys = discarded,
+            batch_size = diagnostics.batch_size,
+            "Uploading our keys failed"
+        );
+
+        discard
+    }
+
+    /// Reset the failures of the one-time key uploads after a successful
+    /// upload.
+    pub(crate) fn mark_keys_upload_as_succeeded(&self) {
+        let mut diagnostics = self.keys_upload.lock().unwrap();
+        diagnostics.consecutive_failures = 0;
+        diagnostics.last_failure = None;
+    }
+
     /// Should account or one-time keys be uploaded to the server.
     pub(crate) async fn should_upload_keys(&self) -> bool {
         if !self.shared() {
@@ -681,6 +818,7 @@ impl ReadOnlyAccount {
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::from(pickle.shared)),
             uploaded_signed_key_count: Arc::new(AtomicI64::new(pickle.uploaded_signed_key_count)),
+            keys_upload: Default::default(),
         })
     }
 
@@ -756,9 +894,14 @@ impl ReadOnlyAccount {
         &self,
     ) -> Result<BTreeMap<DeviceKeyId, OneTimeKey>, ()> {
         let one_time_keys = self.one_time_keys().await;
+        let batch_size = self.keys_upload_diagnostics().batch_size;
         let mut one_time_key_map = BTreeMap::new();
 
-        for (key_id, key) in one_time_keys.curve25519().iter() {
+        // Keys that are kept from an upload that was too large might not fit
+        // into a single batch anymore, Olm can only mark all of them as
+        // published, the ones that don't fit are dropped once a batch was
+        // uploaded.
+        for (key_id, key) in one_time_keys.curve25519().iter().take(batch_size) {
             let key_json = json!({
                 "key": key,
             });
@@ -820,7 +963,7 @@ impl ReadOnlyAccount {
             .await
             .create_outbound_session(their_identity_key, &their_one_time_key.key)?;
 
-        let now = Instant::now();
+        let now = MilliSecondsSinceUnixEpoch::now();
         let session_id = session.session_id();
 
         Ok(Session {
@@ -920,7 +1063,7 @@ impl ReadOnlyAccount {
             "Session was successfully created but the account doesn't hold a matching one-time key",
         );
 
-        let now = Instant::now();
+        let now = MilliSecondsSinceUnixEpoch::now();
         let session_id = session.session_id();
 
         Ok(Session {
@@ -954,9 +1097,7 @@ impl ReadOnlyAccount {
         room_id: &RoomId,
         settings: EncryptionSettings,
     ) -> Result<(OutboundGroupSession, InboundGroupSession), ()> {
-        if settings.algorithm != EventEncryptionAlgorithm::MegolmV1AesSha2 {
-            return Err(());
-        }
+        settings.check_algorithm().map_err(|_| ())?;
 
         let visibility = settings.history_visibility.clone();
 
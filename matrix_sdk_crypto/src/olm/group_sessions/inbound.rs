@@ -12,7 +12,15 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::BTreeMap, convert::TryFrom, fmt, mem, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt, mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock as StdRwLock,
+    },
+};
 
 use matrix_sdk_common::locks::Mutex;
 pub use olm_rs::{
@@ -26,10 +34,7 @@ use olm_rs::{
 use ruma::{
     events::{
         forwarded_room_key::ForwardedRoomKeyToDeviceEventContent,
-        room::{
-            encrypted::{EncryptedEventContent, EncryptedEventScheme},
-            history_visibility::HistoryVisibility,
-        },
+        room::{encrypted::EncryptedEventContent, history_visibility::HistoryVisibility},
         AnySyncRoomEvent, SyncMessageEvent,
     },
     identifiers::{DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId},
@@ -39,9 +44,40 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use zeroize::Zeroizing;
 
-use super::{ExportedGroupSessionKey, ExportedRoomKey, GroupSessionKey};
+use super::{
+    is_shared_history, ExportedGroupSessionKey, ExportedRoomKey, GroupEncryptedContent,
+    GroupSessionKey,
+};
 use crate::error::{EventError, MegolmResult};
 
+/// The way we received the room key of an `InboundGroupSession`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomKeySource {
+    /// The room key was sent to us directly by the device that created the
+    /// session, using a `m.room_key` event.
+    Direct,
+    /// The room key was forwarded to us by another device using a
+    /// `m.forwarded_room_key` event.
+    Forwarded,
+    /// The room key was restored from a server-side key backup.
+    Backup,
+    /// The room key was imported from a key export file.
+    FileImport,
+}
+
+impl RoomKeySource {
+    /// Did we receive the room key directly from the device that created the
+    /// session.
+    ///
+    /// Only for such room keys do we know that the sender claimed keys of the
+    /// session belong to the device that created it, room keys from any other
+    /// source need to be trusted less.
+    pub fn is_direct(&self) -> bool {
+        matches!(self, RoomKeySource::Direct)
+    }
+}
+
 // TODO add creation times to the inbound group sessions so we can export
 // sessions that were created between some time period, this should only be set
 // for non-imported sessions.
@@ -54,6 +90,7 @@ use crate::error::{EventError, MegolmResult};
 pub struct InboundGroupSession {
     inner: Arc<Mutex<OlmInboundGroupSession>>,
     history_visibility: Arc<Option<HistoryVisibility>>,
+    shared_history: bool,
     session_id: Arc<str>,
     first_known_index: u32,
     pub(crate) sender_key: Arc<str>,
@@ -61,6 +98,9 @@ pub struct InboundGroupSession {
     pub(crate) room_id: Arc<RoomId>,
     forwarding_chains: Arc<Vec<String>>,
     imported: Arc<bool>,
+    source: RoomKeySource,
+    backed_up: Arc<AtomicBool>,
+    backup_version: Arc<StdRwLock<Option<String>>>,
 }
 
 impl InboundGroupSession {
@@ -80,6 +120,10 @@ impl InboundGroupSession {
     ///
     /// * `session_key` - The private session key that is used to decrypt
     /// messages.
+    ///
+    /// * `history_visibility` - The history visibility of the room at the time
+    /// the session was created, decides if the session is marked as one that
+    /// can be shared with users that join the room later on.
     pub(crate) fn new(
         sender_key: &str,
         signing_key: &str,
@@ -94,16 +138,22 @@ impl InboundGroupSession {
         let mut keys: BTreeMap<DeviceKeyAlgorithm, String> = BTreeMap::new();
         keys.insert(DeviceKeyAlgorithm::Ed25519, signing_key.to_owned());
 
+        let shared_history = history_visibility.as_ref().map_or(false, is_shared_history);
+
         Ok(InboundGroupSession {
             inner: Arc::new(Mutex::new(session)),
             session_id: session_id.into(),
             history_visibility: history_visibility.into(),
+            shared_history,
             sender_key: sender_key.to_owned().into(),
             first_known_index,
             signing_keys: keys.into(),
             room_id: room_id.clone().into(),
             forwarding_chains: Vec::new().into(),
             imported: false.into(),
+            source: RoomKeySource::Direct,
+            backed_up: AtomicBool::new(false).into(),
+            backup_version: StdRwLock::new(None).into(),
         })
     }
 
@@ -121,6 +171,17 @@ impl InboundGroupSession {
         Self::try_from(exported_session.into())
     }
 
+    /// Create a InboundGroupSession from a room key that was restored from a
+    /// server-side key backup.
+    pub(crate) fn from_backup(
+        exported_session: ExportedRoomKey,
+    ) -> Result<Self, OlmGroupSessionError> {
+        let mut session = Self::try_from(exported_session)?;
+        session.source = RoomKeySource::Backup;
+
+        Ok(session)
+    }
+
     /// Create a new inbound group session from a forwarded room key content.
     ///
     /// # Arguments
@@ -151,10 +212,14 @@ impl InboundGroupSession {
             sender_key: content.sender_key.as_str().into(),
             first_known_index,
             history_visibility: None.into(),
+            shared_history: false,
             signing_keys: sender_claimed_key.into(),
             room_id: content.room_id.clone().into(),
             forwarding_chains: forwarding_chains.into(),
             imported: true.into(),
+            source: RoomKeySource::Forwarded,
+            backed_up: AtomicBool::new(false).into(),
+            backup_version: StdRwLock::new(None).into(),
         })
     }
 
@@ -174,7 +239,11 @@ impl InboundGroupSession {
             room_id: (&*self.room_id).clone(),
             forwarding_chains: self.forwarding_key_chain().to_vec(),
             imported: *self.imported,
+            source: Some(self.source),
+            backed_up: self.backed_up(),
+            backup_version: self.backup_version(),
             history_visibility: self.history_visibility.as_ref().clone(),
+            shared_history: self.shared_history,
         }
     }
 
@@ -205,6 +274,11 @@ impl InboundGroupSession {
         &self.forwarding_chains
     }
 
+    /// Get the way we received the room key of this session.
+    pub fn source(&self) -> RoomKeySource {
+        self.source
+    }
+
     /// Export this session at the given message index.
     pub async fn export_at_index(&self, message_index: u32) -> ExportedRoomKey {
         let message_index = std::cmp::max(self.first_known_index(), message_index);
@@ -221,6 +295,7 @@ impl InboundGroupSession {
             forwarding_curve25519_key_chain: self.forwarding_key_chain().to_vec(),
             sender_claimed_keys: (&*self.signing_keys).clone(),
             session_key,
+            shared_history: self.shared_history,
         }
     }
 
@@ -243,19 +318,84 @@ impl InboundGroupSession {
         let first_known_index = session.first_known_index();
         let session_id = session.session_id();
 
+        // Pickles that were created before we started to track the source of
+        // a room key only tell us if the key was imported.
+        let source = pickle.source.unwrap_or(if !pickle.forwarding_chains.is_empty() {
+            RoomKeySource::Forwarded
+        } else if pickle.imported {
+            RoomKeySource::FileImport
+        } else {
+            RoomKeySource::Direct
+        });
+
         Ok(InboundGroupSession {
             inner: Mutex::new(session).into(),
             session_id: session_id.into(),
             sender_key: pickle.sender_key.into(),
             history_visibility: pickle.history_visibility.into(),
+            shared_history: pickle.shared_history,
             first_known_index,
             signing_keys: pickle.signing_key.into(),
             room_id: pickle.room_id.into(),
             forwarding_chains: pickle.forwarding_chains.into(),
             imported: pickle.imported.into(),
+            source,
+            backed_up: AtomicBool::new(pickle.backed_up).into(),
+            backup_version: StdRwLock::new(pickle.backup_version).into(),
         })
     }
 
+    /// Was the session created while the history of the room was visible to
+    /// users that join the room later on.
+    ///
+    /// Only sessions with this flag set may be shared with such users.
+    pub fn shared_history(&self) -> bool {
+        self.shared_history
+    }
+
+    /// Mark the session as one with shared history, or not, as the room key
+    /// event that sent us the session says.
+    pub(crate) fn set_shared_history(&mut self, shared_history: bool) {
+        self.shared_history = shared_history;
+    }
+
+    /// Has the session been uploaded to the server-side key backup.
+    pub fn backed_up(&self) -> bool {
+        self.backed_up.load(Ordering::SeqCst)
+    }
+
+    /// The version of the server-side key backup the session was uploaded to
+    /// or restored from.
+    pub fn backup_version(&self) -> Option<String> {
+        self.backup_version.read().unwrap().clone()
+    }
+
+    /// Mark the session as uploaded to the server-side key backup with the
+    /// given version.
+    pub(crate) fn mark_as_backed_up(&self, backup_version: &str) {
+        *self.backup_version.write().unwrap() = Some(backup_version.to_owned());
+        self.backed_up.store(true, Ordering::SeqCst)
+    }
+
+    /// Mark the session as not uploaded to the server-side key backup, e.g.
+    /// because a new backup version was created.
+    pub(crate) fn reset_backup_state(&self) {
+        self.backup_version.write().unwrap().take();
+        self.backed_up.store(false, Ordering::SeqCst)
+    }
+
+    /// Was the room key of this session imported, either from a key export,
+    /// from a key backup or by being forwarded to us, instead of being sent
+    /// to us by the device that created the session.
+    pub fn has_been_imported(&self) -> bool {
+        *self.imported
+    }
+
+    /// Was the room key of this session forwarded to us by another device.
+    pub fn is_forwarded(&self) -> bool {
+        self.source == RoomKeySource::Forwarded || !self.forwarding_chains.is_empty()
+    }
+
     /// The room where this session is used in.
     pub fn room_id(&self) -> &RoomId {
         &self.room_id
@@ -295,12 +435,9 @@ impl InboundGroupSession {
         &self,
         event: &SyncMessageEvent<EncryptedEventContent>,
     ) -> MegolmResult<(Raw<AnySyncRoomEvent>, u32)> {
-        let content = match &event.content.scheme {
-            EncryptedEventScheme::MegolmV1AesSha2(c) => c,
-            _ => return Err(EventError::UnsupportedAlgorithm.into()),
-        };
+        let content = GroupEncryptedContent::try_from(&event.content.scheme)?;
 
-        let (plaintext, message_index) = self.decrypt_helper(content.ciphertext.clone()).await?;
+        let (plaintext, message_index) = self.decrypt_helper(content.ciphertext.to_owned()).await?;
 
         let mut decrypted_value = serde_json::from_str::<Value>(&plaintext)?;
         let decrypted_object = decrypted_value.as_object_mut().ok_or(EventError::NotAnObject)?;
@@ -367,8 +504,22 @@ pub struct PickledInboundGroupSession {
     /// Flag remembering if the session was directly sent to us by the sender
     /// or if it was imported.
     pub imported: bool,
+    /// The way we received the room key of the session.
+    #[serde(default)]
+    pub source: Option<RoomKeySource>,
+    /// Flag remembering if the session has been uploaded to the server-side
+    /// key backup.
+    #[serde(default)]
+    pub backed_up: bool,
+    /// The version of the server-side key backup the session was uploaded to.
+    #[serde(default)]
+    pub backup_version: Option<String>,
     /// History visibility of the room when the session was created.
     pub history_visibility: Option<HistoryVisibility>,
+    /// Flag remembering if the session was created while the history of the
+    /// room was visible to users that join later on.
+    #[serde(default)]
+    pub shared_history: bool,
 }
 
 /// The typed representation of a base64 encoded string of the GroupSession
@@ -401,11 +552,15 @@ impl TryFrom<ExportedRoomKey> for InboundGroupSession {
             session_id: key.session_id.into(),
             sender_key: key.sender_key.into(),
             history_visibility: None.into(),
+            shared_history: key.shared_history,
             first_known_index,
             signing_keys: Arc::new(key.sender_claimed_keys),
             room_id: Arc::new(key.room_id),
             forwarding_chains: Arc::new(key.forwarding_curve25519_key_chain),
             imported: Arc::new(true),
+            source: RoomKeySource::FileImport,
+            backed_up: Arc::new(AtomicBool::new(false)),
+            backup_version: Arc::new(StdRwLock::new(None)),
         })
     }
 }
@@ -12,24 +12,100 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::BTreeMap, convert::TryInto};
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+};
 
 use ruma::{
-    events::forwarded_room_key::{
-        ForwardedRoomKeyToDeviceEventContent, ForwardedRoomKeyToDeviceEventContentInit,
+    events::{
+        forwarded_room_key::{
+            ForwardedRoomKeyToDeviceEventContent, ForwardedRoomKeyToDeviceEventContentInit,
+        },
+        room::{encrypted::EncryptedEventScheme, history_visibility::HistoryVisibility},
+        AnyToDeviceEvent,
     },
-    DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId,
+    serde::Raw,
+    DeviceId, DeviceKeyAlgorithm, EventEncryptionAlgorithm, RoomId,
 };
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use zeroize::Zeroize;
 
 mod inbound;
 mod outbound;
+mod room_key_bundle;
+mod sharing_history;
+mod withheld;
 
-pub use inbound::{InboundGroupSession, InboundGroupSessionPickle, PickledInboundGroupSession};
+pub use inbound::{
+    InboundGroupSession, InboundGroupSessionPickle, PickledInboundGroupSession, RoomKeySource,
+};
 pub use outbound::{
     EncryptionSettings, OutboundGroupSession, PickledOutboundGroupSession, ShareState,
 };
+pub use room_key_bundle::StoredRoomKeyBundleData;
+pub use sharing_history::{ShareDecision, SharingHistoryEntry, WithheldReason};
+pub(crate) use withheld::{RoomKeyWithheldEvent, ROOM_KEY_WITHHELD_EVENT_TYPE};
+pub use withheld::{RoomKeyWithheldInfo, WithheldCode};
+
+use crate::error::EventError;
+
+/// The field of room key contents and exports that marks a session as one that
+/// can be shared with users that join the room later on, as proposed in
+/// MSC3061.
+pub(crate) const SHARED_HISTORY_FIELD: &str = "org.matrix.msc3061.shared_history";
+
+/// Can users that join a room with the given history visibility later on see
+/// the messages that were sent before they joined.
+pub(crate) fn is_shared_history(history_visibility: &HistoryVisibility) -> bool {
+    matches!(history_visibility, HistoryVisibility::Shared | HistoryVisibility::WorldReadable)
+}
+
+/// Does the content of the given room key event mark the session as one with
+/// shared history.
+pub(crate) fn has_shared_history_flag(event: &Raw<AnyToDeviceEvent>) -> bool {
+    serde_json::from_str::<Value>(event.json().get())
+        .ok()
+        .and_then(|e| e.get("content")?.get(SHARED_HISTORY_FIELD)?.as_bool())
+        .unwrap_or(false)
+}
+
+/// Is the given room encryption algorithm one that we can encrypt and decrypt
+/// room events with.
+pub(crate) fn is_supported_room_algorithm(algorithm: &EventEncryptionAlgorithm) -> bool {
+    matches!(algorithm, EventEncryptionAlgorithm::MegolmV1AesSha2)
+}
+
+/// The parts of a room event, encrypted using a group session, that are needed
+/// to find the group session and to decrypt the event.
+///
+/// Supporting a new room encryption algorithm only requires a new arm in the
+/// `TryFrom` implementation, the decryption code works with this type instead
+/// of matching on the scheme of the event.
+#[derive(Debug)]
+pub(crate) struct GroupEncryptedContent<'a> {
+    pub sender_key: &'a str,
+    pub session_id: &'a str,
+    pub device_id: &'a DeviceId,
+    pub ciphertext: &'a str,
+}
+
+impl<'a> TryFrom<&'a EncryptedEventScheme> for GroupEncryptedContent<'a> {
+    type Error = EventError;
+
+    fn try_from(scheme: &'a EncryptedEventScheme) -> Result<Self, Self::Error> {
+        match scheme {
+            EncryptedEventScheme::MegolmV1AesSha2(c) => Ok(Self {
+                sender_key: &c.sender_key,
+                session_id: &c.session_id,
+                device_id: &c.device_id,
+                ciphertext: &c.ciphertext,
+            }),
+            _ => Err(EventError::UnsupportedAlgorithm),
+        }
+    }
+}
 
 /// The private session key of a group session.
 /// Can be used to create a new inbound group session.
@@ -69,6 +145,11 @@ pub struct ExportedRoomKey {
     /// Chain of Curve25519 keys through which this session was forwarded, via
     /// m.forwarded_room_key events.
     pub forwarding_curve25519_key_chain: Vec<String>,
+
+    /// Was the session created while the history of the room was visible to
+    /// users that join later on.
+    #[serde(default, rename = "org.matrix.msc3061.shared_history")]
+    pub shared_history: bool,
 }
 
 impl TryInto<ForwardedRoomKeyToDeviceEventContent> for ExportedRoomKey {
@@ -119,24 +200,48 @@ impl From<ForwardedRoomKeyToDeviceEventContent> for ExportedRoomKey {
             sender_claimed_keys,
             sender_key: forwarded_key.sender_key,
             session_key: ExportedGroupSessionKey(forwarded_key.session_key),
+            shared_history: false,
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{
-        sync::Arc,
-        time::{Duration, Instant},
-    };
+    use std::sync::Arc;
 
+    use matrix_sdk_common::{instant::Duration, time::time_ago};
     use ruma::{
-        events::{room::message::MessageEventContent, AnyMessageEventContent},
-        room_id, user_id,
+        events::{
+            room::{history_visibility::HistoryVisibility, message::MessageEventContent},
+            AnyMessageEventContent,
+        },
+        room_id, user_id, EventEncryptionAlgorithm,
     };
 
-    use super::EncryptionSettings;
-    use crate::ReadOnlyAccount;
+    use super::{EncryptionSettings, ExportedRoomKey, InboundGroupSession, SHARED_HISTORY_FIELD};
+    use crate::{MegolmError, ReadOnlyAccount};
+
+    #[tokio::test]
+    async fn unsupported_algorithm() {
+        let settings = EncryptionSettings {
+            algorithm: EventEncryptionAlgorithm::OlmV1Curve25519AesSha2,
+            ..Default::default()
+        };
+
+        assert!(EncryptionSettings::default().check_algorithm().is_ok());
+        assert!(matches!(
+            settings.check_algorithm(),
+            Err(MegolmError::UnsupportedAlgorithm(
+                EventEncryptionAlgorithm::OlmV1Curve25519AesSha2
+            ))
+        ));
+
+        let account = ReadOnlyAccount::new(&user_id!("@alice:example.org"), "DEVICEID".into());
+        assert!(account
+            .create_group_session_pair(&room_id!("!test_room:example.org"), settings)
+            .await
+            .is_err());
+    }
 
     #[tokio::test]
     #[cfg(target_os = "linux")]
@@ -168,7 +273,38 @@ mod test {
             .unwrap();
 
         assert!(!session.expired());
-        session.creation_time = Arc::new(Instant::now() - Duration::from_secs(60 * 60));
+        session.creation_time = Arc::new(time_ago(Duration::from_secs(60 * 60)));
         assert!(session.expired());
     }
+
+    #[tokio::test]
+    async fn shared_history() {
+        let account = ReadOnlyAccount::new(&user_id!("@alice:example.org"), "DEVICEID".into());
+        let room_id = room_id!("!test_room:example.org");
+
+        let (outbound, inbound) = account
+            .create_group_session_pair(&room_id, EncryptionSettings::default())
+            .await
+            .unwrap();
+        assert!(inbound.shared_history());
+        assert_eq!(outbound.as_json().await[SHARED_HISTORY_FIELD], true);
+
+        // The flag survives an export and import.
+        let export = serde_json::to_value(inbound.export().await).unwrap();
+        assert_eq!(export[SHARED_HISTORY_FIELD], true);
+        let imported = InboundGroupSession::from_export(
+            serde_json::from_value::<ExportedRoomKey>(export).unwrap(),
+        )
+        .unwrap();
+        assert!(imported.shared_history());
+
+        let settings = EncryptionSettings {
+            history_visibility: HistoryVisibility::Joined,
+            ..Default::default()
+        };
+        let (outbound, inbound) =
+            account.create_group_session_pair(&room_id, settings).await.unwrap();
+        assert!(!inbound.shared_history());
+        assert_eq!(outbound.as_json().await[SHARED_HISTORY_FIELD], false);
+    }
 }
@@ -24,7 +24,7 @@ use std::{
 };
 
 use dashmap::DashMap;
-use matrix_sdk_common::{instant::Instant, locks::Mutex, uuid::Uuid};
+use matrix_sdk_common::{locks::Mutex, time::elapsed_since, uuid::Uuid};
 pub use olm_rs::{
     account::IdentityKeys,
     session::{OlmMessage, PreKeyMessage},
@@ -44,17 +44,21 @@ use ruma::{
         },
         AnyMessageEventContent, EventContent,
     },
-    DeviceId, DeviceIdBox, EventEncryptionAlgorithm, RoomId, UserId,
+    DeviceId, DeviceIdBox, EventEncryptionAlgorithm, MilliSecondsSinceUnixEpoch, RoomId, UserId,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use tracing::{debug, error, trace};
 
 use super::{
-    super::{deserialize_instant, serialize_instant},
-    GroupSessionKey,
+    super::deserialize_timestamp, is_shared_history, is_supported_room_algorithm, GroupSessionKey,
+    SHARED_HISTORY_FIELD,
+};
+use crate::{
+    error::{MegolmError, MegolmResult},
+    utilities::log_id,
+    ToDeviceRequest,
 };
-use crate::ToDeviceRequest;
 
 const ROTATION_PERIOD: Duration = Duration::from_millis(604800000);
 const ROTATION_MESSAGES: u64 = 100;
@@ -106,6 +110,18 @@ impl EncryptionSettings {
             history_visibility,
         }
     }
+
+    /// Check if the configured encryption algorithm is supported.
+    ///
+    /// Returns an `UnsupportedAlgorithm` error containing the configured
+    /// algorithm if it isn't.
+    pub fn check_algorithm(&self) -> MegolmResult<()> {
+        if is_supported_room_algorithm(&self.algorithm) {
+            Ok(())
+        } else {
+            Err(MegolmError::UnsupportedAlgorithm(self.algorithm.clone()))
+        }
+    }
 }
 
 /// Outbound group session.
@@ -120,7 +136,7 @@ pub struct OutboundGroupSession {
     account_identity_keys: Arc<IdentityKeys>,
     session_id: Arc<str>,
     room_id: Arc<RoomId>,
-    pub(crate) creation_time: Arc<Instant>,
+    pub(crate) creation_time: Arc<MilliSecondsSinceUnixEpoch>,
     message_count: Arc<AtomicU64>,
     shared: Arc<AtomicBool>,
     invalidated: Arc<AtomicBool>,
@@ -160,7 +176,7 @@ impl OutboundGroupSession {
             device_id,
             account_identity_keys: identity_keys,
             session_id: session_id.into(),
-            creation_time: Arc::new(Instant::now()),
+            creation_time: Arc::new(MilliSecondsSinceUnixEpoch::now()),
             message_count: Arc::new(AtomicU64::new(0)),
             shared: Arc::new(AtomicBool::new(false)),
             invalidated: Arc::new(AtomicBool::new(false)),
@@ -184,6 +200,12 @@ impl OutboundGroupSession {
         self.invalidated.store(true, Ordering::Relaxed)
     }
 
+    /// Restore the invalidation state of the session, e.g. if invalidating
+    /// the session couldn't be persisted.
+    pub(crate) fn restore_invalidation(&self, invalidated: bool) {
+        self.invalidated.store(invalidated, Ordering::Relaxed)
+    }
+
     /// Get the encryption settings of this outbound session.
     pub fn settings(&self) -> &EncryptionSettings {
         &self.settings
@@ -219,8 +241,8 @@ impl OutboundGroupSession {
 
             if self.to_share_with_set.is_empty() {
                 debug!(
-                    session_id = self.session_id(),
-                    room_id = self.room_id.as_str(),
+                    session_id = %log_id(self.session_id()),
+                    room_id = %log_id(self.room_id.as_str()),
                     "All m.room_key to-device requests were sent out, marking \
                         session as shared.",
                 );
@@ -269,7 +291,8 @@ impl OutboundGroupSession {
     ///
     /// # Panics
     ///
-    /// Panics if the content can't be serialized.
+    /// Panics if the content can't be serialized or if the session was created
+    /// with an unsupported encryption algorithm.
     pub async fn encrypt(&self, content: AnyMessageEventContent) -> EncryptedEventContent {
         let json_content = json!({
             "content": content,
@@ -287,18 +310,22 @@ impl OutboundGroupSession {
 
         let ciphertext = self.encrypt_helper(plaintext).await;
 
-        let encrypted_content = MegolmV1AesSha2ContentInit {
-            ciphertext,
-            sender_key: self.account_identity_keys.curve25519().to_owned(),
-            session_id: self.session_id().to_owned(),
-            device_id: (&*self.device_id).to_owned(),
-        }
-        .into();
+        let scheme = match self.settings.algorithm {
+            EventEncryptionAlgorithm::MegolmV1AesSha2 => EncryptedEventScheme::MegolmV1AesSha2(
+                MegolmV1AesSha2ContentInit {
+                    ciphertext,
+                    sender_key: self.account_identity_keys.curve25519().to_owned(),
+                    session_id: self.session_id().to_owned(),
+                    device_id: (&*self.device_id).to_owned(),
+                }
+                .into(),
+            ),
+            // Sessions are only created for supported algorithms, see
+            // `EncryptionSettings::check_algorithm()`.
+            _ => unreachable!("Outbound group session with an unsupported algorithm"),
+        };
 
-        EncryptedEventContent::new(
-            EncryptedEventScheme::MegolmV1AesSha2(encrypted_content),
-            relates_to,
-        )
+        EncryptedEventContent::new(scheme, relates_to)
     }
 
     /// Check if the session has expired and if it should be rotated.
@@ -309,7 +336,7 @@ impl OutboundGroupSession {
         let count = self.message_count.load(Ordering::SeqCst);
 
         count >= self.settings.rotation_period_msgs
-            || self.creation_time.elapsed()
+            || elapsed_since(*self.creation_time)
                 // Since the encryption settings are provided by users and not
                 // checked someone could set a really low rotation period so
                 // clamp it to an hour.
@@ -370,6 +397,7 @@ impl OutboundGroupSession {
             "session_id": &*self.session_id,
             "session_key": self.session_key().await,
             "chain_index": self.message_index().await,
+            SHARED_HISTORY_FIELD: is_shared_history(&self.settings.history_visibility),
         })
     }
 
@@ -410,6 +438,15 @@ impl OutboundGroupSession {
         }
     }
 
+    /// Has or will the session be shared with any device of the given user.
+    pub(crate) fn is_shared_with_user(&self, user_id: &UserId) -> bool {
+        self.shared_with_set.get(user_id).map(|d| !d.is_empty()).unwrap_or(false)
+            || self
+                .to_share_with_set
+                .iter()
+                .any(|item| item.value().0.messages.contains_key(user_id))
+    }
+
     /// Mark that the session was shared with the given user/device pair.
     #[cfg(test)]
     pub fn mark_shared_with(&self, user_id: &UserId, device_id: &DeviceId) {
@@ -552,8 +589,8 @@ pub struct PickledOutboundGroupSession {
     /// The room id this session is used for.
     pub room_id: Arc<RoomId>,
     /// The timestamp when this session was created.
-    #[serde(deserialize_with = "deserialize_instant", serialize_with = "serialize_instant")]
-    pub creation_time: Instant,
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub creation_time: MilliSecondsSinceUnixEpoch,
     /// The number of messages this session has already encrypted.
     pub message_count: u64,
     /// Is the session shared.
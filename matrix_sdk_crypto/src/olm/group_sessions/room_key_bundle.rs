@@ -0,0 +1,67 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ruma::{MilliSecondsSinceUnixEpoch, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Information about a bundle of room keys that another user shared with us
+/// when they invited us to a room.
+///
+/// The bundle gives us access to the history of the room that was shared with
+/// the inviter. It's kept around until we actually join the room, only then
+/// should the room keys it contains be imported.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredRoomKeyBundleData {
+    /// The user that sent us the bundle.
+    pub sender_user: UserId,
+    /// The Curve25519 key of the device that sent us the bundle.
+    pub sender_key: String,
+    /// The room the bundle contains room keys for.
+    pub room_id: RoomId,
+    /// The time at which we received the bundle.
+    pub received_at: MilliSecondsSinceUnixEpoch,
+    /// The bundle itself, e.g. the location of the encrypted file that
+    /// contains the room keys and the key to decrypt it.
+    pub bundle_data: Value,
+}
+
+impl StoredRoomKeyBundleData {
+    /// Create a new entry for a bundle that was received just now.
+    ///
+    /// # Arguments
+    ///
+    /// * `sender_user` - The user that sent us the bundle.
+    ///
+    /// * `sender_key` - The Curve25519 key of the device that sent us the
+    /// bundle.
+    ///
+    /// * `room_id` - The room the bundle contains room keys for.
+    ///
+    /// * `bundle_data` - The content of the bundle.
+    pub fn new(
+        sender_user: &UserId,
+        sender_key: &str,
+        room_id: &RoomId,
+        bundle_data: Value,
+    ) -> Self {
+        Self {
+            sender_user: sender_user.clone(),
+            sender_key: sender_key.to_owned(),
+            room_id: room_id.clone(),
+            received_at: MilliSecondsSinceUnixEpoch::now(),
+            bundle_data,
+        }
+    }
+}
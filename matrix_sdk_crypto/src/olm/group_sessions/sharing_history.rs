@@ -0,0 +1,91 @@
+// Copyright 2020 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ruma::{DeviceId, DeviceIdBox, MilliSecondsSinceUnixEpoch, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+
+/// The reason why a room key was not shared with a device.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WithheldReason {
+    /// The device was blacklisted.
+    Blacklisted,
+    /// We didn't share an Olm session with the device and couldn't establish
+    /// one, the device didn't have any one-time keys left.
+    NoOlmSession,
+    /// The device requested the room key but we refused to forward it, the
+    /// string contains the reason why we refused.
+    KeyRequestRefused(String),
+}
+
+/// The decision that was taken when sharing a room key with a device.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ShareDecision {
+    /// The room key was shared with the device when we started to use the
+    /// session in a room.
+    Shared {
+        /// The message index at which the room key was shared.
+        message_index: u32,
+    },
+    /// The room key was not shared with the device.
+    Withheld(WithheldReason),
+    /// The room key was forwarded to the device as an answer to a room key
+    /// request.
+    Forwarded {
+        /// The id of the room key request that was answered.
+        request_id: String,
+        /// The message index at which the room key was forwarded, if `None`
+        /// the room key was forwarded at the earliest known index.
+        message_index: Option<u32>,
+    },
+}
+
+/// An entry in the sharing history of a group session.
+///
+/// The sharing history records for every device that was considered as a
+/// recipient of a group session if the device received the room key, and if
+/// not, why it didn't.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharingHistoryEntry {
+    /// The id of the group session.
+    pub session_id: String,
+    /// The room the group session belongs to.
+    pub room_id: RoomId,
+    /// The owner of the device the decision was taken for.
+    pub user_id: UserId,
+    /// The id of the device the decision was taken for.
+    pub device_id: DeviceIdBox,
+    /// The decision that was taken.
+    pub decision: ShareDecision,
+    /// The time at which the decision was taken.
+    pub timestamp: MilliSecondsSinceUnixEpoch,
+}
+
+impl SharingHistoryEntry {
+    pub(crate) fn new(
+        session_id: &str,
+        room_id: &RoomId,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        decision: ShareDecision,
+    ) -> Self {
+        Self {
+            session_id: session_id.to_owned(),
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+            device_id: device_id.into(),
+            decision,
+            timestamp: MilliSecondsSinceUnixEpoch::now(),
+        }
+    }
+}
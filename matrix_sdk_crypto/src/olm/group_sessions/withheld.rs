@@ -0,0 +1,152 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use ruma::{EventEncryptionAlgorithm, RoomId, UserId};
+use serde::{Deserialize, Serialize};
+
+/// The event type of the to-device event that tells us that a room key was
+/// withheld from us.
+pub(crate) const ROOM_KEY_WITHHELD_EVENT_TYPE: &str = "m.room_key.withheld";
+
+/// The reason why the sender of a room key withheld it from us.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum WithheldCode {
+    /// The sender blacklisted our device.
+    Blacklisted,
+    /// The sender only shares room keys with verified devices and our device
+    /// isn't verified.
+    Unverified,
+    /// We aren't authorised to receive the room key, e.g. we requested a room
+    /// key for a message that was sent before we joined the room.
+    Unauthorised,
+    /// The sender doesn't have the room key we requested.
+    Unavailable,
+    /// The sender couldn't establish an Olm session with our device.
+    NoOlm,
+    /// A code we don't know about.
+    Custom(String),
+}
+
+impl WithheldCode {
+    /// Get the string representation of the code, as it's used in the
+    /// `m.room_key.withheld` event.
+    pub fn as_str(&self) -> &str {
+        match self {
+            WithheldCode::Blacklisted => "m.blacklisted",
+            WithheldCode::Unverified => "m.unverified",
+            WithheldCode::Unauthorised => "m.unauthorised",
+            WithheldCode::Unavailable => "m.unavailable",
+            WithheldCode::NoOlm => "m.no_olm",
+            WithheldCode::Custom(c) => c,
+        }
+    }
+}
+
+impl fmt::Display for WithheldCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            WithheldCode::Blacklisted => "The sender has blocked you.",
+            WithheldCode::Unverified => "The sender has disabled encrypting to unverified devices.",
+            WithheldCode::Unauthorised => "You are not authorised to read the message.",
+            WithheldCode::Unavailable => "The requested key was not found.",
+            WithheldCode::NoOlm => "Unable to establish a secure channel.",
+            WithheldCode::Custom(c) => c,
+        };
+
+        f.write_str(message)
+    }
+}
+
+impl From<String> for WithheldCode {
+    fn from(code: String) -> Self {
+        match code.as_str() {
+            "m.blacklisted" => WithheldCode::Blacklisted,
+            "m.unverified" => WithheldCode::Unverified,
+            "m.unauthorised" => WithheldCode::Unauthorised,
+            "m.unavailable" => WithheldCode::Unavailable,
+            "m.no_olm" => WithheldCode::NoOlm,
+            _ => WithheldCode::Custom(code),
+        }
+    }
+}
+
+impl From<WithheldCode> for String {
+    fn from(code: WithheldCode) -> Self {
+        code.as_str().to_owned()
+    }
+}
+
+/// The content of a `m.room_key.withheld` to-device event.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct RoomKeyWithheldContent {
+    pub algorithm: EventEncryptionAlgorithm,
+    pub room_id: Option<RoomId>,
+    pub session_id: Option<String>,
+    pub sender_key: String,
+    pub code: WithheldCode,
+    pub reason: Option<String>,
+}
+
+/// A `m.room_key.withheld` to-device event.
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct RoomKeyWithheldEvent {
+    pub sender: UserId,
+    pub content: RoomKeyWithheldContent,
+}
+
+/// Information about a room key that the sender withheld from us.
+///
+/// This is kept around so we can tell the user why a message can't be
+/// decrypted instead of only telling them that the room key is missing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoomKeyWithheldInfo {
+    /// The user that withheld the room key.
+    pub sender: UserId,
+    /// The Curve25519 key of the device that withheld the room key.
+    pub sender_key: String,
+    /// The room the room key belongs to.
+    pub room_id: RoomId,
+    /// The unique id of the group session the room key belongs to.
+    pub session_id: String,
+    /// The encryption algorithm of the group session.
+    pub algorithm: EventEncryptionAlgorithm,
+    /// Why the room key was withheld.
+    pub code: WithheldCode,
+    /// A human readable reason why the room key was withheld.
+    pub reason: Option<String>,
+}
+
+impl RoomKeyWithheldInfo {
+    /// Create the withheld info out of a `m.room_key.withheld` event.
+    ///
+    /// Returns `None` if the event doesn't mention a specific group session,
+    /// e.g. if it's a `m.no_olm` notice that applies to all the sessions of
+    /// the sender.
+    pub(crate) fn from_event(event: RoomKeyWithheldEvent) -> Option<Self> {
+        let content = event.content;
+
+        Some(Self {
+            sender: event.sender,
+            sender_key: content.sender_key,
+            room_id: content.room_id?,
+            session_id: content.session_id?,
+            algorithm: content.algorithm,
+            code: content.code,
+            reason: content.reason,
+        })
+    }
+}
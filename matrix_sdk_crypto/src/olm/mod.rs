@@ -24,37 +24,50 @@ mod signing;
 mod utility;
 
 pub(crate) use account::{Account, OlmDecryptionInfo, SessionType};
-pub use account::{AccountPickle, OlmMessageHash, PickledAccount, ReadOnlyAccount};
+pub use account::{
+    AccountPickle, KeysUploadDiagnostics, KeysUploadFailure, OlmMessageHash, PickledAccount,
+    ReadOnlyAccount,
+};
+pub(crate) use group_sessions::{
+    has_shared_history_flag, is_supported_room_algorithm, GroupEncryptedContent, GroupSessionKey,
+    RoomKeyWithheldEvent, ShareState, ROOM_KEY_WITHHELD_EVENT_TYPE, SHARED_HISTORY_FIELD,
+};
 pub use group_sessions::{
-    EncryptionSettings, ExportedRoomKey, InboundGroupSession, InboundGroupSessionPickle,
-    OutboundGroupSession, PickledInboundGroupSession, PickledOutboundGroupSession,
+    EncryptionSettings, ExportedGroupSessionKey, ExportedRoomKey, InboundGroupSession,
+    InboundGroupSessionPickle, OutboundGroupSession, PickledInboundGroupSession,
+    PickledOutboundGroupSession, RoomKeySource, RoomKeyWithheldInfo, ShareDecision,
+    SharingHistoryEntry, StoredRoomKeyBundleData, WithheldCode, WithheldReason,
 };
-pub(crate) use group_sessions::{GroupSessionKey, ShareState};
-use matrix_sdk_common::instant::{Duration, Instant};
+use matrix_sdk_common::{instant::Duration, time::time_ago};
 pub use olm_rs::{account::IdentityKeys, PicklingMode};
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use ruma::MilliSecondsSinceUnixEpoch;
+use serde::{Deserialize, Deserializer};
 pub use session::{PickledSession, Session, SessionPickle};
-pub use signing::{PickledCrossSigningIdentity, PrivateCrossSigningIdentity};
+pub use signing::{CrossSigningReset, PickledCrossSigningIdentity, PrivateCrossSigningIdentity};
 pub(crate) use utility::Utility;
 
-pub(crate) fn serialize_instant<S>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    let duration = instant.elapsed();
-    duration.serialize(serializer)
-}
-
-pub(crate) fn deserialize_instant<'de, D>(deserializer: D) -> Result<Instant, D::Error>
+/// Deserialize a timestamp of a pickle.
+///
+/// Older pickles stored the time that passed between the timestamp and the
+/// moment the pickle was created instead of a wall-clock time, such values
+/// are converted as if the pickle was created just now.
+pub(crate) fn deserialize_timestamp<'de, D>(
+    deserializer: D,
+) -> Result<MilliSecondsSinceUnixEpoch, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let duration = Duration::deserialize(deserializer)?;
-    let now = Instant::now();
-    let instant = now
-        .checked_sub(duration)
-        .ok_or_else(|| serde::de::Error::custom("Can't subtract the current instant"))?;
-    Ok(instant)
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        WallClock(MilliSecondsSinceUnixEpoch),
+        Elapsed(Duration),
+    }
+
+    Ok(match Timestamp::deserialize(deserializer)? {
+        Timestamp::WallClock(timestamp) => timestamp,
+        Timestamp::Elapsed(elapsed) => time_ago(elapsed),
+    })
 }
 
 #[cfg(test)]
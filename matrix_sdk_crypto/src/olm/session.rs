@@ -14,7 +14,7 @@
 
 use std::{collections::BTreeMap, fmt, sync::Arc};
 
-use matrix_sdk_common::{instant::Instant, locks::Mutex};
+use matrix_sdk_common::locks::Mutex;
 use olm_rs::{errors::OlmSessionError, session::OlmSession, PicklingMode};
 pub use olm_rs::{
     session::{OlmMessage, PreKeyMessage},
@@ -29,11 +29,12 @@ use ruma::{
         EventType,
     },
     identifiers::{DeviceId, DeviceKeyAlgorithm, UserId},
+    MilliSecondsSinceUnixEpoch,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use super::{deserialize_instant, serialize_instant, IdentityKeys};
+use super::{deserialize_timestamp, IdentityKeys};
 use crate::{
     error::{EventError, OlmResult, SessionUnpicklingError},
     ReadOnlyDevice,
@@ -49,8 +50,8 @@ pub struct Session {
     pub(crate) inner: Arc<Mutex<OlmSession>>,
     pub(crate) session_id: Arc<str>,
     pub(crate) sender_key: Arc<str>,
-    pub(crate) creation_time: Arc<Instant>,
-    pub(crate) last_use_time: Arc<Instant>,
+    pub(crate) creation_time: Arc<MilliSecondsSinceUnixEpoch>,
+    pub(crate) last_use_time: Arc<MilliSecondsSinceUnixEpoch>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -74,7 +75,7 @@ impl Session {
     /// * `message` - The Olm message that should be decrypted.
     pub async fn decrypt(&mut self, message: OlmMessage) -> Result<String, OlmSessionError> {
         let plaintext = self.inner.lock().await.decrypt(message)?;
-        self.last_use_time = Arc::new(Instant::now());
+        self.last_use_time = Arc::new(MilliSecondsSinceUnixEpoch::now());
         Ok(plaintext)
     }
 
@@ -92,7 +93,7 @@ impl Session {
     /// * `plaintext` - The plaintext that should be encrypted.
     pub(crate) async fn encrypt_helper(&mut self, plaintext: &str) -> OlmMessage {
         let message = self.inner.lock().await.encrypt(plaintext);
-        self.last_use_time = Arc::new(Instant::now());
+        self.last_use_time = Arc::new(MilliSecondsSinceUnixEpoch::now());
         message
     }
 
@@ -250,12 +251,12 @@ pub struct PickledSession {
     pub pickle: SessionPickle,
     /// The curve25519 key of the other user that we share this session with.
     pub sender_key: String,
-    /// The relative time elapsed since the session was created.
-    #[serde(deserialize_with = "deserialize_instant", serialize_with = "serialize_instant")]
-    pub creation_time: Instant,
-    /// The relative time elapsed since the session was last used.
-    #[serde(deserialize_with = "deserialize_instant", serialize_with = "serialize_instant")]
-    pub last_use_time: Instant,
+    /// The time the session was created.
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub creation_time: MilliSecondsSinceUnixEpoch,
+    /// The time the session was last used.
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub last_use_time: MilliSecondsSinceUnixEpoch,
 }
 
 /// The typed representation of a base64 encoded string of the Olm Session
@@ -22,7 +22,7 @@ use std::{
     },
 };
 
-use matrix_sdk_common::locks::Mutex;
+use matrix_sdk_common::{locks::Mutex, uuid::Uuid};
 use pk_signing::{MasterSigning, PickledSignings, SelfSigning, Signing, SigningError, UserSigning};
 use ruma::{
     api::client::r0::keys::{upload_signatures::Request as SignatureUploadRequest, KeyUsage},
@@ -30,13 +30,47 @@ use ruma::{
     DeviceKeyAlgorithm, DeviceKeyId, UserId,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::Error as JsonError;
+use serde_json::{Error as JsonError, Value};
+use zeroize::Zeroizing;
 
 use crate::{
-    error::SignatureError, requests::UploadSigningKeysRequest, OwnUserIdentity, ReadOnlyAccount,
-    ReadOnlyDevice, UserIdentity,
+    error::SignatureError,
+    identities::MasterPubkey,
+    requests::UploadSigningKeysRequest,
+    secret_storage::{
+        SecretImportError, MASTER_KEY_SECRET_NAME, SELF_SIGNING_KEY_SECRET_NAME,
+        USER_SIGNING_KEY_SECRET_NAME,
+    },
+    utilities::decode,
+    OwnUserIdentity, ReadOnlyAccount, ReadOnlyDevice, UserIdentity,
 };
 
+/// The size of the private key of an ed25519 cross signing key.
+const SEED_SIZE: usize = 32;
+
+/// The requests and data that are needed to replace our cross signing
+/// identity with a new one, see [`OlmMachine::reset_cross_signing()`].
+///
+/// [`OlmMachine::reset_cross_signing()`]: crate::OlmMachine::reset_cross_signing
+#[derive(Debug)]
+pub struct CrossSigningReset {
+    /// The unique id of the `upload_signing_keys_request`, needs to be passed
+    /// to `mark_request_as_sent()` together with its response.
+    pub request_id: Uuid,
+    /// The request that uploads the public keys of the new identity, this
+    /// request requires user interactive auth.
+    pub upload_signing_keys_request: UploadSigningKeysRequest,
+    /// The request that uploads the signature of our own device, made by the
+    /// new self-signing key.
+    pub upload_signatures_request: SignatureUploadRequest,
+    /// The encrypted private keys of the new identity, keyed by the name of
+    /// the secret. Each value should be uploaded as the content of the
+    /// account data event with the secret name as the type.
+    ///
+    /// This is empty if no secret storage key was given.
+    pub secret_storage_content: BTreeMap<String, Value>,
+}
+
 /// Private cross signing identity.
 ///
 /// This object holds the private and public ed25519 key triplet that is used
@@ -102,6 +136,11 @@ impl PrivateCrossSigningIdentity {
         }
     }
 
+    /// Get the public part of our master key, if we have the private part.
+    pub(crate) async fn master_public_key(&self) -> Option<MasterPubkey> {
+        self.master_key.lock().await.as_ref().map(|m| m.public_key.clone())
+    }
+
     pub(crate) async fn as_public_identity(&self) -> Result<OwnUserIdentity, SignatureError> {
         let master = self
             .master_key
@@ -367,6 +406,109 @@ impl PrivateCrossSigningIdentity {
         })
     }
 
+    /// Create an identity from private keys that were exported from another
+    /// device, e.g. ones that were restored from secret storage.
+    ///
+    /// The private keys are checked against the public keys of our published
+    /// identity, a key that doesn't belong to our identity is rejected.
+    ///
+    /// # Arguments
+    ///
+    /// * `public_identity` - Our published cross signing identity.
+    ///
+    /// * `master_key` - The unpadded base64 encoded private master key.
+    ///
+    /// * `self_signing_key` - The unpadded base64 encoded private self-signing
+    /// key.
+    ///
+    /// * `user_signing_key` - The unpadded base64 encoded private user-signing
+    /// key, if we have one.
+    pub(crate) fn from_secrets(
+        public_identity: &OwnUserIdentity,
+        master_key: &str,
+        self_signing_key: &str,
+        user_signing_key: Option<&str>,
+    ) -> Result<Self, SecretImportError> {
+        fn signing(
+            secret_name: &'static str,
+            private_key: &str,
+            public_keys: &BTreeMap<String, String>,
+        ) -> Result<Signing, SecretImportError> {
+            let seed = decode(private_key)
+                .ok()
+                .filter(|s| s.len() == SEED_SIZE)
+                .ok_or(SecretImportError::InvalidPrivateKey(secret_name))?;
+
+            let signing = Signing::from_seed(seed);
+
+            if public_keys.values().any(|k| k == signing.public_key().as_str()) {
+                Ok(signing)
+            } else {
+                Err(SecretImportError::MismatchedPublicKey(secret_name))
+            }
+        }
+
+        let master = MasterSigning {
+            inner: signing(
+                MASTER_KEY_SECRET_NAME,
+                master_key,
+                public_identity.master_key().keys(),
+            )?,
+            public_key: public_identity.master_key().clone(),
+        };
+
+        let self_signing = SelfSigning {
+            inner: signing(
+                SELF_SIGNING_KEY_SECRET_NAME,
+                self_signing_key,
+                public_identity.self_signing_key().keys(),
+            )?,
+            public_key: public_identity.self_signing_key().clone(),
+        };
+
+        let user_signing = user_signing_key
+            .map(|k| {
+                Ok(UserSigning {
+                    inner: signing(
+                        USER_SIGNING_KEY_SECRET_NAME,
+                        k,
+                        public_identity.user_signing_key().keys(),
+                    )?,
+                    public_key: public_identity.user_signing_key().clone(),
+                })
+            })
+            .transpose()?;
+
+        Ok(Self {
+            user_id: Arc::new(public_identity.user_id().to_owned()),
+            // The public keys are already published.
+            shared: Arc::new(AtomicBool::new(true)),
+            master_key: Arc::new(Mutex::new(Some(master))),
+            self_signing_key: Arc::new(Mutex::new(Some(self_signing))),
+            user_signing_key: Arc::new(Mutex::new(user_signing)),
+        })
+    }
+
+    /// Export the private keys of this identity, keyed by the name of the
+    /// secret under which they are stored in secret storage.
+    pub(crate) async fn export_secrets(&self) -> BTreeMap<&'static str, Zeroizing<String>> {
+        let mut secrets = BTreeMap::new();
+
+        if let Some(k) = self.master_key.lock().await.as_ref() {
+            secrets.insert(MASTER_KEY_SECRET_NAME, k.inner.export_seed());
+        }
+
+        if let Some(k) = self.self_signing_key.lock().await.as_ref() {
+            secrets.insert(SELF_SIGNING_KEY_SECRET_NAME, k.inner.export_seed());
+        }
+
+        if let Some(k) = self.user_signing_key.lock().await.as_ref() {
+            secrets.insert(USER_SIGNING_KEY_SECRET_NAME, k.inner.export_seed());
+        }
+
+        secrets
+    }
+
     /// Get the upload request that is needed to share the public keys of this
     /// identity.
     pub(crate) async fn as_upload_request(&self) -> UploadSigningKeysRequest {
@@ -348,6 +348,12 @@ impl Signing {
         &self.public_key
     }
 
+    /// Export the private key as unpadded base64, the format that is used to
+    /// store it in secret storage.
+    pub fn export_seed(&self) -> Zeroizing<String> {
+        Zeroizing::new(crate::utilities::encode(self.seed.as_slice()))
+    }
+
     pub fn cross_signing_key(&self, user_id: UserId, usage: KeyUsage) -> CrossSigningKey {
         let mut keys = BTreeMap::new();
 
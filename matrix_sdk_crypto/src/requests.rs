@@ -14,9 +14,14 @@
 
 #![allow(missing_docs)]
 
-use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
 
-use matrix_sdk_common::uuid::Uuid;
+use dashmap::DashMap;
+use matrix_sdk_common::{instant::Instant, time::elapsed_since, uuid::Uuid};
 use ruma::{
     api::client::r0::{
         keys::{
@@ -33,10 +38,12 @@ use ruma::{
         to_device::{send_event_to_device::Response as ToDeviceResponse, DeviceIdOrAllDevices},
     },
     events::{AnyMessageEventContent, EventType},
-    DeviceIdBox, RoomId, UserId,
+    DeviceIdBox, MilliSecondsSinceUnixEpoch, RoomId, UserId,
 };
 use serde::{Deserialize, Serialize};
-use serde_json::value::RawValue as RawJsonValue;
+use serde_json::{value::RawValue as RawJsonValue, Value as JsonValue};
+
+use crate::OlmError;
 
 /// Customized version of
 /// `ruma_client_api::r0::to_device::send_event_to_device::Request`,
@@ -241,12 +248,161 @@ impl<'a> From<&'a KeysClaimResponse> for IncomingResponse<'a> {
     }
 }
 
+impl<'a> From<&'a SigningKeysUploadResponse> for IncomingResponse<'a> {
+    fn from(response: &'a SigningKeysUploadResponse) -> Self {
+        IncomingResponse::SigningKeysUpload(response)
+    }
+}
+
 impl<'a> From<&'a SignatureUploadResponse> for IncomingResponse<'a> {
     fn from(response: &'a SignatureUploadResponse) -> Self {
         IncomingResponse::SignatureUpload(response)
     }
 }
 
+/// The type of an outgoing request, or of the response that belongs to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RequestType {
+    /// A `/keys/upload` request.
+    KeysUpload,
+    /// A `/keys/query` request.
+    KeysQuery,
+    /// A `/keys/claim` request.
+    KeysClaim,
+    /// A `/sendToDevice` request.
+    ToDevice,
+    /// A `/keys/device_signing/upload` request.
+    SigningKeysUpload,
+    /// A `/keys/signatures/upload` request.
+    SignatureUpload,
+    /// A `/rooms/{roomId}/send` request.
+    RoomMessage,
+}
+
+impl OutgoingRequests {
+    /// The type of this request.
+    pub fn request_type(&self) -> RequestType {
+        match self {
+            OutgoingRequests::KeysUpload(_) => RequestType::KeysUpload,
+            OutgoingRequests::KeysQuery(_) => RequestType::KeysQuery,
+            OutgoingRequests::ToDeviceRequest(_) => RequestType::ToDevice,
+            OutgoingRequests::SignatureUpload(_) => RequestType::SignatureUpload,
+            OutgoingRequests::RoomMessage(_) => RequestType::RoomMessage,
+        }
+    }
+}
+
+impl IncomingResponse<'_> {
+    /// The type of the request this response belongs to.
+    pub fn request_type(&self) -> RequestType {
+        match self {
+            IncomingResponse::KeysUpload(_) => RequestType::KeysUpload,
+            IncomingResponse::KeysQuery(_) => RequestType::KeysQuery,
+            IncomingResponse::ToDevice(_) => RequestType::ToDevice,
+            IncomingResponse::KeysClaim(_) => RequestType::KeysClaim,
+            IncomingResponse::SigningKeysUpload(_) => RequestType::SigningKeysUpload,
+            IncomingResponse::SignatureUpload(_) => RequestType::SignatureUpload,
+            IncomingResponse::RoomMessage(_) => RequestType::RoomMessage,
+        }
+    }
+}
+
+/// Keeps track of the requests we handed out and of the responses we already
+/// received, so responses can be checked before they're handled.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RequestTracker {
+    pending: Arc<DashMap<Uuid, (RequestType, Instant)>>,
+    completed: Arc<StdMutex<VecDeque<(Uuid, RequestType, Instant)>>>,
+}
+
+impl RequestTracker {
+    /// How many handled responses are remembered to detect duplicates.
+    const MAX_COMPLETED: usize = 256;
+    /// How long we wait for the response of a request we handed out before we
+    /// forget about the request.
+    const MAX_PENDING_AGE: Duration = Duration::from_secs(60 * 60);
+
+    /// Remember that a request with the given id and type was handed out.
+    pub fn track(&self, request_id: Uuid, request_type: RequestType) {
+        self.pending.insert(request_id, (request_type, Instant::now()));
+    }
+
+    /// Forget the pending requests that didn't get a response for too long.
+    ///
+    /// Key uploads and keys queries are generated anew every time the outgoing
+    /// requests are collected, an older one might still be in flight so they
+    /// are only forgotten once they're too old as well.
+    pub fn evict(&self) {
+        self.pending.retain(|_, (_, handed_out)| handed_out.elapsed() < Self::MAX_PENDING_AGE);
+    }
+
+    /// Check if a response with the given type can be handled for the request
+    /// with the given id and mark it as handled, concurrent calls for the same
+    /// response can't both succeed.
+    ///
+    /// Returns false if the response was already handled. An error is returned
+    /// if we handed out a request of a different type with the given id, or if
+    /// we don't know about a request with the given id.
+    pub fn complete(
+        &self,
+        request_id: &Uuid,
+        response_type: RequestType,
+    ) -> Result<bool, OlmError> {
+        let mut completed = self.completed.lock().unwrap();
+
+        if let Some((_, expected, _)) = completed.iter().find(|(id, ..)| id == request_id) {
+            return if *expected == response_type {
+                Ok(false)
+            } else {
+                Err(OlmError::MismatchedResponse {
+                    request_id: *request_id,
+                    expected: *expected,
+                    received: response_type,
+                })
+            };
+        }
+
+        let handed_out = match self.pending.remove_if(request_id, |_, (t, _)| *t == response_type) {
+            Some((_, (_, handed_out))) => handed_out,
+            None => {
+                return Err(match self.pending.get(request_id).map(|p| p.0) {
+                    Some(expected) => OlmError::MismatchedResponse {
+                        request_id: *request_id,
+                        expected,
+                        received: response_type,
+                    },
+                    None => OlmError::UnknownRequest {
+                        request_id: *request_id,
+                        received: response_type,
+                    },
+                });
+            }
+        };
+
+        if completed.len() >= Self::MAX_COMPLETED {
+            completed.pop_front();
+        }
+
+        completed.push_back((*request_id, response_type, handed_out));
+
+        Ok(true)
+    }
+
+    /// Undo [`complete()`](#method.complete), handling the response failed
+    /// and it may be passed in again.
+    ///
+    /// Only a request that was pending before it got completed is restored.
+    pub fn reopen(&self, request_id: &Uuid) {
+        let mut completed = self.completed.lock().unwrap();
+
+        if let Some(position) = completed.iter().position(|(id, ..)| id == request_id) {
+            if let Some((id, request_type, handed_out)) = completed.remove(position) {
+                self.pending.insert(id, (request_type, handed_out));
+            }
+        }
+    }
+}
+
 /// Outgoing request type, holds the unique ID of the request and the actual
 /// request.
 #[derive(Debug, Clone)]
@@ -270,6 +426,81 @@ impl OutgoingRequest {
     }
 }
 
+/// An outgoing request that is kept in the crypto store until the server
+/// acknowledged it, so it can be sent again after a restart.
+///
+/// Only to-device requests and signature uploads are stored. Key uploads are
+/// created again from the state of the account, and keys queries from the
+/// tracked users that are still marked as dirty.
+///
+/// Requests that the server didn't acknowledge within
+/// [`StoredOutgoingRequest::TTL`] are dropped instead of being sent again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StoredOutgoingRequest {
+    /// The unique id of the request.
+    pub request_id: Uuid,
+    /// The request that should be sent.
+    pub request: StoredRequest,
+    /// The time the request was created at.
+    #[serde(default = "MilliSecondsSinceUnixEpoch::now")]
+    pub created_at: MilliSecondsSinceUnixEpoch,
+}
+
+/// The requests that can be stored as a [`StoredOutgoingRequest`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoredRequest {
+    /// A to-device request, the transaction id of the request makes sending
+    /// it again idempotent.
+    ToDevice(ToDeviceRequest),
+    /// A signature upload request.
+    SignatureUpload {
+        /// The signed keys, grouped by user.
+        signed_keys: BTreeMap<UserId, BTreeMap<String, JsonValue>>,
+    },
+}
+
+impl StoredOutgoingRequest {
+    /// Create a storable copy of the given request, if the request can be
+    /// stored.
+    pub(crate) fn from_request(request: &OutgoingRequest) -> Option<Self> {
+        let stored = match request.request() {
+            OutgoingRequests::ToDeviceRequest(r) => StoredRequest::ToDevice(r.clone()),
+            OutgoingRequests::SignatureUpload(r) => {
+                StoredRequest::SignatureUpload { signed_keys: r.signed_keys.clone() }
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            request_id: request.request_id,
+            request: stored,
+            created_at: MilliSecondsSinceUnixEpoch::now(),
+        })
+    }
+
+    /// How long a request is sent again before we give up on it.
+    pub const TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+    /// Has the request been around for longer than [`Self::TTL`].
+    pub(crate) fn is_expired(&self) -> bool {
+        elapsed_since(self.created_at) > Self::TTL
+    }
+}
+
+impl From<StoredOutgoingRequest> for OutgoingRequest {
+    fn from(r: StoredOutgoingRequest) -> Self {
+        let request = match r.request {
+            StoredRequest::ToDevice(r) => r.into(),
+            StoredRequest::SignatureUpload { signed_keys } => {
+                SignatureUploadRequest::new(signed_keys).into()
+            }
+        };
+
+        Self { request_id: r.request_id, request: Arc::new(request) }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct RoomMessageRequest {
     /// The room to send the event to.
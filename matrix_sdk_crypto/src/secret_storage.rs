@@ -0,0 +1,514 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for secrets that are stored encrypted in the account data of the
+//! user, using the `m.secret_storage.v1.aes-hmac-sha2` algorithm.
+
+use std::convert::{TryFrom, TryInto};
+
+use aes_ctr::{
+    cipher::{NewStreamCipher, SyncStreamCipher},
+    Aes256Ctr,
+};
+use getrandom::getrandom;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use pbkdf2::pbkdf2;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Sha256, Sha512};
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    error::SignatureError,
+    store::CryptoStoreError,
+    utilities::{decode, encode, encode_url_safe, DecodeError},
+};
+
+const KEY_SIZE: usize = 32;
+const IV_SIZE: usize = 16;
+const KEY_ID_SIZE: usize = 24;
+const SALT_SIZE: usize = 24;
+const PBKDF2_ITERATIONS: u32 = 500_000;
+const RECOVERY_KEY_PREFIX: [u8; 2] = [0x8B, 0x01];
+
+/// The name of the only secret storage algorithm we support.
+pub const SECRET_STORAGE_ALGORITHM: &str = "m.secret_storage.v1.aes-hmac-sha2";
+
+/// The name of the secret that holds the private part of our cross signing
+/// master key.
+pub const MASTER_KEY_SECRET_NAME: &str = "m.cross_signing.master";
+
+/// The name of the secret that holds the private part of our cross signing
+/// self-signing key.
+pub const SELF_SIGNING_KEY_SECRET_NAME: &str = "m.cross_signing.self_signing";
+
+/// The name of the secret that holds the private part of our cross signing
+/// user-signing key.
+pub const USER_SIGNING_KEY_SECRET_NAME: &str = "m.cross_signing.user_signing";
+
+/// Error type for the encryption and decryption of secrets.
+#[derive(Debug, Error)]
+pub enum SecretStorageError {
+    /// The recovery key isn't valid, either because of a typo or because it
+    /// isn't a recovery key at all.
+    #[error("The recovery key is invalid")]
+    InvalidRecoveryKey,
+
+    /// The MAC of the encrypted secret doesn't match, the secret was
+    /// encrypted using a different key.
+    #[error("The MAC of the encrypted secret is invalid")]
+    InvalidMac,
+
+    /// The secret isn't encrypted with our secret storage key.
+    #[error("The secret isn't encrypted with the secret storage key {0}")]
+    MissingKey(String),
+
+    /// The encrypted secret isn't valid base64.
+    #[error(transparent)]
+    Decode(#[from] DecodeError),
+
+    /// The encrypted secret isn't valid JSON.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// The decrypted secret isn't valid UTF-8.
+    #[error(transparent)]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+}
+
+/// Error type for the import of our private cross signing keys from secret
+/// storage.
+#[derive(Debug, Error)]
+pub enum SecretImportError {
+    /// The secret couldn't be decrypted.
+    #[error(transparent)]
+    SecretStorage(#[from] SecretStorageError),
+
+    /// A secret that is required to import the keys is missing.
+    #[error("The secret {0} is missing")]
+    MissingSecret(&'static str),
+
+    /// The secret doesn't contain a valid private key.
+    #[error("The secret {0} doesn't contain a valid private key")]
+    InvalidPrivateKey(&'static str),
+
+    /// The private key doesn't belong to the public key of our published
+    /// cross signing identity.
+    #[error("The private key of the secret {0} doesn't match our public cross signing identity")]
+    MismatchedPublicKey(&'static str),
+
+    /// Our public cross signing identity isn't known yet, our own devices and
+    /// identity need to be queried first.
+    #[error("Our public cross signing identity isn't known")]
+    MissingIdentity,
+
+    /// The imported keys failed to sign our device.
+    #[error(transparent)]
+    Signature(#[from] SignatureError),
+
+    /// The imported keys couldn't be stored.
+    #[error(transparent)]
+    Store(#[from] CryptoStoreError),
+}
+
+/// Information on how a secret storage key can be derived from a passphrase.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PassphraseInfo {
+    /// The key derivation algorithm, always `m.pbkdf2`.
+    pub algorithm: String,
+    /// The salt that is used in the key derivation.
+    pub salt: String,
+    /// The number of PBKDF2 iterations.
+    pub iterations: u32,
+}
+
+/// The description of a secret storage key, stored in the
+/// `m.secret_storage.key.<key_id>` account data event.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SecretStorageKeyDescription {
+    /// The algorithm of the key.
+    pub algorithm: String,
+    /// Information on how to derive the key from a passphrase, if the key was
+    /// created from a passphrase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<PassphraseInfo>,
+    /// The IV that was used to calculate the MAC.
+    pub iv: String,
+    /// The MAC of an empty secret, used to check if a key matches the
+    /// description.
+    pub mac: String,
+}
+
+/// A secret that was encrypted using the `m.secret_storage.v1.aes-hmac-sha2`
+/// algorithm.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AesHmacSha2EncryptedData {
+    /// The IV that was used for the encryption.
+    pub iv: String,
+    /// The encrypted secret.
+    pub ciphertext: String,
+    /// The MAC of the ciphertext.
+    pub mac: String,
+}
+
+/// A key that is used to encrypt and decrypt secrets which are stored in the
+/// account data of the user.
+pub struct SecretStorageKey {
+    key_id: String,
+    inner: Box<[u8; KEY_SIZE]>,
+    passphrase: Option<PassphraseInfo>,
+}
+
+impl Drop for SecretStorageKey {
+    fn drop(&mut self) {
+        self.inner.zeroize()
+    }
+}
+
+impl std::fmt::Debug for SecretStorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretStorageKey").field("key_id", &self.key_id).finish()
+    }
+}
+
+impl SecretStorageKey {
+    /// Create a new random secret storage key.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn new() -> Self {
+        let mut inner = Box::new([0u8; KEY_SIZE]);
+        getrandom(&mut inner[..]).expect("Can't generate randomness for a secret storage key");
+
+        Self { key_id: Self::random_key_id(), inner, passphrase: None }
+    }
+
+    /// Create a new secret storage key that is derived from the given
+    /// passphrase.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if it can't get enough randomness from the OS.
+    pub fn new_from_passphrase(passphrase: &str) -> Self {
+        let mut salt = [0u8; SALT_SIZE];
+        getrandom(&mut salt).expect("Can't generate randomness for a secret storage key");
+
+        let info = PassphraseInfo {
+            algorithm: "m.pbkdf2".to_owned(),
+            salt: encode(salt),
+            iterations: PBKDF2_ITERATIONS,
+        };
+
+        Self::from_passphrase(&Self::random_key_id(), passphrase, &info)
+    }
+
+    fn random_key_id() -> String {
+        let mut key_id = [0u8; KEY_ID_SIZE];
+        getrandom(&mut key_id).expect("Can't generate randomness for a secret storage key");
+
+        encode_url_safe(key_id)
+    }
+
+    /// Restore a secret storage key from a passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_id` - The id of the secret storage key.
+    ///
+    /// * `passphrase` - The passphrase the key was derived from.
+    ///
+    /// * `info` - The passphrase info from the description of the key.
+    pub fn from_passphrase(key_id: &str, passphrase: &str, info: &PassphraseInfo) -> Self {
+        let mut inner = Box::new([0u8; KEY_SIZE]);
+        pbkdf2::<Hmac<Sha512>>(
+            passphrase.as_bytes(),
+            info.salt.as_bytes(),
+            info.iterations,
+            &mut inner[..],
+        );
+
+        Self { key_id: key_id.to_owned(), inner, passphrase: Some(info.clone()) }
+    }
+
+    /// Restore a secret storage key from a recovery key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_id` - The id of the secret storage key.
+    ///
+    /// * `recovery_key` - The base58 encoded recovery key, whitespace is
+    /// ignored.
+    pub fn from_recovery_key(key_id: &str, recovery_key: &str) -> Result<Self, SecretStorageError> {
+        let recovery_key: String = recovery_key.chars().filter(|c| !c.is_whitespace()).collect();
+
+        let decoded = Zeroizing::new(
+            bs58::decode(recovery_key)
+                .into_vec()
+                .map_err(|_| SecretStorageError::InvalidRecoveryKey)?,
+        );
+
+        if decoded.len() != RECOVERY_KEY_PREFIX.len() + KEY_SIZE + 1
+            || decoded[..RECOVERY_KEY_PREFIX.len()] != RECOVERY_KEY_PREFIX
+            || decoded.iter().fold(0u8, |parity, b| parity ^ b) != 0
+        {
+            return Err(SecretStorageError::InvalidRecoveryKey);
+        }
+
+        let key: [u8; KEY_SIZE] = decoded
+            [RECOVERY_KEY_PREFIX.len()..RECOVERY_KEY_PREFIX.len() + KEY_SIZE]
+            .try_into()
+            .map_err(|_| SecretStorageError::InvalidRecoveryKey)?;
+
+        Ok(Self { key_id: key_id.to_owned(), inner: Box::new(key), passphrase: None })
+    }
+
+    /// Export the key as a base58 encoded recovery key that can be shown to
+    /// the user.
+    pub fn to_recovery_key(&self) -> String {
+        let mut bytes =
+            Zeroizing::new(Vec::with_capacity(RECOVERY_KEY_PREFIX.len() + KEY_SIZE + 1));
+        bytes.extend_from_slice(&RECOVERY_KEY_PREFIX);
+        bytes.extend_from_slice(&self.inner[..]);
+
+        let parity = bytes.iter().fold(0u8, |parity, b| parity ^ b);
+        bytes.push(parity);
+
+        let encoded = Zeroizing::new(bs58::encode(bytes.as_slice()).into_string());
+
+        encoded
+            .as_bytes()
+            .chunks(4)
+            .map(|c| String::from_utf8_lossy(c).into_owned())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Get the unique id of the key.
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    /// Get the event type of the account data event that holds the
+    /// description of this key.
+    pub fn event_type(&self) -> String {
+        format!("m.secret_storage.key.{}", self.key_id)
+    }
+
+    /// Get the description of this key that should be uploaded as the
+    /// content of the account data event with the type [`event_type`].
+    ///
+    /// [`event_type`]: #method.event_type
+    pub fn key_description(&self) -> SecretStorageKeyDescription {
+        let data = self.encrypt_with_iv("", &[0u8; KEY_SIZE], Self::random_iv());
+
+        SecretStorageKeyDescription {
+            algorithm: SECRET_STORAGE_ALGORITHM.to_owned(),
+            passphrase: self.passphrase.clone(),
+            iv: data.iv,
+            mac: data.mac,
+        }
+    }
+
+    /// Check if the key matches the given key description.
+    pub fn matches_description(&self, description: &SecretStorageKeyDescription) -> bool {
+        if description.algorithm != SECRET_STORAGE_ALGORITHM {
+            return false;
+        }
+
+        let iv = decode(description.iv.trim_end_matches('='))
+            .ok()
+            .and_then(|iv| <[u8; IV_SIZE]>::try_from(iv.as_slice()).ok());
+
+        if let Some(iv) = iv {
+            let data = self.encrypt_with_iv("", &[0u8; KEY_SIZE], iv);
+            data.mac == description.mac.trim_end_matches('=')
+        } else {
+            false
+        }
+    }
+
+    fn random_iv() -> [u8; IV_SIZE] {
+        let mut iv = [0u8; IV_SIZE];
+        getrandom(&mut iv).expect("Can't generate randomness for a secret");
+
+        // Clear bit 63 of the IV, so the counter doesn't wrap around.
+        iv[8] &= 0x7f;
+
+        iv
+    }
+
+    fn derive_keys(&self, secret_name: &str) -> Zeroizing<[u8; KEY_SIZE * 2]> {
+        let mut keys = Zeroizing::new([0u8; KEY_SIZE * 2]);
+
+        Hkdf::<Sha256>::new(Some(&[0u8; KEY_SIZE]), &self.inner[..])
+            .expand(secret_name.as_bytes(), &mut keys[..])
+            .expect("Can't expand the secret storage key");
+
+        keys
+    }
+
+    fn encrypt_with_iv(
+        &self,
+        secret_name: &str,
+        secret: &[u8],
+        iv: [u8; IV_SIZE],
+    ) -> AesHmacSha2EncryptedData {
+        let keys = self.derive_keys(secret_name);
+        let (aes_key, mac_key) = keys.split_at(KEY_SIZE);
+
+        let mut ciphertext = secret.to_vec();
+        let mut aes = Aes256Ctr::new_var(aes_key, &iv).expect("Can't create an AES object");
+        aes.apply_keystream(&mut ciphertext);
+
+        let mut hmac = Hmac::<Sha256>::new_varkey(mac_key).expect("Can't create a HMAC object");
+        hmac.update(&ciphertext);
+        let mac = hmac.finalize().into_bytes();
+
+        AesHmacSha2EncryptedData {
+            iv: encode(iv),
+            ciphertext: encode(ciphertext),
+            mac: encode(mac),
+        }
+    }
+
+    /// Encrypt a secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret, e.g. `m.megolm_backup.v1`.
+    ///
+    /// * `secret` - The secret that should be encrypted.
+    pub fn encrypt_secret(&self, secret_name: &str, secret: &str) -> AesHmacSha2EncryptedData {
+        self.encrypt_with_iv(secret_name, secret.as_bytes(), Self::random_iv())
+    }
+
+    /// Decrypt a secret.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret_name` - The name of the secret, e.g. `m.megolm_backup.v1`.
+    ///
+    /// * `data` - The encrypted secret.
+    pub fn decrypt_secret(
+        &self,
+        secret_name: &str,
+        data: &AesHmacSha2EncryptedData,
+    ) -> Result<String, SecretStorageError> {
+        let keys = self.derive_keys(secret_name);
+        let (aes_key, mac_key) = keys.split_at(KEY_SIZE);
+
+        let iv = decode(data.iv.trim_end_matches('='))?;
+        let mut ciphertext = decode(data.ciphertext.trim_end_matches('='))?;
+        let mac = decode(data.mac.trim_end_matches('='))?;
+
+        let mut hmac = Hmac::<Sha256>::new_varkey(mac_key).expect("Can't create a HMAC object");
+        hmac.update(&ciphertext);
+        hmac.verify(&mac).map_err(|_| SecretStorageError::InvalidMac)?;
+
+        let mut aes =
+            Aes256Ctr::new_var(aes_key, &iv).map_err(|_| SecretStorageError::InvalidMac)?;
+        aes.apply_keystream(&mut ciphertext);
+
+        Ok(String::from_utf8(ciphertext)?)
+    }
+
+    /// Encrypt a secret and put it into the format of the content of the
+    /// account data event that stores the secret.
+    ///
+    /// The content should be uploaded as the account data event with the type
+    /// `secret_name`.
+    pub fn encrypt_secret_content(&self, secret_name: &str, secret: &str) -> Value {
+        json!({
+            "encrypted": {
+                self.key_id.as_str(): self.encrypt_secret(secret_name, secret),
+            }
+        })
+    }
+
+    /// Decrypt a secret from the content of the account data event that
+    /// stores the secret.
+    pub fn decrypt_secret_content(
+        &self,
+        secret_name: &str,
+        content: &Value,
+    ) -> Result<String, SecretStorageError> {
+        let data = content
+            .get("encrypted")
+            .and_then(|e| e.get(&self.key_id))
+            .ok_or_else(|| SecretStorageError::MissingKey(self.key_id.clone()))?;
+
+        let data: AesHmacSha2EncryptedData = serde_json::from_value(data.clone())?;
+
+        self.decrypt_secret(secret_name, &data)
+    }
+}
+
+impl Default for SecretStorageKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SecretStorageError, SecretStorageKey};
+
+    #[test]
+    fn secret_roundtrip() {
+        let key = SecretStorageKey::new();
+        let content = key.encrypt_secret_content("m.megolm_backup.v1", "It's a secret");
+
+        assert_eq!(
+            key.decrypt_secret_content("m.megolm_backup.v1", &content).unwrap(),
+            "It's a secret"
+        );
+        assert!(matches!(
+            key.decrypt_secret_content("m.cross_signing.master", &content),
+            Err(SecretStorageError::InvalidMac)
+        ));
+    }
+
+    #[test]
+    fn recovery_key_roundtrip() {
+        let key = SecretStorageKey::new();
+        let recovery_key = key.to_recovery_key();
+
+        let restored = SecretStorageKey::from_recovery_key(key.key_id(), &recovery_key).unwrap();
+        assert!(restored.matches_description(&key.key_description()));
+
+        let mut typo = recovery_key.into_bytes();
+        typo[0] = if typo[0] == b'E' { b'F' } else { b'E' };
+        let typo = String::from_utf8(typo).unwrap();
+
+        assert!(SecretStorageKey::from_recovery_key(key.key_id(), &typo).is_err());
+    }
+
+    #[test]
+    fn passphrase_key() {
+        let key = SecretStorageKey::new_from_passphrase("It's a secret to everybody");
+        let description = key.key_description();
+
+        let restored = SecretStorageKey::from_passphrase(
+            key.key_id(),
+            "It's a secret to everybody",
+            description.passphrase.as_ref().unwrap(),
+        );
+
+        assert!(restored.matches_description(&description));
+        assert!(!SecretStorageKey::new().matches_description(&description));
+    }
+}
@@ -14,7 +14,10 @@
 
 use std::{
     collections::{BTreeMap, BTreeSet, HashMap, HashSet},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 use dashmap::DashMap;
@@ -29,12 +32,17 @@ use ruma::{
     DeviceId, DeviceIdBox, RoomId, UserId,
 };
 use serde_json::Value;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, instrument, trace};
 
 use crate::{
     error::{EventError, MegolmResult, OlmResult},
-    olm::{Account, InboundGroupSession, OutboundGroupSession, Session, ShareState},
+    identities::ReadOnlyDevice,
+    olm::{
+        Account, InboundGroupSession, OutboundGroupSession, Session, ShareDecision, ShareState,
+        SharingHistoryEntry, WithheldReason,
+    },
     store::{Changes, Result as StoreResult, Store},
+    utilities::log_id,
     Device, EncryptionSettings, OlmError, ToDeviceRequest,
 };
 
@@ -94,6 +102,16 @@ impl GroupSessionCache {
         self.sessions.get(room_id).map(|s| s.clone())
     }
 
+    /// Drop the cached sessions, they will be loaded from the store again.
+    pub(crate) fn clear(&self) {
+        self.sessions.clear();
+    }
+
+    /// Get all the sessions that are currently held in the cache.
+    fn cached_sessions(&self) -> Vec<OutboundGroupSession> {
+        self.sessions.iter().map(|s| s.value().clone()).collect()
+    }
+
     /// Get or load the session for the given room with the given session id.
     ///
     /// This is the same as [get_or_load()](#method.get_or_load) but it will
@@ -107,6 +125,100 @@ impl GroupSessionCache {
     }
 }
 
+/// A handle to follow and cancel the sharing of a room key.
+///
+/// Sharing a room key in a big room can take a while, the room key first needs
+/// to be encrypted for every device in the room and the resulting to-device
+/// requests need to be sent out. This handle can be used to show the progress
+/// of this to the user.
+///
+/// The handle for the latest room key sharing of a room can be fetched using
+/// [`OlmMachine::room_key_sharing_progress()`].
+///
+/// [`OlmMachine::room_key_sharing_progress()`]: crate::OlmMachine::room_key_sharing_progress
+#[derive(Clone, Debug, Default)]
+pub struct RoomKeySharingProgress {
+    inner: Arc<SharingProgress>,
+}
+
+#[derive(Debug, Default)]
+struct SharingProgress {
+    total_devices: AtomicUsize,
+    encrypted_devices: AtomicUsize,
+    total_requests: AtomicUsize,
+    sent_requests: AtomicUsize,
+    cancelled: AtomicBool,
+}
+
+impl RoomKeySharingProgress {
+    fn new(total_devices: usize) -> Self {
+        let progress = Self::default();
+        progress.inner.total_devices.store(total_devices, Ordering::SeqCst);
+
+        progress
+    }
+
+    /// The number of devices the room key is being shared with.
+    pub fn total_devices(&self) -> usize {
+        self.inner.total_devices.load(Ordering::SeqCst)
+    }
+
+    /// The number of devices the room key was already encrypted for.
+    pub fn encrypted_devices(&self) -> usize {
+        self.inner.encrypted_devices.load(Ordering::SeqCst)
+    }
+
+    /// The number of to-device requests that carry the room key.
+    ///
+    /// This is only known once the room key has been encrypted for all the
+    /// devices.
+    pub fn total_requests(&self) -> usize {
+        self.inner.total_requests.load(Ordering::SeqCst)
+    }
+
+    /// The number of to-device requests that were sent out and acknowledged
+    /// by the server.
+    pub fn sent_requests(&self) -> usize {
+        self.inner.sent_requests.load(Ordering::SeqCst)
+    }
+
+    /// Has the room key been encrypted for all the devices and were all the
+    /// to-device requests sent out.
+    pub fn is_done(&self) -> bool {
+        !self.is_cancelled()
+            && self.encrypted_devices() == self.total_devices()
+            && self.sent_requests() >= self.total_requests()
+    }
+
+    /// Cancel the sharing of the room key.
+    ///
+    /// Devices the room key wasn't yet encrypted for are skipped and no more
+    /// to-device requests should be sent out. Since the room key might have
+    /// been received by some of the devices, the outbound group session of the
+    /// room is invalidated and a new one will be shared before the next
+    /// message is encrypted.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Was the sharing of the room key cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn mark_devices_as_encrypted(&self, count: usize) {
+        self.inner.encrypted_devices.fetch_add(count, Ordering::SeqCst);
+    }
+
+    fn set_total_requests(&self, count: usize) {
+        self.inner.total_requests.store(count, Ordering::SeqCst);
+    }
+
+    fn mark_request_as_sent(&self) {
+        self.inner.sent_requests.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct GroupSessionManager {
     account: Account,
@@ -116,13 +228,25 @@ pub struct GroupSessionManager {
     store: Store,
     /// The currently active outbound group sessions.
     sessions: GroupSessionCache,
+    /// The progress of the latest room key sharing for each room.
+    sharing_progress: Arc<DashMap<RoomId, RoomKeySharingProgress>>,
 }
 
 impl GroupSessionManager {
     const MAX_TO_DEVICE_MESSAGES: usize = 250;
 
     pub(crate) fn new(account: Account, store: Store) -> Self {
-        Self { account, store: store.clone(), sessions: GroupSessionCache::new(store) }
+        Self {
+            account,
+            store: store.clone(),
+            sessions: GroupSessionCache::new(store),
+            sharing_progress: DashMap::new().into(),
+        }
+    }
+
+    /// Get the progress of the latest room key sharing in the given room.
+    pub fn sharing_progress(&self, room_id: &RoomId) -> Option<RoomKeySharingProgress> {
+        self.sharing_progress.get(room_id).map(|p| p.value().clone())
     }
 
     pub async fn invalidate_group_session(&self, room_id: &RoomId) -> StoreResult<bool> {
@@ -139,10 +263,114 @@ impl GroupSessionManager {
         }
     }
 
+    /// Invalidate the outbound group session of the given room if it was
+    /// shared with any of the given users.
+    ///
+    /// This should be called when users leave or get banned from a room, the
+    /// session will be rotated before the next message is encrypted so the
+    /// users that left won't be able to decrypt it.
+    ///
+    /// Returns true if the session was invalidated.
+    pub async fn invalidate_for_left_users(
+        &self,
+        room_id: &RoomId,
+        users: impl Iterator<Item = &UserId>,
+    ) -> StoreResult<bool> {
+        let session = if let Some(s) = self.sessions.get_or_load(room_id).await? {
+            s
+        } else {
+            return Ok(false);
+        };
+
+        if session.invalidated() {
+            return Ok(false);
+        }
+
+        let left: Vec<&UserId> = users.filter(|u| session.is_shared_with_user(u)).collect();
+
+        if left.is_empty() {
+            Ok(false)
+        } else {
+            debug!(
+                room_id = %log_id(room_id.as_str()),
+                session_id = %log_id(session.session_id()),
+                users = ?left.iter().map(|u| log_id(u.as_str())).collect::<Vec<_>>(),
+                "Users that received the outbound group session left the room, \
+                 invalidating the session"
+            );
+
+            self.save_invalidated(vec![session]).await?;
+
+            Ok(true)
+        }
+    }
+
+    /// Get the outbound group sessions that were shared with any of the given
+    /// devices and that aren't invalidated yet.
+    pub fn sessions_shared_with(&self, devices: &[ReadOnlyDevice]) -> Vec<OutboundGroupSession> {
+        if devices.is_empty() {
+            return Vec::new();
+        }
+
+        self.sessions
+            .cached_sessions()
+            .into_iter()
+            .filter(|s| {
+                !s.invalidated()
+                    && devices.iter().any(|d| {
+                        matches!(
+                            s.is_shared_with(d.user_id(), d.device_id()),
+                            ShareState::Shared(_)
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// Invalidate all the outbound group sessions that were shared with the
+    /// given devices.
+    ///
+    /// This should be called when devices get deleted or blacklisted, the
+    /// affected sessions will be rotated before the next message is encrypted.
+    ///
+    /// Returns the list of rooms whose session was invalidated.
+    pub async fn invalidate_for_devices(
+        &self,
+        devices: &[ReadOnlyDevice],
+    ) -> StoreResult<Vec<RoomId>> {
+        let sessions = self.sessions_shared_with(devices);
+        let rooms: Vec<RoomId> = sessions.iter().map(|s| s.room_id().to_owned()).collect();
+
+        if !rooms.is_empty() {
+            debug!(
+                rooms = ?rooms.iter().map(|r| log_id(r.as_str())).collect::<Vec<_>>(),
+                "Devices that received outbound group sessions got deleted or \
+                 blacklisted, invalidating the sessions"
+            );
+
+            self.save_invalidated(sessions).await?;
+        }
+
+        Ok(rooms)
+    }
+
+    async fn save_invalidated(&self, sessions: Vec<OutboundGroupSession>) -> StoreResult<()> {
+        for session in &sessions {
+            session.invalidate_session();
+        }
+
+        let changes = Changes { outbound_group_sessions: sessions, ..Default::default() };
+        self.store.save_changes(changes).await
+    }
+
     pub async fn mark_request_as_sent(&self, request_id: &Uuid) -> StoreResult<()> {
         if let Some((_, s)) = self.sessions.sessions_being_shared.remove(request_id) {
             s.mark_request_as_sent(request_id);
 
+            if let Some(progress) = self.sharing_progress.get(s.room_id()) {
+                progress.mark_request_as_sent();
+            }
+
             let mut changes = Changes::default();
             changes.outbound_group_sessions.push(s.clone());
             self.store.save_changes(changes).await?;
@@ -176,6 +404,8 @@ impl GroupSessionManager {
             panic!("Session expired");
         }
 
+        session.settings().check_algorithm()?;
+
         let content = session.encrypt(content).await;
 
         let mut changes = Changes::default();
@@ -189,6 +419,7 @@ impl GroupSessionManager {
     ///
     /// This also creates a matching inbound group session and saves that one in
     /// the store.
+    #[instrument(skip(self, settings), fields(room_id = %log_id(room_id.as_str())))]
     pub async fn create_outbound_group_session(
         &self,
         room_id: &RoomId,
@@ -228,10 +459,14 @@ impl GroupSessionManager {
 
     /// Encrypt the given content for the given devices and create a to-device
     /// requests that sends the encrypted content to them.
+    ///
+    /// Returns the id of the request, the request, the Olm sessions that were
+    /// used and the devices we couldn't encrypt the content for since we don't
+    /// share an Olm session with them.
     async fn encrypt_session_for(
         content: Value,
         devices: Vec<Device>,
-    ) -> OlmResult<(Uuid, ToDeviceRequest, Vec<Session>)> {
+    ) -> OlmResult<(Uuid, ToDeviceRequest, Vec<Session>, Vec<Device>)> {
         let mut messages = BTreeMap::new();
         let mut changed_sessions = Vec::new();
 
@@ -249,8 +484,8 @@ impl GroupSessionManager {
                     Some(session)
                 }
                 // TODO we'll want to create m.room_key.withheld here.
-                Err(OlmError::MissingSession)
-                | Err(OlmError::EventError(EventError::MissingSenderKey)) => None,
+                Err(OlmError::MissingSession(_))
+                | Err(OlmError::EventError(EventError::MissingSenderKey, _)) => None,
                 Err(e) => return Err(e),
             };
 
@@ -274,6 +509,15 @@ impl GroupSessionManager {
             }
         }
 
+        let without_session = devices
+            .into_iter()
+            .filter(|d| {
+                !messages.get(d.user_id()).map_or(false, |m| {
+                    m.contains_key(&DeviceIdOrAllDevices::DeviceId(d.device_id().into()))
+                })
+            })
+            .collect();
+
         let id = Uuid::new_v4();
 
         let request =
@@ -285,27 +529,29 @@ impl GroupSessionManager {
             "Created a to-device request carrying a room_key"
         );
 
-        Ok((id, request, changed_sessions))
+        Ok((id, request, changed_sessions, without_session))
     }
 
     /// Given a list of user and an outbound session, return the list of users
     /// and their devices that this session should be shared with.
     ///
-    /// Returns a boolean indicating whether the session needs to be rotated and
-    /// the list of users/devices that should receive the session.
+    /// Returns a boolean indicating whether the session needs to be rotated,
+    /// the list of users/devices that should receive the session and the list
+    /// of blacklisted devices that won't receive the session.
     pub async fn collect_session_recipients(
         &self,
         users: impl Iterator<Item = &UserId>,
         history_visibility: HistoryVisibility,
         outbound: &OutboundGroupSession,
-    ) -> OlmResult<(bool, HashMap<UserId, Vec<Device>>)> {
+    ) -> OlmResult<(bool, HashMap<UserId, Vec<Device>>, Vec<Device>)> {
         let users: HashSet<&UserId> = users.collect();
         let mut devices: HashMap<UserId, Vec<Device>> = HashMap::new();
+        let mut blacklisted_devices = Vec::new();
 
         debug!(
-            users = ?users,
+            users = ?users.iter().map(|u| log_id(u.as_str())).collect::<Vec<_>>(),
             history_visibility = ?history_visibility,
-            session_id = outbound.session_id(),
+            session_id = %log_id(outbound.session_id()),
             "Calculating group session recipients"
         );
 
@@ -331,8 +577,9 @@ impl GroupSessionManager {
 
         for user_id in users {
             let user_devices = self.store.get_user_devices(user_id).await?;
-            let non_blacklisted_devices: Vec<Device> =
-                user_devices.devices().filter(|d| !d.is_blacklisted()).collect();
+            let (blacklisted, non_blacklisted_devices): (Vec<Device>, Vec<Device>) =
+                user_devices.devices().partition(|d| d.is_blacklisted());
+            blacklisted_devices.extend(blacklisted);
 
             // If we haven't already concluded that the session should be
             // rotated for other reasons, we also need to check whether any
@@ -371,11 +618,11 @@ impl GroupSessionManager {
 
         debug!(
             should_rotate = should_rotate,
-            session_id = outbound.session_id(),
+            session_id = %log_id(outbound.session_id()),
             "Done calculating group session recipients"
         );
 
-        Ok((should_rotate, devices))
+        Ok((should_rotate, devices, blacklisted_devices))
     }
 
     pub async fn encrypt_request(
@@ -384,16 +631,88 @@ impl GroupSessionManager {
         outbound: OutboundGroupSession,
         message_index: u32,
         being_shared: Arc<DashMap<Uuid, OutboundGroupSession>>,
-    ) -> OlmResult<Vec<Session>> {
-        let (id, request, used_sessions) =
+        progress: RoomKeySharingProgress,
+    ) -> OlmResult<(Vec<Session>, Vec<SharingHistoryEntry>)> {
+        if progress.is_cancelled() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+
+        let chunk_size = chunk.len();
+        let (id, request, used_sessions, without_session) =
             Self::encrypt_session_for(content.clone(), chunk).await?;
 
+        progress.mark_devices_as_encrypted(chunk_size);
+
+        let session_id = outbound.session_id();
+        let room_id = outbound.room_id();
+
+        let mut history: Vec<SharingHistoryEntry> = request
+            .messages
+            .iter()
+            .flat_map(|(user_id, devices)| {
+                devices.keys().filter_map(move |d| match d {
+                    DeviceIdOrAllDevices::DeviceId(d) => Some((user_id, d)),
+                    _ => None,
+                })
+            })
+            .map(|(user_id, device_id)| {
+                SharingHistoryEntry::new(
+                    session_id,
+                    room_id,
+                    user_id,
+                    device_id,
+                    ShareDecision::Shared { message_index },
+                )
+            })
+            .collect();
+
+        history.extend(without_session.iter().map(|d| {
+            SharingHistoryEntry::new(
+                session_id,
+                room_id,
+                d.user_id(),
+                d.device_id(),
+                ShareDecision::Withheld(WithheldReason::NoOlmSession),
+            )
+        }));
+
         if !request.messages.is_empty() {
             outbound.add_request(id, request.into(), message_index);
             being_shared.insert(id, outbound.clone());
         }
 
-        Ok(used_sessions)
+        Ok((used_sessions, history))
+    }
+
+    /// Record the given sharing decisions, skipping decisions to withhold the
+    /// session that were already recorded for a device.
+    ///
+    /// Devices we withhold the session from are reconsidered every time the
+    /// session gets shared, we don't want to fill the history with the same
+    /// decision over and over again.
+    async fn record_sharing_history(
+        &self,
+        session_id: &str,
+        history: Vec<SharingHistoryEntry>,
+        changes: &mut Changes,
+    ) -> StoreResult<()> {
+        let known_history = self.store.get_sharing_history(session_id).await?;
+
+        let is_known = |entry: &SharingHistoryEntry| {
+            known_history.iter().any(|e| {
+                e.user_id == entry.user_id
+                    && e.device_id == entry.device_id
+                    && e.decision == entry.decision
+            })
+        };
+
+        changes.sharing_history.extend(
+            history
+                .into_iter()
+                .filter(|e| !matches!(e.decision, ShareDecision::Withheld(_)) || !is_known(e)),
+        );
+
+        Ok(())
     }
 
     pub(crate) fn session_cache(&self) -> GroupSessionCache {
@@ -418,8 +737,8 @@ impl GroupSessionManager {
         encryption_settings: impl Into<EncryptionSettings>,
     ) -> OlmResult<Vec<Arc<ToDeviceRequest>>> {
         debug!(
-            room_id = room_id.as_str(),
-            "Checking if a group session needs to be shared for room {}", room_id
+            room_id = %log_id(room_id.as_str()),
+            "Checking if a group session needs to be shared for the room"
         );
 
         let encryption_settings = encryption_settings.into();
@@ -434,7 +753,7 @@ impl GroupSessionManager {
             changes.inbound_group_sessions.push(inbound);
         }
 
-        let (should_rotate, devices) =
+        let (should_rotate, devices, blacklisted_devices) =
             self.collect_session_recipients(users, history_visibility, &outbound).await?;
 
         let outbound = if should_rotate {
@@ -446,9 +765,9 @@ impl GroupSessionManager {
             changes.inbound_group_sessions.push(inbound);
 
             debug!(
-                room_id = room_id.as_str(),
-                old_session_id = old_session_id,
-                session_id = outbound.session_id(),
+                room_id = %log_id(room_id.as_str()),
+                old_session_id = %log_id(old_session_id),
+                session_id = %log_id(outbound.session_id()),
                 "A user/device has left the group since we last sent a message, \
                    rotating the outbound session.",
             );
@@ -471,19 +790,24 @@ impl GroupSessionManager {
             .flatten()
             .collect();
 
+        let progress = RoomKeySharingProgress::new(devices.len());
+        self.sharing_progress.insert(room_id.clone(), progress.clone());
+
         let key_content = outbound.as_json().await;
         let message_index = outbound.message_index().await;
 
         if !devices.is_empty() {
             let users = devices.iter().fold(BTreeMap::new(), |mut acc, d| {
-                acc.entry(d.user_id()).or_insert_with(BTreeSet::new).insert(d.device_id());
+                acc.entry(log_id(d.user_id().as_str()))
+                    .or_insert_with(BTreeSet::new)
+                    .insert(log_id(d.device_id().as_str()));
                 acc
             });
 
             info!(
                 index = message_index,
                 users = ?users,
-                room_id = room_id.as_str(),
+                room_id = %log_id(room_id.as_str()),
                 "Sharing an outbound group session",
             );
         }
@@ -497,29 +821,65 @@ impl GroupSessionManager {
                     outbound.clone(),
                     message_index,
                     self.sessions.sessions_being_shared.clone(),
+                    progress.clone(),
                 ))
             })
             .collect();
 
+        let mut history: Vec<SharingHistoryEntry> = blacklisted_devices
+            .iter()
+            .map(|d| {
+                SharingHistoryEntry::new(
+                    outbound.session_id(),
+                    room_id,
+                    d.user_id(),
+                    d.device_id(),
+                    ShareDecision::Withheld(WithheldReason::Blacklisted),
+                )
+            })
+            .collect();
+
         for result in join_all(tasks).await {
-            let used_sessions: OlmResult<Vec<Session>> = result.expect("Encryption task panicked");
+            let result: OlmResult<(Vec<Session>, Vec<SharingHistoryEntry>)> =
+                result.expect("Encryption task panicked");
+            let (used_sessions, sharing_history) = result?;
 
-            changes.sessions.extend(used_sessions?);
+            changes.sessions.extend(used_sessions);
+            history.extend(sharing_history);
+        }
+
+        self.record_sharing_history(outbound.session_id(), history, &mut changes).await?;
+
+        if progress.is_cancelled() {
+            debug!(
+                room_id = %log_id(room_id.as_str()),
+                session_id = %log_id(outbound.session_id()),
+                "The room key sharing was cancelled, invalidating the outbound group session",
+            );
+
+            // Some devices might have been skipped, so the session can't be
+            // used, but the Olm sessions that were used need to be saved.
+            outbound.invalidate_session();
+            changes.outbound_group_sessions.push(outbound.clone());
+            self.store.save_changes(changes).await?;
+
+            return Err(OlmError::RoomKeySharingCancelled);
         }
 
         let requests = outbound.pending_requests();
+        progress.set_total_requests(requests.len());
 
         debug!(
-            room_id = room_id.as_str(),
-            session_id = outbound.session_id(),
+            room_id = %log_id(room_id.as_str()),
+            session_id = %log_id(outbound.session_id()),
             request_count = requests.len(),
             "Done generating to-device requests for a room key share"
         );
 
         if requests.is_empty() {
             debug!(
-                room_id = room_id.as_str(),
-                session_id = outbound.session_id(),
+                room_id = %log_id(room_id.as_str()),
+                session_id = %log_id(outbound.session_id()),
                 "The outbound group session doesn't need to be shared with \
                     anyone, marking as shared",
             );
@@ -531,8 +891,8 @@ impl GroupSessionManager {
         self.store.save_changes(changes).await?;
 
         debug!(
-            room_id = room_id.as_str(),
-            session_id = outbound.session_id(),
+            room_id = %log_id(room_id.as_str()),
+            session_id = %log_id(outbound.session_id()),
             session_count = session_count,
             "Stored the changed sessions after encrypting an room key"
         );
@@ -547,14 +907,17 @@ mod test {
     use matrix_sdk_test::response_from_file;
     use ruma::{
         api::{
-            client::r0::keys::{claim_keys, get_keys},
+            client::r0::{
+                keys::{claim_keys, get_keys},
+                to_device::send_event_to_device::Response as ToDeviceResponse,
+            },
             IncomingResponse,
         },
         room_id, user_id, DeviceIdBox, UserId,
     };
     use serde_json::Value;
 
-    use crate::{EncryptionSettings, OlmMachine};
+    use crate::{EncryptionSettings, OlmError, OlmMachine};
 
     fn alice_id() -> UserId {
         user_id!("@alice:example.org")
@@ -613,4 +976,76 @@ mod test {
         // that all 148 valid sessions get an room key.
         assert_eq!(event_count, 148);
     }
+
+    #[tokio::test]
+    async fn test_sharing_progress() {
+        let machine = machine().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+
+        let users: Vec<_> = keys_claim.one_time_keys.keys().collect();
+
+        assert!(machine.room_key_sharing_progress(&room_id).is_none());
+
+        let requests = machine
+            .share_group_session(&room_id, users.clone().into_iter(), EncryptionSettings::default())
+            .await
+            .unwrap();
+
+        let progress = machine.room_key_sharing_progress(&room_id).unwrap();
+
+        assert_eq!(progress.encrypted_devices(), progress.total_devices());
+        assert_eq!(progress.total_requests(), requests.len());
+        assert_eq!(progress.sent_requests(), 0);
+        assert!(!progress.is_done());
+
+        for request in &requests {
+            machine.mark_request_as_sent(&request.txn_id, &ToDeviceResponse::new()).await.unwrap();
+        }
+
+        assert_eq!(progress.sent_requests(), requests.len());
+        assert!(progress.is_done());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_sharing() {
+        let machine = machine().await;
+        let room_id = room_id!("!test:localhost");
+        let keys_claim = keys_claim_response();
+
+        let users: Vec<_> = keys_claim.one_time_keys.keys().collect();
+
+        let sharing = machine.share_group_session(
+            &room_id,
+            users.clone().into_iter(),
+            EncryptionSettings::default(),
+        );
+
+        // Cancel the sharing as soon as the devices are known.
+        let cancel = async {
+            loop {
+                if let Some(progress) = machine.room_key_sharing_progress(&room_id) {
+                    progress.cancel();
+                    break progress;
+                }
+
+                tokio::task::yield_now().await;
+            }
+        };
+
+        let (result, progress) = futures::join!(sharing, cancel);
+
+        assert!(matches!(result, Err(OlmError::RoomKeySharingCancelled)));
+        assert!(progress.is_cancelled());
+        assert!(!progress.is_done());
+
+        // The next attempt shares a new room key with everyone.
+        let requests = machine
+            .share_group_session(&room_id, users.into_iter(), EncryptionSettings::default())
+            .await
+            .unwrap();
+        let event_count: usize = requests.iter().map(|r| r.message_count()).sum();
+
+        assert_eq!(event_count, 148);
+    }
 }
@@ -15,5 +15,7 @@
 mod group_sessions;
 mod sessions;
 
+pub use group_sessions::RoomKeySharingProgress;
 pub(crate) use group_sessions::{GroupSessionCache, GroupSessionManager};
+pub use sessions::PendingToDeviceMessage;
 pub(crate) use sessions::SessionManager;
@@ -15,7 +15,7 @@
 use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
 use dashmap::{DashMap, DashSet};
-use matrix_sdk_common::uuid::Uuid;
+use matrix_sdk_common::{instant::Instant, time::elapsed_since, uuid::Uuid};
 use ruma::{
     api::client::r0::{
         keys::claim_keys::{Request as KeysClaimRequest, Response as KeysClaimResponse},
@@ -23,20 +23,63 @@ use ruma::{
     },
     assign,
     events::EventType,
-    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, UserId,
+    DeviceId, DeviceIdBox, DeviceKeyAlgorithm, MilliSecondsSinceUnixEpoch, UserId,
 };
-use serde_json::{json, value::to_raw_value};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, value::to_raw_value, Value};
 use tracing::{error, info, warn};
 
 use crate::{
-    error::OlmResult,
+    error::{OlmError, OlmResult},
     key_request::KeyRequestMachine,
     olm::Account,
-    requests::{OutgoingRequest, ToDeviceRequest},
+    requests::{OutgoingRequest, StoredOutgoingRequest, ToDeviceRequest},
     store::{Changes, Result as StoreResult, Store},
+    utilities::log_id,
     ReadOnlyDevice,
 };
 
+/// A to-device message that is waiting for an Olm session with the recipient
+/// device before it can be encrypted and sent out.
+///
+/// The messages are kept in the crypto store, a key claim that only finishes
+/// after a restart still sends them out.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PendingToDeviceMessage {
+    /// The unique id of the message.
+    pub id: Uuid,
+    /// The user the message should be sent to.
+    pub user_id: UserId,
+    /// The device the message should be sent to.
+    pub device_id: DeviceIdBox,
+    /// The type of the event.
+    pub event_type: EventType,
+    /// The content of the event, before it gets encrypted.
+    pub content: Value,
+    /// The time the message was queued up at.
+    pub created_at: MilliSecondsSinceUnixEpoch,
+}
+
+impl PendingToDeviceMessage {
+    /// How long a message waits for an Olm session before we give up on it.
+    pub const TTL: Duration = Duration::from_secs(60 * 60 * 24);
+
+    fn new(user_id: UserId, device_id: DeviceIdBox, event_type: EventType, content: Value) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            device_id,
+            event_type,
+            content,
+            created_at: MilliSecondsSinceUnixEpoch::now(),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        elapsed_since(self.created_at) > Self::TTL
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct SessionManager {
     account: Account,
@@ -47,13 +90,26 @@ pub(crate) struct SessionManager {
     /// [`get_missing_sessions`](#method.get_missing_sessions) is called.
     users_for_key_claim: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
     wedged_devices: Arc<DashMap<UserId, DashSet<DeviceIdBox>>>,
+    /// Devices that are part of a key claim request that is currently in
+    /// flight, with the time the request was created.
+    keys_claim_in_flight: Arc<DashMap<(UserId, DeviceIdBox), Instant>>,
     key_request_machine: KeyRequestMachine,
     outgoing_to_device_requests: Arc<DashMap<Uuid, OutgoingRequest>>,
+    /// To-device messages that couldn't be encrypted because we don't share an
+    /// Olm session with the recipient device yet. They get encrypted and sent
+    /// out once a session is established.
+    pending_to_device_messages: Arc<DashMap<(UserId, DeviceIdBox), Vec<PendingToDeviceMessage>>>,
 }
 
 impl SessionManager {
     const KEY_CLAIM_TIMEOUT: Duration = Duration::from_secs(10);
     const UNWEDGING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+    /// The time after which we consider a key claim request that didn't get a
+    /// response as failed and claim keys for the devices again.
+    const KEY_CLAIM_IN_FLIGHT_TIMEOUT: Duration = Duration::from_secs(60);
+    /// The maximal number of to-device messages that can wait for an Olm
+    /// session, over all devices.
+    const MAX_PENDING_MESSAGES: usize = 100;
 
     pub fn new(
         account: Account,
@@ -67,7 +123,9 @@ impl SessionManager {
             key_request_machine,
             users_for_key_claim,
             wedged_devices: Arc::new(DashMap::new()),
+            keys_claim_in_flight: Arc::new(DashMap::new()),
             outgoing_to_device_requests: Arc::new(DashMap::new()),
+            pending_to_device_messages: Arc::new(DashMap::new()),
         }
     }
 
@@ -76,28 +134,273 @@ impl SessionManager {
         self.outgoing_to_device_requests.remove(id);
     }
 
+    /// Load the to-device messages that were waiting for an Olm session before
+    /// a restart and claim keys for their recipients again.
+    pub async fn load_pending_messages(&self) -> StoreResult<()> {
+        for message in self.store.get_pending_to_device_messages().await? {
+            if message.is_expired() {
+                self.store.remove_pending_to_device_message(message.id).await?;
+                continue;
+            }
+
+            self.users_for_key_claim
+                .entry(message.user_id.clone())
+                .or_insert_with(DashSet::new)
+                .insert(message.device_id.clone());
+            self.pending_to_device_messages
+                .entry((message.user_id.clone(), message.device_id.clone()))
+                .or_insert_with(Vec::new)
+                .push(message);
+        }
+
+        for mut messages in self.pending_to_device_messages.iter_mut() {
+            messages.sort_by_key(|m| m.created_at);
+        }
+
+        Ok(())
+    }
+
+    /// Remove the pending to-device messages that waited too long for an Olm
+    /// session.
+    async fn expire_pending_messages(&self) -> StoreResult<()> {
+        let mut expired = Vec::new();
+
+        self.pending_to_device_messages.retain(|_, messages| {
+            messages.retain(|m| {
+                if m.is_expired() {
+                    expired.push(m.id);
+                    false
+                } else {
+                    true
+                }
+            });
+
+            !messages.is_empty()
+        });
+
+        for id in expired {
+            self.store.remove_pending_to_device_message(id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Put a to-device message into the wait queue of the given device and
+    /// claim keys for the device, so an Olm session gets established.
+    async fn queue_pending_message(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+        event_type: EventType,
+        content: Value,
+    ) -> StoreResult<()> {
+        self.expire_pending_messages().await?;
+
+        let pending_count: usize = self.pending_to_device_messages.iter().map(|m| m.len()).sum();
+
+        if pending_count >= Self::MAX_PENDING_MESSAGES {
+            warn!(
+                "Too many to-device messages are waiting for an Olm session, dropping the \
+                 message for {} {}",
+                log_id(user_id.as_str()),
+                log_id(device_id.as_str())
+            );
+
+            return Ok(());
+        }
+
+        let message =
+            PendingToDeviceMessage::new(user_id.to_owned(), device_id.into(), event_type, content);
+        self.store.save_pending_to_device_message(&message).await?;
+
+        self.users_for_key_claim
+            .entry(user_id.to_owned())
+            .or_insert_with(DashSet::new)
+            .insert(device_id.into());
+        self.pending_to_device_messages
+            .entry((user_id.to_owned(), device_id.into()))
+            .or_insert_with(Vec::new)
+            .push(message);
+
+        Ok(())
+    }
+
+    /// Store the request so it survives a restart and queue it up to be sent
+    /// out.
+    async fn queue_request(&self, request: OutgoingRequest) -> StoreResult<()> {
+        if let Some(stored) = StoredOutgoingRequest::from_request(&request) {
+            self.store.save_outgoing_request(&stored).await?;
+        }
+
+        self.outgoing_to_device_requests.insert(request.request_id, request);
+
+        Ok(())
+    }
+
+    /// Encrypt the given content for the given devices and create a to-device
+    /// request that sends the encrypted content to them.
+    ///
+    /// Returns `None` if the content couldn't be encrypted for any of the
+    /// devices right away.
+    ///
+    /// Devices that we don't share an Olm session with are put into the list
+    /// of devices we need to claim one-time keys for, the content is encrypted
+    /// for them and queued up as an outgoing request once the key claim
+    /// response establishes a session. Unknown devices are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `devices` - The user/device pairs the content should be sent to.
+    ///
+    /// * `event_type` - The type of the event.
+    ///
+    /// * `content` - The content of the event that should be encrypted.
+    pub async fn encrypt_to_devices(
+        &self,
+        devices: &[(UserId, DeviceIdBox)],
+        event_type: EventType,
+        content: Value,
+    ) -> OlmResult<Option<ToDeviceRequest>> {
+        let mut messages = BTreeMap::new();
+        let mut changes = Changes::default();
+
+        for (user_id, device_id) in devices {
+            let device = if let Some(d) = self.store.get_device(user_id, device_id).await? {
+                d
+            } else {
+                warn!(
+                    "Tried to encrypt a to-device message for {} {}, but the device is unknown",
+                    log_id(user_id.as_str()),
+                    log_id(device_id.as_str())
+                );
+                continue;
+            };
+
+            match device.encrypt(event_type.clone(), content.clone()).await {
+                Ok((session, encrypted)) => {
+                    messages.entry(user_id.to_owned()).or_insert_with(BTreeMap::new).insert(
+                        DeviceIdOrAllDevices::DeviceId(device_id.to_owned()),
+                        to_raw_value(&encrypted)?,
+                    );
+                    changes.sessions.push(session);
+                }
+                Err(OlmError::MissingSession(_)) => {
+                    info!(
+                        "Missing an Olm session with {} {}, putting the to-device message \
+                         in the wait queue",
+                        log_id(user_id.as_str()),
+                        log_id(device_id.as_str())
+                    );
+
+                    self.queue_pending_message(
+                        user_id,
+                        device_id,
+                        event_type.clone(),
+                        content.clone(),
+                    )
+                    .await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.store.save_changes(changes).await?;
+
+        Ok(if messages.is_empty() {
+            None
+        } else {
+            Some(ToDeviceRequest {
+                event_type: EventType::RoomEncrypted,
+                txn_id: Uuid::new_v4(),
+                messages,
+            })
+        })
+    }
+
+    /// Encrypt the to-device messages that were waiting for an Olm session with
+    /// the given device and queue them up as outgoing requests.
+    async fn send_pending_messages(&self, user_id: &UserId, device_id: &DeviceId) -> OlmResult<()> {
+        let pending = if let Some((_, pending)) =
+            self.pending_to_device_messages.remove(&(user_id.to_owned(), device_id.into()))
+        {
+            pending
+        } else {
+            return Ok(());
+        };
+
+        let device = self.store.get_device(user_id, device_id).await?;
+        let mut changes = Changes::default();
+        let mut requests = Vec::new();
+        let mut sent = Vec::new();
+
+        for message in pending {
+            let device = match &device {
+                Some(d) if !message.is_expired() => d,
+                _ => {
+                    self.store.remove_pending_to_device_message(message.id).await?;
+                    continue;
+                }
+            };
+
+            let (session, encrypted) = device.encrypt(message.event_type, message.content).await?;
+            let id = Uuid::new_v4();
+            let mut messages = BTreeMap::new();
+
+            messages.entry(user_id.to_owned()).or_insert_with(BTreeMap::new).insert(
+                DeviceIdOrAllDevices::DeviceId(device_id.into()),
+                to_raw_value(&encrypted)?,
+            );
+
+            let request = OutgoingRequest {
+                request_id: id,
+                request: Arc::new(
+                    ToDeviceRequest { event_type: EventType::RoomEncrypted, txn_id: id, messages }
+                        .into(),
+                ),
+            };
+
+            requests.push(request);
+            sent.push(message.id);
+            changes.sessions.push(session);
+        }
+
+        self.store.save_changes(changes).await?;
+
+        for request in requests {
+            self.queue_request(request).await?;
+        }
+
+        // The messages are only forgotten once the requests that carry them are
+        // in the store.
+        for id in sent {
+            self.store.remove_pending_to_device_message(id).await?;
+        }
+
+        Ok(())
+    }
+
     pub async fn mark_device_as_wedged(&self, sender: &UserId, curve_key: &str) -> StoreResult<()> {
         if let Some(device) = self.store.get_device_from_curve_key(sender, curve_key).await? {
-            let sessions = device.get_sessions().await?;
-
-            if let Some(sessions) = sessions {
-                let mut sessions = sessions.lock().await;
-                sessions.sort_by_key(|s| s.creation_time.clone());
-
-                let session = sessions.get(0);
-
-                if let Some(session) = session {
-                    if session.creation_time.elapsed() > Self::UNWEDGING_INTERVAL {
-                        self.users_for_key_claim
-                            .entry(device.user_id().clone())
-                            .or_insert_with(DashSet::new)
-                            .insert(device.device_id().into());
-                        self.wedged_devices
-                            .entry(device.user_id().to_owned())
-                            .or_insert_with(DashSet::new)
-                            .insert(device.device_id().into());
-                    }
+            let oldest_session_age = match device.get_sessions().await? {
+                Some(sessions) => {
+                    sessions.lock().await.iter().map(|s| elapsed_since(*s.creation_time)).max()
                 }
+                None => None,
+            };
+
+            // If we lost all our sessions with the device, e.g. because our
+            // store was cleared, a new one needs to be created right away.
+            let wedged = oldest_session_age.map_or(true, |age| age > Self::UNWEDGING_INTERVAL);
+
+            if wedged {
+                self.users_for_key_claim
+                    .entry(device.user_id().clone())
+                    .or_insert_with(DashSet::new)
+                    .insert(device.device_id().into());
+                self.wedged_devices
+                    .entry(device.user_id().to_owned())
+                    .or_insert_with(DashSet::new)
+                    .insert(device.device_id().into());
             }
         }
 
@@ -139,7 +442,7 @@ impl SessionManager {
                     ),
                 };
 
-                self.outgoing_to_device_requests.insert(id, request);
+                self.queue_request(request).await?;
             }
         }
 
@@ -221,9 +524,30 @@ impl SessionManager {
             }
         }
 
+        // Don't claim keys for devices that are already part of a key claim
+        // request that is in flight.
+        let now = Instant::now();
+
+        for (user_id, devices) in missing.iter_mut() {
+            devices.retain(|device_id, _| {
+                self.keys_claim_in_flight
+                    .get(&(user_id.clone(), device_id.clone()))
+                    .map(|t| now.duration_since(*t) >= Self::KEY_CLAIM_IN_FLIGHT_TIMEOUT)
+                    .unwrap_or(true)
+            });
+        }
+
+        missing.retain(|_, devices| !devices.is_empty());
+
         if missing.is_empty() {
             Ok(None)
         } else {
+            for (user_id, devices) in &missing {
+                for device_id in devices.keys() {
+                    self.keys_claim_in_flight.insert((user_id.clone(), device_id.clone()), now);
+                }
+            }
+
             Ok(Some((
                 Uuid::new_v4(),
                 assign!(KeysClaimRequest::new(missing), {
@@ -243,15 +567,19 @@ impl SessionManager {
         // TODO log the failures here
 
         let mut changes = Changes::default();
+        let mut established = Vec::new();
 
         for (user_id, user_devices) in &response.one_time_keys {
             for (device_id, key_map) in user_devices {
+                self.keys_claim_in_flight.remove(&(user_id.clone(), device_id.clone()));
+
                 let device = match self.store.get_readonly_device(user_id, device_id).await {
                     Ok(Some(d)) => d,
                     Ok(None) => {
                         warn!(
                             "Tried to create an Olm session for {} {}, but the device is unknown",
-                            user_id, device_id
+                            log_id(user_id.as_str()),
+                            log_id(device_id.as_str())
                         );
                         continue;
                     }
@@ -259,13 +587,19 @@ impl SessionManager {
                         warn!(
                             "Tried to create an Olm session for {} {}, but \
                             can't fetch the device from the store {:?}",
-                            user_id, device_id, e
+                            log_id(user_id.as_str()),
+                            log_id(device_id.as_str()),
+                            e
                         );
                         continue;
                     }
                 };
 
-                info!("Creating outbound Session for {} {}", user_id, device_id);
+                info!(
+                    "Creating outbound Session for {} {}",
+                    log_id(user_id.as_str()),
+                    log_id(device_id.as_str())
+                );
 
                 let session = match self.account.create_outbound_session(device, key_map).await {
                     Ok(s) => s,
@@ -276,19 +610,35 @@ impl SessionManager {
                 };
 
                 changes.sessions.push(session);
+                established.push((user_id.clone(), device_id.clone()));
 
                 self.key_request_machine.retry_keyshare(user_id, device_id);
 
                 if let Err(e) = self.check_if_unwedged(user_id, device_id).await {
                     error!(
                         "Error while treating an unwedged device {} {} {:?}",
-                        user_id, device_id, e
+                        log_id(user_id.as_str()),
+                        log_id(device_id.as_str()),
+                        e
                     );
                 }
             }
         }
 
-        Ok(self.store.save_changes(changes).await?)
+        self.store.save_changes(changes).await?;
+
+        for (user_id, device_id) in established {
+            if let Err(e) = self.send_pending_messages(&user_id, &device_id).await {
+                error!(
+                    "Error while sending pending to-device messages to {} {} {:?}",
+                    log_id(user_id.as_str()),
+                    log_id(device_id.as_str()),
+                    e
+                );
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -300,9 +650,10 @@ mod test {
     use matrix_sdk_common::locks::Mutex;
     use matrix_sdk_test::async_test;
     use ruma::{
-        api::client::r0::keys::claim_keys::Response as KeyClaimResponse, user_id, DeviceIdBox,
-        UserId,
+        api::client::r0::keys::claim_keys::Response as KeyClaimResponse, events::EventType,
+        user_id, DeviceIdBox, UserId,
     };
+    use serde_json::json;
 
     use super::SessionManager;
     use crate::{
@@ -396,12 +747,94 @@ mod test {
             .is_none());
     }
 
+    #[async_test]
+    async fn key_claims_in_flight_are_not_repeated() {
+        let manager = session_manager().await;
+        let bob = bob_account();
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        manager.store.save_devices(&[bob_device]).await.unwrap();
+
+        assert!(manager
+            .get_missing_sessions(&mut [bob.user_id().clone()].iter())
+            .await
+            .unwrap()
+            .is_some());
+
+        // A claim for the device is already in flight.
+        assert!(manager
+            .get_missing_sessions(&mut [bob.user_id().clone()].iter())
+            .await
+            .unwrap()
+            .is_none());
+
+        // The server didn't have a one-time key for the device, the claim is
+        // done and can be made again.
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys
+            .entry(bob.user_id().clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(bob.device_id().into(), BTreeMap::new());
+
+        let response = KeyClaimResponse::new(one_time_keys);
+        manager.receive_keys_claim_response(&response).await.unwrap();
+
+        assert!(manager
+            .get_missing_sessions(&mut [bob.user_id().clone()].iter())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[async_test]
+    async fn pending_messages_are_stored() {
+        let manager = session_manager().await;
+        let bob = bob_account();
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        manager.store.save_devices(&[bob_device]).await.unwrap();
+
+        let devices = [(bob.user_id().clone(), bob.device_id().into())];
+        let event_type = EventType::from("org.example.custom");
+
+        for _ in 0..SessionManager::MAX_PENDING_MESSAGES + 1 {
+            let request = manager
+                .encrypt_to_devices(&devices, event_type.clone(), json!({ "foo": "bar" }))
+                .await
+                .unwrap();
+            assert!(request.is_none());
+        }
+
+        // The wait queue is bounded.
+        let stored = manager.store.get_pending_to_device_messages().await.unwrap();
+        assert_eq!(stored.len(), SessionManager::MAX_PENDING_MESSAGES);
+
+        // The messages survive a restart.
+        manager.pending_to_device_messages.clear();
+        manager.users_for_key_claim.clear();
+        manager.load_pending_messages().await.unwrap();
+        assert!(manager.users_for_key_claim.contains_key(bob.user_id()));
+
+        bob.generate_one_time_keys_helper(1).await;
+        let one_time = bob.signed_one_time_keys_helper().await.unwrap();
+        bob.mark_keys_as_published().await;
+
+        let mut one_time_keys = BTreeMap::new();
+        one_time_keys
+            .entry(bob.user_id().clone())
+            .or_insert_with(BTreeMap::new)
+            .insert(bob.device_id().into(), one_time);
+
+        manager.receive_keys_claim_response(&KeyClaimResponse::new(one_time_keys)).await.unwrap();
+
+        assert_eq!(manager.outgoing_to_device_requests.len(), SessionManager::MAX_PENDING_MESSAGES);
+        assert!(manager.store.get_pending_to_device_messages().await.unwrap().is_empty());
+    }
+
     // This test doesn't run on macos because we're modifying the session
     // creation time so we can get around the UNWEDGING_INTERVAL.
     #[async_test]
     #[cfg(target_os = "linux")]
     async fn session_unwedging() {
-        use matrix_sdk_common::instant::{Duration, Instant};
+        use matrix_sdk_common::{instant::Duration, time::time_ago};
         use ruma::DeviceKeyAlgorithm;
 
         let manager = session_manager().await;
@@ -409,7 +842,7 @@ mod test {
         let (_, mut session) = bob.create_session_for(&manager.account).await;
 
         let bob_device = ReadOnlyDevice::from_account(&bob).await;
-        session.creation_time = Arc::new(Instant::now() - Duration::from_secs(3601));
+        session.creation_time = Arc::new(time_ago(Duration::from_secs(3601)));
 
         manager.store.save_devices(&[bob_device.clone()]).await.unwrap();
         manager.store.save_sessions(&[session]).await.unwrap();
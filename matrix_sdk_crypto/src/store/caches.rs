@@ -17,9 +17,16 @@
 //! Note: You'll only be interested in these if you are implementing a custom
 //! `CryptoStore`.
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use matrix_sdk_common::locks::Mutex;
 use ruma::{DeviceId, DeviceIdBox, RoomId, UserId};
 
@@ -28,32 +35,283 @@ use crate::{
     olm::{InboundGroupSession, Session},
 };
 
+/// Statistics about the identifiers an [`IdentifierInterner`] holds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InternerStats {
+    /// The number of unique user ids that are interned.
+    pub user_ids: usize,
+    /// The number of unique room ids that are interned.
+    pub room_ids: usize,
+    /// The number of unique device ids that are interned.
+    pub device_ids: usize,
+    /// The number of bytes the string representation of all the interned
+    /// identifiers takes up.
+    pub bytes: usize,
+    /// The number of lookups that were answered with an already interned
+    /// identifier, i.e. the number of allocations we avoided.
+    pub hits: usize,
+    /// The number of lookups that needed to allocate a new identifier.
+    pub misses: usize,
+}
+
+/// Interner for Matrix identifiers.
+///
+/// Large accounts end up storing the same user, room and device ids over and
+/// over again, once for every device, session and sync response that mentions
+/// them. The interner hands out shared `Arc` copies of identifiers so every
+/// user of an identifier points to the same allocation.
+#[derive(Debug, Default, Clone)]
+pub struct IdentifierInterner {
+    user_ids: Arc<DashSet<Arc<UserId>>>,
+    room_ids: Arc<DashSet<Arc<RoomId>>>,
+    device_ids: Arc<DashSet<Arc<DeviceId>>>,
+    hits: Arc<AtomicUsize>,
+    misses: Arc<AtomicUsize>,
+}
+
+impl IdentifierInterner {
+    /// Create a new empty interner.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the shared copy of the given user id, interning it if it isn't
+    /// known yet.
+    pub fn user_id(&self, user_id: &UserId) -> Arc<UserId> {
+        if let Some(interned) = self.user_ids.get(user_id) {
+            self.hit();
+            interned.key().clone()
+        } else {
+            self.miss();
+            let interned = Arc::new(user_id.clone());
+            self.user_ids.insert(interned.clone());
+            interned
+        }
+    }
+
+    /// Get the shared copy of the given room id, interning it if it isn't
+    /// known yet.
+    pub fn room_id(&self, room_id: &RoomId) -> Arc<RoomId> {
+        if let Some(interned) = self.room_ids.get(room_id) {
+            self.hit();
+            interned.key().clone()
+        } else {
+            self.miss();
+            let interned = Arc::new(room_id.clone());
+            self.room_ids.insert(interned.clone());
+            interned
+        }
+    }
+
+    /// Get the shared copy of the given device id, interning it if it isn't
+    /// known yet.
+    pub fn device_id(&self, device_id: &DeviceId) -> Arc<DeviceId> {
+        if let Some(interned) = self.device_ids.get(device_id) {
+            self.hit();
+            interned.key().clone()
+        } else {
+            self.miss();
+            let device_id: DeviceIdBox = device_id.into();
+            let interned: Arc<DeviceId> = device_id.into();
+            self.device_ids.insert(interned.clone());
+            interned
+        }
+    }
+
+    /// Get statistics about the identifiers this interner holds.
+    pub fn stats(&self) -> InternerStats {
+        let bytes = self.user_ids.iter().map(|u| u.as_str().len()).sum::<usize>()
+            + self.room_ids.iter().map(|r| r.as_str().len()).sum::<usize>()
+            + self.device_ids.iter().map(|d| d.as_str().len()).sum::<usize>();
+
+        InternerStats {
+            user_ids: self.user_ids.len(),
+            room_ids: self.room_ids.len(),
+            device_ids: self.device_ids.len(),
+            bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Bookkeeping for the least recently used entries of a bounded cache.
+#[derive(Debug)]
+struct LruTracker<K: Hash + Eq> {
+    last_used: DashMap<K, u64>,
+    clock: AtomicU64,
+    capacity: Option<usize>,
+}
+
+impl<K: Hash + Eq> Default for LruTracker<K> {
+    fn default() -> Self {
+        Self { last_used: DashMap::new(), clock: AtomicU64::new(0), capacity: None }
+    }
+}
+
+impl<K: Hash + Eq + Clone + Ord> LruTracker<K> {
+    fn with_capacity(capacity: usize) -> Self {
+        Self { capacity: Some(capacity), ..Self::default() }
+    }
+
+    fn touch(&self, key: &K) {
+        if self.capacity.is_some() {
+            let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+            self.last_used.insert(key.clone(), tick);
+        }
+    }
+
+    fn forget(&self, key: &K) {
+        self.last_used.remove(key);
+    }
+
+    /// Get the keys that need to be evicted to bring a cache holding `len`
+    /// entries back to our capacity, least recently used first.
+    ///
+    /// Only the given candidates are considered, entries that are currently
+    /// in use shouldn't be part of them.
+    fn overflow(&self, len: usize, candidates: impl Iterator<Item = K>) -> Vec<K> {
+        let capacity = if let Some(c) = self.capacity { c } else { return Vec::new() };
+
+        if len <= capacity {
+            return Vec::new();
+        }
+
+        let mut candidates: Vec<(u64, K)> = candidates
+            .map(|k| {
+                let tick = self.last_used.get(&k).map(|t| *t.value()).unwrap_or_default();
+                (tick, k)
+            })
+            .collect();
+
+        candidates.sort_unstable();
+
+        candidates.into_iter().take(len - capacity).map(|(_, k)| k).collect()
+    }
+
+    fn shrink_to_fit(&self) {
+        self.last_used.shrink_to_fit();
+    }
+
+    fn clear(&self) {
+        self.last_used.clear();
+    }
+}
+
 /// In-memory store for Olm Sessions.
+///
+/// The store can be given a capacity, in which case the least recently used
+/// sender keys are evicted once the capacity is exceeded. A capacity should
+/// only be set if the store is used as a cache in front of a persistent store
+/// which already contains all the sessions of the evicted sender keys.
 #[derive(Debug, Default, Clone)]
 pub struct SessionStore {
     entries: Arc<DashMap<String, Arc<Mutex<Vec<Session>>>>>,
+    lru: Arc<LruTracker<String>>,
 }
 
 impl SessionStore {
     /// Create a new empty Session store.
     pub fn new() -> Self {
-        SessionStore { entries: Arc::new(DashMap::new()) }
+        Self::default()
+    }
+
+    /// Create a new empty Session store that holds the sessions of at most
+    /// `capacity` sender keys.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self { lru: LruTracker::with_capacity(capacity).into(), ..Self::default() }
+    }
+
+    /// Remove the least recently used sender keys until we're back at our
+    /// capacity.
+    ///
+    /// Sessions that are currently in use won't be evicted.
+    fn evict(&self) {
+        let candidates: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|e| Arc::strong_count(e.value()) == 1)
+            .map(|e| e.key().clone())
+            .collect();
+
+        for sender_key in self.lru.overflow(self.entries.len(), candidates.into_iter()) {
+            self.remove(&sender_key);
+        }
+    }
+
+    fn remove(&self, sender_key: &str) {
+        if self.entries.remove_if(sender_key, |_, s| Arc::strong_count(s) == 1).is_some() {
+            self.lru.forget(&sender_key.to_owned());
+        }
+    }
+
+    /// Put the session into the list of sessions, replacing the copy of the
+    /// session that the list already contains.
+    ///
+    /// Returns true if the session wasn't in the list before.
+    fn replace_or_push(sessions: &mut Vec<Session>, session: Session) -> bool {
+        if let Some(existing) = sessions.iter_mut().find(|s| **s == session) {
+            *existing = session;
+            false
+        } else {
+            sessions.push(session);
+            true
+        }
     }
 
     /// Add a session to the store.
     ///
+    /// If the store already contains a copy of the session, the copy is
+    /// replaced with the given session.
+    ///
     /// Returns true if the session was added, false if the session was
     /// already in the store.
     pub async fn add(&self, session: Session) -> bool {
+        let sender_key = session.sender_key.to_string();
+
         let sessions_lock = self
             .entries
-            .entry(session.sender_key.to_string())
-            .or_insert_with(|| Arc::new(Mutex::new(Vec::new())));
+            .entry(sender_key.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(Vec::new())))
+            .clone();
 
-        let mut sessions = sessions_lock.lock().await;
+        let added = Self::replace_or_push(&mut *sessions_lock.lock().await, session);
+
+        drop(sessions_lock);
+
+        self.lru.touch(&sender_key);
+        self.evict();
+
+        added
+    }
+
+    /// Update the sessions of the session's sender key with the given session.
+    ///
+    /// Unlike [`SessionStore::add`] this won't add the session if the store
+    /// doesn't hold the sessions of the sender key, e.g. because they were
+    /// evicted. Caches should use this after a session was persisted, adding
+    /// the session would leave the cache with an incomplete list of sessions
+    /// for the sender key.
+    ///
+    /// Returns true if the store was updated.
+    pub async fn update(&self, session: Session) -> bool {
+        #[allow(clippy::map_clone)]
+        let sessions_lock = self.entries.get(session.sender_key()).map(|s| s.clone());
+
+        if let Some(sessions_lock) = sessions_lock {
+            let sender_key = session.sender_key().to_owned();
+            Self::replace_or_push(&mut *sessions_lock.lock().await, session);
+
+            self.lru.touch(&sender_key);
 
-        if !sessions.contains(&session) {
-            sessions.push(session);
             true
         } else {
             false
@@ -63,26 +321,137 @@ impl SessionStore {
     /// Get all the sessions that belong to the given sender key.
     pub fn get(&self, sender_key: &str) -> Option<Arc<Mutex<Vec<Session>>>> {
         #[allow(clippy::map_clone)]
-        self.entries.get(sender_key).map(|s| s.clone())
+        let sessions = self.entries.get(sender_key).map(|s| s.clone());
+
+        if sessions.is_some() {
+            self.lru.touch(&sender_key.to_owned());
+        }
+
+        sessions
     }
 
     /// Add a list of sessions belonging to the sender key.
-    pub fn set_for_sender(&self, sender_key: &str, sessions: Vec<Session>) {
-        self.entries.insert(sender_key.to_owned(), Arc::new(Mutex::new(sessions)));
+    ///
+    /// If the store already holds sessions for the sender key, the sessions
+    /// the store holds are kept and only the missing ones are added. Other
+    /// users of the store might hold the existing list and the sessions in it
+    /// are at least as new as the given ones.
+    pub async fn set_for_sender(&self, sender_key: &str, sessions: Vec<Session>) {
+        let sessions_lock = self
+            .entries
+            .entry(sender_key.to_owned())
+            .or_insert_with(|| Arc::new(Mutex::new(Vec::new())))
+            .clone();
+
+        {
+            let mut existing = sessions_lock.lock().await;
+
+            for session in sessions {
+                if !existing.contains(&session) {
+                    existing.push(session);
+                }
+            }
+        }
+
+        drop(sessions_lock);
+
+        self.lru.touch(&sender_key.to_owned());
+        self.evict();
+    }
+
+    /// Get all the sessions the store holds.
+    pub async fn get_all(&self) -> Vec<Session> {
+        let entries: Vec<Arc<Mutex<Vec<Session>>>> =
+            self.entries.iter().map(|e| e.value().clone()).collect();
+
+        let mut sessions = Vec::new();
+
+        for entry in entries {
+            sessions.extend(entry.lock().await.iter().cloned());
+        }
+
+        sessions
+    }
+
+    /// Get the number of sender keys this store holds sessions for.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Is the store empty.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove all the sessions that are currently not in use and release the
+    /// memory the store holds on to.
+    ///
+    /// This should only be called if the store is used as a cache in front of
+    /// a persistent store.
+    pub fn shrink_to_fit(&self) {
+        let unused: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|e| Arc::strong_count(e.value()) == 1)
+            .map(|e| e.key().clone())
+            .collect();
+
+        for sender_key in unused {
+            self.remove(&sender_key);
+        }
+
+        self.entries.shrink_to_fit();
+        self.lru.shrink_to_fit();
+    }
+
+    /// Remove all the sessions from the store.
+    pub fn clear(&self) {
+        self.entries.clear();
+        self.lru.clear();
     }
 }
 
 #[derive(Debug, Default, Clone)]
 /// In-memory store that holds inbound group sessions.
+///
+/// Like the [`SessionStore`], the store can be given a capacity, in which case
+/// the sessions of the least recently used rooms are evicted once the capacity
+/// is exceeded.
 pub struct GroupSessionStore {
     #[allow(clippy::type_complexity)]
-    entries: Arc<DashMap<RoomId, HashMap<String, HashMap<String, InboundGroupSession>>>>,
+    entries: Arc<DashMap<Arc<RoomId>, HashMap<String, HashMap<String, InboundGroupSession>>>>,
+    interner: IdentifierInterner,
+    lru: Arc<LruTracker<Arc<RoomId>>>,
 }
 
 impl GroupSessionStore {
     /// Create a new empty store.
     pub fn new() -> Self {
-        GroupSessionStore { entries: Arc::new(DashMap::new()) }
+        Self::with_interner(IdentifierInterner::new())
+    }
+
+    /// Create a new empty store that uses the given interner for its room ids.
+    pub fn with_interner(interner: IdentifierInterner) -> Self {
+        GroupSessionStore { entries: Arc::new(DashMap::new()), interner, lru: Default::default() }
+    }
+
+    /// Create a new empty store that holds the sessions of at most `capacity`
+    /// rooms.
+    pub fn with_capacity(interner: IdentifierInterner, capacity: usize) -> Self {
+        GroupSessionStore {
+            entries: Arc::new(DashMap::new()),
+            interner,
+            lru: LruTracker::with_capacity(capacity).into(),
+        }
+    }
+
+    fn evict(&self) {
+        let candidates: Vec<Arc<RoomId>> = self.entries.iter().map(|e| e.key().clone()).collect();
+
+        for room_id in self.lru.overflow(self.entries.len(), candidates.into_iter()) {
+            self.entries.remove(&room_id);
+            self.lru.forget(&room_id);
+        }
     }
 
     /// Add an inbound group session to the store.
@@ -90,13 +459,21 @@ impl GroupSessionStore {
     /// Returns true if the session was added, false if the session was
     /// already in the store.
     pub fn add(&self, session: InboundGroupSession) -> bool {
-        self.entries
-            .entry((&*session.room_id).clone())
+        let room_id = self.interner.room_id(&session.room_id);
+
+        let added = self
+            .entries
+            .entry(room_id.clone())
             .or_insert_with(HashMap::new)
             .entry(session.sender_key.to_string())
             .or_insert_with(HashMap::new)
             .insert(session.session_id().to_owned(), session)
-            .is_none()
+            .is_none();
+
+        self.lru.touch(&room_id);
+        self.evict();
+
+        added
     }
 
     /// Get all the group sessions the store knows about.
@@ -126,39 +503,90 @@ impl GroupSessionStore {
         sender_key: &str,
         session_id: &str,
     ) -> Option<InboundGroupSession> {
-        self.entries
-            .get(room_id)
-            .and_then(|m| m.get(sender_key).and_then(|m| m.get(session_id).cloned()))
+        let sessions = self.entries.get(room_id)?;
+        self.lru.touch(sessions.key());
+
+        sessions.get(sender_key).and_then(|m| m.get(session_id).cloned())
+    }
+
+    /// Remove all the group sessions from the store.
+    pub fn clear(&self) {
+        self.entries.clear();
+        self.lru.clear();
     }
 }
 
 /// In-memory store holding the devices of users.
+///
+/// Like the [`SessionStore`], the store can be given a capacity, in which case
+/// the devices of the least recently used users are evicted once the capacity
+/// is exceeded.
 #[derive(Clone, Debug, Default)]
 pub struct DeviceStore {
-    entries: Arc<DashMap<UserId, DashMap<Box<DeviceId>, ReadOnlyDevice>>>,
+    entries: Arc<DashMap<Arc<UserId>, DashMap<Arc<DeviceId>, ReadOnlyDevice>>>,
+    interner: IdentifierInterner,
+    lru: Arc<LruTracker<Arc<UserId>>>,
 }
 
 impl DeviceStore {
     /// Create a new empty device store.
     pub fn new() -> Self {
-        DeviceStore { entries: Arc::new(DashMap::new()) }
+        Self::with_interner(IdentifierInterner::new())
+    }
+
+    /// Create a new empty device store that uses the given interner for the
+    /// user and device ids of the stored devices.
+    pub fn with_interner(interner: IdentifierInterner) -> Self {
+        DeviceStore { entries: Arc::new(DashMap::new()), interner, lru: Default::default() }
+    }
+
+    /// Create a new empty device store that holds the devices of at most
+    /// `capacity` users.
+    pub fn with_capacity(interner: IdentifierInterner, capacity: usize) -> Self {
+        DeviceStore {
+            entries: Arc::new(DashMap::new()),
+            interner,
+            lru: LruTracker::with_capacity(capacity).into(),
+        }
+    }
+
+    fn evict(&self) {
+        let candidates: Vec<Arc<UserId>> = self.entries.iter().map(|e| e.key().clone()).collect();
+
+        for user_id in self.lru.overflow(self.entries.len(), candidates.into_iter()) {
+            self.entries.remove(&user_id);
+            self.lru.forget(&user_id);
+        }
     }
 
     /// Add a device to the store.
     ///
     /// Returns true if the device was already in the store, false otherwise.
     pub fn add(&self, device: ReadOnlyDevice) -> bool {
-        let user_id = device.user_id();
-        self.entries
-            .entry(user_id.to_owned())
+        let device = device.with_interned_ids(&self.interner);
+        let user_id = self.interner.user_id(device.user_id());
+        let device_id = self.interner.device_id(device.device_id());
+
+        let added = self
+            .entries
+            .entry(user_id.clone())
             .or_insert_with(DashMap::new)
-            .insert(device.device_id().into(), device)
-            .is_none()
+            .insert(device_id, device)
+            .is_none();
+
+        self.lru.touch(&user_id);
+        self.evict();
+
+        added
     }
 
     /// Get the device with the given device_id and belonging to the given user.
     pub fn get(&self, user_id: &UserId, device_id: &DeviceId) -> Option<ReadOnlyDevice> {
-        self.entries.get(user_id).and_then(|m| m.get(device_id).map(|d| d.value().clone()))
+        let devices = self.entries.get(user_id)?;
+        self.lru.touch(devices.key());
+
+        let device = devices.get(device_id).map(|d| d.value().clone());
+        device
     }
 
     /// Remove the device with the given device_id and belonging to the given
@@ -171,13 +599,27 @@ impl DeviceStore {
 
     /// Get a read-only view over all devices of the given user.
     pub fn user_devices(&self, user_id: &UserId) -> HashMap<DeviceIdBox, ReadOnlyDevice> {
+        if let Some(devices) = self.entries.get(user_id) {
+            self.lru.touch(devices.key());
+            devices.iter().map(|i| ((&**i.key()).into(), i.value().clone())).collect()
+        } else {
+            HashMap::new()
+        }
+    }
+
+    /// Get all the devices the store holds.
+    pub fn get_all(&self) -> Vec<ReadOnlyDevice> {
         self.entries
-            .entry(user_id.clone())
-            .or_insert_with(DashMap::new)
             .iter()
-            .map(|i| (i.key().to_owned(), i.value().clone()))
+            .flat_map(|u| u.value().iter().map(|d| d.value().clone()).collect::<Vec<_>>())
             .collect()
     }
+
+    /// Remove all the devices from the store.
+    pub fn clear(&self) {
+        self.entries.clear();
+        self.lru.clear();
+    }
 }
 
 #[cfg(test)]
@@ -187,7 +629,7 @@ mod test {
     use crate::{
         identities::device::test::get_device,
         olm::{test::get_account_and_session, InboundGroupSession},
-        store::caches::{DeviceStore, GroupSessionStore, SessionStore},
+        store::caches::{DeviceStore, GroupSessionStore, IdentifierInterner, SessionStore},
     };
 
     #[tokio::test]
@@ -212,7 +654,7 @@ mod test {
         let (_, session) = get_account_and_session().await;
 
         let store = SessionStore::new();
-        store.set_for_sender(&session.sender_key, vec![session.clone()]);
+        store.set_for_sender(&session.sender_key, vec![session.clone()]).await;
 
         let sessions = store.get(&session.sender_key).unwrap();
         let sessions = sessions.lock().await;
@@ -222,6 +664,63 @@ mod test {
         assert_eq!(&session, loaded_session);
     }
 
+    #[tokio::test]
+    async fn test_session_store_eviction() {
+        let (_, session) = get_account_and_session().await;
+
+        let store = SessionStore::with_capacity(1);
+        store.set_for_sender("first", vec![session.clone()]).await;
+        store.set_for_sender("second", vec![session.clone()]).await;
+
+        assert_eq!(store.len(), 1);
+        assert!(store.get("first").is_none());
+        assert!(store.get("second").is_some());
+
+        let sessions = store.get("second").unwrap();
+        store.shrink_to_fit();
+        assert_eq!(store.len(), 1);
+
+        drop(sessions);
+        store.shrink_to_fit();
+        assert!(store.is_empty());
+
+        // Updating a session of an evicted sender key must not leave an
+        // incomplete list of sessions behind.
+        assert!(!store.update(session.clone()).await);
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_session_store_replaces_sessions() {
+        let (_, session) = get_account_and_session().await;
+
+        let store = SessionStore::new();
+        store.add(session.clone()).await;
+
+        let sessions = store.get(&session.sender_key).unwrap();
+        let cached = sessions.lock().await[0].clone();
+        drop(sessions);
+
+        let mut newer = session.clone();
+        newer.creation_time = std::sync::Arc::new(ruma::MilliSecondsSinceUnixEpoch::now());
+        assert!(!store.add(newer.clone()).await);
+
+        let sessions = store.get(&session.sender_key).unwrap();
+        let sessions = sessions.lock().await;
+        assert_eq!(sessions.len(), 1);
+        assert!(!std::sync::Arc::ptr_eq(&sessions[0].creation_time, &cached.creation_time));
+        assert!(std::sync::Arc::ptr_eq(&sessions[0].creation_time, &newer.creation_time));
+    }
+
+    #[tokio::test]
+    async fn test_device_store_eviction() {
+        let device = get_device();
+        let store = DeviceStore::with_capacity(IdentifierInterner::new(), 0);
+
+        store.add(device.clone());
+        assert!(store.get(device.user_id(), device.device_id()).is_none());
+    }
+
     #[tokio::test]
     async fn test_group_session_store() {
         let (account, _) = get_account_and_session().await;
@@ -277,4 +776,27 @@ mod test {
         let loaded_device = store.get(device.user_id(), device.device_id());
         assert!(loaded_device.is_none());
     }
+
+    #[tokio::test]
+    async fn test_interner() {
+        let device = get_device();
+        let interner = IdentifierInterner::new();
+        let store = DeviceStore::with_interner(interner.clone());
+
+        store.add(device.clone());
+
+        let first = interner.user_id(device.user_id());
+        let second = interner.user_id(device.user_id());
+        assert!(std::sync::Arc::ptr_eq(&first, &second));
+
+        let stats = interner.stats();
+        assert_eq!(stats.user_ids, 1);
+        assert_eq!(stats.device_ids, 1);
+        assert_eq!(stats.room_ids, 0);
+        assert!(stats.hits >= 2);
+        assert_eq!(
+            stats.bytes,
+            device.user_id().as_str().len() + device.device_id().as_str().len()
+        );
+    }
 }
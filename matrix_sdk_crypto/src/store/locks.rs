@@ -0,0 +1,312 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A lock that serializes the access to a crypto store between processes.
+//!
+//! Mobile platforms run a notification service extension in a separate
+//! process next to the main application and both of them open the same crypto
+//! store. The lock is a lease stored in the database itself, whoever holds the
+//! lease is allowed to write to the store until the lease expires.
+
+use std::{
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use futures::lock::{Mutex, MutexGuard};
+use matrix_sdk_common::time::sleep;
+use ruma::MilliSecondsSinceUnixEpoch;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::{CryptoStore, Result};
+
+/// A lease on a lock, as it's persisted by a [`CryptoStore`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Lease {
+    holder: String,
+    expiration: u64,
+    generation: u64,
+}
+
+impl Lease {
+    /// Try to take over or to extend the given lease for the given holder.
+    ///
+    /// Returns the new lease if the holder got it, the lease is given out if
+    /// there's no current lease, if it expired or if it already belongs to the
+    /// holder. The generation of the lease is bumped every time the lease
+    /// changes hands.
+    pub(crate) fn take(
+        current: Option<&Lease>,
+        holder: &str,
+        lease_duration_ms: u32,
+    ) -> Option<Self> {
+        let now: u64 = MilliSecondsSinceUnixEpoch::now().get().into();
+        let expiration = now + u64::from(lease_duration_ms);
+
+        match current {
+            None => Some(Self { holder: holder.to_owned(), expiration, generation: 0 }),
+            Some(lease) if lease.holder == holder => {
+                Some(Self { holder: holder.to_owned(), expiration, generation: lease.generation })
+            }
+            Some(lease) if lease.expiration <= now => Some(Self {
+                holder: holder.to_owned(),
+                expiration,
+                generation: lease.generation.wrapping_add(1),
+            }),
+            Some(_) => None,
+        }
+    }
+
+    /// Give up the lease if it belongs to the given holder.
+    ///
+    /// Returns the expired lease that should replace this one. The lease is
+    /// kept around instead of being removed so its generation isn't lost.
+    pub(crate) fn release(&self, holder: &str) -> Option<Self> {
+        (self.holder == holder).then(|| Self { expiration: 0, ..self.clone() })
+    }
+
+    /// The generation of the lease.
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+/// A lock that serializes the access to a [`CryptoStore`] between multiple
+/// processes that open the same store.
+///
+/// Every process uses a different holder name. The lock is a lease in the
+/// store, if a process dies while holding the lock the lease will expire and
+/// other processes can take it over.
+///
+/// Since other processes might have modified the store while they held the
+/// lock, objects that were cached in memory might be outdated. The guard
+/// tells if this might be the case with
+/// [`is_dirty()`](CrossProcessStoreLockGuard::is_dirty).
+#[derive(Clone, Debug)]
+pub struct CrossProcessStoreLock {
+    store: Arc<dyn CryptoStore>,
+    key: Arc<str>,
+    holder: Arc<str>,
+    lease_duration_ms: u32,
+    /// Serializes the users of the lock inside of this process, the lease
+    /// doesn't distinguish between them since they share the holder.
+    local_lock: Arc<Mutex<()>>,
+    /// The generation of the lease the last time we acquired it.
+    generation: Arc<StdMutex<Option<u64>>>,
+}
+
+impl CrossProcessStoreLock {
+    /// The duration a lease is valid for unless configured otherwise.
+    pub const DEFAULT_LEASE_DURATION_MS: u32 = 2000;
+
+    /// How long we wait before retrying to take the lease if another process
+    /// holds it.
+    const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+    /// Create a new lock for the given store.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store that persists the lease.
+    ///
+    /// * `key` - The name of the lock, processes using the same key exclude
+    /// each other.
+    ///
+    /// * `holder` - The unique name of this process.
+    pub fn new(store: Arc<dyn CryptoStore>, key: &str, holder: &str) -> Self {
+        Self {
+            store,
+            key: key.into(),
+            holder: holder.into(),
+            lease_duration_ms: Self::DEFAULT_LEASE_DURATION_MS,
+            local_lock: Mutex::new(()).into(),
+            generation: StdMutex::new(None).into(),
+        }
+    }
+
+    /// Set how long a lease stays valid after it has been taken or renewed.
+    ///
+    /// A lease that is held for longer needs to be renewed using
+    /// [`CrossProcessStoreLockGuard::keep_alive()`].
+    pub fn lease_duration_ms(mut self, lease_duration_ms: u32) -> Self {
+        self.lease_duration_ms = lease_duration_ms;
+        self
+    }
+
+    /// The unique name of this process.
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Try to acquire the lock once.
+    ///
+    /// Returns `None` if another process holds the lock.
+    pub async fn try_lock_once(&self) -> Result<Option<CrossProcessStoreLockGuard<'_>>> {
+        let guard = self.local_lock.lock().await;
+
+        Ok(self
+            .store
+            .try_take_leased_lock(self.lease_duration_ms, &self.key, &self.holder)
+            .await?
+            .map(|generation| self.acquired(guard, generation)))
+    }
+
+    /// Acquire the lock, waiting until other processes release it or until
+    /// their lease expires.
+    pub async fn lock(&self) -> Result<CrossProcessStoreLockGuard<'_>> {
+        loop {
+            if let Some(guard) = self.try_lock_once().await? {
+                return Ok(guard);
+            }
+
+            sleep(Self::RETRY_DELAY).await;
+        }
+    }
+
+    fn acquired<'a>(
+        &'a self,
+        guard: MutexGuard<'a, ()>,
+        generation: u64,
+    ) -> CrossProcessStoreLockGuard<'a> {
+        let previous = self.generation.lock().unwrap().replace(generation);
+        let dirty = previous != Some(generation);
+
+        if dirty {
+            debug!(
+                key = self.key.as_ref(),
+                holder = self.holder.as_ref(),
+                generation,
+                "Acquired the cross process store lock after another holder held it"
+            );
+        }
+
+        CrossProcessStoreLockGuard { lock: self, _guard: guard, dirty }
+    }
+}
+
+/// A guard that holds a [`CrossProcessStoreLock`].
+///
+/// The lease isn't given up once the guard is dropped, it expires by itself,
+/// use [`release()`](#method.release) to let other processes take the lock
+/// right away.
+#[derive(Debug)]
+pub struct CrossProcessStoreLockGuard<'a> {
+    lock: &'a CrossProcessStoreLock,
+    _guard: MutexGuard<'a, ()>,
+    dirty: bool,
+}
+
+impl CrossProcessStoreLockGuard<'_> {
+    /// Did another process hold the lock since we held it the last time.
+    ///
+    /// If this is true, objects that were cached in memory need to be loaded
+    /// from the store again. This is always true the first time the lock is
+    /// acquired.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Keep renewing the lease while the lock is held.
+    ///
+    /// The lease is renewed every half lease duration, the returned future
+    /// runs until the lease couldn't be renewed, either because the store
+    /// returned an error or because another process took the lease over in
+    /// the meantime, in which case `Ok(())` is returned. It should be polled
+    /// next to the operation the lock protects and dropped once the operation
+    /// is done.
+    pub async fn keep_alive(&self) -> Result<()> {
+        let lock = self.lock;
+        let interval = Duration::from_millis(u64::from(lock.lease_duration_ms / 2).max(1));
+
+        loop {
+            sleep(interval).await;
+
+            let generation = lock
+                .store
+                .try_take_leased_lock(lock.lease_duration_ms, &lock.key, &lock.holder)
+                .await?;
+
+            if generation.is_none() || generation != *lock.generation.lock().unwrap() {
+                warn!(
+                    key = lock.key.as_ref(),
+                    holder = lock.holder.as_ref(),
+                    "Couldn't renew the lease of the cross process store lock"
+                );
+
+                return Ok(());
+            }
+        }
+    }
+
+    /// Release the lock, allowing other processes to take it.
+    pub async fn release(self) -> Result<()> {
+        self.lock.store.release_leased_lock(&self.lock.key, &self.lock.holder).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use futures::future::{select, Either};
+    use matrix_sdk_common::time::sleep;
+    use matrix_sdk_test::async_test;
+
+    use super::CrossProcessStoreLock;
+    use crate::store::{CryptoStore, MemoryStore};
+
+    #[async_test]
+    async fn cross_process_lock() {
+        let store: Arc<dyn CryptoStore> = Arc::new(MemoryStore::new());
+
+        let app = CrossProcessStoreLock::new(store.clone(), "crypto", "app");
+        let extension = CrossProcessStoreLock::new(store, "crypto", "extension");
+
+        let guard = app.try_lock_once().await.unwrap().unwrap();
+        assert!(guard.is_dirty());
+        assert!(extension.try_lock_once().await.unwrap().is_none());
+        drop(guard);
+
+        let guard = app.try_lock_once().await.unwrap().unwrap();
+        assert!(!guard.is_dirty());
+        guard.release().await.unwrap();
+
+        let guard = extension.lock().await.unwrap();
+        assert!(guard.is_dirty());
+        guard.release().await.unwrap();
+
+        let guard = app.lock().await.unwrap();
+        assert!(guard.is_dirty());
+    }
+
+    #[async_test]
+    async fn lease_renewal() {
+        let store: Arc<dyn CryptoStore> = Arc::new(MemoryStore::new());
+
+        let app = CrossProcessStoreLock::new(store.clone(), "crypto", "app").lease_duration_ms(50);
+        let extension = CrossProcessStoreLock::new(store, "crypto", "extension");
+
+        let guard = app.lock().await.unwrap();
+
+        // The lease would have expired twice without the renewal.
+        let renewal = guard.keep_alive();
+        futures::pin_mut!(renewal);
+        let timeout = sleep(Duration::from_millis(120));
+        futures::pin_mut!(timeout);
+        assert!(matches!(select(renewal, timeout).await, Either::Right(_)));
+
+        assert!(extension.try_lock_once().await.unwrap().is_none());
+    }
+}
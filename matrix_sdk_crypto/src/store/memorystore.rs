@@ -14,30 +14,92 @@
 
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
+    convert::TryFrom,
+    sync::{Arc, RwLock as StdRwLock},
 };
 
-use dashmap::{DashMap, DashSet};
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+use futures::stream;
 use matrix_sdk_common::{async_trait, locks::Mutex, uuid::Uuid};
 use ruma::{events::room_key_request::RequestedKeyInfo, DeviceId, DeviceIdBox, RoomId, UserId};
+use serde::{Deserialize, Serialize};
 
 use super::{
-    caches::{DeviceStore, GroupSessionStore, SessionStore},
-    Changes, CryptoStore, InboundGroupSession, ReadOnlyAccount, Result, Session,
+    caches::{DeviceStore, GroupSessionStore, IdentifierInterner, InternerStats, SessionStore},
+    Changes, CryptoStore, CryptoStoreError, DeviceChanges, EncryptedPickleKey, IdentityChanges,
+    InboundGroupSession, InboundGroupSessionStream, Lease, PickleKey, ReadOnlyAccount, Result,
+    Session,
 };
 use crate::{
     identities::{ReadOnlyDevice, UserIdentities},
     key_request::OutgoingKeyRequest,
-    olm::{OutboundGroupSession, PrivateCrossSigningIdentity},
+    olm::{
+        OlmMessageHash, OutboundGroupSession, PickledAccount, PickledCrossSigningIdentity,
+        PickledInboundGroupSession, PickledSession, PrivateCrossSigningIdentity,
+        RoomKeyWithheldInfo, SharingHistoryEntry, StoredRoomKeyBundleData,
+    },
+    requests::StoredOutgoingRequest,
+    session_manager::PendingToDeviceMessage,
+    to_device_inbox::StoredToDeviceEvent,
+    types::Curve25519PublicKey,
+    utilities::{decode, encode},
+    verification::StoredVerificationFlow,
 };
 
+/// The pickle key that is used for snapshots that aren't protected by a
+/// passphrase.
+const DEFAULT_PICKLE: &str = "DEFAULT_PICKLE_PASSPHRASE_123456";
+
 fn encode_key_info(info: &RequestedKeyInfo) -> String {
     format!("{}{}{}{}", info.room_id, info.sender_key, info.algorithm, info.session_id)
 }
 
+/// A snapshot of a `MemoryStore`, as it's returned by
+/// [`MemoryStore::snapshot()`].
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    /// The key that was used to pickle the content of the snapshot, encrypted
+    /// with the passphrase. `None` if the snapshot isn't protected by a
+    /// passphrase.
+    pickle_key: Option<EncryptedPickleKey>,
+    /// The base64 encoded `SnapshotContent`, it's additionally encrypted with
+    /// the pickle key if the snapshot is protected by a passphrase.
+    content: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotContent {
+    account: Option<PickledAccount>,
+    private_identity: Option<PickledCrossSigningIdentity>,
+    sessions: Vec<PickledSession>,
+    inbound_group_sessions: Vec<PickledInboundGroupSession>,
+    tracked_users: Vec<UserId>,
+    users_for_key_query: Vec<UserId>,
+    message_hashes: Vec<OlmMessageHash>,
+    devices: Vec<ReadOnlyDevice>,
+    identities: Vec<UserIdentities>,
+    outgoing_key_requests: Vec<OutgoingKeyRequest>,
+    sharing_history: Vec<SharingHistoryEntry>,
+    custom_values: HashMap<String, Vec<u8>>,
+    #[serde(default)]
+    room_key_bundles: Vec<StoredRoomKeyBundleData>,
+    #[serde(default)]
+    verification_flows: Vec<StoredVerificationFlow>,
+    #[serde(default)]
+    outgoing_requests: Vec<StoredOutgoingRequest>,
+    #[serde(default)]
+    inbox_events: Vec<StoredToDeviceEvent>,
+    #[serde(default)]
+    pending_to_device_messages: Vec<PendingToDeviceMessage>,
+    #[serde(default)]
+    withheld_info: Vec<RoomKeyWithheldInfo>,
+}
+
 /// An in-memory only store that will forget all the E2EE key once it's dropped.
 #[derive(Debug, Clone)]
 pub struct MemoryStore {
+    account: Arc<StdRwLock<Option<ReadOnlyAccount>>>,
+    private_identity: Arc<StdRwLock<Option<PrivateCrossSigningIdentity>>>,
     sessions: SessionStore,
     inbound_group_sessions: GroupSessionStore,
     tracked_users: Arc<DashSet<UserId>>,
@@ -47,20 +109,44 @@ pub struct MemoryStore {
     identities: Arc<DashMap<UserId, UserIdentities>>,
     outgoing_key_requests: Arc<DashMap<Uuid, OutgoingKeyRequest>>,
     key_requests_by_info: Arc<DashMap<String, Uuid>>,
+    sharing_history: Arc<DashMap<String, Vec<SharingHistoryEntry>>>,
+    room_key_bundles: Arc<DashMap<RoomId, HashMap<UserId, StoredRoomKeyBundleData>>>,
+    verification_flows: Arc<DashMap<String, StoredVerificationFlow>>,
+    outgoing_requests: Arc<DashMap<Uuid, StoredOutgoingRequest>>,
+    inbox_events: Arc<DashMap<Uuid, StoredToDeviceEvent>>,
+    pending_to_device_messages: Arc<DashMap<Uuid, PendingToDeviceMessage>>,
+    withheld_info: Arc<DashMap<(RoomId, String), RoomKeyWithheldInfo>>,
+    leases: Arc<DashMap<String, Lease>>,
+    custom_values: Arc<DashMap<String, Vec<u8>>>,
+    interner: IdentifierInterner,
 }
 
 impl Default for MemoryStore {
     fn default() -> Self {
+        let interner = IdentifierInterner::new();
+
         MemoryStore {
+            account: Arc::new(StdRwLock::new(None)),
+            private_identity: Arc::new(StdRwLock::new(None)),
             sessions: SessionStore::new(),
-            inbound_group_sessions: GroupSessionStore::new(),
+            inbound_group_sessions: GroupSessionStore::with_interner(interner.clone()),
             tracked_users: Arc::new(DashSet::new()),
             users_for_key_query: Arc::new(DashSet::new()),
             olm_hashes: Arc::new(DashMap::new()),
-            devices: DeviceStore::new(),
+            devices: DeviceStore::with_interner(interner.clone()),
             identities: Arc::new(DashMap::new()),
             outgoing_key_requests: Arc::new(DashMap::new()),
             key_requests_by_info: Arc::new(DashMap::new()),
+            sharing_history: Arc::new(DashMap::new()),
+            room_key_bundles: Arc::new(DashMap::new()),
+            verification_flows: Arc::new(DashMap::new()),
+            outgoing_requests: Arc::new(DashMap::new()),
+            inbox_events: Arc::new(DashMap::new()),
+            pending_to_device_messages: Arc::new(DashMap::new()),
+            withheld_info: Arc::new(DashMap::new()),
+            leases: Arc::new(DashMap::new()),
+            custom_values: Arc::new(DashMap::new()),
+            interner,
         }
     }
 }
@@ -71,6 +157,238 @@ impl MemoryStore {
         Self::default()
     }
 
+    /// Get statistics about the interned user, room and device ids this store
+    /// holds.
+    pub fn interner_stats(&self) -> InternerStats {
+        self.interner.stats()
+    }
+
+    fn default_pickle_key() -> PickleKey {
+        PickleKey::try_from(DEFAULT_PICKLE.as_bytes().to_vec())
+            .expect("Can't create default pickle key")
+    }
+
+    /// Serialize the whole content of the store, so it can be persisted and
+    /// later on restored using [`restore()`](#method.restore).
+    ///
+    /// This allows tests and short-lived deployments to keep their state
+    /// without a database backend. Outbound group sessions aren't part of the
+    /// snapshot since the store doesn't hold on to them.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that should be used to encrypt the
+    /// snapshot. If no passphrase is given, the snapshot will contain the
+    /// private keys in an unencrypted form.
+    pub async fn snapshot(&self, passphrase: Option<&str>) -> Result<Vec<u8>> {
+        let (pickle_key, encrypted_pickle_key) = if let Some(passphrase) = passphrase {
+            let pickle_key = PickleKey::new();
+            let encrypted = pickle_key.encrypt(passphrase);
+
+            (pickle_key, Some(encrypted))
+        } else {
+            (Self::default_pickle_key(), None)
+        };
+
+        let account = self.account.read().unwrap().clone();
+        let account = if let Some(a) = account {
+            Some(a.pickle(pickle_key.pickle_mode()).await)
+        } else {
+            None
+        };
+
+        let private_identity = self.private_identity.read().unwrap().clone();
+        let private_identity = if let Some(i) = private_identity {
+            Some(i.pickle(pickle_key.key()).await?)
+        } else {
+            None
+        };
+
+        let mut sessions = Vec::new();
+
+        for session in self.sessions.get_all().await {
+            sessions.push(session.pickle(pickle_key.pickle_mode()).await);
+        }
+
+        let mut inbound_group_sessions = Vec::new();
+
+        for session in self.inbound_group_sessions.get_all() {
+            inbound_group_sessions.push(session.pickle(pickle_key.pickle_mode()).await);
+        }
+
+        let content = SnapshotContent {
+            account,
+            private_identity,
+            sessions,
+            inbound_group_sessions,
+            tracked_users: self.tracked_users.iter().map(|u| u.key().clone()).collect(),
+            users_for_key_query: self.users_for_key_query.iter().map(|u| u.key().clone()).collect(),
+            message_hashes: self
+                .olm_hashes
+                .iter()
+                .flat_map(|e| {
+                    let sender_key = e.key().clone();
+                    e.value()
+                        .iter()
+                        .map(|h| OlmMessageHash { sender_key: sender_key.clone(), hash: h.clone() })
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+            devices: self.devices.get_all(),
+            identities: self.identities.iter().map(|i| i.value().clone()).collect(),
+            outgoing_key_requests: self
+                .outgoing_key_requests
+                .iter()
+                .map(|r| r.value().clone())
+                .collect(),
+            sharing_history: self.sharing_history.iter().flat_map(|e| e.value().clone()).collect(),
+            custom_values: self
+                .custom_values
+                .iter()
+                .map(|e| (e.key().clone(), e.value().clone()))
+                .collect(),
+            room_key_bundles: self
+                .room_key_bundles
+                .iter()
+                .flat_map(|e| e.value().values().cloned().collect::<Vec<_>>())
+                .collect(),
+            verification_flows: self.verification_flows.iter().map(|f| f.value().clone()).collect(),
+            outgoing_requests: self.outgoing_requests.iter().map(|r| r.value().clone()).collect(),
+            inbox_events: self.inbox_events.iter().map(|e| e.value().clone()).collect(),
+            pending_to_device_messages: self
+                .pending_to_device_messages
+                .iter()
+                .map(|m| m.value().clone())
+                .collect(),
+            withheld_info: self.withheld_info.iter().map(|i| i.value().clone()).collect(),
+        };
+
+        let content = serde_json::to_vec(&content)?;
+        let content = if encrypted_pickle_key.is_some() {
+            pickle_key.encrypt_value(&content)
+        } else {
+            content
+        };
+
+        let snapshot = Snapshot { pickle_key: encrypted_pickle_key, content: encode(content) };
+
+        Ok(serde_json::to_vec(&snapshot)?)
+    }
+
+    /// Create a new `MemoryStore` from a snapshot that was created using
+    /// [`snapshot()`](#method.snapshot).
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot` - The serialized snapshot of a store.
+    ///
+    /// * `passphrase` - The passphrase that was used to encrypt the snapshot.
+    pub async fn restore(snapshot: &[u8], passphrase: Option<&str>) -> Result<Self> {
+        let snapshot: Snapshot = serde_json::from_slice(snapshot)?;
+        let content = decode(snapshot.content).map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        let (pickle_key, content) = match (snapshot.pickle_key, passphrase) {
+            (Some(encrypted), Some(passphrase)) => {
+                let pickle_key = PickleKey::from_encrypted(passphrase, encrypted)
+                    .map_err(|_| CryptoStoreError::UnpicklingError)?;
+                let content = pickle_key
+                    .decrypt_value(&content)
+                    .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+                (pickle_key, content)
+            }
+            (Some(_), None) => return Err(CryptoStoreError::UnpicklingError),
+            (None, _) => (Self::default_pickle_key(), content),
+        };
+
+        let content: SnapshotContent = serde_json::from_slice(&content)?;
+
+        let account = content
+            .account
+            .map(|a| ReadOnlyAccount::from_pickle(a, pickle_key.pickle_mode()))
+            .transpose()?;
+
+        let private_identity = if let Some(i) = content.private_identity {
+            Some(
+                PrivateCrossSigningIdentity::from_pickle(i, pickle_key.key())
+                    .await
+                    .map_err(|_| CryptoStoreError::UnpicklingError)?,
+            )
+        } else {
+            None
+        };
+
+        let sessions = match &account {
+            Some(account) => content
+                .sessions
+                .into_iter()
+                .map(|s| {
+                    Session::from_pickle(
+                        account.user_id.clone(),
+                        account.device_id.clone(),
+                        account.identity_keys.clone(),
+                        s,
+                        pickle_key.pickle_mode(),
+                    )
+                })
+                .collect::<std::result::Result<Vec<Session>, _>>()?,
+            None if content.sessions.is_empty() => Vec::new(),
+            None => return Err(CryptoStoreError::AccountUnset),
+        };
+
+        let inbound_group_sessions = content
+            .inbound_group_sessions
+            .into_iter()
+            .map(|s| InboundGroupSession::from_pickle(s, pickle_key.pickle_mode()))
+            .collect::<std::result::Result<Vec<InboundGroupSession>, _>>()?;
+
+        let store = Self::new();
+
+        for user_id in content.tracked_users {
+            store.tracked_users.insert(user_id);
+        }
+
+        for user_id in content.users_for_key_query {
+            store.users_for_key_query.insert(user_id);
+        }
+
+        let changes = Changes {
+            account,
+            private_identity,
+            sessions,
+            message_hashes: content.message_hashes,
+            inbound_group_sessions,
+            identities: IdentityChanges { new: content.identities, ..Default::default() },
+            key_requests: content.outgoing_key_requests,
+            devices: DeviceChanges { new: content.devices, ..Default::default() },
+            sharing_history: content.sharing_history,
+            custom_values: content.custom_values,
+            room_key_bundles: content.room_key_bundles,
+            withheld_session_info: content.withheld_info,
+            ..Default::default()
+        };
+
+        store.save_changes(changes).await?;
+
+        for flow in content.verification_flows {
+            store.verification_flows.insert(flow.flow_id.clone(), flow);
+        }
+
+        for request in content.outgoing_requests {
+            store.outgoing_requests.insert(request.request_id, request);
+        }
+
+        for event in content.inbox_events {
+            store.inbox_events.insert(event.id, event);
+        }
+
+        for message in content.pending_to_device_messages {
+            store.pending_to_device_messages.insert(message.id, message);
+        }
+
+        Ok(store)
+    }
+
     pub(crate) async fn save_devices(&self, mut devices: Vec<ReadOnlyDevice>) {
         for device in devices.drain(..) {
             let _ = self.devices.add(device);
@@ -100,18 +418,28 @@ impl MemoryStore {
 #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 impl CryptoStore for MemoryStore {
     async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
-        Ok(None)
+        Ok(self.account.read().unwrap().clone())
     }
 
-    async fn save_account(&self, _: ReadOnlyAccount) -> Result<()> {
+    async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
+        *self.account.write().unwrap() = Some(account);
+
         Ok(())
     }
 
     async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
-        Ok(None)
+        Ok(self.private_identity.read().unwrap().clone())
     }
 
     async fn save_changes(&self, mut changes: Changes) -> Result<()> {
+        if let Some(account) = changes.account {
+            *self.account.write().unwrap() = Some(account);
+        }
+
+        if let Some(identity) = changes.private_identity {
+            *self.private_identity.write().unwrap() = Some(identity);
+        }
+
         self.save_sessions(changes.sessions).await;
         self.save_inbound_group_sessions(changes.inbound_group_sessions).await;
 
@@ -138,6 +466,28 @@ impl CryptoStore for MemoryStore {
             self.key_requests_by_info.insert(info_string, id);
         }
 
+        for entry in changes.sharing_history {
+            self.sharing_history
+                .entry(entry.session_id.clone())
+                .or_insert_with(Vec::new)
+                .push(entry);
+        }
+
+        for (key, value) in changes.custom_values {
+            self.custom_values.insert(key, value);
+        }
+
+        for info in changes.withheld_session_info {
+            self.withheld_info.insert((info.room_id.clone(), info.session_id.clone()), info);
+        }
+
+        for bundle in changes.room_key_bundles {
+            self.room_key_bundles
+                .entry(bundle.room_id.clone())
+                .or_insert_with(HashMap::new)
+                .insert(bundle.sender_user.clone(), bundle);
+        }
+
         Ok(())
     }
 
@@ -158,6 +508,38 @@ impl CryptoStore for MemoryStore {
         Ok(self.inbound_group_sessions.get_all())
     }
 
+    fn inbound_group_sessions_stream(&self) -> InboundGroupSessionStream<'_> {
+        Box::pin(stream::iter(self.inbound_group_sessions.get_all().into_iter().map(Ok)))
+    }
+
+    fn inbound_group_sessions_for_backup_stream(&self) -> InboundGroupSessionStream<'_> {
+        let sessions = self.inbound_group_sessions.get_all().into_iter().filter(|s| !s.backed_up());
+        Box::pin(stream::iter(sessions.map(Ok)))
+    }
+
+    async fn mark_inbound_group_sessions_as_backed_up(
+        &self,
+        backup_version: &str,
+        sessions: &[(&RoomId, &str, &str)],
+    ) -> Result<()> {
+        for (room_id, sender_key, session_id) in sessions {
+            if let Some(session) = self.inbound_group_sessions.get(room_id, sender_key, session_id)
+            {
+                session.mark_as_backed_up(backup_version);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        for session in self.inbound_group_sessions.get_all() {
+            session.reset_backup_state();
+        }
+
+        Ok(())
+    }
+
     async fn get_outbound_group_sessions(
         &self,
         _: &RoomId,
@@ -173,6 +555,11 @@ impl CryptoStore for MemoryStore {
         !self.users_for_key_query.is_empty()
     }
 
+    fn tracked_users(&self) -> HashSet<UserId> {
+        #[allow(clippy::map_clone)]
+        self.tracked_users.iter().map(|u| u.clone()).collect()
+    }
+
     fn users_for_key_query(&self) -> HashSet<UserId> {
         #[allow(clippy::map_clone)]
         self.users_for_key_query.iter().map(|u| u.clone()).collect()
@@ -208,6 +595,26 @@ impl CryptoStore for MemoryStore {
         Ok(self.devices.get(user_id, device_id))
     }
 
+    async fn get_device_from_curve_key(
+        &self,
+        user_id: &UserId,
+        curve_key: &str,
+    ) -> Result<Option<ReadOnlyDevice>> {
+        let parsed_key = match Curve25519PublicKey::from_base64(curve_key) {
+            Ok(k) => k,
+            Err(_) => return Ok(None),
+        };
+
+        // The devices are all in memory, scanning the devices of a single user
+        // is cheap enough.
+        Ok(self
+            .devices
+            .user_devices(user_id)
+            .into_iter()
+            .map(|(_, d)| d)
+            .find(|d| d.curve25519_key() == Some(parsed_key)))
+    }
+
     async fn get_user_devices(
         &self,
         user_id: &UserId,
@@ -264,6 +671,188 @@ impl CryptoStore for MemoryStore {
 
         Ok(())
     }
+
+    fn release_memory(&self) {
+        // The memory store doesn't have a persistent store behind it, evicting
+        // anything would lose data.
+    }
+
+    async fn get_sharing_history(&self, session_id: &str) -> Result<Vec<SharingHistoryEntry>> {
+        Ok(self.sharing_history.get(session_id).map(|h| h.value().clone()).unwrap_or_default())
+    }
+
+    async fn get_received_room_key_bundle_data(
+        &self,
+        room_id: &RoomId,
+        sender_user: &UserId,
+    ) -> Result<Option<StoredRoomKeyBundleData>> {
+        Ok(self.room_key_bundles.get(room_id).and_then(|b| b.get(sender_user).cloned()))
+    }
+
+    async fn get_received_room_key_bundles(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Vec<StoredRoomKeyBundleData>> {
+        Ok(self
+            .room_key_bundles
+            .get(room_id)
+            .map(|b| b.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn delete_received_room_key_bundles(&self, room_id: &RoomId) -> Result<()> {
+        self.room_key_bundles.remove(room_id);
+
+        Ok(())
+    }
+
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldInfo>> {
+        Ok(self
+            .withheld_info
+            .get(&(room_id.to_owned(), session_id.to_owned()))
+            .map(|i| i.value().clone()))
+    }
+
+    async fn save_verification_flow(&self, flow: &StoredVerificationFlow) -> Result<()> {
+        self.verification_flows.insert(flow.flow_id.clone(), flow.clone());
+
+        Ok(())
+    }
+
+    async fn get_verification_flows(&self) -> Result<Vec<StoredVerificationFlow>> {
+        Ok(self.verification_flows.iter().map(|f| f.value().clone()).collect())
+    }
+
+    async fn remove_verification_flow(&self, flow_id: &str) -> Result<()> {
+        self.verification_flows.remove(flow_id);
+
+        Ok(())
+    }
+
+    async fn save_outgoing_request(&self, request: &StoredOutgoingRequest) -> Result<()> {
+        self.outgoing_requests.insert(request.request_id, request.clone());
+
+        Ok(())
+    }
+
+    async fn get_outgoing_requests(&self) -> Result<Vec<StoredOutgoingRequest>> {
+        Ok(self.outgoing_requests.iter().map(|r| r.value().clone()).collect())
+    }
+
+    async fn remove_outgoing_request(&self, request_id: Uuid) -> Result<()> {
+        self.outgoing_requests.remove(&request_id);
+
+        Ok(())
+    }
+
+    async fn save_inbox_event(&self, event: &StoredToDeviceEvent) -> Result<()> {
+        self.inbox_events.insert(event.id, event.clone());
+
+        Ok(())
+    }
+
+    async fn get_inbox_events(&self) -> Result<Vec<StoredToDeviceEvent>> {
+        Ok(self.inbox_events.iter().map(|e| e.value().clone()).collect())
+    }
+
+    async fn remove_inbox_event(&self, id: Uuid) -> Result<()> {
+        self.inbox_events.remove(&id);
+
+        Ok(())
+    }
+
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<Option<u64>> {
+        Ok(match self.leases.entry(key.to_owned()) {
+            Entry::Occupied(mut entry) => Lease::take(Some(entry.get()), holder, lease_duration_ms)
+                .map(|lease| {
+                    let generation = lease.generation();
+                    entry.insert(lease);
+                    generation
+                }),
+            Entry::Vacant(entry) => Lease::take(None, holder, lease_duration_ms)
+                .map(|lease| entry.insert(lease).generation()),
+        })
+    }
+
+    async fn release_leased_lock(&self, key: &str, holder: &str) -> Result<()> {
+        if let Some(mut lease) = self.leases.get_mut(key) {
+            if let Some(released) = lease.release(holder) {
+                *lease = released;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn save_pending_to_device_message(&self, message: &PendingToDeviceMessage) -> Result<()> {
+        self.pending_to_device_messages.insert(message.id, message.clone());
+
+        Ok(())
+    }
+
+    async fn get_pending_to_device_messages(&self) -> Result<Vec<PendingToDeviceMessage>> {
+        Ok(self.pending_to_device_messages.iter().map(|m| m.value().clone()).collect())
+    }
+
+    async fn remove_pending_to_device_message(&self, id: Uuid) -> Result<()> {
+        self.pending_to_device_messages.remove(&id);
+
+        Ok(())
+    }
+
+    async fn get_custom_value(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.custom_values.get(key).map(|v| v.value().clone()))
+    }
+
+    async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.custom_values.insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    async fn change_passphrase(&self, _: Option<&str>, _: &str) -> Result<()> {
+        // Nothing is pickled, there's nothing to re-encrypt.
+        Ok(())
+    }
+
+    async fn invalidate_caches(&self) -> Result<()> {
+        // Nothing is persisted, the memory store can't be shared between
+        // processes.
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.account.write().unwrap().take();
+        self.private_identity.write().unwrap().take();
+        self.sessions.clear();
+        self.inbound_group_sessions.clear();
+        self.tracked_users.clear();
+        self.users_for_key_query.clear();
+        self.olm_hashes.clear();
+        self.devices.clear();
+        self.identities.clear();
+        self.outgoing_key_requests.clear();
+        self.key_requests_by_info.clear();
+        self.sharing_history.clear();
+        self.room_key_bundles.clear();
+        self.verification_flows.clear();
+        self.outgoing_requests.clear();
+        self.inbox_events.clear();
+        self.pending_to_device_messages.clear();
+        self.withheld_info.clear();
+        self.leases.clear();
+        self.custom_values.clear();
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -371,4 +960,41 @@ mod test {
         store.save_changes(changes).await.unwrap();
         assert!(store.is_message_known(&hash).await.unwrap());
     }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let (account, session) = get_account_and_session().await;
+        let device = get_device();
+        let store = MemoryStore::new();
+
+        store.save_account(account.clone()).await.unwrap();
+        store.save_sessions(vec![session.clone()]).await;
+        store.save_devices(vec![device.clone()]).await;
+        store.update_tracked_user(device.user_id(), true).await.unwrap();
+        store.set_custom_value("custom", b"value".to_vec()).await.unwrap();
+
+        let snapshot = store.snapshot(Some("secret")).await.unwrap();
+
+        assert!(MemoryStore::restore(&snapshot, None).await.is_err());
+        assert!(MemoryStore::restore(&snapshot, Some("wrong")).await.is_err());
+
+        let restored = MemoryStore::restore(&snapshot, Some("secret")).await.unwrap();
+
+        assert_eq!(account, restored.load_account().await.unwrap().unwrap());
+
+        let sessions = restored.get_sessions(&session.sender_key).await.unwrap().unwrap();
+        assert_eq!(&session, &sessions.lock().await[0]);
+
+        assert_eq!(
+            device,
+            restored.get_device(device.user_id(), device.device_id()).await.unwrap().unwrap()
+        );
+        assert!(restored.is_user_tracked(device.user_id()));
+        assert!(restored.users_for_key_query().contains(device.user_id()));
+        assert_eq!(restored.get_custom_value("custom").await.unwrap().unwrap(), b"value");
+
+        let snapshot = store.snapshot(None).await.unwrap();
+        let restored = MemoryStore::restore(&snapshot, None).await.unwrap();
+        assert_eq!(account, restored.load_account().await.unwrap().unwrap());
+    }
 }
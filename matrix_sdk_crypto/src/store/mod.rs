@@ -15,7 +15,9 @@
 //! Types and traits to implement the storage layer for the [`OlmMachine`]
 //!
 //! The storage layer for the [`OlmMachine`] can be customized using a trait.
-//! Implementing your own [`CryptoStore`]
+//! Implementing your own [`CryptoStore`] allows it to be passed to
+//! [`OlmMachine::new_with_store`], the store is held as a type-erased
+//! [`DynCryptoStore`].
 //!
 //! An in-memory only store is provided as well as a Sled based one, depending
 //! on your needs and targets a custom store may be implemented, e.g. for
@@ -29,15 +31,17 @@
 //! # use ruma::{user_id, DeviceIdBox};
 //! # let user_id = user_id!("@example:localhost");
 //! # let device_id: DeviceIdBox = "TEST".into();
-//! let store = Box::new(MemoryStore::new());
+//! let store = MemoryStore::new();
 //!
 //! let machine = OlmMachine::new_with_store(user_id, device_id, store);
 //! ```
 //!
 //! [`OlmMachine`]: /matrix_sdk_crypto/struct.OlmMachine.html
+//! [`OlmMachine::new_with_store`]: /matrix_sdk_crypto/struct.OlmMachine.html#method.new_with_store
 //! [`CryptoStore`]: trait.Cryptostore.html
 
 pub mod caches;
+mod locks;
 mod memorystore;
 mod pickle_key;
 #[cfg(feature = "sled_cryptostore")]
@@ -51,36 +55,56 @@ use std::{
     sync::Arc,
 };
 
-use matrix_sdk_common::{async_trait, locks::Mutex, uuid::Uuid, AsyncTraitDeps};
+use futures::TryStreamExt;
+pub(crate) use locks::Lease;
+pub use locks::{CrossProcessStoreLock, CrossProcessStoreLockGuard};
+use matrix_sdk_common::{
+    async_trait, instant::Instant, locks::Mutex, metrics, uuid::Uuid, AsyncTraitDeps,
+};
 pub use memorystore::MemoryStore;
 use olm_rs::errors::{OlmAccountError, OlmGroupSessionError, OlmSessionError};
 pub use pickle_key::{EncryptedPickleKey, PickleKey};
 use ruma::{
     events::room_key_request::RequestedKeyInfo,
-    identifiers::{
-        DeviceId, DeviceIdBox, DeviceKeyAlgorithm, Error as IdentifierValidationError, RoomId,
-        UserId,
-    },
+    identifiers::{DeviceId, DeviceIdBox, Error as IdentifierValidationError, RoomId, UserId},
 };
 use serde_json::Error as SerdeError;
 use thiserror::Error;
 
 #[cfg(feature = "sled_cryptostore")]
-pub use self::sled::SledStore;
+pub use self::sled::{Durability, SledStore, SledStoreConfig};
 use crate::{
+    backups::BackupProgress,
+    decryption_retry::DecryptionRetryQueue,
     error::SessionUnpicklingError,
     identities::{Device, ReadOnlyDevice, UserDevices, UserIdentities},
     key_request::OutgoingKeyRequest,
     olm::{
         InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
-        ReadOnlyAccount, Session,
+        ReadOnlyAccount, RoomKeyWithheldInfo, Session, SharingHistoryEntry,
+        StoredRoomKeyBundleData,
     },
-    verification::VerificationMachine,
+    requests::StoredOutgoingRequest,
+    session_manager::PendingToDeviceMessage,
+    to_device_inbox::StoredToDeviceEvent,
+    verification::{StoredVerificationFlow, VerificationMachine},
 };
 
 /// A `CryptoStore` specific result type.
 pub type Result<T, E = CryptoStoreError> = std::result::Result<T, E>;
 
+/// A stream of inbound group sessions that are loaded from a [`CryptoStore`]
+/// one by one.
+#[cfg(not(target_arch = "wasm32"))]
+pub type InboundGroupSessionStream<'a> =
+    futures::stream::BoxStream<'a, Result<InboundGroupSession>>;
+
+/// A stream of inbound group sessions that are loaded from a [`CryptoStore`]
+/// one by one.
+#[cfg(target_arch = "wasm32")]
+pub type InboundGroupSessionStream<'a> =
+    futures::stream::LocalBoxStream<'a, Result<InboundGroupSession>>;
+
 /// A wrapper for our CryptoStore trait object.
 ///
 /// This is needed because we want to have a generic interface so we can
@@ -93,6 +117,10 @@ pub(crate) struct Store {
     identity: Arc<Mutex<PrivateCrossSigningIdentity>>,
     inner: Arc<dyn CryptoStore>,
     verification_machine: VerificationMachine,
+    decryption_retries: DecryptionRetryQueue,
+    /// The number of room keys we have and how many of them are backed up,
+    /// counted once and kept up to date while room keys are saved.
+    room_key_counts: Arc<Mutex<Option<BackupProgress>>>,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -107,6 +135,10 @@ pub struct Changes {
     pub identities: IdentityChanges,
     pub key_requests: Vec<OutgoingKeyRequest>,
     pub devices: DeviceChanges,
+    pub sharing_history: Vec<SharingHistoryEntry>,
+    pub room_key_bundles: Vec<StoredRoomKeyBundleData>,
+    pub withheld_session_info: Vec<RoomKeyWithheldInfo>,
+    pub custom_values: HashMap<String, Vec<u8>>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -140,7 +172,18 @@ impl Store {
         store: Arc<dyn CryptoStore>,
         verification_machine: VerificationMachine,
     ) -> Self {
-        Self { user_id, identity, inner: store, verification_machine }
+        Self {
+            user_id,
+            identity,
+            inner: store,
+            verification_machine,
+            decryption_retries: DecryptionRetryQueue::new(),
+            room_key_counts: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn decryption_retries(&self) -> &DecryptionRetryQueue {
+        &self.decryption_retries
     }
 
     pub async fn get_readonly_device(
@@ -151,6 +194,142 @@ impl Store {
         self.inner.get_device(user_id, device_id).await
     }
 
+    pub fn cross_process_lock(&self, key: &str, holder: &str) -> CrossProcessStoreLock {
+        CrossProcessStoreLock::new(self.inner.clone(), key, holder)
+    }
+
+    pub async fn save_changes(&self, changes: Changes) -> Result<()> {
+        let inbound_group_sessions = changes.inbound_group_sessions.clone();
+
+        let mut room_key_counts = self.room_key_counts.lock().await;
+        let counts = match room_key_counts.as_ref() {
+            Some(counts) => {
+                Some(self.count_saved_room_keys(*counts, &inbound_group_sessions).await?)
+            }
+            None => None,
+        };
+
+        let now = Instant::now();
+        let result = self.inner.save_changes(changes).await;
+        metrics::record_store_operation("crypto", "save_changes", now.elapsed());
+
+        if result.is_ok() {
+            if counts.is_some() {
+                *room_key_counts = counts;
+            }
+
+            // Only tell others about the new room keys once they are safely
+            // stored, a retried decryption will load them from the store.
+            self.decryption_retries.sessions_received(&inbound_group_sessions);
+        }
+
+        result
+    }
+
+    /// Update the room key counts for the given room keys that are about to
+    /// be saved.
+    async fn count_saved_room_keys(
+        &self,
+        mut counts: BackupProgress,
+        sessions: &[InboundGroupSession],
+    ) -> Result<BackupProgress> {
+        for session in sessions {
+            let existing = self
+                .inner
+                .get_inbound_group_session(
+                    session.room_id(),
+                    session.sender_key(),
+                    session.session_id(),
+                )
+                .await?;
+
+            match existing {
+                Some(existing) => {
+                    if existing.backed_up() && !session.backed_up() {
+                        counts.backed_up = counts.backed_up.saturating_sub(1);
+                    } else if !existing.backed_up() && session.backed_up() {
+                        counts.backed_up += 1;
+                    }
+                }
+                None => {
+                    counts.total += 1;
+
+                    if session.backed_up() {
+                        counts.backed_up += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Get the number of room keys we have and how many of them are backed
+    /// up.
+    ///
+    /// The room keys are only counted the first time, the counts are updated
+    /// as room keys are saved or backed up afterwards.
+    pub async fn backup_progress(&self) -> Result<BackupProgress> {
+        let mut room_key_counts = self.room_key_counts.lock().await;
+
+        if let Some(counts) = *room_key_counts {
+            return Ok(counts);
+        }
+
+        let counts = self
+            .inner
+            .inbound_group_sessions_stream()
+            .try_fold(BackupProgress::default(), |mut counts, session| async move {
+                counts.total += 1;
+
+                if session.backed_up() {
+                    counts.backed_up += 1;
+                }
+
+                Ok(counts)
+            })
+            .await?;
+
+        *room_key_counts = Some(counts);
+
+        Ok(counts)
+    }
+
+    /// Mark the given room keys, which weren't backed up before, as backed
+    /// up.
+    ///
+    /// See [`CryptoStore::mark_inbound_group_sessions_as_backed_up()`].
+    pub async fn mark_inbound_group_sessions_as_backed_up(
+        &self,
+        backup_version: &str,
+        sessions: &[(&RoomId, &str, &str)],
+    ) -> Result<()> {
+        let mut room_key_counts = self.room_key_counts.lock().await;
+
+        self.inner.mark_inbound_group_sessions_as_backed_up(backup_version, sessions).await?;
+
+        if let Some(counts) = room_key_counts.as_mut() {
+            counts.backed_up = (counts.backed_up + sessions.len()).min(counts.total);
+        }
+
+        Ok(())
+    }
+
+    /// Mark all the room keys as not backed up.
+    ///
+    /// See [`CryptoStore::reset_backup_state()`].
+    pub async fn reset_backup_state(&self) -> Result<()> {
+        let mut room_key_counts = self.room_key_counts.lock().await;
+
+        self.inner.reset_backup_state().await?;
+
+        if let Some(counts) = room_key_counts.as_mut() {
+            counts.backed_up = 0;
+        }
+
+        Ok(())
+    }
+
     pub async fn save_sessions(&self, sessions: &[Session]) -> Result<()> {
         let changes = Changes { sessions: sessions.to_vec(), ..Default::default() };
 
@@ -189,11 +368,24 @@ impl Store {
         user_id: &UserId,
         curve_key: &str,
     ) -> Result<Option<Device>> {
-        self.get_user_devices(user_id).await.map(|d| {
-            d.devices().find(|d| {
-                d.get_key(DeviceKeyAlgorithm::Curve25519).map_or(false, |k| k == curve_key)
-            })
-        })
+        let device =
+            if let Some(d) = self.inner.get_device_from_curve_key(user_id, curve_key).await? {
+                d
+            } else {
+                return Ok(None);
+            };
+
+        let own_identity =
+            self.get_user_identity(&self.user_id).await?.map(|i| i.own().cloned()).flatten();
+        let device_owner_identity = self.get_user_identity(user_id).await?;
+
+        Ok(Some(Device {
+            inner: device,
+            private_identity: self.identity.clone(),
+            verification_machine: self.verification_machine.clone(),
+            own_identity,
+            device_owner_identity,
+        }))
     }
 
     pub async fn get_user_devices(&self, user_id: &UserId) -> Result<UserDevices> {
@@ -247,6 +439,10 @@ pub enum CryptoStoreError {
     #[error("can't save/load sessions or group sessions in the store before an account is stored")]
     AccountUnset,
 
+    /// The store was opened in read-only mode and can't be modified.
+    #[error("the store was opened in read-only mode and can't be modified")]
+    ReadOnly,
+
     /// Error in the internal database
     #[cfg(feature = "sled_cryptostore")]
     #[error(transparent)]
@@ -335,6 +531,49 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Get all the inbound group sessions we have stored.
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>>;
 
+    /// Get a stream over all the inbound group sessions we have stored.
+    ///
+    /// Unlike [`get_inbound_group_sessions()`], the sessions should be loaded
+    /// lazily while the stream is polled, so going through all the sessions
+    /// doesn't require them to be held in memory at once.
+    ///
+    /// [`get_inbound_group_sessions()`]: #tymethod.get_inbound_group_sessions
+    fn inbound_group_sessions_stream(&self) -> InboundGroupSessionStream<'_>;
+
+    /// Get a stream over the inbound group sessions that weren't backed up
+    /// yet.
+    ///
+    /// This is the same as [`inbound_group_sessions_stream()`], but it only
+    /// yields sessions that aren't marked as backed up.
+    ///
+    /// [`inbound_group_sessions_stream()`]: #tymethod.inbound_group_sessions_stream
+    fn inbound_group_sessions_for_backup_stream(&self) -> InboundGroupSessionStream<'_>;
+
+    /// Mark the given inbound group sessions as uploaded to the server-side
+    /// key backup.
+    ///
+    /// Only the backup state of the sessions is updated, sessions that aren't
+    /// in the store are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `backup_version` - The version of the backup the sessions were
+    /// uploaded to.
+    ///
+    /// * `sessions` - The room id, sender key and session id of every session
+    /// that was uploaded.
+    async fn mark_inbound_group_sessions_as_backed_up(
+        &self,
+        backup_version: &str,
+        sessions: &[(&RoomId, &str, &str)],
+    ) -> Result<()>;
+
+    /// Mark all the inbound group sessions as not backed up.
+    ///
+    /// This needs to be called when the backup version changes, all the
+    /// sessions need to be uploaded to the new backup.
+    async fn reset_backup_state(&self) -> Result<()>;
+
     /// Get the outbound group sessions we have stored that is used for the
     /// given room.
     async fn get_outbound_group_sessions(
@@ -345,6 +584,9 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// Is the given user already tracked.
     fn is_user_tracked(&self, user_id: &UserId) -> bool;
 
+    /// Get all the users that are tracked.
+    fn tracked_users(&self) -> HashSet<UserId>;
+
     /// Are there any tracked users that are marked as dirty.
     fn has_users_for_key_query(&self) -> bool;
 
@@ -376,6 +618,24 @@ pub trait CryptoStore: AsyncTraitDeps {
         device_id: &DeviceId,
     ) -> Result<Option<ReadOnlyDevice>>;
 
+    /// Get the device of the given user that uses the given curve25519 key.
+    ///
+    /// Decryption needs to find the device that sent an event from the
+    /// `sender_key` of the event, stores should keep an index from the
+    /// curve25519 key to the device instead of scanning all the devices of
+    /// the user.
+    ///
+    /// # Arguments
+    ///
+    /// * `user_id` - The user that the device belongs to.
+    ///
+    /// * `curve_key` - The curve25519 key of the device.
+    async fn get_device_from_curve_key(
+        &self,
+        user_id: &UserId,
+        curve_key: &str,
+    ) -> Result<Option<ReadOnlyDevice>>;
+
     /// Get all the devices of the given user.
     ///
     /// # Arguments
@@ -430,4 +690,294 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// * `request_id` - The unique request id that identifies this outgoing key
     /// request.
     async fn delete_outgoing_key_request(&self, request_id: Uuid) -> Result<()>;
+
+    /// Release memory that cached objects take up.
+    ///
+    /// Stores that keep objects in memory in front of a persistent storage
+    /// should drop the cached objects that aren't in use. This can be called
+    /// if the host application is under memory pressure.
+    fn release_memory(&self);
+
+    /// Get the sharing history of the group session with the given session id.
+    ///
+    /// The entries are returned in the order they were recorded in.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The unique id of the group session.
+    async fn get_sharing_history(&self, session_id: &str) -> Result<Vec<SharingHistoryEntry>>;
+
+    /// Get the room key bundle the given user sent us for the given room.
+    ///
+    /// Only the latest bundle a user sent us for a room is kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room the bundle contains room keys for.
+    ///
+    /// * `sender_user` - The user that sent us the bundle.
+    async fn get_received_room_key_bundle_data(
+        &self,
+        room_id: &RoomId,
+        sender_user: &UserId,
+    ) -> Result<Option<StoredRoomKeyBundleData>>;
+
+    /// Get all the room key bundles we received for the given room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room the bundles contain room keys for.
+    async fn get_received_room_key_bundles(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Vec<StoredRoomKeyBundleData>>;
+
+    /// Remove all the room key bundles we received for the given room, e.g.
+    /// after they have been imported once we joined the room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room the bundles contain room keys for.
+    async fn delete_received_room_key_bundles(&self, room_id: &RoomId) -> Result<()>;
+
+    /// Get the information about why the given group session was withheld
+    /// from us.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room the group session belongs to.
+    ///
+    /// * `session_id` - The unique id of the group session.
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldInfo>>;
+
+    /// Save the state of an in-progress verification flow.
+    ///
+    /// A flow with the same flow id will be overwritten.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow` - The verification flow that should be stored.
+    async fn save_verification_flow(&self, flow: &StoredVerificationFlow) -> Result<()>;
+
+    /// Get all the verification flows that are stored.
+    async fn get_verification_flows(&self) -> Result<Vec<StoredVerificationFlow>>;
+
+    /// Remove the verification flow with the given flow id, e.g. after it
+    /// finished or got cancelled.
+    ///
+    /// # Arguments
+    ///
+    /// * `flow_id` - The unique id of the verification flow.
+    async fn remove_verification_flow(&self, flow_id: &str) -> Result<()>;
+
+    /// Save an outgoing request that wasn't acknowledged by the server yet.
+    ///
+    /// A request with the same request id will be overwritten.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The outgoing request that should be stored.
+    async fn save_outgoing_request(&self, request: &StoredOutgoingRequest) -> Result<()>;
+
+    /// Get all the outgoing requests that are stored.
+    async fn get_outgoing_requests(&self) -> Result<Vec<StoredOutgoingRequest>>;
+
+    /// Remove the outgoing request with the given request id, e.g. after the
+    /// server acknowledged it.
+    ///
+    /// # Arguments
+    ///
+    /// * `request_id` - The unique id of the request.
+    async fn remove_outgoing_request(&self, request_id: Uuid) -> Result<()>;
+
+    /// Save a to-device event that couldn't be processed yet into the inbox.
+    ///
+    /// An event with the same id will be overwritten.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The to-device event that should be stored.
+    async fn save_inbox_event(&self, event: &StoredToDeviceEvent) -> Result<()>;
+
+    /// Get all the to-device events that are in the inbox.
+    async fn get_inbox_events(&self) -> Result<Vec<StoredToDeviceEvent>>;
+
+    /// Remove the to-device event with the given id from the inbox, e.g.
+    /// after it was processed or we gave up on it.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The unique id of the inbox entry.
+    async fn remove_inbox_event(&self, id: Uuid) -> Result<()>;
+
+    /// Try to take the lease on the lock with the given key for the given
+    /// holder.
+    ///
+    /// The lease is given out if nobody holds it, if the lease of the
+    /// current holder expired or if it already belongs to the given holder, in
+    /// which case it's extended. Taking and extending the lease needs to be
+    /// atomic, other processes might try to take it at the same time.
+    ///
+    /// Returns the generation of the lease if the holder got it, `None`
+    /// otherwise. The generation needs to be bumped every time the lease
+    /// changes hands, this is how a [`CrossProcessStoreLock`] notices that
+    /// another process held the lock.
+    ///
+    /// # Arguments
+    ///
+    /// * `lease_duration_ms` - How long the lease stays valid, in milliseconds.
+    ///
+    /// * `key` - The name of the lock.
+    ///
+    /// * `holder` - The unique name of the process that wants to take the lock.
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<Option<u64>>;
+
+    /// Give up the lease on the lock with the given key if the given holder
+    /// holds it.
+    ///
+    /// The generation of the lease needs to be kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The name of the lock.
+    ///
+    /// * `holder` - The unique name of the process that held the lock.
+    async fn release_leased_lock(&self, key: &str, holder: &str) -> Result<()>;
+
+    /// Save a to-device message that is waiting for an Olm session with its
+    /// recipient.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The to-device message that should be stored.
+    async fn save_pending_to_device_message(&self, message: &PendingToDeviceMessage) -> Result<()>;
+
+    /// Get all the to-device messages that are waiting for an Olm session.
+    async fn get_pending_to_device_messages(&self) -> Result<Vec<PendingToDeviceMessage>>;
+
+    /// Remove the pending to-device message with the given id, e.g. after it
+    /// was encrypted and queued up as an outgoing request.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The unique id of the message.
+    async fn remove_pending_to_device_message(&self, id: Uuid) -> Result<()>;
+
+    /// Get the custom value with the given key.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key the value was stored under.
+    async fn get_custom_value(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Store a custom value with the given key.
+    ///
+    /// Custom values allow applications to persist small pieces of data, e.g.
+    /// their own flags or migration markers, next to the crypto data. Stores
+    /// that encrypt their private data need to encrypt the custom values as
+    /// well. Use [`Changes::custom_values`] to store values atomically
+    /// together with other changes.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The unique key of the value.
+    ///
+    /// * `value` - The value that should be stored.
+    async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<()>;
+
+    /// Change the passphrase that protects the private data of the store.
+    ///
+    /// A new pickle key is created and the account, the Olm sessions, the
+    /// group sessions, the private cross signing keys and the custom values
+    /// are encrypted again using it, all in one transaction. Stores that
+    /// weren't protected by a passphrase are upgraded to be protected by
+    /// the new passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `old_passphrase` - The passphrase that currently protects the store,
+    /// `None` if the store isn't protected by a passphrase.
+    ///
+    /// * `new_passphrase` - The passphrase that should protect the store from
+    /// now on.
+    async fn change_passphrase(
+        &self,
+        old_passphrase: Option<&str>,
+        new_passphrase: &str,
+    ) -> Result<()>;
+
+    /// Drop the objects that are cached in memory, they will be loaded from
+    /// the persistent storage again.
+    ///
+    /// This is called if another process might have modified the store, e.g.
+    /// after a [`CrossProcessStoreLock`] was acquired that was held by another
+    /// process in the meantime.
+    async fn invalidate_caches(&self) -> Result<()>;
+
+    /// Delete all the data the store holds.
+    ///
+    /// This removes the account, all the Olm and group sessions, the private
+    /// cross signing keys and every other piece of data the store persisted.
+    /// Stores that persist their data on disk should delete it from there as
+    /// well.
+    ///
+    /// The store shouldn't be used anymore after it has been cleared.
+    async fn clear(&self) -> Result<()>;
+}
+
+/// A type-erased [`CryptoStore`].
+///
+/// This is the type the [`OlmMachine`] holds its store as, custom store
+/// implementations are converted to it using [`IntoCryptoStore`].
+///
+/// [`OlmMachine`]: crate::OlmMachine
+pub type DynCryptoStore = dyn CryptoStore;
+
+/// A type that can be type-erased into an `Arc<DynCryptoStore>`.
+///
+/// This is implemented for every [`CryptoStore`] implementation, either on its
+/// own or inside an `Arc`, as well as for stores that are already
+/// type-erased, so APIs that take a store can accept any of them.
+pub trait IntoCryptoStore {
+    #[doc(hidden)]
+    fn into_crypto_store(self) -> Arc<DynCryptoStore>;
+}
+
+impl<T> IntoCryptoStore for T
+where
+    T: CryptoStore + 'static,
+{
+    fn into_crypto_store(self) -> Arc<DynCryptoStore> {
+        Arc::new(self)
+    }
+}
+
+impl<T> IntoCryptoStore for Arc<T>
+where
+    T: CryptoStore + 'static,
+{
+    fn into_crypto_store(self) -> Arc<DynCryptoStore> {
+        self
+    }
+}
+
+impl IntoCryptoStore for Arc<DynCryptoStore> {
+    fn into_crypto_store(self) -> Arc<DynCryptoStore> {
+        self
+    }
+}
+
+impl IntoCryptoStore for Box<DynCryptoStore> {
+    fn into_crypto_store(self) -> Arc<DynCryptoStore> {
+        self.into()
+    }
 }
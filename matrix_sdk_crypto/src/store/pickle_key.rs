@@ -121,6 +121,44 @@ impl PickleKey {
         &self.aes256_key
     }
 
+    /// Encrypt the given value using this pickle key.
+    ///
+    /// The returned ciphertext is prefixed with the random nonce that was used
+    /// to encrypt it.
+    ///
+    /// # Arguments
+    ///
+    /// * `plaintext` - The value that should be encrypted.
+    pub fn encrypt_value(&self, plaintext: &[u8]) -> Vec<u8> {
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&self.aes256_key));
+
+        let mut nonce = vec![0u8; NONCE_SIZE];
+        getrandom(&mut nonce).expect("Can't generate new random nonce for a value");
+
+        let ciphertext = cipher
+            .encrypt(GenericArray::from_slice(&nonce), plaintext)
+            .expect("Can't encrypt a value");
+
+        [nonce, ciphertext].concat()
+    }
+
+    /// Decrypt a value that was encrypted using
+    /// [`encrypt_value()`](#method.encrypt_value).
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The nonce prefixed ciphertext.
+    pub fn decrypt_value(&self, value: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        if value.len() < NONCE_SIZE {
+            return Err(DecryptionError);
+        }
+
+        let (nonce, ciphertext) = value.split_at(NONCE_SIZE);
+        let cipher = Aes256Gcm::new(GenericArray::from_slice(&self.aes256_key));
+
+        cipher.decrypt(GenericArray::from_slice(nonce), ciphertext)
+    }
+
     /// Encrypt and export our pickle key using the given passphrase.
     ///
     /// # Arguments
@@ -198,4 +236,16 @@ mod test {
 
         assert_eq!(pickle_key, decrypted);
     }
+
+    #[test]
+    fn encrypting_values() {
+        let pickle_key = PickleKey::new();
+
+        let encrypted = pickle_key.encrypt_value(b"It's a secret to everybody");
+        assert_ne!(encrypted, b"It's a secret to everybody");
+        assert_eq!(pickle_key.decrypt_value(&encrypted).unwrap(), b"It's a secret to everybody");
+
+        assert!(PickleKey::new().decrypt_value(&encrypted).is_err());
+        assert!(pickle_key.decrypt_value(&encrypted[..4]).is_err());
+    }
 }
@@ -15,35 +15,63 @@
 use std::{
     collections::{HashMap, HashSet},
     convert::TryFrom,
+    io::{Error as IoError, ErrorKind},
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
 };
 
 use dashmap::DashSet;
-use matrix_sdk_common::{async_trait, locks::Mutex, uuid};
+use matrix_sdk_common::{
+    async_trait,
+    locks::{Mutex, RwLock as AsyncRwLock},
+    uuid,
+};
 use olm_rs::{account::IdentityKeys, PicklingMode};
-use ruma::{events::room_key_request::RequestedKeyInfo, DeviceId, DeviceIdBox, RoomId, UserId};
+use ruma::{
+    events::room_key_request::RequestedKeyInfo, DeviceId, DeviceIdBox, DeviceKeyAlgorithm, RoomId,
+    UserId,
+};
+use serde::{de::DeserializeOwned, Serialize};
 pub use sled::Error;
 use sled::{
     transaction::{ConflictableTransactionError, TransactionError},
-    Config, Db, Transactional, Tree,
+    Config, Db, Mode, Transactional, Tree,
 };
 use uuid::Uuid;
 
 use super::{
-    caches::SessionStore, Changes, CryptoStore, CryptoStoreError, InboundGroupSession, PickleKey,
-    ReadOnlyAccount, Result, Session,
+    caches::{IdentifierInterner, InternerStats, SessionStore},
+    Changes, CryptoStore, CryptoStoreError, InboundGroupSession, InboundGroupSessionStream, Lease,
+    PickleKey, ReadOnlyAccount, Result, Session,
 };
 use crate::{
     identities::{ReadOnlyDevice, UserIdentities},
     key_request::OutgoingKeyRequest,
-    olm::{OutboundGroupSession, PickledInboundGroupSession, PrivateCrossSigningIdentity},
+    olm::{
+        OutboundGroupSession, PickledInboundGroupSession, PickledOutboundGroupSession,
+        PickledSession, PrivateCrossSigningIdentity, RoomKeyWithheldInfo, SharingHistoryEntry,
+        StoredRoomKeyBundleData,
+    },
+    requests::StoredOutgoingRequest,
+    session_manager::PendingToDeviceMessage,
+    to_device_inbox::StoredToDeviceEvent,
+    types::Curve25519PublicKey,
+    verification::StoredVerificationFlow,
 };
 
 /// This needs to be 32 bytes long since AES-GCM requires it, otherwise we will
 /// panic once we try to pickle a Signing object.
 const DEFAULT_PICKLE: &str = "DEFAULT_PICKLE_PASSPHRASE_123456";
 
+/// The version of the layout of the stored data, stores with an older version
+/// are migrated when they are opened.
+///
+/// * Version 1 stores the timestamps of Olm and outbound group sessions as
+/// wall-clock time instead of the time that passed before they were stored.
+/// * Version 2 populates the `devices_by_curve_key` index for the devices that
+/// were stored before the index existed.
+const DATABASE_VERSION: u8 = 2;
+
 trait EncodeKey {
     const SEPARATOR: u8 = 0xff;
     fn encode(&self) -> Vec<u8>;
@@ -109,6 +137,68 @@ impl EncodeKey for (&str, &str, &str) {
     }
 }
 
+/// How durable the writes of the `SledStore` should be.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// Flush the database to disk after every save, a successfully finished
+    /// save will survive a crash of the application or of the OS.
+    Full,
+    /// Rely on the periodic background flush of the database, saves can be
+    /// lost if the application crashes before the next flush.
+    ///
+    /// This lowers the latency of a save considerably on slow storage, but
+    /// losing Olm sessions or room keys might result in undecryptable
+    /// messages, so use with care.
+    Periodic,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::Full
+    }
+}
+
+/// Configuration for the sled based cryptostore.
+#[derive(Clone, Debug)]
+pub struct SledStoreConfig {
+    /// How durable the writes of the store should be.
+    pub durability: Durability,
+    /// How often the database should be flushed in the background, in
+    /// milliseconds. `None` disables the background flush.
+    pub flush_every_ms: Option<u64>,
+    /// The maximum size of the page cache of the database, in bytes.
+    pub cache_capacity: u64,
+    /// Should the database prefer write throughput over disk space usage.
+    pub high_throughput: bool,
+    /// The maximum number of sender keys whose Olm sessions are kept in
+    /// memory. `None` keeps the sessions of all sender keys that were used in
+    /// memory.
+    pub session_cache_capacity: Option<usize>,
+}
+
+impl Default for SledStoreConfig {
+    fn default() -> Self {
+        Self {
+            durability: Durability::default(),
+            flush_every_ms: Some(500),
+            cache_capacity: 1024 * 1024 * 1024,
+            high_throughput: false,
+            session_cache_capacity: None,
+        }
+    }
+}
+
+impl SledStoreConfig {
+    fn sled_config(&self) -> Config {
+        let mode = if self.high_throughput { Mode::HighThroughput } else { Mode::LowSpace };
+
+        Config::new()
+            .flush_every_ms(self.flush_every_ms)
+            .cache_capacity(self.cache_capacity)
+            .mode(mode)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct AccountInfo {
     user_id: Arc<UserId>,
@@ -122,9 +212,15 @@ pub struct SledStore {
     account_info: Arc<RwLock<Option<AccountInfo>>>,
     path: Option<PathBuf>,
     inner: Db,
-    pickle_key: Arc<PickleKey>,
+    pickle_key: Arc<RwLock<Arc<PickleKey>>>,
+    /// Writes of encrypted or pickled values hold this lock shared, changing
+    /// the passphrase holds it exclusively while everything is re-encrypted.
+    write_lock: Arc<AsyncRwLock<()>>,
+    durability: Durability,
+    read_only: bool,
 
     session_cache: SessionStore,
+    interner: IdentifierInterner,
     tracked_users_cache: Arc<DashSet<UserId>>,
     users_for_key_query_cache: Arc<DashSet<UserId>>,
 
@@ -141,8 +237,19 @@ pub struct SledStore {
     key_requests_by_info: Tree,
 
     devices: Tree,
+    devices_by_curve_key: Tree,
     identities: Tree,
 
+    sharing_history: Tree,
+    room_key_bundles: Tree,
+    withheld_info: Tree,
+    verification_flows: Tree,
+    outgoing_requests: Tree,
+    inbox_events: Tree,
+    leases: Tree,
+    pending_to_device_messages: Tree,
+    custom_values: Tree,
+
     tracked_users: Tree,
     users_for_key_query: Tree,
 }
@@ -170,45 +277,262 @@ impl SledStore {
     /// Open the sled based cryptostore at the given path using the given
     /// passphrase to encrypt private data.
     pub fn open_with_passphrase(path: impl AsRef<Path>, passphrase: Option<&str>) -> Result<Self> {
+        SledStore::open_with_config(path, passphrase, SledStoreConfig::default())
+    }
+
+    /// Open the sled based cryptostore at the given path using the given
+    /// passphrase to encrypt private data and the given config to tune the
+    /// database.
+    pub fn open_with_config(
+        path: impl AsRef<Path>,
+        passphrase: Option<&str>,
+        config: SledStoreConfig,
+    ) -> Result<Self> {
+        let path = path.as_ref().join("matrix-sdk-crypto");
+        let db = config.sled_config().temporary(false).path(&path).open()?;
+
+        SledStore::open_helper(db, Some(path), passphrase, &config, false)
+    }
+
+    /// Open an existing sled based cryptostore at the given path in read-only
+    /// mode.
+    ///
+    /// The store won't be created if it doesn't exist and every attempt to
+    /// modify the store will fail with a [`CryptoStoreError::ReadOnly`] error.
+    /// This allows auxiliary processes, e.g. a notification service that
+    /// decrypts events, to read the sessions without advancing any ratchets
+    /// persistently.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path the store was created at.
+    ///
+    /// * `passphrase` - The passphrase that was used to encrypt private data.
+    pub fn open_read_only(path: impl AsRef<Path>, passphrase: Option<&str>) -> Result<Self> {
         let path = path.as_ref().join("matrix-sdk-crypto");
-        let db = Config::new().temporary(false).path(&path).open()?;
 
-        SledStore::open_helper(db, Some(path), passphrase)
+        if !path.exists() {
+            return Err(IoError::new(ErrorKind::NotFound, "The crypto store doesn't exist").into());
+        }
+
+        let config = SledStoreConfig { flush_every_ms: None, ..Default::default() };
+        let db = config.sled_config().temporary(false).path(&path).open()?;
+
+        SledStore::open_helper(db, Some(path), passphrase, &config, true)
     }
 
     /// Create a sled based cryptostore using the given sled database.
     /// The given passphrase will be used to encrypt private data.
     pub fn open_with_database(db: Db, passphrase: Option<&str>) -> Result<Self> {
-        SledStore::open_helper(db, None, passphrase)
+        SledStore::open_helper(db, None, passphrase, &SledStoreConfig::default(), false)
+    }
+
+    /// Was the store opened in read-only mode.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(CryptoStoreError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Atomically replace the lease with the given key with the one the
+    /// given closure returns, the lease is left alone if it returns `None`.
+    ///
+    /// Returns the new lease.
+    async fn update_lease(
+        &self,
+        key: &str,
+        mut update: impl FnMut(Option<&Lease>) -> Option<Lease>,
+    ) -> Result<Option<Lease>> {
+        self.ensure_writable()?;
+
+        let mut result = Ok(None);
+
+        // The closure may run multiple times if another process changes the
+        // lease concurrently, only the last run counts.
+        self.leases.fetch_and_update(key.encode(), |current| {
+            let lease = current.and_then(|l| serde_json::from_slice::<Lease>(l).ok());
+
+            match update(lease.as_ref()).map(|l| serde_json::to_vec(&l).map(|v| (l, v))) {
+                Some(Ok((lease, value))) => {
+                    result = Ok(Some(lease));
+                    Some(value)
+                }
+                Some(Err(e)) => {
+                    result = Err(e);
+                    current.map(|l| l.to_vec())
+                }
+                None => {
+                    result = Ok(None);
+                    current.map(|l| l.to_vec())
+                }
+            }
+        })?;
+
+        let lease = result?;
+        self.inner.flush_async().await?;
+
+        Ok(lease)
+    }
+
+    /// Get statistics about the interned user and device ids of the devices
+    /// this store loaded.
+    pub fn interner_stats(&self) -> InternerStats {
+        self.interner.stats()
+    }
+
+    /// Get the key and the value of the entry in the `devices_by_curve_key`
+    /// index for the given device.
+    fn curve_key_index_entry(device: &ReadOnlyDevice) -> Option<(Vec<u8>, Vec<u8>)> {
+        device.get_key(DeviceKeyAlgorithm::Curve25519).map(|curve_key| {
+            (
+                (device.user_id().as_str(), curve_key.as_str()).encode(),
+                device.device_id().as_str().as_bytes().to_vec(),
+            )
+        })
     }
 
     fn get_account_info(&self) -> Option<AccountInfo> {
         self.account_info.read().unwrap().clone()
     }
 
-    fn open_helper(db: Db, path: Option<PathBuf>, passphrase: Option<&str>) -> Result<Self> {
-        let account = db.open_tree("account")?;
-        let private_identity = db.open_tree("private_identity")?;
+    /// Migrate the data of a store that was created by an older version.
+    fn upgrade(
+        db: &Db,
+        sessions: &Tree,
+        outbound_group_sessions: &Tree,
+        devices: &Tree,
+        devices_by_curve_key: &Tree,
+    ) -> Result<()> {
+        let version =
+            db.get("store_version".encode())?.and_then(|v| v.first().copied()).unwrap_or_default();
+
+        if version >= DATABASE_VERSION {
+            return Ok(());
+        }
+
+        if version < 1 {
+            // Pickles in the old format are converted when they are
+            // deserialized, storing them again persists the converted
+            // timestamps before more time passes.
+            Self::migrate_pickles::<PickledSession>(sessions)?;
+            Self::migrate_pickles::<PickledOutboundGroupSession>(outbound_group_sessions)?;
+        }
+
+        if version < 2 {
+            // The index is rebuilt in a single batch, the version is only
+            // bumped once the batch was applied so an interrupted migration
+            // is repeated the next time the store is opened.
+            let mut index = sled::Batch::default();
+
+            for value in devices.iter() {
+                let device: ReadOnlyDevice = serde_json::from_slice(&value?.1)?;
+
+                if let Some((key, device_id)) = Self::curve_key_index_entry(&device) {
+                    index.insert(key, device_id);
+                }
+            }
+
+            devices_by_curve_key.apply_batch(index)?;
+        }
+
+        db.insert("store_version".encode(), vec![DATABASE_VERSION])?;
+
+        Ok(())
+    }
+
+    /// Deserialize and store again all the pickles of the given tree.
+    fn migrate_pickles<T: DeserializeOwned + Serialize>(tree: &Tree) -> Result<()> {
+        let pickles: Vec<_> = tree.iter().collect::<Result<_, _>>()?;
 
-        let sessions = db.open_tree("session")?;
-        let inbound_group_sessions = db.open_tree("inbound_group_sessions")?;
-        let outbound_group_sessions = db.open_tree("outbound_group_sessions")?;
+        for (key, value) in pickles {
+            let pickle: T = serde_json::from_slice(&value)?;
+            tree.insert(key, serde_json::to_vec(&pickle)?)?;
+        }
+
+        Ok(())
+    }
 
-        let tracked_users = db.open_tree("tracked_users")?;
-        let users_for_key_query = db.open_tree("users_for_key_query")?;
-        let olm_hashes = db.open_tree("olm_hashes")?;
+    fn open_helper(
+        db: Db,
+        path: Option<PathBuf>,
+        passphrase: Option<&str>,
+        config: &SledStoreConfig,
+        read_only: bool,
+    ) -> Result<Self> {
+        // Sled creates trees that don't exist when they are opened, make sure
+        // a read-only store was initialized before.
+        if read_only && !db.tree_names().iter().any(|name| name.as_ref() == b"account") {
+            return Err(IoError::new(ErrorKind::NotFound, "The crypto store doesn't exist").into());
+        }
 
-        let devices = db.open_tree("devices")?;
-        let identities = db.open_tree("identities")?;
+        // A read-only store must not create the trees it is missing, e.g.
+        // because they were added in a later version of the store. Open them
+        // in a temporary database instead, so they act like empty trees.
+        let scratch = if read_only { Some(Config::new().temporary(true).open()?) } else { None };
+        let tree_names = db.tree_names();
+
+        let open_tree = |name: &str| -> Result<Tree> {
+            match &scratch {
+                Some(scratch) if !tree_names.iter().any(|n| n.as_ref() == name.as_bytes()) => {
+                    Ok(scratch.open_tree(name)?)
+                }
+                _ => Ok(db.open_tree(name)?),
+            }
+        };
 
-        let outgoing_key_requests = db.open_tree("outgoing_key_requests")?;
-        let unsent_key_requests = db.open_tree("unsent_key_requests")?;
-        let key_requests_by_info = db.open_tree("key_requests_by_info")?;
+        let account = open_tree("account")?;
+        let private_identity = open_tree("private_identity")?;
+
+        let sessions = open_tree("session")?;
+        let inbound_group_sessions = open_tree("inbound_group_sessions")?;
+        let outbound_group_sessions = open_tree("outbound_group_sessions")?;
+
+        let tracked_users = open_tree("tracked_users")?;
+        let users_for_key_query = open_tree("users_for_key_query")?;
+        let olm_hashes = open_tree("olm_hashes")?;
+
+        let devices = open_tree("devices")?;
+        let devices_by_curve_key = open_tree("devices_by_curve_key")?;
+        let identities = open_tree("identities")?;
+
+        if !read_only {
+            Self::upgrade(
+                &db,
+                &sessions,
+                &outbound_group_sessions,
+                &devices,
+                &devices_by_curve_key,
+            )?;
+        }
 
-        let session_cache = SessionStore::new();
+        let sharing_history = open_tree("sharing_history")?;
+        let room_key_bundles = open_tree("room_key_bundles")?;
+        let withheld_info = open_tree("withheld_info")?;
+        let verification_flows = open_tree("verification_flows")?;
+        let outgoing_requests = open_tree("outgoing_requests")?;
+        let inbox_events = open_tree("inbox_events")?;
+        let leases = open_tree("leases")?;
+        let pending_to_device_messages = open_tree("pending_to_device_messages")?;
+        let custom_values = open_tree("custom_values")?;
+
+        let outgoing_key_requests = open_tree("outgoing_key_requests")?;
+        let unsent_key_requests = open_tree("unsent_key_requests")?;
+        let key_requests_by_info = open_tree("key_requests_by_info")?;
+
+        let session_cache = if let Some(capacity) = config.session_cache_capacity {
+            SessionStore::with_capacity(capacity)
+        } else {
+            SessionStore::new()
+        };
 
         let pickle_key = if let Some(passphrase) = passphrase {
-            Self::get_or_create_pickle_key(passphrase, &db)?
+            Self::get_or_create_pickle_key(passphrase, &db, read_only)?
         } else {
             PickleKey::try_from(DEFAULT_PICKLE.as_bytes().to_vec())
                 .expect("Can't create default pickle key")
@@ -218,11 +542,15 @@ impl SledStore {
             account_info: RwLock::new(None).into(),
             path,
             inner: db,
-            pickle_key: pickle_key.into(),
+            pickle_key: RwLock::new(Arc::new(pickle_key)).into(),
+            write_lock: AsyncRwLock::new(()).into(),
+            durability: config.durability,
+            read_only,
             account,
             private_identity,
             sessions,
             session_cache,
+            interner: IdentifierInterner::new(),
             tracked_users_cache: DashSet::new().into(),
             users_for_key_query_cache: DashSet::new().into(),
             inbound_group_sessions,
@@ -231,19 +559,35 @@ impl SledStore {
             unsent_key_requests,
             key_requests_by_info,
             devices,
+            devices_by_curve_key,
             tracked_users,
             users_for_key_query,
             olm_hashes,
             identities,
+            sharing_history,
+            room_key_bundles,
+            withheld_info,
+            verification_flows,
+            outgoing_requests,
+            inbox_events,
+            leases,
+            pending_to_device_messages,
+            custom_values,
         })
     }
 
-    fn get_or_create_pickle_key(passphrase: &str, database: &Db) -> Result<PickleKey> {
+    fn get_or_create_pickle_key(
+        passphrase: &str,
+        database: &Db,
+        read_only: bool,
+    ) -> Result<PickleKey> {
         let key = if let Some(key) =
             database.get("pickle_key".encode())?.map(|v| serde_json::from_slice(&v))
         {
             PickleKey::from_encrypted(passphrase, key?)
                 .map_err(|_| CryptoStoreError::UnpicklingError)?
+        } else if read_only {
+            return Err(CryptoStoreError::ReadOnly);
         } else {
             let key = PickleKey::new();
             let encrypted = key.encrypt(passphrase);
@@ -254,12 +598,45 @@ impl SledStore {
         Ok(key)
     }
 
+    async fn flush(&self) -> Result<()> {
+        if self.durability == Durability::Full {
+            self.inner.flush_async().await?;
+        }
+
+        Ok(())
+    }
+
     fn get_pickle_mode(&self) -> PicklingMode {
-        self.pickle_key.pickle_mode()
+        self.get_pickle_key().pickle_mode()
+    }
+
+    fn get_pickle_key(&self) -> Arc<PickleKey> {
+        self.pickle_key.read().unwrap().clone()
+    }
+
+    fn decrypt_room_key_bundle(&self, value: &[u8]) -> Result<StoredRoomKeyBundleData> {
+        let value = self
+            .get_pickle_key()
+            .decrypt_value(value)
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+        Ok(serde_json::from_slice(&value)?)
     }
 
-    fn get_pickle_key(&self) -> &[u8] {
-        self.pickle_key.key()
+    /// Check that the given passphrase is the one that protects our pickle
+    /// key, `None` if the store isn't protected by a passphrase.
+    fn check_passphrase(&self, passphrase: Option<&str>) -> Result<()> {
+        let encrypted = self.inner.get("pickle_key".encode())?;
+
+        match (encrypted, passphrase) {
+            (Some(encrypted), Some(passphrase)) => {
+                PickleKey::from_encrypted(passphrase, serde_json::from_slice(&encrypted)?)
+                    .map(|_| ())
+                    .map_err(|_| CryptoStoreError::UnpicklingError)
+            }
+            (None, None) => Ok(()),
+            _ => Err(CryptoStoreError::UnpicklingError),
+        }
     }
 
     async fn load_tracked_users(&self) -> Result<()> {
@@ -300,7 +677,49 @@ impl SledStore {
             .transpose()
     }
 
+    /// Get a stream over the inbound group sessions whose pickle passes the
+    /// given filter.
+    ///
+    /// Sled iterates over the tree lazily, a session is only read from the
+    /// database and unpickled once the stream is polled for it.
+    fn stream_inbound_group_sessions(
+        &self,
+        filter: impl Fn(&PickledInboundGroupSession) -> bool + Send + 'static,
+    ) -> InboundGroupSessionStream<'_> {
+        let pickle_key = self.get_pickle_key();
+
+        let sessions = self.inbound_group_sessions.iter().filter_map(move |value| {
+            let pickle: PickledInboundGroupSession = match value
+                .map_err(CryptoStoreError::from)
+                .and_then(|(_, p)| Ok(serde_json::from_slice(&p)?))
+            {
+                Ok(p) => p,
+                Err(e) => return Some(Err(e)),
+            };
+
+            if filter(&pickle) {
+                Some(
+                    InboundGroupSession::from_pickle(pickle, pickle_key.pickle_mode())
+                        .map_err(CryptoStoreError::from),
+                )
+            } else {
+                None
+            }
+        });
+
+        Box::pin(futures::stream::iter(sessions))
+    }
+
     async fn save_changes(&self, changes: Changes) -> Result<()> {
+        self.ensure_writable()?;
+        let _guard = self.write_lock.read().await;
+
+        let account_info = changes.account.as_ref().map(|a| AccountInfo {
+            user_id: a.user_id.clone(),
+            device_id: a.device_id.clone(),
+            identity_keys: a.identity_keys.clone(),
+        });
+
         let account_pickle = if let Some(a) = changes.account {
             Some(a.pickle(self.get_pickle_mode()).await)
         } else {
@@ -308,22 +727,47 @@ impl SledStore {
         };
 
         let private_identity_pickle = if let Some(i) = changes.private_identity {
-            Some(i.pickle(DEFAULT_PICKLE.as_bytes()).await?)
+            Some(i.pickle(self.get_pickle_key().key()).await?)
         } else {
             None
         };
 
         let device_changes = changes.devices;
+
+        // Serialize the devices once, outside of the transaction, the
+        // transaction closure might be retried on conflicts.
+        let device_changes_serialized = device_changes
+            .new
+            .iter()
+            .chain(&device_changes.changed)
+            .map(|d| {
+                let key = (d.user_id().as_str(), d.device_id().as_str()).encode();
+                Ok((key, serde_json::to_vec(&d)?))
+            })
+            .collect::<Result<Vec<(Vec<u8>, Vec<u8>)>>>()?;
+        let deleted_devices: Vec<Vec<u8>> = device_changes
+            .deleted
+            .iter()
+            .map(|d| (d.user_id().as_str(), d.device_id().as_str()).encode())
+            .collect();
+        let curve_key_index_changes: Vec<(Vec<u8>, Vec<u8>)> = device_changes
+            .new
+            .iter()
+            .chain(&device_changes.changed)
+            .filter_map(Self::curve_key_index_entry)
+            .collect();
+        let deleted_curve_key_index: Vec<(Vec<u8>, Vec<u8>)> =
+            device_changes.deleted.iter().filter_map(Self::curve_key_index_entry).collect();
+
         let mut session_changes = HashMap::new();
 
-        for session in changes.sessions {
+        for session in &changes.sessions {
             let sender_key = session.sender_key();
             let session_id = session.session_id();
 
             let pickle = session.pickle(self.get_pickle_mode()).await;
             let key = (sender_key, session_id).encode();
 
-            self.session_cache.add(session).await;
             session_changes.insert(key, pickle);
         }
 
@@ -348,14 +792,53 @@ impl SledStore {
             outbound_session_changes.insert(room_id.clone(), pickle);
         }
 
+        let pickle_key = self.get_pickle_key();
+        let custom_values: Vec<(Vec<u8>, Vec<u8>)> = changes
+            .custom_values
+            .iter()
+            .map(|(key, value)| (key.as_str().encode(), pickle_key.encrypt_value(value)))
+            .collect();
+
         let identity_changes = changes.identities;
         let olm_hashes = changes.message_hashes;
         let key_requests = changes.key_requests;
 
+        // The sharing history is append only, there's no need to make it part
+        // of the transaction.
+        let mut sharing_history = sled::Batch::default();
+
+        for entry in &changes.sharing_history {
+            let key = [
+                entry.session_id.as_str().encode(),
+                self.inner.generate_id()?.to_be_bytes().to_vec(),
+            ]
+            .concat();
+            sharing_history.insert(key, serde_json::to_vec(entry)?);
+        }
+
+        // Only the latest bundle of a user is kept for a room, a bundle
+        // replaces the previous one as a whole. The bundles contain the keys
+        // to decrypt the room key files, so they are encrypted.
+        let mut room_key_bundles = sled::Batch::default();
+
+        for bundle in &changes.room_key_bundles {
+            let key = (bundle.room_id.as_str(), bundle.sender_user.as_str()).encode();
+            room_key_bundles.insert(key, pickle_key.encrypt_value(&serde_json::to_vec(bundle)?));
+        }
+
+        // A newer withheld notice for a session replaces the older one.
+        let mut withheld_info = sled::Batch::default();
+
+        for info in &changes.withheld_session_info {
+            let key = (info.room_id.as_str(), info.session_id.as_str()).encode();
+            withheld_info.insert(key, serde_json::to_vec(info)?);
+        }
+
         let ret: Result<(), TransactionError<serde_json::Error>> = (
             &self.account,
             &self.private_identity,
             &self.devices,
+            &self.devices_by_curve_key,
             &self.identities,
             &self.sessions,
             &self.inbound_group_sessions,
@@ -364,12 +847,14 @@ impl SledStore {
             &self.outgoing_key_requests,
             &self.unsent_key_requests,
             &self.key_requests_by_info,
+            &self.custom_values,
         )
             .transaction(
                 |(
                     account,
                     private_identity,
                     devices,
+                    devices_by_curve_key,
                     identities,
                     sessions,
                     inbound_sessions,
@@ -378,6 +863,7 @@ impl SledStore {
                     outgoing_key_requests,
                     unsent_key_requests,
                     key_requests_by_info,
+                    custom_values_tree,
                 )| {
                     if let Some(a) = &account_pickle {
                         account.insert(
@@ -393,16 +879,42 @@ impl SledStore {
                         )?;
                     }
 
-                    for device in device_changes.new.iter().chain(&device_changes.changed) {
-                        let key = (device.user_id().as_str(), device.device_id().as_str()).encode();
-                        let device = serde_json::to_vec(&device)
-                            .map_err(ConflictableTransactionError::Abort)?;
-                        devices.insert(key, device)?;
+                    for (key, device) in &device_changes_serialized {
+                        // Drop the index entry of the previous curve key of
+                        // the device, the entry for the current key is
+                        // inserted again below.
+                        if let Some(old) = devices.get(key.as_slice())? {
+                            let old: ReadOnlyDevice = serde_json::from_slice(&old)
+                                .map_err(ConflictableTransactionError::Abort)?;
+
+                            if let Some((old_key, device_id)) = Self::curve_key_index_entry(&old) {
+                                if devices_by_curve_key.get(old_key.as_slice())?.as_deref()
+                                    == Some(device_id.as_slice())
+                                {
+                                    devices_by_curve_key.remove(old_key.as_slice())?;
+                                }
+                            }
+                        }
+
+                        devices.insert(key.as_slice(), device.as_slice())?;
+                    }
+
+                    for key in &deleted_devices {
+                        devices.remove(key.as_slice())?;
+                    }
+
+                    for (key, device_id) in &deleted_curve_key_index {
+                        // Only remove the entry if no other device took over
+                        // the curve key in the meantime.
+                        if devices_by_curve_key.get(key.as_slice())?.as_deref()
+                            == Some(device_id.as_slice())
+                        {
+                            devices_by_curve_key.remove(key.as_slice())?;
+                        }
                     }
 
-                    for device in &device_changes.deleted {
-                        let key = (device.user_id().as_str(), device.device_id().as_str()).encode();
-                        devices.remove(key)?;
+                    for (key, device_id) in &curve_key_index_changes {
+                        devices_by_curve_key.insert(key.as_slice(), device_id.as_slice())?;
                     }
 
                     for identity in identity_changes.changed.iter().chain(&identity_changes.new) {
@@ -470,12 +982,30 @@ impl SledStore {
                         }
                     }
 
+                    for (key, value) in &custom_values {
+                        custom_values_tree.insert(key.as_slice(), value.as_slice())?;
+                    }
+
                     Ok(())
                 },
             );
 
         ret?;
-        self.inner.flush_async().await?;
+        self.sharing_history.apply_batch(sharing_history)?;
+        self.room_key_bundles.apply_batch(room_key_bundles)?;
+        self.withheld_info.apply_batch(withheld_info)?;
+        self.flush().await?;
+
+        if let Some(account_info) = account_info {
+            *self.account_info.write().unwrap() = Some(account_info);
+        }
+
+        // Only put the sessions into the cache once they are persisted, the
+        // cache might evict them at any point. Sessions of sender keys that
+        // aren't cached will be loaded from the store once they're needed.
+        for session in changes.sessions {
+            self.session_cache.update(session).await;
+        }
 
         Ok(())
     }
@@ -522,14 +1052,6 @@ impl CryptoStore for SledStore {
     }
 
     async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
-        let account_info = AccountInfo {
-            user_id: account.user_id.clone(),
-            device_id: account.device_id.clone(),
-            identity_keys: account.identity_keys.clone(),
-        };
-
-        *self.account_info.write().unwrap() = Some(account_info);
-
         let changes = Changes { account: Some(account), ..Default::default() };
 
         self.save_changes(changes).await
@@ -539,7 +1061,7 @@ impl CryptoStore for SledStore {
         if let Some(i) = self.private_identity.get("identity".encode())? {
             let pickle = serde_json::from_slice(&i)?;
             Ok(Some(
-                PrivateCrossSigningIdentity::from_pickle(pickle, self.get_pickle_key())
+                PrivateCrossSigningIdentity::from_pickle(pickle, self.get_pickle_key().key())
                     .await
                     .map_err(|_| CryptoStoreError::UnpicklingError)?,
             ))
@@ -572,7 +1094,7 @@ impl CryptoStore for SledStore {
                 })
                 .collect();
 
-            self.session_cache.set_for_sender(sender_key, sessions?);
+            self.session_cache.set_for_sender(sender_key, sessions?).await;
         }
 
         Ok(self.session_cache.get(sender_key))
@@ -607,6 +1129,80 @@ impl CryptoStore for SledStore {
             .collect())
     }
 
+    fn inbound_group_sessions_stream(&self) -> InboundGroupSessionStream<'_> {
+        self.stream_inbound_group_sessions(|_| true)
+    }
+
+    fn inbound_group_sessions_for_backup_stream(&self) -> InboundGroupSessionStream<'_> {
+        self.stream_inbound_group_sessions(|p| !p.backed_up)
+    }
+
+    async fn mark_inbound_group_sessions_as_backed_up(
+        &self,
+        backup_version: &str,
+        sessions: &[(&RoomId, &str, &str)],
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        let _guard = self.write_lock.read().await;
+
+        let keys: Vec<_> = sessions
+            .iter()
+            .map(|(room_id, sender_key, session_id)| {
+                (room_id.as_str(), *sender_key, *session_id).encode()
+            })
+            .collect();
+
+        // Only the backup state is touched, the pickle doesn't need to be
+        // unpickled to do so.
+        let ret: Result<(), TransactionError<serde_json::Error>> =
+            self.inbound_group_sessions.transaction(|tree| {
+                for key in &keys {
+                    if let Some(pickle) = tree.get(key)? {
+                        let mut pickle: PickledInboundGroupSession =
+                            serde_json::from_slice(&pickle)
+                                .map_err(ConflictableTransactionError::Abort)?;
+
+                        pickle.backed_up = true;
+                        pickle.backup_version = Some(backup_version.to_owned());
+
+                        tree.insert(
+                            key.as_slice(),
+                            serde_json::to_vec(&pickle)
+                                .map_err(ConflictableTransactionError::Abort)?,
+                        )?;
+                    }
+                }
+
+                Ok(())
+            });
+
+        ret?;
+
+        self.flush().await
+    }
+
+    async fn reset_backup_state(&self) -> Result<()> {
+        self.ensure_writable()?;
+        let _guard = self.write_lock.read().await;
+
+        let mut batch = sled::Batch::default();
+
+        for value in self.inbound_group_sessions.iter() {
+            let (key, pickle) = value?;
+            let mut pickle: PickledInboundGroupSession = serde_json::from_slice(&pickle)?;
+
+            if pickle.backed_up || pickle.backup_version.is_some() {
+                pickle.backed_up = false;
+                pickle.backup_version = None;
+                batch.insert(key, serde_json::to_vec(&pickle)?);
+            }
+        }
+
+        self.inbound_group_sessions.apply_batch(batch)?;
+
+        self.flush().await
+    }
+
     async fn get_outbound_group_sessions(
         &self,
         room_id: &RoomId,
@@ -622,12 +1218,19 @@ impl CryptoStore for SledStore {
         !self.users_for_key_query_cache.is_empty()
     }
 
+    fn tracked_users(&self) -> HashSet<UserId> {
+        #[allow(clippy::map_clone)]
+        self.tracked_users_cache.iter().map(|u| u.clone()).collect()
+    }
+
     fn users_for_key_query(&self) -> HashSet<UserId> {
         #[allow(clippy::map_clone)]
         self.users_for_key_query_cache.iter().map(|u| u.clone()).collect()
     }
 
     async fn update_tracked_user(&self, user: &UserId, dirty: bool) -> Result<bool> {
+        self.ensure_writable()?;
+
         let already_added = self.tracked_users_cache.insert(user.clone());
 
         if dirty {
@@ -649,12 +1252,48 @@ impl CryptoStore for SledStore {
         let key = (user_id.as_str(), device_id.as_str()).encode();
 
         if let Some(d) = self.devices.get(key)? {
-            Ok(Some(serde_json::from_slice(&d)?))
+            let device: ReadOnlyDevice = serde_json::from_slice(&d)?;
+            Ok(Some(device.with_interned_ids(&self.interner)))
         } else {
             Ok(None)
         }
     }
 
+    async fn get_device_from_curve_key(
+        &self,
+        user_id: &UserId,
+        curve_key: &str,
+    ) -> Result<Option<ReadOnlyDevice>> {
+        let parsed_key = match Curve25519PublicKey::from_base64(curve_key) {
+            Ok(k) => k,
+            Err(_) => return Ok(None),
+        };
+
+        if let Some(d) = self.devices_by_curve_key.get((user_id.as_str(), curve_key).encode())? {
+            let device_id: DeviceIdBox = String::from_utf8_lossy(&d).to_string().into();
+
+            // The device might have changed its keys since the index entry
+            // was written.
+            if let Some(device) = self
+                .get_device(user_id, &device_id)
+                .await?
+                .filter(|d| d.curve25519_key() == Some(parsed_key))
+            {
+                return Ok(Some(device));
+            }
+        }
+
+        // The index might be missing entries, e.g. if a read-only store was
+        // opened before the index was populated, fall back to looking at all
+        // the devices of the user.
+        Ok(self
+            .get_user_devices(user_id)
+            .await?
+            .into_iter()
+            .map(|(_, d)| d)
+            .find(|d| d.curve25519_key() == Some(parsed_key)))
+    }
+
     async fn get_user_devices(
         &self,
         user_id: &UserId,
@@ -664,6 +1303,7 @@ impl CryptoStore for SledStore {
             .map(|d| serde_json::from_slice(&d?.1).map_err(CryptoStoreError::Serialization))
             .map(|d| {
                 let d: ReadOnlyDevice = d?;
+                let d = d.with_interned_ids(&self.interner);
                 Ok((d.device_id().to_owned(), d))
             })
             .collect()
@@ -714,6 +1354,8 @@ impl CryptoStore for SledStore {
     }
 
     async fn delete_outgoing_key_request(&self, request_id: Uuid) -> Result<()> {
+        self.ensure_writable()?;
+
         let ret: Result<(), TransactionError<serde_json::Error>> =
             (&self.outgoing_key_requests, &self.unsent_key_requests, &self.key_requests_by_info)
                 .transaction(
@@ -743,84 +1385,486 @@ impl CryptoStore for SledStore {
                 );
 
         ret?;
-        self.inner.flush_async().await?;
+        self.flush().await?;
 
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::collections::BTreeMap;
+    fn release_memory(&self) {
+        self.session_cache.shrink_to_fit();
+    }
 
-    use matrix_sdk_common::uuid::Uuid;
-    use matrix_sdk_test::async_test;
-    use olm_rs::outbound_group_session::OlmOutboundGroupSession;
-    use ruma::{
-        api::client::r0::keys::SignedKey,
-        events::room_key_request::RequestedKeyInfo,
-        identifiers::{room_id, user_id, DeviceId, EventEncryptionAlgorithm, UserId},
-    };
-    use tempfile::tempdir;
+    async fn get_sharing_history(&self, session_id: &str) -> Result<Vec<SharingHistoryEntry>> {
+        self.sharing_history
+            .scan_prefix(session_id.encode())
+            .map(|e| serde_json::from_slice(&e?.1).map_err(CryptoStoreError::Serialization))
+            .collect()
+    }
 
-    use super::{CryptoStore, OutgoingKeyRequest, SledStore};
-    use crate::{
-        identities::{
-            device::test::get_device,
-            user::test::{get_other_identity, get_own_identity},
-        },
-        olm::{
-            GroupSessionKey, InboundGroupSession, OlmMessageHash, PrivateCrossSigningIdentity,
-            ReadOnlyAccount, Session,
-        },
-        store::{Changes, DeviceChanges, IdentityChanges},
-    };
+    async fn get_received_room_key_bundle_data(
+        &self,
+        room_id: &RoomId,
+        sender_user: &UserId,
+    ) -> Result<Option<StoredRoomKeyBundleData>> {
+        self.room_key_bundles
+            .get((room_id.as_str(), sender_user.as_str()).encode())?
+            .map(|v| self.decrypt_room_key_bundle(&v))
+            .transpose()
+    }
 
-    fn alice_id() -> UserId {
-        user_id!("@alice:example.org")
+    async fn get_received_room_key_bundles(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Vec<StoredRoomKeyBundleData>> {
+        self.room_key_bundles
+            .scan_prefix(room_id.as_str().encode())
+            .map(|e| self.decrypt_room_key_bundle(&e?.1))
+            .collect()
     }
 
-    fn alice_device_id() -> Box<DeviceId> {
-        "ALICEDEVICE".into()
+    async fn delete_received_room_key_bundles(&self, room_id: &RoomId) -> Result<()> {
+        self.ensure_writable()?;
+
+        let mut batch = sled::Batch::default();
+
+        for key in self.room_key_bundles.scan_prefix(room_id.as_str().encode()).keys() {
+            batch.remove(key?);
+        }
+
+        self.room_key_bundles.apply_batch(batch)?;
+
+        self.flush().await
     }
 
-    fn bob_id() -> UserId {
-        user_id!("@bob:example.org")
+    async fn get_withheld_info(
+        &self,
+        room_id: &RoomId,
+        session_id: &str,
+    ) -> Result<Option<RoomKeyWithheldInfo>> {
+        self.withheld_info
+            .get((room_id.as_str(), session_id).encode())?
+            .map(|v| serde_json::from_slice(&v).map_err(CryptoStoreError::Serialization))
+            .transpose()
     }
 
-    fn bob_device_id() -> Box<DeviceId> {
-        "BOBDEVICE".into()
+    async fn save_verification_flow(&self, flow: &StoredVerificationFlow) -> Result<()> {
+        self.ensure_writable()?;
+
+        self.verification_flows
+            .insert(flow.flow_id.as_str().encode(), serde_json::to_vec(flow)?)?;
+        self.flush().await
     }
 
-    async fn get_store(passphrase: Option<&str>) -> (SledStore, tempfile::TempDir) {
-        let tmpdir = tempdir().unwrap();
-        let tmpdir_path = tmpdir.path().to_str().unwrap();
+    async fn get_verification_flows(&self) -> Result<Vec<StoredVerificationFlow>> {
+        self.verification_flows
+            .iter()
+            .map(|e| serde_json::from_slice(&e?.1).map_err(CryptoStoreError::Serialization))
+            .collect()
+    }
 
-        let store = SledStore::open_with_passphrase(tmpdir_path, passphrase)
-            .expect("Can't create a passphrase protected store");
+    async fn remove_verification_flow(&self, flow_id: &str) -> Result<()> {
+        self.ensure_writable()?;
 
-        (store, tmpdir)
+        self.verification_flows.remove(flow_id.encode())?;
+        self.flush().await
     }
 
-    async fn get_loaded_store() -> (ReadOnlyAccount, SledStore, tempfile::TempDir) {
-        let (store, dir) = get_store(None).await;
-        let account = get_account();
-        store.save_account(account.clone()).await.expect("Can't save account");
+    async fn save_outgoing_request(&self, request: &StoredOutgoingRequest) -> Result<()> {
+        self.ensure_writable()?;
 
-        (account, store, dir)
+        self.outgoing_requests.insert(request.request_id.encode(), serde_json::to_vec(request)?)?;
+        self.flush().await
     }
 
-    fn get_account() -> ReadOnlyAccount {
-        ReadOnlyAccount::new(&alice_id(), &alice_device_id())
+    async fn get_outgoing_requests(&self) -> Result<Vec<StoredOutgoingRequest>> {
+        self.outgoing_requests
+            .iter()
+            .map(|e| serde_json::from_slice(&e?.1).map_err(CryptoStoreError::Serialization))
+            .collect()
     }
 
-    async fn get_account_and_session() -> (ReadOnlyAccount, Session) {
-        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
-        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+    async fn remove_outgoing_request(&self, request_id: Uuid) -> Result<()> {
+        self.ensure_writable()?;
 
-        bob.generate_one_time_keys_helper(1).await;
-        let one_time_key =
-            bob.one_time_keys().await.curve25519().iter().next().unwrap().1.to_owned();
+        self.outgoing_requests.remove(request_id.encode())?;
+        self.flush().await
+    }
+
+    async fn save_inbox_event(&self, event: &StoredToDeviceEvent) -> Result<()> {
+        self.ensure_writable()?;
+
+        self.inbox_events.insert(event.id.encode(), serde_json::to_vec(event)?)?;
+        self.flush().await
+    }
+
+    async fn get_inbox_events(&self) -> Result<Vec<StoredToDeviceEvent>> {
+        self.inbox_events
+            .iter()
+            .map(|e| serde_json::from_slice(&e?.1).map_err(CryptoStoreError::Serialization))
+            .collect()
+    }
+
+    async fn remove_inbox_event(&self, id: Uuid) -> Result<()> {
+        self.ensure_writable()?;
+
+        self.inbox_events.remove(id.encode())?;
+        self.flush().await
+    }
+
+    async fn try_take_leased_lock(
+        &self,
+        lease_duration_ms: u32,
+        key: &str,
+        holder: &str,
+    ) -> Result<Option<u64>> {
+        let lease = self.update_lease(key, |l| Lease::take(l, holder, lease_duration_ms)).await?;
+
+        Ok(lease.map(|l| l.generation()))
+    }
+
+    async fn release_leased_lock(&self, key: &str, holder: &str) -> Result<()> {
+        self.update_lease(key, |l| l.and_then(|l| l.release(holder))).await?;
+
+        Ok(())
+    }
+
+    async fn save_pending_to_device_message(&self, message: &PendingToDeviceMessage) -> Result<()> {
+        self.ensure_writable()?;
+        let _guard = self.write_lock.read().await;
+
+        // The content isn't encrypted for the recipient yet.
+        let value = self.get_pickle_key().encrypt_value(&serde_json::to_vec(message)?);
+        self.pending_to_device_messages.insert(message.id.encode(), value)?;
+        self.flush().await
+    }
+
+    async fn get_pending_to_device_messages(&self) -> Result<Vec<PendingToDeviceMessage>> {
+        let pickle_key = self.get_pickle_key();
+
+        self.pending_to_device_messages
+            .iter()
+            .map(|e| -> Result<PendingToDeviceMessage> {
+                let value = pickle_key
+                    .decrypt_value(&e?.1)
+                    .map_err(|_| CryptoStoreError::UnpicklingError)?;
+
+                Ok(serde_json::from_slice(&value)?)
+            })
+            .collect()
+    }
+
+    async fn remove_pending_to_device_message(&self, id: Uuid) -> Result<()> {
+        self.ensure_writable()?;
+
+        self.pending_to_device_messages.remove(id.encode())?;
+        self.flush().await
+    }
+
+    async fn get_custom_value(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.custom_values
+            .get(key.encode())?
+            .map(|v| {
+                self.get_pickle_key()
+                    .decrypt_value(&v)
+                    .map_err(|_| CryptoStoreError::UnpicklingError)
+            })
+            .transpose()
+    }
+
+    async fn set_custom_value(&self, key: &str, value: Vec<u8>) -> Result<()> {
+        self.ensure_writable()?;
+        let _guard = self.write_lock.read().await;
+
+        let value = self.get_pickle_key().encrypt_value(&value);
+        self.custom_values.insert(key.encode(), value)?;
+        self.flush().await
+    }
+
+    async fn change_passphrase(
+        &self,
+        old_passphrase: Option<&str>,
+        new_passphrase: &str,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+
+        // No other write may slip in between reading the values with the old
+        // key and switching to the new key, it would be lost or end up
+        // encrypted with the old key.
+        let _guard = self.write_lock.write().await;
+
+        self.check_passphrase(old_passphrase)?;
+
+        let old_key = self.get_pickle_key();
+        let new_key = PickleKey::new();
+
+        let mut account_pickles = Vec::new();
+        let mut session_pickles = Vec::new();
+        let mut inbound_pickles = Vec::new();
+        let mut outbound_pickles = Vec::new();
+        let mut identity_pickles = Vec::new();
+        let mut custom_values = Vec::new();
+        let mut room_key_bundles = Vec::new();
+        let mut pending_messages = Vec::new();
+
+        // Sessions can only be stored once an account exists, they need the
+        // account to be unpickled.
+        if let Some(pickle) = self.account.get("account".encode())? {
+            let account = ReadOnlyAccount::from_pickle(
+                serde_json::from_slice(&pickle)?,
+                old_key.pickle_mode(),
+            )?;
+            let pickle = account.pickle(new_key.pickle_mode()).await;
+            account_pickles.push(("account".encode(), serde_json::to_vec(&pickle)?));
+
+            for value in self.sessions.iter() {
+                let (key, pickle) = value?;
+                let session = Session::from_pickle(
+                    account.user_id.clone(),
+                    account.device_id.clone(),
+                    account.identity_keys.clone(),
+                    serde_json::from_slice(&pickle)?,
+                    old_key.pickle_mode(),
+                )?;
+                let pickle = session.pickle(new_key.pickle_mode()).await;
+                session_pickles.push((key.to_vec(), serde_json::to_vec(&pickle)?));
+            }
+
+            for value in self.outbound_group_sessions.iter() {
+                let (key, pickle) = value?;
+                let session = OutboundGroupSession::from_pickle(
+                    account.device_id.clone(),
+                    account.identity_keys.clone(),
+                    serde_json::from_slice(&pickle)?,
+                    old_key.pickle_mode(),
+                )?;
+                let pickle = session.pickle(new_key.pickle_mode()).await;
+                outbound_pickles.push((key.to_vec(), serde_json::to_vec(&pickle)?));
+            }
+        }
+
+        for value in self.inbound_group_sessions.iter() {
+            let (key, pickle) = value?;
+            let session = InboundGroupSession::from_pickle(
+                serde_json::from_slice(&pickle)?,
+                old_key.pickle_mode(),
+            )?;
+            let pickle = session.pickle(new_key.pickle_mode()).await;
+            inbound_pickles.push((key.to_vec(), serde_json::to_vec(&pickle)?));
+        }
+
+        if let Some(pickle) = self.private_identity.get("identity".encode())? {
+            let identity = PrivateCrossSigningIdentity::from_pickle(
+                serde_json::from_slice(&pickle)?,
+                old_key.key(),
+            )
+            .await
+            .map_err(|_| CryptoStoreError::UnpicklingError)?;
+            let pickle = identity.pickle(new_key.key()).await?;
+            identity_pickles.push(("identity".encode(), serde_json::to_vec(&pickle)?));
+        }
+
+        for value in self.custom_values.iter() {
+            let (key, value) = value?;
+            let value =
+                old_key.decrypt_value(&value).map_err(|_| CryptoStoreError::UnpicklingError)?;
+            custom_values.push((key.to_vec(), new_key.encrypt_value(&value)));
+        }
+
+        for value in self.room_key_bundles.iter() {
+            let (key, value) = value?;
+            let value =
+                old_key.decrypt_value(&value).map_err(|_| CryptoStoreError::UnpicklingError)?;
+            room_key_bundles.push((key.to_vec(), new_key.encrypt_value(&value)));
+        }
+
+        for value in self.pending_to_device_messages.iter() {
+            let (key, value) = value?;
+            let value =
+                old_key.decrypt_value(&value).map_err(|_| CryptoStoreError::UnpicklingError)?;
+            pending_messages.push((key.to_vec(), new_key.encrypt_value(&value)));
+        }
+
+        let encrypted_key = serde_json::to_vec(&new_key.encrypt(new_passphrase))?;
+
+        let ret: Result<(), TransactionError<serde_json::Error>> = (
+            &self.account,
+            &self.sessions,
+            &self.inbound_group_sessions,
+            &self.outbound_group_sessions,
+            &self.private_identity,
+            &self.custom_values,
+            &self.room_key_bundles,
+            &self.pending_to_device_messages,
+            &*self.inner,
+        )
+            .transaction(
+                |(account, sessions, inbound, outbound, identity, custom, bundles, pending, db)| {
+                    for (tree, pickles) in &[
+                        (account, &account_pickles),
+                        (sessions, &session_pickles),
+                        (inbound, &inbound_pickles),
+                        (outbound, &outbound_pickles),
+                        (identity, &identity_pickles),
+                        (custom, &custom_values),
+                        (bundles, &room_key_bundles),
+                        (pending, &pending_messages),
+                    ] {
+                        for (key, pickle) in pickles.iter() {
+                            tree.insert(key.as_slice(), pickle.as_slice())?;
+                        }
+                    }
+
+                    db.insert("pickle_key".encode(), encrypted_key.as_slice())?;
+
+                    Ok(())
+                },
+            );
+
+        ret?;
+        *self.pickle_key.write().unwrap() = Arc::new(new_key);
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn invalidate_caches(&self) -> Result<()> {
+        self.session_cache.clear();
+        self.tracked_users_cache.clear();
+        self.users_for_key_query_cache.clear();
+
+        self.load_tracked_users().await
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.ensure_writable()?;
+
+        *self.account_info.write().unwrap() = None;
+
+        self.session_cache.clear();
+        self.tracked_users_cache.clear();
+        self.users_for_key_query_cache.clear();
+
+        for tree in &[
+            &self.account,
+            &self.private_identity,
+            &self.olm_hashes,
+            &self.sessions,
+            &self.inbound_group_sessions,
+            &self.outgoing_key_requests,
+            &self.unsent_key_requests,
+            &self.key_requests_by_info,
+            &self.outbound_group_sessions,
+            &self.devices,
+            &self.devices_by_curve_key,
+            &self.identities,
+            &self.sharing_history,
+            &self.room_key_bundles,
+            &self.withheld_info,
+            &self.verification_flows,
+            &self.outgoing_requests,
+            &self.inbox_events,
+            &self.leases,
+            &self.pending_to_device_messages,
+            &self.custom_values,
+            &self.tracked_users,
+            &self.users_for_key_query,
+        ] {
+            tree.clear()?;
+        }
+
+        // The default tree holds our pickle key.
+        self.inner.clear()?;
+
+        // The database stays open, other clones of the store might still use
+        // it, so the files can't be removed. Flush the deletions so they
+        // reach the disk, sled reclaims the space the data occupied later on.
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use futures::TryStreamExt;
+    use matrix_sdk_common::{instant::Duration, time::elapsed_since, uuid::Uuid};
+    use matrix_sdk_test::async_test;
+    use olm_rs::outbound_group_session::OlmOutboundGroupSession;
+    use ruma::{
+        api::client::r0::keys::SignedKey,
+        events::room_key_request::RequestedKeyInfo,
+        identifiers::{
+            room_id, user_id, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, EventEncryptionAlgorithm,
+            UserId,
+        },
+    };
+    use tempfile::tempdir;
+
+    use super::{
+        CryptoStore, Durability, EncodeKey, OutgoingKeyRequest, SledStore, SledStoreConfig,
+        DATABASE_VERSION,
+    };
+    use crate::{
+        identities::{
+            device::test::get_device,
+            user::test::{get_other_identity, get_own_identity},
+            ReadOnlyDevice,
+        },
+        olm::{
+            GroupSessionKey, InboundGroupSession, OlmMessageHash, PrivateCrossSigningIdentity,
+            ReadOnlyAccount, Session, StoredRoomKeyBundleData,
+        },
+        store::{Changes, CryptoStoreError, DeviceChanges, IdentityChanges},
+    };
+
+    fn alice_id() -> UserId {
+        user_id!("@alice:example.org")
+    }
+
+    fn alice_device_id() -> Box<DeviceId> {
+        "ALICEDEVICE".into()
+    }
+
+    fn bob_id() -> UserId {
+        user_id!("@bob:example.org")
+    }
+
+    fn bob_device_id() -> Box<DeviceId> {
+        "BOBDEVICE".into()
+    }
+
+    async fn get_store(passphrase: Option<&str>) -> (SledStore, tempfile::TempDir) {
+        let tmpdir = tempdir().unwrap();
+        let tmpdir_path = tmpdir.path().to_str().unwrap();
+
+        let store = SledStore::open_with_passphrase(tmpdir_path, passphrase)
+            .expect("Can't create a passphrase protected store");
+
+        (store, tmpdir)
+    }
+
+    async fn get_loaded_store() -> (ReadOnlyAccount, SledStore, tempfile::TempDir) {
+        let (store, dir) = get_store(None).await;
+        let account = get_account();
+        store.save_account(account.clone()).await.expect("Can't save account");
+
+        (account, store, dir)
+    }
+
+    fn get_account() -> ReadOnlyAccount {
+        ReadOnlyAccount::new(&alice_id(), &alice_device_id())
+    }
+
+    async fn get_account_and_session() -> (ReadOnlyAccount, Session) {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+
+        bob.generate_one_time_keys_helper(1).await;
+        let one_time_key =
+            bob.one_time_keys().await.curve25519().iter().next().unwrap().1.to_owned();
         let one_time_key = SignedKey::new(one_time_key, BTreeMap::new());
         let sender_key = bob.identity_keys().curve25519().to_owned();
         let session =
@@ -871,6 +1915,26 @@ mod test {
         assert_eq!(account, loaded_account);
     }
 
+    #[async_test]
+    async fn load_account_with_periodic_durability() {
+        let dir = tempdir().unwrap();
+        let config = SledStoreConfig {
+            durability: Durability::Periodic,
+            high_throughput: true,
+            ..Default::default()
+        };
+        let store = SledStore::open_with_config(dir.path(), None, config)
+            .expect("Can't create a store with a custom config");
+        let account = get_account();
+
+        store.save_account(account.clone()).await.expect("Can't save account");
+
+        let loaded_account = store.load_account().await.expect("Can't load account");
+        let loaded_account = loaded_account.unwrap();
+
+        assert_eq!(account, loaded_account);
+    }
+
     #[async_test]
     async fn save_and_share_account() {
         let (store, _dir) = get_store(None).await;
@@ -939,6 +2003,44 @@ mod test {
         assert_eq!(session_id, session.session_id());
     }
 
+    #[async_test]
+    async fn migrate_session_timestamps() {
+        let (store, dir) = get_store(None).await;
+        let (account, session) = get_account_and_session().await;
+        let sender_key = session.sender_key.to_owned();
+        store.save_account(account).await.unwrap();
+
+        let changes = Changes { sessions: vec![session], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        // Turn the stored session into one that was stored by an older version,
+        // which stored the time that passed since the timestamps.
+        let (key, value) = store.sessions.iter().next().unwrap().unwrap();
+        let mut pickle: serde_json::Value = serde_json::from_slice(&value).unwrap();
+        pickle["creation_time"] = serde_json::json!({ "secs": 7200, "nanos": 0 });
+        pickle["last_use_time"] = serde_json::json!({ "secs": 60, "nanos": 0 });
+        store.sessions.insert(key.clone(), serde_json::to_vec(&pickle).unwrap()).unwrap();
+        store.inner.remove("store_version".encode()).unwrap();
+
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        let value = store.sessions.get(key).unwrap().unwrap();
+        let pickle: serde_json::Value = serde_json::from_slice(&value).unwrap();
+        assert!(pickle["creation_time"].is_u64());
+        assert_eq!(
+            store.inner.get("store_version".encode()).unwrap().unwrap().as_ref(),
+            &[DATABASE_VERSION]
+        );
+
+        let sessions = store.get_sessions(&sender_key).await.unwrap().unwrap();
+        let session = sessions.lock().await[0].clone();
+
+        assert!(elapsed_since(*session.creation_time) >= Duration::from_secs(7200));
+        assert!(elapsed_since(*session.last_use_time) < Duration::from_secs(7200));
+    }
+
     #[async_test]
     async fn save_inbound_group_session() {
         let (account, store, _dir) = get_loaded_store().await;
@@ -1096,6 +2198,92 @@ mod test {
         assert!(loaded_device.is_none());
     }
 
+    #[async_test]
+    async fn device_by_curve_key() {
+        let (_account, store, _dir) = get_loaded_store().await;
+        let device = get_device();
+        let curve_key = device.get_key(DeviceKeyAlgorithm::Curve25519).unwrap().to_owned();
+
+        assert!(store
+            .get_device_from_curve_key(device.user_id(), &curve_key)
+            .await
+            .unwrap()
+            .is_none());
+
+        let changes = Changes {
+            devices: DeviceChanges { new: vec![device.clone()], ..Default::default() },
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        let loaded_device =
+            store.get_device_from_curve_key(device.user_id(), &curve_key).await.unwrap().unwrap();
+        assert_eq!(loaded_device.device_id(), device.device_id());
+        assert!(store.get_device_from_curve_key(&alice_id(), &curve_key).await.unwrap().is_none());
+
+        let changes = Changes {
+            devices: DeviceChanges { deleted: vec![device.clone()], ..Default::default() },
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        assert!(store
+            .get_device_from_curve_key(device.user_id(), &curve_key)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store.devices_by_curve_key.is_empty());
+    }
+
+    #[async_test]
+    async fn device_by_changed_curve_key() {
+        let (account, store, _dir) = get_loaded_store().await;
+        let device = get_device();
+        let old_key = device.get_key(DeviceKeyAlgorithm::Curve25519).unwrap().to_owned();
+
+        let changes = Changes {
+            devices: DeviceChanges { new: vec![device.clone()], ..Default::default() },
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        let new_key = account.identity_keys().curve25519().to_owned();
+        let mut keys = device.keys().clone();
+        keys.insert(
+            DeviceKeyId::from_parts(DeviceKeyAlgorithm::Curve25519, device.device_id()),
+            new_key.clone(),
+        );
+        let changed = ReadOnlyDevice::new(
+            device.user_id().clone(),
+            device.device_id().to_owned(),
+            device.display_name().clone(),
+            device.local_trust_state(),
+            device.algorithms().to_vec(),
+            keys,
+            device.signatures().clone(),
+        );
+
+        let changes = Changes {
+            devices: DeviceChanges { changed: vec![changed], ..Default::default() },
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        assert_eq!(store.devices_by_curve_key.len(), 1);
+        assert!(store
+            .get_device_from_curve_key(device.user_id(), &old_key)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Devices are found even if the index misses an entry for them.
+        store.devices_by_curve_key.clear().unwrap();
+
+        let loaded_device =
+            store.get_device_from_curve_key(device.user_id(), &new_key).await.unwrap().unwrap();
+        assert_eq!(loaded_device.device_id(), device.device_id());
+    }
+
     #[async_test]
     async fn user_saving() {
         let dir = tempdir().unwrap();
@@ -1180,6 +2368,26 @@ mod test {
         assert_eq!(identity.user_id(), loaded_identity.user_id());
     }
 
+    #[async_test]
+    async fn account_and_identity_saving() {
+        let (store, dir) = get_store(None).await;
+        let account = get_account();
+        let identity = PrivateCrossSigningIdentity::new(alice_id()).await;
+
+        let changes = Changes {
+            account: Some(account.clone()),
+            private_identity: Some(identity.clone()),
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't open store");
+
+        assert_eq!(account, store.load_account().await.unwrap().unwrap());
+        assert_eq!(identity.user_id(), store.load_identity().await.unwrap().unwrap().user_id());
+    }
+
     #[async_test]
     async fn olm_hash_saving() {
         let (_, store, _dir) = get_loaded_store().await;
@@ -1253,4 +2461,265 @@ mod test {
         assert_eq!(None, stored_request);
         assert!(store.get_unsent_key_requests().await.unwrap().is_empty());
     }
+
+    #[async_test]
+    async fn clear_store() {
+        let (store, dir) = get_store(Some("secret_passphrase")).await;
+        let (account, session) = get_account_and_session().await;
+
+        store.save_account(account.clone()).await.expect("Can't save account");
+
+        let changes = Changes { sessions: vec![session], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        store.clear().await.unwrap();
+
+        assert!(store.sessions.is_empty());
+        assert!(store.session_cache.is_empty());
+        assert!(store.inner.is_empty());
+
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), Some("other_passphrase"))
+            .expect("Can't create store");
+
+        assert!(store.load_account().await.unwrap().is_none());
+    }
+
+    #[async_test]
+    async fn read_only_store() {
+        let dir = tempdir().unwrap();
+
+        assert!(SledStore::open_read_only(dir.path(), Some("secret_passphrase")).is_err());
+
+        let store = SledStore::open_with_passphrase(dir.path(), Some("secret_passphrase"))
+            .expect("Can't create store");
+        let (account, session) = get_account_and_session().await;
+
+        store.save_account(account.clone()).await.expect("Can't save account");
+        let changes = Changes { sessions: vec![session.clone()], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+        store.inner.drop_tree("custom_values").unwrap();
+        drop(store);
+
+        let store = SledStore::open_read_only(dir.path(), Some("secret_passphrase"))
+            .expect("Can't open the store in read-only mode");
+        assert!(store.is_read_only());
+
+        // Missing trees are treated as empty and aren't created.
+        assert!(store.get_custom_value("key").await.unwrap().is_none());
+        assert!(!store.inner.tree_names().iter().any(|n| n.as_ref() == b"custom_values"));
+
+        assert_eq!(store.load_account().await.unwrap().unwrap(), account);
+        let sessions = store.get_sessions(&session.sender_key).await.unwrap().unwrap();
+        assert_eq!(sessions.lock().await.len(), 1);
+
+        assert!(matches!(store.save_account(account).await, Err(CryptoStoreError::ReadOnly)));
+        assert!(matches!(
+            store.update_tracked_user(&alice_id(), true).await,
+            Err(CryptoStoreError::ReadOnly)
+        ));
+    }
+
+    #[async_test]
+    async fn change_passphrase() {
+        let dir = tempdir().unwrap();
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+        let (account, session) = get_account_and_session().await;
+        let identity = PrivateCrossSigningIdentity::new(alice_id()).await;
+
+        store.save_account(account.clone()).await.expect("Can't save account");
+
+        let identity_keys = account.identity_keys();
+        let group_session = InboundGroupSession::new(
+            identity_keys.curve25519(),
+            identity_keys.ed25519(),
+            &room_id!("!test:localhost"),
+            GroupSessionKey(OlmOutboundGroupSession::new().session_key()),
+            None,
+        )
+        .unwrap();
+
+        let changes = Changes {
+            sessions: vec![session.clone()],
+            inbound_group_sessions: vec![group_session.clone()],
+            private_identity: Some(identity),
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+        store.set_custom_value("migrated", vec![1]).await.unwrap();
+
+        assert!(store.change_passphrase(Some("wrong_passphrase"), "secret").await.is_err());
+        store.change_passphrase(None, "secret").await.unwrap();
+        assert!(store.change_passphrase(None, "other_secret").await.is_err());
+        drop(store);
+
+        let store =
+            SledStore::open_with_passphrase(dir.path(), Some("secret")).expect("Can't open store");
+
+        assert_eq!(store.load_account().await.unwrap().unwrap(), account);
+        assert!(store.load_identity().await.unwrap().is_some());
+        assert_eq!(store.get_custom_value("migrated").await.unwrap(), Some(vec![1]));
+
+        let sessions = store.get_sessions(&session.sender_key).await.unwrap().unwrap();
+        assert_eq!(sessions.lock().await[0].session_id(), session.session_id());
+
+        let loaded_group_session = store
+            .get_inbound_group_session(
+                &group_session.room_id,
+                &group_session.sender_key,
+                group_session.session_id(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded_group_session, group_session);
+    }
+
+    #[async_test]
+    async fn custom_values() {
+        let (_, store, dir) = get_loaded_store().await;
+
+        assert!(store.get_custom_value("backup_flag").await.unwrap().is_none());
+
+        store.set_custom_value("backup_flag", b"enabled".to_vec()).await.unwrap();
+        let encrypted = store.custom_values.get("backup_flag".encode()).unwrap().unwrap();
+        assert_ne!(&*encrypted, &b"enabled"[..]);
+
+        let mut changes = Changes::default();
+        changes.custom_values.insert("migration".to_owned(), b"v2".to_vec());
+        store.save_changes(changes).await.unwrap();
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't open store");
+
+        assert_eq!(store.get_custom_value("backup_flag").await.unwrap().unwrap(), b"enabled");
+        assert_eq!(store.get_custom_value("migration").await.unwrap().unwrap(), b"v2");
+    }
+
+    #[async_test]
+    async fn stream_inbound_group_sessions() {
+        let (account, store, _dir) = get_loaded_store().await;
+        let identity_keys = account.identity_keys();
+
+        let sessions: Vec<InboundGroupSession> = (0..3)
+            .map(|_| {
+                InboundGroupSession::new(
+                    identity_keys.curve25519(),
+                    identity_keys.ed25519(),
+                    &room_id!("!test:localhost"),
+                    GroupSessionKey(OlmOutboundGroupSession::new().session_key()),
+                    None,
+                )
+                .unwrap()
+            })
+            .collect();
+        sessions[0].mark_as_backed_up("1");
+
+        let changes = Changes { inbound_group_sessions: sessions.clone(), ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        let all: Vec<InboundGroupSession> =
+            store.inbound_group_sessions_stream().try_collect().await.unwrap();
+        assert_eq!(all.len(), 3);
+
+        let not_backed_up: Vec<InboundGroupSession> =
+            store.inbound_group_sessions_for_backup_stream().try_collect().await.unwrap();
+        assert_eq!(not_backed_up.len(), 2);
+        assert!(!not_backed_up.contains(&sessions[0]));
+    }
+
+    #[async_test]
+    async fn backup_state() {
+        let (account, store, _dir) = get_loaded_store().await;
+        let identity_keys = account.identity_keys();
+
+        let session = InboundGroupSession::new(
+            identity_keys.curve25519(),
+            identity_keys.ed25519(),
+            &room_id!("!test:localhost"),
+            GroupSessionKey(OlmOutboundGroupSession::new().session_key()),
+            None,
+        )
+        .unwrap();
+
+        let changes =
+            Changes { inbound_group_sessions: vec![session.clone()], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+
+        store
+            .mark_inbound_group_sessions_as_backed_up(
+                "2",
+                &[(session.room_id(), session.sender_key(), session.session_id())],
+            )
+            .await
+            .unwrap();
+
+        let loaded = store
+            .get_inbound_group_session(
+                session.room_id(),
+                session.sender_key(),
+                session.session_id(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(loaded.backed_up());
+        assert_eq!(loaded.backup_version().as_deref(), Some("2"));
+
+        store.reset_backup_state().await.unwrap();
+
+        let loaded = store
+            .get_inbound_group_session(
+                session.room_id(),
+                session.sender_key(),
+                session.session_id(),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!loaded.backed_up());
+        assert!(loaded.backup_version().is_none());
+    }
+
+    #[async_test]
+    async fn room_key_bundles() {
+        let (_, store, dir) = get_loaded_store().await;
+        let room_id = room_id!("!test:localhost");
+        let bob = user_id!("@bob:example.org");
+
+        assert!(store.get_received_room_key_bundle_data(&room_id, &bob).await.unwrap().is_none());
+
+        let bundle = StoredRoomKeyBundleData::new(
+            &bob,
+            "bob_curve_key",
+            &room_id,
+            serde_json::json!({ "url": "mxc://example.org/bundle" }),
+        );
+        let other_room = StoredRoomKeyBundleData::new(
+            &bob,
+            "bob_curve_key",
+            &room_id!("!other:localhost"),
+            serde_json::json!({ "url": "mxc://example.org/other" }),
+        );
+
+        let changes =
+            Changes { room_key_bundles: vec![bundle.clone(), other_room], ..Default::default() };
+        store.save_changes(changes).await.unwrap();
+        drop(store);
+
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't open store");
+
+        let loaded = store.get_received_room_key_bundle_data(&room_id, &bob).await.unwrap();
+        assert_eq!(Some(bundle.clone()), loaded);
+        assert_eq!(vec![bundle], store.get_received_room_key_bundles(&room_id).await.unwrap());
+
+        store.delete_received_room_key_bundles(&room_id).await.unwrap();
+
+        assert!(store.get_received_room_key_bundles(&room_id).await.unwrap().is_empty());
+        assert_eq!(
+            store.get_received_room_key_bundles(&room_id!("!other:localhost")).await.unwrap().len(),
+            1
+        );
+    }
 }
@@ -0,0 +1,61 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use matrix_sdk_common::uuid::Uuid;
+use ruma::{events::AnyToDeviceEvent, serde::Raw};
+use serde::{Deserialize, Serialize};
+
+use crate::OlmError;
+
+/// How many times we try to process a to-device event before we give up on
+/// it.
+pub(crate) const MAX_ATTEMPTS: u32 = 5;
+
+/// The maximal number of to-device events the inbox holds, a misbehaving
+/// device can't make the inbox grow without limits.
+pub(crate) const MAX_INBOX_SIZE: usize = 100;
+
+/// A to-device event that couldn't be processed when it was received and is
+/// kept in the crypto store to be retried later.
+///
+/// Processing an encrypted to-device event can fail for reasons that go away
+/// on their own, e.g. the Olm message that creates the session arrives after
+/// a message that uses the session, or the store couldn't be written to
+/// during a restart. Those events are put into the inbox and retried after
+/// the following syncs instead of dropping the room keys or secrets they
+/// carry.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StoredToDeviceEvent {
+    /// The unique id of the inbox entry.
+    pub id: Uuid,
+    /// The to-device event as it was received from the server.
+    pub event: Raw<AnyToDeviceEvent>,
+    /// The number of times processing the event failed.
+    pub attempts: u32,
+}
+
+impl StoredToDeviceEvent {
+    pub(crate) fn new(event: Raw<AnyToDeviceEvent>) -> Self {
+        Self { id: Uuid::new_v4(), event, attempts: 1 }
+    }
+}
+
+/// Can processing a to-device event that failed with the given error succeed
+/// if it's retried later.
+///
+/// A wedged session isn't retryable, the event can't be decrypted until the
+/// other side replaces the session, the session recovery is started instead.
+pub(crate) fn is_retryable(error: &OlmError) -> bool {
+    matches!(error, OlmError::Store(_) | OlmError::MissingSession(_))
+}
@@ -0,0 +1,204 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Strongly typed versions of the public keys that are used in Matrix.
+//!
+//! Keys are passed around as unpadded base64 strings in the Matrix protocol.
+//! The types in this module are parsed and validated once, they can be
+//! compared without having to care about the encoding and can't be mixed up
+//! with each other.
+//!
+//! The types (de)serialize to and from the same unpadded base64 strings, so
+//! data that was stored using plain strings can be read using them.
+//!
+//! Keys are parsed into these types wherever the keys of a device are
+//! compared to keys from another source, e.g. when a device is matched to the
+//! sender key of a room key or when the keys of a device are updated. The
+//! device key maps, the sender keys of Olm and Megolm sessions and the store
+//! schemas keep using the base64 strings that are sent over the wire, those are
+//! only used as opaque identifiers.
+
+use std::{
+    convert::TryFrom,
+    fmt::{self, Debug, Display},
+    str::FromStr,
+};
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::utilities::{decode, encode, DecodeError};
+
+/// Error type describing why a public key couldn't be parsed.
+#[derive(Error, Debug)]
+pub enum KeyError {
+    /// The key isn't valid base64.
+    #[error("the key isn't valid base64: {0}")]
+    Base64(#[from] DecodeError),
+
+    /// The decoded key doesn't have the expected length.
+    #[error("the key has an invalid length, expected {expected} bytes, found {found} bytes")]
+    InvalidLength {
+        /// The length a valid key has.
+        expected: usize,
+        /// The length of the decoded key.
+        found: usize,
+    },
+}
+
+macro_rules! public_key {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name([u8; Self::LENGTH]);
+
+        impl $name {
+            /// The length of the key in bytes.
+            pub const LENGTH: usize = 32;
+
+            /// Create the key from its raw bytes.
+            pub fn from_bytes(bytes: [u8; Self::LENGTH]) -> Self {
+                Self(bytes)
+            }
+
+            /// Parse the key from an unpadded base64 string.
+            pub fn from_base64(key: &str) -> Result<Self, KeyError> {
+                let decoded = decode(key)?;
+
+                if decoded.len() != Self::LENGTH {
+                    return Err(KeyError::InvalidLength {
+                        expected: Self::LENGTH,
+                        found: decoded.len(),
+                    });
+                }
+
+                let mut bytes = [0u8; Self::LENGTH];
+                bytes.copy_from_slice(&decoded);
+
+                Ok(Self(bytes))
+            }
+
+            /// Encode the key as an unpadded base64 string.
+            pub fn to_base64(&self) -> String {
+                encode(self.0)
+            }
+
+            /// Get the raw bytes of the key.
+            pub fn as_bytes(&self) -> &[u8; Self::LENGTH] {
+                &self.0
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = KeyError;
+
+            fn try_from(key: &str) -> Result<Self, Self::Error> {
+                Self::from_base64(key)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = KeyError;
+
+            fn from_str(key: &str) -> Result<Self, Self::Err> {
+                Self::from_base64(key)
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.to_base64())
+            }
+        }
+
+        impl Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self.to_base64())
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                serializer.serialize_str(&self.to_base64())
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                let key = String::deserialize(deserializer)?;
+                Self::from_base64(&key).map_err(D::Error::custom)
+            }
+        }
+    };
+}
+
+public_key!(
+    /// A Curve25519 public key, used as the identity key of a device to
+    /// establish Olm sessions.
+    Curve25519PublicKey
+);
+
+public_key!(
+    /// An Ed25519 public key, used as the fingerprint key of a device and to
+    /// sign and verify JSON objects.
+    Ed25519PublicKey
+);
+
+#[cfg(test)]
+mod test {
+    use super::{Curve25519PublicKey, Ed25519PublicKey, KeyError};
+
+    const CURVE_KEY: &str = "xfgbLIC5WAl1OIkpOzoxpCe8FsRDT6nch7NQsOb15nc";
+    const ED_KEY: &str = "2/5LWJMow5zhJqakV88SIc7q/1pa8fmkfgAzx72w9G4";
+
+    #[test]
+    fn parsing_keys() {
+        let curve_key = Curve25519PublicKey::from_base64(CURVE_KEY).unwrap();
+        let ed_key = Ed25519PublicKey::from_base64(ED_KEY).unwrap();
+
+        assert_eq!(curve_key.to_base64(), CURVE_KEY);
+        assert_eq!(ed_key.to_string(), ED_KEY);
+        assert_eq!(curve_key, CURVE_KEY.parse().unwrap());
+
+        assert!(matches!(
+            Curve25519PublicKey::from_base64("not base64!"),
+            Err(KeyError::Base64(_))
+        ));
+        assert!(matches!(
+            Ed25519PublicKey::from_base64("AAAA"),
+            Err(KeyError::InvalidLength { expected: 32, found: 3 })
+        ));
+    }
+
+    #[test]
+    fn key_serialization() {
+        let curve_key = Curve25519PublicKey::from_base64(CURVE_KEY).unwrap();
+
+        let serialized = serde_json::to_value(&curve_key).unwrap();
+        assert_eq!(serialized, CURVE_KEY);
+
+        // Keys that were stored as plain strings can be deserialized.
+        let deserialized: Curve25519PublicKey =
+            serde_json::from_value(serde_json::json!(CURVE_KEY)).unwrap();
+        assert_eq!(deserialized, curve_key);
+
+        assert!(serde_json::from_value::<Ed25519PublicKey>(serde_json::json!("AAAA")).is_err());
+    }
+}
@@ -12,8 +12,35 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::atomic::{AtomicBool, Ordering};
+
 pub use base64::DecodeError;
 use base64::{decode_config, encode_config, STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use sha2::{Digest, Sha256};
+
+static REDACT_IDENTIFIERS: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable the redaction of identifiers in the logs and tracing spans
+/// of the crypto crate.
+///
+/// If enabled, user, device, room and session ids are replaced with a short
+/// hash so logs can be shared for debugging without revealing who the user
+/// talks to. The same identifier will always produce the same hash, so the
+/// flow of a single session can still be followed through the logs.
+pub fn set_identifier_redaction(enable: bool) {
+    REDACT_IDENTIFIERS.store(enable, Ordering::Relaxed);
+}
+
+/// Format an identifier so it can be put into a log line or span, redacting it
+/// if the redaction of identifiers is enabled.
+pub(crate) fn log_id(id: &str) -> String {
+    if REDACT_IDENTIFIERS.load(Ordering::Relaxed) {
+        let hash = Sha256::digest(id.as_bytes());
+        format!("<redacted:{}>", encode(&hash[..8]))
+    } else {
+        id.to_owned()
+    }
+}
 
 /// Decode the input as base64 with no padding.
 pub fn decode(input: impl AsRef<[u8]>) -> Result<Vec<u8>, DecodeError> {
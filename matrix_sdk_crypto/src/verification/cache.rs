@@ -14,22 +14,31 @@
 
 use std::sync::Arc;
 
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use matrix_sdk_common::uuid::Uuid;
 use ruma::{DeviceId, UserId};
+use tracing::warn;
 
+#[cfg(feature = "qrcode")]
+use super::QrVerification;
 use super::{event_enums::OutgoingContent, sas::content_to_request, Sas, Verification};
-use crate::{OutgoingRequest, RoomMessageRequest};
+use crate::{utilities::log_id, OutgoingRequest, RoomMessageRequest};
 
 #[derive(Clone, Debug)]
 pub struct VerificationCache {
     verification: Arc<DashMap<String, Verification>>,
     outgoing_requests: Arc<DashMap<Uuid, OutgoingRequest>>,
+    /// Requests that were queued up but weren't put into the store yet.
+    unpersisted_requests: Arc<DashSet<Uuid>>,
 }
 
 impl VerificationCache {
     pub fn new() -> Self {
-        Self { verification: DashMap::new().into(), outgoing_requests: DashMap::new().into() }
+        Self {
+            verification: DashMap::new().into(),
+            outgoing_requests: DashMap::new().into(),
+            unpersisted_requests: DashSet::new().into(),
+        }
     }
 
     #[cfg(test)]
@@ -38,10 +47,89 @@ impl VerificationCache {
         self.verification.is_empty()
     }
 
+    pub fn get(&self, flow_id: &str) -> Option<Verification> {
+        self.verification.get(flow_id).map(|v| v.value().clone())
+    }
+
+    pub fn get_all(&self) -> Vec<Verification> {
+        self.verification.iter().map(|v| v.value().clone()).collect()
+    }
+
+    pub fn insert(&self, verification: Verification) {
+        self.verification.insert(verification.flow_id().as_str().to_owned(), verification);
+    }
+
     pub fn insert_sas(&self, sas: Sas) {
         self.verification.insert(sas.flow_id().as_str().to_string(), sas.into());
     }
 
+    /// Add a verification flow that we started for a verification request.
+    ///
+    /// Returns false if a flow for the same request already got started, e.g.
+    /// a SAS flow or a QR code that was scanned, the new flow is discarded in
+    /// that case.
+    pub fn try_insert(&self, verification: Verification) -> bool {
+        match self.verification.entry(verification.flow_id().as_str().to_owned()) {
+            Entry::Occupied(mut e) => {
+                if e.get().is_started() {
+                    false
+                } else {
+                    e.insert(verification);
+                    true
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert(verification);
+                true
+            }
+        }
+    }
+
+    #[cfg(feature = "qrcode")]
+    pub fn get_qr(&self, flow_id: &str) -> Option<QrVerification> {
+        self.verification.get(flow_id).and_then(|v| {
+            if let Verification::QrV1(qr) = v.value() {
+                Some(qr.clone())
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Add a SAS verification that the other side started.
+    ///
+    /// If both sides sent a start event for the same flow, only one of them
+    /// can be used. The spec says that the start event of the user with the
+    /// lexicographically smaller user ID wins, if both start events came from
+    /// the same user the device IDs are compared instead.
+    ///
+    /// Returns false if the given SAS lost against an existing one and was
+    /// discarded.
+    pub fn insert_started_sas(&self, sas: Sas) -> bool {
+        if let Some(existing) = self.get_sas(sas.flow_id().as_str()) {
+            if !(existing.is_done() || existing.is_cancelled()) {
+                let their_start_wins = existing.is_start_pending()
+                    && (sas.other_user_id().as_str(), sas.other_device_id().as_str())
+                        < (existing.user_id().as_str(), existing.device_id().as_str());
+
+                if !their_start_wins {
+                    warn!(
+                        user_id = %log_id(sas.other_user_id().as_str()),
+                        device_id = %log_id(sas.other_device_id().as_str()),
+                        flow_id = sas.flow_id().as_str(),
+                        "Ignoring a conflicting verification start event",
+                    );
+
+                    return false;
+                }
+            }
+        }
+
+        self.insert_sas(sas);
+
+        true
+    }
+
     pub fn outgoing_requests(&self) -> Vec<OutgoingRequest> {
         self.outgoing_requests.iter().map(|r| (*r).clone()).collect()
     }
@@ -52,7 +140,6 @@ impl VerificationCache {
         self.verification
             .iter()
             .filter_map(|s| {
-                #[allow(irrefutable_let_patterns)]
                 if let Verification::SasV1(s) = s.value() {
                     s.cancel_if_timed_out().map(|r| OutgoingRequest {
                         request_id: r.request_id(),
@@ -67,7 +154,6 @@ impl VerificationCache {
 
     pub fn get_sas(&self, transaction_id: &str) -> Option<Sas> {
         self.verification.get(transaction_id).and_then(|v| {
-            #[allow(irrefutable_let_patterns)]
             if let Verification::SasV1(sas) = v.value() {
                 Some(sas.clone())
             } else {
@@ -77,9 +163,22 @@ impl VerificationCache {
     }
 
     pub fn add_request(&self, request: OutgoingRequest) {
+        self.unpersisted_requests.insert(request.request_id);
         self.outgoing_requests.insert(request.request_id, request);
     }
 
+    /// Take the queued up requests that still need to be put into the store.
+    pub fn take_unpersisted_requests(&self) -> Vec<OutgoingRequest> {
+        let ids: Vec<Uuid> = self.unpersisted_requests.iter().map(|id| *id).collect();
+
+        ids.into_iter()
+            .filter_map(|id| {
+                self.unpersisted_requests.remove(&id);
+                self.outgoing_requests.get(&id).map(|r| r.value().clone())
+            })
+            .collect()
+    }
+
     pub fn queue_up_content(
         &self,
         recipient: &UserId,
@@ -93,7 +192,7 @@ impl VerificationCache {
 
                 let request = OutgoingRequest { request_id, request: Arc::new(request.into()) };
 
-                self.outgoing_requests.insert(request_id, request);
+                self.add_request(request);
             }
 
             OutgoingContent::Room(r, c) => {
@@ -106,12 +205,13 @@ impl VerificationCache {
                     request_id,
                 };
 
-                self.outgoing_requests.insert(request_id, request);
+                self.add_request(request);
             }
         }
     }
 
     pub fn mark_request_as_sent(&self, uuid: &Uuid) {
+        self.unpersisted_requests.remove(uuid);
         self.outgoing_requests.remove(uuid);
     }
 }
@@ -12,24 +12,27 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{convert::TryFrom, sync::Arc};
+use std::{collections::HashSet, convert::TryFrom, sync::Arc};
 
 use dashmap::DashMap;
 use matrix_sdk_common::{locks::Mutex, uuid::Uuid};
-use ruma::{DeviceId, UserId};
-use tracing::{info, warn};
+use ruma::{events::key::verification::cancel::CancelCode, DeviceId, UserId};
+use tracing::{info, instrument, warn};
 
+#[cfg(feature = "qrcode")]
+use super::QrVerification;
 use super::{
     cache::VerificationCache,
     event_enums::{AnyEvent, AnyVerificationContent, OutgoingContent},
     requests::VerificationRequest,
     sas::{content_to_request, Sas},
-    FlowId, VerificationResult,
+    FlowId, InterruptedVerification, StoredVerificationFlow, Verification, VerificationResult,
 };
 use crate::{
     olm::PrivateCrossSigningIdentity,
-    requests::OutgoingRequest,
+    requests::{OutgoingRequest, StoredOutgoingRequest},
     store::{CryptoStore, CryptoStoreError},
+    utilities::log_id,
     OutgoingVerificationRequest, ReadOnlyAccount, ReadOnlyDevice, RoomMessageRequest,
 };
 
@@ -57,6 +60,13 @@ impl VerificationMachine {
         }
     }
 
+    #[instrument(
+        skip(self, device),
+        fields(
+            user_id = %log_id(device.user_id().as_str()),
+            device_id = %log_id(device.device_id().as_str())
+        )
+    )]
     pub async fn start_sas(
         &self,
         device: ReadOnlyDevice,
@@ -82,6 +92,13 @@ impl VerificationMachine {
                     content_to_request(device.user_id(), device.device_id().to_owned(), c);
 
                 self.verifications.insert_sas(sas.clone());
+                self.store
+                    .save_verification_flow(&StoredVerificationFlow::new(
+                        sas.flow_id(),
+                        device.user_id(),
+                        Some(device.device_id()),
+                    ))
+                    .await?;
 
                 request.into()
             }
@@ -98,6 +115,104 @@ impl VerificationMachine {
         self.verifications.get_sas(transaction_id)
     }
 
+    /// Load the verification flows that were interrupted by a restart of the
+    /// process from the crypto store.
+    ///
+    /// The flows can't be continued, but they are listed by
+    /// [`list_active()`](#method.list_active) and returned by
+    /// [`get_verification()`](#method.get_verification) until they get
+    /// cancelled.
+    pub(crate) async fn load_stored_flows(&self) -> Result<(), CryptoStoreError> {
+        for flow in self.store.get_verification_flows().await? {
+            let flow_id = if let Some(f) = flow.to_flow_id() {
+                f
+            } else {
+                warn!(
+                    flow_id = flow.flow_id.as_str(),
+                    "Invalid flow id for a stored verification flow, removing it"
+                );
+                self.store.remove_verification_flow(&flow.flow_id).await?;
+                continue;
+            };
+
+            if self.verifications.get(flow_id.as_str()).is_none()
+                && !self.requests.contains_key(flow_id.as_str())
+            {
+                self.verifications.insert(InterruptedVerification::new(flow, flow_id).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the verification flow with the given flow id.
+    ///
+    /// This includes flows that were interrupted by a restart of the process.
+    pub fn get_verification(&self, flow_id: &str) -> Option<Verification> {
+        self.verifications.get(flow_id)
+    }
+
+    /// Get all the verification flows that are neither done nor cancelled.
+    ///
+    /// This includes flows that were interrupted by a restart of the process.
+    pub fn list_active(&self) -> Vec<Verification> {
+        self.verifications
+            .get_all()
+            .into_iter()
+            .filter(|v| !(v.is_done() || v.is_cancelled()))
+            .collect()
+    }
+
+    /// Cancel all the verification requests and flows that are in progress.
+    ///
+    /// This also cancels the flows that were interrupted by a restart of the
+    /// process, they are only known to the crypto store. The cancellations
+    /// are sent out with the next batch of outgoing requests.
+    ///
+    /// # Arguments
+    ///
+    /// * `code` - The cancel code that should be sent to the other side, e.g.
+    /// `CancelCode::User` if the user logs out.
+    pub async fn cancel_all(&self, code: CancelCode) -> Result<(), CryptoStoreError> {
+        // A request and the flow it started share the flow id, the other side
+        // only needs a single cancellation per flow id.
+        let mut cancelled = HashSet::new();
+
+        for verification in self.verifications.get_all() {
+            if let Some(r) = verification.cancel_with_code(code.clone()) {
+                self.verifications.add_request(r.into());
+            }
+
+            cancelled.insert(verification.flow_id().as_str().to_owned());
+        }
+
+        for request in self.requests.iter() {
+            let request_cancellation = request.cancel_with_code(code.clone());
+
+            if cancelled.insert(request.flow_id().as_str().to_owned()) {
+                if let Some(r) = request_cancellation {
+                    self.verifications.add_request(r.into());
+                }
+            }
+        }
+
+        for flow in self.store.get_verification_flows().await? {
+            if !cancelled.contains(&flow.flow_id) {
+                if let Some(flow_id) = flow.to_flow_id() {
+                    let flow = InterruptedVerification::new(flow.clone(), flow_id);
+
+                    if let Some(r) = flow.cancel_with_code(code.clone()) {
+                        self.verifications.add_request(r.into());
+                    }
+                }
+            }
+
+            self.store.remove_verification_flow(&flow.flow_id).await?;
+        }
+
+        self.persist_outgoing_requests().await
+    }
+
     fn queue_up_content(
         &self,
         recipient: &UserId,
@@ -115,12 +230,48 @@ impl VerificationMachine {
         self.verifications.outgoing_requests()
     }
 
-    pub fn garbage_collect(&self) {
+    /// Put the requests we queued up into the store, so they are sent out
+    /// again after a restart if the server didn't acknowledge them.
+    pub(crate) async fn persist_outgoing_requests(&self) -> Result<(), CryptoStoreError> {
+        for request in self.verifications.take_unpersisted_requests() {
+            if let Some(stored) = StoredOutgoingRequest::from_request(&request) {
+                self.store.save_outgoing_request(&stored).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn garbage_collect(&self) -> Result<(), CryptoStoreError> {
+        let mut finished: HashSet<String> = self
+            .requests
+            .iter()
+            .filter(|r| r.is_done() || r.is_cancelled())
+            .map(|r| r.flow_id().as_str().to_owned())
+            .collect();
+        finished.extend(
+            self.verifications
+                .get_all()
+                .into_iter()
+                .filter(|v| v.is_done() || v.is_cancelled())
+                .map(|v| v.flow_id().as_str().to_owned()),
+        );
+
         self.requests.retain(|_, r| !(r.is_done() || r.is_cancelled()));
 
         for request in self.verifications.garbage_collect() {
             self.verifications.add_request(request)
         }
+
+        for flow_id in finished {
+            // A request and the flow it started share the flow id, only forget
+            // about the flow once both of them are gone.
+            if !self.requests.contains_key(&flow_id) && self.verifications.get(&flow_id).is_none() {
+                self.store.remove_verification_flow(&flow_id).await?;
+            }
+        }
+
+        self.persist_outgoing_requests().await
     }
 
     async fn mark_sas_as_done(
@@ -151,12 +302,43 @@ impl VerificationMachine {
         Ok(())
     }
 
+    /// The other side confirmed that we scanned their QR code, mark the keys
+    /// we scanned as verified and send out our own done event.
+    #[cfg(feature = "qrcode")]
+    async fn mark_qr_as_done(
+        &self,
+        qr: QrVerification,
+        out_content: OutgoingContent,
+    ) -> Result<(), CryptoStoreError> {
+        match qr.mark_as_done().await? {
+            VerificationResult::Ok => {
+                self.queue_up_content(qr.other_user_id(), qr.other_device_id(), out_content);
+            }
+            VerificationResult::Cancel(c) => {
+                if let Some(r) = qr.cancel_with_code(c) {
+                    self.verifications.add_request(r.into());
+                }
+            }
+            VerificationResult::SignatureUpload(r) => {
+                self.verifications.add_request(r.into());
+                self.queue_up_content(qr.other_user_id(), qr.other_device_id(), out_content);
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn receive_any_event(
         &self,
         event: impl Into<AnyEvent<'_>>,
     ) -> Result<(), CryptoStoreError> {
-        let event = event.into();
+        let result = self.handle_event(event.into()).await;
+        self.persist_outgoing_requests().await?;
+
+        result
+    }
 
+    async fn handle_event(&self, event: AnyEvent<'_>) -> Result<(), CryptoStoreError> {
         let flow_id = if let Ok(flow_id) = FlowId::try_from(&event) {
             flow_id
         } else {
@@ -166,7 +348,7 @@ impl VerificationMachine {
 
         let flow_id_mismatch = || {
             warn!(
-                sender = event.sender().as_str(),
+                sender = %log_id(event.sender().as_str()),
                 flow_id = flow_id.as_str(),
                 "Received a verification event with a mismatched flow id, \
                   the verification object was created for a in-room \
@@ -179,11 +361,23 @@ impl VerificationMachine {
             match &content {
                 AnyVerificationContent::Request(r) => {
                     info!(
-                        sender = event.sender().as_str(),
-                        from_device = r.from_device().as_str(),
+                        sender = %log_id(event.sender().as_str()),
+                        from_device = %log_id(r.from_device().as_str()),
                         "Received a new verification request",
                     );
 
+                    if let Some(existing) = self.get_request(flow_id.as_str()) {
+                        if !(existing.is_done() || existing.is_cancelled()) {
+                            warn!(
+                                sender = %log_id(event.sender().as_str()),
+                                flow_id = flow_id.as_str(),
+                                "Ignoring a verification request for an already ongoing flow",
+                            );
+
+                            return Ok(());
+                        }
+                    }
+
                     let request = VerificationRequest::from_request(
                         self.verifications.clone(),
                         self.account.clone(),
@@ -194,6 +388,14 @@ impl VerificationMachine {
                         r,
                     );
 
+                    self.store
+                        .save_verification_flow(&StoredVerificationFlow::new(
+                            request.flow_id(),
+                            event.sender(),
+                            Some(r.from_device()),
+                        ))
+                        .await?;
+
                     self.requests.insert(request.flow_id().as_str().to_owned(), request);
                 }
                 AnyVerificationContent::Cancel(c) => {
@@ -205,6 +407,17 @@ impl VerificationMachine {
                         // This won't produce an outgoing content
                         let _ = sas.receive_any_event(event.sender(), &content);
                     }
+
+                    #[cfg(feature = "qrcode")]
+                    if let Some(qr) = self.verifications.get_qr(flow_id.as_str()) {
+                        qr.receive_cancel(event.sender(), c.cancel_code());
+                    }
+
+                    if let Some(Verification::Interrupted(flow)) =
+                        self.verifications.get(flow_id.as_str())
+                    {
+                        flow.receive_cancel(event.sender());
+                    }
                 }
                 AnyVerificationContent::Ready(c) => {
                     if let Some(request) = self.requests.get(flow_id.as_str()) {
@@ -243,7 +456,17 @@ impl VerificationMachine {
                                 false,
                             ) {
                                 Ok(sas) => {
-                                    self.verifications.insert_sas(sas);
+                                    if !self.verifications.insert_started_sas(sas.clone()) {
+                                        return Ok(());
+                                    }
+
+                                    self.store
+                                        .save_verification_flow(&StoredVerificationFlow::new(
+                                            sas.flow_id(),
+                                            event.sender(),
+                                            Some(c.from_device()),
+                                        ))
+                                        .await?;
                                 }
                                 Err(cancellation) => self.queue_up_content(
                                     event.sender(),
@@ -294,6 +517,13 @@ impl VerificationMachine {
                             self.mark_sas_as_done(s, content).await?;
                         }
                     }
+
+                    #[cfg(feature = "qrcode")]
+                    if let Some(qr) = self.verifications.get_qr(flow_id.as_str()) {
+                        if let Some(content) = qr.receive_done(event.sender()) {
+                            self.mark_qr_as_done(qr, content).await?;
+                        }
+                    }
                 }
             }
         }
@@ -305,14 +535,18 @@ impl VerificationMachine {
 #[cfg(test)]
 mod test {
 
-    use std::{
-        convert::TryFrom,
-        sync::Arc,
-        time::{Duration, Instant},
-    };
+    use std::{convert::TryFrom, sync::Arc};
 
-    use matrix_sdk_common::locks::Mutex;
-    use ruma::{DeviceId, UserId};
+    use matrix_sdk_common::{
+        instant::{Duration, Instant},
+        locks::Mutex,
+    };
+    use ruma::{
+        events::key::verification::{
+            cancel::CancelCode, request::RequestToDeviceEventContent, VerificationMethod,
+        },
+        DeviceId, MilliSecondsSinceUnixEpoch, UserId,
+    };
 
     use super::{Sas, VerificationMachine};
     use crate::{
@@ -320,7 +554,9 @@ mod test {
         store::{CryptoStore, MemoryStore},
         verification::{
             event_enums::{AcceptContent, KeyContent, MacContent, OutgoingContent},
+            requests::VerificationRequest,
             test::wrap_any_to_device_content,
+            FlowId, StoredVerificationFlow, Verification,
         },
         ReadOnlyAccount, ReadOnlyDevice,
     };
@@ -440,9 +676,145 @@ mod test {
         alice.set_creation_time(Instant::now() - Duration::from_secs(60 * 15));
         assert!(alice.timed_out());
         assert!(alice_machine.verifications.outgoing_requests().is_empty());
-        alice_machine.garbage_collect();
+        alice_machine.garbage_collect().await.unwrap();
         assert!(!alice_machine.verifications.outgoing_requests().is_empty());
-        alice_machine.garbage_collect();
+        alice_machine.garbage_collect().await.unwrap();
         assert!(alice_machine.verifications.is_empty());
     }
+
+    #[tokio::test]
+    async fn cancel_all() {
+        let (alice_machine, bob) = setup_verification_machine().await;
+        let flow_id = bob.flow_id().as_str();
+
+        assert!(alice_machine.get_verification(flow_id).is_some());
+        assert_eq!(alice_machine.list_active().len(), 1);
+        assert_eq!(alice_machine.store.get_verification_flows().await.unwrap().len(), 1);
+
+        // The SAS flow was started by a request, both share the flow id.
+        let content = RequestToDeviceEventContent::new(
+            bob_device_id(),
+            flow_id.to_owned(),
+            vec![VerificationMethod::MSasV1],
+            MilliSecondsSinceUnixEpoch::now(),
+        );
+        let request = VerificationRequest::from_request(
+            alice_machine.verifications.clone(),
+            alice_machine.account.clone(),
+            PrivateCrossSigningIdentity::empty(alice_id()),
+            alice_machine.store.clone(),
+            &bob_id(),
+            FlowId::from(flow_id.to_owned()),
+            &(&content).into(),
+        );
+        alice_machine.requests.insert(flow_id.to_owned(), request);
+
+        // A flow that was interrupted by a restart is only known to the store.
+        let stale_flow = StoredVerificationFlow::new(
+            &FlowId::from("STALE_FLOW".to_owned()),
+            &bob_id(),
+            Some(&bob_device_id()),
+        );
+        alice_machine.store.save_verification_flow(&stale_flow).await.unwrap();
+
+        alice_machine.cancel_all(CancelCode::User).await.unwrap();
+
+        assert!(alice_machine.list_active().is_empty());
+        assert!(alice_machine.get_verification(flow_id).unwrap().is_cancelled());
+        assert!(alice_machine.get_request(flow_id).unwrap().is_cancelled());
+        // One cancellation for the request and its SAS flow, and one for the
+        // stale flow.
+        assert_eq!(alice_machine.outgoing_messages().len(), 2);
+        assert!(alice_machine.store.get_verification_flows().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn interrupted_flows() {
+        let (alice_machine, bob) = setup_verification_machine().await;
+
+        let stale_flow = StoredVerificationFlow::new(
+            &FlowId::from("STALE_FLOW".to_owned()),
+            &bob_id(),
+            Some(&bob_device_id()),
+        );
+        alice_machine.store.save_verification_flow(&stale_flow).await.unwrap();
+
+        alice_machine.load_stored_flows().await.unwrap();
+
+        // The flow that is still in memory isn't replaced.
+        assert!(alice_machine.get_sas(bob.flow_id().as_str()).is_some());
+        assert_eq!(alice_machine.list_active().len(), 2);
+
+        let interrupted = match alice_machine.get_verification("STALE_FLOW").unwrap() {
+            Verification::Interrupted(i) => i,
+            _ => panic!("The stored flow wasn't restored as an interrupted flow"),
+        };
+        assert_eq!(interrupted.other_user_id(), &bob_id());
+        assert_eq!(interrupted.other_device_id(), Some(&*bob_device_id()));
+
+        assert!(interrupted.cancel().is_some());
+        assert!(interrupted.cancel().is_none());
+        assert_eq!(alice_machine.list_active().len(), 1);
+
+        alice_machine.garbage_collect().await.unwrap();
+        assert!(alice_machine.get_verification("STALE_FLOW").is_none());
+        assert_eq!(alice_machine.store.get_verification_flows().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn conflicting_starts() {
+        let (alice_machine, bob) = setup_verification_machine().await;
+        let flow_id = bob.flow_id().as_str();
+
+        // Bob sending the same start event again doesn't replace the flow that
+        // is already in progress.
+        let alice = alice_machine.get_sas(flow_id).unwrap();
+        alice.accept().unwrap();
+
+        let (_, start_content) = Sas::start(
+            ReadOnlyAccount::new(&bob_id(), &bob_device_id()),
+            PrivateCrossSigningIdentity::empty(bob_id()),
+            ReadOnlyDevice::from_account(&alice_machine.account).await,
+            Arc::new(MemoryStore::new()),
+            None,
+            Some(flow_id.to_owned()),
+        );
+        alice_machine
+            .receive_any_event(&wrap_any_to_device_content(&bob_id(), start_content))
+            .await
+            .unwrap();
+
+        assert!(alice_machine.get_sas(flow_id).unwrap().accept().is_none());
+
+        // Both sides start a flow at the same time, Alice has the smaller user
+        // ID so her start event wins.
+        let bob_device =
+            alice_machine.store.get_device(&bob_id(), &bob_device_id()).await.unwrap().unwrap();
+        let (alice_sas, _) = Sas::start(
+            alice_machine.account.clone(),
+            PrivateCrossSigningIdentity::empty(alice_id()),
+            bob_device,
+            alice_machine.store.clone(),
+            None,
+            Some("CONFLICT".to_owned()),
+        );
+        alice_machine.verifications.insert_sas(alice_sas);
+
+        let (_, start_content) = Sas::start(
+            ReadOnlyAccount::new(&bob_id(), &bob_device_id()),
+            PrivateCrossSigningIdentity::empty(bob_id()),
+            ReadOnlyDevice::from_account(&alice_machine.account).await,
+            Arc::new(MemoryStore::new()),
+            None,
+            Some("CONFLICT".to_owned()),
+        );
+        alice_machine
+            .receive_any_event(&wrap_any_to_device_content(&bob_id(), start_content))
+            .await
+            .unwrap();
+
+        let sas = alice_machine.get_sas("CONFLICT").unwrap();
+        assert!(sas.is_start_pending());
+        assert_eq!(sas.user_id(), &alice_id());
+    }
 }
@@ -15,16 +15,29 @@
 mod cache;
 mod event_enums;
 mod machine;
+#[cfg(feature = "qrcode")]
+mod qrcode;
 mod requests;
 mod sas;
 
-use std::sync::Arc;
+use std::{
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use event_enums::OutgoingContent;
 pub use machine::VerificationMachine;
+use matrix_sdk_common::uuid::Uuid;
+#[cfg(feature = "qrcode")]
+pub use qrcode::QrVerification;
 pub use requests::VerificationRequest;
 use ruma::{
-    api::client::r0::keys::upload_signatures::Request as SignatureUploadRequest,
+    api::client::r0::{
+        keys::upload_signatures::Request as SignatureUploadRequest, to_device::DeviceIdOrAllDevices,
+    },
     events::{
         key::verification::{
             cancel::{CancelCode, CancelEventContent, CancelToDeviceEventContent},
@@ -33,33 +46,98 @@ use ruma::{
         },
         AnyMessageEventContent, AnyToDeviceEventContent,
     },
-    DeviceId, EventId, RoomId, UserId,
+    DeviceId, DeviceIdBox, EventId, RoomId, UserId,
+};
+use sas::content_to_request;
+pub use sas::{
+    bytes_to_decimal, bytes_to_emoji, bytes_to_emoji_index, emoji_from_index,
+    short_auth_string_info, AcceptSettings, Sas,
 };
-pub use sas::{AcceptSettings, Sas};
+use serde::{Deserialize, Serialize};
 use tracing::{error, info, trace, warn};
 
 use crate::{
     error::SignatureError,
+    identities::MasterPubkey,
     olm::PrivateCrossSigningIdentity,
     store::{Changes, CryptoStore, DeviceChanges},
-    CryptoStoreError, LocalTrust, ReadOnlyDevice, UserIdentities,
+    utilities::log_id,
+    CryptoStoreError, LocalTrust, OutgoingVerificationRequest, ReadOnlyDevice, RoomMessageRequest,
+    UserIdentities,
 };
 
 #[derive(Clone, Debug)]
 pub enum Verification {
     SasV1(Sas),
+    #[cfg(feature = "qrcode")]
+    QrV1(QrVerification),
+    Interrupted(InterruptedVerification),
 }
 
 impl Verification {
     pub fn is_done(&self) -> bool {
         match self {
             Verification::SasV1(s) => s.is_done(),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.is_done(),
+            Verification::Interrupted(_) => false,
         }
     }
 
     pub fn is_cancelled(&self) -> bool {
         match self {
             Verification::SasV1(s) => s.is_cancelled(),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.is_cancelled(),
+            Verification::Interrupted(i) => i.is_cancelled(),
+        }
+    }
+
+    /// Get the unique ID of this verification flow.
+    pub fn flow_id(&self) -> &FlowId {
+        match self {
+            Verification::SasV1(s) => s.flow_id(),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.flow_id(),
+            Verification::Interrupted(i) => i.flow_id(),
+        }
+    }
+
+    /// Get the id of the other user that is participating in this
+    /// verification flow.
+    pub fn other_user_id(&self) -> &UserId {
+        match self {
+            Verification::SasV1(s) => s.other_user_id(),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.other_user_id(),
+            Verification::Interrupted(i) => i.other_user_id(),
+        }
+    }
+
+    /// Has the verification flow progressed past the point where another
+    /// flow could replace it, e.g. a SAS flow was started or a QR code was
+    /// scanned.
+    ///
+    /// An interrupted flow can't be continued, a new flow can replace it.
+    pub(crate) fn is_started(&self) -> bool {
+        match self {
+            Verification::SasV1(_) => true,
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.is_started(),
+            Verification::Interrupted(_) => false,
+        }
+    }
+
+    /// Cancel the verification flow with the given cancel code.
+    ///
+    /// Returns None if the flow is already done or cancelled, otherwise it
+    /// returns a request that needs to be sent out.
+    pub fn cancel_with_code(&self, code: CancelCode) -> Option<OutgoingVerificationRequest> {
+        match self {
+            Verification::SasV1(s) => s.cancel_with_code(code),
+            #[cfg(feature = "qrcode")]
+            Verification::QrV1(qr) => qr.cancel_with_code(code),
+            Verification::Interrupted(i) => i.cancel_with_code(code),
         }
     }
 }
@@ -70,6 +148,19 @@ impl From<Sas> for Verification {
     }
 }
 
+#[cfg(feature = "qrcode")]
+impl From<QrVerification> for Verification {
+    fn from(qr: QrVerification) -> Self {
+        Self::QrV1(qr)
+    }
+}
+
+impl From<InterruptedVerification> for Verification {
+    fn from(flow: InterruptedVerification) -> Self {
+        Self::Interrupted(flow)
+    }
+}
+
 /// The verification state indicating that the verification finished
 /// successfully.
 ///
@@ -194,6 +285,135 @@ impl From<(&RoomId, &EventId)> for FlowId {
     }
 }
 
+/// The minimal state of an in-progress verification flow that gets persisted
+/// in the crypto store.
+///
+/// The short authentication string can't survive a restart of the process,
+/// the ephemeral keys are only kept in memory. The stored state lets us find
+/// out which flows were interrupted, and tell the other side that they won't
+/// be continued.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredVerificationFlow {
+    /// The unique ID of the flow, the transaction id for to-device flows or
+    /// the event id of the request for in-room flows.
+    pub flow_id: String,
+    /// The room the flow is happening in, if it's an in-room flow.
+    pub room_id: Option<RoomId>,
+    /// The other user that is participating in the flow.
+    pub other_user_id: UserId,
+    /// The device of the other user that is participating in the flow, if we
+    /// know it already.
+    pub other_device_id: Option<DeviceIdBox>,
+}
+
+impl StoredVerificationFlow {
+    pub(crate) fn new(
+        flow_id: &FlowId,
+        other_user_id: &UserId,
+        other_device_id: Option<&DeviceId>,
+    ) -> Self {
+        Self {
+            flow_id: flow_id.as_str().to_owned(),
+            room_id: flow_id.room_id().cloned(),
+            other_user_id: other_user_id.to_owned(),
+            other_device_id: other_device_id.map(|d| d.into()),
+        }
+    }
+
+    /// Get the flow id of the stored verification flow.
+    ///
+    /// Returns `None` if the flow is an in-room flow and the stored flow id
+    /// isn't a valid event id.
+    pub(crate) fn to_flow_id(&self) -> Option<FlowId> {
+        match &self.room_id {
+            Some(r) => EventId::try_from(self.flow_id.as_str()).ok().map(|e| (r.clone(), e).into()),
+            None => Some(self.flow_id.clone().into()),
+        }
+    }
+}
+
+/// A verification flow that was interrupted by a restart of the process.
+///
+/// The flow is restored from the crypto store so it can be listed, but it
+/// can't be continued since the ephemeral keys of the flow were only kept in
+/// memory. It can only be cancelled, to let the other side know that a new
+/// flow needs to be started.
+#[derive(Clone, Debug)]
+pub struct InterruptedVerification {
+    flow: Arc<StoredVerificationFlow>,
+    flow_id: Arc<FlowId>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl InterruptedVerification {
+    pub(crate) fn new(flow: StoredVerificationFlow, flow_id: FlowId) -> Self {
+        Self {
+            flow: flow.into(),
+            flow_id: flow_id.into(),
+            cancelled: AtomicBool::new(false).into(),
+        }
+    }
+
+    /// Get the unique ID of the interrupted flow.
+    pub fn flow_id(&self) -> &FlowId {
+        &self.flow_id
+    }
+
+    /// Get the id of the other user that participated in the flow.
+    pub fn other_user_id(&self) -> &UserId {
+        &self.flow.other_user_id
+    }
+
+    /// Get the id of the other device that participated in the flow, if we
+    /// knew it when the flow got interrupted.
+    pub fn other_device_id(&self) -> Option<&DeviceId> {
+        self.flow.other_device_id.as_deref()
+    }
+
+    /// Has the interrupted flow been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Cancel the interrupted flow.
+    ///
+    /// This cancels the flow with the `CancelCode::User`.
+    ///
+    /// Returns None if the flow is already cancelled, otherwise it returns a
+    /// request that needs to be sent out.
+    pub fn cancel(&self) -> Option<OutgoingVerificationRequest> {
+        self.cancel_with_code(CancelCode::User)
+    }
+
+    pub(crate) fn cancel_with_code(&self, code: CancelCode) -> Option<OutgoingVerificationRequest> {
+        if self.cancelled.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+
+        Some(match Cancelled::new(code).as_content(&self.flow_id) {
+            OutgoingContent::ToDevice(c) => {
+                let recipient_device = self
+                    .flow
+                    .other_device_id
+                    .clone()
+                    .map(DeviceIdOrAllDevices::DeviceId)
+                    .unwrap_or(DeviceIdOrAllDevices::AllDevices);
+
+                content_to_request(self.other_user_id(), recipient_device, c).into()
+            }
+            OutgoingContent::Room(room_id, content) => {
+                RoomMessageRequest { room_id, txn_id: Uuid::new_v4(), content }.into()
+            }
+        })
+    }
+
+    pub(crate) fn receive_cancel(&self, sender: &UserId) {
+        if sender == self.other_user_id() {
+            self.cancelled.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
 /// A result of a verification flow.
 #[derive(Clone, Debug)]
 pub enum VerificationResult {
@@ -230,24 +450,57 @@ impl IdentitiesBeingVerified {
         &self.device_being_verified
     }
 
+    /// Get our own master key if we trust it.
+    ///
+    /// We trust our master key if we have the private part of it or if we
+    /// verified our own user identity.
+    async fn own_master_key(&self) -> Result<Option<MasterPubkey>, CryptoStoreError> {
+        if let Some(master_key) = self.private_identity.master_public_key().await {
+            return Ok(Some(master_key));
+        }
+
+        let identity = self.store.get_user_identity(self.user_id()).await?;
+
+        Ok(identity
+            .as_ref()
+            .and_then(|i| i.own())
+            .filter(|i| i.is_verified())
+            .map(|i| i.master_key().clone()))
+    }
+
     pub async fn mark_as_done(
         &self,
         verified_devices: Option<&[ReadOnlyDevice]>,
         verified_identities: Option<&[UserIdentities]>,
     ) -> Result<VerificationResult, CryptoStoreError> {
-        if let Some(device) = self.mark_device_as_verified(verified_devices).await? {
-            let identity = self.mark_identity_as_verified(verified_identities).await?;
+        // Flows that verify devices, e.g. SAS, fail if the device couldn't be
+        // verified. QR code flows might only verify a user identity.
+        let device = if verified_devices.is_some() {
+            match self.mark_device_as_verified(verified_devices).await? {
+                Some(d) => Some(d),
+                None => return Ok(VerificationResult::Cancel(CancelCode::UserMismatch)),
+            }
+        } else {
+            None
+        };
+
+        let identity = self.mark_identity_as_verified(verified_identities).await?;
 
-            // We only sign devices of our own user here.
-            let signature_request = if device.user_id() == self.user_id() {
-                match self.private_identity.sign_device(&device).await {
+        if device.is_none() && identity.is_none() {
+            return Ok(VerificationResult::Cancel(CancelCode::UserMismatch));
+        }
+
+        // We only sign devices of our own user here.
+        let signature_request = match &device {
+            Some(device) if device.user_id() == self.user_id() => {
+                match self.private_identity.sign_device(device).await {
                     Ok(r) => Some(r),
                     Err(SignatureError::MissingSigningKey) => {
                         warn!(
                             "Can't sign the device keys for {} {}, \
                                   no private user signing key found",
-                            device.user_id(),
-                            device.device_id(),
+                            log_id(device.user_id().as_str()),
+                            log_id(device.device_id().as_str()),
                         );
 
                         None
@@ -255,80 +508,77 @@ impl IdentitiesBeingVerified {
                     Err(e) => {
                         error!(
                             "Error signing device keys for {} {} {:?}",
-                            device.user_id(),
-                            device.device_id(),
+                            log_id(device.user_id().as_str()),
+                            log_id(device.device_id().as_str()),
                             e
                         );
                         None
                     }
                 }
-            } else {
-                None
-            };
+            }
+            _ => None,
+        };
 
-            let mut changes = Changes {
-                devices: DeviceChanges { changed: vec![device], ..Default::default() },
-                ..Default::default()
-            };
+        let mut changes = Changes {
+            devices: DeviceChanges { changed: device.into_iter().collect(), ..Default::default() },
+            ..Default::default()
+        };
 
-            let identity_signature_request = if let Some(i) = identity {
-                // We only sign other users here.
-                let request = if let Some(i) = i.other() {
-                    // Signing can fail if the user signing key is missing.
-                    match self.private_identity.sign_user(i).await {
-                        Ok(r) => Some(r),
-                        Err(SignatureError::MissingSigningKey) => {
-                            warn!(
-                                "Can't sign the public cross signing keys for {}, \
-                                  no private user signing key found",
-                                i.user_id()
-                            );
-                            None
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error signing the public cross signing keys for {} {:?}",
-                                i.user_id(),
-                                e
-                            );
-                            None
-                        }
+        let identity_signature_request = if let Some(i) = identity {
+            // We only sign other users here.
+            let request = if let Some(i) = i.other() {
+                // Signing can fail if the user signing key is missing.
+                match self.private_identity.sign_user(i).await {
+                    Ok(r) => Some(r),
+                    Err(SignatureError::MissingSigningKey) => {
+                        warn!(
+                            "Can't sign the public cross signing keys for {}, \
+                              no private user signing key found",
+                            log_id(i.user_id().as_str())
+                        );
+                        None
                     }
-                } else {
-                    None
-                };
-
-                changes.identities.changed.push(i);
-
-                request
+                    Err(e) => {
+                        error!(
+                            "Error signing the public cross signing keys for {} {:?}",
+                            log_id(i.user_id().as_str()),
+                            e
+                        );
+                        None
+                    }
+                }
             } else {
                 None
             };
 
-            // If there are two signature upload requests, merge them. Otherwise
-            // use the one we have or None.
-            //
-            // Realistically at most one request will be used but let's make
-            // this future proof.
-            let merged_request = if let Some(mut r) = signature_request {
-                if let Some(user_request) = identity_signature_request {
-                    r.signed_keys.extend(user_request.signed_keys);
-                    Some(r)
-                } else {
-                    Some(r)
-                }
-            } else {
-                identity_signature_request
-            };
+            changes.identities.changed.push(i);
 
-            // TODO store the signature upload request as well.
-            self.store.save_changes(changes).await?;
-            Ok(merged_request
-                .map(VerificationResult::SignatureUpload)
-                .unwrap_or(VerificationResult::Ok))
+            request
         } else {
-            Ok(VerificationResult::Cancel(CancelCode::UserMismatch))
-        }
+            None
+        };
+
+        // If there are two signature upload requests, merge them. Otherwise
+        // use the one we have or None.
+        //
+        // Realistically at most one request will be used but let's make
+        // this future proof.
+        let merged_request = if let Some(mut r) = signature_request {
+            if let Some(user_request) = identity_signature_request {
+                r.signed_keys.extend(user_request.signed_keys);
+                Some(r)
+            } else {
+                Some(r)
+            }
+        } else {
+            identity_signature_request
+        };
+
+        // TODO store the signature upload request as well.
+        self.store.save_changes(changes).await?;
+        Ok(merged_request
+            .map(VerificationResult::SignatureUpload)
+            .unwrap_or(VerificationResult::Ok))
     }
 
     async fn mark_identity_as_verified(
@@ -353,7 +603,7 @@ impl IdentitiesBeingVerified {
             {
                 if verified_identities.map_or(false, |i| i.contains(&identity)) {
                     trace!(
-                        user_id = self.other_user_id().as_str(),
+                        user_id = %log_id(self.other_user_id().as_str()),
                         "Marking the user identity of as verified."
                     );
 
@@ -364,7 +614,7 @@ impl IdentitiesBeingVerified {
                     Ok(Some(identity))
                 } else {
                     info!(
-                        user_id = self.other_user_id().as_str(),
+                        user_id = %log_id(self.other_user_id().as_str()),
                         "The interactive verification process didn't verify \
                          the user identity of the user that participated in \
                          the interactive verification",
@@ -374,7 +624,7 @@ impl IdentitiesBeingVerified {
                 }
             } else {
                 warn!(
-                    user_id = self.other_user_id().as_str(),
+                    user_id = %log_id(self.other_user_id().as_str()),
                     "The master keys of the user have changed while an interactive \
                       verification was going on, not marking the identity as verified.",
                 );
@@ -383,7 +633,7 @@ impl IdentitiesBeingVerified {
             }
         } else {
             info!(
-                user_id = self.other_user_id().as_str(),
+                user_id = %log_id(self.other_user_id().as_str()),
                 "The identity of the user was deleted while an interactive \
                  verification was going on.",
             );
@@ -401,8 +651,8 @@ impl IdentitiesBeingVerified {
             if device.keys() == self.device_being_verified.keys() {
                 if verified_devices.map_or(false, |v| v.contains(&device)) {
                     trace!(
-                        user_id = device.user_id().as_str(),
-                        device_id = device.device_id().as_str(),
+                        user_id = %log_id(device.user_id().as_str()),
+                        device_id = %log_id(device.device_id().as_str()),
                         "Marking device as verified.",
                     );
 
@@ -411,8 +661,8 @@ impl IdentitiesBeingVerified {
                     Ok(Some(device))
                 } else {
                     info!(
-                        user_id = device.user_id().as_str(),
-                        device_id = device.device_id().as_str(),
+                        user_id = %log_id(device.user_id().as_str()),
+                        device_id = %log_id(device.device_id().as_str()),
                         "The interactive verification process didn't verify \
                         the device",
                     );
@@ -421,8 +671,8 @@ impl IdentitiesBeingVerified {
                 }
             } else {
                 warn!(
-                    user_id = device.user_id().as_str(),
-                    device_id = device.device_id().as_str(),
+                    user_id = %log_id(device.user_id().as_str()),
+                    device_id = %log_id(device.device_id().as_str()),
                     "The device keys have changed while an interactive \
                      verification was going on, not marking the device as verified.",
                 );
@@ -432,8 +682,8 @@ impl IdentitiesBeingVerified {
             let device = &self.device_being_verified;
 
             info!(
-                user_id = device.user_id().as_str(),
-                device_id = device.device_id().as_str(),
+                user_id = %log_id(device.user_id().as_str()),
+                device_id = %log_id(device.device_id().as_str()),
                 "The device was deleted while an interactive verification was \
                  going on.",
             );
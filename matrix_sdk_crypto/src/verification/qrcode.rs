@@ -0,0 +1,548 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, Mutex};
+
+use getrandom::getrandom;
+use matrix_qrcode::{
+    qrcode::QrCode, EncodingError, QrVerification as QrVerificationData, QrVerificationBuilder,
+};
+use matrix_sdk_common::uuid::Uuid;
+use ruma::{
+    api::client::r0::keys::upload_signatures::Request as SignatureUploadRequest,
+    events::key::verification::{
+        cancel::CancelCode,
+        start::{ReciprocateV1Content, StartEventContent, StartMethod, StartToDeviceEventContent},
+        Relation,
+    },
+    DeviceId, DeviceKeyAlgorithm, UserId,
+};
+use tracing::{trace, warn};
+
+use super::{
+    event_enums::{OutgoingContent, OwnedStartContent},
+    sas::content_to_request,
+    Cancelled, Done, FlowId, IdentitiesBeingVerified, VerificationResult,
+};
+use crate::{
+    error::VerificationRequestError,
+    identities::{ReadOnlyDevice, UserIdentities},
+    olm::PrivateCrossSigningIdentity,
+    requests::{OutgoingVerificationRequest, RoomMessageRequest},
+    store::{CryptoStore, CryptoStoreError},
+    utilities::{encode, log_id},
+    ReadOnlyAccount,
+};
+
+/// The length of the shared secret we put into the QR codes we show, in bytes.
+const SECRET_SIZE: usize = 16;
+
+#[derive(Clone, Debug)]
+enum InnerState {
+    /// We're showing a QR code and wait for the other side to scan it.
+    Created,
+    /// The other side told us that it scanned our QR code, the user needs to
+    /// confirm that this really happened.
+    Scanned,
+    /// The user confirmed that the other side scanned our QR code, we're
+    /// waiting for the done event of the other side.
+    Confirmed,
+    /// We scanned the QR code of the other side and are waiting for it to
+    /// confirm the scan.
+    Reciprocated,
+    Done,
+    Cancelled(Cancelled),
+}
+
+/// An object controlling QR code style key verification flows.
+#[derive(Clone, Debug)]
+pub struct QrVerification {
+    account: ReadOnlyAccount,
+    flow_id: Arc<FlowId>,
+    inner: Arc<QrVerificationData>,
+    state: Arc<Mutex<InnerState>>,
+    identities: IdentitiesBeingVerified,
+    verified_devices: Arc<[ReadOnlyDevice]>,
+    verified_identities: Arc<[UserIdentities]>,
+    we_scanned: bool,
+}
+
+impl QrVerification {
+    /// Get the unique ID that identifies this QR code verification flow.
+    pub fn flow_id(&self) -> &FlowId {
+        &self.flow_id
+    }
+
+    /// Get the user id of the other side.
+    pub fn other_user_id(&self) -> &UserId {
+        self.identities.other_user_id()
+    }
+
+    /// Get the device id of the other side.
+    pub fn other_device_id(&self) -> &DeviceId {
+        self.identities.other_device_id()
+    }
+
+    /// Did we scan the QR code of the other side, or is the other side
+    /// scanning ours.
+    pub fn we_scanned(&self) -> bool {
+        self.we_scanned
+    }
+
+    /// Has the other side scanned our QR code.
+    ///
+    /// The user should be asked to confirm this by calling
+    /// [`confirm_scanning()`](#method.confirm_scanning).
+    pub fn has_been_scanned(&self) -> bool {
+        matches!(&*self.state.lock().unwrap(), InnerState::Scanned)
+    }
+
+    /// Is the QR code verification flow done.
+    pub fn is_done(&self) -> bool {
+        matches!(&*self.state.lock().unwrap(), InnerState::Done)
+    }
+
+    /// Is the QR code verification flow cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        matches!(&*self.state.lock().unwrap(), InnerState::Cancelled(_))
+    }
+
+    /// Has the flow progressed past showing the QR code, i.e. one of the
+    /// sides scanned the QR code of the other one.
+    pub(crate) fn is_started(&self) -> bool {
+        !matches!(&*self.state.lock().unwrap(), InnerState::Created)
+    }
+
+    /// Generate a QR code that can be rendered and shown to the other side.
+    pub fn to_qr_code(&self) -> Result<QrCode, EncodingError> {
+        self.inner.to_qr_code()
+    }
+
+    /// Encode the data of the QR code into bytes, e.g. to render the QR code
+    /// with a different library.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EncodingError> {
+        self.inner.to_bytes()
+    }
+
+    /// Confirm that the other side scanned our QR code.
+    ///
+    /// Does nothing if the other side didn't yet tell us that it scanned our
+    /// QR code, otherwise returns a done request that needs to be sent out and
+    /// a signature upload request if the verification produced new
+    /// signatures.
+    pub async fn confirm_scanning(
+        &self,
+    ) -> Result<
+        (Option<OutgoingVerificationRequest>, Option<SignatureUploadRequest>),
+        CryptoStoreError,
+    > {
+        if !self.has_been_scanned() {
+            return Ok((None, None));
+        }
+
+        let signature_request = match self.verify().await? {
+            VerificationResult::Cancel(c) => return Ok((self.cancel_with_code(c), None)),
+            VerificationResult::Ok => None,
+            VerificationResult::SignatureUpload(r) => Some(r),
+        };
+
+        trace!(
+            user_id = %log_id(self.other_user_id().as_str()),
+            device_id = %log_id(self.other_device_id().as_str()),
+            "Confirming that the QR code was scanned"
+        );
+
+        *self.state.lock().unwrap() = InnerState::Confirmed;
+
+        Ok((Some(self.content_to_request(self.done_content())), signature_request))
+    }
+
+    /// Cancel the verification flow.
+    ///
+    /// This cancels the verification with the `CancelCode::User`.
+    ///
+    /// Returns None if the flow is already done or cancelled, otherwise it
+    /// returns a request that needs to be sent out.
+    pub fn cancel(&self) -> Option<OutgoingVerificationRequest> {
+        self.cancel_with_code(CancelCode::User)
+    }
+
+    pub(crate) fn cancel_with_code(&self, code: CancelCode) -> Option<OutgoingVerificationRequest> {
+        let mut state = self.state.lock().unwrap();
+
+        if matches!(&*state, InnerState::Done | InnerState::Cancelled(_)) {
+            return None;
+        }
+
+        let cancelled = Cancelled::new(code);
+        let content = cancelled.as_content(&self.flow_id);
+        *state = InnerState::Cancelled(cancelled);
+
+        Some(self.content_to_request(content))
+    }
+
+    /// Generate a QR code for the given verification flow, to be shown to
+    /// the other side.
+    ///
+    /// Returns `None` if we don't trust the keys that would need to be put
+    /// into the QR code.
+    pub(crate) async fn generate(
+        store: Arc<dyn CryptoStore>,
+        account: ReadOnlyAccount,
+        private_identity: PrivateCrossSigningIdentity,
+        other_device: ReadOnlyDevice,
+        other_identity: Option<UserIdentities>,
+        flow_id: FlowId,
+    ) -> Result<Option<Self>, CryptoStoreError> {
+        let identities = IdentitiesBeingVerified {
+            private_identity,
+            store,
+            device_being_verified: other_device,
+            identity_being_verified: other_identity,
+        };
+
+        let own_master_key = identities.own_master_key().await?;
+        let own_master_key = own_master_key.as_ref().and_then(|k| k.get_first_key());
+
+        let (builder, verified_devices, verified_identities) = if identities.other_user_id()
+            != identities.user_id()
+        {
+            // We show our master key and the master key we believe belongs to
+            // the other user, on success we verify the other user.
+            let identity =
+                identities.identity_being_verified.clone().filter(|i| i.other().is_some());
+
+            match (&flow_id, own_master_key, identity) {
+                (FlowId::InRoom(_, event_id), Some(own_master_key), Some(identity)) => {
+                    let other_master_key = match identity.master_key().get_first_key() {
+                        Some(k) => k.to_owned(),
+                        None => return Ok(None),
+                    };
+
+                    (
+                        QrVerificationBuilder::verification(event_id.clone())
+                            .master_key(own_master_key)
+                            .other_master_key(other_master_key),
+                        vec![],
+                        vec![identity],
+                    )
+                }
+                _ => return Ok(None),
+            }
+        } else if let Some(own_master_key) = own_master_key {
+            // We trust our master key, on success we verify our other device.
+            let device_key = match identities.other_device().get_key(DeviceKeyAlgorithm::Ed25519) {
+                Some(k) => k.to_owned(),
+                None => return Ok(None),
+            };
+
+            (
+                QrVerificationBuilder::self_verification(flow_id.as_str())
+                    .master_key(own_master_key)
+                    .device_key(device_key),
+                vec![identities.other_device().clone()],
+                vec![],
+            )
+        } else {
+            // We don't trust our master key yet, we show the master key we
+            // believe is ours and let our other device, which trusts the master
+            // key, check it. On success we verify our own identity.
+            let identity =
+                match identities.identity_being_verified.clone().filter(|i| i.own().is_some()) {
+                    Some(i) => i,
+                    None => return Ok(None),
+                };
+            let master_key = match identity.master_key().get_first_key() {
+                Some(k) => k.to_owned(),
+                None => return Ok(None),
+            };
+
+            (
+                QrVerificationBuilder::self_verification_no_master_key(flow_id.as_str())
+                    .device_key(account.identity_keys().ed25519())
+                    .master_key(master_key),
+                vec![],
+                vec![identity],
+            )
+        };
+
+        match builder.shared_secret(generate_secret()).build() {
+            Ok(inner) => Ok(Some(Self::new(
+                account,
+                flow_id,
+                inner,
+                identities,
+                verified_devices,
+                verified_identities,
+                false,
+            ))),
+            Err(e) => {
+                warn!(
+                    flow_id = flow_id.as_str(),
+                    error =? e,
+                    "Can't generate a QR code for the verification flow"
+                );
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Check the QR code we scanned from the other side.
+    ///
+    /// Returns a `KeyMismatch` error if the keys in the QR code don't match
+    /// the keys we know of and trust.
+    pub(crate) async fn from_scan(
+        store: Arc<dyn CryptoStore>,
+        account: ReadOnlyAccount,
+        private_identity: PrivateCrossSigningIdentity,
+        other_device: ReadOnlyDevice,
+        other_identity: Option<UserIdentities>,
+        flow_id: FlowId,
+        qr_code: QrVerificationData,
+    ) -> Result<Self, VerificationRequestError> {
+        if qr_code.flow_id() != flow_id.as_str() {
+            return Err(VerificationRequestError::FlowIdMismatch);
+        }
+
+        let identities = IdentitiesBeingVerified {
+            private_identity,
+            store,
+            device_being_verified: other_device,
+            identity_being_verified: other_identity,
+        };
+
+        let own_master_key = identities.own_master_key().await?;
+        let own_master_key = own_master_key.as_ref().and_then(|k| k.get_first_key());
+        let keys_match = |first_key: Option<&str>, second_key: Option<&str>| {
+            first_key == Some(qr_code.first_key()) && second_key == Some(qr_code.second_key())
+        };
+
+        let (verified_devices, verified_identities) = match &qr_code {
+            QrVerificationData::Verification(_) => {
+                // The other user shows their master key and the master key
+                // they believe belongs to us, we verify the other user.
+                let identity = identities
+                    .identity_being_verified
+                    .clone()
+                    .filter(|i| i.other().is_some())
+                    .ok_or(VerificationRequestError::KeyMismatch)?;
+
+                if keys_match(identity.master_key().get_first_key(), own_master_key) {
+                    (vec![], vec![identity])
+                } else {
+                    return Err(VerificationRequestError::KeyMismatch);
+                }
+            }
+            QrVerificationData::SelfVerification(_) => {
+                // Our other device trusts our master key and shows it together
+                // with the key it believes belongs to this device, we verify
+                // our own identity.
+                let identity = identities
+                    .identity_being_verified
+                    .clone()
+                    .filter(|i| i.own().is_some())
+                    .ok_or(VerificationRequestError::KeyMismatch)?;
+
+                if keys_match(
+                    identity.master_key().get_first_key(),
+                    Some(account.identity_keys().ed25519()),
+                ) {
+                    (vec![], vec![identity])
+                } else {
+                    return Err(VerificationRequestError::KeyMismatch);
+                }
+            }
+            QrVerificationData::SelfVerificationNoMasterKey(_) => {
+                // Our other device doesn't trust our master key yet, it shows
+                // its own device key and the master key it believes belongs to
+                // us, we verify the other device.
+                let device_key = identities
+                    .other_device()
+                    .get_key(DeviceKeyAlgorithm::Ed25519)
+                    .map(|k| k.as_str());
+
+                if keys_match(device_key, own_master_key) {
+                    (vec![identities.other_device().clone()], vec![])
+                } else {
+                    return Err(VerificationRequestError::KeyMismatch);
+                }
+            }
+        };
+
+        Ok(Self::new(
+            account,
+            flow_id,
+            qr_code,
+            identities,
+            verified_devices,
+            verified_identities,
+            true,
+        ))
+    }
+
+    fn new(
+        account: ReadOnlyAccount,
+        flow_id: FlowId,
+        inner: QrVerificationData,
+        identities: IdentitiesBeingVerified,
+        verified_devices: Vec<ReadOnlyDevice>,
+        verified_identities: Vec<UserIdentities>,
+        we_scanned: bool,
+    ) -> Self {
+        let state = if we_scanned { InnerState::Reciprocated } else { InnerState::Created };
+
+        Self {
+            account,
+            flow_id: flow_id.into(),
+            inner: inner.into(),
+            state: Mutex::new(state).into(),
+            identities,
+            verified_devices: verified_devices.into(),
+            verified_identities: verified_identities.into(),
+            we_scanned,
+        }
+    }
+
+    /// Get the start event that tells the other side that we scanned their QR
+    /// code.
+    pub(crate) fn reciprocate(&self) -> OutgoingContent {
+        let method =
+            StartMethod::ReciprocateV1(ReciprocateV1Content::new(self.inner.secret().to_owned()));
+        match &*self.flow_id {
+            FlowId::ToDevice(t) => OwnedStartContent::ToDevice(StartToDeviceEventContent::new(
+                self.account.device_id().into(),
+                t.to_owned(),
+                method,
+            )),
+            FlowId::InRoom(r, e) => OwnedStartContent::Room(
+                r.to_owned(),
+                StartEventContent::new(
+                    self.account.device_id().into(),
+                    method,
+                    Relation::new(e.to_owned()),
+                ),
+            ),
+        }
+        .into()
+    }
+
+    /// The other side scanned our QR code and sent us the shared secret back.
+    ///
+    /// Returns a cancellation that needs to be sent out if the secret doesn't
+    /// match or if we didn't expect the other side to scan our QR code.
+    pub(crate) fn receive_reciprocation(&self, secret: &str) -> Option<OutgoingContent> {
+        let mut state = self.state.lock().unwrap();
+
+        let code = match *state {
+            InnerState::Created if secret == self.inner.secret() => {
+                trace!(
+                    user_id = %log_id(self.other_user_id().as_str()),
+                    device_id = %log_id(self.other_device_id().as_str()),
+                    "The other side scanned our QR code"
+                );
+
+                *state = InnerState::Scanned;
+
+                return None;
+            }
+            InnerState::Created => CancelCode::KeyMismatch,
+            InnerState::Done | InnerState::Cancelled(_) => return None,
+            _ => CancelCode::UnexpectedMessage,
+        };
+
+        let cancelled = Cancelled::new(code);
+        let content = cancelled.as_content(&self.flow_id);
+        *state = InnerState::Cancelled(cancelled);
+
+        Some(content)
+    }
+
+    /// The other side sent us a done event.
+    ///
+    /// Returns our own done event if we scanned the QR code of the other side,
+    /// the verification needs to be marked as done in that case.
+    pub(crate) fn receive_done(&self, sender: &UserId) -> Option<OutgoingContent> {
+        if sender != self.other_user_id() {
+            return None;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        match *state {
+            InnerState::Confirmed => {
+                *state = InnerState::Done;
+                None
+            }
+            InnerState::Reciprocated => Some(self.done_content()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn receive_cancel(&self, sender: &UserId, code: &CancelCode) {
+        if sender == self.other_user_id() {
+            let mut state = self.state.lock().unwrap();
+
+            if !matches!(&*state, InnerState::Done | InnerState::Cancelled(_)) {
+                *state = InnerState::Cancelled(Cancelled::new(code.clone()));
+            }
+        }
+    }
+
+    /// Mark the keys we scanned as verified after the other side confirmed the
+    /// scan.
+    pub(crate) async fn mark_as_done(&self) -> Result<VerificationResult, CryptoStoreError> {
+        let result = self.verify().await?;
+
+        if !matches!(result, VerificationResult::Cancel(_)) {
+            *self.state.lock().unwrap() = InnerState::Done;
+        }
+
+        Ok(result)
+    }
+
+    async fn verify(&self) -> Result<VerificationResult, CryptoStoreError> {
+        let verified_devices =
+            if self.verified_devices.is_empty() { None } else { Some(&*self.verified_devices) };
+
+        self.identities.mark_as_done(verified_devices, Some(&*self.verified_identities)).await
+    }
+
+    fn done_content(&self) -> OutgoingContent {
+        Done {
+            verified_devices: self.verified_devices.clone(),
+            verified_master_keys: self.verified_identities.clone(),
+        }
+        .as_content(&self.flow_id)
+    }
+
+    fn content_to_request(&self, content: OutgoingContent) -> OutgoingVerificationRequest {
+        match content {
+            OutgoingContent::ToDevice(c) => {
+                content_to_request(self.other_user_id(), self.other_device_id().to_owned(), c)
+                    .into()
+            }
+            OutgoingContent::Room(room_id, content) => {
+                RoomMessageRequest { room_id, txn_id: Uuid::new_v4(), content }.into()
+            }
+        }
+    }
+}
+
+fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_SIZE];
+    getrandom(&mut secret).expect("Can't generate randomness for the QR code secret");
+
+    encode(secret)
+}
@@ -16,6 +16,8 @@
 
 use std::sync::{Arc, Mutex};
 
+#[cfg(feature = "qrcode")]
+use matrix_qrcode::QrVerification as QrVerificationData;
 use matrix_sdk_common::uuid::Uuid;
 use ruma::{
     api::client::r0::to_device::DeviceIdOrAllDevices,
@@ -34,6 +36,8 @@ use ruma::{
 };
 use tracing::{info, warn};
 
+#[cfg(feature = "qrcode")]
+use super::QrVerification;
 use super::{
     cache::VerificationCache,
     event_enums::{
@@ -43,14 +47,42 @@ use super::{
     Cancelled, FlowId,
 };
 use crate::{
+    error::VerificationRequestError,
     olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
     store::CryptoStore,
+    utilities::log_id,
     CryptoStoreError, OutgoingVerificationRequest, ReadOnlyDevice, RoomMessageRequest, Sas,
     ToDeviceRequest, UserIdentities,
 };
 
+/// The verification methods we advertise in our requests and ready events.
+///
+/// The QR code methods are only advertised if the `qrcode` feature is enabled,
+/// we can neither show nor scan QR codes otherwise.
+#[cfg(feature = "qrcode")]
+const SUPPORTED_METHODS: &[VerificationMethod] = &[
+    VerificationMethod::MSasV1,
+    VerificationMethod::MQrCodeShowV1,
+    VerificationMethod::MQrCodeScanV1,
+    VerificationMethod::MReciprocateV1,
+];
+
+/// The verification methods we advertise in our requests and ready events.
+///
+/// The QR code methods are only advertised if the `qrcode` feature is enabled,
+/// we can neither show nor scan QR codes otherwise.
+#[cfg(not(feature = "qrcode"))]
 const SUPPORTED_METHODS: &[VerificationMethod] = &[VerificationMethod::MSasV1];
 
+/// Get the methods that are supported by both sides, in the order of our
+/// preference.
+fn common_methods(
+    our_methods: &[VerificationMethod],
+    their_methods: &[VerificationMethod],
+) -> Vec<VerificationMethod> {
+    our_methods.iter().filter(|m| their_methods.contains(m)).cloned().collect()
+}
+
 #[derive(Clone, Debug)]
 /// TODO
 pub struct VerificationRequest {
@@ -235,8 +267,8 @@ impl VerificationRequest {
             s.receive_start(sender, content).await?;
         } else {
             warn!(
-                sender = sender.as_str(),
-                device_id = content.from_device().as_str(),
+                sender = %log_id(sender.as_str()),
+                device_id = %log_id(content.from_device().as_str()),
                 "Received a key verification start event but we're not yet in the ready state"
             )
         }
@@ -258,11 +290,276 @@ impl VerificationRequest {
         }
     }
 
+    /// Cancel the verification request.
+    ///
+    /// This cancels the request with the `CancelCode::User`.
+    ///
+    /// Returns None if the request is already done or cancelled, otherwise it
+    /// returns a request that needs to be sent out.
+    pub fn cancel(&self) -> Option<OutgoingVerificationRequest> {
+        self.cancel_with_code(CancelCode::User)
+    }
+
+    pub(crate) fn cancel_with_code(&self, code: CancelCode) -> Option<OutgoingVerificationRequest> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if matches!(&*inner, InnerRequest::Done(_) | InnerRequest::Cancelled(_)) {
+            return None;
+        }
+
+        let other_device_id = inner.other_device_id();
+        inner.cancel(&code);
+
+        if let InnerRequest::Cancelled(s) = &*inner {
+            Some(match s.state.as_content(&self.flow_id) {
+                OutgoingContent::ToDevice(content) => {
+                    self.content_to_request(other_device_id, content).into()
+                }
+                OutgoingContent::Room(room_id, content) => {
+                    RoomMessageRequest { room_id, txn_id: Uuid::new_v4(), content }.into()
+                }
+            })
+        } else {
+            None
+        }
+    }
+
     /// Is the verification request ready to start a verification flow.
     pub fn is_ready(&self) -> bool {
         matches!(&*self.inner.lock().unwrap(), InnerRequest::Ready(_))
     }
 
+    /// Get the verification methods the other side advertised.
+    ///
+    /// Returns None if the other side didn't yet tell us which methods it
+    /// supports, or if the request already finished.
+    pub fn their_supported_methods(&self) -> Option<Vec<VerificationMethod>> {
+        match &*self.inner.lock().unwrap() {
+            InnerRequest::Requested(s) => Some(s.state.methods.clone()),
+            InnerRequest::Ready(s) => Some(s.state.their_methods.clone()),
+            InnerRequest::Created(_)
+            | InnerRequest::Passive(_)
+            | InnerRequest::Done(_)
+            | InnerRequest::Cancelled(_) => None,
+        }
+    }
+
+    /// Get the verification methods both sides support.
+    ///
+    /// Returns None if the request isn't in the ready state.
+    pub fn common_methods(&self) -> Option<Vec<VerificationMethod>> {
+        if let InnerRequest::Ready(s) = &*self.inner.lock().unwrap() {
+            Some(s.state.methods.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Transition from this verification request into a SAS verification
+    /// flow.
+    ///
+    /// Returns the new `Sas` object and a request that needs to be sent out
+    /// to start the flow. Fails if the request isn't in the ready state, if
+    /// the other side doesn't support SAS, if a verification flow was already
+    /// started for this request or if the device that accepted the request is
+    /// unknown.
+    pub async fn start_sas(
+        &self,
+    ) -> Result<(Sas, OutgoingVerificationRequest), VerificationRequestError> {
+        let state = self.ready_state()?;
+
+        if !state.state.methods.contains(&VerificationMethod::MSasV1) {
+            return Err(VerificationRequestError::UnsupportedMethod(VerificationMethod::MSasV1));
+        }
+
+        self.ensure_not_started()?;
+
+        let (device, identity) = self.other_device_and_identity(&state).await?;
+
+        let (sas, content) = state.clone().start_sas(
+            state.store.clone(),
+            state.account.clone(),
+            state.private_cross_signing_identity.clone(),
+            device,
+            identity,
+        );
+
+        // Another flow might have been started while we were loading the
+        // device.
+        if !self.verification_cache.try_insert(sas.clone().into()) {
+            return Err(VerificationRequestError::AlreadyStarted);
+        }
+
+        let request = self.outgoing_content_to_request(&state, content);
+
+        Ok((sas, request))
+    }
+
+    /// Generate a QR code that the other side can scan to verify us.
+    ///
+    /// Returns `None` if we don't trust the keys that would need to be put
+    /// into the QR code, e.g. if we don't have a cross signing identity yet.
+    /// Fails if the request isn't in the ready state, if the other side can't
+    /// scan QR codes, if a verification flow was already started for this
+    /// request or if the device that accepted the request is unknown.
+    #[cfg(feature = "qrcode")]
+    #[cfg_attr(feature = "docs", doc(cfg(qrcode)))]
+    pub async fn generate_qr_code(
+        &self,
+    ) -> Result<Option<QrVerification>, VerificationRequestError> {
+        let state = self.ready_state()?;
+
+        Self::ensure_their_methods(
+            &state,
+            &[VerificationMethod::MQrCodeScanV1, VerificationMethod::MReciprocateV1],
+        )?;
+        self.ensure_not_started()?;
+
+        let (device, identity) = self.other_device_and_identity(&state).await?;
+
+        let qr_verification = QrVerification::generate(
+            state.store.clone(),
+            state.account.clone(),
+            state.private_cross_signing_identity.clone(),
+            device,
+            identity,
+            (*self.flow_id).clone(),
+        )
+        .await?;
+
+        if let Some(qr_verification) = &qr_verification {
+            if !self.verification_cache.try_insert(qr_verification.clone().into()) {
+                return Err(VerificationRequestError::AlreadyStarted);
+            }
+        }
+
+        Ok(qr_verification)
+    }
+
+    /// Start a QR code verification flow by scanning the QR code of the other
+    /// side.
+    ///
+    /// Returns the new `QrVerification` object and a request that needs to be
+    /// sent out to tell the other side that we scanned their QR code. If the
+    /// keys in the QR code don't match the keys we know of, the request gets
+    /// cancelled and a `KeyMismatch` error is returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The decoded QR code of the other side.
+    #[cfg(feature = "qrcode")]
+    #[cfg_attr(feature = "docs", doc(cfg(qrcode)))]
+    pub async fn scan_qr_code(
+        &self,
+        data: QrVerificationData,
+    ) -> Result<(QrVerification, OutgoingVerificationRequest), VerificationRequestError> {
+        let state = self.ready_state()?;
+
+        Self::ensure_their_methods(
+            &state,
+            &[VerificationMethod::MQrCodeShowV1, VerificationMethod::MReciprocateV1],
+        )?;
+        self.ensure_not_started()?;
+
+        let (device, identity) = self.other_device_and_identity(&state).await?;
+
+        let qr_verification = match QrVerification::from_scan(
+            state.store.clone(),
+            state.account.clone(),
+            state.private_cross_signing_identity.clone(),
+            device,
+            identity,
+            (*self.flow_id).clone(),
+            data,
+        )
+        .await
+        {
+            Ok(v) => v,
+            Err(VerificationRequestError::KeyMismatch) => {
+                if let Some(r) = self.cancel_with_code(CancelCode::KeyMismatch) {
+                    self.verification_cache.add_request(r.into());
+                }
+
+                return Err(VerificationRequestError::KeyMismatch);
+            }
+            Err(e) => return Err(e),
+        };
+
+        if !self.verification_cache.try_insert(qr_verification.clone().into()) {
+            return Err(VerificationRequestError::AlreadyStarted);
+        }
+
+        let request = self.outgoing_content_to_request(&state, qr_verification.reciprocate());
+
+        Ok((qr_verification, request))
+    }
+
+    fn ready_state(&self) -> Result<RequestState<Ready>, VerificationRequestError> {
+        if let InnerRequest::Ready(s) = &*self.inner.lock().unwrap() {
+            Ok(s.clone())
+        } else {
+            Err(VerificationRequestError::NotReady)
+        }
+    }
+
+    #[cfg(feature = "qrcode")]
+    fn ensure_their_methods(
+        state: &RequestState<Ready>,
+        methods: &[VerificationMethod],
+    ) -> Result<(), VerificationRequestError> {
+        match methods.iter().find(|m| !state.state.their_methods.contains(m)) {
+            Some(m) => Err(VerificationRequestError::UnsupportedMethod(m.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Only a single verification flow can be started for a request, a QR
+    /// code that we show but wasn't yet scanned can still be replaced.
+    fn ensure_not_started(&self) -> Result<(), VerificationRequestError> {
+        if self.verification_cache.get(self.flow_id.as_str()).map_or(false, |v| v.is_started()) {
+            Err(VerificationRequestError::AlreadyStarted)
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn other_device_and_identity(
+        &self,
+        state: &RequestState<Ready>,
+    ) -> Result<(ReadOnlyDevice, Option<UserIdentities>), VerificationRequestError> {
+        let device = state
+            .store
+            .get_device(&self.other_user_id, &state.state.other_device_id)
+            .await?
+            .ok_or_else(|| {
+                VerificationRequestError::UnknownDevice(
+                    (*self.other_user_id).clone(),
+                    state.state.other_device_id.clone(),
+                )
+            })?;
+        let identity = state.store.get_user_identity(&self.other_user_id).await?;
+
+        Ok((device, identity))
+    }
+
+    fn outgoing_content_to_request(
+        &self,
+        state: &RequestState<Ready>,
+        content: OutgoingContent,
+    ) -> OutgoingVerificationRequest {
+        match content {
+            OutgoingContent::ToDevice(content) => self
+                .content_to_request(
+                    DeviceIdOrAllDevices::DeviceId(state.state.other_device_id.clone()),
+                    content,
+                )
+                .into(),
+            OutgoingContent::Room(room_id, content) => {
+                RoomMessageRequest { room_id, txn_id: Uuid::new_v4(), content }.into()
+            }
+        }
+    }
+
     pub(crate) fn start(
         &self,
         device: ReadOnlyDevice,
@@ -433,7 +730,7 @@ impl RequestState<Created> {
     }
 
     fn into_ready(self, _sender: &UserId, content: &ReadyContent) -> RequestState<Ready> {
-        // TODO check the flow id, and that the methods match what we suggested.
+        // TODO check the flow id.
         RequestState {
             account: self.account,
             flow_id: self.flow_id,
@@ -442,7 +739,8 @@ impl RequestState<Created> {
             store: self.store,
             other_user_id: self.other_user_id,
             state: Ready {
-                methods: content.methods().to_owned(),
+                methods: common_methods(&self.state.methods, content.methods()),
+                their_methods: content.methods().to_owned(),
                 other_device_id: content.from_device().into(),
                 flow_id: self.state.flow_id,
             },
@@ -508,7 +806,8 @@ impl RequestState<Requested> {
             flow_id: self.flow_id,
             other_user_id: self.other_user_id,
             state: Ready {
-                methods: SUPPORTED_METHODS.to_vec(),
+                methods: common_methods(SUPPORTED_METHODS, &self.state.methods),
+                their_methods: self.state.methods.clone(),
                 other_device_id: self.state.other_device_id.clone(),
                 flow_id: self.state.flow_id.clone(),
             },
@@ -540,9 +839,12 @@ impl RequestState<Requested> {
 
 #[derive(Clone, Debug)]
 struct Ready {
-    /// The verification methods supported by the sender.
+    /// The verification methods supported by both sides.
     pub methods: Vec<VerificationMethod>,
 
+    /// The verification methods the other side advertised.
+    pub their_methods: Vec<VerificationMethod>,
+
     /// The device id of the device that responded to the verification request.
     pub other_device_id: DeviceIdBox,
 
@@ -576,8 +878,8 @@ impl RequestState<Ready> {
         content: &StartContent<'_>,
     ) -> Result<(), CryptoStoreError> {
         info!(
-            sender = sender.as_str(),
-            device = content.from_device().as_str(),
+            sender = %log_id(sender.as_str()),
+            device = %log_id(content.from_device().as_str()),
             "Received a new verification start event",
         );
 
@@ -585,8 +887,8 @@ impl RequestState<Ready> {
             d
         } else {
             warn!(
-                sender = sender.as_str(),
-                device = content.from_device().as_str(),
+                sender = %log_id(sender.as_str()),
+                device = %log_id(content.from_device().as_str()),
                 "Received a key verification start event from an unknown device",
             );
 
@@ -596,15 +898,29 @@ impl RequestState<Ready> {
         let identity = self.store.get_user_identity(sender).await?;
 
         match content.method() {
+            StartMethod::SasV1(_) if !self.state.methods.contains(&VerificationMethod::MSasV1) => {
+                warn!(
+                    user_id = %log_id(device.user_id().as_str()),
+                    device_id = %log_id(device.device_id().as_str()),
+                    "Received a SAS start event but SAS wasn't negotiated, canceling.",
+                );
+
+                self.verification_cache.queue_up_content(
+                    device.user_id(),
+                    device.device_id(),
+                    Cancelled::new(CancelCode::UnknownMethod).as_content(&self.flow_id),
+                )
+            }
             StartMethod::SasV1(_) => match self.to_started_sas(content, device.clone(), identity) {
                 Ok(s) => {
-                    info!("Started a new SAS verification.");
-                    self.verification_cache.insert_sas(s);
+                    if self.verification_cache.insert_started_sas(s) {
+                        info!("Started a new SAS verification.");
+                    }
                 }
                 Err(c) => {
                     warn!(
-                        user_id = device.user_id().as_str(),
-                        device_id = device.device_id().as_str(),
+                        user_id = %log_id(device.user_id().as_str()),
+                        device_id = %log_id(device.device_id().as_str()),
                         content =? c,
                         "Can't start key verification, canceling.",
                     );
@@ -615,6 +931,32 @@ impl RequestState<Ready> {
                     )
                 }
             },
+            #[cfg(feature = "qrcode")]
+            StartMethod::ReciprocateV1(c) => {
+                match self.verification_cache.get_qr(self.flow_id.as_str()) {
+                    Some(qr) if qr.other_device_id() == device.device_id() => {
+                        if let Some(c) = qr.receive_reciprocation(&c.secret) {
+                            warn!(
+                                user_id = %log_id(device.user_id().as_str()),
+                                device_id = %log_id(device.device_id().as_str()),
+                                "The other side sent an invalid QR code secret, canceling.",
+                            );
+
+                            self.verification_cache.queue_up_content(
+                                device.user_id(),
+                                device.device_id(),
+                                c,
+                            )
+                        }
+                    }
+                    _ => warn!(
+                        user_id = %log_id(device.user_id().as_str()),
+                        device_id = %log_id(device.device_id().as_str()),
+                        "Received a QR code reciprocation but we didn't show a QR code to \
+                         this device",
+                    ),
+                }
+            }
             m => {
                 warn!(method =? m, "Received a key verification start event with an unsupported method")
             }
@@ -675,12 +1017,17 @@ struct Done {}
 #[cfg(test)]
 mod test {
     use std::convert::TryFrom;
+    #[cfg(feature = "qrcode")]
+    use std::sync::Arc;
 
+    #[cfg(feature = "qrcode")]
+    use matrix_qrcode::QrVerification as QrVerificationData;
     use matrix_sdk_test::async_test;
     use ruma::{event_id, room_id, DeviceIdBox, UserId};
 
-    use super::VerificationRequest;
+    use super::{VerificationRequest, SUPPORTED_METHODS};
     use crate::{
+        error::VerificationRequestError,
         olm::{PrivateCrossSigningIdentity, ReadOnlyAccount},
         store::{Changes, CryptoStore, MemoryStore},
         verification::{
@@ -690,6 +1037,8 @@ mod test {
         },
         ReadOnlyDevice,
     };
+    #[cfg(feature = "qrcode")]
+    use crate::{verification::VerificationResult, UserIdentities};
 
     fn alice_id() -> UserId {
         UserId::try_from("@alice:example.org").unwrap()
@@ -707,6 +1056,11 @@ mod test {
         "BOBDEVCIE".into()
     }
 
+    #[cfg(feature = "qrcode")]
+    fn second_device_id() -> DeviceIdBox {
+        "SECONDDEVICE".into()
+    }
+
     #[async_test]
     async fn test_request_accepting() {
         let event_id = event_id!("$1234localhost");
@@ -872,4 +1226,233 @@ mod test {
         assert!(!bob_sas.is_cancelled());
         assert!(!alice_sas.is_cancelled());
     }
+
+    #[async_test]
+    async fn test_method_negotiation_and_sas_start() {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let alice_device = ReadOnlyDevice::from_account(&alice).await;
+
+        let alice_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
+        let alice_identity = PrivateCrossSigningIdentity::empty(alice_id());
+
+        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        let bob_store: Box<dyn CryptoStore> = Box::new(MemoryStore::new());
+        let bob_identity = PrivateCrossSigningIdentity::empty(alice_id());
+
+        let mut changes = Changes::default();
+        changes.devices.new.push(bob_device.clone());
+        alice_store.save_changes(changes).await.unwrap();
+
+        let mut changes = Changes::default();
+        changes.devices.new.push(alice_device.clone());
+        bob_store.save_changes(changes).await.unwrap();
+
+        let bob_request = VerificationRequest::new_to_device(
+            VerificationCache::new(),
+            bob,
+            bob_identity,
+            bob_store.into(),
+            &alice_id(),
+        );
+
+        assert!(bob_request.their_supported_methods().is_none());
+        assert!(matches!(bob_request.start_sas().await, Err(VerificationRequestError::NotReady)));
+
+        let content = bob_request.request_to_device();
+        let flow_id = bob_request.flow_id().to_owned();
+
+        let alice_request = VerificationRequest::from_request(
+            VerificationCache::new(),
+            alice,
+            alice_identity,
+            alice_store.into(),
+            &bob_id(),
+            flow_id,
+            &(&content).into(),
+        );
+
+        assert_eq!(alice_request.their_supported_methods(), Some(SUPPORTED_METHODS.to_vec()));
+
+        let content: OutgoingContent = alice_request.accept().unwrap().into();
+        let content = ReadyContent::try_from(&content).unwrap();
+
+        bob_request.receive_ready(&alice_id(), &content).unwrap();
+
+        assert_eq!(bob_request.their_supported_methods(), Some(SUPPORTED_METHODS.to_vec()));
+        assert_eq!(bob_request.common_methods(), Some(SUPPORTED_METHODS.to_vec()));
+
+        let (bob_sas, request) = bob_request.start_sas().await.unwrap();
+
+        let content: OutgoingContent = request.into();
+        let content = StartContent::try_from(&content).unwrap();
+        let flow_id = content.flow_id().to_owned();
+        alice_request.receive_start(bob_device.user_id(), &content).await.unwrap();
+        let alice_sas = alice_request.verification_cache.get_sas(&flow_id).unwrap();
+
+        assert!(bob_request.verification_cache.get_sas(&flow_id).is_some());
+        assert!(!bob_sas.is_cancelled());
+        assert!(!alice_sas.is_cancelled());
+
+        assert!(matches!(
+            bob_request.start_sas().await,
+            Err(VerificationRequestError::AlreadyStarted)
+        ));
+    }
+
+    #[cfg(feature = "qrcode")]
+    #[async_test]
+    async fn test_qr_code_self_verification() {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let alice_device = ReadOnlyDevice::from_account(&alice).await;
+        let alice_store: Arc<dyn CryptoStore> = Arc::new(MemoryStore::new());
+        let alice_identity = PrivateCrossSigningIdentity::new(alice_id()).await;
+
+        let second = ReadOnlyAccount::new(&alice_id(), &second_device_id());
+        let second_device = ReadOnlyDevice::from_account(&second).await;
+        let second_store: Arc<dyn CryptoStore> = Arc::new(MemoryStore::new());
+        let second_identity = PrivateCrossSigningIdentity::empty(alice_id());
+
+        let public_identity: UserIdentities =
+            alice_identity.as_public_identity().await.unwrap().into();
+
+        let mut changes = Changes::default();
+        changes.devices.new.push(second_device.clone());
+        changes.identities.new.push(public_identity.clone());
+        alice_store.save_changes(changes).await.unwrap();
+
+        let mut changes = Changes::default();
+        changes.devices.new.push(alice_device.clone());
+        changes.identities.new.push(public_identity);
+        second_store.save_changes(changes).await.unwrap();
+
+        let second_request = VerificationRequest::new_to_device(
+            VerificationCache::new(),
+            second,
+            second_identity,
+            second_store.clone(),
+            &alice_id(),
+        );
+
+        let content = second_request.request_to_device();
+        let flow_id = second_request.flow_id().to_owned();
+
+        let alice_request = VerificationRequest::from_request(
+            VerificationCache::new(),
+            alice,
+            alice_identity,
+            alice_store.clone(),
+            &alice_id(),
+            flow_id,
+            &(&content).into(),
+        );
+
+        let content: OutgoingContent = alice_request.accept().unwrap().into();
+        let content = ReadyContent::try_from(&content).unwrap();
+        second_request.receive_ready(&alice_id(), &content).unwrap();
+
+        let alice_qr = alice_request.generate_qr_code().await.unwrap().unwrap();
+        let data = QrVerificationData::from_bytes(alice_qr.to_bytes().unwrap()).unwrap();
+
+        let (second_qr, request) = second_request.scan_qr_code(data).await.unwrap();
+        assert!(second_qr.we_scanned());
+        assert!(matches!(
+            second_request.start_sas().await,
+            Err(VerificationRequestError::AlreadyStarted)
+        ));
+
+        let content: OutgoingContent = request.into();
+        let content = StartContent::try_from(&content).unwrap();
+        alice_request.receive_start(&alice_id(), &content).await.unwrap();
+
+        assert!(alice_qr.has_been_scanned());
+        let (done, signature_request) = alice_qr.confirm_scanning().await.unwrap();
+        assert!(done.is_some());
+        assert!(signature_request.is_some());
+
+        let content = second_qr.receive_done(&alice_id()).unwrap();
+        assert!(matches!(second_qr.mark_as_done().await.unwrap(), VerificationResult::Ok));
+        assert!(matches!(content, OutgoingContent::ToDevice(_)));
+        assert!(alice_qr.receive_done(&alice_id()).is_none());
+
+        assert!(alice_qr.is_done());
+        assert!(second_qr.is_done());
+        assert!(alice_store
+            .get_device(&alice_id(), &second_device_id())
+            .await
+            .unwrap()
+            .unwrap()
+            .is_trusted());
+        assert!(second_store
+            .get_user_identity(&alice_id())
+            .await
+            .unwrap()
+            .unwrap()
+            .own()
+            .unwrap()
+            .is_verified());
+    }
+
+    #[cfg(feature = "qrcode")]
+    #[async_test]
+    async fn test_qr_code_key_mismatch() {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let alice_device = ReadOnlyDevice::from_account(&alice).await;
+        let alice_store: Arc<dyn CryptoStore> = Arc::new(MemoryStore::new());
+        let alice_identity = PrivateCrossSigningIdentity::new(alice_id()).await;
+
+        let second = ReadOnlyAccount::new(&alice_id(), &second_device_id());
+        let second_device = ReadOnlyDevice::from_account(&second).await;
+        let second_store: Arc<dyn CryptoStore> = Arc::new(MemoryStore::new());
+        let second_identity = PrivateCrossSigningIdentity::empty(alice_id());
+
+        let public_identity: UserIdentities =
+            alice_identity.as_public_identity().await.unwrap().into();
+
+        let mut changes = Changes::default();
+        changes.devices.new.push(second_device);
+        changes.identities.new.push(public_identity);
+        alice_store.save_changes(changes).await.unwrap();
+
+        // The second device never saw the cross signing identity, it can't
+        // check the master key in the QR code.
+        let mut changes = Changes::default();
+        changes.devices.new.push(alice_device);
+        second_store.save_changes(changes).await.unwrap();
+
+        let second_request = VerificationRequest::new_to_device(
+            VerificationCache::new(),
+            second,
+            second_identity,
+            second_store,
+            &alice_id(),
+        );
+
+        let content = second_request.request_to_device();
+        let flow_id = second_request.flow_id().to_owned();
+
+        let alice_request = VerificationRequest::from_request(
+            VerificationCache::new(),
+            alice,
+            alice_identity,
+            alice_store,
+            &alice_id(),
+            flow_id,
+            &(&content).into(),
+        );
+
+        let content: OutgoingContent = alice_request.accept().unwrap().into();
+        let content = ReadyContent::try_from(&content).unwrap();
+        second_request.receive_ready(&alice_id(), &content).unwrap();
+
+        let alice_qr = alice_request.generate_qr_code().await.unwrap().unwrap();
+        let data = QrVerificationData::from_bytes(alice_qr.to_bytes().unwrap()).unwrap();
+
+        assert!(matches!(
+            second_request.scan_qr_code(data).await,
+            Err(VerificationRequestError::KeyMismatch)
+        ));
+        assert!(second_request.is_cancelled());
+        assert_eq!(second_request.verification_cache.outgoing_requests().len(), 1);
+    }
 }
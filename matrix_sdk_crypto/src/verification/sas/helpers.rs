@@ -26,15 +26,15 @@ use ruma::{
         },
         AnyMessageEventContent, AnyToDeviceEventContent, EventType,
     },
-    DeviceKeyAlgorithm, DeviceKeyId, UserId,
+    DeviceId, DeviceKeyAlgorithm, DeviceKeyId, UserId,
 };
 use sha2::{Digest, Sha256};
 use tracing::{trace, warn};
 
 use super::{FlowId, OutgoingContent};
 use crate::{
-    identities::{ReadOnlyDevice, UserIdentities},
-    utilities::encode,
+    identities::{MasterPubkey, ReadOnlyDevice, UserIdentities},
+    utilities::{encode, log_id},
     verification::event_enums::{MacContent, StartContent},
     ReadOnlyAccount, ToDeviceRequest,
 };
@@ -44,6 +44,9 @@ pub struct SasIds {
     pub account: ReadOnlyAccount,
     pub other_device: ReadOnlyDevice,
     pub other_identity: Option<UserIdentities>,
+    /// Our own master key, it's only set once we confirmed the short auth
+    /// string and only if we trust it.
+    pub own_master_key: Option<MasterPubkey>,
 }
 
 /// Calculate the commitment for a accept event from the public key and the
@@ -73,7 +76,7 @@ pub fn calculate_commitment(public_key: &str, content: &StartContent) -> String
 /// bigger than 63.
 ///
 /// [spec]: https://matrix.org/docs/spec/client_server/latest#sas-method-emoji
-fn emoji_from_index(index: u8) -> (&'static str, &'static str) {
+pub fn emoji_from_index(index: u8) -> (&'static str, &'static str) {
     match index {
         0 => ("🐶", "Dog"),
         1 => ("🐱", "Cat"),
@@ -192,8 +195,8 @@ pub fn receive_mac_event(
 
     trace!(
         "Received a key.verification.mac event from {} {}",
-        sender,
-        ids.other_device.device_id()
+        log_id(sender.as_str()),
+        log_id(ids.other_device.device_id().as_str())
     );
 
     let mut keys = content.mac().keys().map(|k| k.as_str()).collect::<Vec<_>>();
@@ -211,8 +214,8 @@ pub fn receive_mac_event(
         trace!(
             "Checking MAC for the key id {} from {} {}",
             key_id,
-            sender,
-            ids.other_device.device_id()
+            log_id(sender.as_str()),
+            log_id(ids.other_device.device_id().as_str())
         );
 
         let key_id: DeviceKeyId = match key_id.as_str().try_into() {
@@ -226,7 +229,11 @@ pub fn receive_mac_event(
                     .calculate_mac(key, &format!("{}{}", info, key_id))
                     .expect("Can't calculate SAS MAC")
             {
-                trace!("Successfully verified the device key {} from {}", key_id, sender);
+                trace!(
+                    "Successfully verified the device key {} from {}",
+                    key_id,
+                    log_id(sender.as_str())
+                );
 
                 verified_devices.push(ids.other_device.clone());
             } else {
@@ -234,17 +241,37 @@ pub fn receive_mac_event(
             }
         } else if let Some(identity) = &ids.other_identity {
             if let Some(key) = identity.master_key().get_key(&key_id) {
-                // TODO we should check that the master key signs the device,
-                // this way we know the master key also trusts the device
                 if key_mac
-                    == &sas
+                    != &sas
                         .calculate_mac(key, &format!("{}{}", info, key_id))
                         .expect("Can't calculate SAS MAC")
                 {
-                    trace!("Successfully verified the master key {} from {}", key_id, sender);
+                    return Err(CancelCode::KeyMismatch);
+                }
+
+                // The master key is only verified if it trusts the device we
+                // are talking to, otherwise the device could vouch for any
+                // master key.
+                let device_signed = match identity {
+                    UserIdentities::Own(i) => i.is_device_signed(&ids.other_device),
+                    UserIdentities::Other(i) => i.is_device_signed(&ids.other_device),
+                };
+
+                if device_signed.is_ok() {
+                    trace!(
+                        "Successfully verified the master key {} from {}",
+                        key_id,
+                        log_id(sender.as_str())
+                    );
                     verified_identities.push(identity.clone())
                 } else {
-                    return Err(CancelCode::KeyMismatch);
+                    warn!(
+                        "The master key {} from {} doesn't sign the device {}, not \
+                        verifying the user identity",
+                        key_id,
+                        log_id(sender.as_str()),
+                        log_id(ids.other_device.device_id().as_str())
+                    );
                 }
             }
         } else {
@@ -252,8 +279,8 @@ pub fn receive_mac_event(
                 "Key ID {} in MAC event from {} {} doesn't belong to any device \
                 or user identity",
                 key_id,
-                sender,
-                ids.other_device.device_id()
+                log_id(sender.as_str()),
+                log_id(ids.other_device.device_id().as_str())
             );
         }
     }
@@ -306,7 +333,17 @@ pub fn get_mac_content(sas: &OlmSas, ids: &SasIds, flow_id: &FlowId) -> Outgoing
         sas.calculate_mac(key, &format!("{}{}", info, key_id)).expect("Can't calculate SAS MAC"),
     );
 
-    // TODO Add the cross signing master key here if we trust/have it.
+    // Add our master key if we trust it, this lets the other side verify our
+    // user identity in the same go.
+    if let Some(master_key) = &ids.own_master_key {
+        for (key_id, key) in master_key.keys() {
+            mac.insert(
+                key_id.to_owned(),
+                sas.calculate_mac(key, &format!("{}{}", info, key_id))
+                    .expect("Can't calculate SAS MAC"),
+            );
+        }
+    }
 
     let mut keys = mac.keys().cloned().collect::<Vec<String>>();
     keys.sort();
@@ -348,25 +385,68 @@ fn extra_info_sas(
     flow_id: &str,
     we_started: bool,
 ) -> String {
-    let our_info = format!("{}|{}|{}", ids.account.user_id(), ids.account.device_id(), own_pubkey);
-    let their_info =
-        format!("{}|{}|{}", ids.other_device.user_id(), ids.other_device.device_id(), their_pubkey);
-
-    let (first_info, second_info) =
-        if we_started { (our_info, their_info) } else { (their_info, our_info) };
-
-    let info = format!(
-        "MATRIX_KEY_VERIFICATION_SAS|{first_info}|{second_info}|{flow_id}",
-        first_info = first_info,
-        second_info = second_info,
-        flow_id = flow_id,
-    );
+    let ours = (ids.account.user_id(), ids.account.device_id(), own_pubkey);
+    let theirs = (ids.other_device.user_id(), ids.other_device.device_id(), their_pubkey);
+
+    let (first, second) = if we_started { (ours, theirs) } else { (theirs, ours) };
+
+    let info =
+        short_auth_string_info(first.0, first.1, first.2, second.0, second.1, second.2, flow_id);
 
     trace!("Generated a SAS extra info: {}", info);
 
     info
 }
 
+/// Get the info string that is used to generate the bytes of the short
+/// authentication string out of the shared secret using HKDF.
+///
+/// The bytes for the emoji method are generated by asking the Olm SAS object
+/// for 6 bytes with this info string, the decimal method uses 5 bytes.
+///
+/// # Arguments
+///
+/// * `first_user_id` - The user ID of the user that sent the
+/// `m.key.verification.start` event.
+///
+/// * `first_device_id` - The device ID of the device that sent the
+/// `m.key.verification.start` event.
+///
+/// * `first_public_key` - The ephemeral public key of the device that sent
+/// the `m.key.verification.start` event.
+///
+/// * `second_user_id` - The user ID of the user that sent the
+/// `m.key.verification.accept` event.
+///
+/// * `second_device_id` - The device ID of the device that sent the
+/// `m.key.verification.accept` event.
+///
+/// * `second_public_key` - The ephemeral public key of the device that sent
+/// the `m.key.verification.accept` event.
+///
+/// * `flow_id` - The transaction ID, or the event ID of the request in the
+/// case of in-room verification, of the verification flow.
+pub fn short_auth_string_info(
+    first_user_id: &UserId,
+    first_device_id: &DeviceId,
+    first_public_key: &str,
+    second_user_id: &UserId,
+    second_device_id: &DeviceId,
+    second_public_key: &str,
+    flow_id: &str,
+) -> String {
+    format!(
+        "MATRIX_KEY_VERIFICATION_SAS|{}|{}|{}|{}|{}|{}|{}",
+        first_user_id,
+        first_device_id,
+        first_public_key,
+        second_user_id,
+        second_device_id,
+        second_public_key,
+        flow_id,
+    )
+}
+
 /// Get the emoji version of the short authentication string.
 ///
 /// Returns seven tuples where the first element is the emoji and the
@@ -400,7 +480,7 @@ pub fn get_emoji(
         )
         .expect("Can't generate bytes");
 
-    bytes_to_emoji(bytes)
+    bytes_to_emoji(&bytes)
 }
 
 /// Get the index of the emoji of the short authentication string.
@@ -437,10 +517,19 @@ pub fn get_emoji_index(
         )
         .expect("Can't generate bytes");
 
-    bytes_to_emoji_index(bytes)
+    bytes_to_emoji_index(&bytes)
 }
 
-fn bytes_to_emoji_index(bytes: Vec<u8>) -> [u8; 7] {
+/// Convert the given bytes into the indices of the emoji of the short
+/// authentication string.
+///
+/// Returns seven numbers in the range from 0 to 63 inclusive, those can be
+/// converted into an emoji using [`emoji_from_index()`].
+///
+/// # Panics
+///
+/// This will panic if less than 6 bytes are given.
+pub fn bytes_to_emoji_index(bytes: &[u8]) -> [u8; 7] {
     let bytes: Vec<u64> = bytes.iter().map(|b| *b as u64).collect();
     // Join the 6 bytes into one 64 bit unsigned int. This u64 will contain 48
     // bits from our 6 bytes.
@@ -464,7 +553,15 @@ fn bytes_to_emoji_index(bytes: Vec<u8>) -> [u8; 7] {
     ]
 }
 
-fn bytes_to_emoji(bytes: Vec<u8>) -> [(&'static str, &'static str); 7] {
+/// Convert the given bytes into the emoji of the short authentication string.
+///
+/// Returns seven tuples where the first element is the emoji and the second
+/// element the English description of the emoji.
+///
+/// # Panics
+///
+/// This will panic if less than 6 bytes are given.
+pub fn bytes_to_emoji(bytes: &[u8]) -> [(&'static str, &'static str); 7] {
     let numbers = bytes_to_emoji_index(bytes);
 
     // Convert the 6 bit number into a emoji/description tuple.
@@ -512,11 +609,20 @@ pub fn get_decimal(
         )
         .expect("Can't generate bytes");
 
-    bytes_to_decimal(bytes)
+    bytes_to_decimal(&bytes)
 }
 
-fn bytes_to_decimal(bytes: Vec<u8>) -> (u16, u16, u16) {
-    let bytes: Vec<u16> = bytes.into_iter().map(|b| b as u16).collect();
+/// Convert the given bytes into the decimal version of the short
+/// authentication string.
+///
+/// Returns a tuple containing three 4 digit numbers in the range from 1000 to
+/// 9191 inclusive.
+///
+/// # Panics
+///
+/// This will panic if less than 5 bytes are given.
+pub fn bytes_to_decimal(bytes: &[u8]) -> (u16, u16, u16) {
+    let bytes: Vec<u16> = bytes.iter().map(|b| *b as u16).collect();
 
     // This bitwise operation is taken from the [spec]
     // [spec]: https://matrix.org/docs/spec/client_server/latest#sas-method-decimal
@@ -558,12 +664,12 @@ pub fn content_to_request(
 #[cfg(test)]
 mod test {
     use proptest::prelude::*;
-    use ruma::events::key::verification::start::StartToDeviceEventContent;
+    use ruma::{events::key::verification::start::StartToDeviceEventContent, user_id};
     use serde_json::json;
 
     use super::{
         bytes_to_decimal, bytes_to_emoji, bytes_to_emoji_index, calculate_commitment,
-        emoji_from_index,
+        emoji_from_index, short_auth_string_info,
     };
     use crate::verification::event_enums::StartContent;
 
@@ -589,36 +695,55 @@ mod test {
         assert_eq!(commitment, &calculated_commitment);
     }
 
+    #[test]
+    fn info_generation() {
+        let info = short_auth_string_info(
+            &user_id!("@alice:example.org"),
+            "ALICEDEVICE".into(),
+            "alice_key",
+            &user_id!("@bob:example.org"),
+            "BOBDEVICE".into(),
+            "bob_key",
+            "flow_id",
+        );
+
+        assert_eq!(
+            info,
+            "MATRIX_KEY_VERIFICATION_SAS|@alice:example.org|ALICEDEVICE|alice_key|\
+             @bob:example.org|BOBDEVICE|bob_key|flow_id"
+        );
+    }
+
     #[test]
     fn emoji_generation() {
         let bytes = vec![0, 0, 0, 0, 0, 0];
         let index: Vec<(&'static str, &'static str)> =
             vec![0, 0, 0, 0, 0, 0, 0].into_iter().map(emoji_from_index).collect();
-        assert_eq!(bytes_to_emoji(bytes), index.as_ref());
+        assert_eq!(bytes_to_emoji(&bytes), index.as_ref());
 
         let bytes = vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
 
         let index: Vec<(&'static str, &'static str)> =
             vec![63, 63, 63, 63, 63, 63, 63].into_iter().map(emoji_from_index).collect();
-        assert_eq!(bytes_to_emoji(bytes), index.as_ref());
+        assert_eq!(bytes_to_emoji(&bytes), index.as_ref());
     }
 
     #[test]
     fn decimal_generation() {
         let bytes = vec![0, 0, 0, 0, 0];
-        let result = bytes_to_decimal(bytes);
+        let result = bytes_to_decimal(&bytes);
 
         assert_eq!(result, (1000, 1000, 1000));
 
         let bytes = vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
-        let result = bytes_to_decimal(bytes);
+        let result = bytes_to_decimal(&bytes);
         assert_eq!(result, (9191, 9191, 9191));
     }
 
     proptest! {
         #[test]
         fn proptest_emoji(bytes in prop::array::uniform6(0u8..)) {
-            let numbers = bytes_to_emoji_index(bytes.to_vec());
+            let numbers = bytes_to_emoji_index(&bytes);
 
             for number in numbers.iter() {
                 prop_assert!(*number < 64);
@@ -629,7 +754,7 @@ mod test {
     proptest! {
         #[test]
         fn proptest_decimals(bytes in prop::array::uniform5(0u8..)) {
-            let (first, second, third) = bytes_to_decimal(bytes.to_vec());
+            let (first, second, third) = bytes_to_decimal(&bytes);
 
             prop_assert!((1000..=9191).contains(&first));
             prop_assert!((1000..=9191).contains(&second));
@@ -13,9 +13,9 @@
 // limitations under the License.
 
 use std::sync::Arc;
-#[cfg(test)]
-use std::time::Instant;
 
+#[cfg(test)]
+use matrix_sdk_common::instant::Instant;
 use ruma::{
     events::key::verification::{cancel::CancelCode, ShortAuthenticationString},
     EventId, RoomId, UserId,
@@ -28,7 +28,7 @@ use super::{
     FlowId,
 };
 use crate::{
-    identities::{ReadOnlyDevice, UserIdentities},
+    identities::{MasterPubkey, ReadOnlyDevice, UserIdentities},
     verification::{
         event_enums::{AnyVerificationContent, OutgoingContent, OwnedAcceptContent, StartContent},
         Cancelled, Done,
@@ -169,21 +169,24 @@ impl InnerSas {
         (InnerSas::Cancelled(sas), Some(content))
     }
 
-    pub fn confirm(self) -> (InnerSas, Option<OutgoingContent>) {
+    pub fn confirm(
+        self,
+        own_master_key: Option<MasterPubkey>,
+    ) -> (InnerSas, Option<OutgoingContent>) {
         match self {
             InnerSas::KeyReceived(s) => {
-                let sas = s.confirm();
+                let sas = s.confirm(own_master_key);
                 let content = sas.as_content();
                 (InnerSas::Confirmed(sas), Some(content))
             }
             InnerSas::MacReceived(s) => {
                 if s.started_from_request {
-                    let sas = s.confirm_and_wait_for_done();
+                    let sas = s.confirm_and_wait_for_done(own_master_key);
                     let content = sas.as_content();
 
                     (InnerSas::WaitingForDone(sas), Some(content))
                 } else {
-                    let sas = s.confirm();
+                    let sas = s.confirm(own_master_key);
                     let content = sas.as_content();
 
                     (InnerSas::Done(sas), Some(content))
@@ -290,6 +293,10 @@ impl InnerSas {
         matches!(self, InnerSas::KeyReceived(_) | InnerSas::MacReceived(_))
     }
 
+    pub fn is_start_pending(&self) -> bool {
+        matches!(self, InnerSas::Created(_))
+    }
+
     pub fn is_done(&self) -> bool {
         matches!(self, InnerSas::Done(_))
     }
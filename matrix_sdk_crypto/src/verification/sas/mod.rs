@@ -17,11 +17,14 @@ mod inner_sas;
 mod sas_state;
 
 use std::sync::{Arc, Mutex};
-#[cfg(test)]
-use std::time::Instant;
 
-pub use helpers::content_to_request;
+pub use helpers::{
+    bytes_to_decimal, bytes_to_emoji, bytes_to_emoji_index, content_to_request, emoji_from_index,
+    short_auth_string_info,
+};
 use inner_sas::InnerSas;
+#[cfg(test)]
+use matrix_sdk_common::instant::Instant;
 use matrix_sdk_common::uuid::Uuid;
 use ruma::{
     api::client::r0::keys::upload_signatures::Request as SignatureUploadRequest,
@@ -46,6 +49,7 @@ use crate::{
     olm::PrivateCrossSigningIdentity,
     requests::{OutgoingVerificationRequest, RoomMessageRequest},
     store::{CryptoStore, CryptoStoreError},
+    utilities::log_id,
     ReadOnlyAccount, ToDeviceRequest,
 };
 
@@ -289,10 +293,12 @@ impl Sas {
         (Option<OutgoingVerificationRequest>, Option<SignatureUploadRequest>),
         CryptoStoreError,
     > {
+        let own_master_key = self.identities_being_verified.own_master_key().await?;
+
         let (content, done) = {
             let mut guard = self.inner.lock().unwrap();
             let sas: InnerSas = (*guard).clone();
-            let (sas, content) = sas.confirm();
+            let (sas, content) = sas.confirm(own_master_key);
 
             *guard = sas;
             (content, guard.is_done())
@@ -307,8 +313,8 @@ impl Sas {
 
         if mac_request.is_some() {
             trace!(
-                user_id = self.other_user_id().as_str(),
-                device_id = self.other_device_id().as_str(),
+                user_id = %log_id(self.other_user_id().as_str()),
+                device_id = %log_id(self.other_device_id().as_str()),
                 "Confirming SAS verification"
             )
         }
@@ -373,6 +379,12 @@ impl Sas {
         self.inner.lock().unwrap().can_be_presented()
     }
 
+    /// Did we start the SAS flow and are still waiting for the other side to
+    /// accept it.
+    pub(crate) fn is_start_pending(&self) -> bool {
+        self.inner.lock().unwrap().is_start_pending()
+    }
+
     /// Is the SAS flow done.
     pub fn is_done(&self) -> bool {
         self.inner.lock().unwrap().is_done()
@@ -490,8 +502,9 @@ mod test {
 
     use super::Sas;
     use crate::{
+        identities::{UserIdentities, UserIdentity},
         olm::PrivateCrossSigningIdentity,
-        store::{CryptoStore, MemoryStore},
+        store::{Changes, CryptoStore, IdentityChanges, MemoryStore},
         verification::event_enums::{
             AcceptContent, KeyContent, MacContent, OutgoingContent, StartContent,
         },
@@ -587,4 +600,76 @@ mod test {
         assert!(alice.verified_devices().unwrap().contains(alice.other_device()));
         assert!(bob.verified_devices().unwrap().contains(bob.other_device()));
     }
+
+    #[tokio::test]
+    async fn sas_verifies_user_identity() {
+        let alice = ReadOnlyAccount::new(&alice_id(), &alice_device_id());
+        let (alice_identity, _, _) = PrivateCrossSigningIdentity::new_with_account(&alice).await;
+
+        // Bob sees the device of Alice signed by her self signing key.
+        let mut alice_keys = alice.device_keys().await;
+        alice_identity.sign_device_keys(&mut alice_keys).await.unwrap();
+        let alice_device = ReadOnlyDevice::try_from(&alice_keys).unwrap();
+        let alice_public_identity: UserIdentities =
+            UserIdentity::from_private(&alice_identity).await.into();
+
+        let bob = ReadOnlyAccount::new(&bob_id(), &bob_device_id());
+        let bob_device = ReadOnlyDevice::from_account(&bob).await;
+        let (bob_identity, _, _) = PrivateCrossSigningIdentity::new_with_account(&bob).await;
+
+        let alice_store: Arc<dyn CryptoStore> = Arc::new(MemoryStore::new());
+        let bob_store = MemoryStore::new();
+
+        bob_store.save_devices(vec![alice_device.clone()]).await;
+        let changes = Changes {
+            identities: IdentityChanges {
+                new: vec![alice_public_identity.clone()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        bob_store.save_changes(changes).await.unwrap();
+
+        let bob_store: Arc<dyn CryptoStore> = Arc::new(bob_store);
+
+        let (alice, content) =
+            Sas::start(alice, alice_identity, bob_device, alice_store, None, None);
+
+        let flow_id = alice.flow_id().to_owned();
+        let content = StartContent::try_from(&content).unwrap();
+
+        let bob = Sas::from_start_event(
+            flow_id,
+            &content,
+            bob_store,
+            bob,
+            bob_identity,
+            alice_device,
+            Some(alice_public_identity.clone()),
+            false,
+        )
+        .unwrap();
+
+        let request = bob.accept().unwrap();
+        let content = OutgoingContent::try_from(request).unwrap();
+        let content = AcceptContent::try_from(&content).unwrap();
+        let content = alice.receive_any_event(bob.user_id(), &content.into()).unwrap();
+
+        let content = KeyContent::try_from(&content).unwrap();
+        let content = bob.receive_any_event(alice.user_id(), &content.into()).unwrap();
+
+        let content = KeyContent::try_from(&content).unwrap();
+        alice.receive_any_event(bob.user_id(), &content.into());
+
+        let request = alice.confirm().await.unwrap().0.unwrap();
+        let content = OutgoingContent::try_from(request).unwrap();
+        let content = MacContent::try_from(&content).unwrap();
+        bob.receive_any_event(alice.user_id(), &content.into());
+
+        let (_, signature_request) = bob.confirm().await.unwrap();
+
+        assert!(bob.verified_identities().unwrap().contains(&alice_public_identity));
+        // Bob signs the master key of Alice with his user signing key.
+        assert!(signature_request.is_some());
+    }
 }
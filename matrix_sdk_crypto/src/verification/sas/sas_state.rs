@@ -16,10 +16,12 @@ use std::{
     convert::TryFrom,
     matches,
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
 };
 
-use matrix_sdk_common::uuid::Uuid;
+use matrix_sdk_common::{
+    instant::{Duration, Instant},
+    uuid::Uuid,
+};
 use olm_rs::sas::OlmSas;
 use ruma::{
     events::{
@@ -52,7 +54,7 @@ use super::{
     OutgoingContent,
 };
 use crate::{
-    identities::{ReadOnlyDevice, UserIdentities},
+    identities::{MasterPubkey, ReadOnlyDevice, UserIdentities},
     verification::{
         event_enums::{
             AcceptContent, DoneContent, KeyContent, MacContent, OwnedAcceptContent,
@@ -394,7 +396,7 @@ impl SasState<Created> {
     ) -> SasState<Created> {
         SasState {
             inner: Arc::new(Mutex::new(OlmSas::new())),
-            ids: SasIds { account, other_device, other_identity },
+            ids: SasIds { account, other_device, other_identity, own_master_key: None },
             verification_flow_id: flow_id.into(),
 
             creation_time: Arc::new(Instant::now()),
@@ -510,6 +512,7 @@ impl SasState<Started> {
                 account: account.clone(),
                 other_device: other_device.clone(),
                 other_identity: other_identity.clone(),
+                own_master_key: None,
             },
 
             verification_flow_id: flow_id.clone(),
@@ -522,16 +525,13 @@ impl SasState<Started> {
             let pubkey = sas.public_key();
             let commitment = calculate_commitment(&pubkey, content);
 
-            info!(
-                "Calculated commitment for pubkey {} and content {:?} {}",
-                pubkey, content, commitment
-            );
+            info!("Calculated commitment {} for pubkey {}", commitment, pubkey);
 
             if let Ok(accepted_protocols) = AcceptedProtocols::try_from(method_content) {
                 Ok(SasState {
                     inner: Arc::new(Mutex::new(sas)),
 
-                    ids: SasIds { account, other_device, other_identity },
+                    ids: SasIds { account, other_device, other_identity, own_master_key: None },
 
                     creation_time: Arc::new(Instant::now()),
                     last_event_time: Arc::new(Instant::now()),
@@ -813,14 +813,19 @@ impl SasState<KeyReceived> {
     ///
     /// This needs to be done by the user, this will put us in the `Confirmed`
     /// state.
-    pub fn confirm(self) -> SasState<Confirmed> {
+    ///
+    /// # Arguments
+    ///
+    /// * `own_master_key` - Our own master key, if we trust it, it will be
+    /// included in the MAC.
+    pub fn confirm(self, own_master_key: Option<MasterPubkey>) -> SasState<Confirmed> {
         SasState {
             inner: self.inner,
             started_from_request: self.started_from_request,
             verification_flow_id: self.verification_flow_id,
             creation_time: self.creation_time,
             last_event_time: self.last_event_time,
-            ids: self.ids,
+            ids: SasIds { own_master_key, ..self.ids },
             state: Arc::new(Confirmed {
                 accepted_protocols: self.state.accepted_protocols.clone(),
             }),
@@ -920,14 +925,19 @@ impl SasState<MacReceived> {
     ///
     /// This needs to be done by the user, this will put us in the `Done`
     /// state since the other side already confirmed and sent us a MAC event.
-    pub fn confirm(self) -> SasState<Done> {
+    ///
+    /// # Arguments
+    ///
+    /// * `own_master_key` - Our own master key, if we trust it, it will be
+    /// included in the MAC.
+    pub fn confirm(self, own_master_key: Option<MasterPubkey>) -> SasState<Done> {
         SasState {
             inner: self.inner,
             verification_flow_id: self.verification_flow_id,
             creation_time: self.creation_time,
             started_from_request: self.started_from_request,
             last_event_time: self.last_event_time,
-            ids: self.ids,
+            ids: SasIds { own_master_key, ..self.ids },
             state: Arc::new(Done {
                 verified_devices: self.state.verified_devices.clone(),
                 verified_master_keys: self.state.verified_master_keys.clone(),
@@ -941,14 +951,22 @@ impl SasState<MacReceived> {
     /// This needs to be done by the user, this will put us in the `WaitForDone`
     /// state where we wait for the other side to confirm that the MAC event was
     /// successfully received.
-    pub fn confirm_and_wait_for_done(self) -> SasState<WaitingForDone> {
+    ///
+    /// # Arguments
+    ///
+    /// * `own_master_key` - Our own master key, if we trust it, it will be
+    /// included in the MAC.
+    pub fn confirm_and_wait_for_done(
+        self,
+        own_master_key: Option<MasterPubkey>,
+    ) -> SasState<WaitingForDone> {
         SasState {
             inner: self.inner,
             verification_flow_id: self.verification_flow_id,
             creation_time: self.creation_time,
             started_from_request: self.started_from_request,
             last_event_time: self.last_event_time,
-            ids: self.ids,
+            ids: SasIds { own_master_key, ..self.ids },
             state: Arc::new(WaitingForDone {
                 verified_devices: self.state.verified_devices.clone(),
                 verified_master_keys: self.state.verified_master_keys.clone(),
@@ -1201,7 +1219,7 @@ mod test {
 
         let bob_decimals = bob.get_decimal();
 
-        let bob = bob.confirm();
+        let bob = bob.confirm(None);
 
         let content = bob.as_content();
         let content = MacContent::try_from(&content).unwrap();
@@ -1209,7 +1227,7 @@ mod test {
         let alice = alice.into_mac_received(bob.user_id(), &content).unwrap();
         assert!(!alice.get_emoji().is_empty());
         assert_eq!(alice.get_decimal(), bob_decimals);
-        let alice = alice.confirm();
+        let alice = alice.confirm(None);
 
         let content = alice.as_content();
         let content = MacContent::try_from(&content).unwrap();
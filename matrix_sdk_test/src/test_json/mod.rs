@@ -22,6 +22,28 @@ pub use sync::{
     DEFAULT_SYNC_SUMMARY, INVITE_SYNC, LEAVE_SYNC, LEAVE_SYNC_EVENT, MORE_SYNC, SYNC, VOIP_SYNC,
 };
 
+lazy_static! {
+    pub static ref CAPABILITIES: JsonValue = json!({
+        "capabilities": {
+            "m.change_password": {
+                "enabled": false
+            },
+            "m.room_versions": {
+                "default": "6",
+                "available": {
+                    "1": "stable",
+                    "5": "stable",
+                    "6": "stable",
+                    "org.matrix.msc2176": "unstable"
+                }
+            },
+            "m.set_displayname": {
+                "enabled": false
+            }
+        }
+    });
+}
+
 lazy_static! {
     pub static ref DEVICES: JsonValue = json!({
         "devices": [
@@ -43,6 +65,34 @@ lazy_static! {
     });
 }
 
+lazy_static! {
+    pub static ref OPENID_TOKEN: JsonValue = json!({
+        "access_token": "SomeT0kenHere",
+        "token_type": "Bearer",
+        "matrix_server_name": "example.com",
+        "expires_in": 3600
+    });
+}
+
+lazy_static! {
+    pub static ref PUSHERS: JsonValue = json!({
+        "pushers": [
+            {
+                "pushkey": "pushkey",
+                "kind": "http",
+                "app_id": "org.example.app",
+                "app_display_name": "Example App",
+                "device_display_name": "Example Device",
+                "profile_tag": "xxyyzz",
+                "lang": "en",
+                "data": {
+                    "url": "https://push.example.org/_matrix/push/v1/notify"
+                }
+            }
+        ]
+    });
+}
+
 lazy_static! {
     pub static ref WELL_KNOWN: JsonValue = json!({
         "m.homeserver": {